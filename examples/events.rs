@@ -0,0 +1,43 @@
+//! Two nodes in one process: one copies some text, and every event the other
+//! broadcasts while receiving it is printed.
+//!
+//! ```sh
+//! cargo run --example events
+//! ```
+
+use libp2p_clipboard_sync::control::NodeEvent;
+use libp2p_clipboard_sync::in_process::Node;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let sender = Node::start(&["--clipboard"])?;
+    let receiver = Node::start(&["--clipboard", "--connect", &sender.address.to_string()])?;
+
+    let mut events = receiver.events();
+    let printer = tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    println!("{event:?}");
+                    if matches!(event, NodeEvent::ClipboardApplied { .. }) {
+                        break;
+                    }
+                }
+                // A subscriber that falls behind misses the oldest events
+                Err(RecvError::Lagged(missed)) => println!("Missed {missed} events"),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    // Give the nodes a moment to connect and subscribe
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    sender.clipboard.copy_text("hello from the other node");
+    tokio::time::timeout(Duration::from_secs(10), printer).await??;
+
+    sender.stop().await?;
+    receiver.stop().await?;
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use crate::queue;
+use anyhow::{Context, Result};
+use libp2p::{Multiaddr, PeerId};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+/// Most addresses remembered per peer, most recently working first
+const MAX_ADDRS_PER_PEER: usize = 8;
+/// Address book entries dialed at the same time during startup
+const AUTODIAL_CONCURRENCY: usize = 4;
+/// Consecutive failed dials after which a peer is dropped from the book
+const MAX_DIAL_FAILURES: u32 = 5;
+
+/// What we remember about a group member
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(default)]
+    pub device_name: Option<String>,
+    /// Known addresses, the most recently working ones first
+    pub addrs: Vec<Multiaddr>,
+    /// Seconds since the Unix epoch
+    pub last_seen: u64,
+    /// Dials that failed since the peer was last reached
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub dial_failures: u32,
+}
+
+/// Every group member we have synced with, persisted as JSON in the profile
+/// directory or `--peers-file` so the mesh can reform at startup without mDNS
+#[derive(Debug)]
+pub struct AddressBook {
+    path: Option<PathBuf>,
+    peers: BTreeMap<PeerId, Entry>,
+    /// Task writing saved snapshots to disk, started by the first save
+    writer: Option<(queue::Sender<String>, JoinHandle<()>)>,
+}
+
+impl AddressBook {
+    /// Load the book at `path` (in-memory only without one), dropping entries
+    /// not seen for `max_age_days`
+    pub fn load(path: Option<PathBuf>, max_age_days: u64) -> Result<Self> {
+        let mut peers = BTreeMap::new();
+        if let Some(ref path) = path
+            && path.exists()
+        {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read address book {}", path.display()))?;
+            let stored: BTreeMap<String, Entry> = serde_json::from_str(&text)
+                .with_context(|| format!("Address book {} is corrupt", path.display()))?;
+            let cutoff = now_secs().saturating_sub(max_age_days * 24 * 60 * 60);
+            peers = stored
+                .into_iter()
+                .filter(|(_, entry)| entry.last_seen >= cutoff)
+                .filter_map(|(peer, entry)| Some((peer.parse().ok()?, entry)))
+                .collect();
+        }
+        Ok(Self { path, peers, writer: None })
+    }
+
+    /// Write the book back to disk off the event loop, replacing the old
+    /// file atomically. Snapshots are written one at a time, and one still
+    /// waiting is replaced by the next. Failures are logged.
+    pub fn save(&mut self) {
+        let Some(ref path) = self.path else {
+            return;
+        };
+        let stored: BTreeMap<String, &Entry> =
+            self.peers.iter().map(|(peer, entry)| (peer.to_string(), entry)).collect();
+        let text = match serde_json::to_string_pretty(&stored) {
+            Ok(text) => text,
+            Err(e) => {
+                warn!("Failed to encode address book: {e}");
+                return;
+            }
+        };
+        let (writer, _) = self.writer.get_or_insert_with(|| spawn_writer(path.clone()));
+        let _ = writer.send(text);
+    }
+
+    /// Wait until every save has reached the disk, before shutting down
+    pub async fn flush(&mut self) {
+        if let Some((writer, task)) = self.writer.take() {
+            drop(writer);
+            let _ = task.await;
+        }
+    }
+
+    /// Every group member in the book
+    pub fn members(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.peers.keys().copied()
+    }
+
+    /// Record the device name `peer` advertises, if it is in the book
+    pub fn set_device_name(&mut self, peer: &PeerId, name: Option<String>) {
+        if let Some(entry) = self.peers.get_mut(peer) {
+            entry.device_name = name;
+        }
+    }
+
+    /// Remember `peer` along with addresses it claims to listen on, kept
+    /// behind any addresses already known to work
+    pub fn add_member(&mut self, peer: PeerId, addrs: impl IntoIterator<Item = Multiaddr>) {
+        let entry = self.peers.entry(peer).or_default();
+        for addr in addrs {
+            if !entry.addrs.contains(&addr) {
+                entry.addrs.push(addr);
+            }
+        }
+        entry.addrs.truncate(MAX_ADDRS_PER_PEER);
+        entry.last_seen = now_secs();
+    }
+
+    /// Move an address we successfully dialed a known peer on to the front
+    pub fn record_working_addr(&mut self, peer: &PeerId, addr: Multiaddr) {
+        if let Some(entry) = self.peers.get_mut(peer) {
+            entry.addrs.retain(|known| *known != addr);
+            entry.addrs.insert(0, addr);
+            entry.addrs.truncate(MAX_ADDRS_PER_PEER);
+            entry.last_seen = now_secs();
+            entry.dial_failures = 0;
+        }
+    }
+
+    /// Count a failed dial to `peer`, dropping it once it has failed too many
+    /// times in a row. Returns whether the book changed.
+    pub fn record_dial_failure(&mut self, peer: &PeerId) -> bool {
+        let Some(entry) = self.peers.get_mut(peer) else {
+            return false;
+        };
+        entry.dial_failures += 1;
+        if entry.dial_failures >= MAX_DIAL_FAILURES {
+            info!("Forgetting {peer}: {MAX_DIAL_FAILURES} dials in a row failed");
+            self.peers.remove(peer);
+        }
+        true
+    }
+
+    /// Update the last-seen time of a peer already in the book. Returns
+    /// whether the peer is in the book.
+    pub fn touch(&mut self, peer: &PeerId) -> bool {
+        match self.peers.get_mut(peer) {
+            Some(entry) => {
+                entry.last_seen = now_secs();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Entries sorted by most recently seen first
+    pub fn recent(&self) -> Vec<(PeerId, &Entry)> {
+        let mut entries: Vec<_> = self.peers.iter().map(|(peer, entry)| (*peer, entry)).collect();
+        entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.last_seen));
+        entries
+    }
+}
+
+/// Startup dialing of address book entries, most recent first, with a bound
+/// on how many dials are in flight at once
+#[derive(Debug, Default)]
+pub struct Autodial {
+    queue: VecDeque<(PeerId, Vec<Multiaddr>)>,
+    in_flight: HashSet<PeerId>,
+}
+
+impl Autodial {
+    pub fn new(book: &AddressBook) -> Self {
+        Self {
+            queue: book
+                .recent()
+                .into_iter()
+                .filter(|(_, entry)| !entry.addrs.is_empty())
+                .map(|(peer, entry)| (peer, entry.addrs.clone()))
+                .collect(),
+            in_flight: HashSet::new(),
+        }
+    }
+
+    /// Entries to dial now to fill the free dial slots
+    pub fn next_batch(&mut self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let mut batch = Vec::new();
+        while self.in_flight.len() < AUTODIAL_CONCURRENCY
+            && let Some((peer, addrs)) = self.queue.pop_front()
+        {
+            self.in_flight.insert(peer);
+            batch.push((peer, addrs));
+        }
+        batch
+    }
+
+    /// A dial to `peer` finished, successfully or not, freeing its slot.
+    /// Returns whether it was one of ours.
+    pub fn finished(&mut self, peer: &PeerId) -> bool {
+        self.queue.retain(|(queued, _)| queued != peer);
+        self.in_flight.remove(peer)
+    }
+}
+
+/// Start the task writing snapshots of the book to `path`, newest last
+fn spawn_writer(path: PathBuf) -> (queue::Sender<String>, JoinHandle<()>) {
+    let (tx, mut rx) = queue::channel::<String>(1);
+    let task = tokio::spawn(async move {
+        while let Some(text) = rx.recv().await {
+            let path = path.clone();
+            match tokio::task::spawn_blocking(move || write(&path, &text)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("Failed to save address book: {e:?}"),
+                Err(e) => warn!("Address book save task failed: {e}"),
+            }
+        }
+    });
+    (tx, task)
+}
+
+/// Replace the file at `path` with `text` atomically
+fn write(path: &Path, text: &str) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, text).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+/// Human-readable time since a last-seen timestamp
+pub fn format_age(last_seen: u64) -> String {
+    let secs = now_secs().saturating_sub(last_seen);
+    match secs {
+        0..60 => format!("{secs}s ago"),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,392 @@
+use crate::clipboard::{ClipboardContent, ContentType};
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use libp2p::PeerId;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+/// `prev` of the first entry in a log
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Bytes read at a time when looking for the last lines of a log
+const TAIL_BLOCK: u64 = 4096;
+
+type Key = Hmac<Sha256>;
+
+/// Whether content left or entered this machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    Sent,
+    Received,
+}
+
+/// A clipboard item to record, described on the writer thread so its
+/// payload isn't hashed on the event loop
+#[derive(Debug, Clone)]
+pub struct Item(ClipboardContent);
+
+/// What is recorded about one clipboard item
+#[derive(Debug, Serialize, Deserialize)]
+struct Description {
+    content_type: ContentType,
+    size: usize,
+    /// SHA-256 of the payload. For files, of the offered names and hashes.
+    sha256: String,
+    /// The text itself, only with `--audit-include-text`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+}
+
+/// One line of the audit log
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Milliseconds since the Unix epoch when the entry was written
+    time: u64,
+    direction: Direction,
+    /// Where sent content went, or the peer that authored received content
+    peers: Vec<String>,
+    /// Room the content went to or came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    room: Option<String>,
+    #[serde(flatten)]
+    item: Description,
+    /// HMAC-SHA256 of the previous line under the --audit-key, so edited,
+    /// removed or reordered lines break the chain, even for someone who can
+    /// write the file
+    prev: String,
+}
+
+/// An entry waiting for the writer thread
+struct Record {
+    time: u64,
+    direction: Direction,
+    peers: Vec<PeerId>,
+    room: String,
+    item: Item,
+}
+
+/// Append-only record of the clipboard content that left or entered this
+/// machine, for deployments that must account for it. Unlike the history it
+/// holds metadata rather than content, one JSON line per item, each chained
+/// to the one before. Entries are hashed and written on a thread of their own.
+pub struct AuditLog {
+    records: Option<mpsc::Sender<Record>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl AuditLog {
+    /// Open the log at `path`, continuing the chain of any entries already in
+    /// it. Only the end of the log is read, and it has to verify under `key`.
+    pub fn open(path: &Path, key: &str, include_text: bool) -> Result<Self> {
+        let key = mac_key(key);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        let prev = continue_chain(&mut file, &key).with_context(|| {
+            format!(
+                "Audit log {} does not verify at its end. Check it with `verify-audit`, and move it aside to start a new log",
+                path.display()
+            )
+        })?;
+        info!("Recording synced clipboard items in {}", path.display());
+        let mut writer = Writer { path: path.to_path_buf(), file, key, prev, include_text };
+        let (records, received) = mpsc::channel::<Record>();
+        let writer = thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || {
+                for record in received {
+                    writer.append(record);
+                }
+            })
+            .context("Failed to start the audit log writer")?;
+        Ok(Self { records: Some(records), writer: Some(writer) })
+    }
+
+    /// Take `content` for a later `sent_item`, e.g. when publishing has to
+    /// wait for a subscriber
+    pub fn item(&self, content: &ClipboardContent) -> Item {
+        Item(content.clone())
+    }
+
+    /// Record `content` going out to `peers` in `room`
+    pub fn sent(&mut self, content: &ClipboardContent, peers: &[PeerId], room: &str) {
+        let item = self.item(content);
+        self.sent_item(item, peers, room);
+    }
+
+    /// Record an item taken earlier going out to `peers` in `room`
+    pub fn sent_item(&mut self, item: Item, peers: &[PeerId], room: &str) {
+        self.queue(Direction::Sent, peers, room, item);
+    }
+
+    /// Record `content` authored by `from` arriving from `room`
+    pub fn received(&mut self, content: &ClipboardContent, from: PeerId, room: &str) {
+        let item = self.item(content);
+        self.queue(Direction::Received, &[from], room, item);
+    }
+
+    fn queue(&mut self, direction: Direction, peers: &[PeerId], room: &str, item: Item) {
+        let record = Record {
+            time: crate::clipboard::now_millis(),
+            direction,
+            peers: peers.to_vec(),
+            room: room.to_string(),
+            item,
+        };
+        if let Some(ref records) = self.records
+            && records.send(record).is_err()
+        {
+            error!("The audit log writer stopped, items are no longer recorded");
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    /// Wait for the entries still queued to be written
+    fn drop(&mut self) {
+        self.records.take();
+        if let Some(writer) = self.writer.take()
+            && writer.join().is_err()
+        {
+            error!("The audit log writer panicked");
+        }
+    }
+}
+
+/// The writing end of an [`AuditLog`]
+struct Writer {
+    path: PathBuf,
+    file: File,
+    key: Key,
+    prev: String,
+    include_text: bool,
+}
+
+impl Writer {
+    /// Write errors are logged rather than returned, so syncing carries on
+    fn append(&mut self, record: Record) {
+        let Item(content) = record.item;
+        let sha256 = content.sha256().unwrap_or_else(|e| {
+            warn!("Failed to read clipboard payload for the audit log: {e:?}");
+            String::new()
+        });
+        let entry = Entry {
+            time: record.time,
+            direction: record.direction,
+            peers: record.peers.iter().map(PeerId::to_string).collect(),
+            room: Some(record.room),
+            item: Description {
+                content_type: content.content_type,
+                size: content.size(),
+                sha256,
+                text: if self.include_text { content.text() } else { None },
+            },
+            prev: self.prev.clone(),
+        };
+        let result = serde_json::to_string(&entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                // One write per line so a crash leaves at most one partial line
+                self.file.write_all(format!("{line}\n").as_bytes())?;
+                Ok(line)
+            });
+        match result {
+            Ok(line) => self.prev = line_mac(&self.key, &line),
+            Err(e) => error!("Failed to write to audit log {}: {e:?}", self.path.display()),
+        }
+    }
+}
+
+/// Check the chain of the audit log at `path` under `key`, returning how
+/// many entries it holds
+pub fn verify(path: &Path, key: &str) -> Result<usize> {
+    let file = File::open(path).with_context(|| format!("Failed to read audit log {}", path.display()))?;
+    let key = mac_key(key);
+    let mut prev = GENESIS.to_string();
+    let mut count = 0;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("Failed to read audit log {}", path.display()))?;
+        check_link(&line, &prev).with_context(|| format!("line {}", index + 1))?;
+        prev = line_mac(&key, &line);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Check the last entries of `file` and return what the next one chains to
+fn continue_chain(file: &mut File, key: &Key) -> Result<String> {
+    let lines = tail(file, 2)?;
+    match lines.as_slice() {
+        [] => Ok(GENESIS.to_string()),
+        [only] => {
+            check_link(only, GENESIS)?;
+            Ok(line_mac(key, only))
+        }
+        [before, last] => {
+            check_link(last, &line_mac(key, before)).context("the last line")?;
+            Ok(line_mac(key, last))
+        }
+        _ => unreachable!("at most two lines are read"),
+    }
+}
+
+fn check_link(line: &str, prev: &str) -> Result<()> {
+    let entry: Entry = serde_json::from_str(line).context("not an audit entry")?;
+    if entry.prev != prev {
+        bail!("does not follow the line before it");
+    }
+    Ok(())
+}
+
+/// Up to the last `count` lines of `file`, read backwards from its end
+fn tail(file: &mut File, count: usize) -> Result<Vec<String>> {
+    let mut pos = file.seek(SeekFrom::End(0))?;
+    let mut bytes = Vec::new();
+    // One newline more than lines wanted, so the first of them is whole
+    while pos > 0 && bytes.iter().filter(|&&byte| byte == b'\n').count() <= count {
+        let step = pos.min(TAIL_BLOCK);
+        pos -= step;
+        let mut block = vec![0; step as usize];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut block)?;
+        block.extend_from_slice(&bytes);
+        bytes = block;
+    }
+    if bytes.last().is_some_and(|&byte| byte != b'\n') {
+        bail!("the last line is incomplete");
+    }
+    let text = String::from_utf8_lossy(&bytes);
+    let lines: Vec<&str> = text.lines().collect();
+    Ok(lines[lines.len().saturating_sub(count)..].iter().map(|line| line.to_string()).collect())
+}
+
+fn mac_key(key: &str) -> Key {
+    Key::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length")
+}
+
+fn line_mac(key: &Key, line: &str) -> String {
+    let mut mac = key.clone();
+    mac.update(line.as_bytes());
+    hex(&mac.finalize().into_bytes())
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const KEY: &str = "audit secret";
+
+    fn write(path: &Path, texts: &[&str]) {
+        let mut log = AuditLog::open(path, KEY, true).unwrap();
+        for text in texts {
+            log.sent(&ClipboardContent::new_text(text.to_string()), &[PeerId::random()], "default");
+        }
+    }
+
+    #[test]
+    fn entries_chain_across_restarts_under_the_key() {
+        let dir = TempDir::new();
+        let path = dir.path().join("audit.jsonl");
+        write(&path, &["one", "two"]);
+        write(&path, &["three"]);
+        assert_eq!(verify(&path, KEY).unwrap(), 3);
+        assert!(verify(&path, "another secret").is_err());
+    }
+
+    #[test]
+    fn a_rewritten_chain_does_not_verify_without_the_key() {
+        let dir = TempDir::new();
+        let path = dir.path().join("audit.jsonl");
+        write(&path, &["one", "two", "three"]);
+        let text = std::fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+        lines[1] = lines[1].replace("two", "owt");
+        // Someone without the key can't work out the link to the edited line
+        let forged = hex(&<Sha256 as sha2::Digest>::digest(lines[1].as_bytes()));
+        let entry: Entry = serde_json::from_str(&lines[2]).unwrap();
+        lines[2] = lines[2].replace(&entry.prev, &forged);
+        std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+        assert!(verify(&path, KEY).is_err());
+        assert!(AuditLog::open(&path, KEY, false).is_err());
+    }
+
+    #[test]
+    fn only_the_end_of_a_log_is_checked_on_open() {
+        let dir = TempDir::new();
+        let path = dir.path().join("audit.jsonl");
+        let texts: Vec<String> = (0..200).map(|index| format!("item {index} {}", "x".repeat(100))).collect();
+        write(&path, &texts.iter().map(String::as_str).collect::<Vec<_>>());
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.len() as u64 > 4 * TAIL_BLOCK);
+        // A broken first line is left for verify-audit to find
+        std::fs::write(&path, text.replacen("item 0", "item X", 1)).unwrap();
+        write(&path, &["more"]);
+        assert!(verify(&path, KEY).is_err());
+    }
+
+    #[test]
+    fn a_log_ending_in_a_partial_line_is_refused() {
+        let dir = TempDir::new();
+        let path = dir.path().join("audit.jsonl");
+        write(&path, &["one"]);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"time\":").unwrap();
+        assert!(AuditLog::open(&path, KEY, false).is_err());
+    }
+
+    fn entries(path: &Path) -> Vec<Entry> {
+        std::fs::read_to_string(path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn sent_and_received_items_are_described_without_their_text() {
+        let dir = TempDir::new();
+        let path = dir.path().join("audit.jsonl");
+        let (to, from) = (PeerId::random(), PeerId::random());
+        let image = ClipboardContent::new_image(vec![7; 16], 2, 2);
+        {
+            let mut log = AuditLog::open(&path, KEY, false).unwrap();
+            log.sent(&ClipboardContent::new_text("secret".to_string()), &[to, from], "office");
+            log.received(&image, from, "lab");
+        }
+
+        let [sent, received] = <[Entry; 2]>::try_from(entries(&path)).unwrap();
+        assert_eq!(sent.direction, Direction::Sent);
+        assert_eq!(sent.peers, [to.to_string(), from.to_string()]);
+        assert_eq!(sent.room.as_deref(), Some("office"));
+        assert_eq!((sent.item.content_type, sent.item.size), (ContentType::Text, 6));
+        assert_eq!(sent.item.sha256, hex(&<Sha256 as sha2::Digest>::digest(b"secret")));
+        assert_eq!(sent.item.text, None);
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("secret"));
+
+        assert_eq!(received.direction, Direction::Received);
+        assert_eq!(received.peers, [from.to_string()]);
+        assert_eq!(received.room.as_deref(), Some("lab"));
+        assert_eq!((received.item.content_type, received.item.size), (ContentType::Image, 16));
+        assert_eq!(received.item.sha256, hex(&<Sha256 as sha2::Digest>::digest([7; 16])));
+    }
+
+    #[test]
+    fn text_is_recorded_only_when_asked_for() {
+        let dir = TempDir::new();
+        let path = dir.path().join("audit.jsonl");
+        write(&path, &["hello"]);
+        let [entry] = <[Entry; 1]>::try_from(entries(&path)).unwrap();
+        assert_eq!(entry.item.text.as_deref(), Some("hello"));
+    }
+}
@@ -0,0 +1,136 @@
+use crate::audit;
+use crate::clipboard::ClipboardContent;
+use crate::control::NodeEvent;
+use libp2p::gossipsub::{self, PublishError};
+use std::time::{Duration, Instant};
+
+/// Delays before each retry of a publish that found every peer's send queue
+/// full. Once they are used up the content goes out as direct requests.
+const RETRY_DELAYS: [Duration; 4] = [
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+];
+/// How often waiting publishes are checked on
+pub const RETRY_TICK: Duration = Duration::from_millis(50);
+
+/// Whether a publish failed only because gossipsub's send queue to every
+/// peer was full, so it can succeed once they drain
+pub fn is_queue_full(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<PublishError>(), Some(PublishError::AllQueuesFull(_)))
+}
+
+/// A clipboard item whose publish hit full queues, with what the event loop
+/// does once it goes out
+#[derive(Debug)]
+pub struct Retry {
+    pub topic: gossipsub::IdentTopic,
+    pub data: Vec<u8>,
+    /// Sent as is when falling back to direct requests
+    pub content: ClipboardContent,
+    /// Room the item is sent to
+    pub room: String,
+    pub sent_event: NodeEvent,
+    pub audit_item: Option<audit::Item>,
+    /// Hash and preview to track delivery confirmations by
+    pub confirm: Option<(String, String)>,
+    /// Retries made so far
+    attempts: usize,
+    due: Instant,
+}
+
+impl Retry {
+    pub fn new(
+        topic: gossipsub::IdentTopic,
+        data: Vec<u8>,
+        content: ClipboardContent,
+        room: String,
+        sent_event: NodeEvent,
+        audit_item: Option<audit::Item>,
+        confirm: Option<(String, String)>,
+    ) -> Self {
+        Self { topic, data, content, room, sent_event, audit_item, confirm, attempts: 0, due: Instant::now() + RETRY_DELAYS[0] }
+    }
+}
+
+/// Clipboard publishes waiting for gossipsub's queues to drain
+#[derive(Debug, Default)]
+pub struct Retries {
+    waiting: Vec<Retry>,
+}
+
+impl Retries {
+    pub fn push(&mut self, retry: Retry) {
+        self.waiting.push(retry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    /// Publishes whose next retry is due, oldest first
+    pub fn take_due(&mut self) -> Vec<Retry> {
+        let now = Instant::now();
+        let (due, waiting) = std::mem::take(&mut self.waiting).into_iter().partition(|retry| retry.due <= now);
+        self.waiting = waiting;
+        due
+    }
+
+    /// Schedule another retry of a publish that found the queues full again.
+    /// Hands it back once its retries are used up.
+    pub fn retry_later(&mut self, mut retry: Retry) -> Option<Retry> {
+        retry.attempts += 1;
+        match RETRY_DELAYS.get(retry.attempts) {
+            Some(delay) => {
+                retry.due = Instant::now() + *delay;
+                self.waiting.push(retry);
+                None
+            }
+            None => Some(retry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::ContentType;
+
+    fn retry() -> Retry {
+        let content = ClipboardContent::new_text("hello".to_string());
+        let sent_event =
+            NodeEvent::ClipboardSent { content_type: ContentType::Text, size: 5, preview: "hello".to_string(), hash: 0, peers: 1 };
+        Retry::new(gossipsub::IdentTopic::new("clipboard"), content.data.clone(), content, "default".to_string(), sent_event, None, None)
+    }
+
+    #[test]
+    fn only_full_queues_are_worth_retrying() {
+        assert!(is_queue_full(&PublishError::AllQueuesFull(3).into()));
+        assert!(!is_queue_full(&PublishError::NoPeersSubscribedToTopic.into()));
+        assert!(!is_queue_full(&anyhow::anyhow!("something else")));
+    }
+
+    #[test]
+    fn a_publish_is_retried_until_its_delays_are_used_up() {
+        let mut retries = Retries::default();
+        retries.push(retry());
+        assert!(retries.take_due().is_empty(), "not due before its first delay");
+        assert_eq!(retries.len(), 1);
+
+        // Each time the queues are still full it waits again, for longer
+        let mut retry = retries.waiting.pop().unwrap();
+        for delay in &RETRY_DELAYS[1..] {
+            assert!(retries.retry_later(retry).is_none());
+            retry = retries.waiting.pop().unwrap();
+            assert!(retry.due > Instant::now() + *delay / 2);
+        }
+        assert!(retries.is_empty());
+        assert!(retries.retry_later(retry).is_some(), "handed back for direct requests");
+        assert!(retries.is_empty());
+    }
+}
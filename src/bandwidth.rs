@@ -0,0 +1,164 @@
+use libp2p::PeerId;
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Length of the rolling window for recent traffic and the cap
+pub const WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Granularity of the rolling window
+const SLOT: Duration = Duration::from_secs(60);
+
+/// Traffic in one slot of the rolling window
+#[derive(Debug)]
+struct Slot {
+    start: Instant,
+    sent: u64,
+    received: u64,
+}
+
+/// Payload bytes exchanged with one peer
+#[derive(Debug, Default)]
+pub struct PeerTraffic {
+    sent: u64,
+    received: u64,
+    slots: VecDeque<Slot>,
+}
+
+impl PeerTraffic {
+    fn record(&mut self, now: Instant, sent: u64, received: u64) {
+        self.sent += sent;
+        self.received += received;
+        match self.slots.back_mut() {
+            Some(slot) if now.duration_since(slot.start) < SLOT => {
+                slot.sent += sent;
+                slot.received += received;
+            }
+            _ => self.slots.push_back(Slot { start: now, sent, received }),
+        }
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self.slots.front().is_some_and(|slot| now.duration_since(slot.start) >= WINDOW) {
+            self.slots.pop_front();
+        }
+    }
+
+    /// Bytes sent and received since this node started
+    pub fn total(&self) -> (u64, u64) {
+        (self.sent, self.received)
+    }
+
+    /// Bytes sent and received within the last `WINDOW`
+    pub fn recent(&self) -> (u64, u64) {
+        let now = Instant::now();
+        self.slots
+            .iter()
+            .filter(|slot| now.duration_since(slot.start) < WINDOW)
+            .fold((0, 0), |(sent, received), slot| (sent + slot.sent, received + slot.received))
+    }
+}
+
+/// Per-peer accounting of clipboard, chat and direct message payloads, plus
+/// an optional cap on the traffic of the last hour for metered links. Bytes
+/// are payload sizes: protocol overhead and messages gossipsub relays on
+/// behalf of other peers are not counted.
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    peers: HashMap<PeerId, PeerTraffic>,
+    /// Most bytes sent and received per `WINDOW` before images stop going out
+    cap: Option<u64>,
+    /// Whether the cap is currently holding back images, for the notices
+    capped: bool,
+}
+
+impl Bandwidth {
+    pub fn record_sent(&mut self, peer: PeerId, bytes: usize) {
+        self.peers.entry(peer).or_default().record(Instant::now(), bytes as u64, 0);
+    }
+
+    pub fn record_received(&mut self, peer: PeerId, bytes: usize) {
+        self.peers.entry(peer).or_default().record(Instant::now(), 0, bytes as u64);
+    }
+
+    /// Traffic with `peer`, if any was recorded
+    pub fn peer(&self, peer: &PeerId) -> Option<&PeerTraffic> {
+        self.peers.get(peer)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = (&PeerId, &PeerTraffic)> {
+        self.peers.iter()
+    }
+
+    /// Bytes sent and received with all peers within the last `WINDOW`
+    pub fn recent_total(&self) -> u64 {
+        self.peers.values().map(|traffic| {
+            let (sent, received) = traffic.recent();
+            sent + received
+        }).sum()
+    }
+
+    pub fn set_cap(&mut self, cap: Option<u64>) {
+        self.cap = cap;
+    }
+
+    pub fn cap(&self) -> Option<u64> {
+        self.cap
+    }
+
+    /// Whether images (and other bulk content) may be sent right now. Text
+    /// always may. Logs a notice when the cap starts and stops applying.
+    pub fn allows_bulk(&mut self) -> bool {
+        let Some(cap) = self.cap else {
+            self.capped = false;
+            return true;
+        };
+        let used = self.recent_total();
+        let capped = used >= cap;
+        if capped && !self.capped {
+            warn!("Bandwidth cap reached: {used} of {cap} bytes used in the last hour. Images are not sent until usage drops; text still is");
+        } else if !capped && self.capped {
+            info!("Back under the bandwidth cap ({used} of {cap} bytes in the last hour), sending images again");
+        }
+        self.capped = capped;
+        !capped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traffic_is_counted_per_peer_and_leaves_the_window() {
+        let mut traffic = PeerTraffic::default();
+        let start = Instant::now();
+        traffic.record(start, 100, 0);
+        traffic.record(start + SLOT / 2, 0, 40);
+        assert_eq!((traffic.total(), traffic.recent()), ((100, 40), (100, 40)));
+
+        traffic.record(start + WINDOW, 10, 0);
+        assert_eq!(traffic.total(), (110, 40));
+        assert_eq!(traffic.recent(), (10, 0));
+    }
+
+    #[test]
+    fn the_cap_holds_back_bulk_content_once_reached() {
+        let mut bandwidth = Bandwidth::default();
+        let (desk, laptop) = (PeerId::random(), PeerId::random());
+        bandwidth.record_sent(desk, 600);
+        bandwidth.record_received(laptop, 300);
+        assert_eq!(bandwidth.peer(&desk).map(PeerTraffic::total), Some((600, 0)));
+        assert_eq!(bandwidth.peer(&laptop).map(PeerTraffic::total), Some((0, 300)));
+        assert!(bandwidth.allows_bulk(), "no cap");
+
+        bandwidth.set_cap(Some(1_000));
+        assert!(bandwidth.allows_bulk());
+        bandwidth.record_sent(laptop, 100);
+        assert_eq!(bandwidth.recent_total(), 1_000);
+        assert!(!bandwidth.allows_bulk());
+
+        bandwidth.set_cap(Some(2_000));
+        assert!(bandwidth.allows_bulk());
+    }
+}
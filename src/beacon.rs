@@ -0,0 +1,330 @@
+use crate::interfaces;
+use crate::peer_exchange::is_loopback;
+use anyhow::{bail, Context, Result};
+use if_addrs::IfAddr;
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+
+/// UDP port beacons are broadcast to and received on
+pub const DEFAULT_PORT: u16 = 45_871;
+/// Start of every beacon, so other broadcast traffic is dropped unread
+const MAGIC: &[u8] = b"CSBEACON";
+/// Bumped whenever the layout of a beacon changes
+const VERSION: u8 = 1;
+/// Larger datagrams are dropped. Fits in one packet on any LAN.
+const MAX_DATAGRAM: usize = 1400;
+/// Most listen addresses sent or accepted per beacon
+const MAX_ADDRS: usize = 8;
+/// Most groups sent or accepted per beacon
+const MAX_GROUPS: usize = 16;
+/// Beacons sent longer ago than this, or this far ahead of our clock, are
+/// ignored, so a replayed beacon soon stops causing dials
+const MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// What a beacon announces, signed by the sender's key
+#[derive(Debug, Serialize, Deserialize)]
+struct Payload {
+    peer_id: String,
+    /// [`group_hash`] of each room the sender is in
+    groups: Vec<String>,
+    addrs: Vec<String>,
+    /// Seconds since the Unix epoch
+    sent_at: u64,
+}
+
+/// A group member that announced itself with a beacon
+#[derive(Debug)]
+pub struct Discovered {
+    pub peer: PeerId,
+    /// Its listen addresses, those on the subnet the beacon came from first
+    pub addrs: Vec<Multiaddr>,
+}
+
+/// Beacons name groups by hash, so a room name doesn't show up in every
+/// packet capture on the network
+pub fn group_hash(room: &str) -> String {
+    let digest = Sha256::digest(format!("clipboard-sync beacon group {room}"));
+    digest[..16].iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Broadcast a beacon for `rooms` every `interval` on `port` and listen for
+/// the beacons of others, sending back the group members they announce.
+/// `addrs` follows our listen addresses. With `selectors` from `--interface`
+/// beacons only go out through those interfaces.
+pub async fn spawn(
+    keypair: Keypair,
+    rooms: &[String],
+    port: u16,
+    interval: Duration,
+    selectors: Vec<String>,
+    addrs: watch::Receiver<Vec<Multiaddr>>,
+) -> Result<mpsc::Receiver<Discovered>> {
+    let groups: Vec<String> = rooms.iter().map(|room| group_hash(room)).collect();
+    let (socket, listening) = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+        Ok(socket) => (socket, true),
+        Err(e) => {
+            warn!("Failed to bind beacon port {port} ({e}): sending beacons without receiving any");
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.context("Failed to open a beacon socket")?;
+            (socket, false)
+        }
+    };
+    socket.set_broadcast(true).context("Failed to enable broadcast on the beacon socket")?;
+    if listening {
+        info!("Sending and listening for discovery beacons on UDP port {port}");
+    }
+
+    let local_peer_id = keypair.public().to_peer_id();
+    let (tx, rx) = mpsc::channel(32);
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(interval);
+        let mut buf = vec![0; MAX_DATAGRAM + 1];
+        loop {
+            tokio::select! {
+                _ = timer.tick() => {
+                    let addrs = addrs.borrow().clone();
+                    match encode(&keypair, &groups, &addrs, now()) {
+                        Ok(Some(beacon)) => {
+                            for target in targets(&selectors, port) {
+                                if let Err(e) = socket.send_to(&beacon, target).await {
+                                    debug!("Failed to send a beacon to {target}: {e}");
+                                }
+                            }
+                        }
+                        Ok(None) => debug!("Not sending a beacon: no address to announce"),
+                        Err(e) => warn!("Failed to build a beacon: {e:#}"),
+                    }
+                }
+                received = socket.recv_from(&mut buf), if listening => {
+                    let (len, from) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            debug!("Failed to receive a beacon: {e}");
+                            continue;
+                        }
+                    };
+                    if len > MAX_DATAGRAM {
+                        debug!("Ignoring an oversized datagram from {from} on the beacon port");
+                        continue;
+                    }
+                    // Random broadcast traffic is expected on a shared port,
+                    // so failures are only worth a debug line
+                    match decode(&buf[..len], &groups, now()) {
+                        Ok(Some(mut discovered)) if discovered.peer != local_peer_id => {
+                            discovered.addrs.sort_by_key(|addr| interfaces::ip_of(addr) != Some(from.ip()));
+                            if tx.send(discovered).await.is_err() {
+                                return;
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => debug!("Ignoring datagram from {from} on the beacon port: {e:#}"),
+                    }
+                }
+            }
+        }
+    });
+    Ok(rx)
+}
+
+/// A signed beacon announcing `addrs`, or `None` if none of them is worth
+/// announcing. Addresses that don't fit in a datagram are left out. Layout:
+/// magic, version, then the protobuf public key, the JSON payload and the
+/// signature of the payload, each after its u16 LE length.
+fn encode(keypair: &Keypair, groups: &[String], addrs: &[Multiaddr], sent_at: u64) -> Result<Option<Vec<u8>>> {
+    let mut payload = Payload {
+        peer_id: keypair.public().to_peer_id().to_string(),
+        groups: groups.iter().take(MAX_GROUPS).cloned().collect(),
+        addrs: addrs.iter().filter(|addr| announceable(addr)).take(MAX_ADDRS).map(|addr| addr.to_string()).collect(),
+        sent_at,
+    };
+    while !payload.addrs.is_empty() {
+        let json = serde_json::to_vec(&payload)?;
+        let signature = keypair.sign(&json)?;
+        let mut beacon = [MAGIC, &[VERSION]].concat();
+        for field in [&keypair.public().encode_protobuf(), &json, &signature] {
+            beacon.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            beacon.extend_from_slice(field);
+        }
+        if beacon.len() <= MAX_DATAGRAM {
+            return Ok(Some(beacon));
+        }
+        payload.addrs.pop();
+    }
+    Ok(None)
+}
+
+/// The group member a beacon announces. `None` for a well-formed beacon of a
+/// group we are not in; an error for anything not signed by the PeerId it names.
+fn decode(datagram: &[u8], groups: &[String], now: u64) -> Result<Option<Discovered>> {
+    let Some(rest) = datagram.strip_prefix(MAGIC) else {
+        bail!("not a beacon");
+    };
+    let Some((&version, mut rest)) = rest.split_first() else {
+        bail!("truncated beacon");
+    };
+    if version != VERSION {
+        bail!("version {version} beacon");
+    }
+    let mut fields = [&[][..]; 3];
+    for field in &mut fields {
+        let Some((length, tail)) = rest.split_first_chunk::<2>() else {
+            bail!("truncated beacon");
+        };
+        let length = u16::from_le_bytes(*length) as usize;
+        if tail.len() < length {
+            bail!("truncated beacon");
+        }
+        (*field, rest) = tail.split_at(length);
+    }
+    let [key, payload, signature] = fields;
+
+    let key = PublicKey::try_decode_protobuf(key).context("invalid public key")?;
+    if !key.verify(payload, signature) {
+        bail!("bad signature");
+    }
+    let payload: Payload = serde_json::from_slice(payload).context("malformed payload")?;
+    let peer: PeerId = payload.peer_id.parse().context("invalid PeerId")?;
+    if peer != key.to_peer_id() {
+        bail!("signed by {} on behalf of {peer}", key.to_peer_id());
+    }
+    if payload.sent_at.abs_diff(now) > MAX_AGE.as_secs() {
+        bail!("beacon of {peer} is stale or from the future");
+    }
+    if !payload.groups.iter().take(MAX_GROUPS).any(|group| groups.contains(group)) {
+        return Ok(None);
+    }
+    let addrs: Vec<Multiaddr> = payload
+        .addrs
+        .iter()
+        .take(MAX_ADDRS)
+        .filter_map(|addr| addr.parse::<Multiaddr>().ok())
+        .filter(announceable)
+        .collect();
+    if addrs.is_empty() {
+        bail!("beacon of {peer} has no usable address");
+    }
+    Ok(Some(Discovered { peer, addrs }))
+}
+
+/// Whether another machine could reach us at `addr`
+fn announceable(addr: &Multiaddr) -> bool {
+    !is_loopback(addr)
+        && interfaces::ip_of(addr).is_some_and(|ip| !ip.is_unspecified())
+        && !addr.iter().any(|protocol| matches!(protocol, Protocol::P2pCircuit))
+}
+
+/// Broadcast address of each IPv4 interface beacons go out through, or the
+/// limited broadcast address if none has one
+fn targets(selectors: &[String], port: u16) -> Vec<SocketAddr> {
+    let mut targets: Vec<SocketAddr> = if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .iter()
+        .filter(|interface| !interface.is_loopback())
+        .filter(|interface| selectors.is_empty() || selectors.iter().any(|selector| interfaces::matches(interface, selector)))
+        .filter_map(|interface| match interface.addr {
+            IfAddr::V4(ref v4) => v4.broadcast,
+            IfAddr::V6(_) => None,
+        })
+        .map(|broadcast| SocketAddr::from((broadcast, port)))
+        .collect();
+    targets.sort_unstable();
+    targets.dedup();
+    if targets.is_empty() && selectors.is_empty() {
+        targets.push(SocketAddr::from((Ipv4Addr::BROADCAST, port)));
+    }
+    targets
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn addr(text: &str) -> Multiaddr {
+        text.parse().unwrap()
+    }
+
+    fn groups() -> Vec<String> {
+        vec![group_hash("home")]
+    }
+
+    #[test]
+    fn a_beacon_announces_the_reachable_addresses_of_its_signer() {
+        let key = Keypair::generate_ed25519();
+        let addrs = [addr("/ip4/127.0.0.1/tcp/4001"), addr("/ip4/0.0.0.0/tcp/4001"), addr("/ip4/192.168.1.5/tcp/4001")];
+        let beacon = encode(&key, &groups(), &addrs, NOW).unwrap().unwrap();
+        let discovered = decode(&beacon, &groups(), NOW + 10).unwrap().unwrap();
+        assert_eq!(discovered.peer, key.public().to_peer_id());
+        assert_eq!(discovered.addrs, [addr("/ip4/192.168.1.5/tcp/4001")]);
+    }
+
+    #[test]
+    fn nothing_is_announced_without_a_reachable_address() {
+        let key = Keypair::generate_ed25519();
+        assert!(encode(&key, &groups(), &[addr("/ip4/127.0.0.1/tcp/4001")], NOW).unwrap().is_none());
+    }
+
+    #[test]
+    fn beacons_fit_a_datagram() {
+        let key = Keypair::generate_ed25519();
+        let groups: Vec<String> = (0..MAX_GROUPS * 2).map(|index| group_hash(&index.to_string())).collect();
+        let addrs: Vec<Multiaddr> = (0..50)
+            .map(|index| addr(&format!("/ip6/fd00::{index:x}/tcp/4001/p2p/{}", key.public().to_peer_id())))
+            .collect();
+        let beacon = encode(&key, &groups, &addrs, NOW).unwrap().unwrap();
+        assert!(beacon.len() <= MAX_DATAGRAM);
+        let discovered = decode(&beacon, &groups[..1], NOW).unwrap().unwrap();
+        assert!(!discovered.addrs.is_empty() && discovered.addrs.len() <= MAX_ADDRS);
+    }
+
+    #[test]
+    fn beacons_of_other_groups_are_ignored() {
+        let key = Keypair::generate_ed25519();
+        let beacon = encode(&key, &[group_hash("work")], &[addr("/ip4/192.168.1.5/tcp/4001")], NOW).unwrap().unwrap();
+        assert!(decode(&beacon, &groups(), NOW).unwrap().is_none());
+    }
+
+    #[test]
+    fn tampered_stale_and_foreign_beacons_are_errors() {
+        let key = Keypair::generate_ed25519();
+        let beacon = encode(&key, &groups(), &[addr("/ip4/192.168.1.5/tcp/4001")], NOW).unwrap().unwrap();
+        let mut tampered = beacon.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(decode(&tampered, &groups(), NOW).is_err());
+        assert!(decode(&beacon[..beacon.len() - 1], &groups(), NOW).is_err());
+        assert!(decode(&beacon, &groups(), NOW + MAX_AGE.as_secs() + 1).is_err());
+        assert!(decode(b"CSBEACON\x02", &groups(), NOW).is_err());
+        assert!(decode(b"hello", &groups(), NOW).is_err());
+    }
+
+    #[test]
+    fn a_beacon_must_be_signed_by_the_peer_it_names() {
+        let (signer, named) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let payload = Payload {
+            peer_id: named.public().to_peer_id().to_string(),
+            groups: groups(),
+            addrs: vec!["/ip4/192.168.1.5/tcp/4001".to_string()],
+            sent_at: NOW,
+        };
+        let json = serde_json::to_vec(&payload).unwrap();
+        let mut beacon = [MAGIC, &[VERSION]].concat();
+        for field in [&signer.public().encode_protobuf(), &json, &signer.sign(&json).unwrap()] {
+            beacon.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            beacon.extend_from_slice(field);
+        }
+        assert!(decode(&beacon, &groups(), NOW).is_err());
+    }
+}
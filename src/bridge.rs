@@ -0,0 +1,235 @@
+use crate::clipboard::ClipboardContent;
+use crate::delivery;
+use crate::{CLIPBOARD_BULK_TOPIC, CLIPBOARD_TOPIC};
+use libp2p::gossipsub::{IdentTopic, TopicHash};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Room of nodes started without `--room`, using the plain topic names
+pub const DEFAULT_ROOM: &str = "default";
+
+/// Two rooms a bridge node forwards clipboard content between, given as
+/// `--bridge <roomA>=<roomB>`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Bridge {
+    pub a: String,
+    pub b: String,
+}
+
+impl FromStr for Bridge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (a, b) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <roomA>=<roomB>, got {s:?}"))?;
+        let (a, b) = (a.trim(), b.trim());
+        if a.is_empty() || b.is_empty() {
+            return Err("room names must not be empty".to_string());
+        }
+        if a == b {
+            return Err(format!("cannot bridge room {a:?} to itself"));
+        }
+        Ok(Self { a: a.to_string(), b: b.to_string() })
+    }
+}
+
+impl TryFrom<String> for Bridge {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Which way clipboard content flows between this node and a room
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Direction {
+    #[default]
+    Both,
+    /// Local copies go to the room, nothing from it is applied
+    Send,
+    /// Content from the room is applied, local copies never go to it
+    Receive,
+}
+
+impl Direction {
+    pub fn sends(self) -> bool {
+        self != Direction::Receive
+    }
+
+    pub fn receives(self) -> bool {
+        self != Direction::Send
+    }
+}
+
+/// A room joined with `--room <name>[:send|receive|both]`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Membership {
+    pub name: String,
+    pub direction: Direction,
+}
+
+impl FromStr for Membership {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, direction) = match s.split_once(':') {
+            Some((name, "send")) => (name, Direction::Send),
+            Some((name, "receive")) => (name, Direction::Receive),
+            Some((name, "both")) => (name, Direction::Both),
+            Some((_, other)) => return Err(format!("unknown direction {other:?}, expected send, receive or both")),
+            None => (s, Direction::Both),
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("room names must not be empty".to_string());
+        }
+        Ok(Self { name: name.to_string(), direction })
+    }
+}
+
+impl TryFrom<String> for Membership {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The clipboard topics of one room
+#[derive(Debug, Clone)]
+pub struct Room {
+    pub name: String,
+    pub clipboard: IdentTopic,
+    pub bulk: IdentTopic,
+    /// Where receivers confirm content from this room, see `delivery`
+    pub delivery: IdentTopic,
+    pub direction: Direction,
+}
+
+impl Room {
+    /// Rooms other than the default one get their own topics, so their
+    /// members never see each other's content
+    pub fn new(name: &str, direction: Direction) -> Self {
+        let (clipboard, bulk, delivery) = if name == DEFAULT_ROOM {
+            (CLIPBOARD_TOPIC.to_string(), CLIPBOARD_BULK_TOPIC.to_string(), delivery::TOPIC.to_string())
+        } else {
+            (
+                format!("{CLIPBOARD_TOPIC}/{name}"),
+                format!("{CLIPBOARD_BULK_TOPIC}/{name}"),
+                format!("{}/{name}", delivery::TOPIC),
+            )
+        };
+        Self {
+            name: name.to_string(),
+            clipboard: IdentTopic::new(clipboard),
+            bulk: IdentTopic::new(bulk),
+            delivery: IdentTopic::new(delivery),
+            direction,
+        }
+    }
+
+    pub fn has_topic(&self, topic: &TopicHash) -> bool {
+        *topic == self.clipboard.hash() || *topic == self.bulk.hash()
+    }
+
+    /// Whether content that came from the room `from`, or was copied locally
+    /// if `None`, may be sent to this room. Only a bridge passes content from
+    /// one room to another.
+    pub fn may_send(&self, from: Option<&str>, bridging: bool) -> bool {
+        self.direction.sends() && (bridging || from.is_none_or(|from| from == self.name))
+    }
+}
+
+/// Refuse rooms that share a topic, which would carry content from one room
+/// into the other
+pub fn check_rooms(rooms: &[Room]) -> Result<(), String> {
+    let mut owners: HashMap<TopicHash, &str> = HashMap::new();
+    for room in rooms {
+        for topic in [&room.clipboard, &room.bulk] {
+            if let Some(other) = owners.insert(topic.hash(), &room.name) {
+                return Err(if other == room.name {
+                    format!("room {other:?} is joined twice")
+                } else {
+                    format!("rooms {other:?} and {:?} would share the topic {topic}", room.name)
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The room whose topics include `topic`
+pub fn room_of<'a>(rooms: &'a [Room], topic: &TopicHash) -> Option<&'a Room> {
+    rooms.iter().find(|room| room.has_topic(topic))
+}
+
+/// Content that arrived on `topic` in one of the bridged `rooms`, tagged for
+/// re-publishing on the matching topic of the other room. `None` if the
+/// content already passed through the other room, which is what keeps two
+/// bridges between the same rooms from forwarding content back and forth.
+pub fn forward<'a>(
+    rooms: &'a [Room; 2],
+    topic: &TopicHash,
+    content: &ClipboardContent,
+) -> Option<(&'a Room, &'a IdentTopic, ClipboardContent)> {
+    let (from, to) = if rooms[0].has_topic(topic) {
+        (&rooms[0], &rooms[1])
+    } else if rooms[1].has_topic(topic) {
+        (&rooms[1], &rooms[0])
+    } else {
+        return None;
+    };
+    if content.via_rooms.contains(&to.name) {
+        return None;
+    }
+
+    let mut forwarded = content.clone();
+    if !forwarded.via_rooms.contains(&from.name) {
+        forwarded.via_rooms.push(from.name.clone());
+    }
+    let target = if *topic == from.bulk.hash() { &to.bulk } else { &to.clipboard };
+    Some((to, target, forwarded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memberships_parse_an_optional_direction() {
+        let parse = |s: &str| s.parse::<Membership>().map(|m| (m.name, m.direction));
+        assert_eq!(parse("home"), Ok(("home".to_string(), Direction::Both)));
+        assert_eq!(parse("work:send"), Ok(("work".to_string(), Direction::Send)));
+        assert_eq!(parse(" work :receive"), Ok(("work".to_string(), Direction::Receive)));
+        assert!(parse("work:sideways").is_err());
+        assert!(parse(":send").is_err());
+    }
+
+    #[test]
+    fn content_only_goes_back_to_the_room_it_came_from() {
+        let work = Room::new("work", Direction::Both);
+        assert!(work.may_send(None, false));
+        assert!(work.may_send(Some("work"), false));
+        assert!(!work.may_send(Some("home"), false));
+        assert!(work.may_send(Some("home"), true), "a bridge forwards between rooms");
+
+        let listening = Room::new("work", Direction::Receive);
+        assert!(!listening.may_send(None, false));
+        assert!(!listening.may_send(Some("work"), true));
+    }
+
+    #[test]
+    fn a_room_joined_twice_is_refused() {
+        let rooms = [Room::new("home", Direction::Send), Room::new("work", Direction::Receive)];
+        assert!(check_rooms(&rooms).is_ok());
+        assert_eq!(room_of(&rooms, &rooms[1].bulk.hash()).map(|room| room.name.as_str()), Some("work"));
+
+        let rooms = [Room::new("home", Direction::Send), Room::new("home", Direction::Receive)];
+        assert!(check_rooms(&rooms).unwrap_err().contains("joined twice"));
+    }
+}
@@ -0,0 +1,359 @@
+use crate::address_book;
+use crate::config::{Config, CONFIG_FILE};
+use crate::keystore;
+use crate::profile::{Profile, ADDRESS_BOOK_FILE, IDENTITY_FILE};
+use anyhow::{bail, Context, Result};
+use libp2p::PeerId;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Component, Path, PathBuf};
+
+/// Header of a bundle file, followed by the format version and the
+/// passphrase-sealed contents
+const MAGIC: &[u8] = b"CSBUNDLE";
+/// Bumped whenever the layout of the sealed contents changes
+const VERSION: u8 = 1;
+
+/// Describes the files that follow it in a bundle
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u8,
+    /// The identity's PeerId, checked against the key on import
+    peer_id: String,
+    /// Profile the bundle was exported from
+    profile: String,
+    files: Vec<FileEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FileEntry {
+    /// Relative to the profile directory, with `/` separators
+    path: String,
+    size: u64,
+}
+
+/// Arguments of the `export` subcommand
+#[derive(Debug, clap::Args)]
+pub struct ExportArgs {
+    /// File to write the bundle to
+    #[clap(long)]
+    pub out: PathBuf,
+    /// Also carry over spilled clipboard payloads and interrupted downloads
+    #[clap(long)]
+    pub include_history: bool,
+    /// Passphrase to encrypt the bundle with. Prompted for when not given
+    #[clap(long, env = "CLIPBOARD_SYNC_BUNDLE_PASSPHRASE", hide_env_values = true)]
+    pub passphrase: Option<String>,
+}
+
+/// Arguments of the `import` subcommand
+#[derive(Debug, clap::Args)]
+pub struct ImportArgs {
+    /// Bundle written by `export`
+    pub bundle: PathBuf,
+    /// Replace the profile's existing identity and settings
+    #[clap(long)]
+    pub force: bool,
+    /// Passphrase the bundle was encrypted with. Prompted for when not given
+    #[clap(long, env = "CLIPBOARD_SYNC_BUNDLE_PASSPHRASE", hide_env_values = true)]
+    pub passphrase: Option<String>,
+}
+
+/// Package the identity, config file and address book of `profile` into one
+/// encrypted file. `identity_passphrase` unlocks an encrypted identity key,
+/// which is needed to record its PeerId.
+pub fn export(profile: &Profile, args: &ExportArgs, identity_passphrase: Option<&str>) -> Result<()> {
+    let identity_path = profile.identity_path();
+    let identity = fs::read(&identity_path)
+        .with_context(|| format!("Profile '{}' has no identity to export", profile.name()))?;
+    let peer_id = keystore::decode(&identity_path, &identity, identity_passphrase)?.public().to_peer_id();
+
+    let mut files = vec![(IDENTITY_FILE.to_string(), identity)];
+    for name in [CONFIG_FILE, ADDRESS_BOOK_FILE] {
+        let path = profile.dir().join(name);
+        if path.exists() {
+            files.push((name.to_string(), fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?));
+        }
+    }
+    // Spilled payloads and interrupted downloads
+    if args.include_history {
+        collect(profile.dir(), &profile.spill_dir(), &mut files)?;
+    }
+
+    let manifest = Manifest {
+        version: VERSION,
+        peer_id: peer_id.to_string(),
+        profile: profile.name().to_string(),
+        files: files.iter().map(|(path, data)| FileEntry { path: path.clone(), size: data.len() as u64 }).collect(),
+    };
+    let manifest = serde_json::to_vec(&manifest)?;
+    let mut plaintext = (manifest.len() as u32).to_le_bytes().to_vec();
+    plaintext.extend_from_slice(&manifest);
+    for (_, data) in &files {
+        plaintext.extend_from_slice(data);
+    }
+
+    let passphrase = passphrase(args.passphrase.clone(), true)?;
+    let sealed = keystore::seal(&plaintext, &passphrase)?;
+    keystore::write_private(&args.out, &[MAGIC, &[VERSION], &sealed].concat())?;
+    println!("Exported {} ({peer_id}, {} files) to {}", profile.name(), files.len(), args.out.display());
+    Ok(())
+}
+
+/// Restore a bundle into `profile`. Every file is checked before any is
+/// written, and an existing identity is only replaced with `--force`.
+pub fn import(profile: &Profile, args: &ImportArgs, identity_passphrase: Option<&str>) -> Result<()> {
+    if profile.identity_path().exists() && !args.force {
+        bail!(
+            "Profile '{}' already has an identity; use --force to replace it, or import into a new --profile",
+            profile.name()
+        );
+    }
+    let data = fs::read(&args.bundle).with_context(|| format!("Failed to read {}", args.bundle.display()))?;
+    let Some(rest) = data.strip_prefix(MAGIC) else {
+        bail!("{} is not a clipboard-sync bundle", args.bundle.display());
+    };
+    let Some((&version, sealed)) = rest.split_first() else {
+        bail!("{} is truncated", args.bundle.display());
+    };
+    if version != VERSION {
+        bail!("{} is a version {version} bundle; this build reads version {VERSION}", args.bundle.display());
+    }
+    let passphrase = passphrase(args.passphrase.clone(), false)?;
+    let plaintext = keystore::unseal(sealed, &passphrase)
+        .with_context(|| format!("Cannot decrypt {}", args.bundle.display()))?;
+    let files = unpack(&plaintext, identity_passphrase)?;
+
+    for (path, data) in &files {
+        let target = profile.dir().join(path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        // Through a temporary file, so an interrupted import leaves whole files
+        let temp = target.with_extension("import");
+        keystore::write_private(&temp, data)?;
+        fs::rename(&temp, &target).with_context(|| format!("Failed to replace {}", target.display()))?;
+    }
+    info!("Imported {} files into {}", files.len(), profile.dir().display());
+    println!("Imported {} files into profile '{}'", files.len(), profile.name());
+    Ok(())
+}
+
+/// Split the sealed contents into files and check each one
+fn unpack(plaintext: &[u8], identity_passphrase: Option<&str>) -> Result<BTreeMap<PathBuf, Vec<u8>>> {
+    let Some((length, rest)) = plaintext.split_first_chunk::<4>() else {
+        bail!("Bundle is truncated");
+    };
+    let length = u32::from_le_bytes(*length) as usize;
+    if rest.len() < length {
+        bail!("Bundle is truncated");
+    }
+    let (manifest, mut contents) = rest.split_at(length);
+    let manifest: Manifest = serde_json::from_slice(manifest).context("Bundle manifest is corrupt")?;
+    if manifest.version != VERSION {
+        bail!("Bundle manifest is for version {}, not {VERSION}", manifest.version);
+    }
+    let peer_id: PeerId = manifest.peer_id.parse().context("Bundle records an invalid PeerId")?;
+
+    let mut files = BTreeMap::new();
+    for entry in &manifest.files {
+        let path = relative_path(&entry.path)?;
+        let size = usize::try_from(entry.size).ok().filter(|size| *size <= contents.len());
+        let Some(size) = size else {
+            bail!("Bundle is truncated in {}", entry.path);
+        };
+        let (data, rest) = contents.split_at(size);
+        contents = rest;
+        if files.insert(path, data.to_vec()).is_some() {
+            bail!("Bundle holds {} twice", entry.path);
+        }
+    }
+    if !contents.is_empty() {
+        bail!("Bundle holds {} bytes not listed in its manifest", contents.len());
+    }
+
+    let Some(identity) = files.get(Path::new(IDENTITY_FILE)) else {
+        bail!("Bundle holds no identity key");
+    };
+    let key_peer_id = keystore::decode(Path::new(IDENTITY_FILE), identity, identity_passphrase)?.public().to_peer_id();
+    if key_peer_id != peer_id {
+        bail!("Bundle identity key belongs to {key_peer_id}, not the recorded {peer_id}");
+    }
+    if let Some(config) = files.get(Path::new(CONFIG_FILE)) {
+        let text = std::str::from_utf8(config).context("Bundled config file is not UTF-8")?;
+        Config::parse(text).context("Bundled config file is invalid")?;
+    }
+    if let Some(book) = files.get(Path::new(ADDRESS_BOOK_FILE)) {
+        serde_json::from_slice::<BTreeMap<String, address_book::Entry>>(book)
+            .context("Bundled address book is corrupt")?;
+    }
+    info!("Bundle of profile '{}' for {peer_id} checks out", manifest.profile);
+    Ok(files)
+}
+
+/// Add the files under `dir` to `files`, named relative to `base`
+fn collect(base: &Path, dir: &Path, files: &mut Vec<(String, Vec<u8>)>) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect(base, &path, files)?;
+        } else if path.is_file() {
+            let name = path
+                .strip_prefix(base)?
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((name, fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?));
+        }
+    }
+    Ok(())
+}
+
+/// A path from a manifest, which must stay inside the profile directory
+fn relative_path(path: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(path);
+    if path.as_os_str().is_empty() || !path.components().all(|component| matches!(component, Component::Normal(_))) {
+        bail!("Bundle holds a file outside the profile: {}", path.display());
+    }
+    Ok(path)
+}
+
+/// The bundle passphrase given on the command line, or asked for on the
+/// terminal (twice when exporting)
+fn passphrase(given: Option<String>, confirm: bool) -> Result<String> {
+    let passphrase = match given {
+        Some(passphrase) => passphrase,
+        None => {
+            if !std::io::stdin().is_terminal() {
+                bail!("Pass --passphrase or set CLIPBOARD_SYNC_BUNDLE_PASSPHRASE");
+            }
+            let passphrase = rpassword::prompt_password("Bundle passphrase: ").context("Failed to read the passphrase")?;
+            if confirm && rpassword::prompt_password("Repeat it: ").context("Failed to read the passphrase")? != passphrase {
+                bail!("The passphrases don't match");
+            }
+            passphrase
+        }
+    };
+    if passphrase.is_empty() {
+        bail!("Bundle passphrase must not be empty");
+    }
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn export_args(out: PathBuf, include_history: bool) -> ExportArgs {
+        ExportArgs { out, include_history, passphrase: Some(PASSPHRASE.to_string()) }
+    }
+
+    fn import_args(bundle: PathBuf, force: bool) -> ImportArgs {
+        ImportArgs { bundle, force, passphrase: Some(PASSPHRASE.to_string()) }
+    }
+
+    /// A profile with an identity, a config file and a spilled payload
+    fn source(dir: &TempDir) -> (Profile, PeerId) {
+        let profile = Profile::at(&dir.path().join("source"));
+        fs::create_dir_all(profile.spill_dir()).unwrap();
+        let key = keystore::load_or_generate(&profile.identity_path(), None).unwrap();
+        fs::write(profile.dir().join(CONFIG_FILE), "clipboard = true\n").unwrap();
+        fs::write(profile.spill_dir().join("payload"), b"spilled").unwrap();
+        (profile, key.public().to_peer_id())
+    }
+
+    #[test]
+    fn an_exported_profile_imports_with_the_same_identity() {
+        let dir = TempDir::new();
+        let (source, peer_id) = source(&dir);
+        let bundle = dir.path().join("bundle");
+        export(&source, &export_args(bundle.clone(), true), None).unwrap();
+
+        let target = Profile::at(&dir.path().join("target"));
+        import(&target, &import_args(bundle, false), None).unwrap();
+        let key = keystore::load_or_generate(&target.identity_path(), None).unwrap();
+        assert_eq!(key.public().to_peer_id(), peer_id);
+        assert_eq!(fs::read_to_string(target.dir().join(CONFIG_FILE)).unwrap(), "clipboard = true\n");
+        assert_eq!(fs::read(target.spill_dir().join("payload")).unwrap(), b"spilled");
+    }
+
+    #[test]
+    fn history_is_left_out_unless_asked_for() {
+        let dir = TempDir::new();
+        let (source, _) = source(&dir);
+        let bundle = dir.path().join("bundle");
+        export(&source, &export_args(bundle.clone(), false), None).unwrap();
+        let target = Profile::at(&dir.path().join("target"));
+        import(&target, &import_args(bundle, false), None).unwrap();
+        assert!(!target.spill_dir().exists());
+    }
+
+    #[test]
+    fn an_existing_identity_is_only_replaced_with_force() {
+        let dir = TempDir::new();
+        let (source, peer_id) = source(&dir);
+        let bundle = dir.path().join("bundle");
+        export(&source, &export_args(bundle.clone(), false), None).unwrap();
+        let target = Profile::at(&dir.path().join("target"));
+        keystore::load_or_generate(&target.identity_path(), None).unwrap();
+        assert!(import(&target, &import_args(bundle.clone(), false), None).is_err());
+        import(&target, &import_args(bundle, true), None).unwrap();
+        let key = keystore::load_or_generate(&target.identity_path(), None).unwrap();
+        assert_eq!(key.public().to_peer_id(), peer_id);
+    }
+
+    #[test]
+    fn a_wrong_passphrase_or_a_damaged_bundle_imports_nothing() {
+        let dir = TempDir::new();
+        let (source, _) = source(&dir);
+        let bundle = dir.path().join("bundle");
+        export(&source, &export_args(bundle.clone(), false), None).unwrap();
+        let target = Profile::at(&dir.path().join("target"));
+        let wrong = ImportArgs { passphrase: Some("wrong".to_string()), ..import_args(bundle.clone(), false) };
+        assert!(import(&target, &wrong, None).is_err());
+
+        let mut data = fs::read(&bundle).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::write(&bundle, data).unwrap();
+        assert!(import(&target, &import_args(bundle, false), None).is_err());
+        assert!(!target.identity_path().exists());
+    }
+
+    #[test]
+    fn manifest_paths_stay_inside_the_profile() {
+        assert!(relative_path("spill/payload").is_ok());
+        for path in ["", "../identity.key", "/etc/passwd", "spill/../../x"] {
+            assert!(relative_path(path).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn unlisted_bytes_are_refused() {
+        let key = libp2p::identity::Keypair::generate_ed25519();
+        let identity = key.to_protobuf_encoding().unwrap();
+        let manifest = Manifest {
+            version: VERSION,
+            peer_id: key.public().to_peer_id().to_string(),
+            profile: "test".to_string(),
+            files: vec![FileEntry { path: IDENTITY_FILE.to_string(), size: identity.len() as u64 }],
+        };
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+        let mut plaintext = (manifest.len() as u32).to_le_bytes().to_vec();
+        plaintext.extend_from_slice(&manifest);
+        plaintext.extend_from_slice(&identity);
+        assert!(unpack(&plaintext, None).is_ok());
+        plaintext.push(0);
+        assert!(unpack(&plaintext, None).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use crate::clipboard::ContentType;
+use std::fmt;
+
+/// Application name at the start of the identify agent version
+const AGENT_NAME: &str = "libp2p-clipboard-sync";
+/// Version of the clipboard message encoding. Bump it whenever peers running
+/// different versions can no longer decode or route each other's messages.
+///
+/// 1: timestamps in milliseconds since the Unix epoch instead of seconds
+/// 2: images and large payloads moved to the bulk clipboard topic
+/// 3: payloads may be sealed with `--field-encryption`, which older peers
+///    would apply as if they were the content
+pub const WIRE_FORMAT: u32 = 3;
+/// Version of the direct request protocol. Peers only get requests their
+/// version has, since older ones fail to decode the rest.
+///
+/// 1: `Clipboard`, `QueryLatest` and `TooLarge` requests
+pub const DIRECT_PROTOCOL: u32 = 1;
+/// Longest device name advertised or accepted, in characters
+const MAX_DEVICE_NAME: usize = 64;
+
+/// Clipboard features active on a node, advertised to peers through the
+/// identify agent version as `libp2p-clipboard-sync/<version> (key=value; ...)`
+/// so mismatched configurations show up as warnings instead of silence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub wire_format: u32,
+    /// Direct request protocol version, see [`DIRECT_PROTOCOL`]
+    pub direct: u32,
+    pub clipboard: bool,
+    pub compression: bool,
+    /// Records clipboard activity but never publishes
+    pub observer: bool,
+    /// Can rebuild images sent as diffs
+    pub image_diffs: bool,
+    /// Reads chat lines sent as `ChatMessage` envelopes, see [`crate::chat`]
+    pub chat: bool,
+    /// Human readable name of the machine, shown instead of the bare PeerId
+    pub device_name: Option<String>,
+    /// Content types the node applies; it ignores anything else it receives
+    pub formats: Vec<ContentType>,
+    /// Largest image the node accepts, in bytes, with `--max-image-bytes`
+    pub max_image_bytes: Option<u64>,
+}
+
+impl Capabilities {
+    /// Agent version string carrying these capabilities
+    pub fn agent_version(&self) -> String {
+        format!("{AGENT_NAME}/{} ({self})", env!("CARGO_PKG_VERSION"))
+    }
+
+    /// Parse the capabilities out of a peer's agent version. Returns `None`
+    /// for agents that are not this application. Unknown keys are ignored so
+    /// newer peers can advertise more.
+    pub fn from_agent_version(agent_version: &str) -> Option<Self> {
+        let rest = agent_version.strip_prefix(AGENT_NAME)?.strip_prefix('/')?;
+        let list = rest.split_once(" (")?.1.strip_suffix(')')?;
+
+        // Peers predating a key behave as if it were off
+        let mut capabilities = Self {
+            wire_format: 0,
+            direct: 0,
+            clipboard: false,
+            compression: false,
+            observer: false,
+            image_diffs: false,
+            chat: false,
+            device_name: None,
+            // Files came after the key did, so older peers only take these
+            formats: vec![ContentType::Text, ContentType::Image],
+            max_image_bytes: None,
+        };
+        for entry in list.split(';') {
+            let Some((key, value)) = entry.trim().split_once('=') else {
+                continue;
+            };
+            let enabled = value == "on";
+            match key {
+                "wire" => capabilities.wire_format = value.parse().ok()?,
+                "direct" => capabilities.direct = value.parse().unwrap_or(0),
+                "clipboard" => capabilities.clipboard = enabled,
+                "compression" => capabilities.compression = enabled,
+                "observer" => capabilities.observer = enabled,
+                "diffs" => capabilities.image_diffs = enabled,
+                "chat" => capabilities.chat = enabled,
+                "name" => capabilities.device_name = sanitize_device_name(value),
+                // Formats newer than us are skipped
+                "formats" => capabilities.formats = value.split(',').filter_map(parse_format).collect(),
+                "maximage" => capabilities.max_image_bytes = value.parse().ok(),
+                _ => {}
+            }
+        }
+        Some(capabilities)
+    }
+
+    /// Describe every way `peer` cannot sync clipboard content with us.
+    /// Differences that only cost efficiency (compression) are not reported.
+    pub fn incompatibilities(&self, peer: &Self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.clipboard {
+            return problems;
+        }
+
+        if !peer.clipboard {
+            problems.push("clipboard sync is disabled on the peer".to_string());
+        } else if peer.wire_format != self.wire_format {
+            problems.push(format!(
+                "peer uses wire format {} but we use {}, so neither side can decode the other's content",
+                peer.wire_format, self.wire_format
+            ));
+        }
+        problems
+    }
+
+    /// Whether the node applies content of this type
+    pub fn accepts(&self, content_type: ContentType) -> bool {
+        self.formats.contains(&content_type)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |enabled: bool| if enabled { "on" } else { "off" };
+        write!(
+            f,
+            "wire={}; direct={}; clipboard={}; compression={}; observer={}; diffs={}; chat={}",
+            self.wire_format,
+            self.direct,
+            flag(self.clipboard),
+            flag(self.compression),
+            flag(self.observer),
+            flag(self.image_diffs),
+            flag(self.chat)
+        )?;
+        let formats: Vec<&str> = self.formats.iter().map(|format| format_name(*format)).collect();
+        write!(f, "; formats={}", formats.join(","))?;
+        if let Some(max) = self.max_image_bytes {
+            write!(f, "; maximage={max}")?;
+        }
+        if let Some(ref name) = self.device_name {
+            write!(f, "; name={name}")?;
+        }
+        Ok(())
+    }
+}
+
+fn format_name(format: ContentType) -> &'static str {
+    match format {
+        ContentType::Text => "text",
+        ContentType::Image => "image",
+        ContentType::Files => "files",
+    }
+}
+
+fn parse_format(name: &str) -> Option<ContentType> {
+    match name {
+        "text" => Some(ContentType::Text),
+        "image" => Some(ContentType::Image),
+        "files" => Some(ContentType::Files),
+        _ => None,
+    }
+}
+
+/// Make `name` safe to embed in the agent version: no separators of the
+/// capability list, no control characters, and a bounded length. Returns
+/// `None` if nothing is left.
+pub fn sanitize_device_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, ';' | '(' | ')' | '='))
+        .take(MAX_DEVICE_NAME)
+        .collect();
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(direct: u32) -> Capabilities {
+        Capabilities {
+            wire_format: WIRE_FORMAT,
+            direct,
+            clipboard: true,
+            compression: false,
+            observer: false,
+            image_diffs: true,
+            chat: true,
+            device_name: Some("desk".to_string()),
+            formats: vec![ContentType::Text],
+            max_image_bytes: None,
+        }
+    }
+
+    #[test]
+    fn capabilities_survive_the_agent_version() {
+        let ours = capabilities(DIRECT_PROTOCOL);
+        assert_eq!(Capabilities::from_agent_version(&ours.agent_version()), Some(ours));
+    }
+
+    #[test]
+    fn peers_predating_the_direct_key_get_only_the_original_requests() {
+        let old = "libp2p-clipboard-sync/0.1.0 (wire=3; clipboard=on; compression=off; observer=off; diffs=on)";
+        assert_eq!(Capabilities::from_agent_version(old).map(|theirs| theirs.direct), Some(0));
+    }
+
+    #[test]
+    fn peers_predating_the_chat_key_read_only_plain_lines() {
+        let old = "libp2p-clipboard-sync/0.1.0 (wire=3; direct=1; clipboard=on; compression=off; observer=off; diffs=on)";
+        assert_eq!(Capabilities::from_agent_version(old).map(|theirs| theirs.chat), Some(false));
+    }
+
+    #[test]
+    fn a_peer_on_another_wire_format_is_reported_as_incompatible() {
+        let ours = capabilities(DIRECT_PROTOCOL);
+        let agent_version = Capabilities { wire_format: WIRE_FORMAT + 1, ..ours.clone() }.agent_version();
+        let theirs = Capabilities::from_agent_version(&agent_version).unwrap();
+        let problems = ours.incompatibilities(&theirs);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains(&format!("wire format {}", WIRE_FORMAT + 1)), "{problems:?}");
+    }
+
+    #[test]
+    fn only_differences_that_stop_syncing_are_reported() {
+        let ours = capabilities(DIRECT_PROTOCOL);
+        let compressing = Capabilities { compression: true, image_diffs: false, ..ours.clone() };
+        assert!(ours.incompatibilities(&compressing).is_empty());
+
+        let disabled = Capabilities { clipboard: false, ..ours.clone() };
+        assert_eq!(ours.incompatibilities(&disabled), ["clipboard sync is disabled on the peer"]);
+        // Without clipboard sync ourselves there is nothing to be incompatible with
+        assert!(disabled.incompatibilities(&ours).is_empty());
+    }
+
+    #[test]
+    fn a_hostile_device_name_is_cleaned_without_upsetting_the_other_keys() {
+        let long = "x".repeat(100);
+        let agent_version = format!(
+            "libp2p-clipboard-sync/0.1.0 (wire=3; direct=1; name=de)s=k(\u{7}\n\u{1b}[2J{long}; clipboard=on; chat=on; formats=text,image)"
+        );
+        let theirs = Capabilities::from_agent_version(&agent_version).unwrap();
+        assert_eq!(theirs.wire_format, 3);
+        assert_eq!(theirs.direct, 1);
+        assert!(theirs.clipboard && theirs.chat);
+        assert_eq!(theirs.formats, vec![ContentType::Text, ContentType::Image]);
+        let name = theirs.device_name.unwrap();
+        assert!(name.starts_with("desk[2Jxxx"), "{name}");
+        assert_eq!(name.chars().count(), MAX_DEVICE_NAME);
+        assert!(!name.chars().any(|c| c.is_control() || matches!(c, ';' | '(' | ')' | '=')), "{name}");
+
+        // A separator ends the name, and what follows is just another key
+        let theirs = Capabilities::from_agent_version("libp2p-clipboard-sync/0.1.0 (wire=3; name=desk; observer=on)").unwrap();
+        assert_eq!(theirs.device_name.as_deref(), Some("desk"));
+        assert!(theirs.observer);
+        // Nothing left after cleaning is no name at all
+        assert_eq!(sanitize_device_name(";=()\u{7}  "), None);
+    }
+}
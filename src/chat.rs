@@ -0,0 +1,148 @@
+use crate::clipboard::now_millis;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// How often a node announces its presence on the chat topic
+pub const PRESENCE_INTERVAL: Duration = Duration::from_secs(30);
+/// Receipts arriving within this long after a message are summed up together
+pub const RECEIPT_WINDOW: Duration = Duration::from_secs(2);
+
+/// Messages on the chat topic. Lines from peers predating the envelope are
+/// plain text and shown as is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChatMessage {
+    /// A line typed by the user
+    Text {
+        msg_id: u64,
+        text: String,
+        /// Whether the sender wants a `ChatReceipt` direct request back
+        #[serde(default)]
+        receipts: bool,
+    },
+    /// Liveness of the sender, announced when a peer joins, on changes and
+    /// every `PRESENCE_INTERVAL`
+    Presence {
+        state: PresenceState,
+        /// Sender's clock, so repeated announcements aren't dropped as duplicates
+        at: u64,
+    },
+}
+
+impl ChatMessage {
+    pub fn presence(state: PresenceState) -> Self {
+        Self::Presence { state, at: now_millis() }
+    }
+}
+
+/// What a peer last said about itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    /// Running and syncing
+    Active,
+    /// Running with sync paused
+    Paused,
+    /// Shutting down gracefully
+    Offline,
+}
+
+/// Presence of the peers we have heard from
+#[derive(Debug, Default)]
+pub struct Presence {
+    peers: HashMap<PeerId, (PresenceState, Instant)>,
+}
+
+impl Presence {
+    /// Record an announcement, returning whether the peer's state changed
+    pub fn update(&mut self, peer: PeerId, state: PresenceState) -> bool {
+        let previous = self.peers.insert(peer, (state, Instant::now()));
+        previous.is_none_or(|(old, _)| old != state)
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// Presence of a connected peer for `/peers`. A peer that stopped
+    /// announcing is connected but its app is not responding.
+    pub fn describe(&self, peer: &PeerId) -> &'static str {
+        match self.peers.get(peer) {
+            None => "no presence",
+            Some((_, heard)) if heard.elapsed() > PRESENCE_INTERVAL * 3 => "silent",
+            Some((PresenceState::Active, _)) => "active",
+            Some((PresenceState::Paused, _)) => "paused",
+            Some((PresenceState::Offline, _)) => "going offline",
+        }
+    }
+}
+
+/// A chat line waiting for receipts
+#[derive(Debug)]
+struct Pending {
+    text: String,
+    sent_at: Instant,
+    recipients: usize,
+    delivered: HashSet<PeerId>,
+}
+
+/// Chat lines we asked receipts for, reported once `RECEIPT_WINDOW` has passed
+#[derive(Debug, Default)]
+pub struct Receipts {
+    next_id: u64,
+    pending: HashMap<u64, Pending>,
+}
+
+impl Receipts {
+    /// Id for a new chat line, unique among our own messages
+    pub fn next_msg_id(&mut self) -> u64 {
+        self.next_id = now_millis().max(self.next_id + 1);
+        self.next_id
+    }
+
+    /// Start collecting receipts for a line sent to `recipients` peers
+    pub fn track(&mut self, msg_id: u64, text: &str, recipients: usize) {
+        self.pending.insert(
+            msg_id,
+            Pending {
+                text: text.to_string(),
+                sent_at: Instant::now(),
+                recipients,
+                delivered: HashSet::new(),
+            },
+        );
+    }
+
+    /// Count a receipt. Returns false for lines we never sent or already reported.
+    pub fn record(&mut self, msg_id: u64, from: PeerId) -> bool {
+        match self.pending.get_mut(&msg_id) {
+            Some(pending) => {
+                pending.delivered.insert(from);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Lines whose receipt window has passed, as the text with the number of
+    /// peers it reached and was sent to
+    pub fn take_finished(&mut self) -> Vec<(String, usize, usize)> {
+        let finished: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.sent_at.elapsed() >= RECEIPT_WINDOW)
+            .map(|(&msg_id, _)| msg_id)
+            .collect();
+        finished
+            .into_iter()
+            .filter_map(|msg_id| self.pending.remove(&msg_id))
+            .map(|pending| (pending.text, pending.delivered.len(), pending.recipients))
+            .collect()
+    }
+}
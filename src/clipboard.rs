@@ -1,29 +1,131 @@
-use anyhow::{Result, Context};
-use arboard::Clipboard;
+use anyhow::{bail, Result, Context};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::Mutex;
-use tokio::time::{Duration, interval};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, Mutex};
+use tokio::time::Duration;
+
+use crate::delivery::{self, Ack, Delivered};
+use crate::files::FileOffer;
+use crate::focus::FocusProvider;
+use crate::poll::{AdaptiveInterval, Schedule};
+use crate::spill::SpillFile;
+use crate::system_clipboard::{Connector, SystemClipboard};
+use crate::timing::OpTimings;
 
 /// Clipboard content structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClipboardContent {
     pub content_type: ContentType,
     pub data: Vec<u8>,
+    /// Milliseconds since the Unix epoch when the content was copied
     pub timestamp: u64,
     // Add width and height for image content
     pub width: Option<u32>,
     pub height: Option<u32>,
+    /// Bytes per pixel of an image: 4 for RGBA, 3 for packed RGB. Left out
+    /// by older senders, whose layout is inferred from the buffer size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u8>,
     pub from_network: bool,
+    /// Payload moved to disk for large content; `data` is empty while set
+    #[serde(skip)]
+    pub spilled: Option<SpillHandle>,
+    /// Set when `data` is a diff against an earlier image rather than the
+    /// image itself, see `image_diff`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diff_base: Option<String>,
+    /// Rooms a bridge node forwarded this content out of, see `bridge`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub via_rooms: Vec<String>,
+    /// Files offered for download, see `files`. `data` is empty then.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileOffer>,
+    /// Title of the window the content was copied from, with `--source-label`
+    /// where the platform exposes it. Only shown, never used to apply it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_label: Option<String>,
+    /// Set when the sender wants receivers to confirm on the room's delivery
+    /// topic once the content is on their clipboard, see `delivery`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirm_nonce: Option<String>,
+    /// `data` is encrypted with the `--field-encryption` key while the rest
+    /// stays readable, see `crypto`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sealed: bool,
+    /// Machines this content was received on and copied again, oldest first
+    /// and at most [`PROVENANCE_HOPS`]. Only shown and logged, and left out
+    /// of every hash, so the same content deduplicates whatever its path.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub provenance: Vec<Hop>,
+    /// Room the content was received in, where its delivery is confirmed
+    #[serde(skip)]
+    pub received_in: Option<String>,
 }
 
-/// Type of clipboard content
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Hops kept in [`ClipboardContent::provenance`], dropping the oldest first
+pub const PROVENANCE_HOPS: usize = 5;
+
+/// A machine content passed through on its way here
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hop {
+    /// Device name, or the PeerId of a machine that advertises none
+    pub device: String,
+    /// When the content was copied there, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+/// Type of clipboard content. The lowercase aliases are for config files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
 pub enum ContentType {
+    #[serde(alias = "text")]
     Text,
+    #[serde(alias = "image")]
     Image,
+    #[serde(alias = "files")]
+    Files,
+}
+
+/// What happens to line breaks in received text before it is put on the
+/// clipboard, for machines that paste into single-line fields
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum NewlineMode {
+    /// Leave the text as it is
+    #[default]
+    None,
+    /// Join the lines with single spaces, dropping leading and trailing line breaks
+    Space,
+    /// Remove line breaks, joining the lines directly
+    Strip,
+}
+
+/// `text` with its line breaks (`\n`, `\r\n` or `\r`) handled as `mode` says
+pub fn replace_newlines(text: &str, mode: NewlineMode) -> Cow<'_, str> {
+    let is_break = |c: char| c == '\n' || c == '\r';
+    match mode {
+        NewlineMode::None => Cow::Borrowed(text),
+        _ if !text.contains(is_break) => Cow::Borrowed(text),
+        NewlineMode::Space => {
+            Cow::Owned(text.split(is_break).filter(|line| !line.is_empty()).collect::<Vec<_>>().join(" "))
+        }
+        NewlineMode::Strip => Cow::Owned(text.replace(is_break, "")),
+    }
+}
+
+/// Current wall-clock time in milliseconds since the Unix epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
 }
 
 impl ClipboardContent {
@@ -32,132 +134,973 @@ impl ClipboardContent {
         Self {
             content_type: ContentType::Text,
             data: text.into_bytes(),
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
+            timestamp: now_millis(),
             from_network: false,
             width: None,
             height: None,
+            channels: None,
+            spilled: None,
+            diff_base: None,
+            via_rooms: Vec::new(),
+            files: Vec::new(),
+            source_label: None,
+            confirm_nonce: None,
+            sealed: false,
+            provenance: Vec::new(),
+            received_in: None,
         }
     }
     
-    /// Create a new image clipboard content
+    /// Create a new image clipboard content from tightly packed RGBA
     pub fn new_image(data: Vec<u8>, width: u32, height: u32) -> Self {
         Self {
             content_type: ContentType::Image,
             data,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
+            timestamp: now_millis(),
             width: Some(width),
             height: Some(height),
+            channels: Some(4),
             from_network: false,
+            spilled: None,
+            diff_base: None,
+            via_rooms: Vec::new(),
+            files: Vec::new(),
+            source_label: None,
+            confirm_nonce: None,
+            sealed: false,
+            provenance: Vec::new(),
+            received_in: None,
         }
     }
     
+    /// Create clipboard content offering files
+    pub fn new_files(files: Vec<FileOffer>) -> Self {
+        Self {
+            content_type: ContentType::Files,
+            data: Vec::new(),
+            timestamp: now_millis(),
+            width: None,
+            height: None,
+            channels: None,
+            from_network: false,
+            spilled: None,
+            diff_base: None,
+            via_rooms: Vec::new(),
+            files,
+            source_label: None,
+            confirm_nonce: None,
+            sealed: false,
+            provenance: Vec::new(),
+            received_in: None,
+        }
+    }
+
     /// Get text content if this is a text clipboard item
     pub fn text(&self) -> Option<String> {
-        if let ContentType::Text = self.content_type {
-            String::from_utf8(self.data.clone()).ok()
+        if let ContentType::Text = self.content_type
+            && !self.sealed
+        {
+            String::from_utf8(self.bytes().ok()?.into_owned()).ok()
         } else {
             None
         }
     }
+
+    /// Short single-line description for front ends
+    pub fn preview(&self) -> String {
+        preview(self, PREVIEW_CHARS)
+    }
+
+    /// Record that this content passed through `device`, unless it is the
+    /// last hop already. The oldest hops go past [`PROVENANCE_HOPS`].
+    pub fn add_hop(&mut self, device: String, timestamp: u64) {
+        if self.provenance.last().is_none_or(|hop| hop.device != device) {
+            self.provenance.push(Hop { device, timestamp });
+        }
+        let excess = self.provenance.len().saturating_sub(PROVENANCE_HOPS);
+        self.provenance.drain(..excess);
+    }
+
+    /// Where content copied again on the way here came from, e.g. "via
+    /// work-laptop, originally from home-desktop". None if it wasn't.
+    pub fn provenance_note(&self) -> Option<String> {
+        // Sent by peers, so cleaned up like device names and bounded
+        let recent = &self.provenance[self.provenance.len().saturating_sub(PROVENANCE_HOPS)..];
+        let devices: Vec<String> = recent
+            .iter()
+            .map(|hop| crate::capabilities::sanitize_device_name(&hop.device).unwrap_or_else(|| "?".to_string()))
+            .collect();
+        let (origin, via) = devices.split_first()?;
+        (!via.is_empty()).then(|| format!("via {}, originally from {origin}", via.join(", ")))
+    }
+
+    /// Payload size in bytes, whether held in memory or spilled to disk
+    pub fn size(&self) -> usize {
+        match self.spilled {
+            Some(ref handle) => handle.size as usize,
+            None => self.data.len(),
+        }
+    }
+
+    /// Payload bytes, read back from disk if the content was spilled
+    pub fn bytes(&self) -> Result<Cow<'_, [u8]>> {
+        match self.spilled {
+            Some(ref handle) => Ok(Cow::Owned(handle.read()?)),
+            None => Ok(Cow::Borrowed(&self.data)),
+        }
+    }
+
+    /// Keep a spilled payload from eviction for as long as this content, or a
+    /// clone of it, waits to be applied
+    pub fn pin_spilled(&mut self) {
+        if let Some(ref mut handle) = self.spilled {
+            handle.pin();
+        }
+    }
+
+    /// Bring a spilled payload back into memory. Fails if the payload cache
+    /// has already expired it.
+    pub fn unspill(mut self) -> Result<Self> {
+        if let Some(handle) = self.spilled.take() {
+            self.data = handle.read()?;
+        }
+        Ok(self)
+    }
     
-    /// Get image data if this is an image clipboard item
-    pub fn image(&self) -> Option<&[u8]> {
+    /// Get image data if this is an image clipboard item, read back from
+    /// disk if it was spilled. `None` too if the spilled payload is gone.
+    pub fn image(&self) -> Option<Cow<'_, [u8]>> {
         if let ContentType::Image = self.content_type {
-            Some(&self.data)
+            self.bytes().inspect_err(|e| warn!("Failed to load the image payload: {e:#}")).ok()
         } else {
             None
         }
     }
+
+    /// Get the offered files if this is a file clipboard item
+    pub fn files(&self) -> Option<&[FileOffer]> {
+        if let ContentType::Files = self.content_type {
+            Some(&self.files)
+        } else {
+            None
+        }
+    }
+
+    /// Hex SHA-256 of the payload. For files, of the offered names and hashes.
+    pub fn sha256(&self) -> Result<String> {
+        let digest = match self.files() {
+            Some(offers) => {
+                let mut hasher = Sha256::new();
+                for offer in offers {
+                    hasher.update(offer.name.as_bytes());
+                    hasher.update([0]);
+                    hasher.update(offer.sha256.as_bytes());
+                    hasher.update([0]);
+                }
+                hasher.finalize()
+            }
+            // Spill files know their hash, so it takes no read from disk
+            None => match self.spilled {
+                Some(ref handle) => handle.sha256.into(),
+                None => Sha256::digest(&self.data),
+            },
+        };
+        Ok(digest.iter().map(|byte| format!("{byte:02x}")).collect())
+    }
+}
+
+/// Tunables for how clipboard content is applied
+#[derive(Debug, Clone)]
+pub struct ClipboardOptions {
+    /// Factor applied to received image dimensions before setting the clipboard
+    pub image_scale: f32,
+    /// Hold received content in a queue until it is accepted instead of
+    /// applying it right away
+    pub queue_incoming: bool,
+    /// Content types applied right away even with `queue_incoming`
+    pub auto_accept: HashSet<ContentType>,
+    /// Treat whatever is on the clipboard when monitoring starts as already
+    /// seen, so only later changes are published
+    pub ignore_initial: bool,
+    /// Content identical to what was last published or applied is an echo
+    /// within this long of it, and a deliberate copy worth resending after
+    pub resend_window: Duration,
+    /// Log clipboard text in full rather than a preview
+    pub log_content: bool,
+    /// Label copied content with the window it was copied from
+    pub source_label: bool,
+    /// Line breaks in received text
+    pub newlines: NewlineMode,
+    /// Ask receivers to confirm copied text and images once applied
+    pub confirm_delivery: bool,
+    /// How often the clipboard is read for local copies
+    pub poll: Schedule,
+    /// Name this machine is recorded under when it copies received content
+    /// again, see [`ClipboardContent::provenance`]
+    pub device_name: String,
+}
+
+impl Default for ClipboardOptions {
+    fn default() -> Self {
+        Self {
+            image_scale: 1.0,
+            queue_incoming: false,
+            auto_accept: HashSet::new(),
+            ignore_initial: false,
+            resend_window: Duration::from_secs(2),
+            log_content: false,
+            source_label: false,
+            newlines: NewlineMode::None,
+            confirm_delivery: false,
+            poll: Schedule::default(),
+            device_name: String::new(),
+        }
+    }
+}
+
+/// Hit, miss and eviction counts of the payload cache, shared with the
+/// handles it gives out and with `/stats`
+#[derive(Debug, Default)]
+pub struct CacheCounters {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub evictions: AtomicU64,
+    pub entries: AtomicU64,
+    pub bytes: AtomicU64,
+}
+
+/// Reference from clipboard content to a payload in the [`PayloadCache`]. The
+/// cache owns the file, so an expired payload is reclaimed even while content
+/// referring to it is still retained, unless the handle is pinned.
+#[derive(Debug, Clone)]
+pub struct SpillHandle {
+    file: Weak<SpillFile>,
+    /// Keeps the payload from eviction while content waits to be applied
+    pinned: Option<Arc<SpillFile>>,
+    size: u64,
+    sha256: [u8; 32],
+    counters: Arc<CacheCounters>,
+}
+
+impl SpillHandle {
+    /// Keep the payload until this handle and its clones are dropped
+    fn pin(&mut self) {
+        if self.pinned.is_none() {
+            self.pinned = self.file.upgrade();
+        }
+    }
+
+    /// Read the payload back. The file can't be evicted while it is read.
+    fn read(&self) -> Result<Vec<u8>> {
+        let Some(file) = self.file.upgrade() else {
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            bail!("Spilled payload of {} bytes has expired from the cache", self.size);
+        };
+        self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        file.read()
+    }
+}
+
+/// Limits of the payload cache, `None` for unlimited
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheLimits {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+/// Owner of every payload spilled to disk. Expired and excess payloads are
+/// evicted by [`gc`](Self::gc), which the main loop runs periodically, and
+/// everything is removed on [`shutdown`](Self::shutdown).
+#[derive(Debug)]
+pub struct PayloadCache {
+    dir: PathBuf,
+    /// Whether `dir` belongs to this process alone and goes away on shutdown
+    temporary: bool,
+    limits: CacheLimits,
+    /// Oldest first, which is also the eviction order
+    entries: VecDeque<CacheEntry>,
+    counters: Arc<CacheCounters>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    file: Arc<SpillFile>,
+    added: Instant,
+}
+
+impl CacheEntry {
+    /// Someone is reading the payload right now, or content waiting to be
+    /// applied pinned it
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.file) > 1
+    }
+}
+
+impl PayloadCache {
+    /// Cache spilling into `dir`, first removing spill files left there by a
+    /// run that did not shut down cleanly
+    pub fn open(dir: PathBuf, temporary: bool, limits: CacheLimits) -> Self {
+        match crate::spill::scavenge(&dir) {
+            Ok(0) => {}
+            Ok(n) => info!("Removed {n} stale spill files from {}", dir.display()),
+            Err(e) => warn!("Failed to clean spill directory: {e:?}"),
+        }
+        Self { dir, temporary, limits, entries: VecDeque::new(), counters: Arc::default() }
+    }
+
+    pub fn counters(&self) -> Arc<CacheCounters> {
+        self.counters.clone()
+    }
+
+    /// Change the limits, e.g. after a configuration reload. They are
+    /// enforced on the next [`gc`](Self::gc).
+    pub fn set_limits(&mut self, limits: CacheLimits) {
+        self.limits = limits;
+    }
+
+    /// Where payloads are spilled, for spilling off the event loop
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Move the payload of `content` into a spill file, freeing the in-memory copy
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn spill(&mut self, content: &mut ClipboardContent) -> Result<()> {
+        if content.spilled.is_some() {
+            return Ok(());
+        }
+        let file = SpillFile::write(&self.dir, &content.data)?;
+        self.adopt(content, file);
+        Ok(())
+    }
+
+    /// Take over `file`, the payload of `content` spilled elsewhere into
+    /// [`dir`](Self::dir), and refer `content` to it
+    pub fn adopt(&mut self, content: &mut ClipboardContent, file: SpillFile) {
+        let file = Arc::new(file);
+        content.spilled = Some(SpillHandle {
+            file: Arc::downgrade(&file),
+            pinned: None,
+            size: file.size(),
+            sha256: file.sha256(),
+            counters: self.counters.clone(),
+        });
+        content.data = Vec::new();
+        self.entries.push_back(CacheEntry { file, added: Instant::now() });
+        self.update_gauges();
+    }
+
+    /// Drop payloads no content refers to anymore, then evict the oldest
+    /// payloads past the age limit or over the size limit. Payloads being read
+    /// or pinned are never evicted, nor is the newest one for exceeding the size limit,
+    /// so the latest content always stays available. Returns the number of
+    /// payloads evicted.
+    pub fn gc(&mut self) -> usize {
+        self.entries.retain(|entry| Arc::weak_count(&entry.file) > 0 || entry.in_use());
+
+        let mut evicted = 0;
+        if let Some(max_age) = self.limits.max_age {
+            let before = self.entries.len();
+            self.entries.retain(|entry| entry.added.elapsed() <= max_age || entry.in_use());
+            evicted += before - self.entries.len();
+        }
+        if let Some(max_bytes) = self.limits.max_bytes {
+            let mut bytes = self.bytes();
+            let mut index = 0;
+            while bytes > max_bytes && index + 1 < self.entries.len() {
+                if self.entries[index].in_use() {
+                    index += 1;
+                    continue;
+                }
+                if let Some(entry) = self.entries.remove(index) {
+                    bytes -= entry.file.size();
+                    evicted += 1;
+                }
+            }
+        }
+
+        if evicted > 0 {
+            debug!("Evicted {evicted} payloads from the cache");
+            self.counters.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+        }
+        self.update_gauges();
+        evicted
+    }
+
+    /// Remove every cached payload, and the spill directory if it was a
+    /// temporary one. Payloads still being read go once the read finishes.
+    pub fn shutdown(mut self) {
+        self.entries.clear();
+        self.update_gauges();
+        if self.temporary
+            && self.dir.exists()
+            && let Err(e) = fs::remove_dir_all(&self.dir)
+        {
+            warn!("Failed to remove spill directory {}: {e}", self.dir.display());
+        }
+    }
+
+    fn bytes(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.file.size()).sum()
+    }
+
+    fn update_gauges(&self) {
+        self.counters.entries.store(self.entries.len() as u64, Ordering::Relaxed);
+        self.counters.bytes.store(self.bytes(), Ordering::Relaxed);
+    }
+}
+
+/// Characters of text shown in previews and log lines
+pub const PREVIEW_CHARS: usize = 60;
+
+/// Single-line description of `content` that is safe to log: text collapsed
+/// and cut to `max` characters, images and files by their metadata only
+pub fn preview(content: &ClipboardContent, max: usize) -> String {
+    match content.content_type {
+        ContentType::Image => format!(
+            "{}x{} image ({} bytes)",
+            content.width.unwrap_or(0),
+            content.height.unwrap_or(0),
+            content.size()
+        ),
+        ContentType::Files => {
+            let names: Vec<&str> = content.files.iter().map(|file| file.name.as_str()).collect();
+            format!("{} files: {}", names.len(), truncate(&names.join(", "), max))
+        }
+        // Don't read spilled payloads back just for a preview
+        ContentType::Text if content.spilled.is_some() => format!("{} bytes of text", content.size()),
+        ContentType::Text if content.sealed => format!("{} bytes of sealed text", content.size()),
+        ContentType::Text => preview_text(&String::from_utf8_lossy(&content.data), max),
+    }
+}
+
+/// `text` collapsed onto one line and cut to `max` characters
+pub fn preview_text(text: &str, max: usize) -> String {
+    truncate(&text.split_whitespace().collect::<Vec<_>>().join(" "), max)
+}
+
+/// How text appears in log lines: all of it with `--log-content`, otherwise a preview
+pub fn loggable_text(text: &str, log_content: bool) -> String {
+    if log_content { text.to_string() } else { preview_text(text, PREVIEW_CHARS) }
+}
+
+/// How `content` appears in log lines: all of its text with `--log-content`,
+/// otherwise a preview
+pub fn loggable(content: &ClipboardContent, log_content: bool) -> String {
+    match content.text() {
+        Some(text) if log_content => text,
+        _ => preview(content, PREVIEW_CHARS),
+    }
+}
+
+/// `line` cut to `max` characters, ending in an ellipsis and the full length
+/// if anything was cut
+fn truncate(line: &str, max: usize) -> String {
+    let chars = line.chars().count();
+    if chars > max {
+        format!("{}… ({chars} chars)", line.chars().take(max).collect::<String>())
+    } else {
+        line.to_string()
+    }
+}
+
+/// Hash of raw payload bytes, used to detect image changes and copies of
+/// received content
+fn hash_bytes(data: &[u8]) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+    let mut hasher = DefaultHasher::new();
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// Hash of everything a clipboard read found, used to tell whether the
+/// clipboard changed at all since the last read
+fn hash_read(files: &Option<Vec<PathBuf>>, text: &Option<String>, image_hash: Option<u64>) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    (files, text, image_hash).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Describe copied files for a file offer, timing the hashing. Blocks.
+fn describe_files(paths: &[PathBuf], timings: &OpTimings) -> Vec<FileOffer> {
+    let started = Instant::now();
+    let offers = crate::files::describe(paths);
+    let size = offers.iter().map(|offer| offer.size as usize).sum();
+    timings.record("file_hash", size, started.elapsed());
+    offers
+}
+
+/// Files at `paths` as recorded in `last_content`, enough to tell their
+/// echo from a new copy without hashing them
+fn files_at(paths: &[PathBuf]) -> ClipboardContent {
+    ClipboardContent::new_files(
+        paths
+            .iter()
+            .map(|path| FileOffer {
+                name: path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+                size: 0,
+                sha256: String::new(),
+                path: Some(path.clone()),
+            })
+            .collect(),
+    )
+}
+
+/// Maximum number of received items waiting in the incoming queue
+const INCOMING_QUEUE_LIMIT: usize = 20;
+/// Applied items to confirm that may wait for the event loop to take them
+const DELIVERED_CAPACITY: usize = 16;
+
+/// Payload hash and provenance of the received content applied last
+struct LastReceived {
+    hash: u64,
+    provenance: Vec<Hop>,
+}
+
+/// The content last published or applied, and when
+#[derive(Debug)]
+struct LastContent {
+    content: ClipboardContent,
+    at: Instant,
+}
+
+impl LastContent {
+    fn new(content: ClipboardContent) -> Self {
+        Self { content, at: Instant::now() }
+    }
+
+    /// Whether `text` showing up on the clipboard now is an echo of this
+    /// content rather than a deliberate copy of the same text
+    fn echoes_text(&self, text: &str, window: Duration) -> bool {
+        self.at.elapsed() < window && self.content.text().as_deref() == Some(text)
+    }
+
+    /// Whether the RGBA `bytes` showing up on the clipboard now are an echo
+    /// of this content
+    fn echoes_image(&self, bytes: &[u8], window: Duration) -> bool {
+        self.at.elapsed() < window && self.content.content_type == ContentType::Image && self.content.data == bytes
+    }
+
+    /// Whether `paths` showing up on the clipboard now are the files
+    /// `set_files` just put there
+    fn echoes_files(&self, paths: &[PathBuf], window: Duration) -> bool {
+        self.at.elapsed() < window
+            && self.content.files().is_some_and(|offers| {
+                offers.iter().map(|offer| offer.path.as_ref()).eq(paths.iter().map(Some))
+            })
+    }
+}
+
+/// Backend errors in a row after which the clipboard handle is re-created
+const MAX_BACKEND_FAILURES: u32 = 3;
+/// Wait before retrying a backend that could not be re-created, doubled
+/// after every failed attempt up to the maximum
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+const BACKEND_UNAVAILABLE: &str = "The clipboard backend is unavailable";
+
+/// The clipboard handle, re-created when the system clipboard behind it goes
+/// away, e.g. when the X server restarts or the compositor crashes. Until it
+/// is back, clipboard reads and writes fail but the node keeps relaying.
+struct Backend<C = Box<dyn SystemClipboard>> {
+    handle: Option<C>,
+    connect: Box<dyn Fn() -> Result<C, crate::system_clipboard::Error> + Send + Sync>,
+    /// Backend errors in a row, reset by any operation that got through
+    failures: u32,
+    retry_at: Instant,
+    backoff: Duration,
+    /// Bumped on every re-creation, so the monitor knows the clipboard it
+    /// remembered is gone
+    generation: u64,
+}
+
+impl<C> Backend<C> {
+    fn new(connect: Box<dyn Fn() -> Result<C, crate::system_clipboard::Error> + Send + Sync>) -> Result<Self, crate::system_clipboard::Error> {
+        Ok(Self {
+            handle: Some(connect()?),
+            connect,
+            failures: 0,
+            retry_at: Instant::now(),
+            backoff: RECONNECT_BACKOFF_MIN,
+            generation: 0,
+        })
+    }
+
+    /// Run `op` on the handle, re-creating the handle first if it was
+    /// dropped. Returns `None` while the backend is unavailable.
+    fn with<T>(&mut self, op: impl FnOnce(&mut C) -> Result<T, crate::system_clipboard::Error>) -> Option<Result<T, crate::system_clipboard::Error>> {
+        let result = op(self.connected()?);
+        match result {
+            Err(ref e) if is_backend_failure(e) => {
+                self.failures += 1;
+                if self.failures >= MAX_BACKEND_FAILURES {
+                    warn!("Clipboard backend failed {} times in a row, re-creating it: {e}", self.failures);
+                    self.handle = None;
+                    self.failures = 0;
+                    self.retry_at = Instant::now();
+                }
+            }
+            _ => self.failures = 0,
+        }
+        Some(result)
+    }
+
+    fn connected(&mut self) -> Option<&mut C> {
+        if self.handle.is_none() {
+            if Instant::now() < self.retry_at {
+                return None;
+            }
+            match (self.connect)() {
+                Ok(handle) => {
+                    info!("Clipboard backend recovered");
+                    self.handle = Some(handle);
+                    self.backoff = RECONNECT_BACKOFF_MIN;
+                    self.generation += 1;
+                }
+                Err(e) => {
+                    warn!("Failed to re-create the clipboard backend, retrying in {}s: {e}", self.backoff.as_secs());
+                    self.retry_at = Instant::now() + self.backoff;
+                    self.backoff = (self.backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                    return None;
+                }
+            }
+        }
+        self.handle.as_mut()
+    }
+}
+
+/// Errors meaning the backend itself is broken, as opposed to the clipboard
+/// holding nothing usable or being briefly held by another program
+fn is_backend_failure(e: &crate::system_clipboard::Error) -> bool {
+    matches!(e, crate::system_clipboard::Error::ClipboardNotSupported | crate::system_clipboard::Error::Unknown { .. })
+}
+
+/// Tag freshly copied `content` with the focused window and whether its
+/// delivery should be confirmed, as enabled. File offers are never confirmed:
+/// receivers only put them on the clipboard once downloaded.
+async fn tag_copy(
+    content: &mut ClipboardContent,
+    options: &Mutex<ClipboardOptions>,
+    focus: &Arc<dyn FocusProvider>,
+    last_received: &Mutex<Option<LastReceived>>,
+) {
+    let (source_label, confirm_delivery, device_name) = {
+        let options = options.lock().await;
+        (options.source_label, options.confirm_delivery, options.device_name.clone())
+    };
+    content.confirm_nonce = (confirm_delivery && content.files().is_none()).then(delivery::nonce);
+    // A copy of what was received last carries on its provenance
+    if content.files().is_none()
+        && let Some(ref received) = *last_received.lock().await
+        && received.hash == hash_bytes(&content.data)
+    {
+        content.provenance = received.provenance.clone();
+        content.add_hop(device_name, content.timestamp);
+    }
+    if !source_label {
+        return;
+    }
+    let focus = focus.clone();
+    match tokio::task::spawn_blocking(move || focus.focused_window()).await {
+        Ok(label) => content.source_label = label,
+        Err(e) => warn!("Looking up the focused window failed: {e}"),
+    }
 }
 
 /// Clipboard synchronization service
+///
+/// `last_content` must match what is on the clipboard for echoes to be
+/// recognized, so it only changes while `clipboard` is locked: together
+/// with the write when applying content, and together with the read that
+/// found a local copy. Locks are taken in the order `clipboard`,
+/// `last_content`, `last_received`; `options` and `incoming_queue` are
+/// never held while waiting for another lock.
 #[derive(Clone)]
 pub struct ClipboardSync {
-    clipboard: Arc<Mutex<Clipboard>>,
-    last_content: Arc<Mutex<Option<ClipboardContent>>>,
+    clipboard: Arc<Mutex<Backend>>,
+    last_content: Arc<Mutex<Option<LastContent>>>,
+    last_received: Arc<Mutex<Option<LastReceived>>>,
+    incoming_queue: Arc<Mutex<VecDeque<ClipboardContent>>>,
+    options: Arc<Mutex<ClipboardOptions>>,
+    timings: Arc<OpTimings>,
+    focus: Arc<dyn FocusProvider>,
+    /// Applied content whose sender asked for a confirmation
+    delivered: broadcast::Sender<Delivered>,
 }
 
 impl ClipboardSync {
     /// Create a new clipboard sync service
     pub fn new() -> Result<Self> {
-        let clipboard = Clipboard::new()
+        Self::with_options(ClipboardOptions::default())
+    }
+
+    /// Create a new clipboard sync service with custom options
+    pub fn with_options(options: ClipboardOptions) -> Result<Self> {
+        Self::with_backend(options, Box::new(crate::system_clipboard::connect))
+    }
+
+    /// Create a clipboard sync service on the clipboard `connect` opens,
+    /// e.g. an in-memory one
+    pub fn with_backend(options: ClipboardOptions, connect: Connector) -> Result<Self> {
+        let clipboard = Backend::new(connect)
             .context("Failed to initialize clipboard")?;
-        
+
         Ok(Self {
             clipboard: Arc::new(Mutex::new(clipboard)),
             last_content: Arc::new(Mutex::new(None)),
+            last_received: Arc::new(Mutex::new(None)),
+            incoming_queue: Arc::new(Mutex::new(VecDeque::new())),
+            options: Arc::new(Mutex::new(options)),
+            timings: Arc::new(OpTimings::default()),
+            focus: Arc::from(crate::focus::platform_provider()),
+            delivered: broadcast::channel(DELIVERED_CAPACITY).0,
+        })
+    }
+
+    /// Received content put on the clipboard whose sender wants a delivery
+    /// confirmation, whether applied right away or accepted later
+    pub fn subscribe_delivered(&self) -> broadcast::Receiver<Delivered> {
+        self.delivered.subscribe()
+    }
+
+    /// Durations of clipboard reads and writes, shared with the event loop
+    pub fn timings(&self) -> Arc<OpTimings> {
+        self.timings.clone()
+    }
+
+    /// Replace the options, e.g. after a configuration reload. Content already
+    /// being applied keeps the options it started with.
+    pub async fn set_options(&self, options: ClipboardOptions) {
+        *self.options.lock().await = options;
+    }
+
+    /// Read the live clipboard right now: copied files first, then text,
+    /// then image. Returns `Ok(None)` when the clipboard is empty. Monitoring
+    /// state is untouched and nothing is published.
+    pub async fn current(&self) -> Result<Option<ClipboardContent>> {
+        let mut clipboard = self.clipboard.clone().lock_owned().await;
+        let timings = self.timings.clone();
+        tokio::task::spawn_blocking(move || {
+            let started = Instant::now();
+            let files = clipboard.with(|clipboard| clipboard.get_files()).context(BACKEND_UNAVAILABLE)?;
+            if let Some(paths) = files.ok().filter(|paths| !paths.is_empty()) {
+                drop(clipboard);
+                timings.record("clipboard_read", 0, started.elapsed());
+                let offers = describe_files(&paths, &timings);
+                if offers.is_empty() {
+                    bail!("None of the copied files can be shared");
+                }
+                return Ok(Some(ClipboardContent::new_files(offers)));
+            }
+            let content = match clipboard.with(|clipboard| clipboard.get_text()).context(BACKEND_UNAVAILABLE)? {
+                Ok(text) => Some(ClipboardContent::new_text(text)),
+                Err(crate::system_clipboard::Error::ContentNotAvailable) => match clipboard.with(|clipboard| clipboard.get_image()).context(BACKEND_UNAVAILABLE)? {
+                    Ok(image) => {
+                        let (width, height) = (image.width as u32, image.height as u32);
+                        let rgba = crate::imaging::normalize_rgba(&image.bytes, width, height, None)
+                            .context("Clipboard image is malformed")?;
+                        Some(ClipboardContent::new_image(rgba.into_owned(), width, height))
+                    }
+                    Err(crate::system_clipboard::Error::ContentNotAvailable) => None,
+                    Err(e) => return Err(e).context("Failed to read clipboard image"),
+                },
+                Err(e) => return Err(e).context("Failed to read clipboard text"),
+            };
+            timings.record("clipboard_read", content.as_ref().map_or(0, ClipboardContent::size), started.elapsed());
+            Ok(content)
         })
+        .await
+        .context("Clipboard read task failed")?
     }
 
-    /// Start monitoring clipboard changes
-    pub async fn start_monitoring<F>(&self, mut callback: F) -> Result<()>
+    /// Start monitoring clipboard changes on a task that runs until aborted
+    pub async fn start_monitoring<F>(&self, mut callback: F) -> Result<tokio::task::JoinHandle<()>>
     where
         F: FnMut(ClipboardContent) + Send + 'static,
     {
-        println!("Starting clipboard monitoring...");
+        info!("Starting clipboard monitoring...");
         let clipboard = self.clipboard.clone();
         let last_content = self.last_content.clone();
+        let last_received = self.last_received.clone();
+        let timings = self.timings.clone();
+        let options = self.options.clone();
+        let focus = self.focus.clone();
+        let (ignore_initial, schedule) = {
+            let options = options.lock().await;
+            (options.ignore_initial, options.poll)
+        };
         
         // Spawn a task to monitor clipboard changes
-        tokio::spawn(async move {
-            let mut interval = interval(Duration::from_millis(500)); // Check every 500ms
+        let monitor = tokio::spawn(async move {
+            let mut interval = AdaptiveInterval::new(schedule);
+            // Hash of everything the last read found, telling busy from idle
+            let mut previous_read: Option<u64> = None;
             let mut previous_text: Option<String> = None;
             let mut previous_image_hash: Option<u64> = None; // Track image changes by hash
+            let mut previous_files: Option<Vec<PathBuf>> = None;
+            let mut previous_count: Option<u64> = None;
+
+            // Seed the change detection with the current clipboard so content
+            // copied before startup (possibly a password) is never published
+            let mut generation = clipboard.lock().await.generation;
+            if ignore_initial {
+                let mut clipboard = clipboard.lock().await;
+                previous_files = clipboard
+                    .with(|clipboard| clipboard.get_files())
+                    .and_then(Result::ok)
+                    .filter(|paths| !paths.is_empty());
+                previous_text = clipboard.with(|clipboard| clipboard.get_text()).and_then(Result::ok);
+                if let Some(ref text) = previous_text {
+                    *last_content.lock().await = Some(LastContent::new(ClipboardContent::new_text(text.clone())));
+                } else if let Some(Ok(image)) = clipboard.with(|clipboard| clipboard.get_image()) {
+                    previous_image_hash = Some(hash_bytes(&image.bytes));
+                    *last_content.lock().await = Some(LastContent::new(ClipboardContent::new_image(
+                        image.bytes.into_owned(),
+                        image.width as u32,
+                        image.height as u32,
+                    )));
+                }
+                info!("Ignoring content already on the clipboard at startup");
+            }
             
             loop {
-                interval.tick().await;
-                
-                // Try to get clipboard content (both text and image)
-                let current_text = {
-                    let mut clipboard = clipboard.lock().await;
-                    clipboard.get_text().ok()
+                tokio::time::sleep(interval.next()).await;
+                let (schedule, window, log_content) = {
+                    let options = options.lock().await;
+                    (options.poll, options.resend_window, options.log_content)
                 };
+                interval.set_schedule(schedule);
                 
-                let current_image_data = {
-                    let mut clipboard = clipboard.lock().await;
-                    clipboard.get_image().ok().map(|img_data| {
+                // Try to get clipboard content (both text and image). Both
+                // the backend and hashing a large image can block, so keep
+                // them off the async workers. The lock is kept until the
+                // read has been told apart from an echo, so no content is
+                // applied in between.
+                let mut guard = clipboard.clone().lock_owned().await;
+                let read_timings = timings.clone();
+                let read = tokio::task::spawn_blocking(move || {
+                    let started = Instant::now();
+                    // Taken first, so a copy made during the reads still
+                    // changes it for the next round
+                    let count = guard.connected().and_then(|clipboard| clipboard.change_count());
+                    // Nothing to read while the backend is being re-created
+                    let files = guard.with(|clipboard| clipboard.get_files())?.ok().filter(|paths| !paths.is_empty());
+                    let text = guard.with(|clipboard| clipboard.get_text())?.ok();
+                    let image = guard.with(|clipboard| clipboard.get_image())?.ok().map(|img_data| {
                         // Convert image data to bytes and get dimensions
                         (img_data.bytes.to_vec(), img_data.width as u32, img_data.height as u32)
-                    })
+                    });
+                    let generation = guard.generation;
+                    let size = text.as_ref().map_or(0, String::len) + image.as_ref().map_or(0, |(bytes, _, _)| bytes.len());
+                    read_timings.record("clipboard_read", size, started.elapsed());
+                    let image = image.map(|(bytes, width, height)| {
+                        let hash = hash_bytes(&bytes);
+                        (bytes, width, height, hash)
+                    });
+                    Some((files, text, image, count, generation, guard))
+                })
+                .await;
+                let (current_files, current_text, current_image_data, current_count, current_generation, guard) = match read {
+                    Ok(Some(read)) => read,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        warn!("Clipboard read task failed: {e}");
+                        continue;
+                    }
                 };
-                
-                // Check if text content has changed
-                if current_text != previous_text {
+                let current_image_hash = current_image_data.as_ref().map(|(_, _, _, hash)| *hash);
+                let current_read = hash_read(&current_files, &current_text, current_image_hash);
+                // Copying the same text again leaves the text as it was, but
+                // not the platform's change count, where there is one
+                let recopied = previous_count.is_some() && current_count != previous_count;
+                previous_count = current_count;
+                if previous_read.replace(current_read) == Some(current_read) && !recopied {
+                    interval.idle();
+                } else {
+                    interval.changed();
+                }
+
+                // A re-created backend may come back empty or with content
+                // restored by a clipboard manager. Neither was copied by the
+                // user, so start change detection over from what is there now.
+                if current_generation != generation {
+                    generation = current_generation;
+                    previous_image_hash = current_image_data.as_ref().map(|(_, _, _, hash)| *hash);
+                    previous_files = current_files;
+                    previous_text = current_text;
+                    info!("Resumed clipboard monitoring after the backend recovered");
+                    continue;
+                }
+
+                // Copied files come with text (their paths or URIs) in most
+                // file managers, so they take precedence and that text is
+                // never published on its own
+                if current_files != previous_files || (recopied && current_files.is_some()) {
+                    if let Some(paths) = current_files {
+                        // Files we just downloaded and put there ourselves
+                        let applied = {
+                            let mut last = last_content.lock().await;
+                            let applied = last.as_ref().is_some_and(|last| last.echoes_files(&paths, window));
+                            if !applied {
+                                *last = Some(LastContent::new(files_at(&paths)));
+                            }
+                            applied
+                        };
+                        drop(guard);
+                        if !applied {
+                            info!("Clipboard files changed: {} files", paths.len());
+                            let paths = paths.clone();
+                            let timings = timings.clone();
+                            match tokio::task::spawn_blocking(move || describe_files(&paths, &timings)).await {
+                                Ok(offers) if offers.is_empty() => warn!("None of the copied files can be shared"),
+                                Ok(offers) => {
+                                    let mut content = ClipboardContent::new_files(offers);
+                                    tag_copy(&mut content, &options, &focus, &last_received).await;
+                                    callback(content);
+                                }
+                                Err(e) => warn!("Hashing copied files failed: {e}"),
+                            }
+                        }
+                        previous_files = Some(paths);
+                        previous_text = current_text;
+                        previous_image_hash = None;
+                        continue;
+                    }
+                    previous_files = None;
+                } else if current_files.is_some() {
+                    continue;
+                }
+
+                // Check if text content has changed, or was copied again
+                if current_text != previous_text || (recopied && current_text.is_some()) {
                     if let Some(ref text) = current_text {
-                        println!("Clipboard text changed: {}", text);
+                        info!("Clipboard text changed: {}", loggable_text(text, log_content));
                         
-                        // Check if this is different from our last sent content
+                        // The same text as last sent or applied is an echo
+                        // shortly after, and a deliberate resend later on
                         let should_send = {
-                            let last = last_content.lock().await;
-                            if let Some(ref last_content) = *last {
-                                if let Some(last_text) = last_content.text() {
-                                    last_text != *text
-                                } else {
-                                    true // Last content was not text
-                                }
-                            } else {
-                                true // No previous content
+                            let mut last = last_content.lock().await;
+                            let should_send = !last.as_ref().is_some_and(|last| last.echoes_text(text, window));
+                            if should_send {
+                                *last = Some(LastContent::new(ClipboardContent::new_text(text.clone())));
                             }
+                            should_send
                         };
+                        drop(guard);
                         
                         if should_send {
                             let mut content = ClipboardContent::new_text(text.clone());
                             // Mark as coming from network
                             content.from_network = true;
-                            // Update last content
-                            {
-                                let mut last = last_content.lock().await;
-                                *last = Some(content.clone());
-                            }
+                            tag_copy(&mut content, &options, &focus, &last_received).await;
                             
                             // Call the callback with the new content
                             callback(content);
+                        } else {
+                            debug!("Not publishing the clipboard text again: it echoes content handled moments ago");
                         }
                     }
                     
@@ -166,31 +1109,40 @@ impl ClipboardSync {
                     previous_image_hash = None;
                 }
                 // Check if image content has changed
-                else if let Some((image_data, width, height)) = current_image_data {
-                    // Calculate hash of image data to detect changes
-                    let image_hash = {
-                        use std::collections::hash_map::DefaultHasher;
-                        use std::hash::Hasher;
-                        let mut hasher = DefaultHasher::new();
-                        hasher.write(&image_data);
-                        hasher.finish()
-                    };
-                    
+                else if let Some((image_data, width, height, image_hash)) = current_image_data {
                     if Some(image_hash) != previous_image_hash {
-                        println!("Clipboard image changed ({} bytes, {}x{})", image_data.len(), width, height);
-                        
-                        let content = ClipboardContent::new_image(image_data.clone(), width, height);
+                        info!("Clipboard image changed ({} bytes, {}x{})", image_data.len(), width, height);
+
+                        // Catch malformed buffers here rather than on every receiver
+                        let rgba = match crate::imaging::normalize_rgba(&image_data, width, height, None) {
+                            Ok(rgba) => rgba.into_owned(),
+                            Err(e) => {
+                                warn!("Not publishing clipboard image: {e}");
+                                previous_image_hash = Some(image_hash);
+                                continue;
+                            }
+                        };
                         
-                        // Update last content
-                        {
+                        // An image just applied is an echo like text is
+                        let mut content = ClipboardContent::new_image(rgba, width, height);
+                        let should_send = {
                             let mut last = last_content.lock().await;
-                            *last = Some(content.clone());
+                            let should_send = !last.as_ref().is_some_and(|last| last.echoes_image(&content.data, window));
+                            if should_send {
+                                *last = Some(LastContent::new(content.clone()));
+                            }
+                            should_send
+                        };
+                        drop(guard);
+                        previous_image_hash = Some(image_hash);
+                        if !should_send {
+                            debug!("Not publishing the clipboard image again: it echoes content handled moments ago");
+                            continue;
                         }
+                        tag_copy(&mut content, &options, &focus, &last_received).await;
                         
                         // Call the callback with the new content
                         callback(content);
-                        
-                        previous_image_hash = Some(image_hash);
                     }
                 } else {
                     // No image data available, reset image hash
@@ -199,60 +1151,863 @@ impl ClipboardSync {
             }
         });
         
-        Ok(())
+        Ok(monitor)
+    }
+
+    /// Take content received from the network, either applying it right away
+    /// or queueing it for manual selection depending on the options
+    pub async fn receive_content(&self, mut content: ClipboardContent) -> Result<()> {
+        // Queued for `/accept`, or waiting for the clipboard, it must
+        // outlast the cache limits
+        content.pin_spilled();
+        let queue = {
+            let options = self.options.lock().await;
+            options.queue_incoming && !options.auto_accept.contains(&content.content_type)
+        };
+        if queue {
+            self.enqueue_incoming(content).await;
+            Ok(())
+        } else {
+            self.handle_incoming_content(content).await
+        }
+    }
+
+    /// Add received content to the incoming queue, dropping the oldest item when full
+    pub async fn enqueue_incoming(&self, content: ClipboardContent) {
+        let mut queue = self.incoming_queue.lock().await;
+        if queue.len() == INCOMING_QUEUE_LIMIT {
+            queue.pop_front();
+        }
+        info!("Queued received {:?} content as #{}. Use /accept {} to apply it.",
+                 content.content_type, queue.len(), queue.len());
+        queue.push_back(content);
+    }
+
+    /// Snapshot of the incoming queue, oldest first
+    pub async fn peek_incoming(&self) -> Vec<ClipboardContent> {
+        self.incoming_queue.lock().await.iter().cloned().collect()
+    }
+
+    /// Apply the queued item at `index` to the clipboard and remove it from the queue
+    pub async fn accept_incoming(&self, index: usize) -> Result<()> {
+        let content = self.incoming_queue.lock().await.remove(index)
+            .with_context(|| format!("No queued item #{index}"))?;
+        self.handle_incoming_content(content).await
+    }
+
+    /// Remove the queued item at `index` without applying it
+    pub async fn reject_incoming(&self, index: usize) -> Result<ClipboardContent> {
+        self.incoming_queue.lock().await.remove(index)
+            .with_context(|| format!("No queued item #{index}"))
     }
 
     /// Handle incoming clipboard content from network
     pub async fn handle_incoming_content(&self, content: ClipboardContent) -> Result<()> {
-        println!("Received clipboard content: {:?} ({}x{})", content.content_type, 
+        let size = content.size();
+        let started = Instant::now();
+        let result = self.apply_incoming(content).await;
+        self.timings.record("handle_incoming_content", size, started.elapsed());
+        result
+    }
+
+    async fn apply_incoming(&self, content: ClipboardContent) -> Result<()> {
+        info!("Received clipboard content: {:?} ({}x{})", content.content_type, 
                  content.width.unwrap_or(0), content.height.unwrap_or(0));
-        
-        // Update last content to prevent echo
-        {
-            let mut last = self.last_content.lock().await;
-            *last = Some(content.clone());
-        }
-        
-        let result = {
-            let mut clipboard = self.clipboard.lock().await;
-            
-            match content.content_type {
-                ContentType::Text => {
-                    if let Some(text) = content.text() {
-                        println!("Setting clipboard text: {}", text);
-                        clipboard.set_text(text)
-                            .context("Failed to set clipboard text")
+
+        let copied_at = content.timestamp;
+        let received_in = content.received_in.clone();
+        let (image_scale, log_content, newlines) = {
+            let options = self.options.lock().await;
+            (options.image_scale, options.log_content, options.newlines)
+        };
+
+        // Load spilled payloads back, then validate and rescale images before
+        // taking the clipboard lock. All of it can be slow, so none of it runs
+        // on the async workers.
+        let timings = self.timings.clone();
+        let (content, image, confirm) = tokio::task::spawn_blocking(move || -> Result<_> {
+            // Spilled payloads are only loaded back into memory to be applied
+            let mut content = content.unspill()?;
+            // Hashed as sent, before anything is changed for this machine
+            let confirm = match content.confirm_nonce {
+                Some(ref nonce) => Some(delivery::salted(nonce, &content.sha256()?)),
+                None => None,
+            };
+            let replaced = content.text().and_then(|text| match replace_newlines(&text, newlines) {
+                Cow::Owned(replaced) => Some(replaced),
+                Cow::Borrowed(_) => None,
+            });
+            if let Some(replaced) = replaced {
+                debug!("Replaced line breaks in received text ({newlines:?})");
+                content.data = replaced.into_bytes();
+            }
+            let image = match (content.image(), content.width, content.height) {
+                (Some(data), Some(width), Some(height)) => timings.time("image_prepare", data.len(), || -> Result<_> {
+                    let rgba = crate::imaging::normalize_rgba(&data, width, height, content.channels)
+                        .context("Rejected received image")?;
+                    let (bytes, width, height) = if image_scale != 1.0 {
+                        let (bytes, new_width, new_height) =
+                            crate::imaging::scale_image(&rgba, width, height, image_scale)?;
+                        info!("Scaled received image {}x{} -> {}x{}", width, height, new_width, new_height);
+                        (bytes, new_width, new_height)
                     } else {
+                        (rgba.into_owned(), width, height)
+                    };
+                    Ok(Some(crate::system_clipboard::ImageData {
+                        width: width as usize,
+                        height: height as usize,
+                        bytes: Cow::Owned(bytes),
+                    }))
+                })?,
+                (Some(_), _, _) => bail!("Rejected received image: missing dimensions"),
+                (None, _, _) => None,
+            };
+            // Remember the image as it is put on the clipboard, so the monitor
+            // recognizes it there as applied rather than copied
+            if let Some(ref image) = image {
+                content.data = image.bytes.to_vec();
+                content.width = Some(image.width as u32);
+                content.height = Some(image.height as u32);
+                content.channels = Some(4);
+            }
+            Ok((content, image, confirm))
+        })
+        .await
+        .context("Image preparation task failed")??;
+        let mut clipboard = self.clipboard.clone().lock_owned().await;
+        let written = content.clone();
+        let timings = self.timings.clone();
+        let (clipboard, result) = tokio::task::spawn_blocking(move || {
+            let started = Instant::now();
+            let size = content.size();
+
+            // Rewriting identical content still counts as a new copy for
+            // clipboard managers (e.g. a new Win+V history entry), so compare
+            // with what is really on the clipboard, which may have changed
+            // locally since last_content was recorded
+            let write = || match content.content_type {
+                ContentType::Text => match content.text() {
+                    Some(text) if clipboard.with(|clipboard| clipboard.get_text()).and_then(Result::ok).as_deref() == Some(text.as_str()) => {
+                        info!("Clipboard already holds the received text, not rewriting it");
                         Ok(())
                     }
-                }
-                ContentType::Image => {
-                    if let Some(image_data) = content.image() {
-                        println!("Setting clipboard image ({} bytes, {}x{})", 
-                                 image_data.len(), 
-                                 content.width.unwrap_or(0), 
-                                 content.height.unwrap_or(0));
-                        
-                        // Create proper ImageData from the received bytes with correct dimensions
-                        clipboard.set_image(arboard::ImageData {
-                            width: content.width.unwrap_or(100) as usize,  // Use received width or default
-                            height: content.height.unwrap_or(100) as usize, // Use received height or default
-                            bytes: std::borrow::Cow::Borrowed(image_data),
-                        })
-                        .context("Failed to set clipboard image")
-                    } else {
+                    Some(text) => {
+                        info!("Setting clipboard text: {}", loggable_text(&text, log_content));
+                        clipboard.with(|clipboard| clipboard.set_text(text))
+                            .context(BACKEND_UNAVAILABLE)?
+                            .context("Failed to set clipboard text")
+                    }
+                    None => Ok(()),
+                },
+                // Offered files are downloaded, see `files`, and put on the
+                // clipboard with `set_files` once they arrived
+                ContentType::Files => Ok(()),
+                ContentType::Image => match image {
+                    Some(image) if clipboard.with(|clipboard| clipboard.get_image()).and_then(Result::ok).is_some_and(|current| {
+                        current.width == image.width && current.height == image.height && current.bytes == image.bytes
+                    }) => {
+                        info!("Clipboard already holds the received image, not rewriting it");
                         Ok(())
                     }
-                }
+                    Some(image) => {
+                        info!("Setting clipboard image ({} bytes, {}x{})",
+                                 image.bytes.len(), image.width, image.height);
+                        clipboard.with(|clipboard| clipboard.set_image(image))
+                            .context(BACKEND_UNAVAILABLE)?
+                            .context("Failed to set clipboard image")
+                    }
+                    None => Ok(()),
+                },
+            };
+            let result = write();
+            timings.record("clipboard_write", size, started.elapsed());
+            (clipboard, result)
+        })
+        .await
+        .context("Clipboard write task failed")?;
+
+        if result.is_ok() {
+            // Recorded once written, before the clipboard lock is released, so
+            // that neither the monitor nor another received item sees one
+            // without the other, and a failed write leaves no trace
+            if written.files().is_none() {
+                *self.last_received.lock().await =
+                    Some(LastReceived { hash: hash_bytes(&written.data), provenance: written.provenance.clone() });
             }
-        };
-        
+            *self.last_content.lock().await = Some(LastContent::new(written));
+            drop(clipboard);
+            // Copy-to-apply latency, only meaningful when clocks are roughly in sync
+            debug!("Applied clipboard content {} ms after copy", now_millis().saturating_sub(copied_at));
+            if let Some(sha256) = confirm {
+                let _ = self.delivered.send(Delivered { room: received_in, ack: Ack { sha256 } });
+            }
+        }
+
         result
     }
+
+    /// Put downloaded files on the clipboard, as if they had been copied in
+    /// a file manager
+    pub async fn set_files(&self, paths: Vec<PathBuf>) -> Result<()> {
+        // Recorded with the write, under the clipboard lock, so the monitor
+        // doesn't offer them back
+        let mut clipboard = self.clipboard.clone().lock_owned().await;
+        let written = files_at(&paths);
+        let timings = self.timings.clone();
+        let (clipboard, result) = tokio::task::spawn_blocking(move || {
+            let started = Instant::now();
+            info!("Setting clipboard files: {}", paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "));
+            let result = clipboard
+                .with(|clipboard| clipboard.set_files(&paths))
+                .context(BACKEND_UNAVAILABLE)
+                .and_then(|result| result.context("Failed to set clipboard files"));
+            timings.record("clipboard_write", 0, started.elapsed());
+            (clipboard, result)
+        })
+        .await
+        .context("Clipboard write task failed")?;
+        result?;
+        *self.last_content.lock().await = Some(LastContent::new(written));
+        drop(clipboard);
+        Ok(())
+    }
 }
 
 impl Default for ClipboardSync {
     fn default() -> Self {
         Self::new().expect("Failed to create ClipboardSync")
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system_clipboard::MemoryClipboard;
+    use tokio::sync::mpsc;
+
+    /// How often the monitors in these tests read the clipboard
+    const POLL: Duration = Duration::from_millis(10);
+    /// How long a monitor may take to notice a copy
+    const NOTICE: Duration = Duration::from_secs(5);
+
+    /// Monitor an in-memory clipboard, collecting what would be published
+    async fn monitor(options: ClipboardOptions) -> (MemoryClipboard, mpsc::UnboundedReceiver<ClipboardContent>) {
+        let (_, clipboard, published) = monitored(options).await;
+        (clipboard, published)
+    }
+
+    /// [`monitor`], also returning the sync service to apply content with
+    async fn monitored(options: ClipboardOptions) -> (ClipboardSync, MemoryClipboard, mpsc::UnboundedReceiver<ClipboardContent>) {
+        let clipboard = MemoryClipboard::default();
+        let (sync, published) = monitored_on(&clipboard, options).await;
+        (sync, clipboard, published)
+    }
+
+    /// [`monitored`] on a clipboard that may already hold something
+    async fn monitored_on(clipboard: &MemoryClipboard, options: ClipboardOptions) -> (ClipboardSync, mpsc::UnboundedReceiver<ClipboardContent>) {
+        let options = ClipboardOptions { poll: Schedule { min: POLL, max: POLL, backoff: 1.0 }, ..options };
+        let sync = ClipboardSync::with_backend(options, clipboard.connector()).unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        sync.start_monitoring(move |content| {
+            let _ = tx.send(content);
+        })
+        .await
+        .unwrap();
+        (sync, rx)
+    }
+
+    async fn next_text(published: &mut mpsc::UnboundedReceiver<ClipboardContent>) -> String {
+        let content = tokio::time::timeout(NOTICE, published.recv()).await.expect("nothing published").unwrap();
+        content.text().expect("not text")
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_same_text_copied_again_is_only_resent_after_the_window() {
+        let window = Duration::from_millis(500);
+        let (clipboard, mut published) = monitor(ClipboardOptions { resend_window: window, ..Default::default() }).await;
+
+        clipboard.copy_text("hello");
+        assert_eq!(next_text(&mut published).await, "hello");
+
+        // Within the window it is taken for an echo
+        clipboard.copy_text("hello");
+        tokio::time::sleep(POLL * 10).await;
+        assert!(published.try_recv().is_err());
+
+        // After it, a deliberate copy
+        tokio::time::sleep(window).await;
+        clipboard.copy_text("hello");
+        assert_eq!(next_text(&mut published).await, "hello");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn new_text_is_sent_within_the_window() {
+        let (clipboard, mut published) =
+            monitor(ClipboardOptions { resend_window: Duration::from_secs(60), ..Default::default() }).await;
+        clipboard.copy_text("one");
+        assert_eq!(next_text(&mut published).await, "one");
+        clipboard.copy_text("two");
+        assert_eq!(next_text(&mut published).await, "two");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn content_from_before_startup_is_only_published_without_ignore_initial() {
+        for ignore_initial in [true, false] {
+            let clipboard = MemoryClipboard::default();
+            clipboard.copy_text("copied before startup");
+            let (_sync, mut published) = monitored_on(&clipboard, ClipboardOptions { ignore_initial, ..Default::default() }).await;
+            if ignore_initial {
+                tokio::time::sleep(POLL * 10).await;
+                assert!(published.try_recv().is_err(), "the initial content was published");
+            } else {
+                assert_eq!(next_text(&mut published).await, "copied before startup");
+            }
+
+            clipboard.copy_text("copied after startup");
+            assert_eq!(next_text(&mut published).await, "copied after startup");
+        }
+    }
+
+    #[tokio::test]
+    async fn current_reads_the_clipboard_without_publishing() {
+        let clipboard = MemoryClipboard::default();
+        let sync = ClipboardSync::with_backend(ClipboardOptions::default(), clipboard.connector()).unwrap();
+        assert!(sync.current().await.unwrap().is_none());
+
+        clipboard.copy_text("on the clipboard");
+        assert_eq!(sync.current().await.unwrap().and_then(|content| content.text()).as_deref(), Some("on the clipboard"));
+
+        let pixels = vec![0x20; 2 * 2 * 4];
+        clipboard.copy_image(pixels.clone(), 2, 2);
+        let image = sync.current().await.unwrap().unwrap();
+        assert_eq!((image.content_type, image.image().as_deref()), (ContentType::Image, Some(&pixels[..])));
+        // Reading is not copying
+        assert!(sync.last_content.lock().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn auto_accepted_types_skip_the_queue_while_others_wait() {
+        let clipboard = MemoryClipboard::default();
+        let options = ClipboardOptions {
+            queue_incoming: true,
+            auto_accept: HashSet::from([ContentType::Text]),
+            ..ClipboardOptions::default()
+        };
+        let sync = ClipboardSync::with_backend(options, clipboard.connector()).unwrap();
+
+        sync.receive_content(ClipboardContent::new_text("applied at once".to_string())).await.unwrap();
+        assert_eq!(clipboard.text().as_deref(), Some("applied at once"));
+        assert!(sync.peek_incoming().await.is_empty());
+
+        let pixels = vec![0x80; 2 * 2 * 4];
+        sync.receive_content(ClipboardContent::new_image(pixels.clone(), 2, 2)).await.unwrap();
+        assert!(clipboard.image().is_none(), "an unlisted type waits for /accept");
+        let queued = sync.peek_incoming().await;
+        assert_eq!(queued.iter().map(|content| content.content_type).collect::<Vec<_>>(), [ContentType::Image]);
+
+        sync.accept_incoming(0).await.unwrap();
+        assert_eq!(clipboard.image().map(|image| image.bytes.into_owned()), Some(pixels));
+        assert!(sync.peek_incoming().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn without_a_queue_every_type_is_applied() {
+        let clipboard = MemoryClipboard::default();
+        let sync = ClipboardSync::with_backend(ClipboardOptions::default(), clipboard.connector()).unwrap();
+        sync.receive_content(ClipboardContent::new_image(vec![0x40; 4], 1, 1)).await.unwrap();
+        assert!(clipboard.image().is_some());
+        assert!(sync.peek_incoming().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn queued_items_are_applied_only_when_accepted() {
+        let clipboard = MemoryClipboard::default();
+        let sync = ClipboardSync::with_backend(ClipboardOptions::default(), clipboard.connector()).unwrap();
+        for text in ["first", "second", "third"] {
+            sync.enqueue_incoming(ClipboardContent::new_text(text.to_string())).await;
+        }
+        let queued = |items: Vec<ClipboardContent>| items.iter().map(|content| content.text().unwrap()).collect::<Vec<_>>();
+        assert_eq!(queued(sync.peek_incoming().await), ["first", "second", "third"]);
+        assert!(clipboard.text().is_none(), "nothing is applied on reception");
+
+        let rejected = sync.reject_incoming(1).await.unwrap();
+        assert_eq!(rejected.text().as_deref(), Some("second"));
+        assert!(clipboard.text().is_none());
+        assert_eq!(queued(sync.peek_incoming().await), ["first", "third"]);
+
+        sync.accept_incoming(1).await.unwrap();
+        assert_eq!(clipboard.text().as_deref(), Some("third"));
+        assert_eq!(queued(sync.peek_incoming().await), ["first"]);
+
+        assert!(sync.accept_incoming(1).await.is_err());
+        assert!(sync.reject_incoming(1).await.is_err());
+        assert_eq!(clipboard.text().as_deref(), Some("third"));
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_drops_its_oldest_item() {
+        let sync = ClipboardSync::with_backend(ClipboardOptions::default(), MemoryClipboard::default().connector()).unwrap();
+        for index in 0..=INCOMING_QUEUE_LIMIT {
+            sync.enqueue_incoming(ClipboardContent::new_text(index.to_string())).await;
+        }
+        let queued = sync.peek_incoming().await;
+        assert_eq!(queued.len(), INCOMING_QUEUE_LIMIT);
+        assert_eq!(queued[0].text().as_deref(), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn content_already_on_the_clipboard_is_not_written_again() {
+        let clipboard = MemoryClipboard::default();
+        let sync = ClipboardSync::with_backend(ClipboardOptions::default(), clipboard.connector()).unwrap();
+        // Any write would now fail
+        clipboard.fail_writes(true);
+        clipboard.copy_text("already here");
+        sync.handle_incoming_content(ClipboardContent::new_text("already here".to_string())).await.unwrap();
+        let pixels = vec![0x40; 2 * 2 * 4];
+        clipboard.copy_image(pixels.clone(), 2, 2);
+        sync.handle_incoming_content(ClipboardContent::new_image(pixels, 2, 2)).await.unwrap();
+        assert!(sync.handle_incoming_content(ClipboardContent::new_text("new".to_string())).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_failed_write_is_not_taken_for_applied_content() {
+        let (sync, clipboard, mut published) =
+            monitored(ClipboardOptions { resend_window: Duration::from_secs(60), ..Default::default() }).await;
+        clipboard.fail_writes(true);
+        assert!(sync.handle_incoming_content(ClipboardContent::new_text("hello".to_string())).await.is_err());
+        assert!(sync.last_received.lock().await.is_none());
+
+        // The user copying it themselves is a copy like any other, not an echo
+        clipboard.fail_writes(false);
+        clipboard.copy_text("hello");
+        assert_eq!(next_text(&mut published).await, "hello");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn an_applied_image_is_not_published_back() {
+        let (sync, clipboard, mut published) =
+            monitored(ClipboardOptions { resend_window: Duration::from_secs(60), ..Default::default() }).await;
+        let pixels = vec![0x80; 2 * 2 * 4];
+        sync.handle_incoming_content(ClipboardContent::new_image(pixels.clone(), 2, 2)).await.unwrap();
+        tokio::time::sleep(POLL * 10).await;
+        assert!(published.try_recv().is_err(), "the applied image was published back");
+
+        // Text copied over it afterwards is a copy
+        clipboard.copy_text("after the image");
+        assert_eq!(next_text(&mut published).await, "after the image");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_applies_and_copies_are_each_published_at_most_once() {
+        let (sync, clipboard, mut published) =
+            monitored(ClipboardOptions { resend_window: Duration::from_secs(60), ..Default::default() }).await;
+        let applies: Vec<_> = (0..50)
+            .map(|index| {
+                let sync = sync.clone();
+                tokio::spawn(async move { sync.handle_incoming_content(ClipboardContent::new_text(format!("received {index}"))).await })
+            })
+            .collect();
+        let copies = tokio::spawn({
+            let clipboard = clipboard.clone();
+            async move {
+                for index in 0..20 {
+                    clipboard.copy_text(&format!("copied {index}"));
+                    tokio::time::sleep(POLL / 2).await;
+                }
+            }
+        });
+        for apply in applies {
+            apply.await.unwrap().unwrap();
+        }
+        copies.await.unwrap();
+        clipboard.copy_text("copied last");
+        tokio::time::sleep(POLL * 20).await;
+
+        let mut texts = Vec::new();
+        while let Ok(content) = published.try_recv() {
+            texts.push(content.text().unwrap());
+        }
+        assert!(texts.iter().all(|text| text.starts_with("copied")), "applied content was published back: {texts:?}");
+        let unique: HashSet<&String> = texts.iter().collect();
+        assert_eq!(unique.len(), texts.len(), "published more than once: {texts:?}");
+        assert_eq!(texts.last().map(String::as_str), Some("copied last"));
+    }
+
+    fn spilled(cache: &mut PayloadCache, text: &str) -> ClipboardContent {
+        let mut content = ClipboardContent::new_text(text.to_string());
+        cache.spill(&mut content).unwrap();
+        content
+    }
+
+    fn loads(content: &ClipboardContent) -> bool {
+        content.clone().unspill().is_ok()
+    }
+
+    #[test]
+    fn the_oldest_payloads_go_first_once_over_the_size_limit() {
+        let dir = crate::testing::TempDir::new();
+        let limits = CacheLimits { max_bytes: Some(250), max_age: None };
+        let mut cache = PayloadCache::open(dir.path().to_path_buf(), false, limits);
+        let [oldest, middle, newest] = ["a", "b", "c"].map(|fill| spilled(&mut cache, &fill.repeat(100)));
+        assert_eq!(cache.gc(), 1);
+        assert!(!loads(&oldest));
+        assert!(loads(&middle) && loads(&newest));
+        assert_eq!(cache.counters().misses.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.counters().evictions.load(Ordering::Relaxed), 1);
+
+        // The latest content always stays available
+        cache.set_limits(CacheLimits { max_bytes: Some(10), max_age: None });
+        assert_eq!(cache.gc(), 1);
+        assert!(!loads(&middle));
+        assert!(loads(&newest));
+    }
+
+    #[test]
+    fn pinned_payloads_outlast_the_limits_until_released() {
+        let dir = crate::testing::TempDir::new();
+        let limits = CacheLimits { max_bytes: Some(1), max_age: Some(Duration::ZERO) };
+        let mut cache = PayloadCache::open(dir.path().to_path_buf(), false, limits);
+        let mut queued = spilled(&mut cache, "waiting for /accept");
+        // Retained before it was handed on to be applied, so not pinned
+        let retained = queued.clone();
+        queued.pin_spilled();
+        let expired = spilled(&mut cache, "nobody waits for this");
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(cache.gc(), 1);
+        assert!(loads(&queued) && loads(&retained));
+        assert!(!loads(&expired));
+
+        // Once applied, nothing pins it and it is reclaimed like the rest
+        drop(queued);
+        assert_eq!(cache.gc(), 1);
+        assert!(!loads(&retained));
+    }
+
+    #[test]
+    fn payloads_nothing_refers_to_are_dropped() {
+        let dir = crate::testing::TempDir::new();
+        let mut cache = PayloadCache::open(dir.path().to_path_buf(), false, CacheLimits::default());
+        drop(spilled(&mut cache, "gone"));
+        let kept = spilled(&mut cache, "kept");
+        assert_eq!(cache.gc(), 0, "not an eviction");
+        assert_eq!(cache.counters().entries.load(Ordering::Relaxed), 1);
+        assert!(loads(&kept));
+    }
+
+    #[tokio::test]
+    async fn queued_content_survives_the_cache_until_accepted() {
+        let dir = crate::testing::TempDir::new();
+        let limits = CacheLimits { max_bytes: Some(1), max_age: Some(Duration::ZERO) };
+        let mut cache = PayloadCache::open(dir.path().to_path_buf(), false, limits);
+        let clipboard = MemoryClipboard::default();
+        let sync = ClipboardSync::with_backend(ClipboardOptions { queue_incoming: true, ..Default::default() }, clipboard.connector())
+            .unwrap();
+        let content = spilled(&mut cache, "large and queued");
+        sync.receive_content(content.clone()).await.unwrap();
+        drop(content);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        cache.gc();
+
+        sync.accept_incoming(0).await.unwrap();
+        assert_eq!(clipboard.text().as_deref(), Some("large and queued"));
+    }
+
+    #[test]
+    fn spilled_images_are_read_back_and_hashed_without_a_read() {
+        let dir = crate::testing::TempDir::new();
+        let mut cache = PayloadCache::open(dir.path().to_path_buf(), false, CacheLimits::default());
+        let image = ClipboardContent::new_image((0..64 * 64 * 4).map(|i| i as u8).collect(), 64, 64);
+        let mut content = image.clone();
+        // Spilled by the decoder, then adopted by the cache
+        let file = SpillFile::write(cache.dir(), &content.data).unwrap();
+        content.data = Vec::new();
+        cache.adopt(&mut content, file);
+        assert_eq!(content.image().as_deref(), Some(&image.data[..]));
+        assert_eq!(content.sha256().unwrap(), image.sha256().unwrap());
+
+        cache.set_limits(CacheLimits { max_bytes: Some(1), max_age: None });
+        let _newer = spilled(&mut cache, "newer");
+        assert_eq!(cache.gc(), 1);
+        assert!(content.image().is_none());
+        assert_eq!(content.sha256().unwrap(), image.sha256().unwrap());
+    }
+
+    fn devices(content: &ClipboardContent) -> Vec<&str> {
+        content.provenance.iter().map(|hop| hop.device.as_str()).collect()
+    }
+
+    #[test]
+    fn hops_are_recorded_once_per_machine_and_bounded() {
+        let mut content = ClipboardContent::new_text("hello".to_string());
+        content.add_hop("desk".to_string(), 1);
+        content.add_hop("desk".to_string(), 2);
+        content.add_hop("laptop".to_string(), 3);
+        assert_eq!(devices(&content), ["desk", "laptop"]);
+        for index in 0..PROVENANCE_HOPS {
+            content.add_hop(format!("machine-{index}"), 4);
+        }
+        assert_eq!(content.provenance.len(), PROVENANCE_HOPS);
+        assert_eq!(content.provenance.last().unwrap().device, format!("machine-{}", PROVENANCE_HOPS - 1));
+    }
+
+    #[test]
+    fn provenance_names_the_origin_and_every_machine_since() {
+        let mut content = ClipboardContent::new_text("hello".to_string());
+        assert_eq!(content.provenance_note(), None);
+        content.add_hop("home-desktop".to_string(), 1);
+        assert_eq!(content.provenance_note(), None, "received, but never copied again");
+        content.add_hop("work-laptop".to_string(), 2);
+        content.add_hop("tablet".to_string(), 3);
+        assert_eq!(content.provenance_note().as_deref(), Some("via work-laptop, tablet, originally from home-desktop"));
+    }
+
+    #[test]
+    fn provenance_from_peers_is_cleaned_up() {
+        let mut content = ClipboardContent::new_text("hello".to_string());
+        content.provenance = vec![
+            Hop { device: "\u{1b}[31mred".to_string(), timestamp: 1 },
+            Hop { device: "\u{7}".to_string(), timestamp: 2 },
+        ];
+        let note = content.provenance_note().unwrap();
+        assert!(!note.chars().any(char::is_control), "{note:?}");
+        assert_eq!(note, "via ?, originally from [31mred");
+
+        // However many hops a peer sends, only the latest are shown
+        content.provenance = (0..100).map(|index| Hop { device: format!("m{index}"), timestamp: index }).collect();
+        assert_eq!(content.provenance_note().unwrap().matches(", ").count(), PROVENANCE_HOPS - 1);
+    }
+
+    #[tokio::test]
+    async fn copying_received_content_again_carries_on_its_provenance() {
+        let options = Mutex::new(ClipboardOptions { device_name: "laptop".to_string(), ..ClipboardOptions::default() });
+        let focus: Arc<dyn FocusProvider> = Arc::from(crate::focus::platform_provider());
+        let received = Mutex::new(Some(LastReceived {
+            hash: hash_bytes(b"hello"),
+            provenance: vec![Hop { device: "desk".to_string(), timestamp: 1 }],
+        }));
+
+        let mut again = ClipboardContent::new_text("hello".to_string());
+        tag_copy(&mut again, &options, &focus, &received).await;
+        assert_eq!(devices(&again), ["desk", "laptop"]);
+
+        let mut other = ClipboardContent::new_text("something else".to_string());
+        tag_copy(&mut other, &options, &focus, &received).await;
+        assert!(other.provenance.is_empty());
+    }
+
+    /// A clipboard whose backend goes away while `broken` is set, like an X
+    /// server that restarted: every operation fails and so does connecting
+    #[derive(Clone, Default)]
+    struct Flaky {
+        clipboard: MemoryClipboard,
+        broken: Arc<std::sync::atomic::AtomicBool>,
+        connects: Arc<AtomicU64>,
+    }
+
+    impl Flaky {
+        fn connector(&self) -> Connector {
+            let flaky = self.clone();
+            Box::new(move || {
+                flaky.connects.fetch_add(1, Ordering::Relaxed);
+                if flaky.broken() {
+                    return Err(crate::system_clipboard::Error::ClipboardNotSupported);
+                }
+                Ok(Box::new(flaky.clone()))
+            })
+        }
+
+        fn broken(&self) -> bool {
+            self.broken.load(Ordering::Relaxed)
+        }
+
+        fn set_broken(&self, broken: bool) {
+            self.broken.store(broken, Ordering::Relaxed);
+        }
+
+        fn check(&self) -> Result<(), crate::system_clipboard::Error> {
+            match self.broken() {
+                true => Err(crate::system_clipboard::Error::Unknown { description: "connection lost".to_string() }),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl SystemClipboard for Flaky {
+        fn get_text(&mut self) -> Result<String, crate::system_clipboard::Error> {
+            self.check()?;
+            self.clipboard.get_text()
+        }
+
+        fn get_image(&mut self) -> Result<crate::system_clipboard::ImageData<'static>, crate::system_clipboard::Error> {
+            self.check()?;
+            self.clipboard.get_image()
+        }
+
+        fn get_files(&mut self) -> Result<Vec<PathBuf>, crate::system_clipboard::Error> {
+            self.check()?;
+            self.clipboard.get_files()
+        }
+
+        fn set_text(&mut self, text: String) -> Result<(), crate::system_clipboard::Error> {
+            self.check()?;
+            self.clipboard.set_text(text)
+        }
+
+        fn set_image(&mut self, image: crate::system_clipboard::ImageData<'static>) -> Result<(), crate::system_clipboard::Error> {
+            self.check()?;
+            self.clipboard.set_image(image)
+        }
+
+        fn set_files(&mut self, paths: &[PathBuf]) -> Result<(), crate::system_clipboard::Error> {
+            self.check()?;
+            self.clipboard.set_files(paths)
+        }
+    }
+
+    #[test]
+    fn a_failing_backend_is_re_created_after_repeated_errors() {
+        let flaky = Flaky::default();
+        let mut backend = Backend::new(flaky.connector()).unwrap();
+        flaky.set_broken(true);
+        for _ in 0..MAX_BACKEND_FAILURES {
+            assert!(backend.with(|clipboard| clipboard.get_text()).unwrap().is_err());
+        }
+        assert!(backend.handle.is_none());
+
+        // Re-creating fails while the backend is gone, then backs off
+        assert!(backend.with(|clipboard| clipboard.get_text()).is_none());
+        assert_eq!(backend.backoff, RECONNECT_BACKOFF_MIN * 2);
+        let connects = flaky.connects.load(Ordering::Relaxed);
+        assert!(backend.with(|clipboard| clipboard.get_text()).is_none());
+        assert_eq!(flaky.connects.load(Ordering::Relaxed), connects, "retried within the backoff");
+
+        flaky.set_broken(false);
+        flaky.clipboard.copy_text("back");
+        backend.retry_at = Instant::now();
+        assert_eq!(backend.with(|clipboard| clipboard.get_text()).unwrap().unwrap(), "back");
+        assert_eq!((backend.generation, backend.backoff), (1, RECONNECT_BACKOFF_MIN));
+    }
+
+    #[test]
+    fn errors_that_are_not_the_backends_fault_keep_the_handle() {
+        let flaky = Flaky::default();
+        let mut backend = Backend::new(flaky.connector()).unwrap();
+        for _ in 0..MAX_BACKEND_FAILURES * 2 {
+            // An empty clipboard
+            assert!(backend.with(|clipboard| clipboard.get_text()).unwrap().is_err());
+        }
+        assert!(backend.handle.is_some());
+        assert_eq!(flaky.connects.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn monitoring_resumes_once_a_failing_backend_recovers() {
+        let flaky = Flaky::default();
+        let options = ClipboardOptions { poll: Schedule { min: POLL, max: POLL, backoff: 1.0 }, ..Default::default() };
+        let sync = ClipboardSync::with_backend(options, flaky.connector()).unwrap();
+        let (tx, mut published) = mpsc::unbounded_channel();
+        sync.start_monitoring(move |content| {
+            let _ = tx.send(content);
+        })
+        .await
+        .unwrap();
+        flaky.clipboard.copy_text("before");
+        assert_eq!(next_text(&mut published).await, "before");
+
+        flaky.set_broken(true);
+        tokio::time::sleep(POLL * 10).await;
+        // Whatever is there once it is back wasn't copied by the user
+        flaky.clipboard.copy_text("restored by a clipboard manager");
+        flaky.set_broken(false);
+        tokio::time::sleep(RECONNECT_BACKOFF_MIN * 2).await;
+        assert!(published.try_recv().is_err(), "content found on recovery was published");
+
+        flaky.clipboard.copy_text("after");
+        assert_eq!(next_text(&mut published).await, "after");
+        sync.receive_content(ClipboardContent::new_text("applied".to_string())).await.unwrap();
+        assert_eq!(flaky.clipboard.text().as_deref(), Some("applied"));
+    }
+
+    #[test]
+    fn log_lines_show_a_one_line_preview_unless_asked_for_everything() {
+        let secret = format!("-----BEGIN KEY-----\n{}\n-----END KEY-----", "A".repeat(100));
+        let content = ClipboardContent::new_text(secret.clone());
+        let logged = loggable(&content, false);
+        assert!(!logged.contains('\n'));
+        assert!(logged.starts_with("-----BEGIN KEY----- AAAA"));
+        assert!(logged.ends_with(&format!("… ({} chars)", secret.len())), "{logged}");
+        assert_eq!(loggable(&content, true), secret);
+
+        assert_eq!(loggable_text("short  and\tsweet", false), "short and sweet");
+        let image = ClipboardContent::new_image(vec![0; 16], 2, 2);
+        assert_eq!(loggable(&image, true), "2x2 image (16 bytes)");
+    }
+
+    /// Reports a fixed window title, as `xdotool` would
+    struct Window(&'static str);
+
+    impl FocusProvider for Window {
+        fn focused_window(&self) -> Option<String> {
+            crate::focus::label(self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn copies_are_labelled_with_the_focused_window_when_enabled() {
+        let focus: Arc<dyn FocusProvider> = Arc::new(Window("  notes.txt -\n Editor "));
+        let received = Mutex::new(None);
+        let mut content = ClipboardContent::new_text("hello".to_string());
+        tag_copy(&mut content, &Mutex::new(ClipboardOptions::default()), &focus, &received).await;
+        assert_eq!(content.source_label, None);
+
+        let options = Mutex::new(ClipboardOptions { source_label: true, ..ClipboardOptions::default() });
+        tag_copy(&mut content, &options, &focus, &received).await;
+        assert_eq!(content.source_label.as_deref(), Some("notes.txt - Editor"));
+
+        let blank: Arc<dyn FocusProvider> = Arc::new(Window(" \n"));
+        tag_copy(&mut content, &options, &blank, &received).await;
+        assert_eq!(content.source_label, None);
+    }
+
+    #[test]
+    fn line_breaks_are_joined_or_stripped() {
+        let text = "one\r\ntwo\n\nthree\n";
+        assert!(matches!(replace_newlines(text, NewlineMode::None), Cow::Borrowed(_)));
+        assert_eq!(replace_newlines(text, NewlineMode::Space), "one two three");
+        assert_eq!(replace_newlines(text, NewlineMode::Strip), "onetwothree");
+        assert!(matches!(replace_newlines("one line", NewlineMode::Space), Cow::Borrowed(_)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rewritten_text_is_not_published_back() {
+        let options = ClipboardOptions { newlines: NewlineMode::Space, resend_window: Duration::from_secs(60), ..Default::default() };
+        let (sync, clipboard, mut published) = monitored(options).await;
+        sync.handle_incoming_content(ClipboardContent::new_text("user\npassword\n".to_string())).await.unwrap();
+        assert_eq!(clipboard.text().as_deref(), Some("user password"));
+        tokio::time::sleep(POLL * 10).await;
+        assert!(published.try_recv().is_err(), "the rewritten text was published back");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_scaled_image_is_not_published_back() {
+        let options = ClipboardOptions { image_scale: 2.0, resend_window: Duration::from_secs(60), ..Default::default() };
+        let (sync, clipboard, mut published) = monitored(options).await;
+        sync.handle_incoming_content(ClipboardContent::new_image(vec![0x80; 2 * 2 * 4], 2, 2)).await.unwrap();
+        assert_eq!(clipboard.image().map(|image| (image.width, image.height)), Some((4, 4)));
+        tokio::time::sleep(POLL * 10).await;
+        assert!(published.try_recv().is_err(), "the scaled image was published back");
+        // Copying it again later is recognized as the received image
+        let on_clipboard = clipboard.image().unwrap().bytes.into_owned();
+        assert_eq!(sync.last_received.lock().await.as_ref().map(|last| last.hash), Some(hash_bytes(&on_clipboard)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn an_rgb_image_is_not_published_back_as_rgba() {
+        let (sync, _clipboard, mut published) =
+            monitored(ClipboardOptions { resend_window: Duration::from_secs(60), ..Default::default() }).await;
+        let rgb = ClipboardContent { channels: Some(3), ..ClipboardContent::new_image(vec![0x80; 2 * 2 * 3], 2, 2) };
+        sync.handle_incoming_content(rgb).await.unwrap();
+        tokio::time::sleep(POLL * 10).await;
+        assert!(published.try_recv().is_err(), "the normalized image was published back");
+    }
+}
@@ -0,0 +1,255 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::core::upgrade::{InboundConnectionUpgrade, OutboundConnectionUpgrade, UpgradeInfo};
+use libp2p::yamux;
+use log::debug;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+/// Protocol name of yamux running over a deflate-compressed connection
+const PROTOCOL: &str = "/deflate-yamux/1.0.0";
+/// Compressed bytes buffered before writes start applying backpressure
+const MAX_PENDING_OUTPUT: usize = 64 * 1024;
+/// Size of the read buffer for compressed input
+const READ_BUFFER: usize = 16 * 1024;
+
+/// Multiplexer upgrade that runs yamux on top of a deflate-compressed stream.
+///
+/// It is offered ahead of plain yamux during multistream-select negotiation,
+/// so peers without compression (or with it disabled, in which case this
+/// upgrade advertises no protocol at all) fall back to uncompressed yamux.
+#[derive(Debug, Clone)]
+pub struct DeflateYamux {
+    enabled: bool,
+    yamux: yamux::Config,
+}
+
+impl DeflateYamux {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            yamux: yamux::Config::default(),
+        }
+    }
+}
+
+impl UpgradeInfo for DeflateYamux {
+    type Info = &'static str;
+    type InfoIter = Option<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.enabled.then_some(PROTOCOL)
+    }
+}
+
+impl<C> InboundConnectionUpgrade<C> for DeflateYamux
+where
+    C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    type Output = yamux::Muxer<DeflateStream<C>>;
+    type Error = io::Error;
+    type Future = futures::future::Ready<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, socket: C, _: Self::Info) -> Self::Future {
+        debug!("Negotiated transport compression on inbound connection");
+        self.yamux.upgrade_inbound(DeflateStream::new(socket), "/yamux/1.0.0")
+    }
+}
+
+impl<C> OutboundConnectionUpgrade<C> for DeflateYamux
+where
+    C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    type Output = yamux::Muxer<DeflateStream<C>>;
+    type Error = io::Error;
+    type Future = futures::future::Ready<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_outbound(self, socket: C, _: Self::Info) -> Self::Future {
+        debug!("Negotiated transport compression on outbound connection");
+        self.yamux.upgrade_outbound(DeflateStream::new(socket), "/yamux/1.0.0")
+    }
+}
+
+/// Byte stream compressing everything written to it with raw deflate and
+/// decompressing everything read. Flushing emits a deflate sync flush so the
+/// peer can decode everything written so far without waiting for more data.
+pub struct DeflateStream<S> {
+    inner: S,
+    compress: Compress,
+    decompress: Decompress,
+    /// Compressed bytes not yet written to `inner`
+    output: Vec<u8>,
+    /// Whether data was written since the last sync flush
+    unflushed: bool,
+    /// Compressed bytes read from `inner`, consumed from `input_pos`
+    input: Vec<u8>,
+    input_pos: usize,
+}
+
+impl<S> DeflateStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            compress: Compress::new(Compression::fast(), false),
+            decompress: Decompress::new(false),
+            output: Vec::new(),
+            unflushed: false,
+            input: Vec::new(),
+            input_pos: 0,
+        }
+    }
+
+    /// Run the compressor over `data`, appending to the output buffer
+    fn compress(&mut self, mut data: &[u8], flush: FlushCompress) -> io::Result<()> {
+        loop {
+            self.output.reserve(data.len() / 2 + 64);
+            let before_in = self.compress.total_in();
+            let before_out = self.compress.total_out();
+            self.compress
+                .compress_vec(data, &mut self.output, flush)
+                .map_err(io::Error::other)?;
+            let consumed = (self.compress.total_in() - before_in) as usize;
+            let produced = (self.compress.total_out() - before_out) as usize;
+            data = &data[consumed..];
+
+            // Done once all input is consumed and the compressor had room to
+            // spare, meaning it has nothing left to emit for this flush mode
+            if data.is_empty() && (self.output.len() < self.output.capacity() || produced == 0) {
+                return Ok(());
+            }
+            if consumed == 0 && produced == 0 {
+                return Err(io::Error::other("deflate compressor stalled"));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> DeflateStream<S> {
+    /// Write buffered compressed output to the inner stream
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.output.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.output))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.output.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            let before_in = this.decompress.total_in();
+            let before_out = this.decompress.total_out();
+            this.decompress
+                .decompress(&this.input[this.input_pos..], buf, FlushDecompress::None)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let consumed = (this.decompress.total_in() - before_in) as usize;
+            let produced = (this.decompress.total_out() - before_out) as usize;
+            this.input_pos += consumed;
+            if produced > 0 {
+                return Poll::Ready(Ok(produced));
+            }
+            if this.input_pos < this.input.len() {
+                if consumed == 0 {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "deflate stream stalled")));
+                }
+                continue;
+            }
+
+            // Everything buffered was consumed without output, read more
+            this.input.resize(READ_BUFFER, 0);
+            this.input_pos = 0;
+            let read = match Pin::new(&mut this.inner).poll_read(cx, &mut this.input) {
+                Poll::Ready(Ok(read)) => read,
+                other => {
+                    this.input.clear();
+                    return other;
+                }
+            };
+            this.input.truncate(read);
+            if read == 0 {
+                return Poll::Ready(Ok(0));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.output.len() >= MAX_PENDING_OUTPUT {
+            ready!(this.poll_drain(cx))?;
+        }
+        this.compress(buf, FlushCompress::None)?;
+        this.unflushed = true;
+        // Opportunistically push data out; leftovers go out on the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.unflushed {
+            this.compress(&[], FlushCompress::Sync)?;
+            this.unflushed = false;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::io::{AsyncReadExt, AsyncWriteExt, Cursor};
+
+    #[test]
+    fn nothing_is_offered_while_disabled() {
+        assert_eq!(DeflateYamux::new(false).protocol_info(), None);
+        assert_eq!(DeflateYamux::new(true).protocol_info(), Some(PROTOCOL));
+    }
+
+    #[test]
+    fn written_data_is_compressed_and_read_back_after_each_flush() {
+        let text = "clipboard ".repeat(1000);
+        let mut writer = DeflateStream::new(Cursor::new(Vec::new()));
+        block_on(async {
+            writer.write_all(text.as_bytes()).await.unwrap();
+            writer.flush().await.unwrap();
+            writer.write_all(b"more").await.unwrap();
+            writer.flush().await.unwrap();
+        });
+        let compressed = writer.inner.into_inner();
+        assert!(compressed.len() < text.len() / 10, "{} bytes", compressed.len());
+
+        let mut reader = DeflateStream::new(Cursor::new(compressed));
+        let mut read = Vec::new();
+        block_on(reader.read_to_end(&mut read)).unwrap();
+        assert_eq!(read, format!("{text}more").into_bytes());
+    }
+
+    #[test]
+    fn corrupt_input_is_an_error() {
+        let mut reader = DeflateStream::new(Cursor::new(vec![0xff; 64]));
+        let mut read = Vec::new();
+        let error = block_on(reader.read_to_end(&mut read)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,558 @@
+use crate::{bridge::{Bridge, Membership}, clipboard::{ContentType, NewlineMode}, imaging, interfaces::Subnet, profile::Profile, secrets, security::Security, Args};
+use anyhow::{bail, Context, Result};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches};
+use libp2p::PeerId;
+use log::{info, warn};
+use serde::Deserialize;
+use std::ffi::OsString;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// Config file looked up in the profile directory when `--config` is not given
+pub const CONFIG_FILE: &str = "config.toml";
+/// Least `--max-chat-bytes`, below which long commands would be cut off
+const MIN_CHAT_BYTES: usize = 1024;
+
+/// A key that takes a single value or a list, like `room = "home"` and
+/// `room = ["home", "work:receive"]`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T: Clone> OneOrMany<T> {
+    fn to_vec(&self) -> Vec<T> {
+        match self {
+            OneOrMany::One(value) => vec![value.clone()],
+            OneOrMany::Many(values) => values.clone(),
+        }
+    }
+}
+
+/// Settings read from a TOML config file. Keys are named like the command line
+/// flags, and flags given on the command line win over the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    // Applied on reload
+    latency_warn_ms: Option<u64>,
+    conflict_window_ms: Option<u64>,
+    subscription_check_secs: Option<u64>,
+    slow_op_ms: Option<u64>,
+    bandwidth_cap: Option<u64>,
+    max_peers_for_clipboard: Option<usize>,
+    max_image_bytes: Option<usize>,
+    reject_text_containing: Option<Vec<String>>,
+    no_receipts: Option<bool>,
+    max_chat_bytes: Option<usize>,
+    image_scale: Option<f32>,
+    queue_incoming: Option<bool>,
+    resend_window: Option<u64>,
+    poll_min_ms: Option<u64>,
+    poll_max_ms: Option<u64>,
+    poll_backoff: Option<f32>,
+    log_content: Option<bool>,
+    source_label: Option<bool>,
+    replace_newlines: Option<NewlineMode>,
+    download_dir: Option<PathBuf>,
+    files_to_clipboard: Option<bool>,
+    image_export_only: Option<bool>,
+    retained_max_age: Option<u64>,
+    elect_retained_offer: Option<bool>,
+    spill_threshold: Option<usize>,
+    cache_max_bytes: Option<u64>,
+    cache_max_age: Option<u64>,
+    image_diffs: Option<bool>,
+    primary_peer: Option<PeerId>,
+    i_am_primary: Option<bool>,
+
+    // Only read at startup, then switched with /hardened
+    hardened: Option<bool>,
+
+    // Only take effect on restart
+    listen_address: Option<IpAddr>,
+    interface: Option<Vec<String>>,
+    allow_subnet: Option<Vec<Subnet>>,
+    beacon: Option<bool>,
+    beacon_port: Option<u16>,
+    beacon_interval: Option<u64>,
+    confirm_delivery: Option<bool>,
+    accept_formats: Option<Vec<ContentType>>,
+    auto_accept_types: Option<Vec<ContentType>>,
+    port: Option<u16>,
+    port_fallback: Option<bool>,
+    clipboard: Option<bool>,
+    ignore_initial_clipboard: Option<bool>,
+    no_flood_publish: Option<bool>,
+    pause_on_screenshare: Option<bool>,
+    no_peer_exchange: Option<bool>,
+    readonly_topics: Option<bool>,
+    room: Option<OneOrMany<Membership>>,
+    bridge: Option<Bridge>,
+    observer: Option<bool>,
+    transport_compression: Option<bool>,
+    security: Option<Security>,
+    metrics_address: Option<SocketAddr>,
+    device_name: Option<String>,
+    peers_file: Option<PathBuf>,
+    address_book_max_age: Option<u64>,
+    audit_log: Option<PathBuf>,
+    audit_include_text: Option<bool>,
+    session_report: Option<PathBuf>,
+    confirm_large: Option<u64>,
+    strict_size: Option<bool>,
+    watch_network: Option<bool>,
+    image_export_dir: Option<PathBuf>,
+    // Secrets, best given as keyring:<entry-name> references
+    identity_seed: Option<String>,
+    identity_passphrase: Option<String>,
+    field_encryption: Option<String>,
+    audit_key: Option<String>,
+}
+
+impl Config {
+    /// Read and validate a config file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Parse and validate the contents of a config file
+    pub fn parse(text: &str) -> Result<Self> {
+        let config: Self = toml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(scale) = self.image_scale
+            && !(scale.is_finite() && scale > 0.0 && scale <= imaging::MAX_SCALE)
+        {
+            bail!("image-scale must be a positive number of at most {}, got {scale}", imaging::MAX_SCALE);
+        }
+        if self.max_chat_bytes.is_some_and(|max| max < MIN_CHAT_BYTES) {
+            bail!("max-chat-bytes must be at least {MIN_CHAT_BYTES}, so console commands still fit");
+        }
+        if self.poll_min_ms == Some(0) {
+            bail!("poll-min-ms must be at least 1");
+        }
+        if let Some(backoff) = self.poll_backoff
+            && !(backoff.is_finite() && backoff >= 1.0)
+        {
+            bail!("poll-backoff must be at least 1, got {backoff}");
+        }
+        if self.primary_peer.is_some() && self.i_am_primary == Some(true) {
+            bail!("primary-peer and i-am-primary cannot both be set");
+        }
+        if self.files_to_clipboard == Some(true) && self.download_dir.is_none() {
+            bail!("files-to-clipboard needs download-dir");
+        }
+        if self.image_export_only == Some(true) && self.image_export_dir.is_none() {
+            bail!("image-export-only needs image-export-dir");
+        }
+        if self.room.is_some() && self.bridge.is_some() {
+            bail!("room and bridge cannot both be set");
+        }
+        if self.audit_include_text == Some(true) && self.audit_log.is_none() {
+            bail!("audit-include-text needs audit-log");
+        }
+        if self.identity_seed.is_some() && self.identity_passphrase.is_some() {
+            bail!("identity-seed and identity-passphrase cannot both be set");
+        }
+        Ok(())
+    }
+
+    /// Fill in every setting that was not given on the command line
+    fn apply(&self, args: &mut Args, matches: &ArgMatches) {
+        macro_rules! fill {
+            ($($field:ident),*) => {$(
+                if let Some(value) = self.$field
+                    && matches.value_source(stringify!($field)) != Some(ValueSource::CommandLine)
+                {
+                    args.$field = value;
+                }
+            )*};
+        }
+        fill!(
+            latency_warn_ms, conflict_window_ms, subscription_check_secs, slow_op_ms, bandwidth_cap, log_content,
+            max_peers_for_clipboard, max_image_bytes, no_receipts, max_chat_bytes, image_scale, queue_incoming, resend_window,
+            files_to_clipboard, retained_max_age, elect_retained_offer, spill_threshold, cache_max_bytes, cache_max_age, image_diffs,
+            listen_address, port, port_fallback, clipboard, ignore_initial_clipboard, no_flood_publish,
+            pause_on_screenshare, no_peer_exchange, readonly_topics, observer, transport_compression, security,
+            address_book_max_age, audit_include_text, source_label, replace_newlines, beacon, beacon_port,
+            beacon_interval, confirm_delivery, confirm_large, strict_size, watch_network, image_export_only,
+            poll_min_ms, poll_max_ms, poll_backoff, hardened
+        );
+        if self.metrics_address.is_some() && args.metrics_address.is_none() {
+            args.metrics_address = self.metrics_address;
+        }
+        if self.device_name.is_some() && args.device_name.is_none() {
+            args.device_name = self.device_name.clone();
+        }
+        if self.download_dir.is_some() && args.download_dir.is_none() {
+            args.download_dir = self.download_dir.clone();
+        }
+        if self.peers_file.is_some() && args.peers_file.is_none() {
+            args.peers_file = self.peers_file.clone();
+        }
+        if self.audit_log.is_some() && args.audit_log.is_none() {
+            args.audit_log = self.audit_log.clone();
+        }
+        if self.session_report.is_some() && args.session_report.is_none() {
+            args.session_report = self.session_report.clone();
+        }
+        if self.image_export_dir.is_some() && args.image_export_dir.is_none() {
+            args.image_export_dir = self.image_export_dir.clone();
+        }
+        // The two identity secrets exclude each other, so either one given
+        // outside the file overrides both in it
+        if args.identity_seed.is_none() && args.identity_passphrase.is_none() {
+            args.identity_seed = self.identity_seed.clone();
+            args.identity_passphrase = self.identity_passphrase.clone();
+        }
+        if self.field_encryption.is_some() && args.field_encryption.is_none() {
+            args.field_encryption = self.field_encryption.clone();
+        }
+        if self.audit_key.is_some() && args.audit_key.is_none() {
+            args.audit_key = self.audit_key.clone();
+        }
+        if let Some(ref interface) = self.interface
+            && matches.value_source("interface") != Some(ValueSource::CommandLine)
+        {
+            args.interface = interface.clone();
+        }
+        if let Some(ref allow_subnet) = self.allow_subnet
+            && matches.value_source("allow_subnet") != Some(ValueSource::CommandLine)
+        {
+            args.allow_subnet = allow_subnet.clone();
+        }
+        if let Some(ref reject_text_containing) = self.reject_text_containing
+            && matches.value_source("reject_text_containing") != Some(ValueSource::CommandLine)
+        {
+            args.reject_text_containing = reject_text_containing.clone();
+        }
+        if let Some(ref accept_formats) = self.accept_formats
+            && matches.value_source("accept_formats") != Some(ValueSource::CommandLine)
+        {
+            args.accept_formats = accept_formats.clone();
+        }
+        if let Some(ref auto_accept_types) = self.auto_accept_types
+            && matches.value_source("auto_accept_types") != Some(ValueSource::CommandLine)
+        {
+            args.auto_accept_types = auto_accept_types.clone();
+        }
+        // A bridge defines its own rooms, so either one on the command line
+        // overrides both in the file
+        let room_on_command_line = ["room", "bridge"]
+            .into_iter()
+            .any(|id| matches.value_source(id) == Some(ValueSource::CommandLine));
+        if !room_on_command_line {
+            if let Some(ref room) = self.room {
+                args.room = room.to_vec();
+            }
+            args.bridge = self.bridge.clone().or(args.bridge.take());
+        }
+        // The two primary settings exclude each other, so either one on the
+        // command line overrides both in the file
+        let primary_on_command_line = ["primary_peer", "i_am_primary"]
+            .into_iter()
+            .any(|id| matches.value_source(id) == Some(ValueSource::CommandLine));
+        if !primary_on_command_line {
+            args.primary_peer = self.primary_peer.or(args.primary_peer);
+            args.i_am_primary = self.i_am_primary.unwrap_or(args.i_am_primary);
+        }
+    }
+}
+
+/// Parse the command line, layering the config file (if any) underneath it
+pub fn parse_args() -> Result<Args> {
+    let matches = Args::command().get_matches();
+    resolve(&matches)
+}
+
+fn resolve(matches: &ArgMatches) -> Result<Args> {
+    let mut args = Args::from_arg_matches(matches)?;
+    if let Some(path) = config_path(&args)? {
+        Config::load(&path)?.apply(&mut args, matches);
+        args.config = Some(path);
+    }
+    validate_args(&args)?;
+    secrets::resolve_args(&mut args, &secrets::OsKeyring)?;
+    Ok(args)
+}
+
+/// Reject settings that contradict each other or can't do anything together.
+/// Clap only sees the command line, so this runs on the settings merged with
+/// the config file, before anything is set up.
+pub fn validate_args(args: &Args) -> Result<()> {
+    if args.observer {
+        if args.i_am_primary {
+            bail!("--observer and --i-am-primary are mutually exclusive: observers never publish");
+        }
+        if args.stdin_clipboard {
+            bail!("--observer and --stdin-clipboard are mutually exclusive: observers never publish");
+        }
+        if args.confirm_delivery {
+            bail!("--confirm-delivery has nothing to confirm with --observer, which never publishes");
+        }
+        if args.queue_incoming {
+            bail!("--queue-incoming has nothing to queue with --observer, which never writes the clipboard");
+        }
+    }
+    if args.max_chat_bytes < MIN_CHAT_BYTES {
+        bail!("--max-chat-bytes must be at least {MIN_CHAT_BYTES}, so console commands still fit");
+    }
+    if !args.auto_accept_types.is_empty() && !args.queue_incoming {
+        bail!("--auto-accept-types needs --queue-incoming, without which everything is applied right away");
+    }
+    if args.i_am_primary && args.queue_incoming {
+        bail!("--queue-incoming has nothing to queue with --i-am-primary, which never applies received content");
+    }
+    if args.primary_peer.is_some() && args.i_am_primary {
+        bail!("--primary-peer and --i-am-primary are mutually exclusive");
+    }
+    if args.stdin_clipboard {
+        if !args.clipboard {
+            bail!("--stdin-clipboard needs --clipboard");
+        }
+        if args.daemon {
+            bail!("--stdin-clipboard and --daemon are mutually exclusive: a daemon doesn't read stdin");
+        }
+        if args.stdio_transport.is_some() {
+            bail!("--stdin-clipboard and --stdio-transport are mutually exclusive: both need stdin");
+        }
+    }
+    #[cfg(feature = "tui")]
+    if args.tui {
+        if args.daemon {
+            bail!("--tui and --daemon are mutually exclusive: a daemon has no terminal");
+        }
+        if args.stdin_clipboard || args.stdio_transport.is_some() {
+            bail!("--tui can't be used with --stdin-clipboard or --stdio-transport, which take over stdin");
+        }
+    }
+    if args.files_to_clipboard && args.download_dir.is_none() {
+        bail!("--files-to-clipboard needs --download-dir");
+    }
+    if args.image_export_only && args.image_export_dir.is_none() {
+        bail!("--image-export-only needs --image-export-dir");
+    }
+    if args.audit_include_text && args.audit_log.is_none() {
+        bail!("--audit-include-text needs --audit-log");
+    }
+    if args.audit_log.is_some() && args.audit_key.is_none() {
+        bail!("--audit-log needs --audit-key");
+    }
+    if args.identity_seed.is_some() && args.identity_passphrase.is_some() {
+        bail!("--identity-seed and --identity-passphrase are mutually exclusive");
+    }
+    Ok(())
+}
+
+/// The explicit `--config` file, or the profile's config file if it exists
+fn config_path(args: &Args) -> Result<Option<PathBuf>> {
+    if let Some(ref path) = args.config {
+        return Ok(Some(path.clone()));
+    }
+    if let Some(ref name) = args.profile {
+        let path = Profile::open(name)?.dir().join(CONFIG_FILE);
+        if path.exists() {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Re-read the config file and apply the settings that can change at runtime.
+/// Nothing is changed if the file fails to load or validate. Settings that
+/// need a restart are reported and left untouched.
+pub fn reload(args: &mut Args) -> Result<()> {
+    reload_from(args, std::env::args_os())
+}
+
+/// [`reload`], layering the file over `command_line` instead of the process's
+fn reload_from<T: Into<OsString> + Clone>(args: &mut Args, command_line: impl IntoIterator<Item = T>) -> Result<()> {
+    let Some(ref path) = args.config else {
+        bail!("No config file in use; start with --config <file> to enable reloading");
+    };
+    // Start from the original command line so keys removed from the file fall
+    // back to their defaults
+    let matches = Args::command()
+        .try_get_matches_from(command_line)
+        .context("Failed to re-parse the command line")?;
+    let mut fresh = Args::from_arg_matches(&matches)?;
+    Config::load(path)?.apply(&mut fresh, &matches);
+    validate_args(&fresh)?;
+
+    macro_rules! restart_only {
+        ($($field:ident),*) => {$(
+            if fresh.$field != args.$field {
+                warn!(
+                    "Config reload: {} changed to {:?} but only takes effect after a restart",
+                    stringify!($field).replace('_', "-"),
+                    fresh.$field
+                );
+            }
+        )*};
+    }
+    restart_only!(
+        listen_address, interface, accept_formats, port, port_fallback, clipboard, ignore_initial_clipboard,
+        no_flood_publish, pause_on_screenshare, no_peer_exchange, readonly_topics, room, bridge, observer, transport_compression, security,
+        metrics_address, device_name, peers_file, address_book_max_age, audit_log, audit_include_text, beacon,
+        beacon_port, beacon_interval, confirm_delivery, session_report, watch_network, image_export_dir
+    );
+
+    args.latency_warn_ms = fresh.latency_warn_ms;
+    args.conflict_window_ms = fresh.conflict_window_ms;
+    args.subscription_check_secs = fresh.subscription_check_secs;
+    args.slow_op_ms = fresh.slow_op_ms;
+    args.bandwidth_cap = fresh.bandwidth_cap;
+    args.max_peers_for_clipboard = fresh.max_peers_for_clipboard;
+    args.max_image_bytes = fresh.max_image_bytes;
+    args.confirm_large = fresh.confirm_large;
+    args.strict_size = fresh.strict_size;
+    args.reject_text_containing = fresh.reject_text_containing;
+    args.allow_subnet = fresh.allow_subnet;
+    args.no_receipts = fresh.no_receipts;
+    args.max_chat_bytes = fresh.max_chat_bytes;
+    args.image_scale = fresh.image_scale;
+    args.queue_incoming = fresh.queue_incoming;
+    args.auto_accept_types = fresh.auto_accept_types;
+    args.resend_window = fresh.resend_window;
+    args.poll_min_ms = fresh.poll_min_ms;
+    args.poll_max_ms = fresh.poll_max_ms;
+    args.poll_backoff = fresh.poll_backoff;
+    args.log_content = fresh.log_content;
+    args.source_label = fresh.source_label;
+    args.replace_newlines = fresh.replace_newlines;
+    args.download_dir = fresh.download_dir;
+    args.files_to_clipboard = fresh.files_to_clipboard;
+    args.image_export_only = fresh.image_export_only;
+    args.retained_max_age = fresh.retained_max_age;
+    args.elect_retained_offer = fresh.elect_retained_offer;
+    args.spill_threshold = fresh.spill_threshold;
+    args.cache_max_bytes = fresh.cache_max_bytes;
+    args.cache_max_age = fresh.cache_max_age;
+    args.image_diffs = fresh.image_diffs;
+    args.primary_peer = fresh.primary_peer;
+    args.i_am_primary = fresh.i_am_primary;
+    info!("Reloaded configuration from {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stdio;
+    use clap::Parser;
+
+    fn args() -> Args {
+        Args::try_parse_from(["clipboard-sync", "--clipboard"]).unwrap()
+    }
+
+    /// Turns valid arguments into a conflicting combination
+    type Conflict = fn(&mut Args);
+
+    /// Every combination `validate_args` refuses, with part of its message
+    fn conflicts() -> Vec<(Conflict, &'static str)> {
+        vec![
+            (|args| (args.observer, args.i_am_primary) = (true, true), "--i-am-primary"),
+            (|args| (args.observer, args.stdin_clipboard) = (true, true), "--stdin-clipboard"),
+            (|args| (args.observer, args.confirm_delivery) = (true, true), "--confirm-delivery"),
+            (|args| (args.observer, args.queue_incoming) = (true, true), "--queue-incoming"),
+            (|args| args.max_chat_bytes = MIN_CHAT_BYTES - 1, "--max-chat-bytes"),
+            (|args| args.auto_accept_types = vec![ContentType::Text], "--auto-accept-types"),
+            (|args| (args.i_am_primary, args.queue_incoming) = (true, true), "--queue-incoming"),
+            (|args| (args.i_am_primary, args.primary_peer) = (true, Some(libp2p::PeerId::random())), "--primary-peer"),
+            (|args| (args.stdin_clipboard, args.clipboard) = (true, false), "needs --clipboard"),
+            (|args| (args.stdin_clipboard, args.daemon) = (true, true), "--daemon"),
+            (|args| (args.stdin_clipboard, args.stdio_transport) = (true, Some(stdio::Role::Dial)), "--stdio-transport"),
+            (|args| args.files_to_clipboard = true, "--download-dir"),
+            (|args| args.image_export_only = true, "--image-export-dir"),
+            (|args| args.audit_include_text = true, "--audit-log"),
+            (|args| args.audit_log = Some("audit.log".into()), "--audit-key"),
+            (|args| (args.identity_seed, args.identity_passphrase) = (Some("a".into()), Some("b".into())), "--identity-passphrase"),
+        ]
+    }
+
+    #[test]
+    fn each_conflicting_combination_is_refused() {
+        for (index, (conflict, message)) in conflicts().into_iter().enumerate() {
+            let mut args = args();
+            conflict(&mut args);
+            match validate_args(&args) {
+                Ok(()) => panic!("combination {index} was accepted"),
+                Err(e) => assert!(e.to_string().contains(message), "combination {index}: {e}"),
+            }
+        }
+    }
+
+    #[cfg(feature = "tui")]
+    #[test]
+    fn the_dashboard_needs_the_terminal() {
+        for conflict in [|args: &mut Args| args.daemon = true, |args: &mut Args| args.stdin_clipboard = true] {
+            let mut args = args();
+            args.tui = true;
+            conflict(&mut args);
+            assert!(validate_args(&args).is_err());
+        }
+    }
+
+    #[test]
+    fn valid_combinations_pass() {
+        for flags in [
+            &["--clipboard"][..],
+            &["--clipboard", "--observer"],
+            &["--clipboard", "--queue-incoming", "--auto-accept-types", "text"],
+            &["--clipboard", "--i-am-primary", "--confirm-delivery"],
+            &["--clipboard", "--stdin-clipboard"],
+            &["--clipboard", "--files-to-clipboard", "--download-dir", "downloads"],
+            &["--clipboard", "--image-export-only", "--image-export-dir", "exports"],
+            &["--clipboard", "--audit-log", "audit.log", "--audit-key", "secret", "--audit-include-text"],
+            &["--clipboard", "--primary-peer", "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN"],
+        ] {
+            let args = Args::try_parse_from(["clipboard-sync"].iter().chain(flags)).unwrap();
+            assert!(validate_args(&args).is_ok(), "{flags:?}: {:?}", validate_args(&args));
+        }
+    }
+
+    /// A node started with `--config` on a file holding `text`, and its command line
+    fn configured(dir: &crate::testing::TempDir, text: &str) -> (Args, [String; 3]) {
+        let path = dir.path().join(CONFIG_FILE);
+        fs::write(&path, text).unwrap();
+        let command_line = ["clipboard-sync".to_string(), "--config".to_string(), path.display().to_string()];
+        let matches = Args::command().try_get_matches_from(&command_line).unwrap();
+        (resolve(&matches).unwrap(), command_line)
+    }
+
+    #[test]
+    fn a_reload_applies_runtime_settings_and_leaves_the_rest() {
+        let dir = crate::testing::TempDir::new();
+        let (mut args, command_line) = configured(&dir, "resend-window = 5\nlatency-warn-ms = 100\n");
+        let default_latency_warn_ms = Args::try_parse_from(["clipboard-sync"]).unwrap().latency_warn_ms;
+        assert_eq!((args.resend_window, args.latency_warn_ms), (5, 100));
+
+        fs::write(args.config.as_ref().unwrap(), "resend-window = 7\nport = 4242\n").unwrap();
+        reload_from(&mut args, &command_line).unwrap();
+        assert_eq!(args.resend_window, 7);
+        // Gone from the file, so back to its default
+        assert_eq!(args.latency_warn_ms, default_latency_warn_ms);
+        // Needs a restart
+        assert_eq!(args.port, crate::PORT_TCP);
+    }
+
+    #[test]
+    fn an_invalid_file_is_rejected_on_reload_without_changing_anything() {
+        let dir = crate::testing::TempDir::new();
+        let (mut args, command_line) = configured(&dir, "resend-window = 5\n");
+        for invalid in ["resend-window = 7\npoll-min-ms = 0\n", "resend-window = 7\nno-such-key = 1\n", "resend-window = "] {
+            fs::write(args.config.as_ref().unwrap(), invalid).unwrap();
+            assert!(reload_from(&mut args, &command_line).is_err(), "{invalid:?} was accepted");
+            assert_eq!(args.resend_window, 5);
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use crate::clipboard::ClipboardContent;
+
+/// The latest content copied on this machine
+#[derive(Debug)]
+struct LocalCopy {
+    timestamp: u64,
+    /// Empty if the payload could not be hashed
+    digest: String,
+    preview: String,
+}
+
+/// Detects received content overwriting something copied locally at nearly
+/// the same time. Last writer wins, so without a warning the local copy would
+/// be lost silently.
+#[derive(Debug, Default)]
+pub struct ConflictDetector {
+    last_local: Option<LocalCopy>,
+}
+
+impl ConflictDetector {
+    /// Remember content copied on this machine
+    pub fn local_copy(&mut self, content: &ClipboardContent) {
+        self.last_local = Some(LocalCopy {
+            timestamp: content.timestamp,
+            digest: content.sha256().unwrap_or_default(),
+            preview: content.preview(),
+        });
+    }
+
+    /// Check received content that is about to be applied. Returns the
+    /// preview of the local copy it overwrites if the two were copied within
+    /// `window_ms` of each other and differ. Each local copy conflicts once.
+    pub fn check(&mut self, incoming: &ClipboardContent, window_ms: u64) -> Option<String> {
+        let local = self.last_local.as_ref()?;
+        if window_ms == 0
+            || incoming.timestamp.abs_diff(local.timestamp) > window_ms
+            || incoming.sha256().is_ok_and(|digest| digest == local.digest)
+        {
+            return None;
+        }
+        self.last_local.take().map(|local| local.preview)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn copied(text: &str, timestamp: u64) -> ClipboardContent {
+        ClipboardContent { timestamp, ..ClipboardContent::new_text(text.to_string()) }
+    }
+
+    #[test]
+    fn near_simultaneous_copies_conflict_once() {
+        let mut detector = ConflictDetector::default();
+        detector.local_copy(&copied("mine", 10_000));
+        assert_eq!(detector.check(&copied("theirs", 10_400), 1_000).as_deref(), Some("mine"));
+        assert_eq!(detector.check(&copied("theirs again", 10_500), 1_000), None);
+    }
+
+    #[test]
+    fn ordered_or_identical_copies_do_not_conflict() {
+        let mut detector = ConflictDetector::default();
+        assert_eq!(detector.check(&copied("theirs", 10_000), 1_000), None);
+        detector.local_copy(&copied("mine", 10_000));
+        assert_eq!(detector.check(&copied("theirs", 12_000), 1_000), None);
+        assert_eq!(detector.check(&copied("mine", 10_100), 1_000), None);
+        // A window of zero turns detection off
+        assert_eq!(detector.check(&copied("theirs", 10_100), 0), None);
+    }
+}
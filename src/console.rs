@@ -0,0 +1,314 @@
+use crate::capabilities::Capabilities;
+use crate::control::{NodeEvent, PauseCause};
+use crate::peer_label;
+use crate::progress;
+use libp2p::PeerId;
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tokio::sync::broadcast;
+
+/// Moves the cursor to the start of the line and clears it
+const CLEAR_LINE: &str = "\r\x1b[2K";
+/// Longest progress line drawn, so it never wraps on a standard terminal
+const PROGRESS_WIDTH: usize = 79;
+
+/// Set while a progress line is on the terminal, which the next log line
+/// clears first
+static PROGRESS_SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Log target in plain mode on a terminal: stderr, clearing the progress
+/// line before each log line so the two don't run together
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stderr = io::stderr().lock();
+        if PROGRESS_SHOWN.swap(false, Ordering::Relaxed) {
+            stderr.write_all(CLEAR_LINE.as_bytes())?;
+        }
+        stderr.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// A line typed at the console, or how long it was when over the limit
+pub enum Line {
+    Text(String),
+    TooLong(usize),
+}
+
+/// Reads console lines holding at most a given number of bytes in memory.
+/// The rest of a longer line is skipped as it arrives, so pasting a huge
+/// blob costs no more than the limit. Cancel safe, like
+/// `AsyncBufReadExt::lines`: a partial line stays here until it completes.
+pub struct LineReader<R> {
+    inner: R,
+    line: Vec<u8>,
+    /// Bytes of the current line so far once it went over the limit
+    skipped: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> LineReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, line: Vec::new(), skipped: 0 }
+    }
+
+    /// The next line without its line ending, [`Line::TooLong`] if it holds
+    /// more than `limit` bytes, or `None` at the end of input
+    pub async fn next_line(&mut self, limit: usize) -> io::Result<Option<Line>> {
+        loop {
+            let available = self.inner.fill_buf().await?;
+            if available.is_empty() {
+                if self.line.is_empty() && self.skipped == 0 {
+                    return Ok(None);
+                }
+                return self.finish(limit).map(Some);
+            }
+            let (part, used, complete) = match available.iter().position(|&byte| byte == b'\n') {
+                Some(end) => (&available[..end], end + 1, true),
+                None => (available, available.len(), false),
+            };
+            // One byte over, for a carriage return that `finish` takes off
+            if self.skipped > 0 || self.line.len() + part.len() > limit + 1 {
+                self.skipped += self.line.len() + part.len();
+                self.line = Vec::new();
+            } else {
+                self.line.extend_from_slice(part);
+            }
+            self.inner.consume(used);
+            if complete {
+                return self.finish(limit).map(Some);
+            }
+        }
+    }
+
+    fn finish(&mut self, limit: usize) -> io::Result<Line> {
+        if self.skipped > 0 {
+            return Ok(Line::TooLong(std::mem::take(&mut self.skipped)));
+        }
+        let mut line = std::mem::take(&mut self.line);
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.len() > limit {
+            return Ok(Line::TooLong(line.len()));
+        }
+        String::from_utf8(line)
+            .map(Line::Text)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))
+    }
+}
+
+/// Log node events as the console's account of what the node does. This is
+/// an ordinary subscriber: device names come from the identify events it
+/// sees, like they would for any other. With `live_progress` transfer
+/// progress is redrawn in place on one line, which needs [`LogWriter`] as
+/// the log target; otherwise it is logged every tenth of a transfer.
+pub fn spawn(mut events: broadcast::Receiver<NodeEvent>, live_progress: bool) {
+    tokio::spawn(async move {
+        let mut device_names = HashMap::new();
+        let mut transfers = Transfers { live: live_progress, ..Transfers::default() };
+        loop {
+            match events.recv().await {
+                Ok(event) => log_event(&mut device_names, &mut transfers, event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Console fell behind, {missed} node events not logged");
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+/// Transfers in progress, as the console shows them
+#[derive(Default)]
+struct Transfers {
+    /// Redraw one line in place rather than logging every tenth
+    live: bool,
+    /// Latest state of each transfer by id, in the order they started
+    lines: BTreeMap<u64, String>,
+    /// Tenths of each transfer last logged, without `live`
+    logged: HashMap<u64, u64>,
+}
+
+impl Transfers {
+    fn progress(&mut self, id: u64, label: &str, done: u64, total: u64, rate: u64, eta_secs: Option<u64>) {
+        let line = format!("#{id} {label}: {}", progress::describe(done, total, rate, eta_secs));
+        if self.live {
+            self.lines.insert(id, line);
+            self.draw();
+            return;
+        }
+        let tenths = done * 10 / total.max(1);
+        if tenths > self.logged.get(&id).copied().unwrap_or_default() {
+            self.logged.insert(id, tenths);
+            info!("Transfer {line}");
+        }
+    }
+
+    fn finished(&mut self, id: u64, label: &str, bytes: u64, elapsed_ms: u64, error: Option<String>) {
+        self.lines.remove(&id);
+        self.logged.remove(&id);
+        let summary = progress::summarize(bytes, elapsed_ms);
+        match error {
+            None => info!("Transfer #{id} {label} done: {summary}"),
+            Some(error) => warn!("Transfer #{id} {label} stopped after {summary}: {error}"),
+        }
+        // Logging cleared the line, so bring back the transfers still going
+        if self.live && !self.lines.is_empty() {
+            self.draw();
+        }
+    }
+
+    /// Every transfer in progress on one line, replacing what was there
+    fn draw(&self) {
+        let line: String = self.lines.values().cloned().collect::<Vec<_>>().join(" | ").chars().take(PROGRESS_WIDTH).collect();
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "{CLEAR_LINE}{line}");
+        let _ = stderr.flush();
+        PROGRESS_SHOWN.store(true, Ordering::Relaxed);
+    }
+}
+
+fn log_event(device_names: &mut HashMap<PeerId, String>, transfers: &mut Transfers, event: NodeEvent) {
+    match event {
+        NodeEvent::PeerConnected { peer, endpoint } => {
+            info!("Connected to: {}", peer_label(device_names, &peer));
+            debug!("Endpoint: {endpoint:?}");
+        }
+        NodeEvent::PeerDisconnected { peer, endpoint, cause } => {
+            match cause {
+                Some(cause) => info!("Disconnected from: {}, cause: {cause}", peer_label(device_names, &peer)),
+                None => info!("Disconnected from: {}", peer_label(device_names, &peer)),
+            }
+            debug!("Endpoint: {endpoint:?}");
+        }
+        NodeEvent::PeerIdentified { peer, agent_version, listen_addrs } => {
+            if let Some(name) = Capabilities::from_agent_version(&agent_version).and_then(|theirs| theirs.device_name) {
+                device_names.insert(peer, name);
+            }
+            info!("Received identify info from {}: {agent_version}, listening on {listen_addrs:?}", peer_label(device_names, &peer));
+        }
+        NodeEvent::ClipboardSent { content_type, size, preview, peers, .. } => {
+            info!("Clipboard content published to {peers} peers");
+            debug!("Sent {content_type:?} content ({size} bytes): {preview}");
+        }
+        NodeEvent::ClipboardReceived { from, content_type, size, preview, source, provenance, .. } => {
+            debug!("{content_type:?} content from {} ({size} bytes): {preview}", peer_label(device_names, &from));
+            if let Some(source) = source {
+                info!("{content_type:?} content from {} was copied in {source}", peer_label(device_names, &from));
+            }
+            if let Some(provenance) = provenance {
+                info!("{content_type:?} content from {} came {provenance}", peer_label(device_names, &from));
+            }
+        }
+        // Logged by the event loop as it decides them, with more detail
+        NodeEvent::ContentDropped { .. } | NodeEvent::ClipboardApplied { .. } => {}
+        NodeEvent::PublishFailed { topic, reason } => error!("Failed to publish to {topic}: {reason}"),
+        NodeEvent::Downgraded { tier, content_type, size } => {
+            info!("Copied {content_type:?} content ({size} bytes) is too large to send as is, downgraded: {tier}")
+        }
+        NodeEvent::TooLarge { from, content_type, size } => warn!(
+            "{} copied {content_type:?} content of {size} bytes, too large to sync here. \
+             Copy it as a file on that machine to transfer it instead",
+            peer_label(device_names, &from)
+        ),
+        NodeEvent::LargeCopyHeld { preview, size, threshold } => info!(
+            "Copied {preview} is {size} bytes, over the --confirm-large limit of {threshold}. Type /confirm to send it"
+        ),
+        NodeEvent::PauseChanged { paused, cause } => match (paused, cause) {
+            (true, PauseCause::Manual) => info!("Clipboard sync paused"),
+            (false, PauseCause::Manual) => info!("Clipboard sync resumed"),
+            (true, PauseCause::ScreenShare) => info!("Screen sharing detected, clipboard sync paused"),
+            (false, PauseCause::ScreenShare) => info!("Screen sharing ended, clipboard sync resumed"),
+        },
+        NodeEvent::DeliveryConfirmed { peer, preview } => {
+            info!("{} confirmed receiving {preview}", peer_label(device_names, &peer));
+        }
+        NodeEvent::DeliveryUnconfirmed { preview, missing } => {
+            let missing: Vec<String> = missing.iter().map(|peer| peer_label(device_names, peer)).collect();
+            warn!(
+                "No delivery confirmation within {}s from {}: {preview}",
+                crate::delivery::TIMEOUT.as_secs(),
+                missing.join(", ")
+            );
+        }
+        NodeEvent::Derived { from, converter, size, preview } => {
+            info!("{converter} found text in the image from {} ({size} bytes): {preview}", peer_label(device_names, &from));
+        }
+        NodeEvent::Conflict { from, local_preview, remote_preview } => warn!(
+            "Conflict: {remote_preview} from {} replaces what you copied at nearly the same time: {local_preview}",
+            peer_label(device_names, &from)
+        ),
+        NodeEvent::TransferProgress { id, label, done, total, rate, eta_secs } => {
+            transfers.progress(id, &label, done, total, rate, eta_secs)
+        }
+        NodeEvent::TransferFinished { id, label, bytes, elapsed_ms, error } => {
+            transfers.finished(id, &label, bytes, elapsed_ms, error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, BufReader};
+
+    const LIMIT: usize = 64 * 1024;
+    const PASTE: u64 = 8 * 1024 * 1024;
+
+    async fn next(reader: &mut LineReader<impl AsyncBufRead + Unpin>) -> Option<Line> {
+        reader.next_line(LIMIT).await.expect("reading from memory never fails")
+    }
+
+    #[tokio::test]
+    async fn a_multi_megabyte_line_is_rejected_without_holding_it() {
+        // Generated as it is read, so only the reader's own buffer holds any of it
+        let input = tokio::io::repeat(b'x').take(PASTE).chain(&b"\r\nhello\n"[..]);
+        let mut reader = LineReader::new(BufReader::new(input));
+        let Some(Line::TooLong(bytes)) = next(&mut reader).await else {
+            panic!("a line over the limit is rejected");
+        };
+        assert_eq!(bytes as u64, PASTE + 1, "the carriage return is part of the skipped line");
+        assert!(reader.line.capacity() <= LIMIT, "holds no more than the limit");
+        assert!(matches!(next(&mut reader).await, Some(Line::Text(line)) if line == "hello"));
+        assert!(next(&mut reader).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_line_of_exactly_the_limit_is_kept() {
+        let mut input = vec![b'y'; LIMIT];
+        input.extend_from_slice(b"\r\n");
+        let mut reader = LineReader::new(BufReader::new(&input[..]));
+        assert!(matches!(next(&mut reader).await, Some(Line::Text(line)) if line.len() == LIMIT));
+    }
+
+    #[tokio::test]
+    async fn one_byte_over_the_limit_is_rejected() {
+        let input = vec![b'y'; LIMIT + 1];
+        let mut reader = LineReader::new(BufReader::new(&input[..]));
+        assert!(matches!(next(&mut reader).await, Some(Line::TooLong(bytes)) if bytes == LIMIT + 1));
+    }
+
+    #[tokio::test]
+    async fn the_last_line_needs_no_line_ending() {
+        let mut reader = LineReader::new(BufReader::new(&b"first\nlast"[..]));
+        assert!(matches!(next(&mut reader).await, Some(Line::Text(line)) if line == "first"));
+        assert!(matches!(next(&mut reader).await, Some(Line::Text(line)) if line == "last"));
+        assert!(next(&mut reader).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_long_last_line_is_still_rejected() {
+        let input = tokio::io::repeat(b'x').take(PASTE);
+        let mut reader = LineReader::new(BufReader::new(input));
+        assert!(matches!(next(&mut reader).await, Some(Line::TooLong(bytes)) if bytes as u64 == PASTE));
+        assert!(next(&mut reader).await.is_none());
+    }
+}
@@ -0,0 +1,413 @@
+use crate::clipboard::{ClipboardContent, ContentType};
+use libp2p::core::ConnectedPoint;
+use libp2p::gossipsub::TopicHash;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+/// Commands accepted by a running node. The console and the tray both drive
+/// the node exclusively through these.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeCommand {
+    /// Stop publishing local changes and applying received content
+    Pause,
+    /// Undo a previous pause
+    Resume,
+    /// Publish the current clipboard content right away
+    SendClipboard,
+    /// Send the current clipboard content to this one peer only
+    SendTo(PeerId),
+    /// Publish the local copy held back by `--confirm-large`
+    ConfirmLarge,
+    /// Print the most recently received clipboard content
+    ShowLastReceived,
+    /// List connected peers
+    ShowPeers,
+    /// List the peers in the address book
+    ShowKnownPeers,
+    /// Print per-peer sync latency statistics
+    ShowStats,
+    /// Print the health of each gossipsub topic
+    ShowStatus,
+    /// List received items waiting in the incoming queue
+    ShowQueue,
+    /// Apply the queued item with this index
+    AcceptIncoming(usize),
+    /// Drop the queued item with this index
+    RejectIncoming(usize),
+    /// Stop every file download in progress
+    CancelDownloads,
+    /// Ask again for the missing chunks of interrupted downloads
+    ResumeTransfers,
+    /// A screen share started (`true`) or ended, from `--pause-on-screenshare`
+    ScreenShare(bool),
+    /// The machine moved to another network, from `--watch-network`
+    NetworkChanged,
+    /// Switch hardened mode on (`true`) or off
+    Hardened(bool),
+    /// Re-read the config file and apply the settings that can change at runtime
+    Reload,
+    /// Show or change which log lines are written
+    Log(LogFilter),
+    /// Print the available console commands
+    Help,
+    /// Shut the node down gracefully
+    Quit,
+}
+
+/// Console commands and what they do, printed by `/help`
+pub const HELP: &[(&str, &str)] = &[
+    ("/pause", "stop publishing and applying clipboard content"),
+    ("/resume", "resume clipboard sync"),
+    ("/push", "publish the current clipboard content now"),
+    ("/send-to <peer-id>", "send the current clipboard content to one peer"),
+    ("/confirm", "send the large copy held by --confirm-large"),
+    ("/last", "show the most recently received content"),
+    ("/peers", "list connected peers"),
+    ("/peers known", "list remembered peers and when they were last seen"),
+    ("/stats", "show per-peer sync latency"),
+    ("/status", "show topic health and time since the last sync"),
+    ("/queue", "list received items waiting to be accepted"),
+    ("/accept <n>", "apply queued item n"),
+    ("/reject <n>", "drop queued item n"),
+    ("/cancel", "stop all file downloads"),
+    ("/resume-transfers", "continue interrupted downloads"),
+    ("/hardened on|off", "refuse unknown inbound peers and local discovery"),
+    ("/reload", "re-read the config file"),
+    ("/log <filter>|status|reset", "change which log lines are shown, like RUST_LOG"),
+    ("/help", "show this list"),
+    ("/quit", "shut down gracefully"),
+];
+
+/// What `/log` and the control socket's `Log` request do with the log filter
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFilter {
+    /// Report the filter in effect
+    Status,
+    /// Go back to the filter given at startup
+    Reset,
+    /// Use this filter, written like `RUST_LOG`
+    Set(String),
+}
+
+impl LogFilter {
+    /// `status`, `reset` or a filter, as given to `/log` and `log`
+    pub fn from_argument(argument: Option<&str>) -> Self {
+        match argument {
+            None | Some("status") => Self::Status,
+            Some("reset") => Self::Reset,
+            Some(spec) => Self::Set(spec.to_string()),
+        }
+    }
+
+    /// Apply to the running logger, returning the filter now in effect
+    pub fn apply(&self) -> anyhow::Result<String> {
+        match self {
+            Self::Status => {}
+            Self::Reset => crate::logging::reset(),
+            Self::Set(spec) => crate::logging::set(spec)?,
+        }
+        Ok(crate::logging::current())
+    }
+}
+
+/// Snapshot of node state shown by front ends
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeStatus {
+    pub peers: usize,
+    /// Currently connected peers
+    pub connected: Vec<PeerId>,
+    pub paused: bool,
+    /// Hardened mode is on
+    pub hardened: bool,
+}
+
+/// Node events buffered for slow subscribers before the oldest are dropped
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Why clipboard sync was paused or resumed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseCause {
+    /// `/pause`, `/resume` or the tray
+    Manual,
+    /// `--pause-on-screenshare` saw a screen share start or end
+    ScreenShare,
+}
+
+/// Why received clipboard content was not applied, in the order the checks
+/// are made
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropReason {
+    /// The sender is backed off for sending bad content
+    Suppressed,
+    /// The content policy rejected it, penalizing the sender
+    Rejected,
+    /// It came from a room we only send to, or none we share
+    NotReceiving,
+    /// `--primary-peer` is set and it came from another peer within the
+    /// conflict window of the primary's latest copy
+    NotPrimary,
+    /// An image diff whose base we don't have, fetched in full instead
+    MissingBase,
+    /// Not one of `--accept-formats`
+    Format,
+    /// Text its author copied later arrived first
+    Superseded,
+    /// Not newer than what we have, or retained content that is too old
+    Stale,
+    /// Its payload is sealed under a `--field-encryption` key we don't have
+    Sealed,
+}
+
+/// What the node does, broadcast from the event loop to every front end: the
+/// console log, the dashboard and anything else holding a receiver.
+///
+/// Events are sent in the order the event loop handles them, and every
+/// subscriber sees that same order. Sending never waits for subscribers: one
+/// that falls more than [`EVENT_CAPACITY`] events behind gets
+/// `RecvError::Lagged` with the number of events it missed, then carries on
+/// with the oldest event still buffered. Only metadata is sent, never
+/// clipboard payloads.
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    /// A connection to a peer was established. There may be several per peer.
+    PeerConnected {
+        peer: PeerId,
+        endpoint: ConnectedPoint,
+    },
+    /// A connection to a peer closed
+    PeerDisconnected {
+        peer: PeerId,
+        endpoint: ConnectedPoint,
+        /// The error that closed it, if it didn't close normally
+        cause: Option<String>,
+    },
+    /// A peer sent its identify info
+    PeerIdentified {
+        peer: PeerId,
+        agent_version: String,
+        listen_addrs: Vec<Multiaddr>,
+    },
+    /// Local clipboard content was published
+    ClipboardSent {
+        content_type: ContentType,
+        size: usize,
+        preview: String,
+        /// Hash of the payload, telling items apart without their content
+        hash: u64,
+        /// Peers subscribed to the topic it went out on
+        peers: usize,
+    },
+    /// Clipboard content arrived from a peer
+    ClipboardReceived {
+        from: PeerId,
+        content_type: ContentType,
+        size: usize,
+        preview: String,
+        /// Window it was copied from, if the sender labelled it
+        source: Option<String>,
+        /// Machines it was copied again on, see [`ClipboardContent::provenance_note`]
+        provenance: Option<String>,
+        hash: u64,
+        /// When it was copied, on our clock
+        timestamp: u64,
+        /// Pushed to us in a direct request rather than over gossipsub
+        direct: bool,
+    },
+    /// Clipboard content from a peer was not applied
+    ContentDropped {
+        from: PeerId,
+        content_type: ContentType,
+        size: usize,
+        hash: u64,
+        /// When it was copied, on our clock from the ordering check on and
+        /// on the sender's before that
+        timestamp: u64,
+        direct: bool,
+        reason: DropReason,
+    },
+    /// Received content was handed to the clipboard
+    ClipboardApplied {
+        from: PeerId,
+        hash: u64,
+        /// Why writing it failed, if it did
+        error: Option<String>,
+    },
+    /// Publishing a chat message or clipboard content failed
+    PublishFailed {
+        topic: TopicHash,
+        reason: String,
+    },
+    /// A local copy didn't fit every limit as it was, so it went out in
+    /// another form
+    Downgraded {
+        /// Which downgrade, e.g. "shrink"
+        tier: &'static str,
+        content_type: ContentType,
+        size: usize,
+    },
+    /// A peer copied content too large to be sent to us
+    TooLarge {
+        from: PeerId,
+        content_type: ContentType,
+        size: usize,
+    },
+    /// A local copy over `--confirm-large` waits for `/confirm` before it is sent
+    LargeCopyHeld {
+        preview: String,
+        size: usize,
+        threshold: u64,
+    },
+    /// Clipboard sync was paused or resumed
+    PauseChanged {
+        paused: bool,
+        cause: PauseCause,
+    },
+    /// A peer confirmed that content we copied is on its clipboard
+    DeliveryConfirmed {
+        peer: PeerId,
+        preview: String,
+    },
+    /// Peers that got content we copied didn't confirm it in time
+    DeliveryUnconfirmed {
+        preview: String,
+        missing: Vec<PeerId>,
+    },
+    /// A converter found text in an image received from a peer
+    Derived {
+        from: PeerId,
+        /// Which converter, e.g. "OCR"
+        converter: &'static str,
+        size: usize,
+        preview: String,
+    },
+    /// Content from a peer replaced something copied locally at nearly the
+    /// same time
+    Conflict {
+        from: PeerId,
+        local_preview: String,
+        remote_preview: String,
+    },
+    /// A long transfer moved on, at most every `progress::INTERVAL`
+    TransferProgress {
+        /// Tells concurrent transfers apart
+        id: u64,
+        /// What is transferred, e.g. a file name
+        label: String,
+        done: u64,
+        total: u64,
+        /// Bytes per second since it started or resumed
+        rate: u64,
+        /// Seconds left at that rate, unknown before anything moved
+        eta_secs: Option<u64>,
+    },
+    /// A long transfer completed, or failed or was interrupted
+    TransferFinished {
+        id: u64,
+        label: String,
+        /// Bytes moved since it started or resumed
+        bytes: u64,
+        elapsed_ms: u64,
+        /// Why it stopped, if it didn't complete
+        error: Option<String>,
+    },
+}
+
+impl NodeEvent {
+    pub fn sent(content: &ClipboardContent, peers: usize) -> Self {
+        Self::ClipboardSent {
+            content_type: content.content_type,
+            size: content.size(),
+            preview: content.preview(),
+            hash: crate::pipeline::payload_hash(content),
+            peers,
+        }
+    }
+
+    /// `hash` is taken before a large payload is spilled to disk
+    pub fn received(from: PeerId, content: &ClipboardContent, hash: u64, direct: bool) -> Self {
+        Self::ClipboardReceived {
+            from,
+            content_type: content.content_type,
+            size: content.size(),
+            preview: content.preview(),
+            // Cleaned up, as it is shown as is
+            source: content.source_label.as_deref().and_then(crate::focus::label),
+            provenance: content.provenance_note(),
+            hash,
+            timestamp: content.timestamp,
+            direct,
+        }
+    }
+
+    /// `hash` is taken before a large payload is spilled to disk
+    pub fn dropped(from: PeerId, content: &ClipboardContent, hash: u64, direct: bool, reason: DropReason) -> Self {
+        Self::ContentDropped {
+            from,
+            content_type: content.content_type,
+            size: content.size(),
+            hash,
+            timestamp: content.timestamp,
+            direct,
+            reason,
+        }
+    }
+
+    pub fn conflict(from: PeerId, local_preview: String, remote: &ClipboardContent) -> Self {
+        Self::Conflict {
+            from,
+            local_preview,
+            remote_preview: remote.preview(),
+        }
+    }
+}
+
+/// Parse a console line starting with `/` into a command
+pub fn parse_command(line: &str) -> Result<NodeCommand, String> {
+    let mut parts = line.split_whitespace();
+    let command = parts.next().unwrap_or_default();
+    let argument = parts.next();
+
+    match command {
+        "/pause" => Ok(NodeCommand::Pause),
+        "/resume" => Ok(NodeCommand::Resume),
+        "/push" => Ok(NodeCommand::SendClipboard),
+        "/send-to" => argument
+            .and_then(|arg| arg.parse().ok())
+            .map(NodeCommand::SendTo)
+            .ok_or_else(|| "Usage: /send-to <peer-id>".to_string()),
+        "/confirm" => Ok(NodeCommand::ConfirmLarge),
+        "/last" => Ok(NodeCommand::ShowLastReceived),
+        "/peers" => match argument {
+            None => Ok(NodeCommand::ShowPeers),
+            Some("known") => Ok(NodeCommand::ShowKnownPeers),
+            Some(_) => Err("Usage: /peers [known]".to_string()),
+        },
+        "/stats" => Ok(NodeCommand::ShowStats),
+        "/status" => Ok(NodeCommand::ShowStatus),
+        "/queue" => Ok(NodeCommand::ShowQueue),
+        "/accept" => parse_index(command, argument).map(NodeCommand::AcceptIncoming),
+        "/reject" => parse_index(command, argument).map(NodeCommand::RejectIncoming),
+        "/cancel" => Ok(NodeCommand::CancelDownloads),
+        "/resume-transfers" => Ok(NodeCommand::ResumeTransfers),
+        "/hardened" => match argument {
+            Some("on") => Ok(NodeCommand::Hardened(true)),
+            Some("off") => Ok(NodeCommand::Hardened(false)),
+            _ => Err("Usage: /hardened on|off".to_string()),
+        },
+        "/reload" => Ok(NodeCommand::Reload),
+        "/log" if parts.next().is_none() => Ok(NodeCommand::Log(LogFilter::from_argument(argument))),
+        "/log" => Err("Usage: /log <filter>|status|reset, with no spaces in the filter".to_string()),
+        "/help" => Ok(NodeCommand::Help),
+        "/quit" => Ok(NodeCommand::Quit),
+        other => Err(format!("Unknown command {other:?}. Type /help for a list of commands")),
+    }
+}
+
+fn parse_index(command: &str, argument: Option<&str>) -> Result<usize, String> {
+    argument
+        .and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| format!("Usage: {command} <queue index>"))
+}
@@ -0,0 +1,245 @@
+use crate::clipboard::{ClipboardContent, ContentType};
+use crate::control::LogFilter;
+use crate::profile::{self, Profile};
+use anyhow::{bail, Context, Result};
+use libp2p::PeerId;
+use libp2p::request_response::OutboundRequestId;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+/// Requests local clients send to the running node, one JSON line each
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ControlRequest {
+    /// The latest clipboard content known to the group, asking peers for it
+    /// if the node has none
+    Get { apply: bool, timeout_ms: u64 },
+    /// Show or change the node's log filter, like `/log`
+    Log(LogFilter),
+}
+
+/// The node's answer to a [`ControlRequest`], one JSON line
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ControlResponse {
+    /// The content, for `get` without `--apply`
+    Content(Box<ClipboardContent>),
+    /// The content was put on the node's clipboard
+    Applied,
+    /// The log filter in effect, after a `Log` request
+    LogFilter(String),
+    /// The request failed for this reason
+    Error(String),
+}
+
+/// A client's request and where to send the answer
+pub type Incoming = (ControlRequest, oneshot::Sender<ControlResponse>);
+
+/// A `get` waiting for peers to send their latest content
+#[derive(Debug)]
+pub struct PendingGet {
+    pub apply: bool,
+    pub deadline: Instant,
+    /// Peers asked and not answered yet, with the request asking them
+    pub waiting: HashMap<PeerId, OutboundRequestId>,
+    pub reply: oneshot::Sender<ControlResponse>,
+}
+
+/// Socket of the node running `profile`, or of the node running without one
+pub fn socket_path(profile: Option<&str>) -> Result<PathBuf> {
+    match profile {
+        Some(name) => Ok(Profile::open(name)?.control_socket_path()),
+        None => profile::default_control_socket_path(),
+    }
+}
+
+/// Accept local clients on `path` and pass their requests to the event loop.
+/// Nothing is served if another node already listens there.
+#[cfg(unix)]
+pub fn spawn(path: PathBuf, requests: mpsc::UnboundedSender<Incoming>) {
+    tokio::spawn(async move {
+        if let Err(e) = serve(&path, requests).await {
+            log::warn!("Control socket {} unavailable, `get` won't reach this node: {e:?}", path.display());
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn(_path: PathBuf, _requests: mpsc::UnboundedSender<Incoming>) {
+    debug!("The control socket is only supported on Unix");
+}
+
+#[cfg(unix)]
+async fn serve(path: &Path, requests: mpsc::UnboundedSender<Incoming>) -> Result<()> {
+    use tokio::net::UnixStream;
+
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            bail!("another node is already listening on it");
+        }
+        // Left behind by a node that didn't shut down cleanly
+        std::fs::remove_file(path).with_context(|| format!("Failed to remove stale {}", path.display()))?;
+    }
+    let listener = bind_private(path)?;
+    log::info!("Listening for local clients on {}", path.display());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let requests = requests.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, requests).await {
+                debug!("Control client failed: {e:?}");
+            }
+        });
+    }
+}
+
+/// Listen on `path` so that only this user may drive the node. The socket
+/// is bound in a fresh 0700 directory next to `path` and only moved into
+/// place once it is 0600, so nobody else can connect in between.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> Result<tokio::net::UnixListener> {
+    use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let parent = path.parent().context("The control socket path has no directory")?;
+    let mut private = None;
+    for _ in 0..8 {
+        let dir = parent.join(format!(".socket-{:08x}", OsRng.next_u32()));
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => {
+                private = Some(dir);
+                break;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to create {}", dir.display())),
+        }
+    }
+    let private = private.with_context(|| format!("Failed to find a free name for a directory in {}", parent.display()))?;
+    let staged = private.join("control");
+    let bound = tokio::net::UnixListener::bind(&staged)
+        .map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&staged, path).with_context(|| format!("Failed to move the socket to {}", path.display()))?;
+            Ok(listener)
+        });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&private);
+    bound
+}
+
+#[cfg(unix)]
+async fn handle(stream: tokio::net::UnixStream, requests: mpsc::UnboundedSender<Incoming>) -> Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    /// Requests are small; anything longer is not a client of ours
+    const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader.take(MAX_REQUEST_SIZE)).read_line(&mut line).await?;
+    let response = match serde_json::from_str(&line) {
+        Ok(request) => {
+            let (reply, answer) = oneshot::channel();
+            requests.send((request, reply)).context("The node is shutting down")?;
+            answer.await.unwrap_or_else(|_| ControlResponse::Error("The node is shutting down".to_string()))
+        }
+        Err(e) => ControlResponse::Error(format!("Invalid request: {e}")),
+    };
+    let mut data = serde_json::to_vec(&response)?;
+    data.push(b'\n');
+    writer.write_all(&data).await?;
+    Ok(())
+}
+
+/// Send `request` to the node listening on `path` and wait up to `timeout`
+/// for the answer
+#[cfg(unix)]
+async fn request(path: &Path, request: &ControlRequest, timeout: Duration) -> Result<ControlResponse> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("No running node found at {}", path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut data = serde_json::to_vec(request)?;
+    data.push(b'\n');
+    writer.write_all(&data).await?;
+    let mut line = String::new();
+    tokio::time::timeout(timeout, BufReader::new(reader).read_line(&mut line))
+        .await
+        .context("The node did not answer in time")??;
+    if line.is_empty() {
+        bail!("The node closed the connection without answering");
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(not(unix))]
+async fn request(_path: &Path, _request: &ControlRequest, _timeout: Duration) -> Result<ControlResponse> {
+    bail!("Talking to a running node is only supported on Unix so far")
+}
+
+/// The `get` subcommand: print the group's latest clipboard text, or have the
+/// running node put the latest content on the clipboard
+pub async fn get(profile: Option<&str>, apply: bool, timeout: Duration) -> Result<()> {
+    let path = socket_path(profile)?;
+    let get = ControlRequest::Get { apply, timeout_ms: timeout.as_millis() as u64 };
+    // The node answers by the timeout itself; the margin covers applying
+    let response = request(&path, &get, timeout + Duration::from_secs(5)).await?;
+    match response {
+        ControlResponse::Applied => Ok(()),
+        ControlResponse::Error(message) => bail!(message),
+        ControlResponse::LogFilter(_) => bail!("The node answered with something other than content"),
+        ControlResponse::Content(content) => match content.content_type {
+            ContentType::Text => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&content.data)?;
+                stdout.flush()?;
+                Ok(())
+            }
+            ContentType::Image => bail!(
+                "The latest clipboard content is a {}x{} image; use --apply to put it on the clipboard",
+                content.width.unwrap_or(0),
+                content.height.unwrap_or(0)
+            ),
+            ContentType::Files => bail!("The latest clipboard content is a file offer, which get cannot return"),
+        },
+    }
+}
+
+/// The `log` subcommand: show or change the log filter of the running node
+pub async fn log(profile: Option<&str>, filter: LogFilter) -> Result<()> {
+    let path = socket_path(profile)?;
+    match request(&path, &ControlRequest::Log(filter), Duration::from_secs(5)).await? {
+        ControlResponse::LogFilter(active) => {
+            println!("{active}");
+            Ok(())
+        }
+        ControlResponse::Error(message) => bail!(message),
+        _ => bail!("The node answered with something other than its log filter"),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_socket_is_private_from_the_moment_it_appears() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = crate::testing::TempDir::new();
+        let path = dir.path().join("control.sock");
+        let _listener = bind_private(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        // The directory it was bound in is gone
+        let names: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, ["control.sock"]);
+    }
+}
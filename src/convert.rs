@@ -0,0 +1,240 @@
+use crate::clipboard::{ClipboardContent, ContentType};
+use crate::queue;
+use crate::Args;
+use anyhow::{bail, Context, Result};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use image::ImageEncoder;
+use libp2p::PeerId;
+use log::{debug, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// A converter still running after this long is killed and its result dropped
+const TIMEOUT: Duration = Duration::from_secs(20);
+/// How often a running converter is checked on
+const POLL: Duration = Duration::from_millis(50);
+/// Received images waiting for the converters. A newer image pushes out the
+/// oldest, since only the latest is on the clipboard.
+const QUEUED_IMAGES: usize = 2;
+/// Derived text waiting for the event loop
+const QUEUED_TEXT: usize = 16;
+
+/// Turns a received image into text, by running an external program on it.
+/// Only those whose cargo feature is enabled can be turned on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(all(feature = "ocr", feature = "qr")), allow(dead_code))]
+pub enum Converter {
+    /// Text recognized with `tesseract`
+    Ocr,
+    /// Payloads of the QR codes found with `zbarimg`
+    Qr,
+}
+
+impl Converter {
+    /// How derived text is marked in the log, `/last` and the dashboard
+    pub fn label(self) -> &'static str {
+        match self {
+            Converter::Ocr => "OCR",
+            Converter::Qr => "QR code",
+        }
+    }
+
+    fn command(self, image: &Path) -> Command {
+        match self {
+            Converter::Ocr => {
+                let mut command = Command::new("tesseract");
+                command.arg(image).arg("stdout");
+                command
+            }
+            Converter::Qr => {
+                let mut command = Command::new("zbarimg");
+                command.args(["--quiet", "--raw"]).arg(image);
+                command
+            }
+        }
+    }
+
+    /// Text derived from the PNG at `image`, `None` if there was nothing to find
+    fn run(self, image: &Path) -> Result<Option<String>> {
+        let mut command = self.command(image);
+        let program = command.get_program().to_string_lossy().into_owned();
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to run {program}, is it installed?"))?;
+        // Drained while waiting, so a long result can't fill the pipe and stall the program
+        let mut stdout = child.stdout.take().context("No stdout")?;
+        let output = std::thread::spawn(move || {
+            let mut output = Vec::new();
+            stdout.read_to_end(&mut output).map(|_| output)
+        });
+
+        let deadline = Instant::now() + TIMEOUT;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                bail!("{program} took longer than {}s", TIMEOUT.as_secs());
+            }
+            std::thread::sleep(POLL);
+        };
+        let output = output.join().map_err(|_| anyhow::anyhow!("Reading the output of {program} panicked"))??;
+        match self {
+            // zbarimg exits with 4 when the image has no QR code
+            Converter::Qr if status.code() == Some(4) => return Ok(None),
+            _ if !status.success() => bail!("{program} failed with {status}"),
+            _ => {}
+        }
+        let text = String::from_utf8_lossy(&output).trim().to_string();
+        Ok((!text.is_empty()).then_some(text))
+    }
+}
+
+/// The converters turned on by `--ocr-incoming-images` and `--qr-decode`
+#[cfg_attr(not(any(feature = "ocr", feature = "qr")), allow(unused_variables))]
+pub fn from_args(args: &Args) -> Vec<Converter> {
+    #[allow(unused_mut)]
+    let mut converters = Vec::new();
+    #[cfg(feature = "ocr")]
+    if args.ocr_incoming_images {
+        converters.push(Converter::Ocr);
+    }
+    #[cfg(feature = "qr")]
+    if args.qr_decode {
+        converters.push(Converter::Qr);
+    }
+    converters
+}
+
+/// Text a converter derived from a received image
+#[derive(Debug)]
+pub struct Derived {
+    /// Peer that copied the image
+    pub from: PeerId,
+    /// Timestamp of the image, telling which received content this belongs to
+    pub timestamp: u64,
+    pub converter: Converter,
+    pub text: String,
+}
+
+/// Start the task that runs `converters` on received images, one image at a
+/// time and off the event loop. Images are sent in along with the peer that
+/// copied them; whatever text is found comes back out. Images are applied as
+/// usual meanwhile, so a slow converter never holds them up, and images
+/// arriving faster than it converts them push out the oldest waiting.
+pub fn spawn(converters: Vec<Converter>) -> (queue::Sender<(PeerId, ClipboardContent)>, mpsc::Receiver<Derived>) {
+    let (input_tx, mut input_rx) = queue::channel::<(PeerId, ClipboardContent)>(QUEUED_IMAGES);
+    let (output_tx, output_rx) = mpsc::channel(QUEUED_TEXT);
+    tokio::spawn(async move {
+        while let Some((from, content)) = input_rx.recv().await {
+            if content.content_type != ContentType::Image {
+                continue;
+            }
+            let converters = converters.clone();
+            let timestamp = content.timestamp;
+            let results = tokio::task::spawn_blocking(move || convert(&converters, &content)).await;
+            let results = match results {
+                Ok(Ok(results)) => results,
+                Ok(Err(e)) => {
+                    warn!("Failed to prepare a received image for conversion: {e:#}");
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Image converter task failed: {e}");
+                    continue;
+                }
+            };
+            for (converter, text) in results {
+                if output_tx.send(Derived { from, timestamp, converter, text }).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    (input_tx, output_rx)
+}
+
+/// Run each converter on `content`, returning the text they found. A
+/// converter that fails is logged and skipped.
+fn convert(converters: &[Converter], content: &ClipboardContent) -> Result<Vec<(Converter, String)>> {
+    let (width, height) = (content.width.unwrap_or(0), content.height.unwrap_or(0));
+    let bytes = content.bytes()?;
+    let rgba = crate::imaging::normalize_rgba(&bytes, width, height, content.channels)?;
+    let (image, file) = TempImage::create()?;
+    let mut writer = BufWriter::new(file);
+    image::codecs::png::PngEncoder::new(&mut writer)
+        .write_image(&rgba, width, height, image::ExtendedColorType::Rgba8)
+        .map_err(anyhow::Error::from)
+        .and_then(|()| Ok(writer.flush()?))
+        .with_context(|| format!("Failed to write {}", image.0.display()))?;
+
+    let mut results = Vec::new();
+    for &converter in converters {
+        match converter.run(&image.0) {
+            Ok(Some(text)) => results.push((converter, text)),
+            Ok(None) => debug!("{} found nothing in the received image", converter.label()),
+            Err(e) => warn!("{} of a received image failed: {e:#}", converter.label()),
+        }
+    }
+    Ok(results)
+}
+
+/// PNG handed to the converter programs, removed once they are done
+struct TempImage(PathBuf);
+
+impl TempImage {
+    /// A new file under a random name in the temporary directory, readable
+    /// only by us. Nobody sharing the directory can guess the name to plant
+    /// a file or symlink there first, and one that is there anyway is never
+    /// opened.
+    fn create() -> Result<(Self, File)> {
+        for _ in 0..8 {
+            let path = std::env::temp_dir().join(format!("clipboard-sync-convert-{:016x}.png", OsRng.next_u64()));
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options.open(&path) {
+                Ok(file) => return Ok((Self(path), file)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e).with_context(|| format!("Failed to create {}", path.display())),
+            }
+        }
+        bail!("Failed to find a free name for an image in {}", std::env::temp_dir().display())
+    }
+}
+
+impl Drop for TempImage {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temporary_images_get_fresh_private_files() {
+        let (first, _) = TempImage::create().unwrap();
+        let (second, _) = TempImage::create().unwrap();
+        assert_ne!(first.0, second.0);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&first.0).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let path = first.0.clone();
+        drop(first);
+        assert!(!path.exists());
+    }
+}
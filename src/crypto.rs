@@ -0,0 +1,136 @@
+use crate::clipboard::ClipboardContent;
+use anyhow::{bail, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+/// Fixed salt so a passphrase derives the same key on every machine.
+/// Changing it makes older nodes unable to read new content and vice versa.
+const FIELD_SALT: &[u8] = b"libp2p-clipboard-sync/field-encryption/v1";
+const NONCE_LEN: usize = 12;
+
+/// Key for `--field-encryption`, which encrypts only the payload of clipboard
+/// content. Type, timestamp and dimensions stay in the clear, so peers
+/// without the key still forward, deduplicate and count the content.
+#[derive(Clone)]
+pub struct FieldKey(Key);
+
+impl FieldKey {
+    /// Derive the key from a passphrase with Argon2id. Slow on purpose, so
+    /// derive it once at startup.
+    pub fn derive(passphrase: &str) -> Result<Self> {
+        if passphrase.is_empty() {
+            bail!("Field encryption passphrase must not be empty");
+        }
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), FIELD_SALT, &mut key)
+            .map_err(|e| anyhow::anyhow!("Failed to derive field encryption key: {:?}", e))?;
+        Ok(Self(key))
+    }
+}
+
+impl std::fmt::Debug for FieldKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FieldKey(..)")
+    }
+}
+
+/// Encrypt the `data` of `content` in place under a fresh nonce, leaving the
+/// nonce and ciphertext there. Content already sealed is left alone. The
+/// payload must not be spilled to disk.
+pub fn seal_field(content: &mut ClipboardContent, key: &FieldKey) {
+    if content.sealed {
+        return;
+    }
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    // Only fails for plaintexts far beyond any clipboard size limit
+    let ciphertext = ChaCha20Poly1305::new(&key.0)
+        .encrypt(&nonce, content.data.as_slice())
+        .expect("clipboard payload too large to encrypt");
+    content.data = [&nonce[..], &ciphertext].concat();
+    content.sealed = true;
+}
+
+/// Decrypt what [`seal_field`] sealed. Content that isn't sealed is left
+/// alone; on failure `content` is unchanged.
+pub fn open_field(content: &mut ClipboardContent, key: &FieldKey) -> Result<()> {
+    if !content.sealed {
+        return Ok(());
+    }
+    if content.data.len() < NONCE_LEN {
+        bail!("Encrypted clipboard data is truncated");
+    }
+    let (nonce, ciphertext) = content.data.split_at(NONCE_LEN);
+    content.data = ChaCha20Poly1305::new(&key.0)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        // The tag check can't tell a different key from damaged data
+        .map_err(|_| anyhow::anyhow!("Clipboard data is encrypted with a different key, or damaged"))?;
+    content.sealed = false;
+    Ok(())
+}
+
+/// `content` sealed if there is a key, as it goes out to peers
+pub fn sealed(mut content: ClipboardContent, key: Option<&FieldKey>) -> ClipboardContent {
+    if let Some(key) = key {
+        seal_field(&mut content, key);
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::ContentType;
+
+    #[test]
+    fn only_the_payload_is_sealed() {
+        let key = FieldKey::derive("correct horse").unwrap();
+        let original = ClipboardContent::new_image(vec![7; 2 * 2 * 4], 2, 2);
+        let mut content = original.clone();
+        seal_field(&mut content, &key);
+        assert!(content.sealed);
+        assert!(!content.data.windows(original.data.len()).any(|window| window == original.data));
+        assert_eq!(
+            (content.content_type, content.timestamp, content.width, content.height),
+            (ContentType::Image, original.timestamp, Some(2), Some(2))
+        );
+        // The metadata survives the trip through the wire format
+        let received: ClipboardContent = serde_json::from_slice(&serde_json::to_vec(&content).unwrap()).unwrap();
+        assert!(received.sealed);
+        assert_eq!((received.width, received.height, received.timestamp), (Some(2), Some(2), original.timestamp));
+
+        let mut opened = received;
+        open_field(&mut opened, &key).unwrap();
+        assert!(!opened.sealed);
+        assert_eq!(opened.data, original.data);
+    }
+
+    #[test]
+    fn sealed_content_cant_be_opened_with_another_key() {
+        let (key, other) = (FieldKey::derive("correct horse").unwrap(), FieldKey::derive("battery staple").unwrap());
+        let mut content = ClipboardContent::new_text("hunter2".to_string());
+        seal_field(&mut content, &key);
+        let sealed = content.data.clone();
+        assert!(open_field(&mut content, &other).is_err());
+        assert!(content.sealed);
+        assert_eq!(content.data, sealed, "a failed open changed the content");
+
+        content.data.truncate(NONCE_LEN - 1);
+        assert!(open_field(&mut content, &key).is_err());
+    }
+
+    #[test]
+    fn sealing_twice_or_opening_plain_content_changes_nothing() {
+        let key = FieldKey::derive("correct horse").unwrap();
+        let mut content = sealed(ClipboardContent::new_text("hunter2".to_string()), Some(&key));
+        let once = content.data.clone();
+        seal_field(&mut content, &key);
+        assert_eq!(content.data, once);
+
+        let mut plain = sealed(ClipboardContent::new_text("hunter2".to_string()), None);
+        open_field(&mut plain, &key).unwrap();
+        assert_eq!(plain.data, b"hunter2");
+        assert!(FieldKey::derive("").is_err());
+    }
+}
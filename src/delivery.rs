@@ -0,0 +1,160 @@
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Topic receivers confirm content applied from the default room on; other
+/// rooms confirm on their own, see `bridge::Room`. Only nodes that ask for
+/// confirmations subscribe to it; receivers publish without subscribing.
+pub const TOPIC: &str = "clipboard-sync-delivery";
+/// Recipients that haven't confirmed content within this long are reported
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Published by a receiver once content that asked for it is on its clipboard
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ack {
+    /// SHA-256 of the content as it was sent, salted with its nonce, see
+    /// [`salted`]
+    pub sha256: String,
+}
+
+/// A fresh nonce for content that asks to be confirmed. Acks carry the
+/// content hash salted with it, so a peer that never received the content
+/// can't confirm guesses of it, and the same content sent twice is
+/// confirmed apart.
+pub fn nonce() -> String {
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+    nonce.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// What receivers confirm content with `nonce` and SHA-256 `sha256` by
+pub fn salted(nonce: &str, sha256: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(nonce.as_bytes());
+    hasher.update([0]);
+    hasher.update(sha256.as_bytes());
+    hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Content applied here whose sender asked for a confirmation
+#[derive(Debug, Clone)]
+pub struct Delivered {
+    /// Room the content was received in, whose delivery topic the
+    /// confirmation goes to
+    pub room: Option<String>,
+    pub ack: Ack,
+}
+
+#[derive(Debug)]
+struct Pending {
+    preview: String,
+    sent_at: Instant,
+    /// Recipients that haven't confirmed yet
+    waiting: HashSet<PeerId>,
+}
+
+/// Content we asked receivers to confirm, by hash, until every recipient
+/// confirmed it or `TIMEOUT` passed
+#[derive(Debug, Default)]
+pub struct Confirmations {
+    pending: HashMap<String, Pending>,
+}
+
+impl Confirmations {
+    /// Wait for `recipients` to confirm the content with this hash. Sending
+    /// the same content again starts over.
+    pub fn track(&mut self, sha256: String, preview: String, recipients: &[PeerId]) {
+        if recipients.is_empty() {
+            return;
+        }
+        self.pending.insert(
+            sha256,
+            Pending { preview, sent_at: Instant::now(), waiting: recipients.iter().copied().collect() },
+        );
+    }
+
+    /// Count a confirmation, returning the preview of the content `from`
+    /// confirmed. `None` for content we aren't waiting on `from` for.
+    pub fn record(&mut self, sha256: &str, from: PeerId) -> Option<String> {
+        let pending = self.pending.get_mut(sha256)?;
+        if !pending.waiting.remove(&from) {
+            return None;
+        }
+        let preview = pending.preview.clone();
+        if pending.waiting.is_empty() {
+            self.pending.remove(sha256);
+        }
+        Some(preview)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Content whose confirmations timed out by `now`, with the recipients
+    /// that never confirmed it
+    pub fn take_expired(&mut self, now: Instant) -> Vec<(String, Vec<PeerId>)> {
+        let expired: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.saturating_duration_since(pending.sent_at) >= TIMEOUT)
+            .map(|(sha256, _)| sha256.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|sha256| self.pending.remove(&sha256))
+            .map(|pending| (pending.preview, pending.waiting.into_iter().collect()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_is_confirmed_once_every_recipient_has() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut confirmations = Confirmations::default();
+        confirmations.track("hash".to_string(), "lunch?".to_string(), &[a, b]);
+        assert_eq!(confirmations.record("hash", a).as_deref(), Some("lunch?"));
+        assert_eq!(confirmations.record("hash", a), None, "confirmed twice");
+        assert!(!confirmations.is_empty());
+        assert_eq!(confirmations.record("hash", b).as_deref(), Some("lunch?"));
+        assert!(confirmations.is_empty());
+    }
+
+    #[test]
+    fn only_awaited_confirmations_count() {
+        let (a, stranger) = (PeerId::random(), PeerId::random());
+        let mut confirmations = Confirmations::default();
+        confirmations.track("hash".to_string(), "lunch?".to_string(), &[a]);
+        assert_eq!(confirmations.record("hash", stranger), None);
+        assert_eq!(confirmations.record("other", a), None);
+        confirmations.track("unsent".to_string(), "nobody".to_string(), &[]);
+        assert_eq!(confirmations.record("unsent", a), None);
+    }
+
+    #[test]
+    fn recipients_that_never_confirm_are_reported_once_the_time_is_up() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut confirmations = Confirmations::default();
+        confirmations.track("hash".to_string(), "lunch?".to_string(), &[a, b]);
+        confirmations.record("hash", a);
+        assert!(confirmations.take_expired(Instant::now()).is_empty());
+        assert_eq!(confirmations.take_expired(Instant::now() + TIMEOUT), [("lunch?".to_string(), vec![b])]);
+        assert!(confirmations.is_empty());
+    }
+
+    #[test]
+    fn acks_depend_on_the_nonce() {
+        let (first, second) = (nonce(), nonce());
+        assert_ne!(first, second);
+        assert_eq!(salted(&first, "hash"), salted(&first, "hash"));
+        assert_ne!(salted(&first, "hash"), salted(&second, "hash"));
+        assert_ne!(salted(&first, "hash"), "hash");
+    }
+}
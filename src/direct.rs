@@ -0,0 +1,79 @@
+use crate::clipboard::{ClipboardContent, ContentType};
+use crate::peer_exchange::PeerRecord;
+use libp2p::StreamProtocol;
+use libp2p::request_response::{self, ProtocolSupport, json};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Protocol for messages addressed to a single peer rather than a topic
+const PROTOCOL: StreamProtocol = StreamProtocol::new("/clipboard-sync/direct/1.0.0");
+/// Same limit as the gossipsub max transmit size, large images included
+pub const MAX_MESSAGE_SIZE: u64 = 100 * 1024 * 1024;
+/// Large payloads over slow links need more than the default 10s
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// [`DIRECT_PROTOCOL`](crate::capabilities::DIRECT_PROTOCOL) version that
+/// brought `Clipboard`, `QueryLatest` and `TooLarge`
+pub const PUSH_VERSION: u32 = 1;
+
+/// Requests sent directly to one peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DirectRequest {
+    /// The sender's latest clipboard content, offered to a peer that just
+    /// subscribed and missed it on the topic
+    Retained(ClipboardContent),
+    /// Freshly copied content sent to this peer instead of the topic, when
+    /// `--max-peers-for-clipboard` limits how many peers get it
+    Clipboard(ClipboardContent),
+    /// Group members the sender is connected to, so the recipient can find
+    /// them without being told about each one
+    PeerExchange(Vec<PeerRecord>),
+    /// Ask for the full version of the image copied at `timestamp`, after
+    /// receiving it as a diff whose base we don't have. Answered with a
+    /// `Retained` request carrying the image.
+    FullImage { timestamp: u64 },
+    /// Ask for the sender's latest clipboard content, for a `get` on a node
+    /// that has none. Answered with a `Retained` request carrying it, or
+    /// `Ignored` when there is nothing to offer.
+    QueryLatest,
+    /// The chat line with this id arrived. Answered, but never with another
+    /// receipt.
+    ChatReceipt { msg_id: u64 },
+    /// The sender copied content that couldn't be sent to us, being over our
+    /// `--max-image-bytes` or the largest message size
+    TooLarge { content_type: ContentType, size: usize },
+}
+
+impl DirectRequest {
+    /// Approximate size on the wire, for bandwidth accounting: the payload
+    /// of clipboard content, the JSON encoding of anything else
+    pub fn size(&self) -> usize {
+        match self {
+            DirectRequest::Retained(content) | DirectRequest::Clipboard(content) => content.size(),
+            _ => serde_json::to_vec(self).map_or(0, |data| data.len()),
+        }
+    }
+}
+
+/// Answers to a [`DirectRequest`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DirectResponse {
+    /// The request was acted on
+    Accepted,
+    /// The request was understood but deliberately not acted on
+    Ignored,
+}
+
+pub type Behaviour = json::Behaviour<DirectRequest, DirectResponse>;
+
+/// Request-response behaviour for the direct protocol
+pub fn behaviour() -> Behaviour {
+    let codec = json::codec::Codec::default()
+        .set_request_size_maximum(MAX_MESSAGE_SIZE)
+        .set_response_size_maximum(MAX_MESSAGE_SIZE);
+    Behaviour::with_codec(
+        codec,
+        [(PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+    )
+}
@@ -0,0 +1,289 @@
+use crate::system_clipboard::{Error, SystemClipboard};
+use crate::{create_swarm, AppBehaviourEvent, Args};
+use futures::StreamExt;
+use libp2p::{identity, mdns, multiaddr::Protocol, swarm::SwarmEvent, Multiaddr};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+use std::time::Duration;
+
+/// mDNS multicast group and port
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// How long to wait for other peers to show up via mDNS
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// Structured result of a single environment check
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+    /// How to fix the problem, shown when the check did not pass
+    pub hint: Option<&'static str>,
+}
+
+impl CheckResult {
+    pub fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Pass, detail: detail.into(), hint: None }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self { name, status: CheckStatus::Warn, detail: detail.into(), hint: Some(hint) }
+    }
+
+    pub fn fail(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self { name, status: CheckStatus::Fail, detail: detail.into(), hint: Some(hint) }
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.status {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => "FAIL",
+        };
+        write!(f, "[{label}] {}: {}", self.name, self.detail)?;
+        if let Some(hint) = self.hint {
+            write!(f, "\n       hint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Run every check, print the report and return whether nothing failed
+pub async fn run(args: &Args) -> bool {
+    println!("Running environment checks...\n");
+
+    let results = vec![
+        check_clipboard(),
+        check_listen_port(args.listen_address, args.port),
+        check_mdns_multicast(),
+        check_peer_discovery(args).await,
+    ];
+
+    for result in &results {
+        println!("{result}");
+    }
+
+    let failures = results.iter().filter(|r| r.status == CheckStatus::Fail).count();
+    if failures == 0 {
+        println!("\nAll checks passed.");
+    } else {
+        println!("\n{failures} check(s) failed.");
+    }
+    failures == 0
+}
+
+/// Whether the system clipboard can be opened and read
+pub fn check_clipboard() -> CheckResult {
+    check_clipboard_backend(crate::system_clipboard::connect())
+}
+
+/// Whether the clipboard `opened` can be read
+fn check_clipboard_backend(opened: Result<Box<dyn SystemClipboard>, Error>) -> CheckResult {
+    const NAME: &str = "Clipboard backend";
+    match opened {
+        Ok(mut clipboard) => match clipboard.get_text() {
+            Ok(_) => CheckResult::pass(NAME, "clipboard is accessible"),
+            // An empty clipboard or non-text content is not a problem
+            Err(Error::ContentNotAvailable) => {
+                CheckResult::pass(NAME, "clipboard is accessible (currently no text)")
+            }
+            Err(e) => CheckResult::fail(
+                NAME,
+                format!("clipboard opened but could not be read: {e}"),
+                "Another application may hold the clipboard; retry, or check clipboard permissions",
+            ),
+        },
+        Err(e) => CheckResult::fail(
+            NAME,
+            format!("failed to open clipboard: {e}"),
+            "On Linux make sure DISPLAY or WAYLAND_DISPLAY is set (a graphical session is required)",
+        ),
+    }
+}
+
+/// Whether we can bind a TCP listener on the configured address
+pub fn check_listen_port(address: IpAddr, port: u16) -> CheckResult {
+    const NAME: &str = "Listen port";
+    match TcpListener::bind(SocketAddr::new(address, port)) {
+        Ok(listener) => {
+            let local = listener.local_addr().map(|a| a.to_string()).unwrap_or_default();
+            CheckResult::pass(NAME, format!("able to listen on {local}"))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => CheckResult::fail(
+            NAME,
+            format!("port {port} on {address} is already in use"),
+            "Pick another --port, or pass --port-fallback to use a free port instead",
+        ),
+        Err(e) => CheckResult::fail(
+            NAME,
+            format!("cannot bind {address}: {e}"),
+            "Check that --listen-address is assigned to this machine and not blocked by a firewall",
+        ),
+    }
+}
+
+/// Whether we can join the mDNS multicast group and send a query to it
+pub fn check_mdns_multicast() -> CheckResult {
+    const NAME: &str = "mDNS multicast";
+    let result = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+        socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+        socket.send_to(&mdns_query(), (MDNS_ADDR, MDNS_PORT))
+    });
+
+    match result {
+        Ok(_) => CheckResult::pass(NAME, format!("joined {MDNS_ADDR} and sent a query")),
+        Err(e) => CheckResult::fail(
+            NAME,
+            format!("multicast to {MDNS_ADDR}:{MDNS_PORT} failed: {e}"),
+            "Allow UDP port 5353 and multicast in the firewall, or use --connect to dial peers directly",
+        ),
+    }
+}
+
+/// Whether any other peer shows up via mDNS within a few seconds
+pub async fn check_peer_discovery(args: &Args) -> CheckResult {
+    const NAME: &str = "Peer discovery";
+    let mut swarm = match create_swarm(identity::Keypair::generate_ed25519(), args) {
+        Ok(swarm) => swarm,
+        Err(e) => {
+            return CheckResult::fail(NAME, format!("failed to start a node: {e}"), "See the error above");
+        }
+    };
+
+    let address = Multiaddr::from(args.listen_address).with(Protocol::Tcp(0));
+    if let Err(e) = swarm.listen_on(address) {
+        return CheckResult::fail(NAME, format!("failed to listen: {e}"), "See the listen port check");
+    }
+
+    let discovered = tokio::time::timeout(DISCOVERY_TIMEOUT, async {
+        loop {
+            if let SwarmEvent::Behaviour(AppBehaviourEvent::Mdns(mdns::Event::Discovered(list))) =
+                swarm.select_next_some().await
+                && let Some((peer_id, _)) = list.into_iter().next()
+            {
+                return peer_id;
+            }
+        }
+    })
+    .await;
+
+    match discovered {
+        Ok(peer_id) => CheckResult::pass(NAME, format!("discovered peer {peer_id}")),
+        Err(_) => CheckResult::warn(
+            NAME,
+            format!("no peers found within {} seconds", DISCOVERY_TIMEOUT.as_secs()),
+            "Start another instance on the same network, or check that mDNS is not filtered",
+        ),
+    }
+}
+
+/// Minimal mDNS query for the libp2p service name
+fn mdns_query() -> Vec<u8> {
+    let mut packet = vec![
+        0, 0, // transaction id
+        0, 0, // flags
+        0, 1, // one question
+        0, 0, 0, 0, 0, 0, // no answers, authority or additional records
+    ];
+    for label in ["_p2p", "_udp", "local"] {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.extend_from_slice(&[0, 0, 12, 0, 1]); // root, type PTR, class IN
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system_clipboard::{ImageData, MemoryClipboard};
+    use std::path::PathBuf;
+
+    /// A clipboard that opens but refuses every read, like one another program holds
+    struct Occupied;
+
+    impl SystemClipboard for Occupied {
+        fn get_text(&mut self) -> Result<String, Error> {
+            Err(Error::ClipboardOccupied)
+        }
+        fn get_image(&mut self) -> Result<ImageData<'static>, Error> {
+            Err(Error::ClipboardOccupied)
+        }
+        fn get_files(&mut self) -> Result<Vec<PathBuf>, Error> {
+            Err(Error::ClipboardOccupied)
+        }
+        fn set_text(&mut self, _: String) -> Result<(), Error> {
+            Err(Error::ClipboardOccupied)
+        }
+        fn set_image(&mut self, _: ImageData<'static>) -> Result<(), Error> {
+            Err(Error::ClipboardOccupied)
+        }
+        fn set_files(&mut self, _: &[PathBuf]) -> Result<(), Error> {
+            Err(Error::ClipboardOccupied)
+        }
+    }
+
+    #[test]
+    fn a_readable_clipboard_passes_even_without_text() {
+        let clipboard = MemoryClipboard::default();
+        assert_eq!(check_clipboard_backend(Ok(Box::new(clipboard.clone()))).status, CheckStatus::Pass);
+        clipboard.copy_text("hello");
+        assert_eq!(check_clipboard_backend(Ok(Box::new(clipboard))).status, CheckStatus::Pass);
+    }
+
+    #[test]
+    fn a_clipboard_that_cannot_be_opened_or_read_fails_with_a_hint() {
+        let unopened = check_clipboard_backend(Err(Error::ClipboardNotSupported));
+        assert_eq!(unopened.status, CheckStatus::Fail);
+        assert!(unopened.hint.unwrap().contains("DISPLAY"));
+
+        let unreadable = check_clipboard_backend(Ok(Box::new(Occupied)));
+        assert_eq!(unreadable.status, CheckStatus::Fail);
+        assert!(unreadable.hint.is_some());
+    }
+
+    #[test]
+    fn a_free_port_passes_and_a_taken_one_fails() {
+        let localhost = IpAddr::from(Ipv4Addr::LOCALHOST);
+        assert_eq!(check_listen_port(localhost, 0).status, CheckStatus::Pass);
+
+        let taken = TcpListener::bind((localhost, 0)).unwrap();
+        let result = check_listen_port(localhost, taken.local_addr().unwrap().port());
+        assert_eq!(result.status, CheckStatus::Fail);
+        assert!(result.detail.contains("already in use"));
+        assert!(result.hint.unwrap().contains("--port-fallback"));
+    }
+
+    #[test]
+    fn an_address_not_on_this_machine_fails() {
+        // TEST-NET-1, never assigned to a local interface
+        let result = check_listen_port(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)), 0);
+        assert_eq!(result.status, CheckStatus::Fail);
+        assert!(result.hint.unwrap().contains("--listen-address"));
+    }
+
+    #[test]
+    fn the_mdns_query_asks_for_the_libp2p_service() {
+        let packet = mdns_query();
+        assert_eq!(&packet[4..6], &[0, 1]);
+        assert_eq!(&packet[12..], b"\x04_p2p\x04_udp\x05local\x00\x00\x0c\x00\x01");
+    }
+
+    #[test]
+    fn failed_checks_show_their_hint() {
+        let report = CheckResult::fail("Listen port", "port 1 is already in use", "Pick another --port").to_string();
+        assert_eq!(report, "[FAIL] Listen port: port 1 is already in use\n       hint: Pick another --port");
+        assert_eq!(CheckResult::pass("Listen port", "ok").to_string(), "[PASS] Listen port: ok");
+    }
+}
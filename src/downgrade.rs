@@ -0,0 +1,183 @@
+use crate::clipboard::{ClipboardContent, ContentType};
+use crate::imaging;
+use anyhow::Result;
+use libp2p::PeerId;
+use log::debug;
+
+/// Attempts at scaling an image down before giving up on it
+const SHRINK_ATTEMPTS: usize = 6;
+/// Images are not scaled below this many pixels on their shorter side
+const MIN_SIDE: u32 = 32;
+/// Aim this far below the limit, so a guess that is slightly off still fits
+const SHRINK_MARGIN: f64 = 0.9;
+
+/// Tier a scaled down image is logged and counted under
+pub const SHRINK: &str = "shrink";
+
+/// A peer the content would go to, with the largest image it accepts if it
+/// advertises one
+#[derive(Debug, Clone, Copy)]
+pub struct Recipient {
+    pub peer: PeerId,
+    pub max_image_bytes: Option<u64>,
+}
+
+impl Recipient {
+    /// Whether the peer takes content of this type and payload size
+    fn takes(&self, content_type: ContentType, size: usize) -> bool {
+        content_type != ContentType::Image || self.max_image_bytes.is_none_or(|max| size as u64 <= max)
+    }
+}
+
+/// How a local copy goes out when it doesn't fit every limit as it is, from
+/// the mildest downgrade to the last resort. Images are scaled down by the
+/// encoder before this is decided, see [`shrink_target`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Plan {
+    /// Publish it unchanged
+    AsIs,
+    /// Send it unchanged as direct requests to the recipients that take it,
+    /// and a notice to the others
+    Direct { to: Vec<PeerId>, notice: Vec<PeerId> },
+    /// Nobody can take it: only tell the recipients it was too large
+    Notice { to: Vec<PeerId> },
+}
+
+impl Plan {
+    /// Name the downgrade is logged and counted under
+    pub fn label(&self) -> &'static str {
+        match self {
+            Plan::AsIs => "none",
+            Plan::Direct { .. } => "direct",
+            Plan::Notice { .. } => "notice",
+        }
+    }
+}
+
+/// Decide how content of `content_type`, with a payload of `size` bytes that
+/// serializes to `serialized` bytes, reaches `recipients`. `limit` is the
+/// largest serialized message, the same for gossipsub and direct requests,
+/// so going direct only helps to leave out the peers that would refuse it.
+pub fn plan(content_type: ContentType, size: usize, serialized: usize, limit: usize, recipients: &[Recipient]) -> Plan {
+    let (takers, refusers): (Vec<&Recipient>, Vec<&Recipient>) =
+        recipients.iter().partition(|recipient| recipient.takes(content_type, size));
+    if serialized <= limit && refusers.is_empty() {
+        return Plan::AsIs;
+    }
+    if serialized <= limit && !takers.is_empty() {
+        return Plan::Direct {
+            to: takers.iter().map(|recipient| recipient.peer).collect(),
+            notice: refusers.iter().map(|recipient| recipient.peer).collect(),
+        };
+    }
+    Plan::Notice { to: recipients.iter().map(|recipient| recipient.peer).collect() }
+}
+
+/// Payload size to scale `content` down to, if it is an image that
+/// serializes to more than `limit` bytes or whose payload is larger than
+/// `payload`, the smallest image some peer takes
+pub fn shrink_target(content: &ClipboardContent, serialized: usize, limit: usize, payload: usize) -> Option<usize> {
+    let size = content.image()?.len();
+    (serialized > limit || size > payload).then(|| size.min(payload))
+}
+
+/// `content`, an RGBA image, scaled down until its payload is at most
+/// `payload` bytes and it serializes to at most `limit` bytes. `None` if it
+/// would have to get too small for that.
+pub fn shrink(content: &ClipboardContent, payload: usize, limit: usize) -> Result<Option<ClipboardContent>> {
+    let (Some(data), Some(width), Some(height)) = (content.image(), content.width, content.height) else {
+        return Ok(None);
+    };
+    // Both sizes go with the pixel count, so with the square of the factor
+    let over = |size: usize, serialized: usize| (size as f64 / payload as f64).max(serialized as f64 / limit as f64);
+    let mut over_by = over(data.len(), serde_json::to_vec(content)?.len());
+    let mut factor = 1.0f64;
+    for _ in 0..SHRINK_ATTEMPTS {
+        factor = (factor * (SHRINK_MARGIN / over_by).sqrt()).min(1.0);
+        let (bytes, new_width, new_height) = imaging::scale_image(&data, width, height, factor as f32)?;
+        if new_width.min(new_height) < MIN_SIDE {
+            return Ok(None);
+        }
+        let mut shrunk = content.clone();
+        shrunk.data = bytes;
+        shrunk.width = Some(new_width);
+        shrunk.height = Some(new_height);
+        let encoded = serde_json::to_vec(&shrunk)?;
+        debug!("Scaled the image to {new_width}x{new_height}: {} bytes, {} serialized", shrunk.data.len(), encoded.len());
+        if shrunk.data.len() <= payload && encoded.len() <= limit {
+            return Ok(Some(shrunk));
+        }
+        over_by = over(shrunk.data.len(), encoded.len());
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: usize = 1000;
+
+    fn recipient(max_image_bytes: Option<u64>) -> Recipient {
+        Recipient { peer: PeerId::random(), max_image_bytes }
+    }
+
+    fn image(side: u32) -> ClipboardContent {
+        let data = (0..side * side).flat_map(|index| [index as u8, (index >> 8) as u8, 0x55, 255]).collect();
+        ClipboardContent::new_image(data, side, side)
+    }
+
+    #[test]
+    fn content_that_fits_everywhere_goes_out_as_is() {
+        let recipients = [recipient(None), recipient(Some(500))];
+        assert_eq!(plan(ContentType::Image, 400, LIMIT, LIMIT, &recipients), Plan::AsIs);
+        assert_eq!(plan(ContentType::Text, 4000, LIMIT, LIMIT, &recipients), Plan::AsIs);
+    }
+
+    #[test]
+    fn refusing_peers_get_a_notice_and_the_others_the_content() {
+        let (taker, refuser) = (recipient(None), recipient(Some(100)));
+        assert_eq!(
+            plan(ContentType::Image, 400, LIMIT, LIMIT, &[taker, refuser]),
+            Plan::Direct { to: vec![taker.peer], notice: vec![refuser.peer] }
+        );
+    }
+
+    #[test]
+    fn content_too_large_for_any_message_only_gets_a_notice() {
+        let recipients = [recipient(None), recipient(Some(100))];
+        assert_eq!(
+            plan(ContentType::Text, 400, LIMIT + 1, LIMIT, &recipients),
+            Plan::Notice { to: recipients.iter().map(|recipient| recipient.peer).collect() }
+        );
+        let refuser = recipient(Some(100));
+        assert_eq!(plan(ContentType::Image, 400, LIMIT, LIMIT, &[refuser]), Plan::Notice { to: vec![refuser.peer] });
+    }
+
+    #[test]
+    fn only_images_over_a_limit_are_shrunk() {
+        let image = image(64);
+        let size = image.size();
+        assert_eq!(shrink_target(&image, LIMIT, LIMIT, usize::MAX), None);
+        assert_eq!(shrink_target(&image, LIMIT + 1, LIMIT, usize::MAX), Some(size));
+        assert_eq!(shrink_target(&image, LIMIT, LIMIT, size / 2), Some(size / 2));
+        assert_eq!(shrink_target(&ClipboardContent::new_text("x".repeat(2 * LIMIT)), 2 * LIMIT, LIMIT, 0), None);
+    }
+
+    #[test]
+    fn shrinking_fits_the_image_into_both_limits() {
+        let image = image(256);
+        let payload = image.size() / 5;
+        let limit = serde_json::to_vec(&image).unwrap().len() / 3;
+        let shrunk = shrink(&image, payload, limit).unwrap().expect("not shrunk");
+        assert!(shrunk.size() <= payload);
+        assert!(serde_json::to_vec(&shrunk).unwrap().len() <= limit);
+        assert_eq!(shrunk.size(), (shrunk.width.unwrap() * shrunk.height.unwrap() * 4) as usize);
+    }
+
+    #[test]
+    fn images_are_not_shrunk_below_the_minimum_side() {
+        let image = image(256);
+        assert!(shrink(&image, 16, usize::MAX).unwrap().is_none());
+    }
+}
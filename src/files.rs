@@ -0,0 +1,1245 @@
+use crate::control::NodeEvent;
+use crate::progress::Progress;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::request_response::{self, json, OutboundRequestId, ProtocolSupport};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId, StreamProtocol};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Protocol receivers pull the bytes of offered files over, chunks as raw bytes
+const PROTOCOL: StreamProtocol = StreamProtocol::new("/clipboard-sync/files/2.0.0");
+/// The same with chunks as JSON, for peers predating [`PROTOCOL`]
+const JSON_PROTOCOL: StreamProtocol = StreamProtocol::new("/clipboard-sync/files/1.0.0");
+/// Unit downloads are tracked in. Chunk requests ask for whole blocks.
+const BLOCK_SIZE: u64 = 64 * 1024;
+/// Block size of downloads saved before blocks were smaller than a chunk
+const LEGACY_BLOCK_SIZE: u64 = 256 * 1024;
+/// Largest chunk served, whatever a peer asks for
+const MAX_CHUNK_SIZE: u64 = 1024 * 1024;
+/// JSON spells out every byte as a number of up to four characters
+const JSON_BYTE_CHARS: u64 = 4;
+/// Room for the rest of a chunk response besides the data
+const RESPONSE_OVERHEAD: u64 = 1024;
+const MAX_MESSAGE_SIZE: u64 = JSON_BYTE_CHARS * MAX_CHUNK_SIZE + RESPONSE_OVERHEAD;
+const MAX_REQUEST_SIZE: u64 = 1024;
+/// Tags of binary chunk responses, followed by the data or the reason
+const TAG_DATA: u8 = 0;
+const TAG_UNAVAILABLE: u8 = 1;
+const TAG_REFUSED: u8 = 2;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Files copied locally that stay available to peers, oldest dropped first
+const MAX_SHARED_FILES: usize = 64;
+/// Appended to the name of a download until it is complete and verified
+const PARTIAL_SUFFIX: &str = ".part";
+/// How long an interrupted download waits for its sender to come back
+const STALLED_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A file on the clipboard. Only this metadata goes out on the clipboard
+/// topic; peers pull the contents with chunk requests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileOffer {
+    /// File name without any directories
+    pub name: String,
+    pub size: u64,
+    /// Hex SHA-256 of the contents, which also names the file in chunk requests
+    pub sha256: String,
+    /// Where the file is on the node that copied it. Never sent.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+/// Describe files copied locally, hashing their contents. Directories and
+/// unreadable files are skipped with a warning.
+pub fn describe(paths: &[PathBuf]) -> Vec<FileOffer> {
+    paths
+        .iter()
+        .filter_map(|path| match describe_one(path) {
+            Ok(offer) => Some(offer),
+            Err(e) => {
+                warn!("Not offering {}: {e:#}", path.display());
+                None
+            }
+        })
+        .collect()
+}
+
+fn describe_one(path: &Path) -> Result<FileOffer> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_file() {
+        bail!("only regular files can be shared");
+    }
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .context("no file name")?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(FileOffer { name, size, sha256: hex(&hasher.finalize()), path: Some(path.to_path_buf()) })
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// How a connection to a peer is carried, which decides how much to ask
+/// for per chunk request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Its own congestion control per stream, no head-of-line blocking
+    Quic,
+    Tcp,
+    /// A byte stream over stdin and stdout, e.g. through `ssh`
+    Stdio,
+    /// Through a relay, which limits how much a circuit may carry
+    Relayed,
+}
+
+impl Transport {
+    /// The transport of a connection to or from `addr`
+    pub fn of(addr: &Multiaddr) -> Self {
+        if *addr == crate::stdio::address() {
+            return Transport::Stdio;
+        }
+        if addr.iter().any(|protocol| matches!(protocol, Protocol::P2pCircuit)) {
+            Transport::Relayed
+        } else if addr.iter().any(|protocol| matches!(protocol, Protocol::QuicV1 | Protocol::Quic)) {
+            Transport::Quic
+        } else {
+            Transport::Tcp
+        }
+    }
+}
+
+/// Bytes to ask for per chunk request over `transport` when responses may
+/// be up to `max_transmit` bytes: large where a connection carries bulk data
+/// well, small on relayed links, never more than a response can hold. Always
+/// a whole number of blocks between one block and [`MAX_CHUNK_SIZE`].
+pub fn chunk_size_for(transport: Transport, max_transmit: u64) -> u64 {
+    let preferred = match transport {
+        Transport::Quic => MAX_CHUNK_SIZE,
+        Transport::Tcp => 512 * 1024,
+        Transport::Stdio => 256 * 1024,
+        Transport::Relayed => BLOCK_SIZE,
+    };
+    let fits = max_transmit.saturating_sub(RESPONSE_OVERHEAD) / JSON_BYTE_CHARS;
+    let size = preferred.min(fits).min(MAX_CHUNK_SIZE);
+    (size / BLOCK_SIZE).max(1) * BLOCK_SIZE
+}
+
+/// Ask for `len` bytes at `offset` of the offered file with this hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRequest {
+    pub sha256: String,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Answers to a [`ChunkRequest`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChunkResponse {
+    Data(Vec<u8>),
+    /// The file is not offered (anymore), or it changed since it was
+    Unavailable,
+    /// The sender won't serve it right now, e.g. over its bandwidth cap
+    Refused(String),
+}
+
+impl ChunkResponse {
+    /// Payload size, for bandwidth accounting
+    pub fn size(&self) -> usize {
+        match self {
+            ChunkResponse::Data(data) => data.len(),
+            _ => 0,
+        }
+    }
+}
+
+/// Chunk requests as JSON and chunk responses as a tag byte followed by the
+/// raw data, a quarter of their size as JSON. Peers that only speak
+/// [`JSON_PROTOCOL`] get JSON responses.
+#[derive(Clone)]
+pub struct ChunkCodec {
+    json: json::codec::Codec<ChunkRequest, ChunkResponse>,
+}
+
+impl Default for ChunkCodec {
+    fn default() -> Self {
+        let json = json::codec::Codec::default()
+            .set_request_size_maximum(MAX_REQUEST_SIZE)
+            .set_response_size_maximum(MAX_MESSAGE_SIZE);
+        Self { json }
+    }
+}
+
+#[async_trait]
+impl request_response::Codec for ChunkCodec {
+    type Protocol = StreamProtocol;
+    type Request = ChunkRequest;
+    type Response = ChunkResponse;
+
+    async fn read_request<T>(&mut self, protocol: &StreamProtocol, io: &mut T) -> io::Result<ChunkRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.json.read_request(protocol, io).await
+    }
+
+    async fn read_response<T>(&mut self, protocol: &StreamProtocol, io: &mut T) -> io::Result<ChunkResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        if *protocol == JSON_PROTOCOL {
+            return self.json.read_response(protocol, io).await;
+        }
+        let mut message = Vec::new();
+        io.take(MAX_CHUNK_SIZE + RESPONSE_OVERHEAD).read_to_end(&mut message).await?;
+        decode_response(message)
+    }
+
+    async fn write_request<T>(&mut self, protocol: &StreamProtocol, io: &mut T, request: ChunkRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        self.json.write_request(protocol, io, request).await
+    }
+
+    async fn write_response<T>(&mut self, protocol: &StreamProtocol, io: &mut T, response: ChunkResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        if *protocol == JSON_PROTOCOL {
+            return self.json.write_response(protocol, io, response).await;
+        }
+        io.write_all(&encode_response(response)).await?;
+        io.close().await
+    }
+}
+
+fn encode_response(response: ChunkResponse) -> Vec<u8> {
+    match response {
+        ChunkResponse::Data(mut data) => {
+            data.insert(0, TAG_DATA);
+            data
+        }
+        ChunkResponse::Unavailable => vec![TAG_UNAVAILABLE],
+        ChunkResponse::Refused(reason) => [&[TAG_REFUSED][..], reason.as_bytes()].concat(),
+    }
+}
+
+fn decode_response(mut message: Vec<u8>) -> io::Result<ChunkResponse> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("malformed chunk response: {what}"));
+    let Some(&tag) = message.first() else {
+        return Err(invalid("empty"));
+    };
+    match tag {
+        TAG_DATA => {
+            message.remove(0);
+            Ok(ChunkResponse::Data(message))
+        }
+        TAG_UNAVAILABLE => Ok(ChunkResponse::Unavailable),
+        TAG_REFUSED => String::from_utf8(message.split_off(1)).map(ChunkResponse::Refused).map_err(|_| invalid("reason is not UTF-8")),
+        _ => Err(invalid("unknown tag")),
+    }
+}
+
+pub type Behaviour = request_response::Behaviour<ChunkCodec>;
+
+/// Request-response behaviour for the file transfer protocol
+pub fn behaviour() -> Behaviour {
+    Behaviour::with_codec(
+        ChunkCodec::default(),
+        [(PROTOCOL, ProtocolSupport::Full), (JSON_PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+    )
+}
+
+/// Files this node offered, which peers may pull. Only files that were
+/// really copied here are ever served.
+#[derive(Debug, Default)]
+pub struct SharedFiles {
+    files: VecDeque<FileOffer>,
+}
+
+impl SharedFiles {
+    /// Make the local files in `offers` available
+    pub fn offer(&mut self, offers: &[FileOffer]) {
+        for offer in offers.iter().filter(|offer| offer.path.is_some()) {
+            self.files.retain(|shared| shared.sha256 != offer.sha256);
+            if self.files.len() == MAX_SHARED_FILES {
+                self.files.pop_front();
+            }
+            self.files.push_back(offer.clone());
+        }
+    }
+
+    /// The shared file with this hash
+    pub fn get(&self, sha256: &str) -> Option<FileOffer> {
+        self.files.iter().find(|offer| offer.sha256 == sha256).cloned()
+    }
+}
+
+/// Files peers are pulling from us, reported as progress like downloads.
+/// The puller drives the transfer, so one that goes quiet is given up on
+/// after a request timeout.
+#[derive(Debug, Default)]
+pub struct Uploads {
+    active: HashMap<(PeerId, String), Upload>,
+}
+
+#[derive(Debug)]
+struct Upload {
+    progress: Progress,
+    last: Instant,
+}
+
+impl Uploads {
+    /// Count `bytes` of `offer` at `offset` as sent to `peer`, known as
+    /// `to`. Returns the event to report, if any.
+    pub fn sent(&mut self, peer: PeerId, to: &str, offer: &FileOffer, offset: u64, bytes: u64) -> Option<NodeEvent> {
+        let key = (peer, offer.sha256.clone());
+        let upload = self.active.entry(key.clone()).or_insert_with(|| Upload {
+            progress: Progress::new(format!("{} to {to}", offer.name), offer.size, offset),
+            last: Instant::now(),
+        });
+        upload.last = Instant::now();
+        let event = upload.progress.advance(bytes);
+        if offset + bytes < offer.size {
+            return event;
+        }
+        self.active.remove(&key).and_then(|upload| upload.progress.finish(None))
+    }
+
+    /// Give up on uploads whose puller stopped asking, returning their
+    /// summaries
+    pub fn expire(&mut self) -> Vec<NodeEvent> {
+        let quiet: Vec<(PeerId, String)> = self
+            .active
+            .iter()
+            .filter(|(_, upload)| upload.last.elapsed() > REQUEST_TIMEOUT)
+            .map(|(key, _)| key.clone())
+            .collect();
+        quiet
+            .into_iter()
+            .filter_map(|key| self.active.remove(&key))
+            .filter_map(|upload| upload.progress.finish(Some("the peer stopped pulling".to_string())))
+            .collect()
+    }
+}
+
+/// Read the chunk `request` asks for from `offer`. Blocks on disk access.
+pub fn read_chunk(offer: &FileOffer, request: &ChunkRequest) -> ChunkResponse {
+    let Some(ref path) = offer.path else {
+        return ChunkResponse::Unavailable;
+    };
+    let read = || -> Result<Option<Vec<u8>>> {
+        let mut file = File::open(path)?;
+        // A file edited since it was copied would fail the checksum anyway
+        if file.metadata()?.len() != offer.size {
+            return Ok(None);
+        }
+        if request.offset > offer.size {
+            return Ok(None);
+        }
+        let len = request.len.min(MAX_CHUNK_SIZE).min(offer.size - request.offset);
+        file.seek(SeekFrom::Start(request.offset))?;
+        let mut data = Vec::with_capacity(len as usize);
+        file.take(len).read_to_end(&mut data)?;
+        Ok(Some(data))
+    };
+    match read() {
+        Ok(Some(data)) => ChunkResponse::Data(data),
+        Ok(None) => ChunkResponse::Unavailable,
+        Err(e) => {
+            warn!("Failed to read {} for a peer: {e}", path.display());
+            ChunkResponse::Unavailable
+        }
+    }
+}
+
+/// Which blocks of a download arrived, one bit per block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChunkMap {
+    chunks: u64,
+    bits: Vec<u64>,
+    #[serde(default = "legacy_block_size")]
+    block: u64,
+}
+
+fn legacy_block_size() -> u64 {
+    LEGACY_BLOCK_SIZE
+}
+
+impl ChunkMap {
+    fn new(size: u64) -> Self {
+        let chunks = size.div_ceil(BLOCK_SIZE);
+        Self { chunks, bits: vec![0; chunks.div_ceil(64) as usize], block: BLOCK_SIZE }
+    }
+
+    /// Byte range of blocks `blocks` in a file of `size` bytes
+    fn byte_range(&self, blocks: Range<u64>, size: u64) -> Range<u64> {
+        (blocks.start * self.block).min(size)..(blocks.end * self.block).min(size)
+    }
+
+    /// Bytes of a file of `size` bytes in the blocks present
+    fn present(&self, size: u64) -> u64 {
+        (0..self.chunks)
+            .filter(|&chunk| self.has(chunk))
+            .map(|chunk| self.byte_range(chunk..chunk + 1, size))
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+
+    fn has(&self, chunk: u64) -> bool {
+        self.bits[(chunk / 64) as usize] & (1 << (chunk % 64)) != 0
+    }
+
+    fn set(&mut self, chunk: u64) {
+        self.bits[(chunk / 64) as usize] |= 1 << (chunk % 64);
+    }
+
+    fn first_missing(&self) -> Option<u64> {
+        (0..self.chunks).find(|&chunk| !self.has(chunk))
+    }
+
+    /// Runs of missing chunks, as `start..end` chunk indices
+    fn missing_ranges(&self) -> Vec<Range<u64>> {
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for chunk in (0..self.chunks).filter(|&chunk| !self.has(chunk)) {
+            match ranges.last_mut() {
+                Some(range) if range.end == chunk => range.end += 1,
+                _ => ranges.push(chunk..chunk + 1),
+            }
+        }
+        ranges
+    }
+}
+
+/// A file being pulled from a peer, written next to its final location
+#[derive(Debug)]
+struct Download {
+    from: PeerId,
+    offer: FileOffer,
+    dir: PathBuf,
+    partial: PathBuf,
+    file: File,
+    chunks: ChunkMap,
+    /// Blocks the request in flight asks for
+    requested: Range<u64>,
+    /// Contents hashed so far, always a prefix of the file
+    hasher: Sha256,
+    hashed: u64,
+    /// Bytes received, reported as progress events for large files
+    progress: Progress,
+    /// Clipboard entry the file belongs to. Unknown for downloads resumed
+    /// after a restart.
+    batch: Option<u64>,
+    /// When the download was interrupted, in seconds since the Unix epoch
+    stalled_at: u64,
+}
+
+/// What is saved about an interrupted download so it can resume after a
+/// restart
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedDownload {
+    from: PeerId,
+    offer: FileOffer,
+    dir: PathBuf,
+    partial: PathBuf,
+    chunks: ChunkMap,
+    stalled_at: u64,
+}
+
+impl Download {
+    fn received(&self) -> u64 {
+        self.chunks.present(self.offer.size)
+    }
+
+    /// Ask for the first missing blocks, as many in a row as fit in
+    /// `chunk_size` bytes
+    fn next_request(&mut self, chunk_size: u64) -> Option<ChunkRequest> {
+        let first = self.chunks.first_missing()?;
+        let most = (chunk_size / self.chunks.block).max(1);
+        let count = (first..self.chunks.chunks).take(most as usize).take_while(|&chunk| !self.chunks.has(chunk)).count();
+        self.requested = first..first + count as u64;
+        let range = self.chunks.byte_range(self.requested.clone(), self.offer.size);
+        Some(ChunkRequest { sha256: self.offer.sha256.clone(), offset: range.start, len: range.end - range.start })
+    }
+
+    /// Take the requested blocks. Returns whether the file is complete.
+    fn write(&mut self, data: &[u8]) -> Result<bool> {
+        let range = self.chunks.byte_range(self.requested.clone(), self.offer.size);
+        let expected = range.end - range.start;
+        if data.len() as u64 != expected {
+            bail!("expected a chunk of {expected} bytes, got {}", data.len());
+        }
+        self.file.seek(SeekFrom::Start(range.start))?;
+        self.file.write_all(data)?;
+        for chunk in self.requested.clone() {
+            self.chunks.set(chunk);
+        }
+        if range.start == self.hashed {
+            self.hasher.update(data);
+            self.hashed = range.end;
+        }
+        self.hash_received()?;
+        Ok(self.chunks.first_missing().is_none())
+    }
+
+    /// Extend the hashed prefix over chunks that arrived earlier, reading
+    /// them back from the partial file
+    fn hash_received(&mut self) -> Result<()> {
+        while self.hashed < self.offer.size && self.chunks.has(self.hashed / self.chunks.block) {
+            let chunk = self.hashed / self.chunks.block;
+            let range = self.chunks.byte_range(chunk..chunk + 1, self.offer.size);
+            let mut data = Vec::with_capacity((range.end - range.start) as usize);
+            self.file.seek(SeekFrom::Start(range.start))?;
+            (&mut self.file).take(range.end - range.start).read_to_end(&mut data)?;
+            self.hasher.update(&data);
+            self.hashed = range.end;
+        }
+        Ok(())
+    }
+
+    /// Check the contents against the offer and move the file into place,
+    /// reporting how the transfer ended to `events`
+    fn finish(mut self, state_dir: Option<&Path>, events: Option<&broadcast::Sender<NodeEvent>>) -> Result<PathBuf> {
+        remove_saved(state_dir, &self.offer.sha256);
+        // Hashed as the last chunk was written, unless none was since it loaded
+        let hashed = self.hash_received();
+        let Download { file, hasher, partial, dir, offer, progress, .. } = self;
+        let result = match hashed {
+            Ok(()) => Self::verify(file, hasher, &partial, &dir, offer),
+            Err(e) => {
+                remove_partial(&partial);
+                Err(e)
+            }
+        };
+        if let (Some(events), Some(event)) = (events, progress.finish(result.as_ref().err().map(|e| format!("{e:#}")))) {
+            let _ = events.send(event);
+        }
+        result
+    }
+
+    /// Close the partial file, check its hash and rename it to its own name
+    fn verify(mut file: File, hasher: Sha256, partial: &Path, dir: &Path, offer: FileOffer) -> Result<PathBuf> {
+        // Closed before the rename, which Windows refuses for open files
+        let flushed = file.flush();
+        drop(file);
+        let digest = hex(&hasher.finalize());
+        if let Err(e) = flushed {
+            remove_partial(partial);
+            return Err(e.into());
+        }
+        if digest != offer.sha256 {
+            remove_partial(partial);
+            return Err(ChecksumMismatch { got: digest, offered: offer.sha256 }.into());
+        }
+        let path = unused_path(dir, &offer.name);
+        fs::rename(partial, &path)?;
+        Ok(path)
+    }
+
+    fn abort(&self, state_dir: Option<&Path>) {
+        remove_saved(state_dir, &self.offer.sha256);
+        remove_partial(&self.partial);
+    }
+
+    fn save(&self, state_dir: &Path) -> Result<()> {
+        let saved = SavedDownload {
+            from: self.from,
+            offer: self.offer.clone(),
+            dir: self.dir.clone(),
+            partial: self.partial.clone(),
+            chunks: self.chunks.clone(),
+            stalled_at: self.stalled_at,
+        };
+        fs::create_dir_all(state_dir).with_context(|| format!("Failed to create {}", state_dir.display()))?;
+        let path = saved_path(state_dir, &self.offer.sha256);
+        fs::write(&path, serde_json::to_vec(&saved)?).with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Pick up a download saved by an earlier run. What arrived before is
+    /// hashed along with the first chunk written, off the event loop.
+    fn load(path: &Path) -> Result<Self> {
+        let saved: SavedDownload = serde_json::from_slice(&fs::read(path)?)?;
+        check_file_name(&saved.offer.name)?;
+        check_sha256(&saved.offer.sha256)?;
+        if saved.chunks.block == 0 || saved.chunks.chunks != saved.offer.size.div_ceil(saved.chunks.block) {
+            bail!("chunk map does not match the file size");
+        }
+        let progress = Progress::new(saved.offer.name.clone(), saved.offer.size, saved.chunks.present(saved.offer.size));
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&saved.partial)
+            .with_context(|| format!("Failed to open {}", saved.partial.display()))?;
+        Ok(Download {
+            from: saved.from,
+            offer: saved.offer,
+            dir: saved.dir,
+            partial: saved.partial,
+            file,
+            chunks: saved.chunks,
+            requested: 0..0,
+            hasher: Sha256::new(),
+            hashed: 0,
+            progress,
+            batch: None,
+            stalled_at: saved.stalled_at,
+        })
+    }
+}
+
+fn remove_partial(partial: &Path) {
+    if let Err(e) = fs::remove_file(partial) {
+        warn!("Failed to remove partial download {}: {e}", partial.display());
+    }
+}
+
+fn saved_path(state_dir: &Path, sha256: &str) -> PathBuf {
+    state_dir.join(format!("{sha256}.json"))
+}
+
+fn remove_saved(state_dir: Option<&Path>, sha256: &str) {
+    if let Some(state_dir) = state_dir {
+        let _ = fs::remove_file(saved_path(state_dir, sha256));
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// A downloaded file whose contents don't hash to what its sender offered
+#[derive(Debug)]
+struct ChecksumMismatch {
+    got: String,
+    offered: String,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "checksum mismatch (got {}, offered {})", self.got, self.offered)
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Files offered together in one clipboard entry
+#[derive(Debug)]
+struct Batch {
+    remaining: usize,
+    failed: bool,
+    paths: Vec<PathBuf>,
+}
+
+/// A received chunk, to be written off the event loop with [`write`](Self::write)
+#[derive(Debug)]
+pub struct Chunk {
+    download: Download,
+    data: Vec<u8>,
+}
+
+impl Chunk {
+    /// Write the chunk into its download and hash what can be hashed. Blocks
+    /// on disk access. The result goes back to [`Downloads::on_written`].
+    pub fn write(mut self) -> Written {
+        let result = self.download.write(&self.data);
+        Written { download: self.download, len: self.data.len() as u64, result }
+    }
+}
+
+/// A chunk [`Chunk::write`] wrote, or failed to
+#[derive(Debug)]
+pub struct Written {
+    download: Download,
+    len: u64,
+    /// Whether the file is complete
+    result: Result<bool>,
+}
+
+/// Files being pulled from peers, each with one chunk request in flight or
+/// one chunk being written, and downloads cut off midway that wait for their
+/// sender to come back
+#[derive(Debug, Default)]
+pub struct Downloads {
+    active: HashMap<OutboundRequestId, Download>,
+    /// Hashes of the files a chunk is being written to
+    writing: HashSet<String>,
+    /// Of those, the ones cancelled meanwhile
+    cancelled: HashSet<String>,
+    /// Interrupted downloads by file hash
+    stalled: HashMap<String, Download>,
+    /// Where interrupted downloads are saved to survive a restart
+    state_dir: Option<PathBuf>,
+    batches: HashMap<u64, Batch>,
+    next_batch: u64,
+    /// Senders of files that arrived corrupt, not yet taken
+    corrupt: Vec<PeerId>,
+    /// How each connected peer is reached, to size chunk requests
+    transports: HashMap<PeerId, Transport>,
+    /// Where progress of large downloads is reported
+    events: Option<broadcast::Sender<NodeEvent>>,
+}
+
+impl Downloads {
+    /// A connection to `peer` was established over `transport`. Chunks
+    /// requested from it from now on are sized for that transport.
+    pub fn set_transport(&mut self, peer: PeerId, transport: Transport) {
+        if self.transports.insert(peer, transport) != Some(transport) {
+            debug!("Requesting {} KiB chunks from {peer} over {transport:?}", self.chunk_size(&peer) / 1024);
+        }
+    }
+
+    /// Bytes to ask `peer` for per chunk request. Peers of unknown transport
+    /// get what suits TCP.
+    fn chunk_size(&self, peer: &PeerId) -> u64 {
+        chunk_size_for(self.transports.get(peer).copied().unwrap_or(Transport::Tcp), MAX_MESSAGE_SIZE)
+    }
+
+    /// Downloads that save interrupted transfers in `state_dir`, picking up
+    /// the ones an earlier run left there, and report progress to `events`
+    pub fn open(state_dir: Option<PathBuf>, events: broadcast::Sender<NodeEvent>) -> Self {
+        let mut downloads = Self { state_dir, events: Some(events), ..Self::default() };
+        let Some(ref dir) = downloads.state_dir else {
+            return downloads;
+        };
+        let Ok(entries) = fs::read_dir(dir) else {
+            return downloads;
+        };
+        for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            match Download::load(&path) {
+                Ok(download) => {
+                    info!(
+                        "Found interrupted download of {} from {} ({} of {} bytes)",
+                        download.offer.name,
+                        download.from,
+                        download.received(),
+                        download.offer.size
+                    );
+                    downloads.stalled.insert(download.offer.sha256.clone(), download);
+                }
+                Err(e) => {
+                    warn!("Dropping saved download {}: {e:#}", path.display());
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        downloads.expire();
+        downloads
+    }
+
+    /// Start pulling the files `from` offered into `dir`. Files that were
+    /// interrupted before continue where they stopped. `send` sends a
+    /// chunk request and returns its id.
+    pub fn start(
+        &mut self,
+        dir: &Path,
+        from: PeerId,
+        offers: &[FileOffer],
+        mut send: impl FnMut(&PeerId, ChunkRequest) -> OutboundRequestId,
+    ) {
+        let batch = self.next_batch;
+        self.next_batch += 1;
+        let mut started = Batch { remaining: 0, failed: false, paths: Vec::new() };
+        for offer in offers {
+            let mut download = if let Some(mut download) = self.stalled.remove(&offer.sha256) {
+                let received = download.received();
+                info!("Resuming download of {} from {from} at {received} of {} bytes", offer.name, offer.size);
+                remove_saved(self.state_dir.as_deref(), &offer.sha256);
+                // The entry it was first offered with is superseded by this one
+                self.settle(download.batch, None);
+                download.from = from;
+                download.batch = Some(batch);
+                download.progress.restart();
+                download
+            } else {
+                match Self::create(dir, from, offer, batch) {
+                    Ok(download) => {
+                        info!("Downloading {} ({} bytes) from {from}", offer.name, offer.size);
+                        download
+                    }
+                    Err(e) => {
+                        warn!("Not downloading {:?} from {from}: {e:#}", offer.name);
+                        started.failed = true;
+                        continue;
+                    }
+                }
+            };
+            let Some(request) = download.next_request(self.chunk_size(&download.from)) else {
+                match download.finish(self.state_dir.as_deref(), self.events.as_ref()) {
+                    Ok(path) => {
+                        info!("Downloaded {} to {}", offer.name, path.display());
+                        started.paths.push(path);
+                    }
+                    Err(e) => {
+                        warn!("Download of {} failed: {e:#}", offer.name);
+                        started.failed = true;
+                    }
+                }
+                continue;
+            };
+            let id = send(&from, request);
+            self.active.insert(id, download);
+            started.remaining += 1;
+        }
+        if started.remaining > 0 {
+            self.batches.insert(batch, started);
+        }
+    }
+
+    fn create(dir: &Path, from: PeerId, offer: &FileOffer, batch: u64) -> Result<Download> {
+        check_file_name(&offer.name)?;
+        check_sha256(&offer.sha256)?;
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let partial = unused_path(dir, &format!("{}{PARTIAL_SUFFIX}", offer.name));
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&partial)
+            .with_context(|| format!("Failed to create {}", partial.display()))?;
+        Ok(Download {
+            from,
+            offer: offer.clone(),
+            dir: dir.to_path_buf(),
+            partial,
+            file,
+            chunks: ChunkMap::new(offer.size),
+            requested: 0..0,
+            hasher: Sha256::new(),
+            hashed: 0,
+            progress: Progress::new(offer.name.clone(), offer.size, 0),
+            batch: Some(batch),
+            stalled_at: 0,
+        })
+    }
+
+    /// Handle the answer to a chunk request. Returns the chunk it carried, to
+    /// be written off the event loop.
+    pub fn on_response(&mut self, request: OutboundRequestId, response: ChunkResponse) -> Option<Chunk> {
+        let download = self.active.remove(&request)?;
+        match response {
+            ChunkResponse::Data(data) => {
+                self.writing.insert(download.offer.sha256.clone());
+                return Some(Chunk { download, data });
+            }
+            ChunkResponse::Unavailable => {
+                let reason = anyhow::anyhow!("the peer no longer offers it");
+                self.fail(download, &reason);
+            }
+            // Worth asking again later, e.g. once the peer's bandwidth cap resets
+            ChunkResponse::Refused(reason) => self.stall(download, &format!("the peer refused: {reason}")),
+        }
+        None
+    }
+
+    /// Carry on after a chunk was written, asking for the next chunk if there
+    /// is one. Returns the downloaded paths once every file of a clipboard
+    /// entry arrived intact.
+    pub fn on_written(
+        &mut self,
+        written: Written,
+        send: impl FnOnce(&PeerId, ChunkRequest) -> OutboundRequestId,
+    ) -> Option<Vec<PathBuf>> {
+        self.settle_written(written, Some(send))
+    }
+
+    /// Whether chunks are still being written
+    pub fn writing(&self) -> bool {
+        !self.writing.is_empty()
+    }
+
+    /// Take a chunk written while shutting down, interrupting its download
+    /// instead of asking for more
+    pub fn suspend_written(&mut self, written: Written) {
+        self.settle_written(written, None::<fn(&PeerId, ChunkRequest) -> OutboundRequestId>);
+    }
+
+    fn settle_written(
+        &mut self,
+        written: Written,
+        send: Option<impl FnOnce(&PeerId, ChunkRequest) -> OutboundRequestId>,
+    ) -> Option<Vec<PathBuf>> {
+        let Written { mut download, len, result } = written;
+        self.writing.remove(&download.offer.sha256);
+        if self.cancelled.remove(&download.offer.sha256) {
+            self.report(download.progress.finish(Some("cancelled".to_string())));
+            info!("Cancelled download of {} at {} of {} bytes", download.offer.name, download.received(), download.offer.size);
+            download.abort(self.state_dir.as_deref());
+            return None;
+        }
+        if result.is_ok() {
+            let event = download.progress.advance(len);
+            self.report(event);
+        }
+        match result {
+            Ok(false) => {
+                let Some(send) = send else {
+                    self.stall(download, "shutting down");
+                    return None;
+                };
+                let request = download.next_request(self.chunk_size(&download.from))?;
+                let id = send(&download.from, request);
+                self.active.insert(id, download);
+                None
+            }
+            Ok(true) => {
+                let (name, batch, from) = (download.offer.name.clone(), download.batch, download.from);
+                match download.finish(self.state_dir.as_deref(), self.events.as_ref()) {
+                    Ok(path) => {
+                        info!("Downloaded {name} to {}", path.display());
+                        self.settle(batch, Some(path))
+                    }
+                    Err(e) => {
+                        warn!("Download of {name} failed: {e:#}");
+                        if e.is::<ChecksumMismatch>() {
+                            self.corrupt.push(from);
+                        }
+                        self.settle(batch, None)
+                    }
+                }
+            }
+            Err(e) => self.fail(download, &e),
+        }
+    }
+
+    /// Give up on `download`, removing what was written so far
+    fn fail(&mut self, download: Download, e: &anyhow::Error) -> Option<Vec<PathBuf>> {
+        warn!("Download of {} failed: {e:#}", download.offer.name);
+        self.report(download.progress.finish(Some(format!("{e:#}"))));
+        download.abort(self.state_dir.as_deref());
+        self.settle(download.batch, None)
+    }
+
+    /// A chunk request got no answer. Unless the peer can't serve files at
+    /// all, the download waits for the peer to come back.
+    pub fn on_failure(&mut self, request: OutboundRequestId, error: &request_response::OutboundFailure) {
+        let Some(download) = self.active.remove(&request) else {
+            return;
+        };
+        if matches!(error, request_response::OutboundFailure::UnsupportedProtocols) {
+            warn!("Download of {} from {} failed: {error}", download.offer.name, download.from);
+            self.report(download.progress.finish(Some(error.to_string())));
+            download.abort(self.state_dir.as_deref());
+            self.settle(download.batch, None);
+        } else {
+            self.stall(download, &error.to_string());
+        }
+    }
+
+    /// Keep an interrupted download, on disk too if there is a state directory
+    fn stall(&mut self, mut download: Download, reason: &str) {
+        download.stalled_at = now_secs();
+        self.report(download.progress.finish(Some(format!("interrupted, {reason}"))));
+        let missing: Vec<String> = download
+            .chunks
+            .missing_ranges()
+            .iter()
+            .map(|range| format!("{}-{}", range.start, range.end - 1))
+            .collect();
+        info!(
+            "Download of {} from {} interrupted at {} of {} bytes ({reason}); missing chunks {}. \
+             It resumes when the peer reconnects or on /resume-transfers",
+            download.offer.name,
+            download.from,
+            download.received(),
+            download.offer.size,
+            missing.join(", ")
+        );
+        if let Some(ref state_dir) = self.state_dir
+            && let Err(e) = download.save(state_dir)
+        {
+            warn!("Failed to save interrupted download of {}: {e:#}", download.offer.name);
+        }
+        self.stalled.insert(download.offer.sha256.clone(), download);
+    }
+
+    /// Ask for the missing chunks of interrupted downloads from `peer`, or
+    /// from every peer. Returns how many downloads resumed.
+    pub fn resume(
+        &mut self,
+        peer: Option<&PeerId>,
+        mut send: impl FnMut(&PeerId, ChunkRequest) -> OutboundRequestId,
+    ) -> usize {
+        self.expire();
+        let hashes: Vec<String> = self
+            .stalled
+            .iter()
+            .filter(|(_, download)| peer.is_none_or(|peer| download.from == *peer))
+            .map(|(sha256, _)| sha256.clone())
+            .collect();
+        for sha256 in &hashes {
+            let Some(mut download) = self.stalled.remove(sha256) else {
+                continue;
+            };
+            let Some(request) = download.next_request(self.chunk_size(&download.from)) else {
+                continue;
+            };
+            download.progress.restart();
+            info!(
+                "Resuming download of {} from {} at {} of {} bytes",
+                download.offer.name,
+                download.from,
+                download.received(),
+                download.offer.size
+            );
+            let id = send(&download.from, request);
+            self.active.insert(id, download);
+        }
+        hashes.len()
+    }
+
+    /// Give up on interrupted downloads whose sender stayed away too long
+    pub fn expire(&mut self) {
+        let cutoff = now_secs().saturating_sub(STALLED_MAX_AGE.as_secs());
+        let expired: Vec<String> = self
+            .stalled
+            .iter()
+            .filter(|(_, download)| download.stalled_at < cutoff)
+            .map(|(sha256, _)| sha256.clone())
+            .collect();
+        for sha256 in expired {
+            if let Some(download) = self.stalled.remove(&sha256) {
+                info!("Giving up on interrupted download of {} from {}", download.offer.name, download.from);
+                download.abort(self.state_dir.as_deref());
+                self.settle(download.batch, None);
+            }
+        }
+    }
+
+    /// Interrupt every download in progress, so it resumes after a restart
+    pub fn suspend_all(&mut self) {
+        let active: Vec<Download> = self.active.drain().map(|(_, download)| download).collect();
+        for download in active {
+            self.stall(download, "shutting down");
+        }
+    }
+
+    /// Peers that sent files not matching their offer since the last call
+    pub fn take_corrupt(&mut self) -> Vec<PeerId> {
+        std::mem::take(&mut self.corrupt)
+    }
+
+    /// Stop every download, interrupted ones included, removing what was
+    /// written so far. Returns how many were stopped.
+    pub fn cancel_all(&mut self) -> usize {
+        let cancelled = self.active.len() + self.stalled.len() + self.writing.len();
+        // Removed once written
+        self.cancelled.extend(self.writing.iter().cloned());
+        let active: Vec<Download> = self.active.drain().map(|(_, download)| download).collect();
+        for download in &active {
+            self.report(download.progress.finish(Some("cancelled".to_string())));
+        }
+        let mut downloads = active;
+        downloads.extend(self.stalled.drain().map(|(_, download)| download));
+        for download in downloads {
+            let received = download.received();
+            info!("Cancelled download of {} at {received} of {} bytes", download.offer.name, download.offer.size);
+            download.abort(self.state_dir.as_deref());
+        }
+        self.batches.clear();
+        cancelled
+    }
+
+    fn report(&self, event: Option<NodeEvent>) {
+        if let (Some(events), Some(event)) = (&self.events, event) {
+            let _ = events.send(event);
+        }
+    }
+
+    fn settle(&mut self, batch: Option<u64>, path: Option<PathBuf>) -> Option<Vec<PathBuf>> {
+        let batch = batch?;
+        let entry = self.batches.get_mut(&batch)?;
+        entry.remaining -= 1;
+        match path {
+            Some(path) => entry.paths.push(path),
+            None => entry.failed = true,
+        }
+        if entry.remaining > 0 {
+            return None;
+        }
+        let entry = self.batches.remove(&batch)?;
+        (!entry.failed).then_some(entry.paths)
+    }
+}
+
+/// Refuse hashes that aren't a hex SHA-256, since they name the file a
+/// download is saved to in the state directory
+fn check_sha256(sha256: &str) -> Result<()> {
+    if sha256.len() != 64 || !sha256.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        bail!("invalid file hash");
+    }
+    Ok(())
+}
+
+/// Refuse names that would escape the download directory
+fn check_file_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        bail!("unsafe file name");
+    }
+    Ok(())
+}
+
+/// `dir/name`, or `dir/name (n)` with the lowest free `n` if that is taken
+fn unused_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match name.split_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (name, String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{stem} ({n}){extension}")))
+        .find(|path| !path.exists())
+        .expect("some numbered name is free")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::MIN_SIZE;
+
+    fn offer(size: u64) -> FileOffer {
+        FileOffer { name: "big.iso".to_string(), size, sha256: "ab".repeat(32), path: None }
+    }
+
+    #[test]
+    fn chunk_responses_round_trip_as_raw_bytes() {
+        let data: Vec<u8> = (0..=255).collect();
+        let encoded = encode_response(ChunkResponse::Data(data.clone()));
+        assert_eq!(encoded.len(), data.len() + 1);
+        assert!(matches!(decode_response(encoded), Ok(ChunkResponse::Data(decoded)) if decoded == data));
+        assert!(matches!(decode_response(encode_response(ChunkResponse::Data(Vec::new()))), Ok(ChunkResponse::Data(empty)) if empty.is_empty()));
+        assert!(matches!(decode_response(encode_response(ChunkResponse::Unavailable)), Ok(ChunkResponse::Unavailable)));
+        let refused = encode_response(ChunkResponse::Refused("over the cap".to_string()));
+        assert!(matches!(decode_response(refused), Ok(ChunkResponse::Refused(reason)) if reason == "over the cap"));
+
+        assert!(decode_response(Vec::new()).is_err());
+        assert!(decode_response(vec![7, 1, 2]).is_err());
+        assert!(decode_response(vec![TAG_REFUSED, 0xff]).is_err());
+    }
+
+    #[test]
+    fn an_upload_reports_progress_then_a_summary() {
+        let mut uploads = Uploads::default();
+        let peer = PeerId::random();
+        let offer = offer(2 * MIN_SIZE);
+        let Some(NodeEvent::TransferProgress { label, done, .. }) = uploads.sent(peer, "laptop", &offer, 0, MIN_SIZE) else {
+            panic!("the first chunk of a large upload is reported");
+        };
+        assert_eq!((label.as_str(), done), ("big.iso to laptop", MIN_SIZE));
+        let Some(NodeEvent::TransferFinished { bytes, error: None, .. }) = uploads.sent(peer, "laptop", &offer, MIN_SIZE, MIN_SIZE) else {
+            panic!("the last chunk ends the upload with a summary");
+        };
+        assert_eq!(bytes, 2 * MIN_SIZE);
+        assert!(uploads.active.is_empty());
+    }
+
+    #[test]
+    fn uploads_to_different_peers_are_told_apart() {
+        let mut uploads = Uploads::default();
+        let offer = offer(2 * MIN_SIZE);
+        let first = uploads.sent(PeerId::random(), "a", &offer, 0, MIN_SIZE);
+        let second = uploads.sent(PeerId::random(), "b", &offer, 0, MIN_SIZE);
+        let (Some(NodeEvent::TransferProgress { id: first, .. }), Some(NodeEvent::TransferProgress { id: second, .. })) = (first, second) else {
+            panic!("both uploads are reported");
+        };
+        assert_ne!(first, second);
+        assert_eq!(uploads.active.len(), 2);
+    }
+
+    #[test]
+    fn a_quiet_puller_is_given_up_on() {
+        let mut uploads = Uploads::default();
+        let peer = PeerId::random();
+        uploads.sent(peer, "laptop", &offer(2 * MIN_SIZE), 0, MIN_SIZE);
+        assert!(uploads.expire().is_empty(), "still pulling");
+        for upload in uploads.active.values_mut() {
+            upload.last -= REQUEST_TIMEOUT * 2;
+        }
+        let expired = uploads.expire();
+        assert!(matches!(expired.as_slice(), [NodeEvent::TransferFinished { error: Some(_), .. }]));
+        assert!(uploads.active.is_empty());
+    }
+
+    #[test]
+    fn chunks_are_sized_for_the_transport_in_whole_blocks() {
+        let quic: Multiaddr = "/ip4/10.0.0.2/udp/4001/quic-v1".parse().unwrap();
+        let tcp: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+        let relayed: Multiaddr = format!("/ip4/10.0.0.9/tcp/4001/p2p/{}/p2p-circuit", PeerId::random()).parse().unwrap();
+        assert_eq!(Transport::of(&quic), Transport::Quic);
+        assert_eq!(Transport::of(&tcp), Transport::Tcp);
+        assert_eq!(Transport::of(&relayed), Transport::Relayed);
+        assert_eq!(Transport::of(&crate::stdio::address()), Transport::Stdio);
+
+        assert_eq!(chunk_size_for(Transport::Quic, MAX_MESSAGE_SIZE), MAX_CHUNK_SIZE);
+        assert_eq!(chunk_size_for(Transport::Relayed, MAX_MESSAGE_SIZE), BLOCK_SIZE);
+        // Never more than a response can carry, and never less than a block
+        let small = JSON_BYTE_CHARS * (BLOCK_SIZE * 3 / 2) + RESPONSE_OVERHEAD;
+        assert_eq!(chunk_size_for(Transport::Quic, small), BLOCK_SIZE);
+        assert_eq!(chunk_size_for(Transport::Tcp, 0), BLOCK_SIZE);
+    }
+
+    #[test]
+    fn downloads_saved_with_the_old_chunk_size_keep_it() {
+        let saved: ChunkMap = serde_json::from_str(r#"{"chunks":2,"bits":[1]}"#).unwrap();
+        assert_eq!(saved.block, LEGACY_BLOCK_SIZE);
+        assert_eq!(saved.byte_range(1..2, LEGACY_BLOCK_SIZE + 10), LEGACY_BLOCK_SIZE..LEGACY_BLOCK_SIZE + 10);
+        assert_eq!(saved.first_missing(), Some(1));
+    }
+
+    #[test]
+    fn offers_with_a_hash_that_is_not_hex_are_refused() {
+        let dir = crate::testing::TempDir::new();
+        let state_dir = dir.path().join("state");
+        let victim = dir.path().join("victim.json");
+        fs::write(&victim, "keep me").unwrap();
+        let mut downloads = Downloads::open(Some(state_dir.clone()), broadcast::channel(8).0);
+        let offers: Vec<FileOffer> = ["../victim", "/tmp/x", &"AB".repeat(32), &"ab".repeat(31)]
+            .into_iter()
+            .map(|sha256| FileOffer { sha256: sha256.to_string(), ..offer(0) })
+            .collect();
+        downloads.start(&dir.path().join("downloads"), PeerId::random(), &offers, |_, _| -> OutboundRequestId {
+            unreachable!("nothing is requested for a refused offer")
+        });
+
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "keep me");
+        assert!(!state_dir.exists());
+        assert!(fs::read_dir(dir.path().join("downloads")).is_err(), "a download was started");
+    }
+
+    #[test]
+    fn saved_downloads_with_a_bad_hash_are_dropped() {
+        let dir = crate::testing::TempDir::new();
+        let saved = SavedDownload {
+            from: PeerId::random(),
+            offer: FileOffer { sha256: "../../victim".to_string(), ..offer(1) },
+            dir: dir.path().to_path_buf(),
+            partial: dir.path().join("big.iso.part"),
+            chunks: ChunkMap::new(1),
+            stalled_at: 0,
+        };
+        fs::write(dir.path().join("big.iso.part"), [0]).unwrap();
+        let path = dir.path().join("saved.json");
+        fs::write(&path, serde_json::to_vec(&saved).unwrap()).unwrap();
+        assert!(Download::load(&path).is_err());
+    }
+}
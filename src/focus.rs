@@ -0,0 +1,54 @@
+/// Window titles can be long; only this many characters are kept
+const MAX_LABEL_CHARS: usize = 120;
+
+/// Tells which application content is being copied from
+pub trait FocusProvider: Send + Sync {
+    /// Title of the focused window, if the platform exposes it. Called off
+    /// the event loop, so it may block briefly.
+    fn focused_window(&self) -> Option<String>;
+}
+
+/// For platforms that don't expose the focused window
+#[derive(Debug, Default)]
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+pub struct NoFocus;
+
+impl FocusProvider for NoFocus {
+    fn focused_window(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Asks `xdotool` for the title of the active X11 window. Wayland doesn't
+/// tell clients which window is focused, and nothing is found without
+/// `xdotool` installed.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+pub struct ActiveWindow;
+
+#[cfg(target_os = "linux")]
+impl FocusProvider for ActiveWindow {
+    fn focused_window(&self) -> Option<String> {
+        let output = std::process::Command::new("xdotool")
+            .args(["getactivewindow", "getwindowname"])
+            .stderr(std::process::Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        label(&String::from_utf8_lossy(&output.stdout))
+    }
+}
+
+/// The provider for this platform
+pub fn platform_provider() -> Box<dyn FocusProvider> {
+    #[cfg(target_os = "linux")]
+    return Box::new(ActiveWindow);
+    #[cfg(not(target_os = "linux"))]
+    Box::new(NoFocus)
+}
+
+/// A window title trimmed to one short line, or `None` if it is blank
+pub fn label(title: &str) -> Option<String> {
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!title.is_empty()).then(|| title.chars().take(MAX_LABEL_CHARS).collect())
+}
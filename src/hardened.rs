@@ -0,0 +1,206 @@
+use crate::address_book::AddressBook;
+use crate::Args;
+use libp2p::core::{transport::PortUse, Endpoint};
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{
+    dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent, THandlerOutEvent,
+    ToSwarm,
+};
+use libp2p::{Multiaddr, PeerId};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::task::{Context, Poll};
+
+/// Largest received image accepted in hardened mode, in bytes
+const MAX_IMAGE_BYTES: usize = 4 * 1024 * 1024;
+/// Retained content older than this many seconds is neither offered nor
+/// accepted in hardened mode
+const MAX_AGE_SECS: u64 = 10;
+
+/// Image size limit in effect: `--max-image-bytes`, capped in hardened mode.
+/// 0 for no limit.
+pub fn max_image_bytes(args: &Args) -> usize {
+    match (args.hardened, args.max_image_bytes) {
+        (false, max) => max,
+        (true, 0) => MAX_IMAGE_BYTES,
+        (true, max) => max.min(MAX_IMAGE_BYTES),
+    }
+}
+
+/// Age limit in effect for retained content: `--retained-max-age`, capped in
+/// hardened mode
+pub fn retained_max_age(args: &Args) -> u64 {
+    if args.hardened { args.retained_max_age.min(MAX_AGE_SECS) } else { args.retained_max_age }
+}
+
+/// Why an inbound connection was denied in hardened mode
+#[derive(Debug)]
+pub struct Untrusted(pub PeerId);
+
+impl std::fmt::Display for Untrusted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is not a known group member", self.0)
+    }
+}
+
+impl std::error::Error for Untrusted {}
+
+/// Denies inbound connections while hardened mode is on, unless they come
+/// from a group member in the address book or a peer named in a `--connect`
+/// address. The connection is refused before any other behaviour is handed
+/// it. Outbound connections are ours and always allowed.
+#[derive(Debug, Default)]
+pub struct Gate {
+    enabled: bool,
+    trusted: HashSet<PeerId>,
+}
+
+impl Gate {
+    /// Follow `/hardened`, `--connect` and the address book, after any of
+    /// them changed
+    pub fn update(&mut self, args: &Args, address_book: &AddressBook) {
+        self.enabled = args.hardened;
+        self.trusted = address_book.members().chain(connect_peers(args)).collect();
+    }
+}
+
+/// Peers named in a `--connect` address
+fn connect_peers(args: &Args) -> impl Iterator<Item = PeerId> + '_ {
+    args.connect.iter().flatten().flat_map(|addr| {
+        addr.iter().filter_map(|protocol| match protocol {
+            Protocol::P2p(peer) => Some(peer),
+            _ => None,
+        })
+    })
+}
+
+impl NetworkBehaviour for Gate {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        peer: PeerId,
+        _local_addr: &Multiaddr,
+        _remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        if self.enabled && !self.trusted.contains(&peer) {
+            return Err(ConnectionDenied::new(Untrusted(peer)));
+        }
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _peer: PeerId,
+        _addr: &Multiaddr,
+        _role_override: Endpoint,
+        _port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, _event: FromSwarm) {}
+
+    fn on_connection_handler_event(&mut self, _peer: PeerId, _connection: ConnectionId, event: THandlerOutEvent<Self>) {
+        match event {}
+    }
+
+    fn poll(&mut self, _cx: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{NodeCommand, NodeEvent};
+    use crate::in_process::Node;
+    use clap::Parser;
+    use std::time::Duration;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::try_parse_from(["clipboard-sync"].iter().chain(flags)).unwrap()
+    }
+
+    fn admits(gate: &mut Gate, peer: PeerId) -> bool {
+        let addr: Multiaddr = "/ip4/192.168.1.5/tcp/4001".parse().unwrap();
+        gate.handle_established_inbound_connection(ConnectionId::new_unchecked(0), peer, &addr, &addr).is_ok()
+    }
+
+    #[test]
+    fn only_known_peers_get_in_while_hardened() {
+        let (member, named, stranger) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut book = AddressBook::load(None, 30).unwrap();
+        book.add_member(member, []);
+        let connect = format!("/ip4/192.168.1.6/tcp/4001/p2p/{named}");
+        let mut gate = Gate::default();
+        gate.update(&args(&["--hardened", "--connect", &connect]), &book);
+        assert!(admits(&mut gate, member));
+        assert!(admits(&mut gate, named));
+        assert!(!admits(&mut gate, stranger));
+
+        // A peer is trusted from the moment it joins the book
+        book.add_member(stranger, []);
+        gate.update(&args(&["--hardened", "--connect", &connect]), &book);
+        assert!(admits(&mut gate, stranger));
+    }
+
+    #[test]
+    fn everyone_gets_in_otherwise() {
+        let (stranger, book) = (PeerId::random(), AddressBook::load(None, 30).unwrap());
+        let mut gate = Gate::default();
+        gate.update(&args(&["--hardened"]), &book);
+        assert!(!admits(&mut gate, stranger));
+
+        // Reloaded without --hardened, the same gate lets it in
+        gate.update(&args(&[]), &book);
+        assert!(admits(&mut gate, stranger));
+    }
+
+    #[test]
+    fn limits_are_capped_while_hardened() {
+        assert_eq!(max_image_bytes(&args(&["--hardened"])), MAX_IMAGE_BYTES);
+        assert_eq!(max_image_bytes(&args(&["--hardened", "--max-image-bytes", "1000"])), 1000);
+        assert_eq!(max_image_bytes(&args(&["--max-image-bytes", "100000000"])), 100_000_000);
+        assert_eq!(retained_max_age(&args(&["--hardened", "--retained-max-age", "600"])), MAX_AGE_SECS);
+        assert_eq!(retained_max_age(&args(&["--retained-max-age", "600"])), 600);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unknown_peers_never_reach_a_hardened_node() {
+        let mut hardened = Node::start(&["--clipboard", "--hardened"]).unwrap();
+        let address = hardened.address.to_string();
+        let stranger = Node::start(&["--clipboard", "--connect", &address]).unwrap();
+        let stranger_id = stranger.peer_id;
+        let connected = hardened
+            .wait_for(Duration::from_secs(2), |event| {
+                matches!(event, NodeEvent::PeerConnected { peer, .. } if *peer == stranger_id).then_some(())
+            })
+            .await;
+        assert!(connected.is_err(), "the stranger got a connection");
+        stranger.stop().await.unwrap();
+        hardened.stop().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_stranger_gets_in_once_hardened_mode_is_turned_off() {
+        let mut hardened = Node::start(&["--clipboard", "--hardened"]).unwrap();
+        let stranger = Node::start(&["--clipboard", "--connect", &hardened.address.to_string()]).unwrap();
+        let stranger_id = stranger.peer_id;
+        let connected = |event: &NodeEvent| matches!(event, NodeEvent::PeerConnected { peer, .. } if *peer == stranger_id).then_some(());
+        assert!(hardened.wait_for(Duration::from_secs(2), connected).await.is_err(), "the stranger got a connection");
+
+        hardened.command(NodeCommand::Hardened(false));
+        // Commands are handled by the event loop, give it a moment
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // Nothing dials again on its own, a network change makes it
+        stranger.command(NodeCommand::NetworkChanged);
+        hardened.wait_for(Duration::from_secs(10), connected).await.unwrap();
+
+        stranger.stop().await.unwrap();
+        hardened.stop().await.unwrap();
+    }
+}
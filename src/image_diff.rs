@@ -0,0 +1,168 @@
+use crate::clipboard::ClipboardContent;
+use anyhow::{bail, Context, Result};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+/// Recent full images kept as diff bases. Senders diff against the newest
+/// image of the same size; receivers need the same one to reconstruct.
+pub const CACHE_SIZE: usize = 2;
+/// Diffs are only sent if they are at most this fraction of the full image
+const MAX_DIFF_RATIO: f64 = 0.5;
+
+/// Encode `image` as the deflated XOR against `base`. Unchanged pixels XOR to
+/// zero, so overlapping screenshots compress to a fraction of a full send.
+pub fn encode(base: &[u8], image: &[u8]) -> Result<Vec<u8>> {
+    if base.len() != image.len() {
+        bail!("Cannot diff images of {} and {} bytes", base.len(), image.len());
+    }
+    let xor: Vec<u8> = base.iter().zip(image).map(|(a, b)| a ^ b).collect();
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(&xor)?;
+    Ok(encoder.finish()?)
+}
+
+/// Rebuild the image a diff from [`encode`] was made from
+pub fn decode(base: &[u8], diff: &[u8]) -> Result<Vec<u8>> {
+    // Read one byte past the expected size to catch oversized diffs without
+    // inflating an arbitrarily large payload
+    let mut xor = Vec::with_capacity(base.len());
+    DeflateDecoder::new(diff)
+        .take(base.len() as u64 + 1)
+        .read_to_end(&mut xor)
+        .context("Corrupt image diff")?;
+    if xor.len() != base.len() {
+        bail!("Image diff is {} bytes but its base is {}", xor.len(), base.len());
+    }
+    Ok(base.iter().zip(&xor).map(|(a, b)| a ^ b).collect())
+}
+
+/// Identifier of an image used as a diff base, the hex SHA-256 of its bytes
+pub fn image_id(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Full images recently seen on the clipboard topics, sent or received.
+/// Cloning is cheap, so the codec workers diff against a snapshot instead of
+/// holding the shared cache locked.
+#[derive(Debug, Default, Clone)]
+pub struct ImageCache {
+    images: VecDeque<CachedImage>,
+}
+
+/// A full image ready to go into an [`ImageCache`], hashed already
+#[derive(Debug, Clone)]
+pub struct CachedImage {
+    id: String,
+    width: u32,
+    height: u32,
+    data: Arc<[u8]>,
+}
+
+impl CachedImage {
+    /// `content` as a diff base, if it is a full image. Anything else,
+    /// including diffs, gives None.
+    pub fn of(content: &ClipboardContent) -> Option<Self> {
+        // Spilled images stay out of memory
+        if content.diff_base.is_some() || content.spilled.is_some() {
+            return None;
+        }
+        let (Some(data), Some(width), Some(height)) = (content.image(), content.width, content.height) else {
+            return None;
+        };
+        Some(Self { id: image_id(&data), width, height, data: data.into() })
+    }
+}
+
+impl ImageCache {
+    /// Remember a full image. Anything else, including diffs, is ignored.
+    pub fn insert(&mut self, content: &ClipboardContent) {
+        if let Some(image) = CachedImage::of(content) {
+            self.add(image);
+        }
+    }
+
+    /// Remember an image hashed earlier, off the event loop
+    pub fn add(&mut self, image: CachedImage) {
+        self.images.retain(|cached| cached.id != image.id);
+        if self.images.len() == CACHE_SIZE {
+            self.images.pop_back();
+        }
+        self.images.push_front(image);
+    }
+
+    /// Replace a full image with a diff against the newest cached image of
+    /// the same size, if that saves enough to be worth it
+    pub fn diff(&self, content: &ClipboardContent) -> Option<ClipboardContent> {
+        let data = content.image()?;
+        let base = self.images.iter().find(|image| {
+            Some(image.width) == content.width
+                && Some(image.height) == content.height
+                && image.data.len() == data.len()
+                && *image.data != *data
+        })?;
+        let diff = encode(&base.data, &data).ok()?;
+        if diff.len() as f64 > data.len() as f64 * MAX_DIFF_RATIO {
+            return None;
+        }
+        let mut diffed = content.clone();
+        diffed.data = diff;
+        diffed.diff_base = Some(base.id.clone());
+        Some(diffed)
+    }
+
+    /// Turn a received diff back into the full image. Fails if its base is
+    /// not cached, in which case the full image has to be fetched instead.
+    pub fn reconstruct(&self, mut content: ClipboardContent) -> Result<ClipboardContent> {
+        let Some(base_id) = content.diff_base.take() else {
+            return Ok(content);
+        };
+        let base = self
+            .images
+            .iter()
+            .find(|image| image.id == base_id)
+            .with_context(|| format!("Diff base {base_id} is not cached"))?;
+        content.data = decode(&base.data, &content.data)?;
+        Ok(content)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 64x64 RGBA gradient with its first `changed` pixels painted over,
+    /// like a screenshot annotated in one corner
+    fn screenshot(changed: usize) -> Vec<u8> {
+        let mut pixels: Vec<u8> = (0..64 * 64).flat_map(|i| [(i % 64) as u8, (i / 64) as u8, 0x80, 0xff]).collect();
+        pixels[..changed * 4].fill(0xff);
+        pixels
+    }
+
+    #[test]
+    fn similar_images_are_sent_as_a_smaller_diff_that_rebuilds_exactly() {
+        let mut cache = ImageCache::default();
+        cache.insert(&ClipboardContent::new_image(screenshot(0), 64, 64));
+        let next = ClipboardContent::new_image(screenshot(100), 64, 64);
+
+        let diffed = cache.diff(&next).expect("an overlapping image is diffed");
+        assert!(diffed.data.len() < next.data.len());
+        assert_eq!(cache.reconstruct(diffed).unwrap().data, next.data);
+    }
+
+    #[test]
+    fn images_of_another_size_or_without_their_base_are_sent_in_full() {
+        let mut cache = ImageCache::default();
+        cache.insert(&ClipboardContent::new_image(screenshot(0), 64, 64));
+        let resized = ClipboardContent::new_image(vec![0x80; 32 * 32 * 4], 32, 32);
+        assert!(cache.diff(&resized).is_none());
+
+        let diffed = cache.diff(&ClipboardContent::new_image(screenshot(100), 64, 64)).unwrap();
+        assert!(ImageCache::default().reconstruct(diffed).is_err());
+    }
+}
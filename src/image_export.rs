@@ -0,0 +1,88 @@
+use crate::clipboard::{ClipboardContent, ContentType};
+use crate::queue;
+use anyhow::{Context, Result};
+use libp2p::PeerId;
+use log::{info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Received images waiting to be saved. Past that the oldest is dropped
+/// unsaved, rather than holding any number of whole images in memory.
+const QUEUED_IMAGES: usize = 8;
+
+/// Start the task that saves received images as PNG files in `dir`, for
+/// folder-watching automations to pick up. Images are sent in along with the
+/// peer that copied them and saved one at a time, off the event loop. Sending
+/// hands back the oldest waiting image if it had to make room.
+pub fn spawn(dir: PathBuf) -> Result<queue::Sender<(PeerId, ClipboardContent)>> {
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create image export directory {}", dir.display()))?;
+    let (input_tx, mut input_rx) = queue::channel::<(PeerId, ClipboardContent)>(QUEUED_IMAGES);
+    tokio::spawn(async move {
+        while let Some((from, content)) = input_rx.recv().await {
+            if content.content_type != ContentType::Image {
+                continue;
+            }
+            let dir = dir.clone();
+            match tokio::task::spawn_blocking(move || save(&dir, from, &content)).await {
+                Ok(Ok(path)) => info!("Saved received image to {}", path.display()),
+                Ok(Err(e)) => warn!("Failed to export a received image: {e:#}"),
+                Err(e) => warn!("Image export task failed: {e}"),
+            }
+        }
+    });
+    Ok(input_tx)
+}
+
+/// Name of the file an image copied by `from` at `timestamp` is saved as,
+/// `n` telling apart images that would otherwise get the same name
+fn file_name(from: PeerId, timestamp: u64, n: usize) -> String {
+    match n {
+        0 => format!("clipboard-{timestamp}-{from}.png"),
+        n => format!("clipboard-{timestamp}-{from}-{n}.png"),
+    }
+}
+
+/// Write `content`, an image, to a new PNG file in `dir`. The file only
+/// appears under its name once it is complete, so a watcher never reads half
+/// an image.
+fn save(dir: &Path, from: PeerId, content: &ClipboardContent) -> Result<PathBuf> {
+    let (width, height) = (content.width.unwrap_or(0), content.height.unwrap_or(0));
+    let bytes = content.bytes()?;
+    let rgba = crate::imaging::normalize_rgba(&bytes, width, height, content.channels)?;
+    let path = (0..)
+        .map(|n| dir.join(file_name(from, content.timestamp, n)))
+        .find(|path| !path.exists())
+        .expect("unbounded range");
+    let partial = path.with_extension("png.part");
+    image::save_buffer_with_format(&partial, &rgba, width, height, image::ColorType::Rgba8, image::ImageFormat::Png)
+        .with_context(|| format!("Failed to write {}", partial.display()))?;
+    fs::rename(&partial, &path).with_context(|| format!("Failed to move the image into place at {}", path.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn images_are_saved_as_pngs_named_after_their_copy() {
+        let dir = TempDir::new();
+        let from = PeerId::random();
+        let pixels: Vec<u8> = (0..2 * 3 * 4).map(|i| i as u8 * 10).collect();
+        let content = ClipboardContent { timestamp: 1700000000000, ..ClipboardContent::new_image(pixels.clone(), 2, 3) };
+
+        let path = save(dir.path(), from, &content).unwrap();
+        assert_eq!(path.file_name().unwrap().to_string_lossy(), format!("clipboard-1700000000000-{from}.png"));
+        let decoded = image::open(&path).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::Rgba8);
+        assert_eq!((decoded.width(), decoded.height()), (2, 3));
+        assert_eq!(decoded.into_rgba8().into_raw(), pixels);
+
+        // The same copy again doesn't overwrite the first
+        let again = save(dir.path(), from, &content).unwrap();
+        assert_eq!(again.file_name().unwrap().to_string_lossy(), format!("clipboard-1700000000000-{from}-1.png"));
+        assert!(path.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2, "a partial file was left behind");
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::{bail, Context, Result};
+use std::borrow::Cow;
+
+/// Bytes per pixel of the RGBA layout arboard expects
+const RGBA: usize = 4;
+/// Bytes per pixel of packed RGB buffers sent by some implementations
+const RGB: usize = 3;
+/// Largest factor images are scaled by
+pub const MAX_SCALE: f32 = 8.0;
+/// Largest RGBA buffer scaling may produce
+const MAX_SCALED_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Bring an image buffer into the tightly packed RGBA layout arboard expects.
+///
+/// With `channels` given, the buffer must hold exactly `width * height *
+/// channels` bytes: RGBA passes through untouched and RGB gets an opaque alpha
+/// channel. Without it the layout is inferred from the size: exact RGBA and
+/// packed RGB buffers are handled the same way, and RGBA rows padded to a
+/// larger stride are repacked when the stride can be inferred (the buffer
+/// splits evenly into `height` rows). Anything else is rejected with the
+/// expected and actual sizes.
+pub fn normalize_rgba(data: &[u8], width: u32, height: u32, channels: Option<u8>) -> Result<Cow<'_, [u8]>> {
+    let (width, height) = (width as usize, height as usize);
+    if width == 0 || height == 0 {
+        bail!("Image has invalid dimensions {width}x{height}");
+    }
+
+    let (pixels, expected) = width
+        .checked_mul(height)
+        .and_then(|pixels| Some((pixels, pixels.checked_mul(RGBA)?)))
+        .context("Image dimensions overflow")?;
+
+    if let Some(channels) = channels {
+        let channels = channels as usize;
+        if channels != RGBA && channels != RGB {
+            bail!("Image has {channels} channels, only RGB (3) and RGBA (4) are supported");
+        }
+        if data.len() != pixels * channels {
+            bail!(
+                "Image buffer is {} bytes but a {width}x{height} image with {channels} channels needs {} bytes",
+                data.len(),
+                pixels * channels,
+            );
+        }
+    }
+
+    if data.len() == expected {
+        return Ok(Cow::Borrowed(data));
+    }
+
+    if data.len() == pixels * RGB {
+        let mut rgba = Vec::with_capacity(expected);
+        for pixel in data.chunks_exact(RGB) {
+            rgba.extend_from_slice(pixel);
+            rgba.push(u8::MAX);
+        }
+        return Ok(Cow::Owned(rgba));
+    }
+
+    let row_len = width * RGBA;
+    if channels.is_none() && data.len() > expected && data.len().is_multiple_of(height) {
+        let stride = data.len() / height;
+        let mut rgba = Vec::with_capacity(expected);
+        for row in data.chunks_exact(stride) {
+            rgba.extend_from_slice(&row[..row_len]);
+        }
+        return Ok(Cow::Owned(rgba));
+    }
+
+    bail!(
+        "Image buffer is {} bytes but a {width}x{height} RGBA image needs {expected} bytes \
+         (or {} bytes as RGB, or a multiple of {height} rows of at least {row_len} bytes)",
+        data.len(),
+        pixels * RGB,
+    )
+}
+
+/// Resize an RGBA image by `factor`, at most [`MAX_SCALE`], using Lanczos
+/// resampling, returning the new buffer and its dimensions. Images that
+/// would grow past 512 MiB are refused before anything is allocated.
+pub fn scale_image(data: &[u8], width: u32, height: u32, factor: f32) -> Result<(Vec<u8>, u32, u32)> {
+    if !(factor.is_finite() && factor > 0.0) {
+        bail!("Invalid image scale factor {factor}");
+    }
+    let factor = f64::from(factor.min(MAX_SCALE));
+    let new_width = ((f64::from(width) * factor).round() as u32).max(1);
+    let new_height = ((f64::from(height) * factor).round() as u32).max(1);
+    let size = u64::from(new_width) * u64::from(new_height) * RGBA as u64;
+    if size > MAX_SCALED_BYTES {
+        bail!("Scaling a {width}x{height} image to {new_width}x{new_height} would take {size} bytes");
+    }
+    let image = image::RgbaImage::from_raw(width, height, data.to_vec())
+        .context("Image buffer does not match its dimensions")?;
+    let resized = image::imageops::resize(&image, new_width, new_height, image::imageops::FilterType::Lanczos3);
+    Ok((resized.into_raw(), new_width, new_height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgba_passes_through_untouched() {
+        let data = [1, 2, 3, 4, 5, 6, 7, 8];
+        for channels in [None, Some(4)] {
+            let rgba = normalize_rgba(&data, 2, 1, channels).unwrap();
+            assert!(matches!(rgba, Cow::Borrowed(_)));
+            assert_eq!(*rgba, data);
+        }
+    }
+
+    #[test]
+    fn rgb_gets_an_opaque_alpha_channel() {
+        let data = [1, 2, 3, 4, 5, 6];
+        for channels in [None, Some(3)] {
+            assert_eq!(*normalize_rgba(&data, 2, 1, channels).unwrap(), [1, 2, 3, 255, 4, 5, 6, 255]);
+        }
+    }
+
+    #[test]
+    fn padded_rows_are_repacked() {
+        // Two rows of one pixel, each padded to 6 bytes
+        let data = [1, 2, 3, 4, 0, 0, 5, 6, 7, 8, 0, 0];
+        assert_eq!(*normalize_rgba(&data, 1, 2, None).unwrap(), [1, 2, 3, 4, 5, 6, 7, 8]);
+        // With the channels given the stride isn't guessed
+        assert!(normalize_rgba(&data, 1, 2, Some(4)).is_err());
+    }
+
+    #[test]
+    fn buffers_that_match_no_layout_are_rejected() {
+        assert!(normalize_rgba(&[0; 8], 0, 2, None).is_err());
+        assert!(normalize_rgba(&[0; 7], 2, 1, None).is_err());
+        assert!(normalize_rgba(&[0; 8], 2, 1, Some(3)).is_err());
+        assert!(normalize_rgba(&[0; 4], 1, 1, Some(2)).is_err());
+        assert!(normalize_rgba(&[0; 8], u32::MAX, u32::MAX, None).is_err());
+    }
+
+    #[test]
+    fn images_are_scaled_by_the_factor() {
+        let (bytes, width, height) = scale_image(&[9; 4 * 4 * 4], 4, 4, 0.5).unwrap();
+        assert_eq!((width, height, bytes.len()), (2, 2, 2 * 2 * 4));
+        let (_, width, height) = scale_image(&[9; 4], 1, 1, 0.01).unwrap();
+        assert_eq!((width, height), (1, 1));
+    }
+
+    #[test]
+    fn scaling_is_bounded() {
+        let (_, width, height) = scale_image(&[9; 4], 1, 1, 1e30).unwrap();
+        assert_eq!((width, height), (MAX_SCALE as u32, MAX_SCALE as u32));
+        // Refused before the buffer is even looked at
+        assert!(scale_image(&[], 8192, 8192, MAX_SCALE).is_err());
+        assert!(scale_image(&[9; 4], 1, 1, f32::NAN).is_err());
+        assert!(scale_image(&[9; 4], 1, 1, 0.0).is_err());
+    }
+}
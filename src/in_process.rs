@@ -0,0 +1,145 @@
+use crate::control::{self, NodeCommand, NodeEvent};
+use crate::profile::Profile;
+use crate::system_clipboard::MemoryClipboard;
+use crate::{run_node, Args, NodeEnv};
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use libp2p::{identity, multiaddr::Protocol, Multiaddr, PeerId};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+/// In-memory transport ports are shared by the whole process
+static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+
+/// A node running in this process, on an in-memory clipboard and transport.
+/// It runs the same event loop as a node started from the command line, and
+/// is driven through the same commands the console sends.
+pub struct Node {
+    pub peer_id: PeerId,
+    /// Where other in-process nodes `--connect` to
+    pub address: Multiaddr,
+    /// The node's clipboard; copying to it plays the user
+    pub clipboard: MemoryClipboard,
+    commands: mpsc::UnboundedSender<NodeCommand>,
+    events: broadcast::Receiver<NodeEvent>,
+    task: JoinHandle<Result<()>>,
+    /// What the node was started with, for `restart`
+    key: identity::Keypair,
+    port: u16,
+    flags: Vec<String>,
+    profile: Option<PathBuf>,
+}
+
+impl Node {
+    /// Start a node with a fresh identity and `flags`, e.g.
+    /// `["--clipboard"]`. Nothing else is enabled, whatever the user's own
+    /// config says.
+    pub fn start(flags: &[&str]) -> Result<Self> {
+        let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+        let flags = flags.iter().map(|flag| flag.to_string()).collect();
+        Self::launch(identity::Keypair::generate_ed25519(), port, flags, None, MemoryClipboard::default())
+    }
+
+    /// Start a node like [`start`](Self::start) that keeps its profile in
+    /// `dir`, so what it saves there outlives a `restart`
+    pub fn start_in(dir: &Path, flags: &[&str]) -> Result<Self> {
+        let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+        let flags = flags.iter().map(|flag| flag.to_string()).collect();
+        Self::launch(identity::Keypair::generate_ed25519(), port, flags, Some(dir.to_path_buf()), MemoryClipboard::default())
+    }
+
+    /// Stop the node and start it again with the same identity, address,
+    /// flags, profile and clipboard, like a machine whose node was restarted
+    pub async fn restart(self) -> Result<Self> {
+        let (key, port, flags, profile, clipboard) =
+            (self.key.clone(), self.port, self.flags.clone(), self.profile.clone(), self.clipboard.clone());
+        self.stop().await?;
+        Self::launch(key, port, flags, profile, clipboard)
+    }
+
+    fn launch(
+        key: identity::Keypair,
+        port: u16,
+        flags: Vec<String>,
+        profile: Option<PathBuf>,
+        clipboard: MemoryClipboard,
+    ) -> Result<Self> {
+        let port_flag = port.to_string();
+        let args = Args::try_parse_from(
+            ["clipboard-sync", "--port", &port_flag].into_iter().chain(flags.iter().map(String::as_str)),
+        )?;
+        let peer_id = key.public().to_peer_id();
+        let (event_tx, events) = broadcast::channel(control::EVENT_CAPACITY);
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let env = NodeEnv {
+            key: key.clone(),
+            profile: profile.as_deref().map(Profile::at),
+            clipboard: clipboard.connector(),
+            in_memory: true,
+            stdin_content: None,
+            front_end: None,
+            commands: (commands.clone(), command_rx),
+            events: event_tx,
+        };
+        Ok(Self {
+            peer_id,
+            address: Multiaddr::empty().with(Protocol::Memory(port.into())).with(Protocol::P2p(peer_id)),
+            clipboard,
+            commands,
+            events,
+            task: tokio::spawn(run_node(args, env)),
+            key,
+            port,
+            flags,
+            profile,
+        })
+    }
+
+    /// Send a command, as typed in the console
+    pub fn command(&self, command: NodeCommand) {
+        let _ = self.commands.send(command);
+    }
+
+    /// Send the clipboard's current content to `peer` alone over a direct
+    /// request, like `/send-to`. Failures are logged, not returned.
+    pub fn send_to(&self, peer: PeerId) {
+        self.command(NodeCommand::SendTo(peer));
+    }
+
+    /// A new subscriber to the node's events, which sees those sent from now on
+    pub fn events(&self) -> broadcast::Receiver<NodeEvent> {
+        self.events.resubscribe()
+    }
+
+    /// Wait up to `timeout` for an event `matches` picks, returning what it
+    /// made of it
+    pub async fn wait_for<T>(&mut self, timeout: Duration, mut matches: impl FnMut(&NodeEvent) -> Option<T>) -> Result<T> {
+        let wait = async {
+            loop {
+                match self.events.recv().await {
+                    Ok(event) => {
+                        if let Some(found) = matches(&event) {
+                            return Ok(found);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => {
+                        // Say why, if it stopped on an error
+                        (&mut self.task).await??;
+                        bail!("the node stopped")
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.context("timed out")?
+    }
+
+    /// Shut the node down like `/quit` and wait until it has
+    pub async fn stop(self) -> Result<()> {
+        self.command(NodeCommand::Quit);
+        self.task.await?
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::{bail, Context, Result};
+use if_addrs::{IfAddr, Interface};
+use libp2p::Multiaddr;
+use libp2p::multiaddr::Protocol;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How often interface addresses are re-read to follow DHCP renewals and VPNs
+/// coming up or going down
+pub const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The network interfaces selected with `--interface`, each by name (all of
+/// its addresses) or by one of its addresses
+#[derive(Debug)]
+pub struct InterfaceSet {
+    selectors: Vec<String>,
+    current: Vec<Interface>,
+}
+
+impl InterfaceSet {
+    /// Resolve the selectors. Fails if any of them matches no interface.
+    pub fn new(selectors: &[String]) -> Result<Self> {
+        let all = if_addrs::get_if_addrs().context("Failed to list network interfaces")?;
+        for selector in selectors {
+            if !all.iter().any(|interface| matches(interface, selector)) {
+                let mut names: Vec<&str> = all.iter().map(|interface| interface.name.as_str()).collect();
+                names.sort_unstable();
+                names.dedup();
+                bail!("No network interface named or with address {selector} (available: {})", names.join(", "));
+            }
+        }
+        let mut set = Self { selectors: selectors.to_vec(), current: Vec::new() };
+        set.current = set.select(all);
+        Ok(set)
+    }
+
+    /// Addresses to listen on
+    pub fn addrs(&self) -> Vec<IpAddr> {
+        self.current.iter().map(Interface::ip).collect()
+    }
+
+    /// Re-read the interfaces, returning the addresses that appeared and the
+    /// ones that went away. A selected interface disappearing is not an error
+    /// at this point; it may come back.
+    pub fn refresh(&mut self) -> Result<(Vec<IpAddr>, Vec<IpAddr>)> {
+        let before = self.addrs();
+        let all = if_addrs::get_if_addrs().context("Failed to list network interfaces")?;
+        self.current = self.select(all);
+        let after = self.addrs();
+        let added = after.iter().filter(|ip| !before.contains(ip)).copied().collect();
+        let removed = before.into_iter().filter(|ip| !after.contains(ip)).collect();
+        Ok((added, removed))
+    }
+
+    /// Whether `ip` is on the subnet of one of the selected interfaces
+    pub fn reaches(&self, ip: IpAddr) -> bool {
+        self.current.iter().any(|interface| match (&interface.addr, ip) {
+            (IfAddr::V4(v4), IpAddr::V4(ip)) => {
+                let mask = u32::from(v4.netmask);
+                u32::from(v4.ip) & mask == u32::from(ip) & mask
+            }
+            (IfAddr::V6(v6), IpAddr::V6(ip)) => {
+                let mask = u128::from(v6.netmask);
+                u128::from(v6.ip) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        })
+    }
+
+    fn select(&self, all: Vec<Interface>) -> Vec<Interface> {
+        all.into_iter()
+            // Link-local IPv6 addresses can't be bound without a scope id
+            .filter(|interface| !(interface.ip().is_ipv6() && interface.is_link_local()))
+            .filter(|interface| self.selectors.iter().any(|selector| matches(interface, selector)))
+            .collect()
+    }
+}
+
+/// Whether `selector` names `interface` or one of its addresses
+pub fn matches(interface: &Interface, selector: &str) -> bool {
+    interface.name == selector || selector.parse::<IpAddr>().is_ok_and(|ip| ip == interface.ip())
+}
+
+/// IP address of a multiaddr, if it has one
+pub fn ip_of(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
+
+/// An IP range in CIDR notation, e.g. `192.168.1.0/24`, given with
+/// `--allow-subnet`. A bare address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Subnet {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// Whether `ip` lies within the range
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = address
+            .trim()
+            .parse()
+            .map_err(|_| format!("expected an address or CIDR range like 192.168.1.0/24, got {s:?}"))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|&prefix| prefix <= max)
+                .ok_or_else(|| format!("prefix length in {s:?} must be between 0 and {max}"))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl TryFrom<String> for Subnet {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
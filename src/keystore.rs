@@ -0,0 +1,232 @@
+use anyhow::{bail, Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, rand_core::RngCore};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use libp2p::identity::Keypair;
+use log::info;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+
+/// Fixed salt so a phrase derives the same key on every machine. Changing it
+/// changes every seed-derived PeerId.
+const SEED_SALT: &[u8] = b"libp2p-clipboard-sync/identity-seed/v1";
+/// Phrases shorter than this are rejected outright
+const MIN_SEED_LEN: usize = 12;
+/// Header of a passphrase-encrypted identity file, followed by the salt,
+/// nonce and ciphertext. Plaintext files are bare protobuf keypairs, which
+/// never start with these bytes.
+const ENCRYPTED_MAGIC: &[u8] = b"CSKEY\x01";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Load the keypair stored at `path`, generating and saving a new one if the
+/// file does not exist yet.
+///
+/// With a passphrase the file is stored encrypted, and an existing plaintext
+/// key is encrypted in place. An encrypted file without a passphrase prompts
+/// for one on a terminal and fails otherwise.
+pub fn load_or_generate(path: &Path, passphrase: Option<&str>) -> Result<Keypair> {
+    if passphrase == Some("") {
+        bail!("Identity passphrase must not be empty");
+    }
+
+    if path.exists() {
+        let bytes = fs::read(path)
+            .with_context(|| format!("Failed to read identity key {}", path.display()))?;
+        let keypair = decode(path, &bytes, passphrase)?;
+        if let Some(passphrase) = passphrase
+            && !bytes.starts_with(ENCRYPTED_MAGIC)
+        {
+            save(path, &keypair, Some(passphrase))?;
+            info!("Encrypted plaintext identity key {} with the passphrase", path.display());
+        }
+        return Ok(keypair);
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    save(path, &keypair, passphrase)?;
+    Ok(keypair)
+}
+
+/// Decode the contents of an identity file, decrypting them if they are
+/// encrypted. `path` names the file in errors and the passphrase prompt.
+pub fn decode(path: &Path, bytes: &[u8], passphrase: Option<&str>) -> Result<Keypair> {
+    let Some(encrypted) = bytes.strip_prefix(ENCRYPTED_MAGIC) else {
+        return Keypair::from_protobuf_encoding(bytes)
+            .with_context(|| format!("Identity key {} is corrupt", path.display()));
+    };
+
+    let prompted;
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None => {
+            prompted = prompt_passphrase(path)?;
+            &prompted
+        }
+    };
+    let bytes = unseal(encrypted, passphrase)
+        .with_context(|| format!("Cannot decrypt identity key {}", path.display()))?;
+    Keypair::from_protobuf_encoding(&bytes)
+        .with_context(|| format!("Identity key {} is corrupt", path.display()))
+}
+
+/// Write `keypair` to `path`, encrypted if a passphrase is given. Goes
+/// through a temporary file so an interrupted write can't destroy the key.
+fn save(path: &Path, keypair: &Keypair, passphrase: Option<&str>) -> Result<()> {
+    let mut bytes = keypair
+        .to_protobuf_encoding()
+        .context("Failed to encode identity key")?;
+    if let Some(passphrase) = passphrase {
+        bytes = encrypt(&bytes, passphrase)?;
+    }
+    let temp = path.with_extension("tmp");
+    write_private(&temp, &bytes)?;
+    fs::rename(&temp, path)
+        .with_context(|| format!("Failed to replace identity key {}", path.display()))
+}
+
+/// Ask for the passphrase of an encrypted identity file without echoing it
+fn prompt_passphrase(path: &Path) -> Result<String> {
+    if !std::io::stdin().is_terminal() {
+        bail!(
+            "Identity key {} is encrypted, pass --identity-passphrase or set CLIPBOARD_SYNC_IDENTITY_PASSPHRASE",
+            path.display()
+        );
+    }
+    rpassword::prompt_password(format!("Passphrase for {}: ", path.display()))
+        .context("Failed to read the identity passphrase")
+}
+
+/// Derive the file encryption key from a passphrase with Argon2id
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive key from passphrase: {:?}", e))?;
+    Ok(key)
+}
+
+/// Encrypt an identity key, returning the complete file contents
+fn encrypt(plaintext: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    Ok([ENCRYPTED_MAGIC, &seal(plaintext, passphrase)?].concat())
+}
+
+/// Encrypt with ChaCha20-Poly1305 under a fresh salt and nonce, returning the
+/// salt, nonce and ciphertext
+pub fn seal(plaintext: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?)
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt"))?;
+    Ok([&salt[..], &nonce, &ciphertext].concat())
+}
+
+/// Decrypt what [`seal`] returned
+pub fn unseal(data: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    if data.len() < SALT_LEN + NONCE_LEN {
+        bail!("Encrypted data is truncated");
+    }
+    let (salt, rest) = data.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    ChaCha20Poly1305::new(&derive_key(passphrase, salt)?)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        // The tag check can't tell a wrong passphrase from a damaged file
+        .map_err(|_| anyhow::anyhow!("Wrong passphrase, or the file is damaged"))
+}
+
+/// Derive an Ed25519 keypair deterministically from a passphrase, so the same
+/// phrase yields the same PeerId everywhere. Argon2id makes guessing phrases
+/// expensive, but anyone who knows the phrase can impersonate the node.
+pub fn keypair_from_seed(phrase: &str) -> Result<Keypair> {
+    if phrase.chars().count() < MIN_SEED_LEN {
+        bail!("Identity seed must be at least {MIN_SEED_LEN} characters");
+    }
+
+    let mut secret = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(phrase.as_bytes(), SEED_SALT, &mut secret)
+        .map_err(|e| anyhow::anyhow!("Failed to derive identity from seed: {:?}", e))?;
+    Keypair::ed25519_from_bytes(secret).context("Failed to build identity from seed")
+}
+
+/// Write a file readable only by the current user
+pub fn write_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        file.write_all(bytes)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    #[cfg(not(unix))]
+    fs::write(path, bytes)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_phrase_always_derives_the_same_peer_id() {
+        let peer_id = |phrase| keypair_from_seed(phrase).unwrap().public().to_peer_id();
+        assert_eq!(peer_id("correct horse battery staple"), peer_id("correct horse battery staple"));
+        assert_ne!(peer_id("correct horse battery staple"), peer_id("correct horse battery stapler"));
+    }
+
+    #[test]
+    fn short_phrases_are_rejected() {
+        assert!(keypair_from_seed("too short").is_err());
+    }
+
+    #[test]
+    fn an_encrypted_key_loads_only_with_its_passphrase() {
+        let dir = crate::testing::TempDir::new();
+        let path = dir.path().join("identity.key");
+        let created = load_or_generate(&path, Some("open sesame")).unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(ENCRYPTED_MAGIC));
+
+        let loaded = load_or_generate(&path, Some("open sesame")).unwrap();
+        assert_eq!(loaded.public().to_peer_id(), created.public().to_peer_id());
+
+        let error = load_or_generate(&path, Some("close sesame")).unwrap_err();
+        assert!(format!("{error:#}").contains("Wrong passphrase"), "{error:#}");
+    }
+
+    #[test]
+    fn a_plaintext_key_is_encrypted_in_place() {
+        let dir = crate::testing::TempDir::new();
+        let path = dir.path().join("identity.key");
+        let created = load_or_generate(&path, None).unwrap();
+        assert!(Keypair::from_protobuf_encoding(&fs::read(&path).unwrap()).is_ok());
+
+        let migrated = load_or_generate(&path, Some("open sesame")).unwrap();
+        assert_eq!(migrated.public().to_peer_id(), created.public().to_peer_id());
+        assert!(fs::read(&path).unwrap().starts_with(ENCRYPTED_MAGIC));
+        assert!(load_or_generate(&path, Some("open sesame")).is_ok());
+    }
+
+    #[test]
+    fn damaged_files_fail_cleanly() {
+        let dir = crate::testing::TempDir::new();
+        let path = dir.path().join("identity.key");
+        fs::write(&path, [ENCRYPTED_MAGIC, b"short"].concat()).unwrap();
+        assert!(load_or_generate(&path, Some("open sesame")).is_err());
+        fs::write(&path, b"not a key").unwrap();
+        assert!(load_or_generate(&path, None).is_err());
+        assert!(load_or_generate(&path, Some("")).is_err());
+    }
+}
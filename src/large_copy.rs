@@ -0,0 +1,81 @@
+use crate::pipeline::Outgoing;
+use log::info;
+
+/// Holds back local copies larger than `--confirm-large` until `/confirm`,
+/// so a giant paste doesn't go out to everyone by accident. Only the latest
+/// large copy is held: a newer one replaces it, and a newer copy going out
+/// drops it, since sending it afterwards would overwrite that copy everywhere.
+#[derive(Debug, Default)]
+pub struct Guard {
+    held: Option<Outgoing>,
+    /// Timestamp of the copy released by `/confirm`, let through once
+    released: Option<u64>,
+}
+
+impl Guard {
+    /// `outgoing` back if it may be published now, or `None` if it is held
+    /// for confirmation. `threshold` is in serialized bytes, 0 to hold nothing.
+    pub fn check(&mut self, outgoing: Outgoing, threshold: u64) -> Option<Outgoing> {
+        let timestamp = outgoing.content.timestamp;
+        let released = self.released.take_if(|released| *released == timestamp).is_some();
+        if released || threshold == 0 || outgoing.data.len() as u64 <= threshold {
+            if let Some(held) = self.held.take_if(|held| held.content.timestamp < timestamp) {
+                info!("Dropping the held {} since newer content was copied", held.content.preview());
+            }
+            return Some(outgoing);
+        }
+        if let Some(held) = self.held.replace(outgoing) {
+            info!("Dropping the held {} in favour of the newer copy", held.content.preview());
+        }
+        None
+    }
+
+    /// The held copy, to be published after all
+    pub fn confirm(&mut self) -> Option<Outgoing> {
+        let held = self.held.take()?;
+        self.released = Some(held.content.timestamp);
+        Some(held)
+    }
+
+    /// Preview and size of the held copy, if any
+    pub fn held(&self) -> Option<(String, usize)> {
+        self.held.as_ref().map(|held| (held.content.preview(), held.data.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::ClipboardContent;
+
+    fn copy(timestamp: u64, size: usize) -> Outgoing {
+        let content = ClipboardContent { timestamp, ..ClipboardContent::new_text("x".repeat(size)) };
+        Outgoing { content, data: vec![0; size], diffed: None, sha256: None, shrunk_from: None }
+    }
+
+    #[test]
+    fn a_large_copy_waits_for_confirm_and_then_goes_out_once() {
+        let mut guard = Guard::default();
+        assert!(guard.check(copy(1, 10), 100).is_some());
+        assert!(guard.check(copy(2, 1000), 100).is_none());
+        assert_eq!(guard.held().map(|(_, size)| size), Some(1000));
+
+        let confirmed = guard.confirm().unwrap();
+        assert!(guard.held().is_none());
+        assert!(guard.check(confirmed, 100).is_some(), "a confirmed copy isn't held again");
+        assert!(guard.confirm().is_none());
+        assert!(guard.check(copy(2, 1000), 0).is_some(), "nothing is held without a threshold");
+    }
+
+    #[test]
+    fn only_the_latest_large_copy_is_held() {
+        let mut guard = Guard::default();
+        assert!(guard.check(copy(1, 1000), 100).is_none());
+        assert!(guard.check(copy(2, 2000), 100).is_none());
+        assert_eq!(guard.held().map(|(_, size)| size), Some(2000));
+
+        // A newer small copy would be overwritten by sending the held one
+        assert!(guard.check(copy(3, 10), 100).is_some());
+        assert!(guard.held().is_none());
+    }
+}
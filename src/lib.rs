@@ -0,0 +1,4919 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::StreamExt;
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use tokio::{io, select, sync::{broadcast, mpsc, oneshot, watch}};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    error::Error, 
+    hash::{Hash, Hasher}, 
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use libp2p::{
+    gossipsub, identify, identity, 
+    mdns, ping, relay, request_response,
+    swarm::{behaviour::toggle::Toggle, dial_opts::DialOpts, ListenError, NetworkBehaviour, SwarmEvent}, 
+    tcp, yamux, 
+    core::{transport::{ListenerId, MemoryTransport}, upgrade, Transport as _}, multiaddr::{Multiaddr, Protocol}, 
+    PeerId, Swarm, SwarmBuilder
+};
+
+// Default ports
+const PORT_TCP: u16 = 0;  // 0 means OS will assign a random available port
+const CHAT_TOPIC: &str = "libp2p-chat";
+const CLIPBOARD_TOPIC: &str = "libp2p-clipboard";
+/// Images and large payloads go here so they don't hold up quick text copies
+const CLIPBOARD_BULK_TOPIC: &str = "libp2p-clipboard-bulk";
+/// Largest message on the fast path clipboard topic; anything bigger goes bulk
+const FAST_PATH_MAX_SIZE: usize = 1024 * 1024;
+/// Largest gossipsub message, and so the largest serialized clipboard content
+const MAX_TRANSMIT_SIZE: usize = 100 * 1024 * 1024;
+/// How often expired spilled payloads are evicted
+const CACHE_GC_INTERVAL: Duration = Duration::from_secs(30);
+/// How long the swarm keeps running on shutdown to deliver the offline notice
+const SHUTDOWN_FLUSH: Duration = Duration::from_millis(500);
+/// Estimated clock offsets beyond this many ms get a warning
+const CLOCK_SKEW_WARN_MS: u64 = 10_000;
+/// File in the spill directory holding `--stdin-clipboard` input too large
+/// for one message
+const STDIN_FILE: &str = "stdin.txt";
+
+/// Copied content waiting for a subscriber: the topic, the serialized content,
+/// its audit log item, and the hash and preview to confirm its delivery by
+type PendingClipboard = (gossipsub::IdentTopic, Vec<u8>, Option<audit::Item>, Option<(String, String)>);
+
+#[derive(NetworkBehaviour)]
+struct AppBehaviour {
+    // First, so a connection it denies is never handed to the others
+    gate: hardened::Gate,
+    identify: identify::Behaviour,
+    gossipsub: gossipsub::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    ping: ping::Behaviour,
+    direct: direct::Behaviour,
+    files: files::Behaviour,
+    relay_client: Toggle<relay::client::Behaviour>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "libp2p app", version = "1.0", author = "Eric Xu")]
+struct Args {
+    /// Address to listen on
+    #[clap(long, default_value = "0.0.0.0")]
+    listen_address: IpAddr,
+
+    /// Only listen on and accept mDNS discoveries through this network interface,
+    /// given by name or address. Repeatable; addresses are followed as they change
+    #[clap(long, value_name = "NAME|IP", conflicts_with = "listen_address")]
+    interface: Vec<String>,
+
+    /// Only accept peers discovered over mDNS at an address in this range,
+    /// e.g. 192.168.1.0/24. Repeatable
+    #[clap(long, value_name = "CIDR")]
+    allow_subnet: Vec<interfaces::Subnet>,
+
+    /// Also find group members through signed UDP broadcasts, for networks
+    /// that block mDNS but not broadcast
+    #[clap(long)]
+    beacon: bool,
+
+    /// UDP port beacons are sent to and received on
+    #[clap(long, value_name = "PORT", default_value_t = beacon::DEFAULT_PORT)]
+    beacon_port: u16,
+
+    /// Seconds between beacons
+    #[clap(long, value_name = "SECS", default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    beacon_interval: u64,
+
+    /// TCP port to listen on (0 lets the OS pick one)
+    #[clap(long, default_value_t = PORT_TCP)]
+    port: u16,
+
+    /// Listen on an OS-assigned port instead of exiting if --port is already in use
+    #[clap(long)]
+    port_fallback: bool,
+
+    /// Nodes to connect to on startup
+    #[clap(long)]
+    connect: Option<Vec<Multiaddr>>,
+
+    /// Reserve a slot on this `relay-server`, given with its /p2p/ id, so
+    /// peers that can't reach this node directly can through the relay.
+    /// Repeatable
+    #[clap(long, value_name = "MULTIADDR")]
+    relay: Vec<Multiaddr>,
+
+    /// Start in hardened mode, for untrusted networks: no mDNS or beacons,
+    /// inbound connections only from known group members, local metrics only
+    /// and tighter size and age limits. Switched at runtime with /hardened
+    #[clap(long)]
+    hardened: bool,
+    
+    /// Enable clipboard sync
+    #[clap(long)]
+    clipboard: bool,
+
+    /// Only publish to gossipsub mesh peers instead of flooding all subscribed peers
+    #[clap(long)]
+    no_flood_publish: bool,
+
+    /// Send copied content to at most this many peers, those with the lowest
+    /// ping round-trip time, directly instead of over the topic. 0 means no limit
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    max_peers_for_clipboard: usize,
+
+    /// Scale factor applied to received images (e.g. 2.0 for high-DPI displays)
+    #[clap(long, default_value_t = 1.0, value_parser = parse_image_scale)]
+    image_scale: f32,
+
+    /// Send images that mostly match the previous one as a compressed diff,
+    /// when every subscribed peer can rebuild it
+    #[clap(long)]
+    image_diffs: bool,
+
+    /// Content types to apply from peers, advertised so they know what we
+    /// ignore. Anything else is dropped on arrival and not offered to us directly
+    #[clap(long, value_name = "TYPES", value_delimiter = ',', default_values = ["text", "image", "files"])]
+    accept_formats: Vec<clipboard::ContentType>,
+
+    /// Join topics as a receive-only subscriber that stays out of the gossipsub
+    /// mesh and does not forward messages for other peers
+    #[clap(long)]
+    readonly_topics: bool,
+
+    /// Pause clipboard sync while the screen is being shared or recorded, and
+    /// resume when that ends
+    #[clap(long)]
+    pause_on_screenshare: bool,
+
+    /// React to the machine moving to another network (Wi-Fi, cable, VPN) by
+    /// rediscovering and redialing peers right away
+    #[clap(long)]
+    watch_network: bool,
+
+    /// Show a system tray icon with status and quick actions
+    #[cfg(feature = "tray")]
+    #[clap(long)]
+    tray: bool,
+
+    /// Name advertised to peers and shown by them next to our PeerId in logs
+    /// and peer lists. Defaults to the hostname; pass "" to advertise none
+    #[clap(long)]
+    device_name: Option<String>,
+
+    /// Read text piped into stdin and publish it once as clipboard content as
+    /// soon as a peer subscribes, or offer it as a file when it is too large
+    /// for one message. The console is not read in this mode.
+    #[clap(long, requires = "clipboard", conflicts_with = "observer")]
+    stdin_clipboard: bool,
+
+    /// Run without a console: stdin is not read and the dashboard is off.
+    /// Added by `service install`
+    #[clap(long)]
+    daemon: bool,
+
+    /// Carry one peer connection over stdin/stdout, e.g. through an `ssh`
+    /// channel. One end of the tunnel dials, the other listens. The console
+    /// is not read in this mode and the node exits when the tunnel closes.
+    #[clap(long, value_name = "ROLE", conflicts_with = "stdin_clipboard")]
+    stdio_transport: Option<stdio::Role>,
+
+    /// Show a full-screen terminal dashboard instead of the plain console
+    #[cfg(feature = "tui")]
+    #[clap(long)]
+    tui: bool,
+
+    /// Recognize text in received images with `tesseract`, keeping it
+    /// alongside the image
+    #[cfg(feature = "ocr")]
+    #[clap(long)]
+    ocr_incoming_images: bool,
+
+    /// Decode QR codes in received images with `zbarimg`, keeping their
+    /// payload alongside the image
+    #[cfg(feature = "qr")]
+    #[clap(long)]
+    qr_decode: bool,
+
+    /// Serve Prometheus metrics on this address (e.g. 127.0.0.1:9090)
+    #[clap(long)]
+    metrics_address: Option<SocketAddr>,
+
+    /// Warn when a received item's sync latency exceeds this many milliseconds
+    #[clap(long, default_value_t = 2000)]
+    latency_warn_ms: u64,
+
+    /// Don't send delivery receipts for chat messages or ask peers for them,
+    /// and don't confirm received clipboard content
+    #[clap(long)]
+    no_receipts: bool,
+
+    /// Reject console lines longer than this many bytes before reading them
+    /// whole, so an accidental paste of a huge blob isn't sent as chat
+    #[clap(long, value_name = "BYTES", default_value_t = 64 * 1024)]
+    max_chat_bytes: usize,
+
+    /// Ask peers to confirm copied text and images once they are on their
+    /// clipboard, and log which peers did and which didn't
+    #[clap(long)]
+    confirm_delivery: bool,
+
+    /// Warn when received content replaces something copied locally within this many ms of it (0 disables)
+    #[clap(long, default_value_t = 2000)]
+    conflict_window_ms: u64,
+
+    /// Stop sending images once this many payload bytes were sent and received
+    /// within the last hour, for metered links. Text still goes out (0 for no cap)
+    #[clap(long, default_value_t = 0)]
+    bandwidth_cap: u64,
+
+    /// Warn about hot path operations (serializing, publishing, clipboard
+    /// access) taking longer than this many ms (0 disables)
+    #[clap(long, default_value_t = 100)]
+    slow_op_ms: u64,
+
+    /// Check every this many seconds that we are still in the gossipsub mesh of
+    /// our topics, re-subscribing if not (0 disables)
+    #[clap(long, default_value_t = 60)]
+    subscription_check_secs: u64,
+
+    /// Download files peers copy into this directory. Without it, file offers
+    /// are only logged
+    #[clap(long, value_name = "PATH")]
+    download_dir: Option<PathBuf>,
+
+    /// Put downloaded files on the clipboard once all files of an offer arrived
+    #[clap(long, requires = "download_dir")]
+    files_to_clipboard: bool,
+
+    /// Also save every received image as a PNG file in this directory, named
+    /// after the time it was copied and the peer that copied it
+    #[clap(long, value_name = "PATH")]
+    image_export_dir: Option<PathBuf>,
+
+    /// Only save received images to --image-export-dir, leaving the clipboard alone
+    #[clap(long, requires = "image_export_dir")]
+    image_export_only: bool,
+
+    /// Queue received clipboard content for manual /accept instead of applying it immediately
+    #[clap(long)]
+    queue_incoming: bool,
+
+    /// Content types applied right away even with --queue-incoming, e.g.
+    /// "text" to queue only images and files
+    #[clap(long, value_name = "TYPES", value_delimiter = ',')]
+    auto_accept_types: Vec<clipboard::ContentType>,
+
+    /// Don't publish what is already on the clipboard at startup, only later changes
+    #[clap(long)]
+    ignore_initial_clipboard: bool,
+
+    /// Seconds within which text identical to what was last sent or received
+    /// counts as an echo. Copying it again after that resends it
+    #[clap(long, value_name = "SECS", default_value_t = 2)]
+    resend_window: u64,
+
+    /// Milliseconds between clipboard reads right after a change
+    #[clap(long, value_name = "MS", default_value_t = 250, value_parser = clap::value_parser!(u64).range(1..))]
+    poll_min_ms: u64,
+
+    /// Milliseconds between clipboard reads the monitor backs off to while the
+    /// clipboard stays the same. Set it to --poll-min-ms to poll at a fixed rate
+    #[clap(long, value_name = "MS", default_value_t = 2000)]
+    poll_max_ms: u64,
+
+    /// Factor the interval between clipboard reads grows by with every read
+    /// that finds nothing new
+    #[clap(long, value_name = "FACTOR", default_value_t = 1.5, value_parser = parse_poll_backoff)]
+    poll_backoff: f32,
+
+    /// Log clipboard text in full. By default log lines only show a short
+    /// preview, so copied secrets don't end up in log files
+    #[clap(long)]
+    log_content: bool,
+
+    /// Label copied content with the title of the window it was copied from,
+    /// shown by receivers. Needs xdotool on Linux X11
+    #[clap(long)]
+    source_label: bool,
+
+    /// Line breaks in received text: none leaves them, space joins the lines
+    /// with spaces and strip removes them, for pasting into single-line fields
+    #[clap(long, value_enum, value_name = "MODE", default_value_t = clipboard::NewlineMode::None)]
+    replace_newlines: clipboard::NewlineMode,
+
+    /// Don't share connected group members with peers or dial members they share
+    #[clap(long)]
+    no_peer_exchange: bool,
+
+    /// Remember group members and their addresses in this file and redial them at
+    /// startup. Defaults to peers.json in the profile directory
+    #[clap(long, value_name = "PATH")]
+    peers_file: Option<PathBuf>,
+
+    /// Forget address book entries not seen for this many days
+    #[clap(long, default_value_t = 30)]
+    address_book_max_age: u64,
+
+    /// Append a line per synced clipboard item (direction, peers, type, size,
+    /// hash) to this file, chained under --audit-key so edits are detectable
+    #[clap(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// On exit, also write the session summary (peers, items, failures,
+    /// latency) to this file as JSON
+    #[clap(long, value_name = "PATH")]
+    session_report: Option<PathBuf>,
+
+    /// Record a trace of what the node sees and decides (items by hash and
+    /// size, never their content) to this file, for `replay`
+    #[clap(long, value_name = "PATH")]
+    record: Option<PathBuf>,
+
+    /// Reject received images larger than this many bytes, penalizing the
+    /// peer that sent them (0 for no limit)
+    #[clap(long, value_name = "BYTES", default_value_t = 0)]
+    max_image_bytes: usize,
+
+    /// Reject received text containing this string, ignoring case. Can be
+    /// given more than once
+    #[clap(long, value_name = "TEXT")]
+    reject_text_containing: Vec<String>,
+
+    /// Hold local copies that serialize to more than this many bytes until
+    /// `/confirm` (0 sends everything right away)
+    #[clap(long, value_name = "BYTES", default_value_t = 0)]
+    confirm_large: u64,
+
+    /// Fail to publish local copies too large for the mesh or for peers'
+    /// --max-image-bytes, instead of shrinking images, sending directly to
+    /// the peers that take them or telling peers they were too large
+    #[clap(long)]
+    strict_size: bool,
+
+    /// Include the text itself in audit log entries, not just its hash
+    #[clap(long, requires = "audit_log")]
+    audit_include_text: bool,
+
+    /// Offer deflate compression of whole connections; peers without it fall back to uncompressed
+    #[clap(long)]
+    transport_compression: bool,
+
+    /// Connection security: noise, tls, or both (negotiates TLS, falls back to Noise)
+    #[clap(long, value_enum, default_value_t = security::Security::Noise)]
+    security: security::Security,
+
+    /// Received payloads larger than this many bytes are kept on disk until applied (0 disables)
+    #[clap(long, default_value_t = 16 * 1024 * 1024)]
+    spill_threshold: usize,
+
+    /// Total bytes of spilled payloads kept on disk before the oldest are evicted (0 for no limit)
+    #[clap(long, default_value_t = 1024 * 1024 * 1024)]
+    cache_max_bytes: u64,
+
+    /// Evict spilled payloads after this many seconds (0 for no limit)
+    #[clap(long, default_value_t = 3600)]
+    cache_max_age: u64,
+
+    /// Offer our latest clipboard content to newly subscribed peers if it is at most this many seconds old (0 disables)
+    #[clap(long, default_value_t = 300)]
+    retained_max_age: u64,
+
+    /// Let only one node offer its latest clipboard content to a newly
+    /// subscribed peer: the one that copied it, or if that one isn't
+    /// subscribed, the holder of the content with the lowest PeerId
+    #[clap(long)]
+    elect_retained_offer: bool,
+
+    /// Peer whose clipboard always wins: content from it is applied even if
+    /// older than ours, and content from other peers is ignored when copied
+    /// within --conflict-window-ms of the primary's latest
+    #[clap(long, value_name = "PEER_ID", conflicts_with = "i_am_primary")]
+    primary_peer: Option<PeerId>,
+
+    /// This node's clipboard is the source of truth: publish it, but never
+    /// apply content received from peers
+    #[clap(long)]
+    i_am_primary: bool,
+
+    /// Sync group to join: nodes only sync with others in the same room.
+    /// Repeat to join several rooms, which never exchange content. A `:send`
+    /// or `:receive` suffix limits which way content flows with a room
+    #[clap(long, value_name = "NAME[:DIRECTION]", default_value = bridge::DEFAULT_ROOM, conflicts_with = "bridge")]
+    room: Vec<bridge::Membership>,
+
+    /// Join two rooms and forward clipboard content between them, for groups
+    /// that can't reach each other directly. Local copies go to both rooms
+    #[clap(long, value_name = "ROOM_A=ROOM_B")]
+    bridge: Option<bridge::Bridge>,
+
+    /// Record clipboard activity from peers without ever publishing: the
+    /// clipboard is not monitored or written and chat input is rejected
+    #[clap(long)]
+    observer: bool,
+
+    /// Check the environment (clipboard, network, discovery) and exit
+    #[clap(long)]
+    doctor: bool,
+
+    /// Copy text and an image between two nodes started in this process,
+    /// in memory, report whether each arrived intact and exit
+    #[clap(long, conflicts_with_all = ["doctor", "stdio_transport"])]
+    self_test: bool,
+
+    /// Profile namespacing the identity key and settings under ~/.config/clipboard-sync/<name>/
+    #[clap(long)]
+    profile: Option<String>,
+
+    /// TOML file with settings named like these flags; flags given here take precedence.
+    /// Defaults to config.toml in the profile directory. Reloaded on SIGHUP or /reload
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Derive a stable identity from this secret passphrase instead of a key file
+    #[clap(long, value_name = "PHRASE")]
+    identity_seed: Option<String>,
+
+    /// Store the profile's identity key encrypted with this passphrase, encrypting an
+    /// existing plaintext key. Prompted for when the key is encrypted and this is unset
+    #[clap(long, value_name = "PHRASE", env = "CLIPBOARD_SYNC_IDENTITY_PASSPHRASE", hide_env_values = true, conflicts_with = "identity_seed")]
+    identity_passphrase: Option<String>,
+
+    /// Encrypt clipboard payloads with a key derived from this passphrase, leaving the
+    /// content type, timestamp and dimensions readable. Peers without it still forward
+    /// and count the content but never apply it
+    #[clap(long, value_name = "PHRASE", env = "CLIPBOARD_SYNC_FIELD_ENCRYPTION", hide_env_values = true)]
+    field_encryption: Option<String>,
+
+    /// Key the chain of the --audit-log is computed and checked with. Keep it
+    /// away from the log, so whoever can edit the log can't redo the chain
+    #[clap(long, value_name = "SECRET", env = "CLIPBOARD_SYNC_AUDIT_KEY", hide_env_values = true)]
+    audit_key: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Set up the --profile: name this device, pick a group, create the
+    /// identity key and write the config file. Asks on a terminal
+    Init(wizard::InitArgs),
+    /// Manage configuration profiles
+    Profile {
+        #[clap(subcommand)]
+        action: ProfileAction,
+    },
+    /// Relay connections for clipboard-sync nodes that cannot reach each
+    /// other directly. Uses the global listen, identity and metrics flags.
+    RelayServer(relay_server::RelayArgs),
+    /// Start the node at login with the flags given before `service`: a
+    /// systemd user unit, a LaunchAgent or a logon scheduled task
+    Service {
+        #[clap(subcommand)]
+        action: service::ServiceAction,
+    },
+    /// Package the --profile's identity, config file and address book into one
+    /// passphrase-encrypted file, to move the node to another machine
+    Export(bundle::ExportArgs),
+    /// Restore a bundle written by `export` into the --profile
+    Import(bundle::ImportArgs),
+    /// Check that an audit log written with --audit-log is intact, under the
+    /// same --audit-key
+    VerifyAudit {
+        path: PathBuf,
+    },
+    /// Go through a trace written with --record, checking that this build
+    /// makes the same ordering, change detection and echo decisions on the
+    /// content it records
+    Replay {
+        path: PathBuf,
+    },
+    /// Manage secrets kept in the OS keyring
+    Secret {
+        #[clap(subcommand)]
+        action: SecretAction,
+    },
+    /// Print the latest clipboard text known to the group, asking peers if
+    /// the node running with the same --profile has none
+    Get {
+        /// Have the node put the content on the clipboard instead of printing it
+        #[clap(long)]
+        apply: bool,
+        /// Seconds to wait for peers to answer
+        #[clap(long, default_value_t = 5)]
+        timeout: u64,
+    },
+    /// Show or change the log filter of the node running with the same
+    /// --profile, without restarting it
+    Log {
+        /// A filter written like RUST_LOG, e.g. `debug` or
+        /// `libp2p_clipboard_sync=trace`, or `status` or `reset`
+        filter: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ProfileAction {
+    /// List existing profiles
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum SecretAction {
+    /// Store a secret, read from the terminal or stdin, for settings to
+    /// refer to as keyring:<name> instead of holding it in plain text
+    Set {
+        name: String,
+    },
+}
+
+fn parse_poll_backoff(value: &str) -> Result<f32, String> {
+    let backoff: f32 = value.parse().map_err(|e| format!("invalid factor: {e}"))?;
+    if backoff.is_finite() && backoff >= 1.0 {
+        Ok(backoff)
+    } else {
+        Err("backoff factor must be at least 1".to_string())
+    }
+}
+
+fn parse_image_scale(value: &str) -> Result<f32, String> {
+    let scale: f32 = value.parse().map_err(|e| format!("invalid scale factor: {e}"))?;
+    if scale.is_finite() && scale > 0.0 && scale <= imaging::MAX_SCALE {
+        Ok(scale)
+    } else {
+        Err(format!("scale factor must be a positive number of at most {}", imaging::MAX_SCALE))
+    }
+}
+
+mod address_book;
+mod audit;
+mod backpressure;
+mod bandwidth;
+mod beacon;
+mod bridge;
+mod bundle;
+mod capabilities;
+mod chat;
+mod clipboard;
+mod compression;
+mod config;
+mod control_socket;
+mod convert;
+mod crypto;
+mod conflict;
+mod console;
+pub mod control;
+mod delivery;
+mod direct;
+mod doctor;
+mod downgrade;
+mod files;
+mod focus;
+mod hardened;
+mod image_diff;
+pub mod in_process;
+mod image_export;
+mod imaging;
+mod interfaces;
+mod large_copy;
+mod keystore;
+mod logging;
+mod metrics;
+mod network_watch;
+mod peer_backoff;
+mod peer_exchange;
+mod policy;
+mod poll;
+mod pipeline;
+mod profile;
+mod progress;
+mod queue;
+mod relay_server;
+mod report;
+mod screenshare;
+mod self_test;
+mod service;
+#[cfg(all(test, feature = "soak"))]
+mod soak;
+mod secrets;
+mod security;
+mod spill;
+mod stats;
+mod stdio;
+mod system_clipboard;
+mod subscriptions;
+#[cfg(test)]
+mod testing;
+mod timing;
+mod trace;
+#[cfg(all(feature = "tray", target_os = "linux"))]
+mod tray;
+#[cfg(feature = "tui")]
+mod tui;
+mod wizard;
+
+/// Run the command line app, as the binary does
+pub async fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = config::parse_args()?;
+
+    // A new profile started by hand is set up before it first joins a group
+    if wizard::first_run(&args) {
+        let profile = profile::Profile::open(args.profile.as_deref().unwrap_or_default())?;
+        wizard::run(&profile, &wizard::InitArgs::default(), args.identity_passphrase.as_deref())?;
+        args = config::parse_args()?;
+    }
+
+    // Initialize logger. The dashboard takes over the terminal, so log output
+    // goes to its activity pane instead. `logging` filters by RUST_LOG, so
+    // that `/log` can change it.
+    let mut logger = env_logger::Builder::from_env(env_logger::Env::new().write_style("RUST_LOG_STYLE"));
+    #[cfg(feature = "tui")]
+    let tui = if args.tui && !args.doctor && !args.self_test && !args.stdin_clipboard && !args.daemon && args.stdio_transport.is_none() && args.command.is_none() {
+        match tui::init() {
+            Ok((tui, log_writer)) => {
+                logger.target(env_logger::Target::Pipe(Box::new(log_writer)));
+                Ok(Some(tui))
+            }
+            Err(e) => Err(e),
+        }
+    } else {
+        Ok(None)
+    };
+    // On a terminal the console redraws transfer progress in place, and log
+    // lines clear it first
+    #[cfg(feature = "tui")]
+    let plain = !matches!(tui, Ok(Some(_)));
+    #[cfg(not(feature = "tui"))]
+    let plain = true;
+    let live_progress = plain && std::io::IsTerminal::is_terminal(&std::io::stderr());
+    if live_progress {
+        // Colors as if writing to stderr directly, unless RUST_LOG_STYLE says otherwise
+        if std::env::var_os("RUST_LOG_STYLE").is_none() {
+            logger.write_style(env_logger::WriteStyle::Always);
+        }
+        logger.target(env_logger::Target::Pipe(Box::new(console::LogWriter)));
+    }
+    logging::init(logger).expect("logger already set");
+    #[cfg(feature = "tui")]
+    let tui = tui.unwrap_or_else(|e| {
+        warn!("Dashboard unavailable, using plain mode: {e}");
+        None
+    });
+
+    if let Some(Command::Profile { action: ProfileAction::List }) = args.command {
+        for name in profile::Profile::list()? {
+            println!("{name}");
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Secret { action: SecretAction::Set { ref name } }) = args.command {
+        return Ok(secrets::set(&secrets::OsKeyring, name)?);
+    }
+
+    if let Some(Command::Service { ref action }) = args.command {
+        return Ok(service::run(action, args.profile.as_deref())?);
+    }
+
+    if let Some(Command::Get { apply, timeout }) = args.command {
+        return Ok(control_socket::get(args.profile.as_deref(), apply, Duration::from_secs(timeout)).await?);
+    }
+
+    if let Some(Command::Log { ref filter }) = args.command {
+        return Ok(control_socket::log(args.profile.as_deref(), control::LogFilter::from_argument(filter.as_deref())).await?);
+    }
+
+    if let Some(Command::Init(ref init_args)) = args.command {
+        let Some(ref name) = args.profile else {
+            return Err(anyhow::anyhow!("init needs the --profile to set up").into());
+        };
+        return Ok(wizard::run(&profile::Profile::open(name)?, init_args, args.identity_passphrase.as_deref())?);
+    }
+
+    if let Some(Command::Export(ref export_args)) = args.command {
+        let Some(ref name) = args.profile else {
+            return Err(anyhow::anyhow!("export needs the --profile to package").into());
+        };
+        return Ok(bundle::export(&profile::Profile::open(name)?, export_args, args.identity_passphrase.as_deref())?);
+    }
+
+    if let Some(Command::Import(ref import_args)) = args.command {
+        let Some(ref name) = args.profile else {
+            return Err(anyhow::anyhow!("import needs the --profile to restore into").into());
+        };
+        return Ok(bundle::import(&profile::Profile::open(name)?, import_args, args.identity_passphrase.as_deref())?);
+    }
+
+    if let Some(Command::VerifyAudit { ref path }) = args.command {
+        let Some(ref key) = args.audit_key else {
+            return Err("verify-audit needs the --audit-key the log was written with".into());
+        };
+        let entries = audit::verify(path, key)?;
+        println!("{}: {entries} entries, chain intact", path.display());
+        return Ok(());
+    }
+
+    if let Some(Command::Replay { ref path }) = args.command {
+        let diverged = trace::replay(path).await?;
+        if diverged > 0 {
+            return Err(format!("{diverged} decisions differ from the trace").into());
+        }
+        return Ok(());
+    }
+
+    if args.doctor {
+        let healthy = doctor::run(&args).await;
+        std::process::exit(if healthy { 0 } else { 1 });
+    }
+
+    if args.self_test {
+        let passed = self_test::run().await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    let profile = args.profile.as_deref().map(profile::Profile::open).transpose()?;
+
+    // Piped input is read up front so bad input fails before joining the group
+    let stdin_content = if args.stdin_clipboard {
+        Some(read_stdin_clipboard(profile.as_ref().map(|profile| profile.spill_dir()).as_deref())?)
+    } else {
+        None
+    };
+
+    // A seed phrase wins over the profile's stored key; without either the
+    // PeerId is random
+    let local_key = if let Some(ref phrase) = args.identity_seed {
+        warn!("Deriving identity from a seed phrase: anyone who knows it can impersonate this node, keep it secret and high-entropy");
+        keystore::keypair_from_seed(phrase)?
+    } else if let Some(ref profile) = profile {
+        info!("Using profile '{}' at {}", profile.name(), profile.dir().display());
+        keystore::load_or_generate(&profile.identity_path(), args.identity_passphrase.as_deref())?
+    } else {
+        if args.identity_passphrase.is_some() {
+            warn!("Ignoring the identity passphrase: without --profile there is no key file to encrypt");
+        }
+        identity::Keypair::generate_ed25519()
+    };
+
+    if let Some(Command::RelayServer(ref relay_args)) = args.command {
+        return Ok(relay_server::run(local_key, &args, relay_args).await?);
+    }
+
+    let (events, _) = broadcast::channel(control::EVENT_CAPACITY);
+    let env = NodeEnv {
+        key: local_key,
+        profile,
+        clipboard: Box::new(system_clipboard::connect),
+        in_memory: false,
+        stdin_content,
+        front_end: Some(FrontEnd {
+            live_progress,
+            #[cfg(feature = "tui")]
+            tui,
+        }),
+        commands: mpsc::unbounded_channel(),
+        events,
+    };
+    Ok(run_node(args, env).await?)
+}
+
+/// What a node needs besides its flags. `main` hands it the system clipboard
+/// and the terminal; nodes started in this process, like the self-test's,
+/// an in-memory clipboard and transport and no front end.
+struct NodeEnv {
+    key: identity::Keypair,
+    profile: Option<profile::Profile>,
+    clipboard: system_clipboard::Connector,
+    /// Connect over the in-memory transport only, listening on
+    /// `/memory/<--port>`, and leave mDNS off
+    in_memory: bool,
+    /// Text read for `--stdin-clipboard`, published once a peer subscribes
+    stdin_content: Option<clipboard::ClipboardContent>,
+    /// The console, dashboard, control socket, signal handlers and session
+    /// report. Without it the node is driven through `commands` alone.
+    front_end: Option<FrontEnd>,
+    /// Commands from every front end, handled by the event loop in order
+    commands: (mpsc::UnboundedSender<control::NodeCommand>, mpsc::UnboundedReceiver<control::NodeCommand>),
+    /// Live activity, see [`control::NodeEvent`]
+    events: broadcast::Sender<control::NodeEvent>,
+}
+
+/// The terminal side of a node run from the command line
+struct FrontEnd {
+    /// Redraw transfer progress in place on the console
+    live_progress: bool,
+    #[cfg(feature = "tui")]
+    tui: Option<tui::Tui>,
+}
+
+/// Run the node until `/quit`, Ctrl+C or a closed stdio tunnel
+async fn run_node(mut args: Args, env: NodeEnv) -> Result<()> {
+    let NodeEnv { key: local_key, profile, clipboard: clipboard_backend, in_memory, stdin_content, front_end, commands, events } = env;
+    let (command_tx, mut command_rx) = commands;
+    let event_tx = events;
+    let has_front_end = front_end.is_some();
+    #[cfg(feature = "tui")]
+    let (live_progress, tui) = match front_end {
+        Some(FrontEnd { live_progress, tui }) => (live_progress, tui),
+        None => (false, None),
+    };
+    #[cfg(not(feature = "tui"))]
+    let live_progress = front_end.as_ref().is_some_and(|front_end| front_end.live_progress);
+    #[cfg(feature = "tui")]
+    let tui_active = tui.is_some();
+    #[cfg(not(feature = "tui"))]
+    let tui_active = false;
+
+    // Large payloads spill into the profile directory, where leftovers from a
+    // crashed run can be found again, or a per-process temp directory
+    let mut payload_cache = match profile {
+        Some(ref profile) => clipboard::PayloadCache::open(profile.spill_dir(), false, cache_limits(&args)),
+        None => clipboard::PayloadCache::open(
+            std::env::temp_dir().join(format!("clipboard-sync-spill-{}-{}", std::process::id(), PeerId::from(local_key.public()))),
+            true,
+            cache_limits(&args),
+        ),
+    };
+    let mut cache_gc_timer = tokio::time::interval(CACHE_GC_INTERVAL);
+
+    // Remembered group members, persisted with the profile
+    let mut address_book = address_book::AddressBook::load(
+        args.peers_file.clone().or_else(|| profile.as_ref().map(|profile| profile.address_book_path())),
+        args.address_book_max_age,
+    )?;
+    let mut audit_log = match (args.audit_log.as_deref(), args.audit_key.as_deref()) {
+        (Some(path), Some(key)) => Some(audit::AuditLog::open(path, key, args.audit_include_text)?),
+        _ => None,
+    };
+    // Created before anything that can fail so every exit gets a session report
+    let stats: stats::SharedStats = Arc::new(Mutex::new(stats::Stats::default()));
+    let reporter = has_front_end.then(|| report::Reporter::install(stats.clone(), args.session_report.clone()));
+
+    let local_peer_id = PeerId::from(local_key.public());
+    info!("Local peer id: {:?}", local_peer_id);
+
+    // Create the swarm
+    let beacon_key = args.beacon.then(|| local_key.clone());
+    let mut swarm = if in_memory { create_memory_swarm(local_key, &args)? } else { create_swarm(local_key, &args)? };
+    // In-memory nodes never run mDNS, not even once hardened mode is left
+    let mdns_wanted = !in_memory;
+    swarm.behaviour_mut().gate.update(&args, &address_book);
+    let capabilities = local_capabilities(&args);
+
+    // Create a Gossipsub topic and subscribe to it
+    let chat_topic = gossipsub::IdentTopic::new(CHAT_TOPIC);
+    swarm.behaviour_mut().gossipsub.subscribe(&chat_topic)
+        .map_err(|e| anyhow::anyhow!("Failed to subscribe to chat topic: {:?}", e))?;
+    
+    // Subscribe to the room's clipboard topics if enabled: a fast path for
+    // text and a bulk topic for images and large payloads. A bridge is in
+    // both of its rooms, and a node may be a member of several.
+    let rooms = joined_rooms(&args);
+    bridge::check_rooms(&rooms).map_err(|e| anyhow::anyhow!("Refusing to join rooms that would exchange content: {e}"))?;
+    let clipboard_topic = if args.clipboard || args.observer {
+        for room in &rooms {
+            for topic in [&room.clipboard, &room.bulk] {
+                swarm.behaviour_mut().gossipsub.subscribe(topic)
+                    .map_err(|e| anyhow::anyhow!("Failed to subscribe to clipboard topic: {:?}", e))?;
+            }
+        }
+        if args.observer {
+            info!("Observer mode: recording clipboard activity, never publishing");
+        } else {
+            info!("Clipboard sync enabled");
+        }
+        if let Some(ref bridge) = args.bridge {
+            info!("Bridging clipboard content between rooms '{}' and '{}'", bridge.a, bridge.b);
+        } else {
+            let plain_default = |room: &&bridge::Room| room.name == bridge::DEFAULT_ROOM && room.direction == bridge::Direction::Both;
+            for room in rooms.iter().filter(|room| !plain_default(room)) {
+                match room.direction {
+                    bridge::Direction::Both => info!("Syncing in room '{}'", room.name),
+                    bridge::Direction::Send => info!("Syncing in room '{}', send only", room.name),
+                    bridge::Direction::Receive => info!("Syncing in room '{}', receive only", room.name),
+                }
+            }
+        }
+        if !args.observer && !rooms.iter().any(|room| room.direction.sends()) {
+            warn!("Every room is receive only: local copies are never sent");
+        }
+        Some(rooms[0].clipboard.clone())
+    } else {
+        if args.bridge.is_some() {
+            return Err(anyhow::anyhow!("--bridge needs --clipboard"));
+        }
+        None
+    };
+    // Only a bridge forwards between its rooms. Content never crosses between
+    // rooms a node is merely a member of.
+    let bridge_rooms: Option<[bridge::Room; 2]> = args.bridge.as_ref().and_then(|_| rooms.clone().try_into().ok());
+
+    // Periodically make sure we are still in the mesh of every topic we joined
+    let mut expected_topics = vec![chat_topic.clone()];
+    if args.clipboard || args.observer {
+        expected_topics.extend(rooms.iter().flat_map(|room| [room.clipboard.clone(), room.bulk.clone()]));
+    }
+    let mut subscription_check = subscriptions::SubscriptionCheck::new(expected_topics, args.readonly_topics);
+    let mut subscription_timer = subscription_interval(&args);
+    // A suspend silently breaks connections and meshes, so check right after waking
+    let mut sleep_detector = subscriptions::SleepDetector::new();
+    let mut sleep_timer = tokio::time::interval(subscriptions::SLEEP_CHECK_INTERVAL);
+    // Peers we forward everything to, which gossipsub keeps out of the mesh
+    let mut explicit_peers: HashSet<PeerId> = HashSet::new();
+
+    // With --interface, listen on the interfaces' own addresses and follow them
+    // as they change
+    let mut interfaces = if args.interface.is_empty() || in_memory {
+        None
+    } else {
+        Some(interfaces::InterfaceSet::new(&args.interface)?)
+    };
+    let mut interface_listeners: HashMap<IpAddr, ListenerId> = HashMap::new();
+    let mut memory_listener = None;
+    match interfaces {
+        Some(ref interfaces) => {
+            for address in interfaces.addrs() {
+                interface_listeners.insert(address, listen_on(&mut swarm, &args, address)?);
+            }
+        }
+        None if in_memory => {
+            memory_listener = Some(swarm.listen_on(Multiaddr::empty().with(Protocol::Memory(args.port.into())))?);
+        }
+        None => listen(&mut swarm, &args)?,
+    }
+    let mut interface_timer = tokio::time::interval(interfaces::POLL_INTERVAL);
+
+    // The peer on the other end of a stdio tunnel
+    match args.stdio_transport {
+        Some(stdio::Role::Dial) => {
+            info!("Connecting to the peer over stdio...");
+            swarm.dial(stdio::address())?;
+        }
+        Some(stdio::Role::Listen) => {
+            info!("Waiting for the peer to connect over stdio...");
+            swarm.listen_on(stdio::address())?;
+        }
+        None => {}
+    }
+
+    // Listening through a relay reserves a slot on it, and peers reach us at
+    // the relay's address followed by /p2p-circuit/p2p/<our id>
+    if !in_memory {
+        for relay in &args.relay {
+            if !relay.iter().any(|protocol| matches!(protocol, Protocol::P2p(_))) {
+                anyhow::bail!("--relay {relay} needs the relay's peer id, e.g. /ip4/203.0.113.7/tcp/4001/p2p/12D3KooW...");
+            }
+            info!("Reserving a slot on relay {relay}");
+            swarm.listen_on(relay.clone().with(Protocol::P2pCircuit))?;
+        }
+    }
+
+    // Connect to specified peers
+    dial_connect_addrs(&mut swarm, &args);
+
+    // Beacons announce our listen addresses as they come and go
+    let (beacon_addrs, beacon_addrs_rx) = tokio::sync::watch::channel(Vec::new());
+    let mut beacon_rx = match beacon_key {
+        Some(key) => {
+            let names: Vec<String> = rooms.iter().map(|room| room.name.clone()).collect();
+            let interval = Duration::from_secs(args.beacon_interval);
+            Some(beacon::spawn(key, &names, args.beacon_port, interval, args.interface.clone(), beacon_addrs_rx).await?)
+        }
+        None => None,
+    };
+
+    // Redial remembered peers so the group reforms even when mDNS is flaky
+    let mut autodial = address_book::Autodial::new(&address_book);
+    dial_known_peers(&mut swarm, &mut autodial);
+
+    // Initialize clipboard sync if enabled
+    let mut clipboard_rx = None;
+    let mut clipboard_tx = None;
+    let mut monitor = None;
+    let clipboard_sync = clipboard::ClipboardSync::with_backend(clipboard_options(&args, &local_peer_id), clipboard_backend)
+        .expect("Failed to create clipboard sync");
+    // Hot path timings, shared with the clipboard tasks
+    let timings = clipboard_sync.timings();
+    timings.set_budget(args.slow_op_ms);
+    // Recent full images, the bases image diffs are made from and applied to
+    let image_cache = pipeline::SharedImageCache::default();
+    // Payloads are sealed on the way out and opened on the way in
+    let field_key = args.field_encryption.as_deref().map(crypto::FieldKey::derive).transpose()?;
+    // Clipboard content is serialized and decoded off the event loop, where
+    // large payloads are spilled too. Bridges forward payloads as they came,
+    // so they keep them in memory.
+    let spill_threshold = Arc::new(AtomicUsize::new(args.spill_threshold));
+    let spill = bridge_rooms.is_none().then(|| pipeline::Spill { dir: payload_cache.dir().to_path_buf(), threshold: spill_threshold.clone() });
+    let (decode_tx, mut decoded_rx) = pipeline::spawn_decoder(image_cache.clone(), timings.clone(), field_key.clone(), spill);
+    let mut superseded = pipeline::Superseded::default();
+    // Largest image payload every peer takes, for the encoder to scale to
+    let image_budget = Arc::new(AtomicUsize::new(if args.strict_size { 0 } else { usize::MAX }));
+    let mut peer_backoff = peer_backoff::PeerBackoff::default();
+    if args.clipboard && !args.observer {
+        let shrink = pipeline::Shrink { limit: MAX_TRANSMIT_SIZE, payload: image_budget.clone() };
+        let (tx, rx) = pipeline::spawn_encoder(image_cache.clone(), timings.clone(), field_key.clone(), shrink);
+        clipboard_rx = Some(rx);
+        clipboard_tx = Some(tx.clone());
+        
+        // Start clipboard monitoring in a separate task
+        if let Some(ref _clipboard_topic) = clipboard_topic {
+            let clipboard_tx_clone = tx.clone();
+            
+            // Aborted on shutdown, which drops its sender and with it the encoder
+            monitor = Some(clipboard_sync.start_monitoring(move |content| {
+                // Send clipboard content to be encoded for network transmission
+                let _ = clipboard_tx_clone.send(content);
+            }).await?);
+        }
+
+        // Goes out like a local copy, so it waits for a subscriber if needed
+        if let Some(content) = stdin_content {
+            info!("Publishing {} bytes read from stdin as clipboard content", content.size());
+            let _ = tx.send(content);
+        }
+    }
+
+    // Latest clipboard content that could not be published yet because no peer
+    // was subscribed; sent as soon as a peer subscribes to the clipboard topic
+    let mut pending_clipboard: Option<PendingClipboard> = None;
+    let mut last_received: Option<(PeerId, clipboard::ClipboardContent)> = None;
+    // Text converters found in received images, for `/last`
+    let mut last_derived: Vec<convert::Derived> = Vec::new();
+    let converters = convert::from_args(&args);
+    let (convert_tx, mut derived_rx) = if converters.is_empty() {
+        (None, None)
+    } else {
+        let (tx, rx) = convert::spawn(converters);
+        (Some(tx), Some(rx))
+    };
+    let image_export_tx = args.image_export_dir.clone().map(image_export::spawn).transpose()?;
+    // Latest clipboard content seen on the topic, local or received, offered
+    // directly to peers that subscribe after it was published
+    let mut retained: Option<clipboard::ClipboardContent> = None;
+    // Room the retained content came from, None for local copies
+    let mut retained_room: Option<String> = None;
+    // Peers known to hold the retained content, None for local copies
+    let mut retained_holders: Option<Holders> = None;
+    // Room and payload hash of the content received last, so copying it again
+    // after the resend window still only sends it back to that room
+    let mut received_from: Option<(String, u64)> = None;
+    // Timestamp of the newest content seen, so older retained offers are ignored
+    let mut newest_timestamp = 0u64;
+    // The primary peer and when, on our clock, its content was last applied
+    let mut primary_copied_at: Option<(PeerId, u64)> = None;
+    // What each connected peer advertised in identify, replaced whenever it
+    // identifies again, e.g. after reconnecting with a new version
+    let mut peer_capabilities: HashMap<PeerId, capabilities::Capabilities> = HashMap::new();
+    // Device names peers advertise, shown next to their PeerIds
+    let mut device_names: HashMap<PeerId, String> = HashMap::new();
+    // Catches received content overwriting a simultaneous local copy
+    let mut conflicts = conflict::ConflictDetector::default();
+    // Peers already warned about for a skewed clock
+    let mut skewed_peers: HashSet<PeerId> = HashSet::new();
+    // Files copied here that peers may pull
+    let mut shared_files = files::SharedFiles::default();
+    // File chunks read off the event loop, waiting to be sent back
+    let (chunk_tx, mut chunk_rx) = mpsc::unbounded_channel::<(
+        PeerId,
+        request_response::ResponseChannel<files::ChunkResponse>,
+        files::ChunkRequest,
+        files::ChunkResponse,
+    )>();
+    // Chunks pulled from peers, written off the event loop
+    let (written_tx, mut written_rx) = mpsc::unbounded_channel::<files::Written>();
+    // Files peers are pulling from us, for progress
+    let mut uploads = files::Uploads::default();
+
+    let (status_tx, _status_rx) = watch::channel(control::NodeStatus { hardened: args.hardened, ..Default::default() });
+    // Subscribed before anything happens, so the log misses nothing
+    if has_front_end {
+        console::spawn(event_tx.subscribe(), live_progress);
+    }
+    // Files being pulled from peers. Interrupted downloads are saved with a
+    // profile so they survive a restart.
+    let mut downloads =
+        files::Downloads::open(profile.as_ref().map(|profile| profile.spill_dir().join("transfers")), event_tx.clone());
+    if let Some(ref path) = args.record {
+        trace::spawn(path, event_tx.subscribe(), Duration::from_secs(args.resend_window))?;
+        info!("Recording a trace to {}", path.display());
+    }
+    // Local clients such as `get`, and the `get`s waiting on peers
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel::<control_socket::Incoming>();
+    if has_front_end {
+        match control_socket::socket_path(args.profile.as_deref()) {
+            Ok(path) => control_socket::spawn(path, control_tx),
+            Err(e) => warn!("No control socket, `get` won't reach this node: {e:?}"),
+        }
+    }
+    let mut pending_gets: Vec<control_socket::PendingGet> = Vec::new();
+    // Rules received clipboard content must pass to be forwarded and applied
+    let mut policy = policy::Policy::from_args(&args);
+    let mut paused = false;
+    // Pauses caused by a screen share, undone when it ends
+    let mut auto_pause = screenshare::AutoPause::default();
+    if args.pause_on_screenshare {
+        screenshare::spawn(screenshare::platform_detector(), command_tx.clone());
+    }
+    if args.watch_network {
+        network_watch::spawn(network_watch::platform_watcher(), command_tx.clone());
+    }
+
+    stats.lock().expect("stats lock poisoned").set_payload_cache(payload_cache.counters());
+    stats.lock().expect("stats lock poisoned").set_op_timings(timings.clone());
+    stats.lock().expect("stats lock poisoned").bandwidth().set_cap(bandwidth_cap(&args));
+    if let Some(address) = args.metrics_address {
+        let stats = stats.clone();
+        let status = status_tx.subscribe();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(address, stats, status).await {
+                error!("Metrics endpoint failed: {e:?}");
+            }
+        });
+    }
+
+    #[cfg(feature = "tray")]
+    if args.tray {
+        #[cfg(target_os = "linux")]
+        tray::spawn(command_tx.clone(), status_tx.subscribe());
+        #[cfg(not(target_os = "linux"))]
+        error!("The tray icon is only supported on Linux");
+    }
+
+    // SIGHUP reloads the config file, like /reload
+    #[cfg(unix)]
+    if has_front_end {
+        let command_tx = command_tx.clone();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+                warn!("Failed to install SIGHUP handler; use /reload instead");
+                return;
+            };
+            while hangup.recv().await.is_some() {
+                let _ = command_tx.send(control::NodeCommand::Reload);
+            }
+        });
+    }
+
+    // Chat presence of peers and receipts for our own lines
+    let mut presence = chat::Presence::default();
+    let mut receipts = chat::Receipts::default();
+    let mut presence_timer = tokio::time::interval(chat::PRESENCE_INTERVAL);
+    let mut receipt_timer = tokio::time::interval(chat::RECEIPT_WINDOW / 2);
+
+    // Confirmations of our copies, on the delivery topic of each room we
+    // send to, and of received content to send out
+    if args.confirm_delivery {
+        for room in rooms.iter().filter(|room| room.direction.sends()) {
+            swarm.behaviour_mut().gossipsub.subscribe(&room.delivery)
+                .map_err(|e| anyhow::anyhow!("Failed to subscribe to delivery topic: {:?}", e))?;
+        }
+    }
+    let mut confirmations = delivery::Confirmations::default();
+    let mut confirmation_timer = tokio::time::interval(delivery::TIMEOUT / 4);
+    // Large local copies waiting for /confirm, and the ones confirmed
+    let mut large_copies = large_copy::Guard::default();
+    let (release_tx, mut release_rx) = mpsc::unbounded_channel::<pipeline::Outgoing>();
+    // Clipboard content read for /send-to, and the requests carrying it with
+    // their progress. A direct message moves in one piece, so large ones only
+    // report their start and a summary.
+    let (send_to_tx, mut send_to_rx) = mpsc::unbounded_channel::<(PeerId, clipboard::ClipboardContent)>();
+    let mut sends_to: HashMap<request_response::OutboundRequestId, progress::Progress> = HashMap::new();
+    // Retained content loaded off the event loop for a peer, with the room it
+    // goes to and the request to answer once it is on its way, if any
+    let (retained_tx, mut retained_rx) = mpsc::unbounded_channel::<LoadedRetained>();
+    // Clipboard publishes that found gossipsub's send queues full
+    let mut publish_retries = backpressure::Retries::default();
+    let mut retry_timer = tokio::time::interval(backpressure::RETRY_TICK);
+    let mut delivered_rx = clipboard_sync.subscribe_delivered();
+
+    // Group members to share with peers through the peer exchange
+    let mut known_peers = peer_exchange::KnownPeers::default();
+    let mut peer_exchange_timer = tokio::time::interval(peer_exchange::INTERVAL);
+
+    #[cfg(feature = "tui")]
+    let tui_thread = tui.map(|tui| {
+        tui.spawn(local_peer_id, command_tx.clone(), status_tx.subscribe(), event_tx.subscribe(), stats.clone())
+    });
+
+    // Read full lines from stdin, unless it was consumed as clipboard content
+    let mut stdin = console::LineReader::new(io::BufReader::new(io::stdin()));
+    let console_active =
+        has_front_end && !tui_active && !args.stdin_clipboard && !args.daemon && args.stdio_transport.is_none();
+    // Piped lines too long for chat go out as clipboard text instead, so a
+    // pipe may hold up to a whole clipboard message of one in memory
+    let piped_to_clipboard = clipboard_tx.is_some() && !std::io::IsTerminal::is_terminal(&std::io::stdin());
+    let line_limit = if piped_to_clipboard { args.max_chat_bytes.max(MAX_TRANSMIT_SIZE) } else { args.max_chat_bytes };
+    // Main event loop
+    if console_active {
+        info!("Enter messages to send to peers, or /help for commands. Press Ctrl+C to exit.");
+    }
+    loop {
+        select! {
+            // Handle user input from stdin
+            // The dashboard reads the keyboard itself
+            Ok(Some(line)) = stdin.next_line(line_limit), if console_active => {
+                let line = match line {
+                    console::Line::Text(line) if line.len() > args.max_chat_bytes => {
+                        info!("Sending a piped line of {} bytes, over the --max-chat-bytes limit, as clipboard content", line.len());
+                        if let Some(ref clipboard_tx) = clipboard_tx {
+                            let _ = clipboard_tx.send(clipboard::ClipboardContent::new_text(line));
+                        }
+                        continue;
+                    }
+                    console::Line::Text(line) => line,
+                    console::Line::TooLong(bytes) => {
+                        error!(
+                            "Ignored a console line of {bytes} bytes, over the --max-chat-bytes limit of {}; copy large text to the clipboard or pipe it to --stdin-clipboard instead",
+                            args.max_chat_bytes
+                        );
+                        continue;
+                    }
+                };
+                if line.starts_with('/') {
+                    match control::parse_command(&line) {
+                        Ok(command) => { let _ = command_tx.send(command); }
+                        Err(e) => error!("{e}"),
+                    }
+                } else if args.observer {
+                    warn!("Observer nodes cannot send chat messages");
+                } else if !line.is_empty() {
+                    // Only peers subscribed to the chat topic get the message
+                    let peers = subscriptions::topic_subscribers(&swarm.behaviour().gossipsub, &chat_topic.hash()).len();
+                    if peers > 0 {
+                        let msg_id = receipts.next_msg_id();
+                        // Peers predating the envelope get the bare line, without receipts
+                        let envelope = chat_envelope(&swarm.behaviour().gossipsub, &chat_topic, &peer_capabilities);
+                        let encoded = if envelope {
+                            let message = chat::ChatMessage::Text { msg_id, text: line.clone(), receipts: !args.no_receipts };
+                            serde_json::to_vec(&message).map_err(anyhow::Error::from)
+                        } else {
+                            Ok(line.clone().into_bytes())
+                        };
+                        match encoded.and_then(|data| publish(&mut swarm, &args, &stats, chat_topic.clone(), data)) {
+                            Ok(_) => {
+                                info!("Sent to {peers} peers on {chat_topic}: {line}");
+                                subscription_check.record_sync(&chat_topic.hash());
+                                if envelope && !args.no_receipts {
+                                    receipts.track(msg_id, &line, peers);
+                                }
+                            }
+                            Err(e) => {
+                                stats.lock().expect("stats lock poisoned").record_publish_failure(&e.to_string());
+                                let _ = event_tx.send(control::NodeEvent::PublishFailed { topic: chat_topic.hash(), reason: e.to_string() });
+                            }
+                        }
+                    } else {
+                        // With nobody on the chat topic, just echo the message locally
+                        info!("[Local] {}", line);
+                        info!("No peers subscribed to {chat_topic}, message not sent");
+                    }
+                }
+            }
+            
+            // Requests from local clients over the control socket
+            Some((request, reply)) = control_rx.recv() => match request {
+                control_socket::ControlRequest::Get { apply, timeout_ms } => {
+                    let askable: Vec<PeerId> = swarm.behaviour().gossipsub.all_peers()
+                        .filter(|(_, topics)| {
+                            rooms
+                                .iter()
+                                .filter(|room| room.direction.receives())
+                                .any(|room| topics.contains(&&room.clipboard.hash()))
+                        })
+                        .map(|(peer, _)| *peer)
+                        .filter(|peer| understands(&peer_capabilities, peer, direct::PUSH_VERSION))
+                        .collect();
+                    if clipboard_topic.is_none() {
+                        let _ = reply.send(control_socket::ControlResponse::Error("Clipboard sync is not enabled on the running node".to_string()));
+                    } else if apply && args.observer {
+                        let _ = reply.send(control_socket::ControlResponse::Error("Observer nodes never write the clipboard".to_string()));
+                    } else if retained.is_some() {
+                        answer_get(&clipboard_sync, retained.as_ref(), apply, reply);
+                    } else if askable.is_empty() {
+                        let _ = reply.send(control_socket::ControlResponse::Error(
+                            "No clipboard content is known and no peers are connected to ask".to_string(),
+                        ));
+                    } else {
+                        info!("Asking {} peers for their latest clipboard content", askable.len());
+                        let waiting = askable
+                            .into_iter()
+                            .map(|peer| (peer, send_direct(&mut swarm, &stats, &peer, direct::DirectRequest::QueryLatest)))
+                            .collect();
+                        pending_gets.push(control_socket::PendingGet {
+                            apply,
+                            deadline: Instant::now() + Duration::from_millis(timeout_ms),
+                            waiting,
+                            reply,
+                        });
+                    }
+                }
+                control_socket::ControlRequest::Log(filter) => {
+                    let _ = reply.send(match filter.apply() {
+                        Ok(active) => {
+                            info!(target: logging::REPLY_TARGET, "Log filter: {active}");
+                            control_socket::ControlResponse::LogFilter(active)
+                        }
+                        Err(e) => control_socket::ControlResponse::Error(e.to_string()),
+                    });
+                }
+            },
+
+            // A `get` gave up on peers that didn't answer
+            _ = async {
+                match pending_gets.iter().map(|get| get.deadline).min() {
+                    Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                    None => futures::future::pending().await,
+                }
+            } => {
+                finish_gets(&mut pending_gets, &clipboard_sync, retained.as_ref());
+            }
+
+            // Handle commands from the console and the tray
+            Some(command) = command_rx.recv() => match command {
+                control::NodeCommand::Pause => {
+                    paused = true;
+                    auto_pause.on_manual_change();
+                    let _ = event_tx.send(control::NodeEvent::PauseChanged { paused: true, cause: control::PauseCause::Manual });
+                    status_tx.send_modify(|status| status.paused = true);
+                    announce_presence(&mut swarm, &args, &stats, &peer_capabilities, &chat_topic, chat::PresenceState::Paused);
+                }
+                control::NodeCommand::Resume => {
+                    paused = false;
+                    auto_pause.on_manual_change();
+                    let _ = event_tx.send(control::NodeEvent::PauseChanged { paused: false, cause: control::PauseCause::Manual });
+                    status_tx.send_modify(|status| status.paused = false);
+                    announce_presence(&mut swarm, &args, &stats, &peer_capabilities, &chat_topic, chat::PresenceState::Active);
+                }
+                control::NodeCommand::ScreenShare(sharing) => match auto_pause.on_screenshare(sharing, paused) {
+                    Some(pause) => {
+                        paused = pause;
+                        let _ = event_tx.send(control::NodeEvent::PauseChanged { paused: pause, cause: control::PauseCause::ScreenShare });
+                        status_tx.send_modify(|status| status.paused = pause);
+                        let state = if pause { chat::PresenceState::Paused } else { chat::PresenceState::Active };
+                        announce_presence(&mut swarm, &args, &stats, &peer_capabilities, &chat_topic, state);
+                    }
+                    None if sharing => info!("Screen sharing detected, clipboard sync was already paused"),
+                    None if paused => info!("Screen sharing ended, clipboard sync stays paused until /resume"),
+                    None => info!("Screen sharing ended"),
+                },
+                control::NodeCommand::Hardened(on) if on == args.hardened => {
+                    info!("Hardened mode is already {}", if on { "on" } else { "off" });
+                }
+                control::NodeCommand::Hardened(on) => {
+                    args.hardened = on;
+                    // Listeners stay up, new inbound connections are gated as
+                    // they are established
+                    swarm.behaviour_mut().gate.update(&args, &address_book);
+                    swarm.behaviour_mut().mdns = if on || !mdns_wanted {
+                        None.into()
+                    } else {
+                        match mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id) {
+                            Ok(mdns) => Some(mdns).into(),
+                            Err(e) => {
+                                warn!("Failed to restart mDNS discovery: {e}");
+                                None.into()
+                            }
+                        }
+                    };
+                    beacon_addrs.send_replace(announced_addrs(&swarm, &args));
+                    policy = policy::Policy::from_args(&args);
+                    status_tx.send_modify(|status| status.hardened = on);
+                    if on {
+                        info!("Hardened mode on: no local discovery, inbound connections from known group members only");
+                    } else {
+                        info!("Hardened mode off");
+                    }
+                }
+                control::NodeCommand::NetworkChanged => {
+                    info!("Network changed, rediscovering and redialing peers");
+                    // A fresh mDNS behaviour queries on every interface right
+                    // away instead of at the next query interval. Nodes that
+                    // run without mDNS, hardened or in-memory, stay without.
+                    if swarm.behaviour().mdns.is_enabled() {
+                        match mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id) {
+                            Ok(mdns) => swarm.behaviour_mut().mdns = Some(mdns).into(),
+                            Err(e) => warn!("Failed to restart mDNS discovery: {e}"),
+                        }
+                    }
+                    dial_connect_addrs(&mut swarm, &args);
+                    autodial = address_book::Autodial::new(&address_book);
+                    dial_known_peers(&mut swarm, &mut autodial);
+                    if interfaces.is_some() {
+                        interface_timer.reset_immediately();
+                    }
+                    if args.subscription_check_secs > 0 {
+                        subscription_timer.reset_immediately();
+                    }
+                }
+                control::NodeCommand::SendClipboard => {
+                    if args.observer {
+                        warn!("Observer nodes never publish clipboard content");
+                        continue;
+                    }
+                    let Some(ref tx) = clipboard_tx else {
+                        info!("Clipboard sync is not enabled");
+                        continue;
+                    };
+                    // Fresh content has a fresh timestamp, so gossipsub won't
+                    // treat a re-send as a duplicate. The backend may be slow,
+                    // so read it without holding up the event loop.
+                    let clipboard = clipboard_sync.clone();
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        match clipboard.current().await {
+                            Ok(Some(content)) => {
+                                let _ = tx.send(content);
+                            }
+                            Ok(None) => info!("The clipboard is empty"),
+                            Err(e) => error!("Failed to read the clipboard: {e:?}"),
+                        }
+                    });
+                }
+                control::NodeCommand::SendTo(peer) => {
+                    if args.observer {
+                        warn!("Observer nodes never publish clipboard content");
+                        continue;
+                    }
+                    if clipboard_tx.is_none() {
+                        info!("Clipboard sync is not enabled");
+                        continue;
+                    }
+                    if !swarm.is_connected(&peer) {
+                        error!("Can't send to {peer}: not connected. /peers lists the connected peers");
+                        continue;
+                    }
+                    let clipboard = clipboard_sync.clone();
+                    let tx = send_to_tx.clone();
+                    tokio::spawn(async move {
+                        match clipboard.current().await {
+                            Ok(Some(content)) => {
+                                let _ = tx.send((peer, content));
+                            }
+                            Ok(None) => info!("The clipboard is empty"),
+                            Err(e) => error!("Failed to read the clipboard: {e:?}"),
+                        }
+                    });
+                }
+                control::NodeCommand::ConfirmLarge => match large_copies.confirm() {
+                    Some(outgoing) => {
+                        let _ = release_tx.send(outgoing);
+                    }
+                    None => info!("No large copy is waiting to be confirmed"),
+                },
+                control::NodeCommand::ShowLastReceived => match &last_received {
+                    Some((peer_id, content)) => {
+                        info!("Last received from {peer_id}: {}", clipboard::loggable(content, args.log_content));
+                        if let Some(provenance) = content.provenance_note() {
+                            info!("  Came {provenance}");
+                        }
+                        for derived in last_derived.iter().filter(|derived| derived.timestamp == content.timestamp) {
+                            info!(
+                                "  Derived by {}: {}",
+                                derived.converter.label(),
+                                clipboard::loggable_text(&derived.text, args.log_content)
+                            );
+                        }
+                    }
+                    None => info!("No clipboard content received yet"),
+                },
+                control::NodeCommand::ShowPeers => {
+                    let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                    info!("{} connected peers", peers.len());
+                    let stats = stats.lock().expect("stats lock poisoned");
+                    for peer in peers {
+                        let mut details = Vec::new();
+                        if let Some(latency) = stats.latency(&peer).filter(|latency| latency.samples() > 0) {
+                            details.push(format!("clock offset {}ms", latency.clock_offset_ms()));
+                        }
+                        if let Some(failures) = peer_backoff.describe(&peer, Instant::now()) {
+                            details.push(failures);
+                        }
+                        if let Some(((sent, received), (recent_sent, recent_received))) = stats.traffic(&peer) {
+                            details.push(format!(
+                                "sent {sent} B, received {received} B, last hour {recent_sent}/{recent_received} B"
+                            ));
+                        }
+                        let details = if details.is_empty() { String::new() } else { format!(" ({})", details.join(", ")) };
+                        info!("  {} {}{details}", peer_label(&device_names, &peer), presence.describe(&peer));
+                    }
+                }
+                control::NodeCommand::ShowKnownPeers => {
+                    let entries = address_book.recent();
+                    if entries.is_empty() {
+                        info!("The address book is empty");
+                    }
+                    for (peer, entry) in entries {
+                        let name = entry.device_name.as_deref().unwrap_or("-");
+                        let state = if swarm.is_connected(&peer) { "connected" } else { "offline" };
+                        info!("{peer} {name} {state}, last seen {}", address_book::format_age(entry.last_seen));
+                        for addr in &entry.addrs {
+                            info!("    {addr}");
+                        }
+                    }
+                }
+                control::NodeCommand::ShowStats => {
+                    let lines = stats.lock().expect("stats lock poisoned").summary();
+                    if lines.is_empty() {
+                        info!("No sync statistics yet");
+                    }
+                    for line in lines {
+                        info!("{line}");
+                    }
+                }
+                control::NodeCommand::ShowStatus => {
+                    if args.hardened {
+                        info!("HARDENED: no local discovery, inbound connections from known group members only");
+                    }
+                    for health in subscription_check.health(&swarm.behaviour().gossipsub) {
+                        let since_sync = match health.since_sync {
+                            Some(elapsed) => format!("last sync {}s ago", elapsed.as_secs()),
+                            None => "no sync yet".to_string(),
+                        };
+                        info!(
+                            "{}: {}, {} mesh peers, {} subscribers, {since_sync}",
+                            health.topic,
+                            if health.subscribed { "subscribed" } else { "NOT subscribed" },
+                            health.mesh_peers,
+                            health.subscribers
+                        );
+                    }
+                }
+                control::NodeCommand::ShowQueue => {
+                    let queue = clipboard_sync.peek_incoming().await;
+                    if queue.is_empty() {
+                        info!("Incoming queue is empty");
+                    }
+                    for (index, content) in queue.iter().enumerate() {
+                        info!("#{index}: {}", clipboard::loggable(content, args.log_content));
+                    }
+                }
+                control::NodeCommand::AcceptIncoming(index) => {
+                    let clipboard = clipboard_sync.clone();
+                    tokio::spawn(async move {
+                        match clipboard.accept_incoming(index).await {
+                            Ok(()) => info!("Applied queued item #{index}"),
+                            Err(e) => error!("Failed to accept queued item: {e:?}"),
+                        }
+                    });
+                }
+                control::NodeCommand::RejectIncoming(index) => {
+                    match clipboard_sync.reject_incoming(index).await {
+                        Ok(_) => info!("Dropped queued item #{index}"),
+                        Err(e) => error!("{e}"),
+                    }
+                }
+                control::NodeCommand::CancelDownloads => match downloads.cancel_all() {
+                    0 => info!("No downloads in progress"),
+                    n => info!("Cancelled {n} downloads"),
+                },
+                control::NodeCommand::ResumeTransfers => {
+                    let send = |peer: &PeerId, request| swarm.behaviour_mut().files.send_request(peer, request);
+                    if downloads.resume(None, send) == 0 {
+                        info!("No interrupted downloads");
+                    }
+                }
+                control::NodeCommand::Help => {
+                    for (command, description) in control::HELP {
+                        info!("{command:<14} {description}");
+                    }
+                }
+                control::NodeCommand::Reload => match config::reload(&mut args) {
+                    Ok(()) => {
+                        clipboard_sync.set_options(clipboard_options(&args, &local_peer_id)).await;
+                        update_image_budget(&image_budget, &args, &peer_capabilities);
+                        policy = policy::Policy::from_args(&args);
+                        payload_cache.set_limits(cache_limits(&args));
+                        spill_threshold.store(args.spill_threshold, Ordering::Relaxed);
+                        timings.set_budget(args.slow_op_ms);
+                        stats.lock().expect("stats lock poisoned").bandwidth().set_cap(bandwidth_cap(&args));
+                        if subscription_timer.period() != subscription_interval(&args).period() {
+                            subscription_timer = subscription_interval(&args);
+                        }
+                    }
+                    Err(e) => error!("Config reload failed, keeping the current configuration: {e:?}"),
+                },
+                control::NodeCommand::Log(filter) => match filter.apply() {
+                    Ok(active) => info!(target: logging::REPLY_TARGET, "Log filter: {active}"),
+                    Err(e) => error!(target: logging::REPLY_TARGET, "{e}"),
+                },
+                control::NodeCommand::Quit => {
+                    announce_presence(&mut swarm, &args, &stats, &peer_capabilities, &chat_topic, chat::PresenceState::Offline);
+                    // Chunks being written are kept, so the downloads resume after them
+                    while downloads.writing() {
+                        let Some(written) = written_rx.recv().await else { break };
+                        downloads.suspend_written(written);
+                    }
+                    downloads.suspend_all();
+                    break;
+                }
+            },
+
+            // Periodically tell each group member about the others
+            _ = peer_exchange_timer.tick(), if !args.no_peer_exchange => {
+                let recipients: Vec<PeerId> = known_peers.peers().copied().collect();
+                for recipient in recipients {
+                    let records = known_peers.records_for(&recipient);
+                    if !records.is_empty() {
+                        send_direct(&mut swarm, &stats, &recipient, direct::DirectRequest::PeerExchange(records));
+                    }
+                }
+            }
+
+            // Re-join topics whose subscription or mesh got lost
+            // Remedies escalate while a topic stays desynced
+            _ = subscription_timer.tick(), if args.subscription_check_secs > 0 => {
+                let members: Vec<PeerId> = known_peers.peers().copied().collect();
+                let mut applied = HashSet::new();
+                for (topic, desync, remedy) in subscription_check.run(&swarm.behaviour().gossipsub, &explicit_peers, &members) {
+                    let problem = match desync {
+                        subscriptions::Desync::Lost => "subscription was lost",
+                        subscriptions::Desync::Unmeshed => "no subscribed peer forwards to us",
+                        subscriptions::Desync::Deserted => "no connected group member is subscribed",
+                    };
+                    match remedy {
+                        subscriptions::Remedy::ReaddExplicitPeers => {
+                            warn!("Topic {topic}: {problem}, re-adding connected peers");
+                            if !args.readonly_topics && applied.insert(remedy) {
+                                for peer in &members {
+                                    swarm.behaviour_mut().gossipsub.add_explicit_peer(peer);
+                                    explicit_peers.insert(*peer);
+                                }
+                            }
+                        }
+                        subscriptions::Remedy::RedialKnownPeers => {
+                            warn!("Topic {topic}: {problem}, redialing remembered peers");
+                            if applied.insert(remedy) {
+                                autodial = address_book::Autodial::new(&address_book);
+                                dial_known_peers(&mut swarm, &mut autodial);
+                            }
+                        }
+                        subscriptions::Remedy::Resubscribe => {
+                            warn!("Topic {topic}: {problem}, re-subscribing");
+                            match subscriptions::SubscriptionCheck::recover(&mut swarm.behaviour_mut().gossipsub, &topic) {
+                                Ok(()) => info!("Recovered subscription to topic {topic}"),
+                                Err(e) => error!("Failed to re-subscribe to topic {topic}: {e:?}"),
+                            }
+                        }
+                    }
+                }
+            }
+
+            // After a suspend, redial and check the topics without waiting
+            _ = sleep_timer.tick() => {
+                if let Some(slept) = sleep_detector.check() {
+                    info!("Woke up after about {}s asleep, checking connections and topics", slept.as_secs());
+                    autodial = address_book::Autodial::new(&address_book);
+                    dial_known_peers(&mut swarm, &mut autodial);
+                    if args.subscription_check_secs > 0 {
+                        subscription_timer.reset_immediately();
+                    }
+                }
+            }
+
+            // Tell the group we are still here
+            _ = presence_timer.tick() => {
+                let state = if paused { chat::PresenceState::Paused } else { chat::PresenceState::Active };
+                announce_presence(&mut swarm, &args, &stats, &peer_capabilities, &chat_topic, state);
+            }
+
+            // Content applied here whose sender wants to know
+            Ok(delivered) = delivered_rx.recv() => {
+                if args.no_receipts {
+                    debug!("Not confirming received clipboard content: receipts are off");
+                    continue;
+                }
+                let Some(room) = delivered.room.and_then(|name| rooms.iter().find(|room| room.name == name)) else {
+                    debug!("Not confirming received clipboard content: it came from no room");
+                    continue;
+                };
+                match serde_json::to_vec(&delivered.ack) {
+                    Ok(data) => {
+                        if let Err(e) = publish(&mut swarm, &args, &stats, room.delivery.clone(), data) {
+                            debug!("Failed to confirm received clipboard content: {e}");
+                        }
+                    }
+                    Err(e) => warn!("Failed to serialize delivery confirmation: {e}"),
+                }
+            }
+
+            // Report recipients that never confirmed our copies
+            _ = confirmation_timer.tick(), if !confirmations.is_empty() => {
+                for (preview, missing) in confirmations.take_expired(Instant::now()) {
+                    let _ = event_tx.send(control::NodeEvent::DeliveryUnconfirmed { preview, missing });
+                }
+            }
+
+            // Publishes that found the send queues full go out once they drain,
+            // or directly to the topic's peers if they don't
+            _ = retry_timer.tick(), if !publish_retries.is_empty() => {
+                for retry in publish_retries.take_due() {
+                    stats.lock().expect("stats lock poisoned").record_publish_retry();
+                    let size = retry.data.len();
+                    match timings.time("publish", size, || publish(&mut swarm, &args, &stats, retry.topic.clone(), retry.data.clone())) {
+                        Ok(_) => {
+                            debug!("Clipboard content published after the send queues drained");
+                            let subscribers = subscriptions::topic_subscribers(&swarm.behaviour().gossipsub, &retry.topic.hash());
+                            subscription_check.record_sync(&retry.topic.hash());
+                            let _ = event_tx.send(retry.sent_event);
+                            stats.lock().expect("stats lock poisoned").record_room(stats::Direction::Sent, &retry.room);
+                            if let (Some(audit_log), Some(item)) = (&mut audit_log, retry.audit_item) {
+                                audit_log.sent_item(item, &subscribers, &retry.room);
+                            }
+                            if let Some((sha256, preview)) = retry.confirm {
+                                confirmations.track(sha256, preview, &subscribers);
+                            }
+                        }
+                        Err(e) if backpressure::is_queue_full(&e) => {
+                            let Some(retry) = publish_retries.retry_later(retry) else { continue };
+                            let hash = retry.topic.hash();
+                            let mut targets: Vec<PeerId> = swarm.behaviour().gossipsub.mesh_peers(&hash).copied().collect();
+                            if targets.is_empty() {
+                                targets = subscriptions::topic_subscribers(&swarm.behaviour().gossipsub, &hash);
+                            }
+                            targets.retain(|peer| {
+                                peer_capabilities.get(peer).is_none_or(|theirs| theirs.accepts(retry.content.content_type))
+                                    && understands(&peer_capabilities, peer, direct::PUSH_VERSION)
+                            });
+                            if targets.is_empty() {
+                                stats.lock().expect("stats lock poisoned").record_publish_failure(&e.to_string());
+                                let _ = event_tx.send(control::NodeEvent::PublishFailed { topic: hash, reason: e.to_string() });
+                                continue;
+                            }
+                            warn!("Send queues stayed full, sending the clipboard content directly to {} peers", targets.len());
+                            stats.lock().expect("stats lock poisoned").record_publish_fallback();
+                            let sent = DirectSend { content: &retry.content, room: &retry.room, audit_item: retry.audit_item, confirm: retry.confirm };
+                            send_directly(&mut swarm, &stats, &event_tx, &mut audit_log, &mut confirmations, field_key.as_ref(), &targets, sent);
+                        }
+                        Err(e) => {
+                            stats.lock().expect("stats lock poisoned").record_publish_failure(&e.to_string());
+                            let _ = event_tx.send(control::NodeEvent::PublishFailed { topic: retry.topic.hash(), reason: e.to_string() });
+                        }
+                    }
+                }
+                stats.lock().expect("stats lock poisoned").set_publish_queue(publish_retries.len());
+            }
+
+            // Report chat lines whose receipt window has passed
+            _ = receipt_timer.tick(), if !receipts.is_empty() => {
+                for (text, delivered, recipients) in receipts.take_finished() {
+                    info!("Delivered to {delivered}/{recipients}: {text}");
+                }
+            }
+
+            // Rebind listeners when the chosen interfaces' addresses change
+            _ = interface_timer.tick(), if interfaces.is_some() => {
+                let Some(ref mut interfaces) = interfaces else { continue };
+                match interfaces.refresh() {
+                    Ok((added, removed)) => {
+                        for address in removed {
+                            if let Some(id) = interface_listeners.remove(&address) {
+                                info!("Address {address} went away, closing its listener");
+                                swarm.remove_listener(id);
+                            }
+                        }
+                        for address in added {
+                            match listen_on(&mut swarm, &args, address) {
+                                Ok(id) => { interface_listeners.insert(address, id); }
+                                Err(e) => warn!("Failed to listen on new address {address}: {e}"),
+                            }
+                        }
+                    }
+                    Err(e) => warn!("{e:?}"),
+                }
+            }
+
+            // Group members announced by beacons are taken like mDNS
+            // discoveries, then dialed since no behaviour knows their addresses
+            Some(found) = async {
+                match beacon_rx {
+                    Some(ref mut rx) => rx.recv().await,
+                    None => futures::future::pending().await,
+                }
+            } => {
+                let beacon::Discovered { peer, addrs } = found;
+                if args.hardened {
+                    continue;
+                }
+                let addrs: Vec<Multiaddr> = addrs
+                    .into_iter()
+                    .filter(|addr| discovery_allowed(&args, interfaces.as_ref(), "beacon", &peer, addr))
+                    .collect();
+                if addrs.is_empty() || swarm.is_connected(&peer) {
+                    continue;
+                }
+                info!("Beacon discovered a new peer: {peer} at {}", addrs[0]);
+                add_discovered(&mut swarm, &args, &mut explicit_peers, peer);
+                if let Err(e) = swarm.dial(DialOpts::peer_id(peer).addresses(addrs).build()) {
+                    debug!("Failed to dial beacon peer {peer}: {e}");
+                }
+            }
+
+            // Expire spilled payloads
+            _ = cache_gc_timer.tick() => {
+                payload_cache.gc();
+                downloads.expire();
+                for event in uploads.expire() {
+                    let _ = event_tx.send(event);
+                }
+                peer_backoff.prune(Instant::now());
+                superseded.prune(clipboard::now_millis());
+            }
+
+            // Ctrl+C shuts down through the same path as /quit
+            _ = tokio::signal::ctrl_c(), if has_front_end => {
+                let _ = command_tx.send(control::NodeCommand::Quit);
+            }
+
+            // Handle clipboard content to be sent
+            Some(outgoing) = async {
+                if let Some(ref mut rx) = clipboard_rx {
+                    // Confirmed large copies first, they were copied earlier
+                    tokio::select! {
+                        biased;
+                        Some(outgoing) = release_rx.recv() => Some(outgoing),
+                        outgoing = rx.recv() => outgoing,
+                    }
+                } else {
+                    futures::future::pending().await
+                }
+            } => {
+                let Some(outgoing) = large_copies.check(outgoing, args.confirm_large) else {
+                    if let Some((preview, size)) = large_copies.held() {
+                        let _ = event_tx.send(control::NodeEvent::LargeCopyHeld { preview, size, threshold: args.confirm_large });
+                    }
+                    continue;
+                };
+                // Send clipboard content to network
+                if paused {
+                    info!("Clipboard sync is paused. Content not published.");
+                } else if clipboard_topic.is_some() {
+                    let pipeline::Outgoing { content, mut data, diffed, sha256, shrunk_from } = outgoing;
+                    let confirm = sha256.map(|sha256| (sha256, content.preview()));
+                    let from_room = received_from
+                        .as_ref()
+                        .filter(|(_, hash)| *hash == pipeline::payload_hash(&content))
+                        .map(|(room, _)| room.clone());
+                    let bridging = bridge_rooms.is_some();
+                    let Some(home) = rooms.iter().find(|room| room.may_send(from_room.as_deref(), bridging)) else {
+                        debug!("Not publishing the clipboard content: no room to send it to");
+                        continue;
+                    };
+                    if let Some(offers) = content.files() {
+                        shared_files.offer(offers);
+                    }
+                    // Route by content type so a large image never holds up text
+                    let topic = if content.content_type == clipboard::ContentType::Image || data.len() > FAST_PATH_MAX_SIZE {
+                        &home.bulk
+                    } else {
+                        &home.clipboard
+                    };
+                    conflicts.local_copy(&content);
+                    if content.content_type == clipboard::ContentType::Image && !stats.lock().expect("stats lock poisoned").bandwidth().allows_bulk() {
+                        info!("Bandwidth cap reached, not publishing the copied image");
+                        continue;
+                    }
+                    // Only peers subscribed to this clipboard topic get the content
+                    let subscribers = subscriptions::topic_subscribers(&swarm.behaviour().gossipsub, &topic.hash());
+                    let clipboard_peers = subscribers.len();
+                    // The topic carries it to everyone, wanted or not
+                    let ignoring: Vec<String> = subscribers
+                        .iter()
+                        .filter(|peer| peer_capabilities.get(peer).is_some_and(|theirs| !theirs.accepts(content.content_type)))
+                        .map(|peer| peer_label(&device_names, peer))
+                        .collect();
+                    if !ignoring.is_empty() {
+                        info!(
+                            "{} of {clipboard_peers} peers will ignore this {:?} content: {}",
+                            ignoring.len(),
+                            content.content_type,
+                            ignoring.join(", ")
+                        );
+                    }
+                    if let Some(size) = shrunk_from {
+                        stats.lock().expect("stats lock poisoned").record_downgrade(downgrade::SHRINK);
+                        let _ = event_tx.send(control::NodeEvent::Downgraded {
+                            tier: downgrade::SHRINK,
+                            content_type: content.content_type,
+                            size,
+                        });
+                    }
+                    // Content too large for the mesh or for some peers goes out in another form
+                    let mut plan = downgrade::Plan::AsIs;
+                    if !args.strict_size {
+                        let recipients: Vec<downgrade::Recipient> = subscribers
+                            .iter()
+                            .map(|peer| downgrade::Recipient {
+                                peer: *peer,
+                                max_image_bytes: peer_capabilities.get(peer).and_then(|theirs| theirs.max_image_bytes),
+                            })
+                            .collect();
+                        let size = content.size();
+                        plan = downgrade::plan(content.content_type, size, data.len(), MAX_TRANSMIT_SIZE, &recipients);
+                        if plan != downgrade::Plan::AsIs {
+                            stats.lock().expect("stats lock poisoned").record_downgrade(plan.label());
+                            let _ = event_tx.send(control::NodeEvent::Downgraded {
+                                tier: plan.label(),
+                                content_type: content.content_type,
+                                size,
+                            });
+                        }
+                    }
+                    let sent_event = control::NodeEvent::sent(&content, clipboard_peers);
+                    // A member of several rooms sends its copies to every room
+                    // it sends to, encoded once off the event loop. Not as a
+                    // diff, as only the home room's subscribers are checked
+                    // for being able to rebuild one.
+                    if !bridging {
+                        let others = rooms.iter().filter(|room| room.name != home.name && room.may_send(from_room.as_deref(), false));
+                        for room in others {
+                            let target = if topic.hash() == home.bulk.hash() { &room.bulk } else { &room.clipboard };
+                            publish_to_room(&mut swarm, &args, &stats, &mut audit_log, room, target, &content, || Ok(data.clone()));
+                        }
+                    }
+                    // Peers that can't rebuild a diff would reject it, so
+                    // only send one if every subscriber can
+                    if args.image_diffs
+                        && clipboard_peers > 0
+                        && subscribers.iter().all(|peer| peer_capabilities.get(peer).is_some_and(|theirs| theirs.image_diffs))
+                        && let Some(encoded) = diffed
+                    {
+                        info!("Sending image as a diff: {} bytes instead of {}", encoded.len(), data.len());
+                        data = encoded;
+                    }
+                    stats.lock().expect("stats lock poisoned")
+                        .record_size(stats::Direction::Sent, &content.content_type, data.len());
+                    newest_timestamp = newest_timestamp.max(content.timestamp);
+                    // A bridge's own copies go to its other room as well
+                    if let Some(ref rooms) = bridge_rooms
+                        && let Some((room, target, forwarded)) = bridge::forward(rooms, &topic.hash(), &content)
+                    {
+                        forward_to_room(&mut swarm, &args, &stats, &timings, &mut audit_log, field_key.as_ref(), room, target, &forwarded);
+                    }
+                    let too_large = |content: &clipboard::ClipboardContent| direct::DirectRequest::TooLarge {
+                        content_type: content.content_type,
+                        size: content.size(),
+                    };
+                    // Peers too old to decode pushed content or notices get neither
+                    let plan = match plan {
+                        downgrade::Plan::Direct { mut to, mut notice } => {
+                            to.retain(|peer| understands(&peer_capabilities, peer, direct::PUSH_VERSION));
+                            notice.retain(|peer| understands(&peer_capabilities, peer, direct::PUSH_VERSION));
+                            downgrade::Plan::Direct { to, notice }
+                        }
+                        downgrade::Plan::Notice { mut to } => {
+                            to.retain(|peer| understands(&peer_capabilities, peer, direct::PUSH_VERSION));
+                            downgrade::Plan::Notice { to }
+                        }
+                        downgrade::Plan::AsIs => downgrade::Plan::AsIs,
+                    };
+                    let direct_to = match plan {
+                        downgrade::Plan::Direct { to, notice } => {
+                            info!(
+                                "Copied content is too large to publish, sending it directly to {} peers and a notice to {}",
+                                to.len(),
+                                notice.len()
+                            );
+                            for peer in &notice {
+                                send_direct(&mut swarm, &stats, peer, too_large(&content));
+                            }
+                            Some(to)
+                        }
+                        downgrade::Plan::Notice { to } => {
+                            warn!("Copied content is too large for any peer, telling {} peers instead of sending it", to.len());
+                            for peer in &to {
+                                send_direct(&mut swarm, &stats, peer, too_large(&content));
+                            }
+                            continue;
+                        }
+                        downgrade::Plan::AsIs => capped_targets(
+                            args.max_peers_for_clipboard,
+                            &subscribers,
+                            &peer_capabilities,
+                            content.content_type,
+                            &stats.lock().expect("stats lock poisoned"),
+                        ),
+                    };
+                    let audit_item = audit_log.as_ref().map(|audit_log| audit_log.item(&content));
+                    retained = Some(content);
+                    retained_room = from_room;
+                    retained_holders = None;
+                    if let Some(targets) = direct_to {
+                        let content = retained.as_ref().expect("retained above");
+                        let sent = DirectSend { content, room: &home.name, audit_item, confirm };
+                        send_directly(&mut swarm, &stats, &event_tx, &mut audit_log, &mut confirmations, field_key.as_ref(), &targets, sent);
+                        continue;
+                    }
+
+                    if clipboard_peers > 0 {
+                        let size = data.len();
+                        // A copy is kept in case the publish has to be retried
+                        match timings.time("publish", size, || publish(&mut swarm, &args, &stats, topic.clone(), data.clone())) {
+                            Ok(_) => {
+                                // The console logs the send at info, this only adds the topic
+                                debug!("Sent clipboard content to {clipboard_peers} peers on {topic}");
+                                subscription_check.record_sync(&topic.hash());
+                                let _ = event_tx.send(sent_event);
+                                stats.lock().expect("stats lock poisoned").record_room(stats::Direction::Sent, &home.name);
+                                if let (Some(audit_log), Some(item)) = (&mut audit_log, audit_item) {
+                                    audit_log.sent_item(item, &subscribers, &home.name);
+                                }
+                                if let Some((sha256, preview)) = confirm {
+                                    confirmations.track(sha256, preview, &subscribers);
+                                }
+                            }
+                            Err(e) if backpressure::is_queue_full(&e) => {
+                                debug!("Send queues to every peer are full, retrying the clipboard content shortly");
+                                publish_retries.push(backpressure::Retry::new(
+                                    topic.clone(),
+                                    data,
+                                    retained.clone().expect("retained above"),
+                                    home.name.clone(),
+                                    sent_event,
+                                    audit_item,
+                                    confirm,
+                                ));
+                                stats.lock().expect("stats lock poisoned").set_publish_queue(publish_retries.len());
+                            }
+                            Err(e) => {
+                                stats.lock().expect("stats lock poisoned").record_publish_failure(&e.to_string());
+                                let _ = event_tx.send(control::NodeEvent::PublishFailed { topic: topic.hash(), reason: e.to_string() });
+                            }
+                        }
+                    } else {
+                        info!("No peers subscribed to {topic}, the content will be published when a peer subscribes");
+                        pending_clipboard = Some((topic.clone(), data, audit_item, confirm));
+                    }
+                }
+            }
+
+            // The clipboard read for /send-to, going to that peer only
+            Some((peer, content)) = send_to_rx.recv() => {
+                let label = peer_label(&device_names, &peer);
+                // It may have gone away while the clipboard was read
+                if !swarm.is_connected(&peer) {
+                    error!("Can't send to {label}: the peer disconnected");
+                    continue;
+                }
+                if content.size() as u64 > direct::MAX_MESSAGE_SIZE {
+                    error!("Can't send to {label}: {} bytes is more than a direct message may carry", content.size());
+                    continue;
+                }
+                if peer_capabilities.get(&peer).is_some_and(|theirs| !theirs.accepts(content.content_type)) {
+                    error!("Can't send to {label}: it does not accept {:?} content", content.content_type);
+                    continue;
+                }
+                if !understands(&peer_capabilities, &peer, direct::PUSH_VERSION) {
+                    error!("Can't send to {label}: it runs a version that can't take content sent to it alone");
+                    continue;
+                }
+                let Some(room) = sharing_room(&swarm, &rooms, &peer, None, bridge_rooms.is_some()) else {
+                    error!("Can't send to {label}: it is in none of the rooms we send to");
+                    continue;
+                };
+                info!("Sending clipboard content to {label}");
+                if let Some(ref mut audit_log) = audit_log {
+                    audit_log.sent(&content, &[peer], &room.name);
+                }
+                stats.lock().expect("stats lock poisoned").record_room(stats::Direction::Sent, &room.name);
+                let _ = event_tx.send(control::NodeEvent::sent(&content, 1));
+                let mut progress = progress::Progress::new(format!("{:?} clipboard to {label}", content.content_type), content.size() as u64, 0);
+                if let Some(event) = progress.advance(0) {
+                    let _ = event_tx.send(event);
+                }
+                let request_id = send_direct(&mut swarm, &stats, &peer, direct::DirectRequest::Clipboard(crypto::sealed(content, field_key.as_ref())));
+                sends_to.insert(request_id, progress);
+            }
+
+            // Retained content loaded for a peer
+            Some(loaded) = retained_rx.recv() => {
+                let LoadedRetained { peer, room, channel, content } = loaded;
+                let response = match content {
+                    Ok(content) => {
+                        if let Some(ref mut audit_log) = audit_log {
+                            audit_log.sent(&content, &[peer], &room);
+                        }
+                        send_direct(&mut swarm, &stats, &peer, direct::DirectRequest::Retained(crypto::sealed(content, field_key.as_ref())));
+                        direct::DirectResponse::Accepted
+                    }
+                    Err(e) => {
+                        error!("Failed to load retained clipboard content: {e:?}");
+                        direct::DirectResponse::Ignored
+                    }
+                };
+                if let Some(channel) = channel
+                    && swarm.behaviour_mut().direct.send_response(channel, response).is_err()
+                {
+                    debug!("Peer {peer} went away before the direct response was sent");
+                }
+            }
+
+            // File chunks written to downloads
+            Some(written) = written_rx.recv() => {
+                let finished = downloads.on_written(written, |peer, request| swarm.behaviour_mut().files.send_request(peer, request));
+                for peer in downloads.take_corrupt() {
+                    peer_backoff.record(peer, peer_backoff::Failure::HashMismatch, Instant::now());
+                }
+                if let Some(paths) = finished
+                    && args.files_to_clipboard
+                    && !paused
+                {
+                    let clipboard = clipboard_sync.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = clipboard.set_files(paths).await {
+                            error!("Failed to put downloaded files on the clipboard: {e:?}");
+                        }
+                    });
+                }
+            }
+
+            // File chunks read for a peer
+            Some((peer, channel, request, response)) = chunk_rx.recv() => {
+                stats.lock().expect("stats lock poisoned").bandwidth().record_sent(peer, response.size());
+                if let (files::ChunkResponse::Data(data), Some(offer)) = (&response, shared_files.get(&request.sha256)) {
+                    let to = peer_label(&device_names, &peer);
+                    if let Some(event) = uploads.sent(peer, &to, &offer, request.offset, data.len() as u64) {
+                        let _ = event_tx.send(event);
+                    }
+                }
+                if swarm.behaviour_mut().files.send_response(channel, response).is_err() {
+                    debug!("Peer {peer} went away before the file chunk was sent");
+                }
+            }
+
+            // Received clipboard messages, back from the decoder
+            Some(decoded) = decoded_rx.recv() => {
+                let incoming = match decoded {
+                    pipeline::Decoded::Content(incoming) => *incoming,
+                    pipeline::Decoded::Malformed { propagation_source, message_id, source } => {
+                        swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                            &message_id,
+                            &propagation_source,
+                            gossipsub::MessageAcceptance::Reject,
+                        );
+                        peer_backoff.record(source.unwrap_or(propagation_source), peer_backoff::Failure::Malformed, Instant::now());
+                        continue;
+                    }
+                    pipeline::Decoded::Dropped(received) => {
+                        ignore_undecoded(&mut swarm, *received);
+                        continue;
+                    }
+                };
+                let pipeline::Incoming { propagation_source: peer_id, via, topic, source, size, arrived_ms, mut content, missing_base, hash, spilled, image } = incoming;
+                if let Some(file) = spilled {
+                    payload_cache.adopt(&mut content, file);
+                }
+                let direct = via.is_direct();
+                // Direct requests are answered with how this turned out
+                let response = 'apply: {
+                    // Nothing is forwarded before the policy had its say
+                    let decision = policy.validate(&content, source.unwrap_or(peer_id));
+                    if let pipeline::Via::Gossip(ref message_id) = via {
+                        swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                            message_id,
+                            &peer_id,
+                            decision.acceptance(source.is_some_and(|source| source != peer_id)),
+                        );
+                    }
+                    match decision {
+                        policy::ValidationDecision::Accept => {}
+                        policy::ValidationDecision::Reject(reason) => {
+                            warn!("Rejected clipboard content from {}: {reason}", peer_label(&device_names, &peer_id));
+                            peer_backoff.record(source.unwrap_or(peer_id), peer_backoff::Failure::Rejected, Instant::now());
+                            let _ = event_tx.send(control::NodeEvent::dropped(peer_id, &content, hash, direct, control::DropReason::Rejected));
+                            break 'apply direct::DirectResponse::Ignored;
+                        }
+                    }
+                    let Some(room) = bridge::room_of(&rooms, &topic).filter(|room| room.direction.receives()) else {
+                        debug!("Not applying clipboard content from {}: the room is send only", peer_label(&device_names, &peer_id));
+                        let _ = event_tx.send(control::NodeEvent::dropped(peer_id, &content, hash, direct, control::DropReason::NotReceiving));
+                        break 'apply direct::DirectResponse::Ignored;
+                    };
+                    stats.lock().expect("stats lock poisoned")
+                        .record_size(stats::Direction::Received, &content.content_type, size);
+                    // Attribute latency to the original author, not the forwarding
+                    // peer. Direct content may be a catch-up, as old as it gets.
+                    let origin = source.unwrap_or(peer_id);
+                    if direct {
+                        subscription_check.record_sync(&topic);
+                    } else {
+                        let latency = stats.lock().expect("stats lock poisoned")
+                            .peer(origin)
+                            .record_delivery(content.timestamp, arrived_ms);
+                        if latency > args.latency_warn_ms {
+                            warn!("Clipboard content from {} took {latency} ms to arrive", peer_label(&device_names, &origin));
+                        }
+                    }
+                    let offset = stats.lock().expect("stats lock poisoned").peer(origin).clock_offset_ms();
+                    if offset.unsigned_abs() > CLOCK_SKEW_WARN_MS && skewed_peers.insert(origin) {
+                        warn!(
+                            "Clock of {origin} appears to be {}s {} ours; its timestamps are corrected by that offset",
+                            offset.unsigned_abs() / 1000,
+                            if offset > 0 { "behind" } else { "ahead of" }
+                        );
+                    }
+                    if !accepts_from(&args, &origin, content.timestamp.saturating_add_signed(offset), primary_copied_at) {
+                        debug!("Ignoring clipboard content from {origin}: it conflicts with the primary peer's latest copy");
+                        let _ = event_tx.send(control::NodeEvent::dropped(origin, &content, hash, direct, control::DropReason::NotPrimary));
+                        break 'apply direct::DirectResponse::Ignored;
+                    }
+                    if let Some(e) = missing_base {
+                        // Leave newest_timestamp alone so the full image is accepted
+                        warn!("Cannot rebuild image diff from {origin}, fetching the full image: {e}");
+                        let timestamp = content.timestamp;
+                        send_direct(&mut swarm, &stats, &origin, direct::DirectRequest::FullImage { timestamp });
+                        let _ = event_tx.send(control::NodeEvent::dropped(origin, &content, hash, direct, control::DropReason::MissingBase));
+                        break 'apply direct::DirectResponse::Ignored;
+                    }
+                    // Forwarded untouched, so two bridges between the same rooms
+                    // publish identical messages that gossipsub deduplicates
+                    if let Some(ref rooms) = bridge_rooms
+                        && !paused
+                        && let Some((room, target, forwarded)) = bridge::forward(rooms, &topic, &content)
+                    {
+                        forward_to_room(&mut swarm, &args, &stats, &timings, &mut audit_log, field_key.as_ref(), room, target, &forwarded);
+                    }
+                    if content.sealed {
+                        if field_key.is_some() {
+                            warn!("Clipboard content from {origin} is sealed under a different --field-encryption key, not applying it");
+                        } else {
+                            debug!("Not applying clipboard content from {origin}: its payload is sealed and we have no --field-encryption key");
+                        }
+                        let _ = event_tx.send(control::NodeEvent::dropped(origin, &content, hash, direct, control::DropReason::Sealed));
+                        break 'apply direct::DirectResponse::Ignored;
+                    }
+                    if !args.accept_formats.contains(&content.content_type) {
+                        debug!("Ignoring {:?} content from {origin}: not an accepted format", content.content_type);
+                        let _ = event_tx.send(control::NodeEvent::dropped(origin, &content, hash, direct, control::DropReason::Format));
+                        break 'apply direct::DirectResponse::Ignored;
+                    }
+                    if superseded.check(origin, &content) {
+                        debug!("Not applying {:?} content from {origin}: text copied after it arrived first", content.content_type);
+                        // On our clock, like everything else recorded about received content
+                        content.timestamp = content.timestamp.saturating_add_signed(offset);
+                        let _ = event_tx.send(control::NodeEvent::dropped(origin, &content, hash, direct, control::DropReason::Superseded));
+                        break 'apply direct::DirectResponse::Ignored;
+                    }
+                    // Everything kept from here on is compared against our clock
+                    content.timestamp = content.timestamp.saturating_add_signed(offset);
+                    // Gossipsub drops what it has seen before, direct requests don't.
+                    // The primary's content wins even over newer timestamps.
+                    if let pipeline::Via::Direct { live, .. } = via
+                        && ((content.timestamp <= newest_timestamp && args.primary_peer != Some(origin))
+                            || (!live && !is_fresh(&content, hardened::retained_max_age(&args))))
+                    {
+                        debug!("Ignoring direct content from {origin}: already seen or stale");
+                        let _ = event_tx.send(control::NodeEvent::dropped(origin, &content, hash, direct, control::DropReason::Stale));
+                        break 'apply direct::DirectResponse::Ignored;
+                    }
+                    if direct {
+                        info!("Received {:?} content from {} directly", content.content_type, peer_label(&device_names, &origin));
+                    }
+                    if !paused && !args.observer && !queues(&args, content.content_type) {
+                        report_conflict(&mut conflicts, &args, &event_tx, origin, &content);
+                    }
+                    if let Some(ref mut audit_log) = audit_log {
+                        audit_log.received(&content, origin, &room.name);
+                    }
+                    received_from = Some((room.name.clone(), hash));
+                    content.received_in = Some(room.name.clone());
+                    newest_timestamp = newest_timestamp.max(content.timestamp);
+                    // A base for the diffs that follow, now that it's accepted
+                    if let Some(image) = image {
+                        image_cache.lock().expect("image cache lock poisoned").add(image);
+                    }
+                    if args.primary_peer == Some(origin) {
+                        primary_copied_at = Some((origin, content.timestamp));
+                    }
+                    stats.lock().expect("stats lock poisoned").record_room(stats::Direction::Received, &room.name);
+                    match via {
+                        // Content pushed to us alone is never offered on to others
+                        pipeline::Via::Direct { pushed: true, .. } => {}
+                        pipeline::Via::Direct { .. } => {
+                            retained = Some(content.clone());
+                            retained_room = Some(room.name.clone());
+                            retained_holders = Some(Holders { origin, peers: HashSet::from([origin]) });
+                        }
+                        pipeline::Via::Gossip(_) => {
+                            retained = Some(content.clone());
+                            retained_room = Some(room.name.clone());
+                            // Whoever was subscribed when it was published and takes
+                            // this type got it too
+                            let mut peers: HashSet<PeerId> = subscriptions::topic_subscribers(&swarm.behaviour().gossipsub, &topic)
+                                .into_iter()
+                                .filter(|peer| peer_capabilities.get(peer).is_none_or(|theirs| theirs.accepts(content.content_type)))
+                                .collect();
+                            peers.insert(origin);
+                            retained_holders = Some(Holders { origin, peers });
+                        }
+                    }
+                    last_received = Some((peer_id, content.clone()));
+                    let _ = event_tx.send(control::NodeEvent::received(origin, &content, hash, direct));
+                    if args.observer {
+                        info!("Observed {:?} content from {origin} ({} bytes)", content.content_type, content.size());
+                        break 'apply direct::DirectResponse::Ignored;
+                    }
+                    if paused {
+                        info!("Clipboard sync is paused. Ignoring content from {peer_id}");
+                        break 'apply direct::DirectResponse::Ignored;
+                    }
+                    // Only the metadata came with the message, the files are pulled
+                    // from the node that copied them
+                    if let Some(offers) = content.files() {
+                        fetch_files(&mut swarm, &args, &stats, &mut downloads, &device_names, origin, offers);
+                        break 'apply direct::DirectResponse::Accepted;
+                    }
+                    if let Some(ref convert_tx) = convert_tx
+                        && content.content_type == clipboard::ContentType::Image
+                    {
+                        let _ = convert_tx.send((origin, content.clone()));
+                    }
+                    if let Some(ref image_export_tx) = image_export_tx
+                        && content.content_type == clipboard::ContentType::Image
+                    {
+                        if let Ok(Some((from, dropped))) = image_export_tx.send((origin, content.clone())) {
+                            warn!("Images arrive faster than they can be exported, not saving the one {from} copied at {}", dropped.timestamp);
+                        }
+                        if args.image_export_only {
+                            break 'apply direct::DirectResponse::Accepted;
+                        }
+                    }
+                    // So a copy made here can tell where it came from
+                    content.add_hop(device_names.get(&origin).cloned().unwrap_or_else(|| origin.to_string()), content.timestamp);
+                    // Handle clipboard content in a separate task
+                    let clipboard = clipboard_sync.clone();
+                    let event_tx = event_tx.clone();
+                    tokio::spawn(async move {
+                        let result = clipboard.receive_content(content).await;
+                        if let Err(ref e) = result {
+                            error!("Failed to handle incoming clipboard content: {:?}", e);
+                        }
+                        let error = result.err().map(|e| format!("{e:#}"));
+                        let _ = event_tx.send(control::NodeEvent::ClipboardApplied { from: origin, hash, error });
+                    });
+                    direct::DirectResponse::Accepted
+                };
+                if let pipeline::Via::Direct { channel, .. } = via {
+                    if swarm.behaviour_mut().direct.send_response(channel, response).is_err() {
+                        debug!("Peer {peer_id} went away before the direct response was sent");
+                    }
+                    // Answers to a `get` are in by now
+                    if pending_gets.iter().any(|get| get.waiting.contains_key(&peer_id)) {
+                        for get in &mut pending_gets {
+                            get.waiting.remove(&peer_id);
+                        }
+                        finish_gets(&mut pending_gets, &clipboard_sync, retained.as_ref());
+                    }
+                }
+            }
+
+            // Text found in received images
+            Some(derived) = async {
+                match derived_rx {
+                    Some(ref mut rx) => rx.recv().await,
+                    None => futures::future::pending().await,
+                }
+            } => {
+                let _ = event_tx.send(control::NodeEvent::Derived {
+                    from: derived.from,
+                    converter: derived.converter.label(),
+                    size: derived.text.len(),
+                    preview: clipboard::preview_text(&derived.text, clipboard::PREVIEW_CHARS),
+                });
+                last_derived.retain(|earlier| earlier.timestamp == derived.timestamp);
+                last_derived.push(derived);
+            }
+
+            // Handle swarm events
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    info!("Local node is listening on {address}");
+                    beacon_addrs.send_replace(announced_addrs(&swarm, &args));
+                },
+                SwarmEvent::ExpiredListenAddr { .. } => {
+                    beacon_addrs.send_replace(announced_addrs(&swarm, &args));
+                },
+                
+                // Identify events
+                SwarmEvent::Behaviour(AppBehaviourEvent::Identify(identify::Event::Sent { peer_id, .. })) => {
+                    info!("Sent identify info to {peer_id:?}")
+                }
+                SwarmEvent::Behaviour(AppBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. })) => {
+                    let _ = event_tx.send(control::NodeEvent::PeerIdentified {
+                        peer: peer_id,
+                        agent_version: info.agent_version.clone(),
+                        listen_addrs: info.listen_addrs.clone(),
+                    });
+                    match capabilities::Capabilities::from_agent_version(&info.agent_version) {
+                        Some(theirs) => {
+                            if let Some(ref name) = theirs.device_name
+                                && device_names.insert(peer_id, name.clone()).as_ref() != Some(name)
+                            {
+                                stats.lock().expect("stats lock poisoned").record_peer(peer_id, Some(name));
+                                info!("Peer {peer_id} is '{name}'");
+                            }
+                            if theirs.observer {
+                                info!("Peer {peer_id} is an observer and never publishes clipboard content");
+                            }
+                            let problems = capabilities.incompatibilities(&theirs);
+                            for problem in &problems {
+                                warn!("Peer {peer_id} is incompatible: {problem}");
+                            }
+                            // Only peers we can actually sync with count as group members
+                            if problems.is_empty() && theirs.clipboard {
+                                address_book.add_member(peer_id, info.listen_addrs.iter().cloned());
+                                address_book.set_device_name(&peer_id, theirs.device_name.clone());
+                                swarm.behaviour_mut().gate.update(&args, &address_book);
+                                address_book.save();
+                                known_peers.insert(peer_id, info.listen_addrs);
+                                // A newcomer learns about the rest of the group right away
+                                let records = known_peers.records_for(&peer_id);
+                                if !args.no_peer_exchange && !records.is_empty() {
+                                    send_direct(&mut swarm, &stats, &peer_id, direct::DirectRequest::PeerExchange(records));
+                                }
+                            }
+                            let ignored: Vec<&clipboard::ContentType> = clipboard::ContentType::value_variants()
+                                .iter()
+                                .filter(|format| !theirs.accepts(**format))
+                                .collect();
+                            let known = peer_capabilities.get(&peer_id).map(|known| &known.formats);
+                            if theirs.clipboard && !ignored.is_empty() && known != Some(&theirs.formats) {
+                                info!("Peer {} ignores {ignored:?} content", peer_label(&device_names, &peer_id));
+                            }
+                            let reads_chat = theirs.chat && !peer_capabilities.get(&peer_id).is_some_and(|known| known.chat);
+                            peer_capabilities.insert(peer_id, theirs);
+                            update_image_budget(&image_budget, &args, &peer_capabilities);
+                            // Presence held back until the peer said it reads the envelope
+                            if reads_chat {
+                                let state = if paused { chat::PresenceState::Paused } else { chat::PresenceState::Active };
+                                announce_presence(&mut swarm, &args, &stats, &peer_capabilities, &chat_topic, state);
+                            }
+                        }
+                        None => debug!("Peer {peer_id} does not advertise clipboard capabilities ({})", info.agent_version),
+                    }
+                },
+                
+                SwarmEvent::Behaviour(AppBehaviourEvent::RelayClient(relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal: false, .. })) => {
+                    info!("Relay {relay_peer_id} accepted our reservation");
+                },
+
+                // Ping events feed RTTs into the latency stats
+                SwarmEvent::Behaviour(AppBehaviourEvent::Ping(ping::Event { peer, result: Ok(rtt), .. })) => {
+                    stats.lock().expect("stats lock poisoned").peer(peer).record_rtt(rtt);
+                },
+
+                // mDNS events
+                SwarmEvent::Behaviour(AppBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                    for (peer_id, multiaddr) in list {
+                        if !discovery_allowed(&args, interfaces.as_ref(), "mDNS", &peer_id, &multiaddr) {
+                            continue;
+                        }
+                        info!("mDNS discovered a new peer: {peer_id} at {multiaddr}");
+                        // Dial the allowed address before gossipsub does: its dial
+                        // would also try every other address mDNS found for the peer
+                        if !swarm.is_connected(&peer_id) {
+                            let dial = DialOpts::peer_id(peer_id)
+                                .addresses(vec![multiaddr.clone()])
+                                .build();
+                            if let Err(e) = swarm.dial(dial) {
+                                debug!("Failed to dial mDNS peer {peer_id}: {e}");
+                            }
+                        }
+                        add_discovered(&mut swarm, &args, &mut explicit_peers, peer_id);
+                    }
+                },
+                SwarmEvent::Behaviour(AppBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
+                    for (peer_id, _multiaddr) in list {
+                        info!("mDNS peer has expired: {peer_id}");
+                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                        explicit_peers.remove(&peer_id);
+                    }
+                },
+                
+                // Gossipsub events
+                SwarmEvent::Behaviour(AppBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                    propagation_source: peer_id,
+                    message_id,
+                    message,
+                })) => {
+                    stats.lock().expect("stats lock poisoned").bandwidth().record_received(peer_id, message.data.len());
+                    subscription_check.record_sync(&message.topic);
+                    // Check which topic the message is from by comparing with our subscribed topics
+                    // For chat messages
+                    if message.topic == chat_topic.hash() {
+                        swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                            &message_id,
+                            &peer_id,
+                            gossipsub::MessageAcceptance::Accept,
+                        );
+                        let origin = message.source.unwrap_or(peer_id);
+                        match serde_json::from_slice::<chat::ChatMessage>(&message.data) {
+                            Ok(chat::ChatMessage::Text { msg_id, text, receipts: wanted }) => {
+                                info!("Received message from {}: {text}", peer_label(&device_names, &origin));
+                                // Receipts go back directly and are never acknowledged themselves
+                                if wanted && !args.no_receipts && !args.observer {
+                                    send_direct(&mut swarm, &stats, &origin, direct::DirectRequest::ChatReceipt { msg_id });
+                                }
+                            }
+                            Ok(chat::ChatMessage::Presence { state, .. }) => {
+                                if presence.update(origin, state) {
+                                    info!("Peer {} is {}", peer_label(&device_names, &origin), presence.describe(&origin));
+                                }
+                            }
+                            // Peers predating the chat envelope send plain text
+                            Err(_) => {
+                                if let Ok(text) = String::from_utf8(message.data) {
+                                    info!("Received message from {}: {}", peer_label(&device_names, &origin), text);
+                                }
+                            }
+                        }
+                    }
+                    // Receivers confirming our copies
+                    else if rooms.iter().any(|room| room.delivery.hash() == message.topic) {
+                        swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                            &message_id,
+                            &peer_id,
+                            gossipsub::MessageAcceptance::Accept,
+                        );
+                        let origin = message.source.unwrap_or(peer_id);
+                        match serde_json::from_slice::<delivery::Ack>(&message.data) {
+                            Ok(ack) => match confirmations.record(&ack.sha256, origin) {
+                                Some(preview) => {
+                                    let _ = event_tx.send(control::NodeEvent::DeliveryConfirmed { peer: origin, preview });
+                                }
+                                None => debug!("Ignoring delivery confirmation from {origin} for content we aren't waiting on"),
+                            },
+                            Err(e) => debug!("Ignoring malformed delivery confirmation from {origin}: {e}"),
+                        }
+                    }
+                    // For clipboard messages
+                    else if clipboard_topic.is_some() && rooms.iter().any(|room| room.has_topic(&message.topic)) {
+                        if peer_backoff.suppress(&message.source.unwrap_or(peer_id), Instant::now()) {
+                            swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                                &message_id,
+                                &peer_id,
+                                gossipsub::MessageAcceptance::Reject,
+                            );
+                        } else {
+                            // Decoding a large payload can take a while, so it
+                            // happens off the event loop and comes back decoded,
+                            // to be validated
+                            if let Ok(Some(dropped)) = decode_tx.send(pipeline::Received::Gossip(peer_id, message_id, message)) {
+                                ignore_undecoded(&mut swarm, dropped);
+                            }
+                        }
+                    } else {
+                        swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                            &message_id,
+                            &peer_id,
+                            gossipsub::MessageAcceptance::Ignore,
+                        );
+                    }
+                },
+                
+                SwarmEvent::Behaviour(AppBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
+                    info!("Peer {peer_id} subscribed to topic {topic}");
+                    // A newcomer learns who is around without waiting for the next heartbeat
+                    if topic == chat_topic.hash() {
+                        let state = if paused { chat::PresenceState::Paused } else { chat::PresenceState::Active };
+                        announce_presence(&mut swarm, &args, &stats, &peer_capabilities, &chat_topic, state);
+                    }
+                    // Flush clipboard content copied while nobody was listening
+                    if let Some((ref pending_topic, _, _, _)) = pending_clipboard
+                        && topic == pending_topic.hash()
+                        && let Some((pending_topic, data, audit_item, confirm)) = pending_clipboard.take()
+                    {
+                        let hash = pending_topic.hash();
+                        match publish(&mut swarm, &args, &stats, pending_topic, data) {
+                            Ok(_) => {
+                                info!("Pending clipboard content published to {peer_id}");
+                                subscription_check.record_sync(&hash);
+                                if let Some((sha256, preview)) = confirm {
+                                    confirmations.track(sha256, preview, &[peer_id]);
+                                }
+                                if let Some(room) = bridge::room_of(&rooms, &hash) {
+                                    stats.lock().expect("stats lock poisoned").record_room(stats::Direction::Sent, &room.name);
+                                    if let (Some(audit_log), Some(item)) = (&mut audit_log, audit_item) {
+                                        audit_log.sent_item(item, &[peer_id], &room.name);
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                stats.lock().expect("stats lock poisoned").record_publish_failure(&e.to_string());
+                                let _ = event_tx.send(control::NodeEvent::PublishFailed { topic: hash, reason: e.to_string() });
+                            }
+                        }
+                    } else if clipboard_topic.is_some()
+                        && let Some(room) = rooms.iter().find(|room| topic == room.clipboard.hash())
+                        && room.may_send(retained_room.as_deref(), bridge_rooms.is_some())
+                        && let Some(ref content) = retained
+                        && !paused
+                        && !args.observer
+                        && is_fresh(content, hardened::retained_max_age(&args))
+                        && !is_foreign_file_offer(content)
+                        && peer_capabilities.get(&peer_id).is_none_or(|theirs| theirs.accepts(content.content_type))
+                        && (content.content_type != clipboard::ContentType::Image || stats.lock().expect("stats lock poisoned").bandwidth().allows_bulk())
+                        && (!args.elect_retained_offer
+                            || elected_to_offer(
+                                &subscriptions::topic_subscribers(&swarm.behaviour().gossipsub, &topic),
+                                &local_peer_id,
+                                retained_holders.as_ref(),
+                                &peer_id,
+                            ))
+                    {
+                        // Gossipsub never redelivers what was published before the
+                        // peer subscribed, so hand it the latest item directly
+                        debug!("Offering retained clipboard content to {peer_id}");
+                        load_retained(content, peer_id, &room.name, None, &retained_tx);
+                    }
+                }
+
+                // Direct requests from single peers
+                SwarmEvent::Behaviour(AppBehaviourEvent::Direct(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Request { request, channel, .. },
+                    ..
+                })) => {
+                    stats.lock().expect("stats lock poisoned").bandwidth().record_received(peer, request.size());
+                    // Content pushed by a capped sender is live, not a catch-up,
+                    // so it is never too old, and neither is an answer to our `get`
+                    let answering_get = pending_gets.iter().any(|get| get.waiting.contains_key(&peer));
+                    let pushed = matches!(request, direct::DirectRequest::Clipboard(_));
+                    let live = pushed || (answering_get && matches!(request, direct::DirectRequest::Retained(_)));
+                    // Clipboard content is handled like content from the topic,
+                    // and answered once it was applied or dropped
+                    let request = match request {
+                        direct::DirectRequest::Retained(content) | direct::DirectRequest::Clipboard(content) => {
+                            let hash = pipeline::payload_hash(&content);
+                            let room = peer_rooms(&swarm, &rooms, &peer).into_iter().find(|room| room.direction.receives());
+                            let response = if peer_backoff.suppress(&peer, Instant::now()) {
+                                let _ = event_tx.send(control::NodeEvent::dropped(peer, &content, hash, true, control::DropReason::Suppressed));
+                                direct::DirectResponse::Ignored
+                            } else if clipboard_topic.is_none() {
+                                debug!("Ignoring clipboard content from {peer}: clipboard sync is not enabled");
+                                direct::DirectResponse::Ignored
+                            } else if let Some(room) = room {
+                                // On the topic it would have been published on
+                                let topic = if content.content_type == clipboard::ContentType::Image || content.size() > FAST_PATH_MAX_SIZE {
+                                    room.bulk.hash()
+                                } else {
+                                    room.clipboard.hash()
+                                };
+                                let via = pipeline::Via::Direct { channel, pushed, live };
+                                if let Ok(Some(dropped)) = decode_tx.send(pipeline::Received::Direct { peer, topic, content, via }) {
+                                    ignore_undecoded(&mut swarm, dropped);
+                                }
+                                continue;
+                            } else {
+                                debug!("Ignoring clipboard content from {peer}: not in a room we receive from");
+                                let _ = event_tx.send(control::NodeEvent::dropped(peer, &content, hash, true, control::DropReason::NotReceiving));
+                                direct::DirectResponse::Ignored
+                            };
+                            if swarm.behaviour_mut().direct.send_response(channel, response).is_err() {
+                                debug!("Peer {peer} went away before the direct response was sent");
+                            }
+                            continue;
+                        }
+                        request => request,
+                    };
+                    let response = match request {
+                        direct::DirectRequest::Retained(_) | direct::DirectRequest::Clipboard(_) => unreachable!("handled above"),
+                        direct::DirectRequest::FullImage { timestamp } => match (
+                            &retained,
+                            sharing_room(&swarm, &rooms, &peer, retained_room.as_deref(), bridge_rooms.is_some()),
+                        ) {
+                            (Some(content), Some(room))
+                                if content.timestamp == timestamp
+                                    && content.content_type == clipboard::ContentType::Image
+                                    && !args.observer
+                                    && stats.lock().expect("stats lock poisoned").bandwidth().allows_bulk() =>
+                            {
+                                debug!("Sending full image to {peer}, which could not rebuild our diff");
+                                load_retained(content, peer, &room.name, Some(channel), &retained_tx);
+                                continue;
+                            }
+                            _ => direct::DirectResponse::Ignored,
+                        },
+                        direct::DirectRequest::QueryLatest => match (
+                            &retained,
+                            sharing_room(&swarm, &rooms, &peer, retained_room.as_deref(), bridge_rooms.is_some()),
+                        ) {
+                            (Some(content), Some(room))
+                                if !paused
+                                    && !args.observer
+                                    && !is_foreign_file_offer(content)
+                                    && peer_capabilities.get(&peer).is_none_or(|theirs| theirs.accepts(content.content_type)) =>
+                            {
+                                debug!("Sending our latest clipboard content to {peer}, which asked for it");
+                                load_retained(content, peer, &room.name, Some(channel), &retained_tx);
+                                continue;
+                            }
+                            _ => direct::DirectResponse::Ignored,
+                        },
+                        direct::DirectRequest::TooLarge { content_type, size } => {
+                            let _ = event_tx.send(control::NodeEvent::TooLarge { from: peer, content_type, size });
+                            direct::DirectResponse::Accepted
+                        }
+                        direct::DirectRequest::ChatReceipt { msg_id } => {
+                            if receipts.record(msg_id, peer) {
+                                direct::DirectResponse::Accepted
+                            } else {
+                                direct::DirectResponse::Ignored
+                            }
+                        }
+                        direct::DirectRequest::PeerExchange(records) => {
+                            if args.no_peer_exchange {
+                                direct::DirectResponse::Ignored
+                            } else {
+                                for (member, addrs) in peer_exchange::parse_records(records) {
+                                    if member == local_peer_id || swarm.is_connected(&member) {
+                                        continue;
+                                    }
+                                    info!("Dialing group member {member} shared by {peer}");
+                                    let opts = DialOpts::peer_id(member).addresses(addrs).build();
+                                    if let Err(e) = swarm.dial(opts) {
+                                        debug!("Failed to dial shared peer {member}: {e}");
+                                    }
+                                }
+                                direct::DirectResponse::Accepted
+                            }
+                        }
+                    };
+                    if swarm.behaviour_mut().direct.send_response(channel, response).is_err() {
+                        debug!("Peer {peer} went away before the direct response was sent");
+                    }
+                    if answering_get {
+                        for get in &mut pending_gets {
+                            get.waiting.remove(&peer);
+                        }
+                        finish_gets(&mut pending_gets, &clipboard_sync, retained.as_ref());
+                    }
+                }
+                SwarmEvent::Behaviour(AppBehaviourEvent::Direct(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Response { request_id, response },
+                    ..
+                })) => {
+                    debug!("Peer {peer} answered direct request: {response:?}");
+                    if let Some(mut progress) = sends_to.remove(&request_id) {
+                        if let Some(event) = progress.complete() {
+                            let _ = event_tx.send(event);
+                        }
+                        match response {
+                            direct::DirectResponse::Accepted => info!("{} applied the clipboard content", peer_label(&device_names, &peer)),
+                            direct::DirectResponse::Ignored => {
+                                warn!("{} received the clipboard content but did not apply it", peer_label(&device_names, &peer))
+                            }
+                        }
+                    }
+                    // Nothing to offer; an answer with content comes as a
+                    // `Retained` request instead
+                    if response == direct::DirectResponse::Ignored {
+                        give_up_on_request(&mut pending_gets, request_id);
+                        finish_gets(&mut pending_gets, &clipboard_sync, retained.as_ref());
+                    }
+                }
+                SwarmEvent::Behaviour(AppBehaviourEvent::Direct(request_response::Event::OutboundFailure { peer, request_id, error, .. })) => {
+                    if let Some(progress) = sends_to.remove(&request_id) {
+                        if let Some(event) = progress.finish(Some(error.to_string())) {
+                            let _ = event_tx.send(event);
+                        }
+                        error!("Failed to send clipboard content to {}, the peer is unreachable: {error}", peer_label(&device_names, &peer));
+                    } else {
+                        warn!("Direct request to {peer} failed: {error}");
+                    }
+                    give_up_on_request(&mut pending_gets, request_id);
+                    finish_gets(&mut pending_gets, &clipboard_sync, retained.as_ref());
+                }
+
+                // File chunks peers pull from us. Only files copied here are
+                // served, and reading them happens off the event loop.
+                SwarmEvent::Behaviour(AppBehaviourEvent::Files(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Request { request, channel, .. },
+                    ..
+                })) => {
+                    let response = match shared_files.get(&request.sha256) {
+                        None => files::ChunkResponse::Unavailable,
+                        Some(_) if !stats.lock().expect("stats lock poisoned").bandwidth().allows_bulk() => {
+                            files::ChunkResponse::Refused("bandwidth cap reached".to_string())
+                        }
+                        Some(offer) => {
+                            let chunk_tx = chunk_tx.clone();
+                            tokio::task::spawn_blocking(move || {
+                                let response = files::read_chunk(&offer, &request);
+                                let _ = chunk_tx.send((peer, channel, request, response));
+                            });
+                            continue;
+                        }
+                    };
+                    if swarm.behaviour_mut().files.send_response(channel, response).is_err() {
+                        debug!("Peer {peer} went away before the file response was sent");
+                    }
+                }
+                // File chunks we pulled
+                SwarmEvent::Behaviour(AppBehaviourEvent::Files(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Response { request_id, response },
+                    ..
+                })) => {
+                    stats.lock().expect("stats lock poisoned").bandwidth().record_received(peer, response.size());
+                    // Writing happens off the event loop
+                    if let Some(chunk) = downloads.on_response(request_id, response) {
+                        let written_tx = written_tx.clone();
+                        tokio::task::spawn_blocking(move || {
+                            let _ = written_tx.send(chunk.write());
+                        });
+                    }
+                }
+                SwarmEvent::Behaviour(AppBehaviourEvent::Files(request_response::Event::OutboundFailure { request_id, error, .. })) => {
+                    downloads.on_failure(request_id, &error);
+                }
+                
+                // Connection events
+                SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                    let _ = event_tx.send(control::NodeEvent::PeerConnected { peer: peer_id, endpoint: endpoint.clone() });
+                    peer_backoff.on_connected(&peer_id, Instant::now());
+                    stats.lock().expect("stats lock poisoned").record_peer(peer_id, None);
+                    downloads.set_transport(peer_id, files::Transport::of(endpoint.get_remote_address()));
+                    let connected: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                    status_tx.send_modify(|status| {
+                        status.peers = connected.len();
+                        status.connected = connected;
+                    });
+                    // Add peer to gossipsub when connection is established. Readonly
+                    // nodes skip this since explicit peers get every message forwarded,
+                    // and peers connecting in from outside `--allow-subnet` are left out
+                    let remote = endpoint.get_remote_address();
+                    let allowed = endpoint.is_dialer() || *remote == stdio::address() || inbound_allowed(&args, remote);
+                    if !args.readonly_topics && allowed {
+                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                        explicit_peers.insert(peer_id);
+                    }
+                    if endpoint.is_dialer() && *endpoint.get_remote_address() != stdio::address() {
+                        address_book.record_working_addr(&peer_id, endpoint.get_remote_address().clone());
+                    }
+                    if autodial.finished(&peer_id) {
+                        dial_known_peers(&mut swarm, &mut autodial);
+                    }
+                    downloads.resume(Some(&peer_id), |peer, request| {
+                        swarm.behaviour_mut().files.send_request(peer, request)
+                    });
+                },
+                SwarmEvent::IncomingConnectionError { error: ListenError::Denied { cause }, .. } => {
+                    if let Some(hardened::Untrusted(peer_id)) = cause.downcast_ref() {
+                        info!("Hardened mode: refused inbound connection from unknown peer {peer_id}");
+                    }
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. } => {
+                    debug!("Failed to connect to {peer_id}: {error}");
+                    // With no connection up the failure is as likely ours, e.g.
+                    // no network yet, so it only counts against the peer while
+                    // some other connection is established
+                    let online = swarm.connected_peers().next().is_some();
+                    if online && !swarm.is_connected(&peer_id) && address_book.record_dial_failure(&peer_id) {
+                        swarm.behaviour_mut().gate.update(&args, &address_book);
+                        address_book.save();
+                    }
+                    if autodial.finished(&peer_id) {
+                        dial_known_peers(&mut swarm, &mut autodial);
+                    }
+                },
+                SwarmEvent::ConnectionClosed { peer_id, endpoint, cause, .. } => {
+                    let _ = event_tx.send(control::NodeEvent::PeerDisconnected {
+                        peer: peer_id,
+                        endpoint: endpoint.clone(),
+                        cause: cause.map(|e| e.to_string()),
+                    });
+                    // The tunnel can't be reopened from this end
+                    if *endpoint.get_remote_address() == stdio::address() {
+                        info!("The stdio tunnel closed");
+                        let _ = command_tx.send(control::NodeCommand::Quit);
+                    }
+                    let connected: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                    status_tx.send_modify(|status| {
+                        status.peers = connected.len();
+                        status.connected = connected;
+                    });
+                    // Remove peer from gossipsub when connection is closed
+                    swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                    explicit_peers.remove(&peer_id);
+                    if !swarm.is_connected(&peer_id) {
+                        known_peers.remove(&peer_id);
+                        peer_capabilities.remove(&peer_id);
+                        update_image_budget(&image_budget, &args, &peer_capabilities);
+                        device_names.remove(&peer_id);
+                        presence.remove(&peer_id);
+                        if address_book.touch(&peer_id) {
+                            address_book.save();
+                        }
+                    }
+                },
+                
+                _ => {}
+            }
+        }
+    }
+
+    info!("Shutting down");
+    if let Some(reporter) = reporter {
+        reporter.finish(report::Exit::Shutdown);
+    }
+    if let Some(monitor) = monitor {
+        monitor.abort();
+    }
+    // The memory transport only frees a port when its listener is removed,
+    // so a node restarted in this process can listen on it again
+    if let Some(listener) = memory_listener {
+        swarm.remove_listener(listener);
+    }
+    // Give the offline announcement a moment to go out
+    let _ = tokio::time::timeout(SHUTDOWN_FLUSH, async {
+        loop {
+            swarm.select_next_some().await;
+        }
+    })
+    .await;
+    payload_cache.shutdown();
+    address_book.flush().await;
+    // Dropping the status sender tells the dashboard to restore the terminal
+    #[cfg(feature = "tui")]
+    if let Some(thread) = tui_thread {
+        drop(status_tx);
+        let _ = thread.join();
+    }
+    Ok(())
+}
+
+/// Tell the encoder the largest image every connected peer takes. With
+/// `--strict-size` images are sent as copied.
+fn update_image_budget(budget: &AtomicUsize, args: &Args, peer_capabilities: &HashMap<PeerId, capabilities::Capabilities>) {
+    let payload = if args.strict_size {
+        0
+    } else {
+        peer_capabilities
+            .values()
+            .filter(|theirs| theirs.accepts(clipboard::ContentType::Image))
+            .filter_map(|theirs| theirs.max_image_bytes)
+            .min()
+            .map_or(usize::MAX, |max| usize::try_from(max).unwrap_or(usize::MAX))
+    };
+    budget.store(payload, Ordering::Relaxed);
+}
+
+/// Peer scoring that only counts clipboard messages the policy rejected from
+/// their authors. Everything else gossipsub can score, like mesh delivery
+/// rates or many peers behind one IP, is normal in a small LAN group and
+/// left out.
+fn peer_scoring(args: &Args) -> (gossipsub::PeerScoreParams, gossipsub::PeerScoreThresholds) {
+    let rejected_only = gossipsub::TopicScoreParams {
+        topic_weight: 1.0,
+        time_in_mesh_weight: 0.0,
+        first_message_deliveries_weight: 0.0,
+        mesh_message_deliveries_weight: 0.0,
+        mesh_failure_penalty_weight: 0.0,
+        // Squared: three rejected messages (-9) stop gossip with the author,
+        // nine (-81) graylist it, and the count decays by a tenth per second
+        invalid_message_deliveries_weight: -1.0,
+        invalid_message_deliveries_decay: 0.9,
+        ..Default::default()
+    };
+    let topics = joined_rooms(args)
+        .into_iter()
+        .flat_map(|room| [room.clipboard.hash(), room.bulk.hash()])
+        .map(|topic| (topic, rejected_only.clone()))
+        .collect();
+    let params = gossipsub::PeerScoreParams {
+        topics,
+        ip_colocation_factor_weight: 0.0,
+        behaviour_penalty_weight: 0.0,
+        slow_peer_weight: 0.0,
+        ..Default::default()
+    };
+    // Between two rejections (-4) and three, and eight (-64) and nine
+    let thresholds = gossipsub::PeerScoreThresholds {
+        gossip_threshold: -5.0,
+        graylist_threshold: -80.0,
+        ..Default::default()
+    };
+    (params, thresholds)
+}
+
+fn create_swarm(local_key: identity::Keypair, args: &Args) -> Result<Swarm<AppBehaviour>> {
+    let behaviour = app_behaviour(&local_key, args, !args.hardened)?;
+    // Dials /p2p-circuit addresses, and holds the --relay reservations
+    build_swarm(local_key, args, |relay_client| AppBehaviour { relay_client: Toggle::from(Some(relay_client)), ..behaviour })
+}
+
+/// A swarm that only speaks the in-memory transport, for nodes started in
+/// this process. mDNS stays off so they never find anything on the LAN.
+/// Muxers are negotiated as over TCP, compressed one first.
+fn create_memory_swarm(local_key: identity::Keypair, args: &Args) -> Result<Swarm<AppBehaviour>> {
+    let behaviour = app_behaviour(&local_key, args, false)?;
+    let muxer = upgrade::SelectUpgrade::new(compression::DeflateYamux::new(args.transport_compression), yamux::Config::default());
+    let builder = SwarmBuilder::with_existing_identity(local_key).with_tokio();
+    macro_rules! build {
+        ($security:expr) => {
+            builder
+                .with_other_transport(|key| -> Result<_, Box<dyn Error + Send + Sync>> {
+                    Ok(MemoryTransport::default()
+                        .upgrade(upgrade::Version::V1)
+                        .authenticate($security(key)?)
+                        .multiplex(muxer))
+                })?
+                .with_behaviour(|_| behaviour)?
+                .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
+                .build()
+        };
+    }
+    let swarm = match args.security {
+        security::Security::Noise => build!(security::noise),
+        security::Security::Tls => build!(security::tls),
+        security::Security::Both => build!(security::both),
+    };
+    Ok(swarm)
+}
+
+fn app_behaviour(local_key: &identity::Keypair, args: &Args, mdns: bool) -> Result<AppBehaviour> {
+    let local_peer_id = PeerId::from(local_key.public());
+    debug!("Creating swarm for local peer id: {local_peer_id}");
+
+    // Configure Gossipsub
+    let mut gossipsub = gossipsub::Behaviour::new(
+        gossipsub::MessageAuthenticity::Signed(local_key.clone()),
+        gossipsub_config(args)?,
+    ).map_err(|e| anyhow::anyhow!("Failed to create gossipsub behaviour: {:?}", e))?;
+    let (score_params, score_thresholds) = peer_scoring(args);
+    gossipsub.with_peer_score(score_params, score_thresholds)
+        .map_err(|e| anyhow::anyhow!("Failed to enable gossipsub peer scoring: {e}"))?;
+
+    // Configure Identify
+    // The agent version advertises our clipboard capabilities to peers
+    let identify = identify::Behaviour::new(
+        identify::Config::new("/ipfs/0.1.0".into(), local_key.public())
+            .with_agent_version(local_capabilities(args).agent_version())
+    );
+
+    // Configure mDNS
+    let mdns = mdns
+        .then(|| mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id))
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Failed to create mdns behaviour: {:?}", e))?;
+
+    // Ping peers periodically to measure round-trip times
+    let ping = ping::Behaviour::new(ping::Config::new());
+
+    // Create the behaviour
+    let behaviour = AppBehaviour {
+        gate: hardened::Gate::default(),
+        gossipsub,
+        identify,
+        mdns: Toggle::from(mdns),
+        ping,
+        direct: direct::behaviour(),
+        files: files::behaviour(),
+        // Only swarms with a relay transport have one, see `create_swarm`
+        relay_client: Toggle::from(None),
+    };
+    Ok(behaviour)
+}
+
+/// Gossipsub settings for the topics `args` joins
+fn gossipsub_config(args: &Args) -> Result<gossipsub::Config> {
+    // The topic is part of the id, so a copy sent to several rooms is a
+    // separate message in each rather than a duplicate
+    let message_id_fn = |message: &gossipsub::Message| {
+        let mut s = DefaultHasher::new();
+        message.topic.hash(&mut s);
+        message.data.hash(&mut s);
+        gossipsub::MessageId::from(s.finish().to_string())
+    };
+
+    // Increase the max transmit size to support image transfers (10MB)
+    // Flood publishing sends to every subscribed peer (explicit peers
+    // included), not only to those in the mesh
+    let mut gossipsub_builder = gossipsub::ConfigBuilder::default();
+    gossipsub_builder
+        .heartbeat_interval(Duration::from_secs(10))
+        .flood_publish(!args.no_flood_publish)
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        // Messages are only forwarded once the event loop reports them valid
+        .validate_messages()
+        .message_id_fn(message_id_fn)
+        .max_transmit_size(MAX_TRANSMIT_SIZE);
+
+    // A zero mesh degree means we never graft and prune every incoming graft,
+    // so we only receive via flood publishing and from peers that list us as explicit
+    if args.readonly_topics {
+        info!("Readonly topics: staying out of the gossipsub mesh");
+        gossipsub_builder
+            .mesh_n(0)
+            .mesh_n_low(0)
+            .mesh_n_high(0)
+            .mesh_outbound_min(0);
+    }
+
+    // The fast path only carries small messages. The bulk topic keeps a
+    // smaller mesh so large payloads are forwarded fewer times.
+    for room in joined_rooms(args) {
+        gossipsub_builder.max_transmit_size_for_topic(FAST_PATH_MAX_SIZE, room.clipboard.hash());
+        if !args.readonly_topics {
+            let bulk = room.bulk.hash();
+            gossipsub_builder
+                .mesh_n_for_topic(3, bulk.clone())
+                .mesh_n_low_for_topic(2, bulk.clone())
+                .mesh_n_high_for_topic(4, bulk.clone())
+                .mesh_outbound_min_for_topic(1, bulk);
+        }
+    }
+
+    gossipsub_builder
+        .build()
+        .map_err(|e| anyhow::anyhow!("Failed to build gossipsub config: {:?}", e))
+}
+
+/// Build a swarm around the behaviour `behaviour` makes out of the relay
+/// client, with the transport configured by `args` and relayed connections.
+/// The stdio link, if any, always uses Noise and plain yamux: both ends run
+/// this app, and the tunnel under it usually compresses already.
+///
+/// The compressed muxer is negotiated ahead of plain yamux and advertises
+/// nothing unless transport compression is enabled. Each security choice
+/// yields a differently typed transport, hence the macro.
+fn build_swarm<B: NetworkBehaviour>(
+    local_key: identity::Keypair,
+    args: &Args,
+    behaviour: impl FnOnce(relay::client::Behaviour) -> B,
+) -> Result<Swarm<B>> {
+    let transport_compression = args.transport_compression;
+    let stdio_link = args.stdio_transport.map(|role| (role, stdio::Pipe::stdio()));
+    let builder = SwarmBuilder::with_existing_identity(local_key).with_tokio();
+    // The stdio transport is upgraded outside `with_tcp`, so it takes a
+    // single upgrade rather than a tuple
+    macro_rules! build {
+        ($security:expr, $stdio_security:expr) => {
+            builder
+                .with_tcp(
+                    tcp::Config::default(),
+                    $security,
+                    (move || compression::DeflateYamux::new(transport_compression), yamux::Config::default)
+                )?
+                .with_other_transport(|key| -> Result<_, Box<dyn Error + Send + Sync>> {
+                    Ok(stdio::Transport::new(stdio_link)
+                        .upgrade(upgrade::Version::V1)
+                        .authenticate($stdio_security(key)?)
+                        .multiplex(yamux::Config::default()))
+                })?
+                .with_relay_client($security, yamux::Config::default)?
+                .with_behaviour(|_, relay_client| behaviour(relay_client))?
+                .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
+                .build()
+        };
+    }
+    let swarm = match args.security {
+        security::Security::Noise => build!(security::noise, security::noise),
+        security::Security::Tls => build!(security::tls, security::tls),
+        security::Security::Both => build!((security::tls, security::noise), security::both),
+    };
+
+    Ok(swarm)
+}
+
+/// Start listening on the configured TCP address, moving to an OS-assigned
+/// port if requested and the configured one is taken
+fn listen<B: NetworkBehaviour>(swarm: &mut Swarm<B>, args: &Args) -> Result<()> {
+    listen_on(swarm, args, args.listen_address).map(|_| ())
+}
+
+/// Listen on `address` at the configured port, falling back to an
+/// OS-assigned port if allowed
+fn listen_on<B: NetworkBehaviour>(swarm: &mut Swarm<B>, args: &Args, address: IpAddr) -> Result<ListenerId> {
+    let tcp_address = Multiaddr::from(address)
+        .with(Protocol::Tcp(args.port));
+
+    match swarm.listen_on(tcp_address.clone()) {
+        Ok(id) => {
+            info!("Listening on TCP: {}", tcp_address);
+            Ok(id)
+        }
+        Err(_) if args.port_fallback && args.port != 0 && port_in_use(address, args.port) => {
+            let fallback = Multiaddr::from(address).with(Protocol::Tcp(0));
+            let id = swarm.listen_on(fallback.clone())
+                .map_err(|e| anyhow::anyhow!("Failed to listen on TCP address: {:?}", e))?;
+            warn!("Port {} is already in use, falling back to an OS-assigned port", args.port);
+            info!("Listening on TCP: {}", fallback);
+            Ok(id)
+        }
+        Err(e) => anyhow::bail!("Failed to listen on TCP address {}: {:?}", tcp_address, e),
+    }
+}
+
+/// Publish `data` on `topic`. Every publish goes through here so observer
+/// nodes are guaranteed never to send anything.
+fn publish(
+    swarm: &mut Swarm<AppBehaviour>,
+    args: &Args,
+    stats: &stats::SharedStats,
+    topic: impl Into<gossipsub::TopicHash>,
+    data: impl Into<Vec<u8>>,
+) -> Result<gossipsub::MessageId> {
+    if args.observer {
+        anyhow::bail!("observer nodes never publish");
+    }
+    let (topic, data) = (topic.into(), data.into());
+    let size = data.len();
+    // Kept as a PublishError so callers can tell full queues apart
+    let id = swarm.behaviour_mut().gossipsub.publish(topic.clone(), data).map_err(anyhow::Error::new)?;
+    // Flood publishing hands the message to every subscriber
+    let mut stats = stats.lock().expect("stats lock poisoned");
+    for peer in subscriptions::topic_subscribers(&swarm.behaviour().gossipsub, &topic) {
+        stats.bandwidth().record_sent(peer, size);
+    }
+    Ok(id)
+}
+
+/// Whether `peer` advertised a direct protocol version of at least `version`.
+/// Peers not identified yet only get the requests every version decodes.
+fn understands(peer_capabilities: &HashMap<PeerId, capabilities::Capabilities>, peer: &PeerId, version: u32) -> bool {
+    version == 0 || peer_capabilities.get(peer).is_some_and(|theirs| theirs.direct >= version)
+}
+
+/// The topic reaches every subscriber, so a copy capped at `cap` peers goes
+/// out as direct requests to the closest few of them instead. `None` when it
+/// isn't capped, or none of the subscribers can take content sent to them
+/// alone, and it is published to all.
+fn capped_targets(
+    cap: usize,
+    subscribers: &[PeerId],
+    peer_capabilities: &HashMap<PeerId, capabilities::Capabilities>,
+    content_type: clipboard::ContentType,
+    stats: &stats::Stats,
+) -> Option<Vec<PeerId>> {
+    if cap == 0 || subscribers.len() <= cap {
+        return None;
+    }
+    let wanted: Vec<PeerId> = subscribers
+        .iter()
+        .filter(|peer| peer_capabilities.get(peer).is_none_or(|theirs| theirs.accepts(content_type)))
+        .filter(|peer| understands(peer_capabilities, peer, direct::PUSH_VERSION))
+        .copied()
+        .collect();
+    if wanted.is_empty() {
+        warn!("None of the {} peers can take content sent to them alone, publishing it to all of them", subscribers.len());
+        return None;
+    }
+    let targets = stats.closest_peers(&wanted, cap);
+    info!("Sending clipboard content to the {} of {} peers with the lowest round-trip time", targets.len(), subscribers.len());
+    Some(targets)
+}
+
+/// Let go of received content the decoder fell too far behind to get to:
+/// ignored on gossipsub, and answered as ignored when sent directly
+fn ignore_undecoded(swarm: &mut Swarm<AppBehaviour>, received: pipeline::Received) {
+    match received {
+        pipeline::Received::Gossip(peer, message_id, _) => {
+            warn!("Dropped clipboard content from {peer} undecoded: newer content arrived faster than it could be decoded");
+            swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                &message_id,
+                &peer,
+                gossipsub::MessageAcceptance::Ignore,
+            );
+        }
+        pipeline::Received::Direct { peer, via, .. } => {
+            warn!("Dropped clipboard content {peer} sent directly undecoded: newer content arrived faster than it could be decoded");
+            if let pipeline::Via::Direct { channel, .. } = via
+                && swarm.behaviour_mut().direct.send_response(channel, direct::DirectResponse::Ignored).is_err()
+            {
+                debug!("Peer {peer} went away before the direct response was sent");
+            }
+        }
+    }
+}
+
+/// Send a direct request, counting it towards the traffic with `peer`
+fn send_direct(
+    swarm: &mut Swarm<AppBehaviour>,
+    stats: &stats::SharedStats,
+    peer: &PeerId,
+    request: direct::DirectRequest,
+) -> request_response::OutboundRequestId {
+    stats.lock().expect("stats lock poisoned").bandwidth().record_sent(*peer, request.size());
+    swarm.behaviour_mut().direct.send_request(peer, request)
+}
+
+/// A local copy sent to peers as direct requests instead of published
+struct DirectSend<'a> {
+    content: &'a clipboard::ClipboardContent,
+    /// Room it is sent to
+    room: &'a str,
+    audit_item: Option<audit::Item>,
+    /// Hash and preview to track delivery confirmations by
+    confirm: Option<(String, String)>,
+}
+
+/// Send a local copy to each of `targets` as a direct request, recorded as a
+/// publish is: the sent event, the room's stats, the audit log and the
+/// confirmations to wait for
+#[allow(clippy::too_many_arguments)]
+fn send_directly(
+    swarm: &mut Swarm<AppBehaviour>,
+    stats: &stats::SharedStats,
+    event_tx: &broadcast::Sender<control::NodeEvent>,
+    audit_log: &mut Option<audit::AuditLog>,
+    confirmations: &mut delivery::Confirmations,
+    field_key: Option<&crypto::FieldKey>,
+    targets: &[PeerId],
+    sent: DirectSend,
+) {
+    let DirectSend { content, room, audit_item, confirm } = sent;
+    for peer in targets {
+        send_direct(swarm, stats, peer, direct::DirectRequest::Clipboard(crypto::sealed(content.clone(), field_key)));
+    }
+    let _ = event_tx.send(control::NodeEvent::sent(content, targets.len()));
+    stats.lock().expect("stats lock poisoned").record_room(stats::Direction::Sent, room);
+    if let (Some(audit_log), Some(item)) = (audit_log, audit_item) {
+        audit_log.sent_item(item, targets, room);
+    }
+    if let Some((sha256, preview)) = confirm {
+        confirmations.track(sha256, preview, targets);
+    }
+}
+
+/// Retained content loaded back from a possible spill file for `peer`
+struct LoadedRetained {
+    peer: PeerId,
+    /// Room it is sent to, for the audit log
+    room: String,
+    /// The request to answer once it is on its way, if it was asked for
+    channel: Option<request_response::ResponseChannel<direct::DirectResponse>>,
+    content: Result<clipboard::ClipboardContent>,
+}
+
+/// Load `content` for `peer` off the event loop, since a spilled payload may
+/// be large, and hand it back to the loop through `loaded` to be sent
+fn load_retained(
+    content: &clipboard::ClipboardContent,
+    peer: PeerId,
+    room: &str,
+    channel: Option<request_response::ResponseChannel<direct::DirectResponse>>,
+    loaded: &mpsc::UnboundedSender<LoadedRetained>,
+) {
+    let (content, room, loaded) = (content.clone(), room.to_string(), loaded.clone());
+    tokio::task::spawn_blocking(move || {
+        let _ = loaded.send(LoadedRetained { peer, room, channel, content: content.unspill() });
+    });
+}
+
+/// Answer a `get` with `latest`, applying it first if asked to
+fn answer_get(
+    clipboard: &clipboard::ClipboardSync,
+    latest: Option<&clipboard::ClipboardContent>,
+    apply: bool,
+    reply: oneshot::Sender<control_socket::ControlResponse>,
+) {
+    use control_socket::ControlResponse;
+
+    let content = match latest {
+        None => Err("No clipboard content is known in the group".to_string()),
+        Some(content) if content.files().is_some() => {
+            Err("The latest clipboard content is a file offer, which get cannot return".to_string())
+        }
+        Some(content) => Ok(content.clone()),
+    };
+    let content = match content {
+        Ok(content) => content,
+        Err(message) => {
+            let _ = reply.send(ControlResponse::Error(message));
+            return;
+        }
+    };
+    let clipboard = clipboard.clone();
+    tokio::spawn(async move {
+        // A spilled payload may be large, so it is read back off the event loop
+        let content = match tokio::task::spawn_blocking(move || content.unspill()).await {
+            Ok(Ok(content)) => content,
+            Ok(Err(e)) => {
+                let _ = reply.send(ControlResponse::Error(format!("{e:#}")));
+                return;
+            }
+            Err(e) => {
+                let _ = reply.send(ControlResponse::Error(format!("Loading the content failed: {e}")));
+                return;
+            }
+        };
+        if !apply {
+            let _ = reply.send(ControlResponse::Content(Box::new(content)));
+            return;
+        }
+        let response = match clipboard.handle_incoming_content(content).await {
+            Ok(()) => ControlResponse::Applied,
+            Err(e) => ControlResponse::Error(format!("{e:#}")),
+        };
+        let _ = reply.send(response);
+    });
+}
+
+/// Stop waiting for the peer asked with `request_id`
+fn give_up_on_request(pending_gets: &mut [control_socket::PendingGet], request_id: request_response::OutboundRequestId) {
+    for get in pending_gets {
+        get.waiting.retain(|_, id| *id != request_id);
+    }
+}
+
+/// Answer the `get`s every peer has answered, or that ran out of time, with
+/// the newest content by then
+fn finish_gets(
+    pending_gets: &mut Vec<control_socket::PendingGet>,
+    clipboard: &clipboard::ClipboardSync,
+    latest: Option<&clipboard::ClipboardContent>,
+) {
+    let now = Instant::now();
+    let (finished, waiting): (Vec<_>, Vec<_>) =
+        pending_gets.drain(..).partition(|get| get.waiting.is_empty() || get.deadline <= now);
+    *pending_gets = waiting;
+    for get in finished {
+        if latest.is_none() && !get.waiting.is_empty() {
+            let _ = get.reply.send(control_socket::ControlResponse::Error(format!(
+                "No clipboard content is known and {} peers did not answer in time",
+                get.waiting.len()
+            )));
+        } else {
+            answer_get(clipboard, latest, get.apply, get.reply);
+        }
+    }
+}
+
+/// Read text piped into stdin for `--stdin-clipboard`, refusing a terminal.
+/// Input too large for one clipboard message is offered as a file kept in
+/// `spill_dir`, when there is a profile to keep it in.
+fn read_stdin_clipboard(spill_dir: Option<&Path>) -> Result<clipboard::ClipboardContent> {
+    use std::io::IsTerminal;
+
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        anyhow::bail!("--stdin-clipboard needs input piped or redirected into stdin, but stdin is a terminal");
+    }
+    stdin_content(stdin.lock(), MAX_TRANSMIT_SIZE, spill_dir)
+}
+
+/// Clipboard content for piped `input`: its text when that encodes to at
+/// most `limit` bytes, otherwise a file offer of all of it, written to
+/// `spill_dir`. Holds no more than `limit` bytes of the input in memory.
+fn stdin_content(mut input: impl std::io::Read, limit: usize, spill_dir: Option<&Path>) -> Result<clipboard::ClipboardContent> {
+    use std::io::{Read, Write};
+
+    let mut data = Vec::new();
+    (&mut input)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| anyhow::anyhow!("Failed to read stdin: {e}"))?;
+    if data.is_empty() {
+        anyhow::bail!("Input from stdin is empty, nothing to publish");
+    }
+    if data.len() <= limit {
+        let text = String::from_utf8(data)
+            .map_err(|_| anyhow::anyhow!("Input from stdin is not valid UTF-8 text"))?;
+        let content = clipboard::ClipboardContent::new_text(text);
+        let encoded = serde_json::to_vec(&content)?.len();
+        if encoded <= limit {
+            return Ok(content);
+        }
+        data = content.text().unwrap_or_default().into_bytes();
+    }
+    let Some(dir) = spill_dir else {
+        anyhow::bail!(
+            "Input from stdin is over the {limit} byte limit for one clipboard message; with --profile it is offered as a file instead"
+        );
+    };
+    std::fs::create_dir_all(dir)?;
+    // One per profile, replaced by the next run's
+    let path = dir.join(STDIN_FILE);
+    let mut file = std::fs::File::create(&path).map_err(|e| anyhow::anyhow!("Failed to create {}: {e}", path.display()))?;
+    file.write_all(&data)?;
+    drop(data);
+    std::io::copy(&mut input, &mut file).map_err(|e| anyhow::anyhow!("Failed to read stdin: {e}"))?;
+    file.sync_all()?;
+    let offers = files::describe(std::slice::from_ref(&path));
+    if offers.is_empty() {
+        anyhow::bail!("Failed to offer {} for the input from stdin", path.display());
+    }
+    info!("Input from stdin is over the {limit} byte limit for one clipboard message, offering it as the file {}", path.display());
+    Ok(clipboard::ClipboardContent::new_files(offers))
+}
+
+/// Whether a failed listen was caused by the port being taken. The transport
+/// buries the bind error under several wrappers, so probe the port directly.
+fn port_in_use(address: IpAddr, port: u16) -> bool {
+    std::net::TcpListener::bind(SocketAddr::new(address, port))
+        .is_err_and(|e| e.kind() == std::io::ErrorKind::AddrInUse)
+}
+
+/// Whether content from `origin` copied at `timestamp` may replace our
+/// clipboard. A primary node keeps its own clipboard. A node following a
+/// primary takes content from the others too, unless it was copied within
+/// the conflict window of the primary's latest, which wins. Without either,
+/// every peer may.
+fn accepts_from(args: &Args, origin: &PeerId, timestamp: u64, primary_copied_at: Option<(PeerId, u64)>) -> bool {
+    if args.i_am_primary {
+        return false;
+    }
+    let Some(primary) = args.primary_peer else {
+        return true;
+    };
+    primary == *origin
+        || primary_copied_at
+            .filter(|(peer, _)| *peer == primary)
+            .is_none_or(|(_, at)| timestamp >= at.saturating_add(args.conflict_window_ms))
+}
+
+/// Publish our presence on the chat topic. Observers stay invisible, and with
+/// nobody subscribed there is nobody to tell.
+fn announce_presence(
+    swarm: &mut Swarm<AppBehaviour>,
+    args: &Args,
+    stats: &stats::SharedStats,
+    peer_capabilities: &HashMap<PeerId, capabilities::Capabilities>,
+    topic: &gossipsub::IdentTopic,
+    state: chat::PresenceState,
+) {
+    // Presence has no plain text form for peers predating the envelope
+    if args.observer
+        || subscriptions::topic_subscribers(&swarm.behaviour().gossipsub, &topic.hash()).is_empty()
+        || !chat_envelope(&swarm.behaviour().gossipsub, topic, peer_capabilities)
+    {
+        return;
+    }
+    match serde_json::to_vec(&chat::ChatMessage::presence(state)) {
+        Ok(data) => {
+            if let Err(e) = publish(swarm, args, stats, topic.clone(), data) {
+                debug!("Failed to announce presence: {e}");
+            }
+        }
+        Err(e) => debug!("Failed to encode presence: {e}"),
+    }
+}
+
+/// Whether every peer on the chat topic advertised that it reads chat
+/// envelopes. Older peers would show them as raw JSON.
+fn chat_envelope(
+    gossipsub: &gossipsub::Behaviour,
+    topic: &gossipsub::IdentTopic,
+    peer_capabilities: &HashMap<PeerId, capabilities::Capabilities>,
+) -> bool {
+    subscriptions::topic_subscribers(gossipsub, &topic.hash())
+        .iter()
+        .all(|peer| peer_capabilities.get(peer).is_some_and(|theirs| theirs.chat))
+}
+
+/// Rooms this node syncs in: its `--room`s, or both rooms of a bridge with
+/// the one local copies are published to first
+fn joined_rooms(args: &Args) -> Vec<bridge::Room> {
+    match args.bridge {
+        Some(ref bridge) => vec![
+            bridge::Room::new(&bridge.a, bridge::Direction::Both),
+            bridge::Room::new(&bridge.b, bridge::Direction::Both),
+        ],
+        None => args.room.iter().map(|room| bridge::Room::new(&room.name, room.direction)).collect(),
+    }
+}
+
+/// Rooms `peer` shares with us, going by its clipboard topic subscriptions.
+/// A node in a single room takes every peer to be in it, since a peer's
+/// subscriptions may arrive after its first direct request.
+fn peer_rooms<'a>(swarm: &Swarm<AppBehaviour>, rooms: &'a [bridge::Room], peer: &PeerId) -> Vec<&'a bridge::Room> {
+    if let [room] = rooms {
+        return vec![room];
+    }
+    let Some((_, topics)) = swarm.behaviour().gossipsub.all_peers().find(|(other, _)| *other == peer) else {
+        return Vec::new();
+    };
+    rooms.iter().filter(|room| topics.iter().any(|topic| room.has_topic(topic))).collect()
+}
+
+/// A room shared with `peer` that content from the room `from` may be sent to
+fn sharing_room<'a>(
+    swarm: &Swarm<AppBehaviour>,
+    rooms: &'a [bridge::Room],
+    peer: &PeerId,
+    from: Option<&str>,
+    bridging: bool,
+) -> Option<&'a bridge::Room> {
+    peer_rooms(swarm, rooms, peer).into_iter().find(|room| room.may_send(from, bridging))
+}
+
+/// Publish content a bridge forwards into its other room. Content we can't
+/// open is forwarded sealed.
+#[allow(clippy::too_many_arguments)]
+fn forward_to_room(
+    swarm: &mut Swarm<AppBehaviour>,
+    args: &Args,
+    stats: &stats::SharedStats,
+    timings: &timing::OpTimings,
+    audit_log: &mut Option<audit::AuditLog>,
+    field_key: Option<&crypto::FieldKey>,
+    room: &bridge::Room,
+    topic: &gossipsub::IdentTopic,
+    content: &clipboard::ClipboardContent,
+) {
+    publish_to_room(swarm, args, stats, audit_log, room, topic, content, || {
+        timings
+            .time("serialize", content.size(), || serde_json::to_vec(&crypto::sealed(content.clone(), field_key)))
+            .map_err(anyhow::Error::from)
+    });
+}
+
+/// Publish `content` into another room, serialized by `data` only if it is
+/// sent: content a bridge forwards, or a copy a member of several rooms sends
+/// to each. Nobody listening there is normal and not worth more than a debug
+/// line.
+#[allow(clippy::too_many_arguments)]
+fn publish_to_room(
+    swarm: &mut Swarm<AppBehaviour>,
+    args: &Args,
+    stats: &stats::SharedStats,
+    audit_log: &mut Option<audit::AuditLog>,
+    room: &bridge::Room,
+    topic: &gossipsub::IdentTopic,
+    content: &clipboard::ClipboardContent,
+    data: impl FnOnce() -> Result<Vec<u8>>,
+) {
+    // Only a bridge records the rooms content passed through
+    let verb = if content.via_rooms.is_empty() { "Sent" } else { "Bridged" };
+    if content.content_type == clipboard::ContentType::Image && !stats.lock().expect("stats lock poisoned").bandwidth().allows_bulk() {
+        info!("Bandwidth cap reached, not sending an image to {topic}");
+        return;
+    }
+    if is_foreign_file_offer(content) {
+        debug!("Not bridging a file offer to {topic}: peers there could not pull the files through us");
+        return;
+    }
+    let result = data().and_then(|data| publish(swarm, args, stats, topic.clone(), data));
+    match result {
+        Ok(_) => {
+            info!("{verb} {:?} content to {topic}", content.content_type);
+            stats.lock().expect("stats lock poisoned").record_room(stats::Direction::Sent, &room.name);
+            if let Some(audit_log) = audit_log {
+                let peers = subscriptions::topic_subscribers(&swarm.behaviour().gossipsub, &topic.hash());
+                audit_log.sent(content, &peers, &room.name);
+            }
+        }
+        Err(e) => debug!("Failed to send clipboard content to {topic}: {e}"),
+    }
+}
+
+/// Warn and emit a conflict event if `content`, about to be applied, replaces
+/// something copied locally at nearly the same time
+fn report_conflict(
+    conflicts: &mut conflict::ConflictDetector,
+    args: &Args,
+    event_tx: &broadcast::Sender<control::NodeEvent>,
+    from: PeerId,
+    content: &clipboard::ClipboardContent,
+) {
+    if let Some(local_preview) = conflicts.check(content, args.conflict_window_ms) {
+        let _ = event_tx.send(control::NodeEvent::conflict(from, local_preview, content));
+    }
+}
+
+/// Start pulling the files `from` offered into the download directory. Without
+/// one the offer is only logged.
+fn fetch_files(
+    swarm: &mut Swarm<AppBehaviour>,
+    args: &Args,
+    stats: &stats::SharedStats,
+    downloads: &mut files::Downloads,
+    device_names: &HashMap<PeerId, String>,
+    from: PeerId,
+    offers: &[files::FileOffer],
+) {
+    let names: Vec<&str> = offers.iter().map(|offer| offer.name.as_str()).collect();
+    let Some(ref dir) = args.download_dir else {
+        info!(
+            "{} offers files: {}. Start with --download-dir to download them",
+            peer_label(device_names, &from),
+            names.join(", ")
+        );
+        return;
+    };
+    if !stats.lock().expect("stats lock poisoned").bandwidth().allows_bulk() {
+        info!("Bandwidth cap reached, not downloading {}", names.join(", "));
+        return;
+    }
+    downloads.start(dir, from, offers, |peer, request| swarm.behaviour_mut().files.send_request(peer, request));
+}
+
+/// Whether `content` offers files this node can't serve itself: they were
+/// received, not copied here, so nobody else can pull them through us
+fn is_foreign_file_offer(content: &clipboard::ClipboardContent) -> bool {
+    content.files().is_some_and(|offers| offers.iter().any(|offer| offer.path.is_none()))
+}
+
+/// Peers known to hold received retained content
+#[derive(Debug)]
+struct Holders {
+    /// The peer that copied it, or that handed it to us directly
+    origin: PeerId,
+    /// The origin and the peers subscribed to its topic when it arrived,
+    /// unless they don't take its type
+    peers: HashSet<PeerId>,
+}
+
+/// Whether this node is the one to offer retained content to `newcomer`
+/// with `--elect-retained-offer`: the node that copied it, or if that one
+/// isn't among the topic's `subscribers`, the one with the lowest PeerId of
+/// those known to hold it. Subscribers that joined after it was published
+/// may have nothing to offer, so they are never elected. `holders` is None
+/// for our own copies.
+fn elected_to_offer(subscribers: &[PeerId], local_peer_id: &PeerId, holders: Option<&Holders>, newcomer: &PeerId) -> bool {
+    let Some(holders) = holders else {
+        return true;
+    };
+    // The origin offers it itself, and a newcomer that copied it has it
+    if subscribers.contains(&holders.origin) {
+        return false;
+    }
+    subscribers
+        .iter()
+        .filter(|peer| *peer != newcomer && holders.peers.contains(peer))
+        .all(|peer| local_peer_id < peer)
+}
+
+/// Whether received content of this type waits in the incoming queue
+fn queues(args: &Args, content_type: clipboard::ContentType) -> bool {
+    args.queue_incoming && !args.auto_accept_types.contains(&content_type)
+}
+
+/// Whether content is recent enough to hand to a peer that missed it
+fn is_fresh(content: &clipboard::ClipboardContent, max_age_secs: u64) -> bool {
+    max_age_secs > 0 && clipboard::now_millis().saturating_sub(content.timestamp) <= max_age_secs * 1000
+}
+
+/// Clipboard capabilities of this node as configured by `args`
+fn local_capabilities(args: &Args) -> capabilities::Capabilities {
+    capabilities::Capabilities {
+        wire_format: capabilities::WIRE_FORMAT,
+        direct: capabilities::DIRECT_PROTOCOL,
+        clipboard: args.clipboard || args.observer,
+        compression: args.transport_compression,
+        observer: args.observer,
+        image_diffs: true,
+        chat: true,
+        device_name: match args.device_name {
+            Some(ref name) => capabilities::sanitize_device_name(name),
+            None => capabilities::sanitize_device_name(&gethostname::gethostname().to_string_lossy()),
+        },
+        formats: args.accept_formats.clone(),
+        max_image_bytes: Some(hardened::max_image_bytes(args) as u64).filter(|max| *max > 0),
+    }
+}
+
+/// Whether a peer discovered `how` at `addr` is on a selected interface and
+/// within `--allow-subnet`. Without either every address is allowed.
+fn discovery_allowed(
+    args: &Args,
+    interfaces: Option<&interfaces::InterfaceSet>,
+    how: &str,
+    peer: &PeerId,
+    addr: &Multiaddr,
+) -> bool {
+    if let Some(interfaces) = interfaces
+        && !interfaces::ip_of(addr).is_some_and(|ip| interfaces.reaches(ip))
+    {
+        debug!("Ignoring {how} peer {peer} at {addr}: not on a selected interface");
+        return false;
+    }
+    if !inbound_allowed(args, addr) {
+        debug!("Ignoring {how} peer {peer} at {addr}: outside the allowed subnets");
+        return false;
+    }
+    true
+}
+
+/// Whether a peer connecting in from `addr` is within `--allow-subnet`.
+/// Without it every address is allowed.
+fn inbound_allowed(args: &Args, addr: &Multiaddr) -> bool {
+    args.allow_subnet.is_empty()
+        || interfaces::ip_of(addr).is_some_and(|ip| args.allow_subnet.iter().any(|subnet| subnet.contains(ip)))
+}
+
+/// Forward everything to a group member found on the local network
+fn add_discovered(swarm: &mut Swarm<AppBehaviour>, args: &Args, explicit_peers: &mut HashSet<PeerId>, peer: PeerId) {
+    if !args.readonly_topics {
+        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
+        explicit_peers.insert(peer);
+    }
+}
+
+/// `peer` with its device name if it advertised one
+fn peer_label(device_names: &HashMap<PeerId, String>, peer: &PeerId) -> String {
+    match device_names.get(peer) {
+        Some(name) => format!("{name} ({peer})"),
+        None => peer.to_string(),
+    }
+}
+
+/// Options for applying received content, derived from `args`
+fn clipboard_options(args: &Args, local_peer_id: &PeerId) -> clipboard::ClipboardOptions {
+    clipboard::ClipboardOptions {
+        image_scale: args.image_scale,
+        queue_incoming: args.queue_incoming,
+        auto_accept: args.auto_accept_types.iter().copied().collect(),
+        ignore_initial: args.ignore_initial_clipboard,
+        resend_window: Duration::from_secs(args.resend_window),
+        log_content: args.log_content,
+        source_label: args.source_label,
+        newlines: args.replace_newlines,
+        confirm_delivery: args.confirm_delivery,
+        poll: poll::Schedule {
+            min: Duration::from_millis(args.poll_min_ms),
+            max: Duration::from_millis(args.poll_max_ms),
+            backoff: args.poll_backoff,
+        },
+        device_name: local_capabilities(args).device_name.unwrap_or_else(|| local_peer_id.to_string()),
+    }
+}
+
+/// Timer for the subscription self-check. Never ticks when disabled, but
+/// tokio does not accept a zero period.
+fn subscription_interval(args: &Args) -> tokio::time::Interval {
+    tokio::time::interval(Duration::from_secs(args.subscription_check_secs.max(1)))
+}
+
+fn bandwidth_cap(args: &Args) -> Option<u64> {
+    (args.bandwidth_cap > 0).then_some(args.bandwidth_cap)
+}
+
+fn cache_limits(args: &Args) -> clipboard::CacheLimits {
+    clipboard::CacheLimits {
+        max_bytes: (args.cache_max_bytes > 0).then_some(args.cache_max_bytes),
+        max_age: (args.cache_max_age > 0).then(|| Duration::from_secs(args.cache_max_age)),
+    }
+}
+
+/// Dial the `--connect` addresses, except those of peers we are connected to
+fn dial_connect_addrs(swarm: &mut Swarm<AppBehaviour>, args: &Args) {
+    for addr in args.connect.iter().flatten() {
+        let peer = addr.iter().find_map(|protocol| match protocol {
+            Protocol::P2p(peer) => Some(peer),
+            _ => None,
+        });
+        if peer.is_some_and(|peer| swarm.is_connected(&peer)) {
+            continue;
+        }
+        info!("Dialing {addr}...");
+        if let Err(e) = swarm.dial(addr.clone()) {
+            error!("Failed to dial {addr}: {e}");
+        }
+    }
+}
+
+/// Listen addresses to announce in beacons: none in hardened mode, which
+/// stops them going out
+fn announced_addrs(swarm: &Swarm<AppBehaviour>, args: &Args) -> Vec<Multiaddr> {
+    if args.hardened { Vec::new() } else { swarm.listeners().cloned().collect() }
+}
+
+/// Dial address book entries until the autodial concurrency limit is reached
+fn dial_known_peers(swarm: &mut Swarm<AppBehaviour>, autodial: &mut address_book::Autodial) {
+    // Entries that don't need a dial free their slot right away, so refill
+    // until the batch is all real dials
+    loop {
+        let batch = autodial.next_batch();
+        if batch.is_empty() {
+            return;
+        }
+        let mut freed = false;
+        for (peer, addrs) in batch {
+            if swarm.is_connected(&peer) {
+                freed |= autodial.finished(&peer);
+                continue;
+            }
+            debug!("Dialing remembered peer {peer}");
+            let opts = DialOpts::peer_id(peer).addresses(addrs).build();
+            if let Err(e) = swarm.dial(opts) {
+                debug!("Failed to dial remembered peer {peer}: {e}");
+                freed |= autodial.finished(&peer);
+            }
+        }
+        if !freed {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use control::NodeEvent;
+    use in_process::Node;
+
+    /// How long a node may take to connect or to pass content on
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn peers<const N: usize>() -> [PeerId; N] {
+        let mut peers = [(); N].map(|_| PeerId::random());
+        peers.sort();
+        peers
+    }
+
+    #[test]
+    fn piped_text_within_a_message_is_published_as_text() {
+        let content = stdin_content(&b"deploy token"[..], 1024, None).unwrap();
+        assert_eq!(content.text().as_deref(), Some("deploy token"));
+    }
+
+    #[test]
+    fn empty_or_binary_input_is_refused() {
+        assert!(stdin_content(&b""[..], 1024, None).is_err());
+        assert!(stdin_content(&[0xff, 0xfe][..], 1024, None).is_err());
+    }
+
+    #[test]
+    fn oversized_input_without_a_profile_is_refused() {
+        let input = std::io::Read::take(std::io::repeat(b'x'), 4 * 1024 * 1024);
+        let error = stdin_content(input, 1024, None).unwrap_err().to_string();
+        assert!(error.contains("--profile"), "{error}");
+    }
+
+    #[test]
+    fn oversized_input_is_offered_as_a_file() {
+        let dir = testing::TempDir::new();
+        let input = std::io::Read::take(std::io::repeat(b'x'), 4 * 1024 * 1024);
+        let content = stdin_content(input, 1024, Some(dir.path())).unwrap();
+        let [offer] = content.files().expect("offered as a file") else {
+            panic!("one file");
+        };
+        assert_eq!(offer.size, 4 * 1024 * 1024);
+        assert_eq!(std::fs::metadata(dir.path().join(STDIN_FILE)).unwrap().len(), offer.size);
+    }
+
+    #[test]
+    fn text_that_grows_past_the_limit_when_encoded_is_offered_as_a_file() {
+        let dir = testing::TempDir::new();
+        // Every quote is escaped, doubling the encoded size
+        let input = vec![b'"'; 1000];
+        let content = stdin_content(&input[..], 1024, Some(dir.path())).unwrap();
+        assert!(content.files().is_some_and(|offers| offers[0].size == 1000));
+    }
+
+    #[test]
+    fn three_rejections_stop_gossip_and_nine_graylist() {
+        let args = Args::try_parse_from(["clipboard-sync"]).unwrap();
+        let (params, thresholds) = peer_scoring(&args);
+        let topic = params.topics.values().next().unwrap();
+        let score = |rejected: f64| topic.topic_weight * topic.invalid_message_deliveries_weight * rejected * rejected;
+        assert!(score(2.0) >= thresholds.gossip_threshold);
+        assert!(score(3.0) < thresholds.gossip_threshold);
+        assert!(score(8.0) >= thresholds.graylist_threshold);
+        assert!(score(9.0) < thresholds.graylist_threshold);
+        assert!(thresholds.validate().is_ok());
+    }
+
+    #[test]
+    fn discovered_addresses_outside_the_allowed_subnets_are_ignored() {
+        let args = Args::try_parse_from(["clipboard-sync", "--allow-subnet", "192.168.1.0/24"]).unwrap();
+        let peer = PeerId::random();
+        let inside: Multiaddr = "/ip4/192.168.1.20/tcp/4001".parse().unwrap();
+        let outside: Multiaddr = "/ip4/10.0.0.5/tcp/4001".parse().unwrap();
+        assert!(discovery_allowed(&args, None, "mDNS", &peer, &inside));
+        assert!(!discovery_allowed(&args, None, "mDNS", &peer, &outside));
+        let open = Args::try_parse_from(["clipboard-sync"]).unwrap();
+        assert!(discovery_allowed(&open, None, "mDNS", &peer, &outside));
+        assert!(inbound_allowed(&args, &inside));
+        assert!(!inbound_allowed(&args, &outside));
+        assert!(inbound_allowed(&open, &outside));
+    }
+
+    #[test]
+    fn only_the_author_is_penalized_for_rejected_content() {
+        let rejected = policy::ValidationDecision::Reject("too large".to_string());
+        assert!(matches!(rejected.acceptance(false), gossipsub::MessageAcceptance::Reject));
+        assert!(matches!(rejected.acceptance(true), gossipsub::MessageAcceptance::Ignore));
+        assert!(matches!(policy::ValidationDecision::Accept.acceptance(true), gossipsub::MessageAcceptance::Accept));
+    }
+
+    #[test]
+    fn a_capped_copy_is_published_to_all_when_no_peer_takes_it_directly() {
+        let old = capabilities::Capabilities::from_agent_version(
+            "libp2p-clipboard-sync/0.1.0 (wire=3; clipboard=on; compression=off; observer=off; diffs=on)",
+        )
+        .unwrap();
+        let subscribers = peers::<3>();
+        let mut peer_capabilities: HashMap<PeerId, capabilities::Capabilities> =
+            subscribers.iter().map(|peer| (*peer, old.clone())).collect();
+        let stats = stats::Stats::default();
+        let text = clipboard::ContentType::Text;
+        assert_eq!(capped_targets(2, &subscribers, &peer_capabilities, text, &stats), None);
+
+        // Once one can, it is the only one sent to
+        peer_capabilities.insert(subscribers[1], local_capabilities(&Args::try_parse_from(["clipboard-sync"]).unwrap()));
+        assert_eq!(capped_targets(2, &subscribers, &peer_capabilities, text, &stats), Some(vec![subscribers[1]]));
+        assert_eq!(capped_targets(3, &subscribers, &peer_capabilities, text, &stats), None);
+        assert_eq!(capped_targets(0, &subscribers, &peer_capabilities, text, &stats), None);
+    }
+
+    #[test]
+    fn our_own_copies_are_always_offered() {
+        let [local, newcomer] = peers();
+        assert!(elected_to_offer(&[newcomer], &local, None, &newcomer));
+    }
+
+    #[test]
+    fn the_origin_offers_its_copy_itself() {
+        let [local, origin, newcomer] = peers();
+        let holders = Holders { origin, peers: HashSet::from([origin]) };
+        assert!(!elected_to_offer(&[origin, newcomer], &local, Some(&holders), &newcomer));
+    }
+
+    #[test]
+    fn the_lowest_holder_offers_when_the_origin_is_gone() {
+        let [lower, local, higher, origin, newcomer] = peers();
+        let holders = Holders { origin, peers: HashSet::from([origin, lower, higher]) };
+        assert!(!elected_to_offer(&[lower, higher, newcomer], &local, Some(&holders), &newcomer));
+        assert!(elected_to_offer(&[higher, newcomer], &local, Some(&holders), &newcomer));
+    }
+
+    #[test]
+    fn subscribers_that_may_not_hold_it_are_never_elected() {
+        let [joined_later, local, origin, newcomer] = peers();
+        let holders = Holders { origin, peers: HashSet::from([origin]) };
+        assert!(elected_to_offer(&[joined_later, newcomer], &local, Some(&holders), &newcomer));
+    }
+
+    /// Wait until `node` identified all of `peers`, in whatever order
+    async fn identified(node: &mut Node, peers: &[PeerId]) {
+        let mut unmet: HashSet<PeerId> = peers.iter().copied().collect();
+        while !unmet.is_empty() {
+            let peer = node
+                .wait_for(TIMEOUT, |event| match event {
+                    NodeEvent::PeerIdentified { peer, .. } => Some(*peer),
+                    _ => None,
+                })
+                .await
+                .unwrap();
+            unmet.remove(&peer);
+        }
+    }
+
+    async fn applied(node: &mut Node) {
+        let error = node
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::ClipboardApplied { error, .. } => Some(error.clone()),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(error, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_copied_file_is_pulled_in_chunks_and_verified() {
+        use sha2::{Digest, Sha256};
+
+        let source = crate::testing::TempDir::new();
+        let target = crate::testing::TempDir::new();
+        let contents: Vec<u8> = (0..5 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        let path = source.path().join("big.bin");
+        std::fs::write(&path, &contents).unwrap();
+        let sender = Node::start(&["--clipboard"]).unwrap();
+        let download_dir = target.path().to_string_lossy().into_owned();
+        let mut receiver =
+            Node::start(&["--clipboard", "--download-dir", &download_dir, "--files-to-clipboard", "--connect", &sender.address.to_string()])
+                .unwrap();
+        identified(&mut receiver, &[sender.peer_id]).await;
+
+        sender.clipboard.copy_files(vec![path]);
+        let error = receiver
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::TransferFinished { error, .. } => Some(error.clone()),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(error, None);
+        let downloaded = target.path().join("big.bin");
+        assert_eq!(Sha256::digest(std::fs::read(&downloaded).unwrap()), Sha256::digest(&contents));
+        // Put there without an apply event, once the download checks out
+        let deadline = Instant::now() + TIMEOUT;
+        while receiver.clipboard.files().is_none() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(receiver.clipboard.files(), Some(vec![downloaded]));
+
+        for node in [sender, receiver] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_download_cut_off_by_a_restart_resumes_from_its_missing_chunks() {
+        use sha2::{Digest, Sha256};
+
+        let source = crate::testing::TempDir::new();
+        let target = crate::testing::TempDir::new();
+        let profile = crate::testing::TempDir::new();
+        let contents: Vec<u8> = (0..32 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        let path = source.path().join("big.bin");
+        std::fs::write(&path, &contents).unwrap();
+        let sender = Node::start(&["--clipboard"]).unwrap();
+        let download_dir = target.path().to_string_lossy().into_owned();
+        let mut receiver =
+            Node::start_in(profile.path(), &["--clipboard", "--download-dir", &download_dir, "--connect", &sender.address.to_string()])
+                .unwrap();
+        identified(&mut receiver, &[sender.peer_id]).await;
+
+        sender.clipboard.copy_files(vec![path]);
+        receiver.wait_for(TIMEOUT, |event| matches!(event, NodeEvent::TransferProgress { .. }).then_some(())).await.unwrap();
+        // Cut off midway, the download is saved with the profile
+        let mut receiver = receiver.restart().await.unwrap();
+        let (bytes, error) = receiver
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::TransferFinished { bytes, error, .. } => Some((*bytes, error.clone())),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(error, None);
+        // Only what was missing came over after the restart
+        assert!(bytes < contents.len() as u64, "all {bytes} bytes were sent again");
+        assert_eq!(Sha256::digest(std::fs::read(target.path().join("big.bin")).unwrap()), Sha256::digest(&contents));
+
+        for node in [sender, receiver] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn peers_remembered_in_one_session_are_dialed_in_the_next() {
+        let profile = crate::testing::TempDir::new();
+        let member = Node::start(&["--clipboard"]).unwrap();
+        let mut node = Node::start_in(profile.path(), &["--clipboard", "--connect", &member.address.to_string()]).unwrap();
+        identified(&mut node, &[member.peer_id]).await;
+        node.stop().await.unwrap();
+
+        // Nothing says where the member is but the address book
+        let mut node = Node::start_in(profile.path(), &["--clipboard"]).unwrap();
+        node.wait_for(TIMEOUT, |event| {
+            matches!(event, NodeEvent::PeerConnected { peer, .. } if *peer == member.peer_id).then_some(())
+        })
+        .await
+        .unwrap();
+
+        for node in [member, node] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_late_joiner_gets_the_latest_copy_after_its_origin_left() {
+        const ELECT: &str = "--elect-retained-offer";
+        // A subscriber that never takes text, so it never holds the copy,
+        // with a lower PeerId than the node that does
+        let mut holder = Node::start(&["--clipboard", ELECT]).unwrap();
+        let holder_address = holder.address.to_string();
+        let bystander = loop {
+            let node = Node::start(&["--clipboard", ELECT, "--accept-formats", "image", "--connect", &holder_address]).unwrap();
+            if node.peer_id < holder.peer_id {
+                break node;
+            }
+            node.stop().await.unwrap();
+        };
+        identified(&mut holder, &[bystander.peer_id]).await;
+        let origin = Node::start(&["--clipboard", ELECT, "--connect", &holder_address]).unwrap();
+        identified(&mut holder, &[origin.peer_id]).await;
+
+        origin.clipboard.copy_text("copied before you came");
+        applied(&mut holder).await;
+        let origin_id = origin.peer_id;
+        origin.stop().await.unwrap();
+        holder
+            .wait_for(TIMEOUT, |event| matches!(event, NodeEvent::PeerDisconnected { peer, .. } if *peer == origin_id).then_some(()))
+            .await
+            .unwrap();
+
+        let mut newcomer = Node::start(&["--clipboard", ELECT, "--connect", &holder_address]).unwrap();
+        applied(&mut newcomer).await;
+        assert_eq!(newcomer.clipboard.text().as_deref(), Some("copied before you came"));
+
+        for node in [bystander, holder, newcomer] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_bridge_forwards_content_sent_to_it_directly() {
+        let mut sender = Node::start(&["--clipboard", "--room", "office"]).unwrap();
+        let mut bridge = Node::start(&["--clipboard", "--bridge", "office=lab", "--connect", &sender.address.to_string()]).unwrap();
+        let mut lab = Node::start(&["--clipboard", "--room", "lab", "--connect", &bridge.address.to_string()]).unwrap();
+        identified(&mut sender, &[bridge.peer_id]).await;
+        identified(&mut bridge, &[sender.peer_id, lab.peer_id]).await;
+        identified(&mut lab, &[bridge.peer_id]).await;
+        // Subscriptions follow the connection, give them a moment
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        sender.command(control::NodeCommand::Pause);
+        sender.wait_for(TIMEOUT, |event| matches!(event, NodeEvent::PauseChanged { paused: true, .. }).then_some(())).await.unwrap();
+
+        sender.clipboard.copy_text("through the bridge");
+        sender.send_to(bridge.peer_id);
+        let direct = bridge
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::ClipboardReceived { direct, .. } => Some(*direct),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert!(direct);
+        applied(&mut lab).await;
+        assert_eq!(lab.clipboard.text().as_deref(), Some("through the bridge"));
+
+        for node in [sender, bridge, lab] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn send_to_reaches_the_targeted_peer_only() {
+        let mut sender = Node::start(&["--clipboard"]).unwrap();
+        let address = sender.address.to_string();
+        let mut target = Node::start(&["--clipboard", "--connect", &address]).unwrap();
+        let mut third = Node::start(&["--clipboard", "--connect", &address]).unwrap();
+        identified(&mut sender, &[target.peer_id, third.peer_id]).await;
+        identified(&mut target, &[sender.peer_id]).await;
+        identified(&mut third, &[sender.peer_id]).await;
+        // Paused, the copy isn't published, so only the direct send carries it
+        sender.command(control::NodeCommand::Pause);
+        sender.wait_for(TIMEOUT, |event| matches!(event, NodeEvent::PauseChanged { paused: true, .. }).then_some(())).await.unwrap();
+
+        sender.clipboard.copy_text("for one device only");
+        sender.send_to(target.peer_id);
+        applied(&mut target).await;
+        assert_eq!(target.clipboard.text().as_deref(), Some("for one device only"));
+        let received = third
+            .wait_for(Duration::from_secs(2), |event| matches!(event, NodeEvent::ClipboardReceived { .. }).then(|| format!("{event:?}")))
+            .await;
+        assert!(received.is_err(), "the third peer received it: {received:?}");
+        assert_eq!(third.clipboard.text(), None);
+
+        for node in [sender, target, third] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn a_taken_port_fails_unless_falling_back_to_another() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port().to_string();
+        let swarm = |fallback: bool| {
+            let mut flags = vec!["clipboard-sync", "--listen-address", "127.0.0.1", "--port", &port];
+            if fallback {
+                flags.push("--port-fallback");
+            }
+            let args = Args::try_parse_from(flags).unwrap();
+            let swarm = build_swarm(identity::Keypair::generate_ed25519(), &args, |_| libp2p::swarm::dummy::Behaviour).unwrap();
+            (swarm, args)
+        };
+
+        let (mut strict, args) = swarm(false);
+        assert!(listen(&mut strict, &args).is_err());
+
+        let (mut falling_back, args) = swarm(true);
+        listen(&mut falling_back, &args).unwrap();
+        let address = tokio::time::timeout(TIMEOUT, async {
+            loop {
+                if let SwarmEvent::NewListenAddr { address, .. } = falling_back.select_next_some().await {
+                    return address;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let Some(Protocol::Tcp(listening)) = address.iter().nth(1) else { panic!("not TCP: {address}") };
+        assert_ne!(listening.to_string(), port);
+        assert_ne!(listening, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn text_copied_during_an_image_transfer_overtakes_it() {
+        use clipboard::ContentType;
+
+        let mut sender = Node::start(&["--clipboard"]).unwrap();
+        let mut receiver = Node::start(&["--clipboard", "--connect", &sender.address.to_string()]).unwrap();
+        identified(&mut receiver, &[sender.peer_id]).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // Noise, so it stays large after compression
+        let (width, height) = (1000, 1000);
+        let pixels: Vec<u8> = (0..width * height * 4u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        sender.clipboard.copy_image(pixels, width as usize, height as usize);
+        sender
+            .wait_for(TIMEOUT, |event| matches!(event, NodeEvent::ClipboardSent { content_type: ContentType::Image, .. }).then_some(()))
+            .await
+            .unwrap();
+        sender.clipboard.copy_text("while the image is in flight");
+
+        // The text overtakes the image, which is then stale
+        let first = receiver
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::ClipboardReceived { content_type, .. } => Some(*content_type),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(first, ContentType::Text);
+        applied(&mut receiver).await;
+        assert_eq!(receiver.clipboard.text().as_deref(), Some("while the image is in flight"));
+        let reason = receiver
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::ContentDropped { content_type: ContentType::Image, reason, .. } => Some(*reason),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(reason, control::DropReason::Superseded);
+
+        for node in [sender, receiver] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn an_observer_records_what_it_receives_but_never_publishes() {
+        let mut peer = Node::start(&["--clipboard"]).unwrap();
+        let mut observer = Node::start(&["--clipboard", "--observer", "--connect", &peer.address.to_string()]).unwrap();
+        let agent_version = peer
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::PeerIdentified { peer, agent_version, .. } if *peer == observer.peer_id => Some(agent_version.clone()),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert!(capabilities::Capabilities::from_agent_version(&agent_version).unwrap().observer);
+        identified(&mut observer, &[peer.peer_id]).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // Neither a copy nor an explicit send gets anything out
+        observer.clipboard.copy_text("never leaves the observer");
+        observer.command(control::NodeCommand::SendClipboard);
+        observer.send_to(peer.peer_id);
+        let sent = observer
+            .wait_for(Duration::from_secs(1), |event| matches!(event, NodeEvent::ClipboardSent { .. }).then_some(()))
+            .await;
+        assert!(sent.is_err(), "the observer published");
+        assert_eq!(peer.clipboard.text(), None);
+
+        peer.clipboard.copy_text("seen by the observer");
+        let from = observer
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::ClipboardReceived { from, .. } => Some(*from),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(from, peer.peer_id);
+
+        for node in [peer, observer] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_screen_share_pauses_sync_until_it_ends() {
+        let mut sender = Node::start(&["--clipboard"]).unwrap();
+        let mut sharing = Node::start(&["--clipboard", "--connect", &sender.address.to_string()]).unwrap();
+        identified(&mut sharing, &[sender.peer_id]).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let pause_changed = |event: &NodeEvent| match event {
+            NodeEvent::PauseChanged { paused, cause: control::PauseCause::ScreenShare } => Some(*paused),
+            _ => None,
+        };
+
+        sharing.command(control::NodeCommand::ScreenShare(true));
+        assert!(sharing.wait_for(TIMEOUT, pause_changed).await.unwrap());
+        sharing.clipboard.copy_text("shown on screen");
+        sender.clipboard.copy_text("not for the audience");
+        let sent = |event: &NodeEvent| matches!(event, NodeEvent::ClipboardSent { .. }).then_some(());
+        sender.wait_for(TIMEOUT, sent).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(sharing.clipboard.text().as_deref(), Some("shown on screen"));
+        assert_eq!(sender.clipboard.text().as_deref(), Some("not for the audience"));
+
+        sharing.command(control::NodeCommand::ScreenShare(false));
+        assert!(!sharing.wait_for(TIMEOUT, pause_changed).await.unwrap());
+        sender.clipboard.copy_text("after the share");
+        applied(&mut sharing).await;
+        assert_eq!(sharing.clipboard.text().as_deref(), Some("after the share"));
+
+        for node in [sender, sharing] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_readonly_node_receives_but_stays_out_of_the_mesh() {
+        let args = Args::try_parse_from(["clipboard-sync", "--clipboard", "--readonly-topics"]).unwrap();
+        let config = gossipsub_config(&args).unwrap();
+        for room in joined_rooms(&args) {
+            for topic in [room.clipboard.hash(), room.bulk.hash()] {
+                assert_eq!((config.mesh_n_for_topic(&topic), config.mesh_n_high_for_topic(&topic)), (0, 0));
+            }
+        }
+
+        let sender = Node::start(&["--clipboard"]).unwrap();
+        let mut readonly = Node::start(&["--clipboard", "--readonly-topics", "--connect", &sender.address.to_string()]).unwrap();
+        identified(&mut readonly, &[sender.peer_id]).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        sender.clipboard.copy_text("for the readonly node");
+        applied(&mut readonly).await;
+        assert_eq!(readonly.clipboard.text().as_deref(), Some("for the readonly node"));
+
+        for node in [sender, readonly] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn transport_compression_is_used_when_both_ends_offer_it() {
+        let mut sender = Node::start(&["--clipboard", "--transport-compression"]).unwrap();
+        let connect = sender.address.to_string();
+        let mut compressed = Node::start(&["--clipboard", "--transport-compression", "--connect", &connect]).unwrap();
+        let mut plain = Node::start(&["--clipboard", "--connect", &connect]).unwrap();
+        identified(&mut sender, &[compressed.peer_id, plain.peer_id]).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let text = "compressed ".repeat(1000);
+        sender.clipboard.copy_text(&text);
+        for node in [&mut compressed, &mut plain] {
+            applied(node).await;
+            assert_eq!(node.clipboard.text().as_deref(), Some(text.as_str()));
+        }
+
+        for node in [sender, compressed, plain] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn nodes_sync_over_tls_and_need_a_security_protocol_in_common() {
+        let tls = Node::start(&["--clipboard", "--security", "tls"]).unwrap();
+        let noise = Node::start(&["--clipboard", "--security", "noise"]).unwrap();
+        let mut receiver =
+            Node::start(&["--clipboard", "--security", "tls", "--connect", &tls.address.to_string()]).unwrap();
+        identified(&mut receiver, &[tls.peer_id]).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        tls.clipboard.copy_text("secured with TLS");
+        applied(&mut receiver).await;
+        assert_eq!(receiver.clipboard.text().as_deref(), Some("secured with TLS"));
+
+        // Offering both reaches either kind
+        let mut both = Node::start(&[
+            "--clipboard",
+            "--security",
+            "both",
+            "--connect",
+            &tls.address.to_string(),
+            "--connect",
+            &noise.address.to_string(),
+        ])
+        .unwrap();
+        identified(&mut both, &[tls.peer_id, noise.peer_id]).await;
+
+        let mut mismatched =
+            Node::start(&["--clipboard", "--security", "noise", "--connect", &tls.address.to_string()]).unwrap();
+        let connected = mismatched
+            .wait_for(Duration::from_secs(2), |event| matches!(event, NodeEvent::PeerConnected { .. }).then_some(()))
+            .await;
+        assert!(connected.is_err(), "a Noise-only node connected to a TLS-only one");
+
+        for node in [tls, noise, receiver, both, mismatched] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_copy_overwritten_moments_later_is_reported_as_a_conflict() {
+        let mut remote = Node::start(&["--clipboard"]).unwrap();
+        let mut local =
+            Node::start(&["--clipboard", "--conflict-window-ms", "1000", "--connect", &remote.address.to_string()]).unwrap();
+        identified(&mut local, &[remote.peer_id]).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let sent = |event: &NodeEvent| matches!(event, NodeEvent::ClipboardSent { .. }).then_some(());
+        let conflict = |event: &NodeEvent| match event {
+            NodeEvent::Conflict { local_preview, remote_preview, .. } => Some((local_preview.clone(), remote_preview.clone())),
+            _ => None,
+        };
+
+        // Copied one after the other, well apart
+        local.clipboard.copy_text("an earlier copy");
+        local.wait_for(TIMEOUT, sent).await.unwrap();
+        applied(&mut remote).await;
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        remote.clipboard.copy_text("a later copy");
+        applied(&mut local).await;
+        assert!(local.wait_for(Duration::from_millis(500), conflict).await.is_err());
+
+        // Copied at nearly the same time
+        local.clipboard.copy_text("about to be lost");
+        local.wait_for(TIMEOUT, sent).await.unwrap();
+        // Not to be overwritten by what it receives
+        applied(&mut remote).await;
+        remote.clipboard.copy_text("the winner");
+        let (local_preview, remote_preview) = local.wait_for(TIMEOUT, conflict).await.unwrap();
+        assert!(local_preview.contains("about to be lost"), "{local_preview}");
+        assert!(remote_preview.contains("the winner"), "{remote_preview}");
+
+        for node in [remote, local] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[test]
+    fn a_peer_with_a_slow_clock_is_judged_on_ours() {
+        const BEHIND_MS: u64 = 5 * 60 * 1000;
+        let primary = PeerId::random();
+        let skewed = PeerId::random();
+        let primary_id = primary.to_string();
+        let args = Args::try_parse_from(["clipboard-sync", "--clipboard", "--primary-peer", &primary_id]).unwrap();
+
+        let mut latency = stats::PeerLatency::default();
+        latency.record_rtt(Duration::from_millis(20));
+        let now = 1_800_000_000_000;
+        for (arrived, took) in [(now, 15), (now + 1_000, 12), (now + 2_000, 30)] {
+            let corrected = latency.record_delivery(arrived - BEHIND_MS - took, arrived);
+            assert!(corrected < 100, "a {took} ms delivery took {corrected} ms after correction");
+        }
+        let offset = latency.clock_offset_ms();
+        assert!(offset.abs_diff(BEHIND_MS as i64) < 20, "offset {offset}");
+
+        // Copied well after the primary's latest, though its clock says otherwise
+        let primary_copied_at = Some((primary, now));
+        let copied = now + args.conflict_window_ms + 1_000 - BEHIND_MS;
+        assert!(!accepts_from(&args, &skewed, copied, primary_copied_at));
+        assert!(accepts_from(&args, &skewed, copied.saturating_add_signed(offset), primary_copied_at));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn other_peers_are_applied_unless_they_conflict_with_the_primary() {
+        let primary = Node::start(&["--clipboard", "--i-am-primary"]).unwrap();
+        let mut other = Node::start(&["--clipboard"]).unwrap();
+        let primary_id = primary.peer_id.to_string();
+        let mut viewer = Node::start(&[
+            "--clipboard",
+            "--primary-peer",
+            &primary_id,
+            "--connect",
+            &primary.address.to_string(),
+            "--connect",
+            &other.address.to_string(),
+        ])
+        .unwrap();
+        identified(&mut viewer, &[primary.peer_id, other.peer_id]).await;
+        // Subscriptions follow the connection, give them a moment
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // Nothing from the primary yet to conflict with
+        other.clipboard.copy_text("from another peer");
+        applied(&mut viewer).await;
+        assert_eq!(viewer.clipboard.text().as_deref(), Some("from another peer"));
+
+        primary.clipboard.copy_text("from the primary");
+        applied(&mut viewer).await;
+        // Forwarded on to the other peer too, which would replace its copy
+        applied(&mut other).await;
+        other.clipboard.copy_text("right after the primary");
+        let reason = viewer
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::ContentDropped { reason, .. } => Some(*reason),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(reason, control::DropReason::NotPrimary);
+        assert_eq!(viewer.clipboard.text().as_deref(), Some("from the primary"));
+
+        for node in [primary, other, viewer] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_loop_keeps_going_while_the_clipboard_stalls() {
+        const STALL: Duration = Duration::from_secs(3);
+        let sender = Node::start(&["--clipboard"]).unwrap();
+        let mut receiver = Node::start(&["--clipboard", "--connect", &sender.address.to_string()]).unwrap();
+        identified(&mut receiver, &[sender.peer_id]).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        receiver.clipboard.stall_writes(STALL);
+
+        sender.clipboard.copy_text("slow to land");
+        receiver.wait_for(TIMEOUT, |event| matches!(event, NodeEvent::ClipboardReceived { .. }).then_some(())).await.unwrap();
+        // A peer turning up while the write hangs is still seen to
+        let started = Instant::now();
+        let newcomer = Node::start(&["--connect", &receiver.address.to_string()]).unwrap();
+        let first = receiver
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::PeerIdentified { peer, .. } if *peer == newcomer.peer_id => Some("identified"),
+                NodeEvent::ClipboardApplied { .. } => Some("applied"),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(first, "identified");
+        assert!(started.elapsed() < STALL);
+        applied(&mut receiver).await;
+        assert_eq!(receiver.clipboard.text().as_deref(), Some("slow to land"));
+
+        for node in [sender, receiver, newcomer] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn capped_content_reaches_no_more_peers_than_the_cap() {
+        let mut sender = Node::start(&["--clipboard", "--no-peer-exchange", "--max-peers-for-clipboard", "2"]).unwrap();
+        let address = sender.address.to_string();
+        let mut receivers = Vec::new();
+        for _ in 0..3 {
+            receivers.push(Node::start(&["--clipboard", "--no-peer-exchange", "--connect", &address]).unwrap());
+        }
+        let peers: Vec<PeerId> = receivers.iter().map(|receiver| receiver.peer_id).collect();
+        identified(&mut sender, &peers).await;
+        for receiver in &mut receivers {
+            identified(receiver, &[sender.peer_id]).await;
+        }
+        // A copy from each receiver arrives after its subscription, so the
+        // sender then knows all three are on the topic. One at a time, so
+        // none is overwritten by another forwarded to it.
+        for i in 0..receivers.len() {
+            receivers[i].clipboard.copy_text(&format!("warm-up {i}"));
+            let others = receivers.iter_mut().enumerate().filter(|(j, _)| *j != i).map(|(_, node)| node);
+            for node in std::iter::once(&mut sender).chain(others) {
+                applied(node).await;
+            }
+        }
+
+        sender.clipboard.copy_text("for two devices only");
+        let sent = sender
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::ClipboardSent { preview, peers, .. } if preview.contains("two devices") => Some(*peers),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(sent, 2);
+        let mut reached = 0;
+        for receiver in &mut receivers {
+            let deadline = tokio::time::Instant::now() + Duration::from_secs(3);
+            while receiver.clipboard.text().as_deref() != Some("for two devices only") {
+                let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+                let applied = receiver
+                    .wait_for(remaining, |event| matches!(event, NodeEvent::ClipboardApplied { .. }).then_some(()))
+                    .await;
+                if applied.is_err() {
+                    break;
+                }
+            }
+            if receiver.clipboard.text().as_deref() == Some("for two devices only") {
+                reached += 1;
+            }
+        }
+        assert_eq!(reached, 2);
+
+        sender.stop().await.unwrap();
+        for receiver in receivers {
+            receiver.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn only_subscribers_of_the_topic_count_as_reached() {
+        let mut sender = Node::start(&["--clipboard", "--no-peer-exchange", "--room", "home"]).unwrap();
+        let address = sender.address.to_string();
+        let mut home = Node::start(&["--clipboard", "--no-peer-exchange", "--room", "home", "--connect", &address]).unwrap();
+        let mut work = Node::start(&["--clipboard", "--no-peer-exchange", "--room", "work", "--connect", &address]).unwrap();
+        identified(&mut sender, &[home.peer_id, work.peer_id]).await;
+        identified(&mut home, &[sender.peer_id]).await;
+        identified(&mut work, &[sender.peer_id]).await;
+        // Subscriptions follow the connection, give them a moment
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // Connected to both, but only one of them is in the room
+        sender.clipboard.copy_text("for the home room");
+        let peers = sender
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::ClipboardSent { peers, .. } => Some(*peers),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(peers, 1);
+        applied(&mut home).await;
+        assert_eq!(work.clipboard.text(), None);
+
+        for node in [sender, home, work] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_receiver_confirms_delivery_on_the_room_it_got_the_copy_in() {
+        let mut sender = Node::start(&["--clipboard", "--confirm-delivery", "--room", "office"]).unwrap();
+        let mut receiver =
+            Node::start(&["--clipboard", "--confirm-delivery", "--room", "office", "--connect", &sender.address.to_string()]).unwrap();
+        identified(&mut sender, &[receiver.peer_id]).await;
+        identified(&mut receiver, &[sender.peer_id]).await;
+        // Subscriptions follow the connection, give them a moment
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        sender.clipboard.copy_text("please confirm");
+        applied(&mut receiver).await;
+        let (peer, preview) = sender
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::DeliveryConfirmed { peer, preview } => Some((*peer, preview.clone())),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(peer, receiver.peer_id);
+        assert!(preview.contains("please confirm"), "{preview}");
+
+        for node in [sender, receiver] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_network_change_redials_the_peers_given_to_connect_to() {
+        let member = Node::start(&["--clipboard"]).unwrap();
+        let mut node = Node::start(&["--clipboard", "--connect", &member.address.to_string()]).unwrap();
+        identified(&mut node, &[member.peer_id]).await;
+
+        // The member goes away and comes back, but nothing dials it again
+        let member = member.restart().await.unwrap();
+        let member_id = member.peer_id;
+        node.wait_for(TIMEOUT, |event| matches!(event, NodeEvent::PeerDisconnected { peer, .. } if *peer == member_id).then_some(()))
+            .await
+            .unwrap();
+        let connected = |event: &NodeEvent| matches!(event, NodeEvent::PeerConnected { peer, .. } if *peer == member_id).then_some(());
+        assert!(node.wait_for(Duration::from_secs(1), connected).await.is_err(), "redialed without a network change");
+
+        node.command(control::NodeCommand::NetworkChanged);
+        node.wait_for(TIMEOUT, connected).await.unwrap();
+
+        for node in [member, node] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_relay_neither_applies_nor_forwards_an_image_over_its_limit() {
+        use clipboard::ContentType;
+
+        // Sent as copied, not shrunk to the limit the relay advertises
+        let mut sender = Node::start(&["--clipboard", "--no-peer-exchange", "--strict-size"]).unwrap();
+        let mut relay =
+            Node::start(&["--clipboard", "--no-peer-exchange", "--max-image-bytes", "1000", "--connect", &sender.address.to_string()])
+                .unwrap();
+        let mut third = Node::start(&["--clipboard", "--no-peer-exchange", "--connect", &relay.address.to_string()]).unwrap();
+        identified(&mut relay, &[sender.peer_id, third.peer_id]).await;
+        identified(&mut sender, &[relay.peer_id]).await;
+        identified(&mut third, &[relay.peer_id]).await;
+        // Subscriptions follow the connection, give them a moment
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // Noise, so it stays over the limit after compression
+        let (width, height) = (64, 64);
+        let pixels: Vec<u8> = (0..width * height * 4u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        sender.clipboard.copy_image(pixels, width as usize, height as usize);
+        let reason = relay
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::ContentDropped { content_type: ContentType::Image, reason, .. } => Some(*reason),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(reason, control::DropReason::Rejected);
+        assert!(relay.clipboard.image().is_none());
+
+        // Text sent after it gets through, so the image would have too
+        sender.clipboard.copy_text("after the image");
+        let first = third
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::ClipboardReceived { content_type, .. } => Some(*content_type),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(first, ContentType::Text);
+        applied(&mut third).await;
+        assert_eq!(third.clipboard.text().as_deref(), Some("after the image"));
+        assert!(third.clipboard.image().is_none());
+
+        for node in [sender, relay, third] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_bridge_forwards_a_published_copy_once_and_not_back() {
+        let mut sender = Node::start(&["--clipboard", "--no-peer-exchange", "--room", "office"]).unwrap();
+        let mut bridge =
+            Node::start(&["--clipboard", "--no-peer-exchange", "--bridge", "office=lab", "--connect", &sender.address.to_string()]).unwrap();
+        let mut lab = Node::start(&["--clipboard", "--no-peer-exchange", "--room", "lab", "--connect", &bridge.address.to_string()]).unwrap();
+        identified(&mut sender, &[bridge.peer_id]).await;
+        identified(&mut bridge, &[sender.peer_id, lab.peer_id]).await;
+        identified(&mut lab, &[bridge.peer_id]).await;
+        // Subscriptions follow the connection, give them a moment
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        sender.clipboard.copy_text("across the bridge once");
+        applied(&mut lab).await;
+        assert_eq!(lab.clipboard.text().as_deref(), Some("across the bridge once"));
+        let received = |event: &NodeEvent| matches!(event, NodeEvent::ClipboardReceived { .. }).then_some(());
+        assert!(lab.wait_for(Duration::from_secs(2), received).await.is_err(), "the lab received it twice");
+        assert!(sender.wait_for(Duration::from_secs(1), received).await.is_err(), "the bridge echoed it into the office");
+
+        for node in [sender, bridge, lab] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_first_copy_after_a_reconnect_does_not_wait_for_a_heartbeat() {
+        let mut sender = Node::start(&["--clipboard", "--no-peer-exchange"]).unwrap();
+        let mut receiver = Node::start(&["--clipboard", "--no-peer-exchange", "--connect", &sender.address.to_string()]).unwrap();
+        identified(&mut sender, &[receiver.peer_id]).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        sender.clipboard.copy_text("before the reconnect");
+        applied(&mut receiver).await;
+
+        let receiver_id = receiver.peer_id;
+        let mut receiver = receiver.restart().await.unwrap();
+        identified(&mut sender, &[receiver_id]).await;
+
+        // Well within the 10 s heartbeat
+        sender.clipboard.copy_text("after the reconnect");
+        let peers = sender
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::ClipboardSent { preview, peers, .. } if preview.contains("after the reconnect") => Some(*peers),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(peers, 1);
+        // The retained copy from before may be applied first
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(3);
+        while receiver.clipboard.text().as_deref() != Some("after the reconnect") {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            receiver
+                .wait_for(remaining, |event| matches!(event, NodeEvent::ClipboardApplied { .. }).then_some(()))
+                .await
+                .expect("not applied within a heartbeat");
+        }
+
+        for node in [sender, receiver] {
+            node.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_member_of_several_rooms_sends_its_copies_to_each() {
+        use clipboard::ContentType;
+
+        let mut sender = Node::start(&["--clipboard", "--no-peer-exchange", "--room", "home", "--room", "work"]).unwrap();
+        let address = sender.address.to_string();
+        let mut home = Node::start(&["--clipboard", "--no-peer-exchange", "--room", "home", "--connect", &address]).unwrap();
+        let mut work = Node::start(&["--clipboard", "--no-peer-exchange", "--room", "work", "--connect", &address]).unwrap();
+        identified(&mut sender, &[home.peer_id, work.peer_id]).await;
+        // Subscriptions follow the connection, give them a moment
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        sender.clipboard.copy_text("for both rooms");
+        for node in [&mut home, &mut work] {
+            applied(node).await;
+            assert_eq!(node.clipboard.text().as_deref(), Some("for both rooms"));
+        }
+
+        // Images go out on the bulk topic of each
+        let pixels: Vec<u8> = (0..8 * 8 * 4u32).map(|i| (i * 7) as u8).collect();
+        sender.clipboard.copy_image(pixels, 8, 8);
+        for node in [&mut home, &mut work] {
+            let received = node
+                .wait_for(TIMEOUT, |event| match event {
+                    NodeEvent::ClipboardReceived { content_type, .. } => Some(*content_type),
+                    _ => None,
+                })
+                .await
+                .unwrap();
+            assert_eq!(received, ContentType::Image);
+            applied(node).await;
+        }
+
+        for node in [sender, home, work] {
+            node.stop().await.unwrap();
+        }
+    }
+}
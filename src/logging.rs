@@ -0,0 +1,108 @@
+use anyhow::{bail, Result};
+use env_filter::Filter;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::sync::{OnceLock, RwLock};
+
+/// Filter used when `RUST_LOG` is not set
+const DEFAULT_FILTER: &str = "info";
+/// Target of the node's answers to `/log`, shown whatever the filter
+pub const REPLY_TARGET: &str = "log-filter";
+
+/// The filter given at startup, which `/log reset` goes back to
+static INITIAL: OnceLock<String> = OnceLock::new();
+/// The filter in effect and how it was written
+static ACTIVE: RwLock<Option<(String, Filter)>> = RwLock::new(None);
+
+/// An `env_logger` whose filter can be replaced while the node runs. The
+/// inner logger only formats and writes; filtering happens here.
+struct Logger {
+    inner: env_logger::Logger,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target() == REPLY_TARGET
+            || ACTIVE.read().expect("log filter lock poisoned").as_ref().is_some_and(|(_, filter)| filter.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install `builder` as the logger, filtered by `RUST_LOG` or `info`. The
+/// builder's own filter is ignored.
+pub fn init(mut builder: env_logger::Builder) -> Result<(), SetLoggerError> {
+    let spec = std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_FILTER.to_string());
+    // Lenient like env_logger itself: bad directives are reported and skipped
+    let filter = env_filter::Builder::new().parse(&spec).build();
+    let _ = INITIAL.set(spec.clone());
+    activate(spec, filter);
+    log::set_boxed_logger(Box::new(Logger { inner: builder.filter_level(LevelFilter::Trace).build() }))
+}
+
+/// Replace the filter, written like `RUST_LOG`, e.g. `debug` or
+/// `libp2p_gossipsub=trace,libp2p_clipboard_sync=debug`
+pub fn set(spec: &str) -> Result<()> {
+    let mut builder = env_filter::Builder::new();
+    if let Err(e) = builder.try_parse(spec) {
+        bail!("Invalid log filter {spec:?}: {e}");
+    }
+    activate(spec.to_string(), builder.build());
+    Ok(())
+}
+
+/// Go back to the filter given at startup
+pub fn reset() {
+    let spec = INITIAL.get().cloned().unwrap_or_else(|| DEFAULT_FILTER.to_string());
+    let filter = env_filter::Builder::new().parse(&spec).build();
+    activate(spec, filter);
+}
+
+/// The filter in effect, as written
+pub fn current() -> String {
+    ACTIVE
+        .read()
+        .expect("log filter lock poisoned")
+        .as_ref()
+        .map_or_else(|| DEFAULT_FILTER.to_string(), |(spec, _)| spec.clone())
+}
+
+fn activate(spec: String, filter: Filter) {
+    // Replies to `/log` are at info level, so those must get through
+    log::set_max_level(filter.filter().max(LevelFilter::Info));
+    *ACTIVE.write().expect("log filter lock poisoned") = Some((spec, filter));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled(logger: &Logger, target: &str, level: log::Level) -> bool {
+        logger.enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    // One test, since the filter is shared by the whole process
+    #[test]
+    fn the_filter_can_be_replaced_and_reset_at_runtime() {
+        let logger = Logger { inner: env_logger::Builder::new().build() };
+        set("info,libp2p_gossipsub=trace").unwrap();
+        assert_eq!(current(), "info,libp2p_gossipsub=trace");
+        assert!(enabled(&logger, "libp2p_gossipsub::behaviour", log::Level::Trace));
+        assert!(!enabled(&logger, "libp2p_swarm", log::Level::Debug));
+
+        assert!(set("libp2p_swarm=loud").is_err());
+        assert_eq!(current(), "info,libp2p_gossipsub=trace", "a bad filter replaced the good one");
+
+        set("error").unwrap();
+        assert!(enabled(&logger, REPLY_TARGET, log::Level::Info), "replies to /log are always shown");
+        reset();
+        assert_eq!(current(), INITIAL.get().map_or(DEFAULT_FILTER, String::as_str));
+    }
+}
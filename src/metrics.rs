@@ -0,0 +1,53 @@
+use crate::control::NodeStatus;
+use crate::stats::SharedStats;
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+/// Serve the stats registry in Prometheus format at `http://<address>/metrics`.
+/// Only clients on this machine are answered while the node is hardened.
+pub async fn serve(address: SocketAddr, stats: SharedStats, status: watch::Receiver<NodeStatus>) -> Result<()> {
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to bind metrics endpoint on {address}"))?;
+    info!("Metrics available at http://{address}/metrics");
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        if status.borrow().hardened && !peer.ip().is_loopback() {
+            debug!("Hardened mode: not answering the metrics request from {peer}");
+            continue;
+        }
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, stats).await {
+                debug!("Metrics request from {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+/// Answer a single HTTP request; only `GET /metrics` is served
+async fn respond(mut stream: TcpStream, stats: SharedStats) -> Result<()> {
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+
+    let (status, body) = if request.starts_with("GET /metrics ") {
+        let body = stats.lock().expect("stats lock poisoned").render_prometheus();
+        ("200 OK", body)
+    } else {
+        ("404 Not Found", "Not found\n".to_string())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
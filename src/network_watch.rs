@@ -0,0 +1,143 @@
+use crate::control::NodeCommand;
+use log::{debug, warn};
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How often to look at the network
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Tells when the machine moved to another network: a different Wi-Fi, a
+/// cable plugged in or pulled, a VPN coming up, waking up somewhere else
+pub trait NetworkWatcher: Send {
+    /// Whether the network changed since the last call. Called off the event
+    /// loop, so it may block briefly.
+    fn changed(&mut self) -> bool;
+}
+
+/// For when the network can't be looked at: it never changes
+#[derive(Debug, Default)]
+pub struct NoWatch;
+
+impl NetworkWatcher for NoWatch {
+    fn changed(&mut self) -> bool {
+        false
+    }
+}
+
+/// Compares the addresses of the machine's network interfaces, loopback
+/// aside, from one call to the next
+#[derive(Debug)]
+pub struct AddressScan {
+    addrs: BTreeSet<(String, IpAddr)>,
+}
+
+impl AddressScan {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self { addrs: Self::scan()? })
+    }
+
+    fn scan() -> std::io::Result<BTreeSet<(String, IpAddr)>> {
+        Ok(if_addrs::get_if_addrs()?
+            .into_iter()
+            .filter(|interface| !interface.is_loopback())
+            .map(|interface| {
+                let ip = interface.ip();
+                (interface.name, ip)
+            })
+            .collect())
+    }
+}
+
+impl NetworkWatcher for AddressScan {
+    fn changed(&mut self) -> bool {
+        match Self::scan() {
+            Ok(addrs) if addrs != self.addrs => {
+                debug!("Network interface addresses changed: {addrs:?}");
+                self.addrs = addrs;
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                debug!("Failed to list network interfaces: {e}");
+                false
+            }
+        }
+    }
+}
+
+/// The watcher for this machine
+pub fn platform_watcher() -> Box<dyn NetworkWatcher> {
+    match AddressScan::new() {
+        Ok(scan) => Box::new(scan),
+        Err(e) => {
+            warn!("Failed to list network interfaces, --watch-network has no effect: {e}");
+            Box::new(NoWatch)
+        }
+    }
+}
+
+/// Poll `watcher` in the background and report each network change as a
+/// [`NodeCommand::NetworkChanged`]. A change is reported once the network
+/// settled, so switching Wi-Fi networks (one going down, the next coming up)
+/// counts once.
+pub fn spawn(mut watcher: Box<dyn NetworkWatcher>, command_tx: mpsc::UnboundedSender<NodeCommand>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        let mut settling = false;
+        loop {
+            interval.tick().await;
+            let (returned, changed) = match tokio::task::spawn_blocking(move || {
+                let changed = watcher.changed();
+                (watcher, changed)
+            })
+            .await
+            {
+                Ok(result) => result,
+                Err(e) => {
+                    warn!("Watching the network failed, giving up: {e}");
+                    return;
+                }
+            };
+            watcher = returned;
+            if changed {
+                settling = true;
+            } else if settling {
+                settling = false;
+                if command_tx.send(NodeCommand::NetworkChanged).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Reports the changes it was given, one per call, then none
+    struct Scripted(VecDeque<bool>);
+
+    impl NetworkWatcher for Scripted {
+        fn changed(&mut self) -> bool {
+            self.0.pop_front().unwrap_or(false)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_change_is_reported_once_the_network_settled() {
+        let (command_tx, mut commands) = mpsc::unbounded_channel();
+        // Wi-Fi going down, then the next one coming up on the following poll
+        spawn(Box::new(Scripted(VecDeque::from([true, true]))), command_tx);
+
+        tokio::time::sleep(POLL_INTERVAL + POLL_INTERVAL / 2).await;
+        assert!(commands.try_recv().is_err(), "reported while still changing");
+        let command = tokio::time::timeout(POLL_INTERVAL * 2, commands.recv()).await.unwrap();
+        assert!(matches!(command, Some(NodeCommand::NetworkChanged)));
+        tokio::time::sleep(POLL_INTERVAL * 2).await;
+        assert!(commands.try_recv().is_err(), "one change reported twice");
+    }
+}
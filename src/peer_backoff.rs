@@ -0,0 +1,228 @@
+use libp2p::PeerId;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Failure counts halve over this long
+const HALF_LIFE: Duration = Duration::from_secs(60);
+/// A peer is suspended once its decayed failure count reaches this
+const THRESHOLD: f64 = 10.0;
+/// First suspension, doubled for each one after it up to `MAX_BACKOFF`
+const BASE_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// Something a peer sent that we could not use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Not a clipboard message at all
+    Malformed,
+    /// Turned down by the content policy
+    Rejected,
+    /// A file whose contents don't match the offered hash
+    HashMismatch,
+}
+
+impl Failure {
+    fn describe(self) -> &'static str {
+        match self {
+            Failure::Malformed => "malformed message",
+            Failure::Rejected => "rejected content",
+            Failure::HashMismatch => "file with the wrong checksum",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PeerState {
+    /// Failures, decayed to `updated`
+    score: f64,
+    updated: Instant,
+    last: Failure,
+    /// Times the peer was suspended, each longer than the one before
+    suspensions: u32,
+    suspended_until: Option<Instant>,
+    /// Messages dropped during the current suspension
+    dropped: u64,
+}
+
+impl PeerState {
+    fn decay(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.score *= 0.5f64.powf(elapsed / HALF_LIFE.as_secs_f64());
+        self.updated = now;
+    }
+}
+
+/// Keeps one misbehaving peer from costing a decode, a log line and a task
+/// per message it sends. Failures from each peer are counted with exponential
+/// decay, and a peer that fails too often has its clipboard messages dropped
+/// unread for a while, logging one line when that starts and one when it ends.
+///
+/// Gossipsub messages count against the peer that signed them, not the one
+/// that forwarded them, so a relaying member isn't suspended for what it
+/// passes on. Direct requests count against the peer that sent them.
+#[derive(Debug, Default)]
+pub struct PeerBackoff {
+    peers: HashMap<PeerId, PeerState>,
+}
+
+impl PeerBackoff {
+    /// Count a failure from `peer`, suspending it if that was one too many
+    pub fn record(&mut self, peer: PeerId, failure: Failure, now: Instant) {
+        let state = self.peers.entry(peer).or_insert(PeerState {
+            score: 0.0,
+            updated: now,
+            last: failure,
+            suspensions: 0,
+            suspended_until: None,
+            dropped: 0,
+        });
+        state.decay(now);
+        state.score += 1.0;
+        state.last = failure;
+        if state.suspended_until.is_none() && state.score >= THRESHOLD {
+            let backoff = BASE_BACKOFF.saturating_mul(1 << state.suspensions.min(16)).min(MAX_BACKOFF);
+            warn!(
+                "Ignoring clipboard messages from {peer} for {}s after {:.0} failures, the last a {}",
+                backoff.as_secs(),
+                state.score,
+                failure.describe()
+            );
+            state.suspensions += 1;
+            state.suspended_until = Some(now + backoff);
+            state.score = 0.0;
+            state.dropped = 0;
+        }
+    }
+
+    /// Whether to drop a message from `peer` unread. Counts the drops, and
+    /// lifts a suspension that has run out.
+    pub fn suppress(&mut self, peer: &PeerId, now: Instant) -> bool {
+        let Some(state) = self.peers.get_mut(peer) else {
+            return false;
+        };
+        match state.suspended_until {
+            Some(until) if now < until => {
+                state.dropped += 1;
+                true
+            }
+            Some(_) => {
+                info!("Accepting clipboard messages from {peer} again, {} were dropped meanwhile", state.dropped);
+                state.suspended_until = None;
+                state.updated = now;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// A connection to `peer` was established. A peer whose suspension ran
+    /// out starts over with a clean record.
+    pub fn on_connected(&mut self, peer: &PeerId, now: Instant) {
+        if self.peers.get(peer).is_some_and(|state| state.suspended_until.is_none_or(|until| now >= until)) {
+            self.peers.remove(peer);
+        }
+    }
+
+    /// Forget peers that aren't suspended and haven't failed for
+    /// `MAX_BACKOFF`, whose failures have long decayed. Otherwise every peer
+    /// that ever failed once stays on record for the life of the node.
+    pub fn prune(&mut self, now: Instant) {
+        self.peers.retain(|_, state| {
+            state.suspended_until.is_some_and(|until| now < until)
+                || now.saturating_duration_since(state.updated) < MAX_BACKOFF
+        });
+    }
+
+    /// State of `peer` for `/peers`, if it has failed recently
+    pub fn describe(&self, peer: &PeerId, now: Instant) -> Option<String> {
+        let state = self.peers.get(peer)?;
+        if let Some(until) = state.suspended_until.filter(|until| now < *until) {
+            return Some(format!(
+                "suspended for {}s more, {} messages dropped",
+                until.duration_since(now).as_secs(),
+                state.dropped
+            ));
+        }
+        let elapsed = now.saturating_duration_since(state.updated).as_secs_f64();
+        let score = state.score * 0.5f64.powf(elapsed / HALF_LIFE.as_secs_f64());
+        (score >= 0.5).then(|| format!("{score:.0} recent failures, the last a {}", state.last.describe()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fail(backoff: &mut PeerBackoff, peer: PeerId, times: usize, now: Instant) {
+        for _ in 0..times {
+            backoff.record(peer, Failure::Malformed, now);
+        }
+    }
+
+    #[test]
+    fn suspends_a_peer_after_too_many_failures_until_the_backoff_ends() {
+        let mut backoff = PeerBackoff::default();
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+        fail(&mut backoff, peer, THRESHOLD as usize - 1, now);
+        assert!(!backoff.suppress(&peer, now));
+        fail(&mut backoff, peer, 1, now);
+        assert!(backoff.suppress(&peer, now));
+        assert!(backoff.suppress(&peer, now + BASE_BACKOFF - Duration::from_secs(1)));
+        assert!(!backoff.suppress(&other, now));
+        assert!(!backoff.suppress(&peer, now + BASE_BACKOFF));
+    }
+
+    #[test]
+    fn failures_decay() {
+        let mut backoff = PeerBackoff::default();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        fail(&mut backoff, peer, THRESHOLD as usize - 1, now);
+        // Half of them are left a half-life later
+        let later = now + HALF_LIFE;
+        fail(&mut backoff, peer, 5, later);
+        assert!(!backoff.suppress(&peer, later));
+    }
+
+    #[test]
+    fn each_suspension_is_longer_than_the_last() {
+        let mut backoff = PeerBackoff::default();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        fail(&mut backoff, peer, THRESHOLD as usize, now);
+        let lifted = now + BASE_BACKOFF;
+        assert!(!backoff.suppress(&peer, lifted));
+        fail(&mut backoff, peer, THRESHOLD as usize, lifted);
+        assert!(backoff.suppress(&peer, lifted + BASE_BACKOFF));
+        assert!(!backoff.suppress(&peer, lifted + BASE_BACKOFF * 2));
+    }
+
+    #[test]
+    fn reconnecting_only_clears_a_peer_that_is_not_suspended() {
+        let mut backoff = PeerBackoff::default();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        fail(&mut backoff, peer, THRESHOLD as usize, now);
+        backoff.on_connected(&peer, now);
+        assert!(backoff.suppress(&peer, now));
+        backoff.on_connected(&peer, now + BASE_BACKOFF);
+        assert!(backoff.describe(&peer, now + BASE_BACKOFF).is_none());
+    }
+
+    #[test]
+    fn prune_keeps_suspended_and_recent_peers() {
+        let mut backoff = PeerBackoff::default();
+        let (suspended, failed, old) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let now = Instant::now();
+        fail(&mut backoff, old, 1, now);
+        let later = now + MAX_BACKOFF;
+        fail(&mut backoff, suspended, THRESHOLD as usize, later);
+        fail(&mut backoff, failed, 3, later);
+        backoff.prune(later);
+        assert_eq!(backoff.peers.len(), 2);
+        assert!(!backoff.peers.contains_key(&old));
+        assert!(backoff.describe(&failed, later).is_some_and(|state| state.starts_with("3 recent failures")));
+    }
+}
@@ -0,0 +1,84 @@
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// How often connected group members are sent our list of members
+pub const INTERVAL: Duration = Duration::from_secs(60);
+/// Most peers shared or accepted in a single exchange
+const MAX_PEERS: usize = 32;
+/// Most addresses shared or accepted per peer
+const MAX_ADDRS_PER_PEER: usize = 4;
+
+/// A group member and the addresses it listens on, as sent over the wire
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerRecord {
+    pub peer_id: String,
+    pub addrs: Vec<String>,
+}
+
+/// Group members we are connected to and have identified ourselves. Peers
+/// only learned through an exchange are never shared until we have connected
+/// to them, which keeps stale or bogus entries from spreading.
+#[derive(Debug, Default)]
+pub struct KnownPeers {
+    peers: HashMap<PeerId, Vec<Multiaddr>>,
+}
+
+impl KnownPeers {
+    /// Record the listen addresses a group member reported via identify
+    pub fn insert(&mut self, peer: PeerId, mut addrs: Vec<Multiaddr>) {
+        // Loopback addresses only help peers on the same machine, try them last
+        addrs.sort_by_key(is_loopback);
+        addrs.truncate(MAX_ADDRS_PER_PEER);
+        self.peers.insert(peer, addrs);
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.keys()
+    }
+
+    /// Records to send to `recipient`, leaving the recipient itself out
+    pub fn records_for(&self, recipient: &PeerId) -> Vec<PeerRecord> {
+        self.peers
+            .iter()
+            .filter(|(peer, addrs)| *peer != recipient && !addrs.is_empty())
+            .take(MAX_PEERS)
+            .map(|(peer, addrs)| PeerRecord {
+                peer_id: peer.to_string(),
+                addrs: addrs.iter().map(|addr| addr.to_string()).collect(),
+            })
+            .collect()
+    }
+}
+
+/// Parse received records, dropping malformed entries and anything over the caps
+pub fn parse_records(records: Vec<PeerRecord>) -> Vec<(PeerId, Vec<Multiaddr>)> {
+    records
+        .into_iter()
+        .take(MAX_PEERS)
+        .filter_map(|record| {
+            let peer = record.peer_id.parse().ok()?;
+            let addrs: Vec<Multiaddr> = record
+                .addrs
+                .iter()
+                .take(MAX_ADDRS_PER_PEER)
+                .filter_map(|addr| addr.parse().ok())
+                .collect();
+            (!addrs.is_empty()).then_some((peer, addrs))
+        })
+        .collect()
+}
+
+/// Whether `addr` only reaches this machine
+pub fn is_loopback(addr: &Multiaddr) -> bool {
+    addr.iter().any(|protocol| match protocol {
+        libp2p::multiaddr::Protocol::Ip4(ip) => ip.is_loopback(),
+        libp2p::multiaddr::Protocol::Ip6(ip) => ip.is_loopback(),
+        _ => false,
+    })
+}
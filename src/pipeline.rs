@@ -0,0 +1,670 @@
+use crate::clipboard::{now_millis, ClipboardContent, ContentType};
+use crate::crypto::{self, FieldKey};
+use crate::delivery;
+use crate::direct::DirectResponse;
+use crate::downgrade;
+use crate::image_diff::{CachedImage, ImageCache};
+use crate::queue;
+use crate::spill::SpillFile;
+use crate::timing::OpTimings;
+use libp2p::{gossipsub, request_response, PeerId};
+use log::{debug, info, warn};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Text up to this many bytes takes the priority lane
+const PRIORITY_TEXT_MAX: usize = 4 * 1024;
+/// Received messages up to this size take the priority lane. Payload bytes
+/// are serialized as numbers of up to four characters each.
+const PRIORITY_MESSAGE_MAX: usize = 8 * PRIORITY_TEXT_MAX;
+/// Text copied longer ago than this, by its author's clock, no longer
+/// supersedes anything: bulk content that late is stale anyway
+const SUPERSEDE_WINDOW_MS: u64 = 60 * 60 * 1000;
+/// Items waiting in a codec worker's input or priority lane. Once the input
+/// is full the oldest item is dropped for the newest.
+const QUEUED_ITEMS: usize = 64;
+/// Large items waiting to be serialized or decoded, or for the event loop to
+/// take them, each of which may be a whole image
+const QUEUED_BULK: usize = 4;
+
+/// Image cache shared between the event loop and the codec workers
+pub type SharedImageCache = Arc<Mutex<ImageCache>>;
+
+/// Output of a codec worker in two lanes: small text, handled right away, and
+/// everything else, serialized or decoded one at a time on a blocking thread.
+/// Order holds within each lane, and small text overtakes bulk content. A
+/// worker whose lane is full waits for the event loop to catch up.
+#[derive(Debug)]
+pub struct Lanes<T> {
+    priority: mpsc::Receiver<T>,
+    bulk: mpsc::Receiver<T>,
+}
+
+impl<T> Lanes<T> {
+    /// The next item, from the priority lane whenever it has one
+    pub async fn recv(&mut self) -> Option<T> {
+        tokio::select! {
+            biased;
+            Some(item) = self.priority.recv() => Some(item),
+            Some(item) = self.bulk.recv() => Some(item),
+            else => None,
+        }
+    }
+}
+
+fn is_priority(content: &ClipboardContent) -> bool {
+    is_priority_item(content.content_type, content.size())
+}
+
+fn is_priority_item(content_type: ContentType, size: usize) -> bool {
+    content_type == ContentType::Text && size <= PRIORITY_TEXT_MAX
+}
+
+/// A local copy, serialized and ready to publish
+#[derive(Debug)]
+pub struct Outgoing {
+    /// The content as copied, never sealed
+    pub content: ClipboardContent,
+    /// Serialized content, with the payload sealed under `--field-encryption`
+    pub data: Vec<u8>,
+    /// The content as a diff against a cached image, if that is small enough
+    /// to be worth sending. Only usable when every subscriber can rebuild it.
+    pub diffed: Option<Vec<u8>>,
+    /// SHA-256 receivers confirm the content by, if it asks for confirmations
+    pub sha256: Option<String>,
+    /// Payload size as copied, if the image was scaled down to fit
+    pub shrunk_from: Option<usize>,
+}
+
+/// How far the encoder scales down copied images, see
+/// [`downgrade::shrink_target`]
+#[derive(Debug, Clone)]
+pub struct Shrink {
+    /// Largest serialized message
+    pub limit: usize,
+    /// Largest image payload every peer takes, `usize::MAX` if none has a
+    /// limit and 0 to leave images as copied. Kept up to date by the event loop.
+    pub payload: Arc<AtomicUsize>,
+}
+
+/// Where the decoder moves large received payloads, see `--spill-threshold`
+#[derive(Debug, Clone)]
+pub struct Spill {
+    /// The payload cache directory, which adopts the files
+    pub dir: PathBuf,
+    /// Payloads larger than this many bytes are spilled, none if 0. Kept up to
+    /// date by the event loop.
+    pub threshold: Arc<AtomicUsize>,
+}
+
+/// How received clipboard content reached us
+#[derive(Debug)]
+pub enum Via {
+    /// Published on a topic, validated under this message id
+    Gossip(gossipsub::MessageId),
+    /// Sent to us alone, answered on `channel` once applied or dropped
+    Direct {
+        channel: request_response::ResponseChannel<DirectResponse>,
+        /// Copied content pushed by `/send-to` or a capped sender, never
+        /// offered on to others
+        pushed: bool,
+        /// Pushed, or answering our `get`: live content that is never too old
+        live: bool,
+    },
+}
+
+impl Via {
+    pub fn is_direct(&self) -> bool {
+        matches!(self, Via::Direct { .. })
+    }
+}
+
+/// Received clipboard content, waiting to be decoded
+#[derive(Debug)]
+pub enum Received {
+    /// A message from gossipsub
+    Gossip(PeerId, gossipsub::MessageId, gossipsub::Message),
+    /// Content `peer` sent us directly, handled as if it arrived on `topic`
+    Direct { peer: PeerId, topic: gossipsub::TopicHash, content: ClipboardContent, via: Via },
+}
+
+/// Clipboard content received over gossipsub or a direct request, decoded
+#[derive(Debug)]
+pub struct Incoming {
+    /// Peer that forwarded the content to us
+    pub propagation_source: PeerId,
+    pub via: Via,
+    pub topic: gossipsub::TopicHash,
+    /// Original author of the message, if it is signed
+    pub source: Option<PeerId>,
+    /// Serialized size of the message, or the payload size of direct content
+    pub size: usize,
+    /// When the message reached the node, in ms since the Unix epoch
+    pub arrived_ms: u64,
+    /// The content, rebuilt into the full image if it was sent as a diff
+    pub content: ClipboardContent,
+    /// Why a diff could not be rebuilt. `content` is still the diff then.
+    pub missing_base: Option<anyhow::Error>,
+    /// [`payload_hash`] of the content, taken before it was spilled
+    pub hash: u64,
+    /// The payload, moved out of `content` into a file for the payload cache
+    /// to adopt
+    pub spilled: Option<SpillFile>,
+    /// The full image, hashed for the image cache, which only takes it once
+    /// the content is accepted
+    pub image: Option<CachedImage>,
+}
+
+/// What the decoder makes of a received message
+#[derive(Debug)]
+pub enum Decoded {
+    Content(Box<Incoming>),
+    /// Not clipboard content at all, to be rejected
+    Malformed {
+        propagation_source: PeerId,
+        message_id: gossipsub::MessageId,
+        /// Peer that signed it
+        source: Option<PeerId>,
+    },
+    /// Left undecoded to make room for newer content, to be ignored
+    Dropped(Box<Received>),
+}
+
+/// Serialize local copies off the event loop. Small text is serialized as it
+/// comes in and never waits for an image. Bulk copies come out in the order
+/// they went in, except that one copied before the latest small text is
+/// dropped rather than published after it, as is one copied over before it
+/// was serialized. With a `field_key` the payloads are sealed before
+/// serializing.
+pub fn spawn_encoder(
+    image_cache: SharedImageCache,
+    timings: Arc<OpTimings>,
+    field_key: Option<FieldKey>,
+    shrink: Shrink,
+) -> (queue::Sender<ClipboardContent>, Lanes<Outgoing>) {
+    let (input_tx, mut input_rx) = queue::channel::<ClipboardContent>(QUEUED_ITEMS);
+    let (priority_tx, priority) = mpsc::channel(QUEUED_ITEMS);
+    let (bulk_input_tx, mut bulk_input_rx) = queue::channel::<ClipboardContent>(QUEUED_BULK);
+    let (bulk_tx, bulk) = mpsc::channel(QUEUED_BULK);
+    // Copy time of the latest small text, which supersedes older bulk copies
+    let latest_text = Arc::new(AtomicU64::new(0));
+
+    let superseded = {
+        let latest_text = latest_text.clone();
+        move |content: &ClipboardContent| {
+            let superseded = content.timestamp < latest_text.load(Ordering::Relaxed);
+            if superseded {
+                debug!("Not publishing the {:?} content: text copied after it went out first", content.content_type);
+            }
+            superseded
+        }
+    };
+    let bulk_field_key = field_key.clone();
+    tokio::spawn(async move {
+        while let Some(content) = bulk_input_rx.recv().await {
+            if superseded(&content) {
+                continue;
+            }
+            let image_cache = image_cache.clone();
+            let timings = timings.clone();
+            let field_key = bulk_field_key.clone();
+            let shrink = shrink.clone();
+            let encoded = tokio::task::spawn_blocking(move || {
+                encode(content, &image_cache, &timings, field_key.as_ref(), &shrink)
+            })
+            .await;
+            match encoded {
+                Ok(Some(outgoing)) => {
+                    if !superseded(&outgoing.content) && bulk_tx.send(outgoing).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Clipboard encoder task failed: {e}"),
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(content) = input_rx.recv().await {
+            if !is_priority(&content) {
+                match bulk_input_tx.send(content) {
+                    Ok(Some(dropped)) => {
+                        debug!("Not publishing the {:?} content: copied over before it could be serialized", dropped.content_type);
+                    }
+                    Ok(None) => {}
+                    Err(_) => break,
+                }
+                continue;
+            }
+            latest_text.fetch_max(content.timestamp, Ordering::Relaxed);
+            // Small text serializes in microseconds, and has no image to diff
+            match serde_json::to_vec(&crypto::sealed(content.clone(), field_key.as_ref())) {
+                Ok(data) => {
+                    let sha256 = confirmation_hash(&content);
+                    if priority_tx.send(Outgoing { content, data, diffed: None, sha256, shrunk_from: None }).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!("Failed to serialize clipboard content: {e}"),
+            }
+        }
+    });
+    (input_tx, Lanes { priority, bulk })
+}
+
+fn encode(
+    mut content: ClipboardContent,
+    image_cache: &SharedImageCache,
+    timings: &OpTimings,
+    field_key: Option<&FieldKey>,
+    shrink: &Shrink,
+) -> Option<Outgoing> {
+    let serialize = |content: &ClipboardContent| {
+        timings
+            .time("serialize", content.size(), || serde_json::to_vec(&crypto::sealed(content.clone(), field_key)))
+            .inspect_err(|e| warn!("Failed to serialize clipboard content: {e}"))
+            .ok()
+    };
+    let mut data = serialize(&content)?;
+    // An image too large for the mesh or for some peer is scaled down here
+    // rather than on the event loop, it takes a while
+    let mut shrunk_from = None;
+    if let Some(payload) =
+        downgrade::shrink_target(&content, data.len(), shrink.limit, shrink.payload.load(Ordering::Relaxed))
+    {
+        match timings.time("image_shrink", content.size(), || downgrade::shrink(&content, payload, shrink.limit)) {
+            Ok(Some(shrunk)) => {
+                info!(
+                    "Copied image is too large to send as is, scaled it down to {}x{} ({} bytes)",
+                    shrunk.width.unwrap_or(0),
+                    shrunk.height.unwrap_or(0),
+                    shrunk.size()
+                );
+                shrunk_from = Some(content.size());
+                content = shrunk;
+                data = serialize(&content)?;
+            }
+            Ok(None) => info!("Copied image is too large to send and can't be scaled down far enough"),
+            Err(e) => warn!("Failed to scale down the copied image: {e:#}"),
+        }
+    }
+    let snapshot = image_cache.lock().expect("image cache lock poisoned").clone();
+    let diffed = content.image().and_then(|image| {
+        timings
+            .time("image_diff_encode", image.len(), || snapshot.diff(&content))
+            .and_then(|diffed| serde_json::to_vec(&crypto::sealed(diffed, field_key)).ok())
+    });
+    image_cache.lock().expect("image cache lock poisoned").insert(&content);
+    let sha256 = confirmation_hash(&content);
+    Some(Outgoing { content, data, diffed, sha256, shrunk_from })
+}
+
+/// Salted hash of `content` to match delivery confirmations against, if it
+/// asks for them
+fn confirmation_hash(content: &ClipboardContent) -> Option<String> {
+    let nonce = content.confirm_nonce.as_ref()?;
+    let sha256 = content.sha256().inspect_err(|e| warn!("Failed to hash clipboard content: {e:#}")).ok()?;
+    Some(delivery::salted(nonce, &sha256))
+}
+
+/// Received content waiting to be decoded, with its arrival time
+type Undecoded = (Received, u64);
+
+/// Decode received clipboard content off the event loop, rebuilding image
+/// diffs. Small content is decoded as it comes in and overtakes large content,
+/// which comes out in the order it arrived. Sealed payloads are opened with
+/// `field_key`, and stay sealed without it. With `spill`, payloads over its
+/// threshold are written to disk on the blocking thread that decoded them.
+/// Direct content takes the same way, so it is handled like the rest. When
+/// large content arrives faster than it decodes, the oldest waiting is
+/// handed back undecoded. Nothing goes into `image_cache` here: received
+/// images come out hashed, for the event loop to cache once it accepts them.
+pub fn spawn_decoder(
+    image_cache: SharedImageCache,
+    timings: Arc<OpTimings>,
+    field_key: Option<FieldKey>,
+    spill: Option<Spill>,
+) -> (queue::Sender<Received>, Lanes<Decoded>) {
+    let (input_tx, mut input_rx) = queue::channel::<Received>(QUEUED_ITEMS);
+    let (priority_tx, priority) = mpsc::channel(QUEUED_ITEMS);
+    let (bulk_input_tx, mut bulk_input_rx) = queue::channel::<Undecoded>(QUEUED_BULK);
+    let (bulk_tx, bulk) = mpsc::channel(QUEUED_BULK);
+    {
+        let image_cache = image_cache.clone();
+        let timings = timings.clone();
+        let field_key = field_key.clone();
+        let spill = spill.clone();
+        tokio::spawn(async move {
+            while let Some((received, arrived_ms)) = bulk_input_rx.recv().await {
+                let image_cache = image_cache.clone();
+                let timings = timings.clone();
+                let field_key = field_key.clone();
+                let spill = spill.clone();
+                let decoded = tokio::task::spawn_blocking(move || {
+                    let mut decoded = decode(received, arrived_ms, &image_cache, &timings, field_key.as_ref());
+                    if let (Decoded::Content(incoming), Some(spill)) = (&mut decoded, spill) {
+                        spill_payload(incoming, &spill);
+                    }
+                    decoded
+                })
+                .await;
+                match decoded {
+                    Ok(decoded) => {
+                        if bulk_tx.send(decoded).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => warn!("Clipboard decoder task failed: {e}"),
+                }
+            }
+        });
+    }
+    tokio::spawn(async move {
+        while let Some(received) = input_rx.recv().await {
+            let arrived_ms = now_millis();
+            let (size, bulk) = match received {
+                Received::Gossip(_, _, ref message) => (message.data.len(), message.data.len() > PRIORITY_MESSAGE_MAX),
+                Received::Direct { ref content, .. } => (content.size(), !is_priority(content)),
+            };
+            // Anything that may be spilled is decoded on a blocking thread
+            let spills = spill.as_ref().map(|spill| spill.threshold.load(Ordering::Relaxed)).is_some_and(|threshold| {
+                threshold > 0 && size > threshold
+            });
+            let decoded = if bulk || spills {
+                match bulk_input_tx.send((received, arrived_ms)) {
+                    Ok(Some((dropped, _))) => Decoded::Dropped(Box::new(dropped)),
+                    Ok(None) => continue,
+                    Err(_) => break,
+                }
+            } else {
+                decode(received, arrived_ms, &image_cache, &timings, field_key.as_ref())
+            };
+            if priority_tx.send(decoded).await.is_err() {
+                break;
+            }
+        }
+    });
+    (input_tx, Lanes { priority, bulk })
+}
+
+/// Move the payload of `incoming` to disk if it is over the threshold and
+/// going to be applied. Sealed content and unrebuilt diffs are only ever
+/// forwarded or dropped, so they stay as they came.
+fn spill_payload(incoming: &mut Incoming, spill: &Spill) {
+    let threshold = spill.threshold.load(Ordering::Relaxed);
+    let content = &mut incoming.content;
+    if threshold == 0 || content.data.len() <= threshold || content.sealed || incoming.missing_base.is_some() {
+        return;
+    }
+    match SpillFile::write(&spill.dir, &content.data) {
+        Ok(file) => {
+            content.data = Vec::new();
+            incoming.spilled = Some(file);
+        }
+        Err(e) => warn!("Failed to spill large clipboard payload, keeping it in memory: {e:?}"),
+    }
+}
+
+/// Hash identifying clipboard content by its payload, taken before it is spilled
+pub fn payload_hash(content: &ClipboardContent) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Bulk content received after newer small text from the same author, which
+/// must not replace that text on the clipboard
+#[derive(Debug, Default)]
+pub struct Superseded {
+    latest_text: HashMap<PeerId, u64>,
+}
+
+impl Superseded {
+    /// Whether `content` from `origin`, timestamped by the origin's clock,
+    /// was overtaken by newer text. Small text is remembered as the newest.
+    pub fn check(&mut self, origin: PeerId, content: &ClipboardContent) -> bool {
+        self.check_item(origin, content.content_type, content.size(), content.timestamp)
+    }
+
+    /// [`check`](Self::check) on what a trace recorded about the content
+    pub fn check_item(&mut self, origin: PeerId, content_type: ContentType, size: usize, timestamp: u64) -> bool {
+        if is_priority_item(content_type, size) {
+            let latest = self.latest_text.entry(origin).or_default();
+            *latest = (*latest).max(timestamp);
+            return false;
+        }
+        self.latest_text.get(&origin).is_some_and(|latest| timestamp < *latest)
+    }
+
+    /// Forget authors whose latest text is older than `SUPERSEDE_WINDOW_MS`,
+    /// so authors that left the group don't pile up
+    pub fn prune(&mut self, now_ms: u64) {
+        self.latest_text.retain(|_, latest| now_ms.saturating_sub(*latest) < SUPERSEDE_WINDOW_MS);
+    }
+}
+
+fn decode(
+    received: Received,
+    arrived_ms: u64,
+    image_cache: &SharedImageCache,
+    timings: &OpTimings,
+    field_key: Option<&FieldKey>,
+) -> Decoded {
+    let (propagation_source, via, topic, source, size, mut content) = match received {
+        Received::Gossip(propagation_source, message_id, message) => {
+            let size = message.data.len();
+            match timings.time("deserialize", size, || serde_json::from_slice::<ClipboardContent>(&message.data)) {
+                Ok(content) => (propagation_source, Via::Gossip(message_id), message.topic, message.source, size, content),
+                Err(e) => {
+                    debug!("Ignoring malformed clipboard message from {propagation_source}: {e}");
+                    return Decoded::Malformed { propagation_source, message_id, source: message.source };
+                }
+            }
+        }
+        Received::Direct { peer, topic, content, via } => (peer, via, topic, Some(peer), content.size(), content),
+    };
+    // Content sealed under another key stays sealed, to be forwarded but not applied
+    if let Some(key) = field_key
+        && let Err(e) = crypto::open_field(&mut content, key)
+    {
+        debug!("Can't open clipboard content from {propagation_source}: {e}");
+    }
+    let (content, missing_base) = if content.sealed {
+        (content, None)
+    } else if content.diff_base.is_some() {
+        let snapshot = image_cache.lock().expect("image cache lock poisoned").clone();
+        match timings.time("image_diff_decode", content.data.len(), || snapshot.reconstruct(content.clone())) {
+            Ok(full) => (full, None),
+            Err(e) => (content, Some(e)),
+        }
+    } else {
+        (content, None)
+    };
+    // Hashed here, but only cached once the event loop accepted the content,
+    // so peers it drops can't push the bases of others out of the cache
+    let image = if missing_base.is_none() && !content.sealed { CachedImage::of(&content) } else { None };
+    Decoded::Content(Box::new(Incoming {
+        propagation_source,
+        via,
+        topic,
+        source,
+        size,
+        arrived_ms,
+        hash: payload_hash(&content),
+        content,
+        missing_base,
+        spilled: None,
+        image,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn text(timestamp: u64) -> ClipboardContent {
+        ClipboardContent { timestamp, ..ClipboardContent::new_text("small".to_string()) }
+    }
+
+    fn image(timestamp: u64) -> ClipboardContent {
+        ClipboardContent { timestamp, ..ClipboardContent::new_image(vec![7; 128 * 128 * 4], 128, 128) }
+    }
+
+    fn encoder() -> (queue::Sender<ClipboardContent>, Lanes<Outgoing>) {
+        let shrink = Shrink { limit: usize::MAX, payload: Arc::new(AtomicUsize::new(0)) };
+        spawn_encoder(SharedImageCache::default(), Arc::new(OpTimings::new(0)), None, shrink)
+    }
+
+    async fn next<T>(lanes: &mut Lanes<T>) -> T {
+        tokio::time::timeout(Duration::from_secs(5), lanes.recv()).await.expect("nothing came out").unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_priority_lane_goes_first() {
+        let (priority_tx, priority) = mpsc::channel(QUEUED_ITEMS);
+        let (bulk_tx, bulk) = mpsc::channel(QUEUED_BULK);
+        let mut lanes = Lanes { priority, bulk };
+        bulk_tx.send(1).await.unwrap();
+        bulk_tx.send(2).await.unwrap();
+        priority_tx.send(3).await.unwrap();
+        assert_eq!(next(&mut lanes).await, 3);
+        assert_eq!(next(&mut lanes).await, 1);
+        assert_eq!(next(&mut lanes).await, 2);
+        drop((priority_tx, bulk_tx));
+        assert!(lanes.recv().await.is_none());
+    }
+
+    #[test]
+    fn small_text_supersedes_older_bulk_content_from_the_same_author() {
+        let mut superseded = Superseded::default();
+        let (author, other) = (PeerId::random(), PeerId::random());
+        assert!(!superseded.check(author, &text(100)));
+        assert!(superseded.check(author, &image(50)));
+        assert!(!superseded.check(author, &image(150)));
+        assert!(!superseded.check(other, &image(50)));
+        // Long text takes the bulk lane itself
+        let long = ClipboardContent { timestamp: 50, ..ClipboardContent::new_text("x".repeat(PRIORITY_TEXT_MAX + 1)) };
+        assert!(superseded.check(author, &long));
+    }
+
+    #[test]
+    fn pruning_forgets_authors_whose_text_is_old() {
+        let mut superseded = Superseded::default();
+        let author = PeerId::random();
+        superseded.check(author, &text(1000));
+        superseded.prune(1000 + SUPERSEDE_WINDOW_MS - 1);
+        assert!(superseded.check(author, &image(500)));
+        superseded.prune(1000 + SUPERSEDE_WINDOW_MS);
+        assert!(!superseded.check(author, &image(500)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn small_text_overtakes_an_image_in_the_encoder() {
+        let (encoder, mut encoded) = encoder();
+        encoder.send(image(100)).unwrap();
+        encoder.send(text(100)).unwrap();
+        assert_eq!(next(&mut encoded).await.content.content_type, ContentType::Text);
+        assert_eq!(next(&mut encoded).await.content.content_type, ContentType::Image);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn what_the_encoder_sends_the_decoder_rebuilds() {
+        let (encoder, mut encoded) = encoder();
+        let (decoder, mut decoded) =
+            spawn_decoder(SharedImageCache::default(), Arc::new(OpTimings::new(0)), None, None);
+        let author = PeerId::random();
+        for content in [text(1), image(2)] {
+            encoder.send(content.clone()).unwrap();
+            let message = gossipsub::Message {
+                source: Some(author),
+                data: next(&mut encoded).await.data,
+                sequence_number: None,
+                topic: gossipsub::IdentTopic::new("test").hash(),
+            };
+            decoder.send(Received::Gossip(author, gossipsub::MessageId::from("id"), message)).unwrap();
+            let Decoded::Content(incoming) = next(&mut decoded).await else {
+                panic!("malformed");
+            };
+            assert_eq!(incoming.source, Some(author));
+            assert_eq!((incoming.content.content_type, &incoming.content.data), (content.content_type, &content.data));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_decoder_spills_payloads_over_the_threshold() {
+        let dir = crate::testing::TempDir::new();
+        let spill = Spill { dir: dir.path().to_path_buf(), threshold: Arc::new(AtomicUsize::new(1024)) };
+        let (encoder, mut encoded) = encoder();
+        let (decoder, mut decoded) =
+            spawn_decoder(SharedImageCache::default(), Arc::new(OpTimings::new(0)), None, Some(spill));
+        let author = PeerId::random();
+        for content in [text(1), image(2)] {
+            encoder.send(content.clone()).unwrap();
+            let message = gossipsub::Message {
+                source: Some(author),
+                data: next(&mut encoded).await.data,
+                sequence_number: None,
+                topic: gossipsub::IdentTopic::new("test").hash(),
+            };
+            decoder.send(Received::Gossip(author, gossipsub::MessageId::from("id"), message)).unwrap();
+            let Decoded::Content(incoming) = next(&mut decoded).await else {
+                panic!("malformed");
+            };
+            assert_eq!(incoming.hash, payload_hash(&content));
+            match incoming.spilled {
+                Some(ref file) => {
+                    assert_eq!(content.content_type, ContentType::Image);
+                    assert!(incoming.content.data.is_empty());
+                    assert_eq!(file.read().unwrap(), content.data);
+                }
+                None => {
+                    assert_eq!(content.content_type, ContentType::Text);
+                    assert_eq!(incoming.content.data, content.data);
+                }
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn received_images_are_only_cached_once_accepted() {
+        let image_cache = SharedImageCache::default();
+        let (decoder, mut decoded) = spawn_decoder(image_cache.clone(), Arc::new(OpTimings::new(0)), None, None);
+        let author = PeerId::random();
+        let message = gossipsub::Message {
+            source: Some(author),
+            data: serde_json::to_vec(&image(1)).unwrap(),
+            sequence_number: None,
+            topic: gossipsub::IdentTopic::new("test").hash(),
+        };
+        decoder.send(Received::Gossip(author, gossipsub::MessageId::from("id"), message)).unwrap();
+        let Decoded::Content(incoming) = next(&mut decoded).await else {
+            panic!("malformed");
+        };
+        let mut next_copy = image(2);
+        next_copy.data[0] ^= 1;
+        assert!(image_cache.lock().unwrap().diff(&next_copy).is_none());
+        image_cache.lock().unwrap().add(incoming.image.expect("a full image is hashed for the cache"));
+        assert!(image_cache.lock().unwrap().diff(&next_copy).is_some());
+    }
+
+    #[tokio::test]
+    async fn garbage_is_malformed() {
+        let (decoder, mut decoded) =
+            spawn_decoder(SharedImageCache::default(), Arc::new(OpTimings::new(0)), None, None);
+        let author = PeerId::random();
+        let message = gossipsub::Message {
+            source: Some(author),
+            data: b"not json".to_vec(),
+            sequence_number: None,
+            topic: gossipsub::IdentTopic::new("test").hash(),
+        };
+        decoder.send(Received::Gossip(author, gossipsub::MessageId::from("id"), message)).unwrap();
+        assert!(matches!(next(&mut decoded).await, Decoded::Malformed { source: Some(source), .. } if source == author));
+    }
+}
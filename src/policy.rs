@@ -0,0 +1,94 @@
+use crate::clipboard::{ClipboardContent, ContentType};
+use crate::Args;
+use libp2p::gossipsub::MessageAcceptance;
+use libp2p::PeerId;
+
+/// What to do with clipboard content received from a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationDecision {
+    /// Apply it and forward it to other peers
+    Accept,
+    /// Drop it and penalize the peer that authored it
+    Reject(String),
+}
+
+impl ValidationDecision {
+    /// The verdict reported to gossipsub, which forwards accepted messages
+    /// and scores down the peer a rejected one came from. A message
+    /// `relayed` by a peer other than its author is only ignored, so a relay
+    /// isn't penalized for content it merely forwarded. The peers the author
+    /// sent it to reject it.
+    pub fn acceptance(&self, relayed: bool) -> MessageAcceptance {
+        match self {
+            ValidationDecision::Accept => MessageAcceptance::Accept,
+            ValidationDecision::Reject(_) if relayed => MessageAcceptance::Ignore,
+            ValidationDecision::Reject(_) => MessageAcceptance::Reject,
+        }
+    }
+}
+
+/// One rule, given the content and the peer that authored it
+pub type Validator = Box<dyn Fn(&ClipboardContent, PeerId) -> ValidationDecision + Send + Sync>;
+
+/// Policy for received clipboard content, checked once per item whether it
+/// arrives over gossipsub, where it decides what is forwarded, or as a direct
+/// request. Validators run in order and the first one not accepting decides.
+#[derive(Default)]
+pub struct Policy {
+    validators: Vec<Validator>,
+}
+
+impl Policy {
+    /// The rules set with `--max-image-bytes` and `--reject-text-containing`,
+    /// with the image limit tightened in hardened mode
+    pub fn from_args(args: &Args) -> Self {
+        let mut policy = Self::default();
+        let max_image_bytes = crate::hardened::max_image_bytes(args);
+        if max_image_bytes > 0 {
+            policy.add(self::max_image_bytes(max_image_bytes));
+        }
+        if !args.reject_text_containing.is_empty() {
+            policy.add(reject_text_containing(args.reject_text_containing.clone()));
+        }
+        policy
+    }
+
+    pub fn add(&mut self, validator: Validator) {
+        self.validators.push(validator);
+    }
+
+    pub fn validate(&self, content: &ClipboardContent, from: PeerId) -> ValidationDecision {
+        self.validators
+            .iter()
+            .map(|validator| validator(content, from))
+            .find(|decision| *decision != ValidationDecision::Accept)
+            .unwrap_or(ValidationDecision::Accept)
+    }
+}
+
+/// Reject images whose payload is larger than `max` bytes
+pub fn max_image_bytes(max: usize) -> Validator {
+    Box::new(move |content, _| {
+        if content.content_type == ContentType::Image && content.size() > max {
+            ValidationDecision::Reject(format!("image of {} bytes exceeds the {max} byte limit", content.size()))
+        } else {
+            ValidationDecision::Accept
+        }
+    })
+}
+
+/// Reject text containing any of `patterns`, ignoring case
+pub fn reject_text_containing(patterns: Vec<String>) -> Validator {
+    let patterns: Vec<String> = patterns.into_iter().map(|pattern| pattern.to_lowercase()).collect();
+    Box::new(move |content, _| {
+        let Some(text) = content.text() else {
+            return ValidationDecision::Accept;
+        };
+        let text = text.to_lowercase();
+        match patterns.iter().find(|pattern| text.contains(pattern.as_str())) {
+            // The pattern may itself be sensitive, so it is not repeated
+            Some(_) => ValidationDecision::Reject("text matches a --reject-text-containing rule".to_string()),
+            None => ValidationDecision::Accept,
+        }
+    })
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+/// How the clipboard monitor spaces its reads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Schedule {
+    /// Interval right after the clipboard changed
+    pub min: Duration,
+    /// Interval the monitor backs off to while the clipboard stays the same.
+    /// Below `min` it polls at `min` throughout.
+    pub max: Duration,
+    /// Factor the interval grows by with every read that finds nothing new
+    pub backoff: f32,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self { min: Duration::from_millis(250), max: Duration::from_secs(2), backoff: 1.5 }
+    }
+}
+
+/// Poll interval that is short while the user is copying and grows while
+/// the clipboard is idle, to spend less CPU and battery on reads that find
+/// nothing. A change is noticed up to `max` later, so content copied and
+/// replaced again before the next read is never seen.
+#[derive(Debug)]
+pub struct AdaptiveInterval {
+    schedule: Schedule,
+    current: Duration,
+}
+
+impl AdaptiveInterval {
+    pub fn new(schedule: Schedule) -> Self {
+        Self { schedule, current: schedule.min }
+    }
+
+    /// How long to wait before the next read
+    pub fn next(&self) -> Duration {
+        self.current
+    }
+
+    /// The last read found a change: poll quickly again
+    pub fn changed(&mut self) {
+        self.current = self.schedule.min;
+    }
+
+    /// The last read found nothing new: back off a step
+    pub fn idle(&mut self) {
+        // Past what a Duration holds the interval is at `max` anyway
+        let next = Duration::try_from_secs_f64(self.current.as_secs_f64() * f64::from(self.schedule.backoff)).unwrap_or(self.schedule.max);
+        self.current = self.clamp(next);
+    }
+
+    /// Switch to `schedule`, keeping the current interval within its bounds
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.schedule = schedule;
+        self.current = self.clamp(self.current);
+    }
+
+    fn clamp(&self, interval: Duration) -> Duration {
+        interval.min(self.schedule.max).max(self.schedule.min)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEDULE: Schedule = Schedule { min: Duration::from_millis(100), max: Duration::from_millis(400), backoff: 2.0 };
+
+    #[test]
+    fn backs_off_while_idle_up_to_max() {
+        let mut interval = AdaptiveInterval::new(SCHEDULE);
+        assert_eq!(interval.next(), SCHEDULE.min);
+        interval.idle();
+        assert_eq!(interval.next(), Duration::from_millis(200));
+        interval.idle();
+        interval.idle();
+        assert_eq!(interval.next(), SCHEDULE.max);
+    }
+
+    #[test]
+    fn a_change_resets_to_min() {
+        let mut interval = AdaptiveInterval::new(SCHEDULE);
+        interval.idle();
+        interval.idle();
+        interval.changed();
+        assert_eq!(interval.next(), SCHEDULE.min);
+    }
+
+    #[test]
+    fn a_new_schedule_clamps_the_current_interval() {
+        let mut interval = AdaptiveInterval::new(SCHEDULE);
+        interval.idle();
+        interval.idle();
+        interval.set_schedule(Schedule { max: Duration::from_millis(150), ..SCHEDULE });
+        assert_eq!(interval.next(), Duration::from_millis(150));
+        interval.set_schedule(Schedule { min: Duration::from_millis(300), max: Duration::from_millis(300), ..SCHEDULE });
+        assert_eq!(interval.next(), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn huge_factors_and_intervals_stay_at_max() {
+        let schedule = Schedule { min: Duration::from_secs(1), max: Duration::MAX, backoff: f32::MAX };
+        let mut interval = AdaptiveInterval::new(schedule);
+        interval.idle();
+        interval.idle();
+        assert_eq!(interval.next(), Duration::MAX);
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the application directory under the user's config home
+const APP_DIR: &str = "clipboard-sync";
+/// File holding the profile's persistent identity key
+pub const IDENTITY_FILE: &str = "identity.key";
+/// Directory for large payloads temporarily moved out of memory
+const SPILL_DIR: &str = "spill";
+/// File holding the persisted address book
+pub const ADDRESS_BOOK_FILE: &str = "peers.json";
+/// Socket the running node listens on for local clients such as `get`
+const CONTROL_SOCKET_FILE: &str = "control.sock";
+
+/// A named set of on-disk state (identity, settings) under
+/// `~/.config/clipboard-sync/<name>/`
+#[derive(Debug, Clone)]
+pub struct Profile {
+    name: String,
+    dir: PathBuf,
+}
+
+impl Profile {
+    /// Open a profile, creating its directory on first use
+    pub fn open(name: &str) -> Result<Self> {
+        validate_name(name)?;
+        let dir = base_dir()?.join(name);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create profile directory {}", dir.display()))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            dir,
+        })
+    }
+
+    /// List the names of all existing profiles
+    pub fn list() -> Result<Vec<String>> {
+        let base = base_dir()?;
+        if !base.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&base).with_context(|| format!("Failed to read {}", base.display()))? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir()
+                && let Some(name) = entry.file_name().to_str()
+                && validate_name(name).is_ok()
+            {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// A profile kept in `dir`, for tests
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn at(dir: &Path) -> Self {
+        fs::create_dir_all(dir).expect("failed to create profile directory");
+        Self { name: "test".to_string(), dir: dir.to_path_buf() }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the profile's identity key
+    pub fn identity_path(&self) -> PathBuf {
+        self.dir.join(IDENTITY_FILE)
+    }
+
+    /// Path of the profile's address book
+    pub fn address_book_path(&self) -> PathBuf {
+        self.dir.join(ADDRESS_BOOK_FILE)
+    }
+
+    /// Directory for payloads spilled to disk
+    pub fn spill_dir(&self) -> PathBuf {
+        self.dir.join(SPILL_DIR)
+    }
+
+    /// Socket of the node running with this profile
+    pub fn control_socket_path(&self) -> PathBuf {
+        self.dir.join(CONTROL_SOCKET_FILE)
+    }
+}
+
+/// Socket of a node running without a profile, next to the profiles so it
+/// is private to the user
+pub fn default_control_socket_path() -> Result<PathBuf> {
+    let base = base_dir()?;
+    fs::create_dir_all(&base).with_context(|| format!("Failed to create {}", base.display()))?;
+    Ok(base.join(CONTROL_SOCKET_FILE))
+}
+
+/// Directory containing every profile
+fn base_dir() -> Result<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .context("Could not determine the user config directory")?;
+    Ok(config_home.join(APP_DIR))
+}
+
+/// Profile names become directory names, so keep them to a safe charset
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.len() > 64
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Invalid profile name {name:?}: use 1-64 letters, digits, '-' or '_'");
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+use crate::control::NodeEvent;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Transfers smaller than this finish too quickly to be worth reporting
+pub const MIN_SIZE: u64 = 1024 * 1024;
+/// Least time between two progress events of one transfer
+pub const INTERVAL: Duration = Duration::from_millis(500);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Bytes moved so far of one long transfer, turned into
+/// [`NodeEvent::TransferProgress`] and [`NodeEvent::TransferFinished`]
+/// events. Anything moving a known number of bytes in steps can use it.
+#[derive(Debug)]
+pub struct Progress {
+    /// Tells concurrent transfers apart in events, unique for this run
+    id: u64,
+    label: String,
+    total: u64,
+    done: u64,
+    /// `done` when timing started, so a resumed transfer's rate only counts
+    /// what moved since
+    base: u64,
+    started: Instant,
+    reported: Option<Instant>,
+}
+
+impl Progress {
+    /// A transfer of `total` bytes called `label`, `done` of them already
+    /// there. Labels often name what a peer sent, so control characters are
+    /// dropped before they can reach a terminal.
+    pub fn new(label: impl Into<String>, total: u64, done: u64) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            label: label.into().chars().filter(|c| !c.is_control()).collect(),
+            total,
+            done,
+            base: done,
+            started: Instant::now(),
+            reported: None,
+        }
+    }
+
+    /// Start timing over, e.g. when an interrupted transfer resumes
+    pub fn restart(&mut self) {
+        self.base = self.done;
+        self.started = Instant::now();
+        self.reported = None;
+    }
+
+    /// Count `bytes` more as moved. Returns an event at most every
+    /// [`INTERVAL`], and never for transfers under [`MIN_SIZE`].
+    pub fn advance(&mut self, bytes: u64) -> Option<NodeEvent> {
+        self.done = (self.done + bytes).min(self.total);
+        if self.total < MIN_SIZE || self.done == self.total {
+            return None;
+        }
+        let now = Instant::now();
+        if self.reported.is_some_and(|reported| now.duration_since(reported) < INTERVAL) {
+            return None;
+        }
+        self.reported = Some(now);
+        let rate = self.rate();
+        Some(NodeEvent::TransferProgress {
+            id: self.id,
+            label: self.label.clone(),
+            done: self.done,
+            total: self.total,
+            rate,
+            eta_secs: (rate > 0).then(|| (self.total - self.done).div_ceil(rate)),
+        })
+    }
+
+    /// The summary event once the transfer completed, or failed with `error`
+    pub fn finish(&self, error: Option<String>) -> Option<NodeEvent> {
+        (self.total >= MIN_SIZE).then(|| NodeEvent::TransferFinished {
+            id: self.id,
+            label: self.label.clone(),
+            bytes: self.done - self.base,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            error,
+        })
+    }
+
+    /// The summary event of a transfer whose bytes all arrived at once
+    pub fn complete(&mut self) -> Option<NodeEvent> {
+        self.done = self.total;
+        self.finish(None)
+    }
+
+    /// Bytes per second since timing started
+    fn rate(&self) -> u64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed > 0.0 { ((self.done - self.base) as f64 / elapsed) as u64 } else { 0 }
+    }
+}
+
+/// One line about a transfer in progress, e.g. "45% (90.0 MiB of 200.0
+/// MiB) at 12.3 MiB/s, 9s left"
+pub fn describe(done: u64, total: u64, rate: u64, eta_secs: Option<u64>) -> String {
+    let percent = done * 100 / total.max(1);
+    let eta = match eta_secs {
+        Some(secs) => format!(", {} left", format_secs(secs)),
+        None => String::new(),
+    };
+    format!("{percent}% ({} of {}) at {}/s{eta}", format_bytes(done), format_bytes(total), format_bytes(rate))
+}
+
+/// Final line about a transfer, e.g. "200.0 MiB in 16.0s, 12.5 MiB/s on average"
+pub fn summarize(bytes: u64, elapsed_ms: u64) -> String {
+    let rate = (bytes * 1000).checked_div(elapsed_ms).unwrap_or(bytes);
+    format!("{} in {:.1}s, {}/s on average", format_bytes(bytes), elapsed_ms as f64 / 1000.0, format_bytes(rate))
+}
+
+/// Seconds in the largest unit that keeps them at least 1, e.g. "3m"
+fn format_secs(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        _ => format!("{}h", secs / 3600),
+    }
+}
+
+/// Byte count in the largest unit that keeps it at least 1
+pub fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} B"),
+        1024..0x10_0000 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        0x10_0000..0x4000_0000 => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+        _ => format!("{:.1} GiB", bytes as f64 / 1_073_741_824.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_characters_are_dropped_from_labels() {
+        let progress = Progress::new("evil\x1b[2J\r\nname.bin", 10, 0);
+        assert_eq!(progress.label, "evil[2Jname.bin");
+    }
+
+    #[test]
+    fn small_transfers_are_never_reported() {
+        let mut progress = Progress::new("small", MIN_SIZE - 1, 0);
+        assert!(progress.advance(1).is_none());
+        assert!(progress.finish(None).is_none());
+    }
+
+    #[test]
+    fn progress_is_reported_at_most_every_interval() {
+        let mut progress = Progress::new("large", 4 * MIN_SIZE, 0);
+        let Some(NodeEvent::TransferProgress { done, total, .. }) = progress.advance(MIN_SIZE) else {
+            panic!("the first step of a large transfer is reported");
+        };
+        assert_eq!((done, total), (MIN_SIZE, 4 * MIN_SIZE));
+        assert!(progress.advance(MIN_SIZE).is_none(), "too soon after the last report");
+        // The last step is left to the summary
+        assert!(progress.advance(2 * MIN_SIZE).is_none());
+    }
+
+    #[test]
+    fn the_summary_counts_only_bytes_since_a_restart() {
+        let mut progress = Progress::new("resumed", 4 * MIN_SIZE, MIN_SIZE);
+        progress.advance(MIN_SIZE);
+        progress.restart();
+        progress.advance(2 * MIN_SIZE);
+        let Some(NodeEvent::TransferFinished { bytes, error, .. }) = progress.finish(Some("gone".to_string())) else {
+            panic!("a large transfer gets a summary");
+        };
+        assert_eq!(bytes, 2 * MIN_SIZE);
+        assert_eq!(error.as_deref(), Some("gone"));
+    }
+
+    #[test]
+    fn completing_counts_every_remaining_byte() {
+        let mut progress = Progress::new("direct", 3 * MIN_SIZE, 0);
+        let Some(NodeEvent::TransferFinished { bytes, error: None, .. }) = progress.complete() else {
+            panic!("a large transfer gets a summary");
+        };
+        assert_eq!(bytes, 3 * MIN_SIZE);
+    }
+
+    #[test]
+    fn describe_shows_percent_sizes_rate_and_time_left() {
+        assert_eq!(describe(90 * 1_048_576, 200 * 1_048_576, 12 * 1_048_576, Some(9)), "45% (90.0 MiB of 200.0 MiB) at 12.0 MiB/s, 9s left");
+        assert_eq!(describe(0, 2048, 0, None), "0% (0 B of 2.0 KiB) at 0 B/s");
+        assert_eq!(describe(0, 0, 0, Some(200)), "0% (0 B of 0 B) at 0 B/s, 3m left");
+    }
+
+    #[test]
+    fn summarize_averages_over_the_elapsed_time() {
+        assert_eq!(summarize(200 * 1_048_576, 16_000), "200.0 MiB in 16.0s, 12.5 MiB/s on average");
+        // Too quick to time counts as one second
+        assert_eq!(summarize(512, 0), "512 B in 0.0s, 512 B/s on average");
+    }
+
+    #[test]
+    fn sizes_and_times_use_the_largest_fitting_unit() {
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1_073_741_824), "3.0 GiB");
+        assert_eq!(format_secs(59), "59s");
+        assert_eq!(format_secs(7200), "2h");
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Queue of at most `capacity` items for a worker that may fall behind,
+/// such as one handling whole images. Once it is full the oldest item makes
+/// room for the newest, which is the one that counts on a clipboard. Sending
+/// never waits, so the event loop can feed it.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State { items: VecDeque::new(), senders: 1, receiving: true }),
+        ready: Notify::new(),
+        capacity: capacity.max(1),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Signalled when an item is queued or the last sender goes away
+    ready: Notify,
+    capacity: usize,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    receiving: bool,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> std::sync::MutexGuard<'_, State<T>> {
+        self.state.lock().expect("queue lock poisoned")
+    }
+}
+
+/// Sending end of a [`channel`]
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Queue `item`, handing back the oldest item if it had to make room.
+    /// Fails with `item` once the receiver is gone.
+    pub fn send(&self, item: T) -> Result<Option<T>, T> {
+        let mut state = self.shared.lock();
+        if !state.receiving {
+            return Err(item);
+        }
+        let dropped = if state.items.len() == self.shared.capacity { state.items.pop_front() } else { None };
+        state.items.push_back(item);
+        drop(state);
+        self.shared.ready.notify_one();
+        Ok(dropped)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.ready.notify_one();
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sender").field("capacity", &self.shared.capacity).finish_non_exhaustive()
+    }
+}
+
+/// Receiving end of a [`channel`]
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// The oldest queued item, or None once it is empty and every sender is
+    /// gone. Nothing is lost if this is cancelled.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let ready = self.shared.ready.notified();
+            {
+                let mut state = self.shared.lock();
+                if let Some(item) = state.items.pop_front() {
+                    return Some(item);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            ready.await;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.receiving = false;
+        state.items.clear();
+    }
+}
+
+impl<T> std::fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Receiver").field("capacity", &self.shared.capacity).finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_oldest_item_makes_room_for_the_newest() {
+        let (tx, mut rx) = channel(2);
+        assert_eq!(tx.send(1), Ok(None));
+        assert_eq!(tx.send(2), Ok(None));
+        assert_eq!(tx.send(3), Ok(Some(1)));
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.recv().await, Some(3));
+        drop(tx);
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn a_waiting_receiver_wakes_for_the_next_item() {
+        let (tx, mut rx) = channel(1);
+        let received = tokio::spawn(async move { (rx.recv().await, rx.recv().await) });
+        tokio::task::yield_now().await;
+        let other = tx.clone();
+        tx.send("copied").unwrap();
+        drop((tx, other));
+        assert_eq!(received.await.unwrap(), (Some("copied"), None));
+    }
+
+    #[test]
+    fn nothing_is_queued_once_the_receiver_is_gone() {
+        let (tx, rx) = channel(1);
+        drop(rx);
+        assert_eq!(tx.send(1), Err(1));
+    }
+}
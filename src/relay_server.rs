@@ -0,0 +1,229 @@
+use crate::control::NodeStatus;
+use crate::stats::{SharedStats, Stats};
+use crate::{build_swarm, listen, metrics, peer_exchange, Args};
+use anyhow::Result;
+use futures::StreamExt;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{NetworkBehaviour, SwarmEvent};
+use libp2p::{identify, identity, ping, relay, Multiaddr, PeerId};
+use log::{debug, error, info, warn};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Options of the `relay-server` subcommand
+#[derive(clap::Args, Debug)]
+pub struct RelayArgs {
+    /// Most reservations held at once
+    #[clap(long, default_value_t = 128)]
+    max_reservations: usize,
+
+    /// Most connections relayed at once
+    #[clap(long, default_value_t = 16)]
+    max_circuits: usize,
+
+    /// Longest a single relayed connection may stay open, in seconds
+    #[clap(long, default_value_t = 120)]
+    max_circuit_duration: u64,
+
+    /// Most bytes relayed per connection before it is closed (0 for no limit)
+    #[clap(long, default_value_t = 64 * 1024 * 1024)]
+    max_circuit_bytes: u64,
+
+    /// Only serve these peers, the members of the group; may be given
+    /// multiple times. Required unless --open
+    #[clap(long = "allow", value_name = "PEER_ID", required_unless_present = "open")]
+    allow: Vec<PeerId>,
+
+    /// Serve anyone who asks, not only the --allow list
+    #[clap(long, conflicts_with = "allow")]
+    open: bool,
+
+    /// Publicly reachable address of this relay, if it differs from the listen address (e.g. behind port forwarding)
+    #[clap(long)]
+    external_address: Vec<Multiaddr>,
+}
+
+#[derive(NetworkBehaviour)]
+struct RelayBehaviour {
+    identify: identify::Behaviour,
+    ping: ping::Behaviour,
+    relay: relay::Behaviour,
+}
+
+/// Run as a relay server until interrupted. No clipboard access is needed.
+pub async fn run(local_key: identity::Keypair, args: &Args, options: &RelayArgs) -> Result<()> {
+    let local_peer_id = PeerId::from(local_key.public());
+    info!("Local peer id: {local_peer_id}");
+
+    let mut config = relay::Config {
+        max_reservations: options.max_reservations,
+        max_circuits: options.max_circuits,
+        max_circuit_duration: Duration::from_secs(options.max_circuit_duration),
+        max_circuit_bytes: options.max_circuit_bytes,
+        ..Default::default()
+    };
+    if options.open {
+        warn!("Serving any peer that asks, not only the group");
+    } else {
+        info!("Serving only {} allowed peers", options.allow.len());
+        // Destinations need a reservation, so checking the source of each
+        // circuit as well is enough to keep strangers out entirely
+        let allowed: HashSet<PeerId> = options.allow.iter().copied().collect();
+        let for_circuits = allowed.clone();
+        config.reservation_rate_limiters.push(Box::new(move |peer, _: &Multiaddr, _| allowed.contains(&peer)));
+        config.circuit_src_rate_limiters.push(Box::new(move |peer, _: &Multiaddr, _| for_circuits.contains(&peer)));
+    }
+
+    let behaviour = RelayBehaviour {
+        identify: identify::Behaviour::new(
+            identify::Config::new("/ipfs/0.1.0".into(), local_key.public())
+                .with_agent_version(format!("clipboard-sync-relay/{}", env!("CARGO_PKG_VERSION"))),
+        ),
+        ping: ping::Behaviour::new(ping::Config::new()),
+        relay: relay::Behaviour::new(local_peer_id, config),
+    };
+    // Relayed connections to the relay itself are never needed
+    let mut swarm = build_swarm(local_key, args, |_| behaviour)?;
+    listen(&mut swarm, args)?;
+    for address in &options.external_address {
+        swarm.add_external_address(address.clone());
+    }
+
+    let stats: SharedStats = Arc::new(Mutex::new(Stats::default()));
+    stats.lock().expect("stats lock poisoned").set_relay_usage(0, 0);
+    if let Some(address) = args.metrics_address {
+        let stats = stats.clone();
+        // The relay has no runtime commands, so --hardened holds throughout
+        let (_, status) = watch::channel(NodeStatus { hardened: args.hardened, ..Default::default() });
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(address, stats, status).await {
+                error!("Metrics endpoint failed: {e:?}");
+            }
+        });
+    }
+
+    let mut reservations: HashSet<PeerId> = HashSet::new();
+    let mut circuits: usize = 0;
+    loop {
+        let event = tokio::select! {
+            event = swarm.select_next_some() => event,
+            _ = tokio::signal::ctrl_c() => {
+                info!("Shutting down relay server");
+                return Ok(());
+            }
+        };
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                // Clients are told the relay's external addresses with their
+                // reservation, so only advertise addresses others can reach
+                if options.external_address.is_empty() && !peer_exchange::is_loopback(&address) {
+                    swarm.add_external_address(address.clone());
+                }
+                info!("Relay listening on {}", address.with(Protocol::P2p(local_peer_id)));
+                continue;
+            }
+            SwarmEvent::Behaviour(RelayBehaviourEvent::Relay(event)) => match event {
+                relay::Event::ReservationReqAccepted { src_peer_id, renewed } => {
+                    reservations.insert(src_peer_id);
+                    if !renewed {
+                        info!("Reservation accepted for {src_peer_id} ({} active)", reservations.len());
+                    }
+                }
+                relay::Event::ReservationReqDenied { src_peer_id, status } => {
+                    warn!("Reservation denied for {src_peer_id}: {status:?}");
+                }
+                relay::Event::ReservationClosed { src_peer_id }
+                | relay::Event::ReservationTimedOut { src_peer_id } => {
+                    reservations.remove(&src_peer_id);
+                    info!("Reservation of {src_peer_id} ended ({} active)", reservations.len());
+                }
+                relay::Event::CircuitReqAccepted { src_peer_id, dst_peer_id } => {
+                    circuits += 1;
+                    info!("Relaying {src_peer_id} -> {dst_peer_id} ({circuits} circuits active)");
+                }
+                relay::Event::CircuitReqDenied { src_peer_id, dst_peer_id, status } => {
+                    warn!("Circuit {src_peer_id} -> {dst_peer_id} denied: {status:?}");
+                }
+                relay::Event::CircuitClosed { src_peer_id, dst_peer_id, error } => {
+                    circuits = circuits.saturating_sub(1);
+                    match error {
+                        Some(e) => info!("Circuit {src_peer_id} -> {dst_peer_id} closed: {e} ({circuits} active)"),
+                        None => info!("Circuit {src_peer_id} -> {dst_peer_id} closed ({circuits} active)"),
+                    }
+                }
+                other => debug!("Relay event: {other:?}"),
+            },
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => debug!("Connected to {peer_id}"),
+            SwarmEvent::ConnectionClosed { peer_id, .. } => debug!("Disconnected from {peer_id}"),
+            _ => continue,
+        }
+        stats.lock().expect("stats lock poisoned").set_relay_usage(reservations.len(), circuits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Command;
+    use clap::Parser;
+
+    #[derive(NetworkBehaviour)]
+    struct Client {
+        relay: relay::client::Behaviour,
+    }
+
+    /// Whether the relay at `relay` grants `key` a reservation
+    async fn reserves(key: identity::Keypair, relay: Multiaddr) -> bool {
+        let args = Args::try_parse_from(["clipboard-sync"]).unwrap();
+        let mut swarm = build_swarm(key, &args, |relay| Client { relay }).unwrap();
+        swarm.listen_on(relay.with(Protocol::P2pCircuit)).unwrap();
+        let answer = async {
+            loop {
+                match swarm.select_next_some().await {
+                    SwarmEvent::Behaviour(ClientEvent::Relay(relay::client::Event::ReservationReqAccepted { .. })) => return true,
+                    SwarmEvent::ListenerClosed { .. } => return false,
+                    _ => {}
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), answer).await.expect("the relay never answered")
+    }
+
+    #[tokio::test]
+    async fn only_allowed_peers_get_a_reservation() {
+        let (relay_key, member) = (identity::Keypair::generate_ed25519(), identity::Keypair::generate_ed25519());
+        let relay_id = relay_key.public().to_peer_id();
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let port_flag = port.to_string();
+        let member_flag = member.public().to_peer_id().to_string();
+        // Loopback addresses aren't advertised unless given
+        let address = format!("/ip4/127.0.0.1/tcp/{port}");
+        let args = Args::try_parse_from([
+            "clipboard-sync", "--listen-address", "127.0.0.1", "--port", &port_flag,
+            "relay-server", "--allow", &member_flag, "--external-address", &address,
+        ])
+        .unwrap();
+        let server = tokio::spawn(async move {
+            let Some(Command::RelayServer(ref options)) = args.command else {
+                unreachable!("parsed as relay-server")
+            };
+            run(relay_key, &args, options).await
+        });
+        while tokio::net::TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let relay: Multiaddr = format!("{address}/p2p/{relay_id}").parse().unwrap();
+        assert!(reserves(member, relay.clone()).await);
+        assert!(!reserves(identity::Keypair::generate_ed25519(), relay).await, "a stranger got a reservation");
+        server.abort();
+    }
+
+    #[test]
+    fn a_relay_serves_only_a_group_unless_opened() {
+        assert!(Args::try_parse_from(["clipboard-sync", "relay-server"]).is_err());
+        assert!(Args::try_parse_from(["clipboard-sync", "relay-server", "--open"]).is_ok());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::clipboard::now_millis;
+use crate::stats::{SharedStats, Stats};
+use anyhow::{Context, Result};
+use log::{error, info};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, TryLockError};
+use std::time::Instant;
+
+/// Bumped whenever a field of the JSON report is renamed, removed or changes meaning
+const SCHEMA_VERSION: u32 = 1;
+
+/// How the session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Exit {
+    /// Ctrl+C, `/quit` or the control socket
+    Shutdown,
+    /// Startup or the event loop returned an error
+    Error,
+    Panic,
+}
+
+#[derive(Debug, Serialize)]
+struct Peer {
+    peer_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_name: Option<String>,
+}
+
+/// Clipboard items of one content type that went one way
+#[derive(Debug, Serialize)]
+struct Items {
+    direction: &'static str,
+    content_type: &'static str,
+    count: u64,
+    bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Payload {
+    direction: &'static str,
+    content_type: &'static str,
+    bytes: u64,
+}
+
+/// What happened during a session, written when the process exits
+#[derive(Debug, Serialize)]
+pub struct SessionReport {
+    schema_version: u32,
+    exit: Exit,
+    /// The error or panic message of an abnormal exit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// Set when the stats couldn't be read and only the session times are known
+    truncated: bool,
+    /// Milliseconds since the Unix epoch
+    started_at_ms: u64,
+    ended_at_ms: u64,
+    duration_secs: u64,
+    peers: Vec<Peer>,
+    items: Vec<Items>,
+    publish_failures: BTreeMap<String, u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    largest_payload: Option<Payload>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    average_latency_ms: Option<u64>,
+}
+
+impl SessionReport {
+    /// Report of a session that started at `started` and ends now. Without
+    /// `stats` the report is truncated to the session times.
+    fn new(started: (Instant, u64), exit: Exit, reason: Option<String>, stats: Option<&Stats>) -> Self {
+        let mut report = Self {
+            schema_version: SCHEMA_VERSION,
+            exit,
+            reason,
+            truncated: stats.is_none(),
+            started_at_ms: started.1,
+            ended_at_ms: now_millis(),
+            duration_secs: started.0.elapsed().as_secs(),
+            peers: Vec::new(),
+            items: Vec::new(),
+            publish_failures: BTreeMap::new(),
+            largest_payload: None,
+            average_latency_ms: None,
+        };
+        let Some(stats) = stats else {
+            return report;
+        };
+        report.peers = stats
+            .peers_seen()
+            .iter()
+            .map(|(peer, name)| Peer { peer_id: peer.to_string(), device_name: name.clone() })
+            .collect();
+        for (direction, content_type, histogram) in stats.sizes() {
+            let direction = direction.label();
+            report.items.push(Items { direction, content_type, count: histogram.count(), bytes: histogram.sum() });
+            if report.largest_payload.as_ref().is_none_or(|largest| histogram.max() > largest.bytes) {
+                report.largest_payload = Some(Payload { direction, content_type, bytes: histogram.max() });
+            }
+        }
+        report.publish_failures = stats.publish_failures().clone();
+        report.average_latency_ms = stats.average_latency_ms();
+        report
+    }
+
+    /// The report as lines for the console
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = vec![match self.reason {
+            Some(ref reason) => format!("Session ended ({:?}: {reason}) after {}s", self.exit, self.duration_secs),
+            None => format!("Session ended after {}s", self.duration_secs),
+        }];
+        if self.truncated {
+            lines.push("  Stats unavailable, report truncated".to_string());
+            return lines;
+        }
+        let peers: Vec<String> = self
+            .peers
+            .iter()
+            .map(|peer| match peer.device_name {
+                Some(ref name) => format!("{name} ({})", peer.peer_id),
+                None => peer.peer_id.clone(),
+            })
+            .collect();
+        lines.push(format!("  Peers seen: {}", if peers.is_empty() { "none".to_string() } else { peers.join(", ") }));
+        if self.items.is_empty() {
+            lines.push("  Items: none".to_string());
+        }
+        for items in &self.items {
+            lines.push(format!(
+                "  Items {} ({}): {} totalling {} bytes",
+                items.direction, items.content_type, items.count, items.bytes
+            ));
+        }
+        for (reason, count) in &self.publish_failures {
+            lines.push(format!("  Publish failures ({reason}): {count}"));
+        }
+        if let Some(ref largest) = self.largest_payload {
+            lines.push(format!(
+                "  Largest payload: {} bytes ({} {})",
+                largest.bytes, largest.direction, largest.content_type
+            ));
+        }
+        if let Some(latency) = self.average_latency_ms {
+            lines.push(format!("  Average sync latency: {latency}ms"));
+        }
+        lines
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(self)?;
+        fs::write(path, text).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Emits the session report once, however the process exits: `finish` on a
+/// graceful shutdown, the panic hook on a panic of the event loop, and drop
+/// for an error returned from `main`
+pub struct Reporter {
+    stats: SharedStats,
+    path: Option<PathBuf>,
+    started: (Instant, u64),
+    done: Arc<AtomicBool>,
+}
+
+impl Reporter {
+    /// Start timing the session and install the panic hook. The report goes
+    /// to the console, and as JSON to `path` if given.
+    pub fn install(stats: SharedStats, path: Option<PathBuf>) -> Self {
+        let reporter = Self { stats, path, started: (Instant::now(), now_millis()), done: Arc::default() };
+        let (stats, path, started, done) =
+            (reporter.stats.clone(), reporter.path.clone(), reporter.started, reporter.done.clone());
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            previous(info);
+            // Panicked tasks on worker threads don't end the session
+            if std::thread::current().name() != Some("main") || done.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            let reason = info
+                .payload()
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| info.payload().downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            // The panic may have happened with the stats locked
+            let guard = match stats.try_lock() {
+                Ok(guard) => Some(guard),
+                Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+                Err(TryLockError::WouldBlock) => None,
+            };
+            let report = SessionReport::new(started, Exit::Panic, Some(reason), guard.as_deref());
+            drop(guard);
+            emit(&report, path.as_deref());
+        }));
+        reporter
+    }
+
+    /// Emit the report of a graceful shutdown
+    pub fn finish(&self, exit: Exit) {
+        if self.done.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let stats = self.stats.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let report = SessionReport::new(self.started, exit, None, Some(&stats));
+        drop(stats);
+        emit(&report, self.path.as_deref());
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        if !self.done.load(Ordering::SeqCst) {
+            self.finish(Exit::Error);
+        }
+    }
+}
+
+fn emit(report: &SessionReport, path: Option<&Path>) {
+    for line in report.summary() {
+        info!("{line}");
+    }
+    if let Some(path) = path {
+        match report.write(path) {
+            Ok(()) => info!("Session report written to {}", path.display()),
+            Err(e) => error!("Failed to write the session report: {e:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::ContentType;
+    use crate::stats::Direction;
+    use crate::testing::TempDir;
+    use libp2p::PeerId;
+
+    fn started() -> (Instant, u64) {
+        (Instant::now(), now_millis())
+    }
+
+    #[test]
+    fn the_report_sums_up_peers_items_and_failures() {
+        let mut stats = Stats::default();
+        let peer = PeerId::random();
+        stats.record_peer(peer, None);
+        stats.record_peer(peer, Some("laptop"));
+        stats.record_size(Direction::Sent, &ContentType::Text, 10);
+        stats.record_size(Direction::Sent, &ContentType::Text, 30);
+        stats.record_size(Direction::Received, &ContentType::Image, 500);
+        stats.record_publish_failure("no peers");
+
+        let report = SessionReport::new(started(), Exit::Shutdown, None, Some(&stats));
+        let summary = report.summary();
+        assert!(summary.contains(&format!("  Peers seen: laptop ({peer})")), "{summary:?}");
+        assert!(summary.contains(&"  Items sent (text): 2 totalling 40 bytes".to_string()), "{summary:?}");
+        assert!(summary.contains(&"  Publish failures (no peers): 1".to_string()), "{summary:?}");
+        assert!(summary.contains(&"  Largest payload: 500 bytes (received image)".to_string()), "{summary:?}");
+
+        let dir = TempDir::new();
+        let path = dir.path().join("report.json");
+        report.write(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["exit"], "shutdown");
+        assert_eq!(json["truncated"], false);
+        assert_eq!(json["peers"][0]["device_name"], "laptop");
+    }
+
+    #[test]
+    fn without_stats_only_the_session_times_are_reported() {
+        let report = SessionReport::new(started(), Exit::Panic, Some("boom".to_string()), None);
+        assert!(report.truncated);
+        assert_eq!(report.summary(), ["Session ended (Panic: boom) after 0s", "  Stats unavailable, report truncated"]);
+    }
+}
@@ -0,0 +1,189 @@
+use crate::control::NodeCommand;
+use log::{debug, warn};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How often to look for a screen share
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Tells whether the screen is being shared or recorded right now
+pub trait ScreenShareDetector: Send {
+    /// Called off the event loop, so it may block briefly
+    fn is_sharing(&mut self) -> bool;
+}
+
+/// For platforms without detection: the screen is never considered shared
+#[derive(Debug, Default)]
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+pub struct NoDetection;
+
+impl ScreenShareDetector for NoDetection {
+    fn is_sharing(&mut self) -> bool {
+        false
+    }
+}
+
+/// Looks for running screen recorders and remote desktop servers by process
+/// name. Video call apps that only sometimes share can't be told apart this
+/// way and are not detected.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+pub struct ProcessScan;
+
+/// Process names (`/proc/<pid>/comm`) of screen recorders and remote
+/// desktop servers that capture the screen whenever they run
+#[cfg(target_os = "linux")]
+const SHARING_PROCESSES: &[&str] = &[
+    "obs",
+    "simplescreenrec",
+    "kazam",
+    "peek",
+    "vokoscreenNG",
+    "wf-recorder",
+    "wl-screenrec",
+    "gpu-screen-reco",
+    "x11vnc",
+    "x0vncserver",
+    "wayvnc",
+    "krfb",
+    "xrdp-chansrv",
+];
+
+#[cfg(target_os = "linux")]
+impl ScreenShareDetector for ProcessScan {
+    fn is_sharing(&mut self) -> bool {
+        let Ok(entries) = std::fs::read_dir("/proc") else {
+            return false;
+        };
+        entries
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().bytes().all(|byte| byte.is_ascii_digit()))
+            .filter_map(|entry| std::fs::read_to_string(entry.path().join("comm")).ok())
+            .any(|comm| SHARING_PROCESSES.contains(&comm.trim_end()))
+    }
+}
+
+/// The detector for this platform
+pub fn platform_detector() -> Box<dyn ScreenShareDetector> {
+    #[cfg(target_os = "linux")]
+    return Box::new(ProcessScan);
+    #[cfg(not(target_os = "linux"))]
+    {
+        warn!("Screen share detection is not supported on this platform, --pause-on-screenshare has no effect");
+        Box::new(NoDetection)
+    }
+}
+
+/// Poll `detector` in the background and report each start and end of a
+/// screen share as a [`NodeCommand::ScreenShare`]
+pub fn spawn(mut detector: Box<dyn ScreenShareDetector>, command_tx: mpsc::UnboundedSender<NodeCommand>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        let mut sharing = false;
+        loop {
+            interval.tick().await;
+            let (returned, now_sharing) = match tokio::task::spawn_blocking(move || {
+                let sharing = detector.is_sharing();
+                (detector, sharing)
+            })
+            .await
+            {
+                Ok(result) => result,
+                Err(e) => {
+                    warn!("Screen share detection failed, giving up: {e}");
+                    return;
+                }
+            };
+            detector = returned;
+            if now_sharing != sharing {
+                sharing = now_sharing;
+                debug!("Screen share {}", if sharing { "started" } else { "ended" });
+                if command_tx.send(NodeCommand::ScreenShare(sharing)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Decides when a screen share pauses and resumes sync. Only a pause it
+/// caused itself is undone when sharing ends; pausing or resuming by hand in
+/// between takes over.
+#[derive(Debug, Default)]
+pub struct AutoPause {
+    paused_by_us: bool,
+}
+
+impl AutoPause {
+    /// A screen share started or ended. Returns the paused state to switch
+    /// to, if it should change.
+    pub fn on_screenshare(&mut self, sharing: bool, paused: bool) -> Option<bool> {
+        match (sharing, paused) {
+            (true, false) => {
+                self.paused_by_us = true;
+                Some(true)
+            }
+            (false, true) if self.paused_by_us => {
+                self.paused_by_us = false;
+                Some(false)
+            }
+            _ => None,
+        }
+    }
+
+    /// Sync was paused or resumed by hand
+    pub fn on_manual_change(&mut self) {
+        self.paused_by_us = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Reports whatever the test sets
+    struct Mocked(Arc<AtomicBool>);
+
+    impl ScreenShareDetector for Mocked {
+        fn is_sharing(&mut self) -> bool {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    #[tokio::test]
+    async fn each_start_and_end_of_a_share_is_reported_once() {
+        let sharing = Arc::new(AtomicBool::new(false));
+        let (command_tx, mut commands) = mpsc::unbounded_channel();
+        spawn(Box::new(Mocked(sharing.clone())), command_tx);
+        let next = async |commands: &mut mpsc::UnboundedReceiver<NodeCommand>| {
+            match tokio::time::timeout(POLL_INTERVAL * 3, commands.recv()).await {
+                Ok(Some(NodeCommand::ScreenShare(sharing))) => sharing,
+                other => panic!("expected a screen share command, got {other:?}"),
+            }
+        };
+
+        sharing.store(true, Ordering::Relaxed);
+        assert!(next(&mut commands).await);
+        sharing.store(false, Ordering::Relaxed);
+        assert!(!next(&mut commands).await);
+        assert!(commands.try_recv().is_err());
+    }
+
+    #[test]
+    fn only_a_pause_caused_by_the_share_is_undone() {
+        let mut auto_pause = AutoPause::default();
+        assert_eq!(auto_pause.on_screenshare(true, false), Some(true));
+        assert_eq!(auto_pause.on_screenshare(false, true), Some(false));
+
+        // Already paused by hand: left alone both ways
+        assert_eq!(auto_pause.on_screenshare(true, true), None);
+        assert_eq!(auto_pause.on_screenshare(false, true), None);
+
+        // Resumed by hand during the share: not paused again when it ends
+        assert_eq!(auto_pause.on_screenshare(true, false), Some(true));
+        auto_pause.on_manual_change();
+        assert_eq!(auto_pause.on_screenshare(false, false), None);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{bundle, Args, Command};
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::IsTerminal;
+
+/// A setting starting with this names an entry of the OS keyring instead of
+/// holding the secret itself, like `keyring:identity`
+pub const KEYRING_PREFIX: &str = "keyring:";
+/// Service every entry is stored under in the keyring
+const SERVICE: &str = "clipboard-sync";
+
+/// Where secrets named by `keyring:` references are kept
+pub trait SecretStore {
+    /// The secret stored as `name`, or `None` if there is no such entry
+    fn get(&self, name: &str) -> Result<Option<String>>;
+    /// Store `secret` as `name`, replacing what was there
+    fn set(&self, name: &str, secret: &str) -> Result<()>;
+}
+
+/// The platform's keyring: the Secret Service on Linux, the Keychain on
+/// macOS, the Credential Manager on Windows
+#[derive(Debug, Default)]
+pub struct OsKeyring;
+
+impl SecretStore for OsKeyring {
+    fn get(&self, name: &str) -> Result<Option<String>> {
+        match keyring::Entry::new(SERVICE, name)?.get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set(&self, name: &str, secret: &str) -> Result<()> {
+        Ok(keyring::Entry::new(SERVICE, name)?.set_password(secret)?)
+    }
+}
+
+/// The keyring entry `value` refers to, if it is a reference
+fn entry_name(value: &str) -> Option<&str> {
+    value.strip_prefix(KEYRING_PREFIX)
+}
+
+/// `value` with a `keyring:` reference replaced by the secret it names, or
+/// why the keyring couldn't provide it. Errors for a malformed reference.
+fn lookup(store: &dyn SecretStore, setting: &str, value: String) -> Result<Result<String, anyhow::Error>> {
+    let Some(name) = entry_name(&value) else {
+        return Ok(Ok(value));
+    };
+    if name.is_empty() {
+        bail!("{setting} names no keyring entry, use {KEYRING_PREFIX}<entry-name>");
+    }
+    Ok(match store.get(name) {
+        Ok(Some(secret)) => Ok(secret),
+        Ok(None) => Err(anyhow!(
+            "Keyring has no entry '{name}' for {setting}, store it with `clipboard-sync secret set {name}`"
+        )),
+        Err(e) => Err(e.context(format!("Keyring unavailable, can't look up '{name}' for {setting}"))),
+    })
+}
+
+/// `value` of the setting `setting` with a `keyring:` reference replaced by
+/// the secret it names. When the keyring is unavailable or has no such entry
+/// the secret is asked for on a terminal, and is an error otherwise.
+pub fn resolve(store: &dyn SecretStore, setting: &str, value: String) -> Result<String> {
+    let problem = match lookup(store, setting, value)? {
+        Ok(secret) => return Ok(secret),
+        Err(problem) => problem,
+    };
+    if !std::io::stdin().is_terminal() {
+        return Err(problem);
+    }
+    eprintln!("{problem:#}");
+    rpassword::prompt_password(format!("{setting}: ")).with_context(|| format!("Failed to read {setting}"))
+}
+
+/// Resolve the `keyring:` references of every secret-bearing setting in
+/// `args`, the command line and config file alike. Subcommands that don't
+/// use the secrets are left alone, so `secret set` can store a missing one.
+pub fn resolve_args(args: &mut Args, store: &dyn SecretStore) -> Result<()> {
+    if matches!(
+        args.command,
+        Some(Command::Secret { .. } | Command::Profile { .. } | Command::Service { .. } | Command::Get { .. })
+    ) {
+        return Ok(());
+    }
+    args.identity_seed = args.identity_seed.take().map(|value| resolve(store, "identity-seed", value)).transpose()?;
+    args.identity_passphrase =
+        args.identity_passphrase.take().map(|value| resolve(store, "identity-passphrase", value)).transpose()?;
+    args.field_encryption = args.field_encryption.take().map(|value| resolve(store, "field-encryption", value)).transpose()?;
+    args.audit_key = args.audit_key.take().map(|value| resolve(store, "audit-key", value)).transpose()?;
+    if let Some(
+        Command::Export(bundle::ExportArgs { ref mut passphrase, .. })
+        | Command::Import(bundle::ImportArgs { ref mut passphrase, .. }),
+    ) = args.command
+    {
+        *passphrase = passphrase.take().map(|value| resolve(store, "bundle passphrase", value)).transpose()?;
+    }
+    Ok(())
+}
+
+/// Store a secret under `name` for settings to refer to as `keyring:<name>`.
+/// Read without echoing on a terminal, or from the first line of stdin.
+pub fn set(store: &dyn SecretStore, name: &str) -> Result<()> {
+    if name.is_empty() || entry_name(name).is_some() {
+        bail!("Give the bare entry name, settings then refer to it as {KEYRING_PREFIX}<name>");
+    }
+    let secret = if std::io::stdin().is_terminal() {
+        let secret = rpassword::prompt_password(format!("Secret for {name}: ")).context("Failed to read the secret")?;
+        if rpassword::prompt_password("Repeat it: ").context("Failed to read the secret")? != secret {
+            bail!("The secrets don't match");
+        }
+        secret
+    } else {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).context("Failed to read the secret from stdin")?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    };
+    if secret.is_empty() {
+        bail!("Secret must not be empty");
+    }
+    store.set(name, &secret).with_context(|| format!("Failed to store '{name}' in the keyring"))?;
+    println!("Stored '{name}', refer to it as {KEYRING_PREFIX}{name}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    /// A keyring in memory, or an unavailable one
+    #[derive(Default)]
+    struct MockStore {
+        entries: RefCell<HashMap<String, String>>,
+        unavailable: bool,
+    }
+
+    impl MockStore {
+        fn with(entries: &[(&str, &str)]) -> Self {
+            let store = Self::default();
+            for (name, secret) in entries {
+                store.set(name, secret).unwrap();
+            }
+            store
+        }
+    }
+
+    impl SecretStore for MockStore {
+        fn get(&self, name: &str) -> Result<Option<String>> {
+            if self.unavailable {
+                bail!("no keyring daemon");
+            }
+            Ok(self.entries.borrow().get(name).cloned())
+        }
+
+        fn set(&self, name: &str, secret: &str) -> Result<()> {
+            self.entries.borrow_mut().insert(name.to_string(), secret.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn plain_values_are_kept() {
+        let store = MockStore { unavailable: true, ..MockStore::default() };
+        assert_eq!(lookup(&store, "identity-seed", "hunter2".to_string()).unwrap().unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn references_are_replaced_by_their_secret() {
+        let store = MockStore::with(&[("seed", "hunter2")]);
+        assert_eq!(lookup(&store, "identity-seed", "keyring:seed".to_string()).unwrap().unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn missing_entries_and_unavailable_keyrings_are_explained() {
+        let missing = lookup(&MockStore::default(), "identity-seed", "keyring:seed".to_string()).unwrap().unwrap_err();
+        assert!(format!("{missing:#}").contains("secret set seed"), "{missing:#}");
+        let store = MockStore { unavailable: true, ..MockStore::default() };
+        let unavailable = lookup(&store, "identity-seed", "keyring:seed".to_string()).unwrap().unwrap_err();
+        assert!(format!("{unavailable:#}").contains("Keyring unavailable"), "{unavailable:#}");
+    }
+
+    #[test]
+    fn a_reference_must_name_an_entry() {
+        assert!(lookup(&MockStore::default(), "identity-seed", KEYRING_PREFIX.to_string()).is_err());
+        assert!(set(&MockStore::default(), "").is_err());
+        assert!(set(&MockStore::default(), "keyring:seed").is_err());
+    }
+
+    #[test]
+    fn every_secret_setting_is_resolved() {
+        let store = MockStore::with(&[("seed", "hunter2"), ("field", "swordfish")]);
+        let mut args = Args::try_parse_from([
+            "clipboard-sync",
+            "--identity-seed",
+            "keyring:seed",
+            "--field-encryption",
+            "keyring:field",
+        ])
+        .unwrap();
+        resolve_args(&mut args, &store).unwrap();
+        assert_eq!(args.identity_seed.as_deref(), Some("hunter2"));
+        assert_eq!(args.field_encryption.as_deref(), Some("swordfish"));
+    }
+
+    #[test]
+    fn secret_subcommands_leave_references_alone() {
+        let store = MockStore { unavailable: true, ..MockStore::default() };
+        let mut args =
+            Args::try_parse_from(["clipboard-sync", "--identity-seed", "keyring:seed", "secret", "set", "seed"]).unwrap();
+        resolve_args(&mut args, &store).unwrap();
+        assert_eq!(args.identity_seed.as_deref(), Some("keyring:seed"));
+    }
+}
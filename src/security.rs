@@ -0,0 +1,177 @@
+use clap::ValueEnum;
+use either::Either;
+use futures::future::BoxFuture;
+use futures::{FutureExt, TryFutureExt};
+use libp2p::core::upgrade::{InboundConnectionUpgrade, OutboundConnectionUpgrade, UpgradeInfo};
+use libp2p::{identity, noise, tls, PeerId};
+use log::info;
+use serde::Deserialize;
+
+/// Connection security protocols offered to peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    /// Noise only, understood by every version of this app
+    Noise,
+    /// TLS 1.3 only; cannot connect to Noise-only peers
+    Tls,
+    /// Offer both and prefer TLS, falling back to Noise for older peers
+    Both,
+}
+
+/// Security upgrade that logs which protocol secured each connection, since
+/// the swarm's connection events don't say
+#[derive(Debug, Clone)]
+pub struct Logged<U> {
+    name: &'static str,
+    inner: U,
+}
+
+impl<U: UpgradeInfo> UpgradeInfo for Logged<U> {
+    type Info = U::Info;
+    type InfoIter = U::InfoIter;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.inner.protocol_info()
+    }
+}
+
+impl<C, S, U> InboundConnectionUpgrade<C> for Logged<U>
+where
+    U: InboundConnectionUpgrade<C, Output = (PeerId, S)>,
+    U::Future: Send + 'static,
+{
+    type Output = (PeerId, S);
+    type Error = U::Error;
+    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
+        let name = self.name;
+        self.inner
+            .upgrade_inbound(socket, info)
+            .inspect(move |result| {
+                if let Ok((peer, _)) = result {
+                    info!("Inbound connection from {peer} secured with {name}");
+                }
+            })
+            .boxed()
+    }
+}
+
+impl<C, S, U> OutboundConnectionUpgrade<C> for Logged<U>
+where
+    U: OutboundConnectionUpgrade<C, Output = (PeerId, S)>,
+    U::Future: Send + 'static,
+{
+    type Output = (PeerId, S);
+    type Error = U::Error;
+    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+        let name = self.name;
+        self.inner
+            .upgrade_outbound(socket, info)
+            .inspect(move |result| {
+                if let Ok((peer, _)) = result {
+                    info!("Outbound connection to {peer} secured with {name}");
+                }
+            })
+            .boxed()
+    }
+}
+
+/// Noise security upgrade, for `SwarmBuilder::with_tcp`
+pub fn noise(key: &identity::Keypair) -> Result<Logged<noise::Config>, noise::Error> {
+    Ok(Logged { name: "Noise", inner: noise::Config::new(key)? })
+}
+
+/// TLS 1.3 security upgrade, for `SwarmBuilder::with_tcp`
+pub fn tls(key: &identity::Keypair) -> Result<Logged<tls::Config>, tls::certificate::GenError> {
+    Ok(Logged { name: "TLS", inner: tls::Config::new(key)? })
+}
+
+/// Noise and TLS offered together with TLS preferred, for transports built
+/// outside `SwarmBuilder::with_tcp`, which does this for a tuple itself
+pub fn both(key: &identity::Keypair) -> Result<Select<Logged<tls::Config>, Logged<noise::Config>>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(Select(tls(key)?, noise(key)?))
+}
+
+/// Security upgrade offering the protocols of both, the first one's preferred
+#[derive(Debug, Clone)]
+pub struct Select<A, B>(A, B);
+
+impl<A: UpgradeInfo, B: UpgradeInfo> UpgradeInfo for Select<A, B> {
+    type Info = Either<A::Info, B::Info>;
+    type InfoIter = Vec<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        let first = self.0.protocol_info().into_iter().map(Either::Left);
+        first.chain(self.1.protocol_info().into_iter().map(Either::Right)).collect()
+    }
+}
+
+impl<C, A, B, SA, SB> InboundConnectionUpgrade<C> for Select<A, B>
+where
+    A: InboundConnectionUpgrade<C, Output = (PeerId, SA)>,
+    B: InboundConnectionUpgrade<C, Output = (PeerId, SB)>,
+    A::Future: Send + 'static,
+    B::Future: Send + 'static,
+    A::Error: Send + 'static,
+    B::Error: Send + 'static,
+    SA: Send + 'static,
+    SB: Send + 'static,
+{
+    type Output = (PeerId, futures::future::Either<SA, SB>);
+    type Error = Either<A::Error, B::Error>;
+    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
+        match info {
+            Either::Left(info) => self
+                .0
+                .upgrade_inbound(socket, info)
+                .map_ok(|(peer, stream)| (peer, futures::future::Either::Left(stream)))
+                .map_err(Either::Left)
+                .boxed(),
+            Either::Right(info) => self
+                .1
+                .upgrade_inbound(socket, info)
+                .map_ok(|(peer, stream)| (peer, futures::future::Either::Right(stream)))
+                .map_err(Either::Right)
+                .boxed(),
+        }
+    }
+}
+
+impl<C, A, B, SA, SB> OutboundConnectionUpgrade<C> for Select<A, B>
+where
+    A: OutboundConnectionUpgrade<C, Output = (PeerId, SA)>,
+    B: OutboundConnectionUpgrade<C, Output = (PeerId, SB)>,
+    A::Future: Send + 'static,
+    B::Future: Send + 'static,
+    A::Error: Send + 'static,
+    B::Error: Send + 'static,
+    SA: Send + 'static,
+    SB: Send + 'static,
+{
+    type Output = (PeerId, futures::future::Either<SA, SB>);
+    type Error = Either<A::Error, B::Error>;
+    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+        match info {
+            Either::Left(info) => self
+                .0
+                .upgrade_outbound(socket, info)
+                .map_ok(|(peer, stream)| (peer, futures::future::Either::Left(stream)))
+                .map_err(Either::Left)
+                .boxed(),
+            Either::Right(info) => self
+                .1
+                .upgrade_outbound(socket, info)
+                .map_ok(|(peer, stream)| (peer, futures::future::Either::Right(stream)))
+                .map_err(Either::Right)
+                .boxed(),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::control::NodeEvent;
+use crate::doctor::{CheckResult, CheckStatus};
+use crate::in_process::Node;
+use anyhow::{bail, Result};
+use std::time::{Duration, Instant};
+
+/// How long connecting, or one round trip, may take before it counts as failed
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Size of the synthetic image, large enough to take the bulk lane
+const IMAGE_SIDE: usize = 256;
+
+/// The two nodes, the receiver connected to the sender
+struct Pair {
+    sender: Node,
+    receiver: Node,
+}
+
+impl Pair {
+    /// Start both nodes and wait until the receiver has identified the
+    /// sender. They only sync their in-memory clipboards and only speak the
+    /// in-memory transport, so nothing reaches the group or the system
+    /// clipboard.
+    async fn connect() -> Result<Self> {
+        let sender = Node::start(&["--clipboard"])?;
+        let address = sender.address.to_string();
+        let mut receiver = Node::start(&["--clipboard", "--connect", &address])?;
+        let sender_id = sender.peer_id;
+        receiver
+            .wait_for(TIMEOUT, |event| matches!(event, NodeEvent::PeerIdentified { peer, .. } if *peer == sender_id).then_some(()))
+            .await?;
+        Ok(Self { sender, receiver })
+    }
+
+    /// Wait for the receiver to apply what was just copied on the sender
+    async fn round_trip(&mut self) -> Result<()> {
+        let sender_id = self.sender.peer_id;
+        let error = self
+            .receiver
+            .wait_for(TIMEOUT, |event| match event {
+                NodeEvent::ClipboardApplied { from, error, .. } if *from == sender_id => Some(error.clone()),
+                NodeEvent::ContentDropped { from, reason, .. } if *from == sender_id => Some(Some(format!("dropped: {reason:?}"))),
+                _ => None,
+            })
+            .await?;
+        if let Some(error) = error {
+            bail!("the receiver did not apply it: {error}");
+        }
+        Ok(())
+    }
+
+    async fn stop(self) {
+        let _ = self.sender.stop().await;
+        let _ = self.receiver.stop().await;
+    }
+}
+
+/// Copy text on the sender and check that the receiver's clipboard ends up
+/// holding exactly that
+async fn check_text(pair: &mut Pair) -> CheckResult {
+    const NAME: &str = "Text round trip";
+    let started = Instant::now();
+    let text = format!("clipboard-sync self-test from {}", pair.sender.peer_id);
+    pair.sender.clipboard.copy_text(&text);
+    match pair.round_trip().await {
+        Ok(()) if pair.receiver.clipboard.text().as_deref() == Some(&text) => {
+            CheckResult::pass(NAME, format!("{} bytes in {} ms", text.len(), started.elapsed().as_millis()))
+        }
+        Ok(()) => CheckResult::fail(NAME, "the receiver's clipboard holds something else", "Please report this, it is a bug"),
+        Err(e) => CheckResult::fail(NAME, format!("{e:#}"), "Run again with RUST_LOG=debug to see where it stops"),
+    }
+}
+
+/// Copy an image on the sender and check that the receiver's clipboard ends
+/// up holding the same pixels
+async fn check_image(pair: &mut Pair) -> CheckResult {
+    const NAME: &str = "Image round trip";
+    let started = Instant::now();
+    let pixels = synthetic_image();
+    pair.sender.clipboard.copy_image(pixels.clone(), IMAGE_SIDE, IMAGE_SIDE);
+    match pair.round_trip().await {
+        Ok(()) => match pair.receiver.clipboard.image() {
+            Some(image) if (image.width, image.height) == (IMAGE_SIDE, IMAGE_SIDE) && *image.bytes == *pixels => {
+                CheckResult::pass(NAME, format!("{} bytes in {} ms", pixels.len(), started.elapsed().as_millis()))
+            }
+            _ => CheckResult::fail(NAME, "the receiver's clipboard holds something else", "Please report this, it is a bug"),
+        },
+        Err(e) => CheckResult::fail(NAME, format!("{e:#}"), "Run again with RUST_LOG=debug to see where it stops"),
+    }
+}
+
+/// A gradient that doesn't compress to nothing, in RGBA
+fn synthetic_image() -> Vec<u8> {
+    (0..IMAGE_SIDE * IMAGE_SIDE)
+        .flat_map(|index| {
+            let (x, y) = (index % IMAGE_SIDE, index / IMAGE_SIDE);
+            [x as u8, y as u8, (x ^ y) as u8, 255]
+        })
+        .collect()
+}
+
+/// Start two nodes in this process, each with an in-memory clipboard,
+/// connect them over the in-memory transport and copy text and an image on
+/// one. Everything from noticing the copy to writing the other clipboard
+/// runs as in a real node. Returns a pass/fail line per check.
+async fn checks() -> Vec<CheckResult> {
+    match Pair::connect().await {
+        Ok(mut pair) => {
+            let results = vec![
+                CheckResult::pass("Connection", "two nodes connected in memory"),
+                check_text(&mut pair).await,
+                check_image(&mut pair).await,
+            ];
+            pair.stop().await;
+            results
+        }
+        Err(e) => vec![CheckResult::fail("Connection", format!("{e:#}"), "Please report this, it is a bug")],
+    }
+}
+
+/// Run the checks, print a line per check and return whether all passed
+pub async fn run() -> bool {
+    println!("Round-tripping clipboard content between two in-process nodes...\n");
+
+    let results = checks().await;
+    for result in &results {
+        println!("{result}");
+    }
+    let passed = results.iter().all(|result| result.status != CheckStatus::Fail);
+    if passed {
+        println!("\nSelf-test passed.");
+    } else {
+        println!("\nSelf-test failed.");
+    }
+    passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_check_passes() {
+        for result in checks().await {
+            assert_eq!(result.status, CheckStatus::Pass, "{result}");
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::secrets;
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+use std::process::Command;
+
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+mod launchd;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod systemd;
+#[cfg_attr(not(windows), allow(dead_code))]
+mod windows;
+
+/// Actions of the `service` subcommand
+#[derive(clap::Subcommand, Debug)]
+pub enum ServiceAction {
+    /// Start the node at login with the flags given before `service`,
+    /// replacing an earlier installation
+    Install,
+    /// Stop the node and remove what `install` created
+    Uninstall,
+    /// Report whether the node is installed and running
+    Status,
+}
+
+/// Flags that carry secrets, which would end up readable in the service definition
+const SECRET_FLAGS: &[&str] = &["--identity-seed", "--identity-passphrase", "--field-encryption", "--audit-key"];
+
+/// What the login item runs: this executable with the flags it was given,
+/// in daemon mode
+#[derive(Debug, Clone)]
+pub struct Launch {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    /// Entry name, per profile so several profiles can each start at login
+    pub name: String,
+}
+
+impl Launch {
+    /// The current executable with the global flags given before `service`
+    pub fn current(profile: Option<&str>) -> Result<Self> {
+        let program = std::env::current_exe().context("Failed to locate the current executable")?;
+        Self::new(program, std::env::args().skip(1), profile)
+    }
+
+    /// Running `program` with the flags of `command_line` before `service`
+    fn new(program: PathBuf, command_line: impl IntoIterator<Item = String>, profile: Option<&str>) -> Result<Self> {
+        let mut args: Vec<String> = command_line.into_iter().take_while(|arg| arg != "service").collect();
+        for (i, arg) in args.iter().enumerate() {
+            let Some(flag) = SECRET_FLAGS.iter().find(|secret| arg.starts_with(*secret)) else {
+                continue;
+            };
+            // A keyring reference is no secret itself
+            let value = arg
+                .strip_prefix(flag)
+                .and_then(|rest| rest.strip_prefix('='))
+                .or(args.get(i + 1).map(String::as_str));
+            if !value.is_some_and(|value| value.starts_with(secrets::KEYRING_PREFIX)) {
+                bail!(
+                    "{flag} would be stored in plain text in the service definition. Store it with \
+                     `clipboard-sync secret set <name>` and pass {flag} keyring:<name> instead"
+                );
+            }
+        }
+        if !args.iter().any(|arg| arg == "--daemon") {
+            args.push("--daemon".to_string());
+        }
+        let name = match profile {
+            Some(profile) => format!("clipboard-sync-{profile}"),
+            None => "clipboard-sync".to_string(),
+        };
+        Ok(Self { program, args, name })
+    }
+}
+
+/// Run a `service` action for the current platform
+pub fn run(action: &ServiceAction, profile: Option<&str>) -> Result<()> {
+    let launch = Launch::current(profile)?;
+    #[cfg(target_os = "linux")]
+    return systemd::run(action, &launch);
+    #[cfg(target_os = "macos")]
+    return launchd::run(action, &launch);
+    #[cfg(windows)]
+    return windows::run(action, &launch);
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    {
+        let _ = (action, launch);
+        bail!("Installing a service is not supported on this platform");
+    }
+}
+
+/// Run a system tool, failing with its error output if it fails
+fn command(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run {program}"))?;
+    if !output.status.success() {
+        bail!(
+            "{program} {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Run a system tool for its output alone, whatever its exit status.
+/// `None` if it could not run or printed nothing.
+fn query(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string()).filter(|output| !output.is_empty())
+}
+
+/// The user's home directory
+#[cfg_attr(windows, allow(dead_code))]
+fn home_dir() -> Result<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .context("Could not determine the home directory")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn launch(command_line: &[&str], profile: Option<&str>) -> Result<Launch> {
+        Launch::new(PathBuf::from("/usr/bin/clipboard-sync"), command_line.iter().map(|arg| arg.to_string()), profile)
+    }
+
+    #[test]
+    fn the_flags_before_service_run_as_a_daemon() {
+        let launch = launch(&["--clipboard", "--port", "4001", "service", "install"], None).unwrap();
+        assert_eq!(launch.args, ["--clipboard", "--port", "4001", "--daemon"]);
+        assert_eq!(launch.name, "clipboard-sync");
+
+        let launch = self::launch(&["--daemon", "--profile", "work", "service", "install"], Some("work")).unwrap();
+        assert_eq!(launch.args, ["--daemon", "--profile", "work"]);
+        assert_eq!(launch.name, "clipboard-sync-work");
+    }
+
+    #[test]
+    fn secrets_are_only_stored_as_keyring_references() {
+        for command_line in [&["--audit-key", "hunter2", "service", "install"][..], &["--identity-seed=correct horse", "service", "install"]] {
+            let error = launch(command_line, None).unwrap_err();
+            assert!(error.to_string().contains("plain text"), "{error}");
+        }
+        let keyring = format!("{}audit", secrets::KEYRING_PREFIX);
+        assert!(launch(&["--audit-key", &keyring, "service", "install"], None).is_ok());
+        assert!(launch(&[&format!("--audit-key={keyring}"), "service", "install"], None).is_ok());
+    }
+}
@@ -0,0 +1,125 @@
+use super::{command, query, Launch, ServiceAction};
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Reverse DNS prefix of the LaunchAgent labels
+const LABEL_PREFIX: &str = "io.github.xujiewocao";
+
+pub fn label(launch: &Launch) -> String {
+    format!("{LABEL_PREFIX}.{}", launch.name)
+}
+
+/// Contents of the LaunchAgent plist running `launch`. `KeepAlive` with
+/// `SuccessfulExit` false restarts the node only when it fails.
+pub fn plist(launch: &Launch, log: &Path) -> String {
+    let arguments: String = std::iter::once(launch.program.to_string_lossy().into_owned())
+        .chain(launch.args.iter().cloned())
+        .map(|arg| format!("        <string>{}</string>\n", escape(&arg)))
+        .collect();
+    let log = escape(&log.to_string_lossy());
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <!-- Installed by `clipboard-sync service install` -->\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n\
+         \x20   <key>Label</key>\n\
+         \x20   <string>{label}</string>\n\
+         \x20   <key>ProgramArguments</key>\n\
+         \x20   <array>\n\
+         {arguments}\
+         \x20   </array>\n\
+         \x20   <key>RunAtLoad</key>\n\
+         \x20   <true/>\n\
+         \x20   <key>KeepAlive</key>\n\
+         \x20   <dict>\n\
+         \x20       <key>SuccessfulExit</key>\n\
+         \x20       <false/>\n\
+         \x20   </dict>\n\
+         \x20   <key>ThrottleInterval</key>\n\
+         \x20   <integer>5</integer>\n\
+         \x20   <key>StandardErrorPath</key>\n\
+         \x20   <string>{log}</string>\n\
+         </dict>\n\
+         </plist>\n",
+        label = escape(&label(launch)),
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn plist_path(launch: &Launch) -> Result<PathBuf> {
+    Ok(super::home_dir()?.join("Library/LaunchAgents").join(format!("{}.plist", label(launch))))
+}
+
+fn log_path(launch: &Launch) -> Result<PathBuf> {
+    Ok(super::home_dir()?.join("Library/Logs").join(format!("{}.log", launch.name)))
+}
+
+pub fn run(action: &ServiceAction, launch: &Launch) -> Result<()> {
+    let path = plist_path(launch)?;
+    let path_arg = path.to_string_lossy().into_owned();
+    let label = label(launch);
+    match action {
+        ServiceAction::Install => {
+            // A loaded agent keeps its old definition until it is unloaded
+            if path.exists() {
+                let _ = command("launchctl", &["unload", &path_arg]);
+            }
+            let dir = path.parent().context("Plist path has no directory")?;
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+            fs::write(&path, plist(launch, &log_path(launch)?))
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            command("launchctl", &["load", "-w", &path_arg])?;
+            println!("Installed and started {label} ({})", path.display());
+        }
+        ServiceAction::Uninstall => {
+            if !path.exists() {
+                bail!("{label} is not installed");
+            }
+            command("launchctl", &["unload", "-w", &path_arg])?;
+            fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+            println!("Stopped and removed {label}");
+        }
+        ServiceAction::Status => {
+            if !path.exists() {
+                println!("{label} is not installed");
+                return Ok(());
+            }
+            let state = match query("launchctl", &["list", &label]) {
+                Some(listing) if listing.contains("\"PID\"") => "running",
+                Some(_) => "loaded, not running",
+                None => "not loaded",
+            };
+            println!("{label} is installed at {}: {state}", path.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_plist_runs_the_daemon_and_restarts_it_on_failure() {
+        let launch = Launch {
+            program: PathBuf::from("/Applications/clipboard-sync"),
+            args: vec!["--device-name".to_string(), "<me> & co".to_string(), "--daemon".to_string()],
+            name: "clipboard-sync-work".to_string(),
+        };
+        let plist = plist(&launch, Path::new("/Users/me/Library/Logs/clipboard-sync-work.log"));
+        assert!(plist.contains("<string>io.github.xujiewocao.clipboard-sync-work</string>"));
+        assert!(plist.contains(
+            "        <string>/Applications/clipboard-sync</string>\n\
+             \x20       <string>--device-name</string>\n\
+             \x20       <string>&lt;me&gt; &amp; co</string>\n\
+             \x20       <string>--daemon</string>\n"
+        ), "{plist}");
+        assert!(plist.contains("<key>SuccessfulExit</key>\n        <false/>"));
+        assert!(plist.contains("<string>/Users/me/Library/Logs/clipboard-sync-work.log</string>"));
+    }
+}
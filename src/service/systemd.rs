@@ -0,0 +1,103 @@
+use super::{command, query, Launch, ServiceAction};
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::PathBuf;
+
+/// Contents of the systemd user unit running `launch`
+pub fn unit(launch: &Launch) -> String {
+    let exec = std::iter::once(launch.program.to_string_lossy().into_owned())
+        .chain(launch.args.iter().cloned())
+        .map(|arg| quote(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "# Installed by `clipboard-sync service install`\n\
+         [Unit]\n\
+         Description=libp2p clipboard sync ({name})\n\
+         After=graphical-session.target network-online.target\n\
+         \n\
+         [Service]\n\
+         ExecStart={exec}\n\
+         Restart=on-failure\n\
+         RestartSec=5\n\
+         \n\
+         [Install]\n\
+         WantedBy=default.target\n",
+        name = launch.name
+    )
+}
+
+/// Quote one command line word for `ExecStart`. Specifiers (`%`) and
+/// variables (`$`) are escaped so arguments reach the node unchanged.
+fn quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{escaped}\"")
+}
+
+fn unit_path(launch: &Launch) -> Result<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => super::home_dir()?.join(".config"),
+    };
+    Ok(config_home.join("systemd/user").join(format!("{}.service", launch.name)))
+}
+
+pub fn run(action: &ServiceAction, launch: &Launch) -> Result<()> {
+    let path = unit_path(launch)?;
+    let unit_name = format!("{}.service", launch.name);
+    match action {
+        ServiceAction::Install => {
+            let dir = path.parent().context("Unit path has no directory")?;
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+            fs::write(&path, unit(launch)).with_context(|| format!("Failed to write {}", path.display()))?;
+            command("systemctl", &["--user", "daemon-reload"])?;
+            command("systemctl", &["--user", "enable", &unit_name])?;
+            // Restart rather than start so a reinstall picks up new flags
+            command("systemctl", &["--user", "restart", &unit_name])?;
+            println!("Installed and started {unit_name} ({})", path.display());
+        }
+        ServiceAction::Uninstall => {
+            if !path.exists() {
+                bail!("{unit_name} is not installed");
+            }
+            command("systemctl", &["--user", "disable", "--now", &unit_name])?;
+            fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+            command("systemctl", &["--user", "daemon-reload"])?;
+            println!("Stopped and removed {unit_name}");
+        }
+        ServiceAction::Status => {
+            if !path.exists() {
+                println!("{unit_name} is not installed");
+                return Ok(());
+            }
+            let active = query("systemctl", &["--user", "is-active", &unit_name]).unwrap_or_else(|| "unknown".to_string());
+            let enabled = query("systemctl", &["--user", "is-enabled", &unit_name]).unwrap_or_else(|| "unknown".to_string());
+            println!("{unit_name} is installed at {}: {active}, {enabled}", path.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_unit_runs_the_daemon_and_restarts_it_on_failure() {
+        let launch = Launch {
+            program: PathBuf::from("/opt/clipboard sync/clipboard-sync"),
+            args: vec!["--device-name".to_string(), "50% \"desk\" $HOME".to_string(), "--daemon".to_string()],
+            name: "clipboard-sync".to_string(),
+        };
+        let unit = unit(&launch);
+        assert!(unit.contains(
+            "ExecStart=\"/opt/clipboard sync/clipboard-sync\" \"--device-name\" \"50%% \\\"desk\\\" $$HOME\" \"--daemon\"\n"
+        ), "{unit}");
+        assert!(unit.contains("Restart=on-failure\n"));
+        assert!(unit.contains("WantedBy=default.target\n"));
+    }
+}
@@ -0,0 +1,214 @@
+use super::{command, query, Launch, ServiceAction};
+use anyhow::{bail, Context, Result};
+use std::fs;
+
+/// Per-user autostart entries, used when a scheduled task can't be created
+const RUN_KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Run";
+
+/// Full command line of `launch`, quoted the way the C runtime splits it
+pub fn command_line(launch: &Launch) -> String {
+    std::iter::once(launch.program.to_string_lossy().into_owned())
+        .chain(launch.args.iter().cloned())
+        .map(|arg| quote(&arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Quote one argument so `CommandLineToArgvW` gives it back unchanged
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                quoted.push('"');
+                backslashes = 0;
+            }
+            _ => {
+                quoted.push_str(&"\\".repeat(backslashes));
+                quoted.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+/// Task Scheduler definition starting `launch` at logon of `user` in their
+/// desktop session, restarted if it fails. A Windows service would run in
+/// session 0, which has no access to the user's clipboard.
+pub fn task_xml(launch: &Launch, user: &str) -> String {
+    let arguments = launch.args.iter().map(|arg| quote(arg)).collect::<Vec<_>>().join(" ");
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-16\"?>\r\n\
+         <Task version=\"1.2\" xmlns=\"http://schemas.microsoft.com/windows/2004/02/mit/task\">\r\n\
+         \x20 <RegistrationInfo>\r\n\
+         \x20   <Description>libp2p clipboard sync ({name}), installed by `clipboard-sync service install`</Description>\r\n\
+         \x20 </RegistrationInfo>\r\n\
+         \x20 <Triggers>\r\n\
+         \x20   <LogonTrigger>\r\n\
+         \x20     <Enabled>true</Enabled>\r\n\
+         \x20     <UserId>{user}</UserId>\r\n\
+         \x20   </LogonTrigger>\r\n\
+         \x20 </Triggers>\r\n\
+         \x20 <Principals>\r\n\
+         \x20   <Principal id=\"Author\">\r\n\
+         \x20     <UserId>{user}</UserId>\r\n\
+         \x20     <LogonType>InteractiveToken</LogonType>\r\n\
+         \x20     <RunLevel>LeastPrivilege</RunLevel>\r\n\
+         \x20   </Principal>\r\n\
+         \x20 </Principals>\r\n\
+         \x20 <Settings>\r\n\
+         \x20   <MultipleInstancesPolicy>IgnoreNew</MultipleInstancesPolicy>\r\n\
+         \x20   <DisallowStartIfOnBatteries>false</DisallowStartIfOnBatteries>\r\n\
+         \x20   <StopIfGoingOnBatteries>false</StopIfGoingOnBatteries>\r\n\
+         \x20   <ExecutionTimeLimit>PT0S</ExecutionTimeLimit>\r\n\
+         \x20   <RestartOnFailure>\r\n\
+         \x20     <Interval>PT1M</Interval>\r\n\
+         \x20     <Count>999</Count>\r\n\
+         \x20   </RestartOnFailure>\r\n\
+         \x20   <Enabled>true</Enabled>\r\n\
+         \x20 </Settings>\r\n\
+         \x20 <Actions Context=\"Author\">\r\n\
+         \x20   <Exec>\r\n\
+         \x20     <Command>{command}</Command>\r\n\
+         \x20     <Arguments>{arguments}</Arguments>\r\n\
+         \x20   </Exec>\r\n\
+         \x20 </Actions>\r\n\
+         </Task>\r\n",
+        name = escape(&launch.name),
+        user = escape(user),
+        command = escape(&launch.program.to_string_lossy()),
+        arguments = escape(&arguments),
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// `DOMAIN\user` of whoever runs the command
+fn current_user() -> Result<String> {
+    let user = std::env::var("USERNAME").context("USERNAME is not set")?;
+    Ok(match std::env::var("USERDOMAIN") {
+        Ok(domain) => format!("{domain}\\{user}"),
+        Err(_) => user,
+    })
+}
+
+/// Whether a scheduled task named like `launch` exists
+fn task_exists(launch: &Launch) -> bool {
+    command("schtasks", &["/Query", "/TN", &launch.name]).is_ok()
+}
+
+/// Whether the Run key has an entry named like `launch`
+fn run_entry_exists(launch: &Launch) -> bool {
+    command("reg", &["query", RUN_KEY, "/v", &launch.name]).is_ok()
+}
+
+pub fn run(action: &ServiceAction, launch: &Launch) -> Result<()> {
+    match action {
+        ServiceAction::Install => {
+            // schtasks only reads task XML reliably as UTF-16 with a BOM
+            let xml = task_xml(launch, &current_user()?);
+            let encoded: Vec<u8> = std::iter::once(0xfeff)
+                .chain(xml.encode_utf16())
+                .flat_map(u16::to_le_bytes)
+                .collect();
+            let path = std::env::temp_dir().join(format!("{}-task.xml", launch.name));
+            fs::write(&path, encoded).with_context(|| format!("Failed to write {}", path.display()))?;
+            let path_arg = path.to_string_lossy().into_owned();
+            let created = command("schtasks", &["/Create", "/TN", &launch.name, "/XML", &path_arg, "/F"]);
+            let _ = fs::remove_file(&path);
+            match created {
+                Ok(_) => {
+                    // Only one kind of entry, or the node would start twice
+                    if run_entry_exists(launch) {
+                        command("reg", &["delete", RUN_KEY, "/v", &launch.name, "/f"])?;
+                    }
+                    command("schtasks", &["/Run", "/TN", &launch.name])?;
+                    println!("Installed and started scheduled task {}", launch.name);
+                }
+                Err(e) => {
+                    eprintln!("Could not create a scheduled task ({e}), using the Run key instead");
+                    eprintln!("The Run key starts the node at login but does not restart it if it fails");
+                    command("reg", &["add", RUN_KEY, "/v", &launch.name, "/t", "REG_SZ", "/d", &command_line(launch), "/f"])?;
+                    println!("Installed {} in {RUN_KEY}; it starts at the next login", launch.name);
+                }
+            }
+        }
+        ServiceAction::Uninstall => {
+            let (task, run_entry) = (task_exists(launch), run_entry_exists(launch));
+            if !task && !run_entry {
+                bail!("{} is not installed", launch.name);
+            }
+            if task {
+                let _ = command("schtasks", &["/End", "/TN", &launch.name]);
+                command("schtasks", &["/Delete", "/TN", &launch.name, "/F"])?;
+                println!("Stopped and removed scheduled task {}", launch.name);
+            }
+            if run_entry {
+                command("reg", &["delete", RUN_KEY, "/v", &launch.name, "/f"])?;
+                println!("Removed {} from {RUN_KEY}; a running node keeps running until logout", launch.name);
+            }
+        }
+        ServiceAction::Status => {
+            if task_exists(launch) {
+                let listing = query("schtasks", &["/Query", "/TN", &launch.name, "/FO", "LIST"]).unwrap_or_default();
+                let status = listing
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Status:"))
+                    .map_or("unknown", str::trim);
+                println!("Scheduled task {} is installed: {status}", launch.name);
+            } else if run_entry_exists(launch) {
+                println!("{} is installed in {RUN_KEY} and starts at login", launch.name);
+            } else {
+                println!("{} is not installed", launch.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn launch() -> Launch {
+        Launch {
+            program: PathBuf::from(r"C:\Program Files\clipboard-sync.exe"),
+            args: vec!["--device-name".to_string(), r#"my "desk""#.to_string(), "--daemon".to_string()],
+            name: "clipboard-sync".to_string(),
+        }
+    }
+
+    #[test]
+    fn arguments_are_quoted_for_the_c_runtime() {
+        assert_eq!(quote("--daemon"), "--daemon");
+        assert_eq!(quote(""), r#""""#);
+        assert_eq!(quote(r"C:\dir with space\"), r#""C:\dir with space\\""#);
+        assert_eq!(quote(r#"a\"b"#), r#""a\\\"b""#);
+        assert_eq!(
+            command_line(&launch()),
+            r#""C:\Program Files\clipboard-sync.exe" --device-name "my \"desk\"" --daemon"#
+        );
+    }
+
+    #[test]
+    fn the_task_starts_at_logon_and_restarts_on_failure() {
+        let xml = task_xml(&launch(), r"DESK\me");
+        assert!(xml.contains("<UserId>DESK\\me</UserId>"));
+        assert!(xml.contains(r"<Command>C:\Program Files\clipboard-sync.exe</Command>"));
+        assert!(xml.contains("<Arguments>--device-name &quot;my \\&quot;desk\\&quot;&quot; --daemon</Arguments>"), "{xml}");
+        assert!(xml.contains("<RestartOnFailure>"));
+        assert!(xml.contains("<LogonType>InteractiveToken</LogonType>"));
+    }
+}
@@ -0,0 +1,262 @@
+//! Soak test: a group of in-process nodes, each running the whole event loop
+//! on a swarm over the in-memory transport, taken through a long timeline of
+//! copies, churn, restarts and garbage. Built with `--features soak`; the
+//! timeline is sized by `SOAK_NODES`, `SOAK_PHASES` and `SOAK_SEED`.
+
+use crate::control::NodeEvent;
+use crate::in_process::Node;
+use crate::{create_memory_swarm, Args, CLIPBOARD_BULK_TOPIC, CLIPBOARD_TOPIC};
+use anyhow::{ensure, Result};
+use clap::Parser;
+use futures::StreamExt;
+use libp2p::gossipsub::IdentTopic;
+use libp2p::{identity, Multiaddr, PeerId};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// Longest the group may take to agree on a copy
+const CONVERGE: Duration = Duration::from_secs(30);
+/// Largest text copied, in bytes
+const MAX_TEXT: u64 = 256 * 1024;
+/// Sizes of copied images
+const IMAGE_SIZES: &[(usize, usize)] = &[(16, 16), (64, 64), (128, 96)];
+/// How long a rogue peer sends garbage for
+const ROGUE_TIME: Duration = Duration::from_secs(2);
+/// How long after meeting everyone a node may still be handed the latest
+/// copy by peers that just saw it subscribe
+const CATCH_UP: Duration = Duration::from_secs(1);
+
+/// xorshift64*, enough to make a timeline reproducible from its seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed ^ 0x9E37_79B9_7F4A_7C15 | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+/// A node of the group and the one it joined through
+struct Member {
+    node: Node,
+    contact: Option<PeerId>,
+}
+
+/// What was copied last, which every node should end up holding
+enum Copied {
+    Text(String),
+    Image(Vec<u8>),
+}
+
+impl Copied {
+    fn random(rng: &mut Rng) -> Self {
+        if rng.chance(0.2) {
+            let (width, height) = IMAGE_SIZES[rng.below(IMAGE_SIZES.len() as u64) as usize];
+            return Copied::Image((0..width * height * 4).map(|_| rng.next() as u8).collect());
+        }
+        // Mostly short text, now and then a large paste
+        let max = if rng.chance(0.1) { MAX_TEXT } else { 512 };
+        let len = 1 + rng.below(max);
+        Copied::Text((0..len).map(|_| (b'a' + rng.below(26) as u8) as char).collect())
+    }
+
+    fn copy_on(&self, node: &Node) {
+        match self {
+            Copied::Text(text) => node.clipboard.copy_text(text),
+            Copied::Image(bytes) => {
+                let (width, height) = IMAGE_SIZES.iter().copied().find(|(width, height)| width * height * 4 == bytes.len()).unwrap();
+                node.clipboard.copy_image(bytes.clone(), width, height);
+            }
+        }
+    }
+
+    fn held_by(&self, node: &Node) -> bool {
+        match self {
+            Copied::Text(text) => node.clipboard.text().as_deref() == Some(text.as_str()),
+            Copied::Image(bytes) => node.clipboard.image().is_some_and(|image| image.bytes.as_ref() == bytes.as_slice()),
+        }
+    }
+}
+
+fn setting(name: &str, default: u64) -> u64 {
+    std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+/// Start a node joining `group` through the member at `contact`
+async fn join(group: &[Member], contact: Option<usize>) -> Result<Member> {
+    let mut flags = vec!["--clipboard".to_string(), "--ignore-initial-clipboard".to_string()];
+    if let Some(contact) = contact {
+        flags.extend(["--connect".to_string(), group[contact].node.address.to_string()]);
+    }
+    let node = Node::start(&flags.iter().map(String::as_str).collect::<Vec<_>>())?;
+    let mut member = Member { node, contact: contact.map(|contact| group[contact].node.peer_id) };
+    member.met(group).await?;
+    Ok(member)
+}
+
+impl Member {
+    /// Wait until the node found every member of `group` and was handed the
+    /// latest copy. A copy made before then can be overwritten by that
+    /// catch-up before the clipboard monitor reads it, as on a real machine.
+    async fn met(&mut self, group: &[Member]) -> Result<()> {
+        let mut unmet: HashSet<PeerId> = group.iter().map(|member| member.node.peer_id).collect();
+        while !unmet.is_empty() {
+            let peer = self
+                .node
+                .wait_for(CONVERGE, |event| match event {
+                    NodeEvent::PeerIdentified { peer, .. } => Some(*peer),
+                    _ => None,
+                })
+                .await?;
+            unmet.remove(&peer);
+        }
+        tokio::time::sleep(CATCH_UP).await;
+        Ok(())
+    }
+}
+
+/// Wait until every member holds `copied`
+async fn converge(group: &[Member], copied: &Copied, phase: u64) -> Result<()> {
+    let deadline = Instant::now() + CONVERGE;
+    loop {
+        let behind: Vec<PeerId> =
+            group.iter().filter(|member| !copied.held_by(&member.node)).map(|member| member.node.peer_id).collect();
+        if behind.is_empty() {
+            return Ok(());
+        }
+        ensure!(Instant::now() < deadline, "phase {phase}: {} of {} nodes never got the last copy: {behind:?}", behind.len(), group.len());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// A peer from outside the group, on a real swarm, publishing frames that
+/// don't decode on the clipboard topics of the node at `target`
+async fn send_garbage(target: Multiaddr, rng: &mut Rng) -> Result<usize> {
+    let args = Args::try_parse_from(["clipboard-sync"])?;
+    let mut swarm = create_memory_swarm(identity::Keypair::generate_ed25519(), &args)?;
+    let topics = [IdentTopic::new(CLIPBOARD_TOPIC), IdentTopic::new(CLIPBOARD_BULK_TOPIC)];
+    for topic in &topics {
+        swarm.behaviour_mut().gossipsub.subscribe(topic)?;
+    }
+    swarm.dial(target)?;
+    let frames: Vec<Vec<u8>> = (0..16)
+        .map(|index| match index % 3 {
+            0 => (0..1 + rng.below(64)).map(|_| rng.next() as u8).collect(),
+            1 => b"{\"content_type\":\"Text\",\"data\":".to_vec(),
+            _ => vec![0xff; 1 + rng.below(4096) as usize],
+        })
+        .collect();
+    let mut published = 0;
+    let mut tick = tokio::time::interval(Duration::from_millis(100));
+    let until = tokio::time::Instant::now() + ROGUE_TIME;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(until) => return Ok(published),
+            _ = swarm.select_next_some() => {}
+            _ = tick.tick() => {
+                let frame = frames[published % frames.len()].clone();
+                // Refused until the node's subscription is known
+                if swarm.behaviour_mut().gossipsub.publish(topics[published % 2].clone(), frame).is_ok() {
+                    published += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Alive tasks once the ones that should be finishing have had the chance
+async fn settled_tasks(bound: usize) -> usize {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    for _ in 0..200 {
+        if metrics.num_alive_tasks() <= bound {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(25)).await;
+    }
+    metrics.num_alive_tasks()
+}
+
+/// Take a group through the timeline. After every phase all nodes must hold
+/// the last copy, whoever left, joined or restarted, and no tasks of stopped
+/// nodes may be left behind.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_group_keeps_converging_through_churn_restarts_and_garbage() -> Result<()> {
+    let (nodes, phases, seed) = (setting("SOAK_NODES", 4).max(2), setting("SOAK_PHASES", 10), setting("SOAK_SEED", 1));
+    println!("Soaking {nodes} nodes for {phases} phases, rerun with SOAK_SEED={seed}");
+    let mut rng = Rng::new(seed);
+    let baseline = tokio::runtime::Handle::current().metrics().num_alive_tasks();
+    let mut group = vec![join(&[], None).await?];
+    while group.len() < nodes as usize {
+        // Each joins through a random member and finds the rest by peer exchange
+        let contact = rng.below(group.len() as u64) as usize;
+        let member = join(&group, Some(contact)).await?;
+        group.push(member);
+    }
+    let mut last: Option<Copied> = None;
+    let (mut copies, mut garbage, mut churned, mut restarted) = (0u64, 0usize, 0u64, 0u64);
+
+    for phase in 1..=phases {
+        // A node leaves and a new one joins through someone else
+        if rng.chance(0.3) {
+            let leaving = rng.below(group.len() as u64) as usize;
+            let member = group.swap_remove(leaving);
+            member.node.stop().await?;
+            let contact = rng.below(group.len() as u64) as usize;
+            let member = join(&group, Some(contact)).await?;
+            group.push(member);
+            churned += 1;
+            // The newcomer catches up on the latest copy
+            if let Some(ref last) = last {
+                converge(&group, last, phase).await?;
+            }
+        }
+        // A node restarts and dials back in, if the member it joined through is still around
+        let index = rng.below(group.len() as u64) as usize;
+        if rng.chance(0.3) && group[index].contact.is_some_and(|contact| group.iter().any(|member| member.node.peer_id == contact)) {
+            let member = group.swap_remove(index);
+            let node = member.node.restart().await?;
+            let mut member = Member { node, contact: member.contact };
+            member.met(&group).await?;
+            group.push(member);
+            restarted += 1;
+        }
+        // A peer outside the group sends frames that don't decode
+        if rng.chance(0.3) {
+            let target = group[rng.below(group.len() as u64) as usize].node.address.clone();
+            garbage += send_garbage(target, &mut rng).await?;
+        }
+
+        for _ in 0..1 + rng.below(4) {
+            let copied = Copied::random(&mut rng);
+            let on = rng.below(group.len() as u64) as usize;
+            copied.copy_on(&group[on].node);
+            converge(&group, &copied, phase).await?;
+            last = Some(copied);
+            copies += 1;
+        }
+
+        let tasks = tokio::runtime::Handle::current().metrics().num_alive_tasks();
+        println!("phase {phase:>4}: {copies} copies, {garbage} garbage frames, {churned} nodes replaced, {restarted} restarted, tasks {tasks}");
+    }
+
+    for member in group {
+        member.node.stop().await?;
+    }
+    let tasks = settled_tasks(baseline).await;
+    ensure!(tasks <= baseline, "{} tasks still alive after every node stopped", tasks - baseline);
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Spill files are named `spill-<pid>-<n>.bin` so leftovers from a crashed
+/// run can be recognised and removed
+const SPILL_PREFIX: &str = "spill-";
+const SPILL_SUFFIX: &str = ".bin";
+/// Block size for streaming payloads to and from disk
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A payload moved out of memory into a temporary file. The file is deleted
+/// when the last reference goes away.
+#[derive(Debug)]
+pub struct SpillFile {
+    path: PathBuf,
+    len: u64,
+    sha256: [u8; 32],
+}
+
+impl SpillFile {
+    /// Stream `data` into a new spill file in `dir`, hashing as it is written
+    pub fn write(dir: &Path, data: &[u8]) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create spill directory {}", dir.display()))?;
+        let name = format!(
+            "{SPILL_PREFIX}{}-{}{SPILL_SUFFIX}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+
+        let mut file = File::create(&path)
+            .with_context(|| format!("Failed to create spill file {}", path.display()))?;
+        // Build the handle first so the file is removed if writing fails midway
+        let mut spill = Self { path, len: 0, sha256: [0; 32] };
+        let mut hasher = Sha256::new();
+        for block in data.chunks(BLOCK_SIZE) {
+            hasher.update(block);
+            file.write_all(block)
+                .with_context(|| format!("Failed to write spill file {}", spill.path.display()))?;
+        }
+        spill.len = data.len() as u64;
+        spill.sha256 = hasher.finalize().into();
+        debug!("Spilled {} bytes to {}", spill.len, spill.path.display());
+        Ok(spill)
+    }
+
+    /// Read the payload back, verifying its hash as it streams in
+    pub fn read(&self) -> Result<Vec<u8>> {
+        let mut file = File::open(&self.path)
+            .with_context(|| format!("Failed to open spill file {}", self.path.display()))?;
+        let mut data = Vec::with_capacity(self.len as usize);
+        let mut hasher = Sha256::new();
+        let mut block = vec![0u8; BLOCK_SIZE];
+        loop {
+            let n = file.read(&mut block)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&block[..n]);
+            data.extend_from_slice(&block[..n]);
+        }
+
+        if data.len() as u64 != self.len || <[u8; 32]>::from(hasher.finalize()) != self.sha256 {
+            bail!("Spill file {} was modified on disk", self.path.display());
+        }
+        Ok(data)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// SHA-256 of the payload, taken as it was written
+    pub fn sha256(&self) -> [u8; 32] {
+        self.sha256
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove spill file {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// Remove spill files left behind in `dir` by a previous run that did not
+/// shut down cleanly, returning how many were removed
+pub fn scavenge(dir: &Path) -> Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(SPILL_PREFIX) && name.ends_with(SPILL_SUFFIX) {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
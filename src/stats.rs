@@ -0,0 +1,548 @@
+use crate::bandwidth::Bandwidth;
+use crate::clipboard::{CacheCounters, ContentType};
+use crate::timing::OpTimings;
+use libp2p::PeerId;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Number of delivery samples kept per peer for the rolling percentiles
+const LATENCY_WINDOW: usize = 100;
+/// Percentiles reported in `/stats` and the metrics endpoint
+const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+/// Upper bounds of the payload size histogram buckets, in bytes
+const SIZE_BUCKETS: [u64; 9] = [
+    1 << 10, 4 << 10, 16 << 10, 64 << 10, 256 << 10, 1 << 20, 4 << 20, 16 << 20, 64 << 20,
+];
+
+/// Stats registry shared between the event loop and the metrics endpoint
+pub type SharedStats = Arc<Mutex<Stats>>;
+
+/// Sync latency bookkeeping for a single peer
+#[derive(Debug, Default)]
+pub struct PeerLatency {
+    /// Arrival time minus the sender's timestamp, in ms. Includes clock offset.
+    delays: VecDeque<i64>,
+    rtt_ms: Option<u64>,
+    /// Arrival time of the latest delivery, in ms since the Unix epoch
+    last_sync_ms: Option<u64>,
+}
+
+impl PeerLatency {
+    /// Record a ping round-trip time
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.rtt_ms = Some(rtt.as_millis() as u64);
+    }
+
+    /// Record a message sent at `sent_ms` (sender clock) arriving at
+    /// `arrived_ms` (our clock), returning the skew-corrected latency
+    pub fn record_delivery(&mut self, sent_ms: u64, arrived_ms: u64) -> u64 {
+        if self.delays.len() == LATENCY_WINDOW {
+            self.delays.pop_front();
+        }
+        self.delays.push_back(arrived_ms as i64 - sent_ms as i64);
+        self.last_sync_ms = Some(arrived_ms);
+        self.correct(arrived_ms as i64 - sent_ms as i64)
+    }
+
+    /// Estimated offset of our clock relative to the peer's, in ms.
+    ///
+    /// The fastest delivery in the window can't have taken less than half a
+    /// round trip, so anything it shows beyond that is clock offset. Offsets
+    /// within one RTT are indistinguishable from network jitter and treated as
+    /// synchronized clocks.
+    pub fn clock_offset_ms(&self) -> i64 {
+        let Some(&min_delay) = self.delays.iter().min() else {
+            return 0;
+        };
+        let rtt = self.rtt_ms.unwrap_or(0) as i64;
+        let offset = min_delay - rtt / 2;
+        if min_delay >= 0 && offset.abs() <= rtt {
+            0
+        } else {
+            offset
+        }
+    }
+
+    pub fn rtt_ms(&self) -> Option<u64> {
+        self.rtt_ms
+    }
+
+    pub fn last_sync_ms(&self) -> Option<u64> {
+        self.last_sync_ms
+    }
+
+    pub fn samples(&self) -> usize {
+        self.delays.len()
+    }
+
+    /// Skew-corrected latency summed over the window, with the number of samples
+    fn total(&self) -> (u64, u64) {
+        (self.delays.iter().map(|&d| self.correct(d)).sum(), self.delays.len() as u64)
+    }
+
+    /// Skew-corrected latency percentile over the window, `q` in 0..=1
+    pub fn percentile(&self, q: f64) -> Option<u64> {
+        if self.delays.is_empty() {
+            return None;
+        }
+        let mut corrected: Vec<u64> = self.delays.iter().map(|&d| self.correct(d)).collect();
+        corrected.sort_unstable();
+        let index = ((corrected.len() - 1) as f64 * q).round() as usize;
+        Some(corrected[index])
+    }
+
+    fn correct(&self, delay: i64) -> u64 {
+        (delay - self.clock_offset_ms()).max(0) as u64
+    }
+}
+
+/// Whether a payload was published by us or received from a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    pub fn label(self) -> &'static str {
+        match self {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        }
+    }
+}
+
+/// Distribution of serialized payload sizes over fixed buckets
+#[derive(Debug, Default)]
+pub struct SizeHistogram {
+    /// Non-cumulative count per bucket in `SIZE_BUCKETS`, plus one overflow bucket
+    buckets: [u64; SIZE_BUCKETS.len() + 1],
+    sum: u64,
+    max: u64,
+}
+
+impl SizeHistogram {
+    pub fn record(&mut self, size: usize) {
+        let size = size as u64;
+        let bucket = SIZE_BUCKETS.iter().position(|&bound| size <= bound).unwrap_or(SIZE_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += size;
+        self.max = self.max.max(size);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// Largest payload recorded
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Cumulative count of payloads no larger than each bucket bound, as
+    /// Prometheus expects
+    pub fn cumulative(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        SIZE_BUCKETS.iter().zip(self.buckets.iter().scan(0, |total, &n| {
+            *total += n;
+            Some(*total)
+        }))
+        .map(|(&bound, count)| (bound, count))
+    }
+}
+
+/// Runtime statistics shown by `/stats` and exported as metrics
+#[derive(Debug, Default)]
+pub struct Stats {
+    peers: HashMap<PeerId, PeerLatency>,
+    sizes: BTreeMap<(Direction, &'static str), SizeHistogram>,
+    /// Clipboard items sent to and received from each room
+    rooms: BTreeMap<(String, Direction), u64>,
+    /// Active reservations and circuits when running as a relay server
+    relay: Option<(usize, usize)>,
+    payload_cache: Option<Arc<CacheCounters>>,
+    op_timings: Option<Arc<OpTimings>>,
+    bandwidth: Bandwidth,
+    /// Every peer connected to this session, with its device name if known
+    peers_seen: BTreeMap<PeerId, Option<String>>,
+    /// Failed publishes by reason
+    publish_failures: BTreeMap<String, u64>,
+    /// Clipboard publishes waiting for gossipsub's send queues to drain
+    publish_queue: usize,
+    /// Publishes retried after finding the send queues full
+    publish_retries: u64,
+    /// Clipboard items sent as direct requests after their retries ran out
+    publish_fallbacks: u64,
+    /// Local copies sent in another form for being too large, by downgrade
+    downgrades: BTreeMap<&'static str, u64>,
+}
+
+impl Stats {
+    /// Latency bookkeeping for `peer`, created on first use
+    pub fn peer(&mut self, peer: PeerId) -> &mut PeerLatency {
+        self.peers.entry(peer).or_default()
+    }
+
+    /// Latency bookkeeping for `peer`, if anything was recorded yet
+    pub fn latency(&self, peer: &PeerId) -> Option<&PeerLatency> {
+        self.peers.get(peer)
+    }
+
+    /// Up to `n` of `candidates` with the lowest ping round-trip time. Peers
+    /// not pinged yet come last.
+    pub fn closest_peers(&self, candidates: &[PeerId], n: usize) -> Vec<PeerId> {
+        let mut ranked = candidates.to_vec();
+        ranked.sort_by_key(|peer| self.latency(peer).and_then(PeerLatency::rtt_ms).unwrap_or(u64::MAX));
+        ranked.truncate(n);
+        ranked
+    }
+
+    /// Per-peer traffic accounting and the bandwidth cap
+    pub fn bandwidth(&mut self) -> &mut Bandwidth {
+        &mut self.bandwidth
+    }
+
+    /// Bytes sent to and received from `peer` in total and within the
+    /// rolling window, if any were recorded
+    pub fn traffic(&self, peer: &PeerId) -> Option<((u64, u64), (u64, u64))> {
+        self.bandwidth.peer(peer).map(|traffic| (traffic.total(), traffic.recent()))
+    }
+
+    /// Record the serialized size of a clipboard payload
+    pub fn record_size(&mut self, direction: Direction, content_type: &ContentType, size: usize) {
+        let content_type = match content_type {
+            ContentType::Text => "text",
+            ContentType::Image => "image",
+            ContentType::Files => "files",
+        };
+        self.sizes.entry((direction, content_type)).or_default().record(size);
+    }
+
+    /// Payload size histograms by direction and content type
+    pub fn sizes(&self) -> impl Iterator<Item = (Direction, &'static str, &SizeHistogram)> {
+        self.sizes.iter().map(|(&(direction, content_type), histogram)| (direction, content_type, histogram))
+    }
+
+    /// Remember that `peer` was connected, and its device name once known
+    pub fn record_peer(&mut self, peer: PeerId, device_name: Option<&str>) {
+        let name = self.peers_seen.entry(peer).or_default();
+        if let Some(device_name) = device_name {
+            *name = Some(device_name.to_string());
+        }
+    }
+
+    /// Peers connected during this session, with their device names if known
+    pub fn peers_seen(&self) -> &BTreeMap<PeerId, Option<String>> {
+        &self.peers_seen
+    }
+
+    /// Count a failed publish. Details in parentheses, such as the number of
+    /// peers in `AllQueuesFull(3)`, are left out of the reason.
+    pub fn record_publish_failure(&mut self, reason: &str) {
+        let reason = reason.split('(').next().unwrap_or(reason).trim();
+        *self.publish_failures.entry(reason.to_string()).or_default() += 1;
+    }
+
+    /// Failed publishes by reason
+    pub fn publish_failures(&self) -> &BTreeMap<String, u64> {
+        &self.publish_failures
+    }
+
+    /// Report how many clipboard publishes are waiting to be retried
+    pub fn set_publish_queue(&mut self, waiting: usize) {
+        self.publish_queue = waiting;
+    }
+
+    pub fn record_publish_retry(&mut self) {
+        self.publish_retries += 1;
+    }
+
+    pub fn record_publish_fallback(&mut self) {
+        self.publish_fallbacks += 1;
+    }
+
+    /// Count a local copy downgraded by `tier` for being too large
+    pub fn record_downgrade(&mut self, tier: &'static str) {
+        *self.downgrades.entry(tier).or_default() += 1;
+    }
+
+    /// Skew-corrected sync latency averaged over every peer's window
+    pub fn average_latency_ms(&self) -> Option<u64> {
+        let (sum, samples) = self.peers.values().map(PeerLatency::total).fold((0, 0), |(sum, samples), (s, n)| (sum + s, samples + n));
+        (samples > 0).then(|| sum / samples)
+    }
+
+    /// Count a clipboard item sent to or received from `room`
+    pub fn record_room(&mut self, direction: Direction, room: &str) {
+        *self.rooms.entry((room.to_string(), direction)).or_default() += 1;
+    }
+
+    /// Record the relay server's current number of reservations and circuits
+    pub fn set_relay_usage(&mut self, reservations: usize, circuits: usize) {
+        self.relay = Some((reservations, circuits));
+    }
+
+    /// Report the counters of the payload cache
+    pub fn set_payload_cache(&mut self, counters: Arc<CacheCounters>) {
+        self.payload_cache = Some(counters);
+    }
+
+    /// Report the durations of hot path operations
+    pub fn set_op_timings(&mut self, timings: Arc<OpTimings>) {
+        self.op_timings = Some(timings);
+    }
+
+    /// Human readable summary, one line per peer plus the payload cache, the
+    /// publish queue and one line per timed operation
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (peer, latency) in &self.peers {
+            let quantiles: Vec<String> = QUANTILES
+                .iter()
+                .map(|&q| match latency.percentile(q) {
+                    Some(ms) => format!("p{}={ms}ms", (q * 100.0) as u32),
+                    None => format!("p{}=-", (q * 100.0) as u32),
+                })
+                .collect();
+            let rtt = latency.rtt_ms().map(|ms| format!("{ms}ms")).unwrap_or_else(|| "-".to_string());
+            let last_sync = latency
+                .last_sync_ms()
+                .map(|ms| format!("{}s ago", crate::clipboard::now_millis().saturating_sub(ms) / 1000))
+                .unwrap_or_else(|| "never".to_string());
+            lines.push(format!(
+                "{peer}: {} (n={}), rtt={rtt}, clock offset={}ms, last sync {last_sync}",
+                quantiles.join(" "),
+                latency.samples(),
+                latency.clock_offset_ms()
+            ));
+        }
+        if let Some(ref cache) = self.payload_cache {
+            lines.push(format!(
+                "payload cache: {} entries, {} bytes, {} hits, {} misses, {} evictions",
+                cache.entries.load(Ordering::Relaxed),
+                cache.bytes.load(Ordering::Relaxed),
+                cache.hits.load(Ordering::Relaxed),
+                cache.misses.load(Ordering::Relaxed),
+                cache.evictions.load(Ordering::Relaxed)
+            ));
+        }
+        lines.push(format!(
+            "publish queue: {} waiting, {} retries, {} sent directly, {} failed",
+            self.publish_queue,
+            self.publish_retries,
+            self.publish_fallbacks,
+            self.publish_failures.values().sum::<u64>()
+        ));
+        if !self.downgrades.is_empty() {
+            let downgrades: Vec<String> = self.downgrades.iter().map(|(tier, count)| format!("{tier} {count}")).collect();
+            lines.push(format!("too large to send as is: {}", downgrades.join(", ")));
+        }
+        if let Some(ref timings) = self.op_timings {
+            for (operation, histogram) in timings.snapshot() {
+                lines.push(format!(
+                    "{operation}: n={}, avg={}ms, max={}ms",
+                    histogram.count(),
+                    histogram.sum_ms() / histogram.count().max(1),
+                    histogram.max_ms()
+                ));
+            }
+        }
+        lines
+    }
+
+    /// Render in the Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP clipboard_sync_latency_ms Skew-corrected copy-to-arrival latency per peer");
+        let _ = writeln!(out, "# TYPE clipboard_sync_latency_ms summary");
+        for (peer, latency) in &self.peers {
+            for q in QUANTILES {
+                if let Some(ms) = latency.percentile(q) {
+                    let _ = writeln!(out, "clipboard_sync_latency_ms{{peer=\"{peer}\",quantile=\"{q}\"}} {ms}");
+                }
+            }
+            let (sum, samples) = latency.total();
+            let _ = writeln!(out, "clipboard_sync_latency_ms_sum{{peer=\"{peer}\"}} {sum}");
+            let _ = writeln!(out, "clipboard_sync_latency_ms_count{{peer=\"{peer}\"}} {samples}");
+        }
+
+        let _ = writeln!(out, "# HELP clipboard_sync_peer_rtt_ms Last ping round-trip time per peer");
+        let _ = writeln!(out, "# TYPE clipboard_sync_peer_rtt_ms gauge");
+        for (peer, latency) in &self.peers {
+            if let Some(rtt) = latency.rtt_ms() {
+                let _ = writeln!(out, "clipboard_sync_peer_rtt_ms{{peer=\"{peer}\"}} {rtt}");
+            }
+        }
+
+        let _ = writeln!(out, "# HELP clipboard_sync_clock_offset_ms Estimated clock offset relative to each peer");
+        let _ = writeln!(out, "# TYPE clipboard_sync_clock_offset_ms gauge");
+        for (peer, latency) in &self.peers {
+            let _ = writeln!(out, "clipboard_sync_clock_offset_ms{{peer=\"{peer}\"}} {}", latency.clock_offset_ms());
+        }
+
+        let _ = writeln!(out, "# HELP clipboard_sync_peer_bytes_total Payload bytes exchanged with each peer");
+        let _ = writeln!(out, "# TYPE clipboard_sync_peer_bytes_total counter");
+        for (peer, traffic) in self.bandwidth.peers() {
+            let (sent, received) = traffic.total();
+            let _ = writeln!(out, "clipboard_sync_peer_bytes_total{{peer=\"{peer}\",direction=\"sent\"}} {sent}");
+            let _ = writeln!(out, "clipboard_sync_peer_bytes_total{{peer=\"{peer}\",direction=\"received\"}} {received}");
+        }
+        let _ = writeln!(out, "# HELP clipboard_sync_peer_bytes_last_hour Payload bytes exchanged with each peer in the last hour");
+        let _ = writeln!(out, "# TYPE clipboard_sync_peer_bytes_last_hour gauge");
+        for (peer, traffic) in self.bandwidth.peers() {
+            let (sent, received) = traffic.recent();
+            let _ = writeln!(out, "clipboard_sync_peer_bytes_last_hour{{peer=\"{peer}\",direction=\"sent\"}} {sent}");
+            let _ = writeln!(out, "clipboard_sync_peer_bytes_last_hour{{peer=\"{peer}\",direction=\"received\"}} {received}");
+        }
+        if let Some(cap) = self.bandwidth.cap() {
+            let _ = writeln!(out, "# HELP clipboard_sync_bandwidth_cap_bytes Bytes per hour after which images stop going out");
+            let _ = writeln!(out, "# TYPE clipboard_sync_bandwidth_cap_bytes gauge");
+            let _ = writeln!(out, "clipboard_sync_bandwidth_cap_bytes {cap}");
+        }
+
+        if let Some((reservations, circuits)) = self.relay {
+            let _ = writeln!(out, "# HELP clipboard_sync_relay_reservations Peers holding a reservation on this relay");
+            let _ = writeln!(out, "# TYPE clipboard_sync_relay_reservations gauge");
+            let _ = writeln!(out, "clipboard_sync_relay_reservations {reservations}");
+            let _ = writeln!(out, "# HELP clipboard_sync_relay_circuits Connections currently relayed");
+            let _ = writeln!(out, "# TYPE clipboard_sync_relay_circuits gauge");
+            let _ = writeln!(out, "clipboard_sync_relay_circuits {circuits}");
+        }
+
+        if let Some(ref cache) = self.payload_cache {
+            let metrics = [
+                ("cache_hits_total", "counter", "Spilled payloads read back", &cache.hits),
+                ("cache_misses_total", "counter", "Spilled payloads requested after eviction", &cache.misses),
+                ("cache_evictions_total", "counter", "Spilled payloads evicted for age or size", &cache.evictions),
+                ("cache_entries", "gauge", "Spilled payloads on disk", &cache.entries),
+                ("cache_bytes", "gauge", "Total size of spilled payloads on disk", &cache.bytes),
+            ];
+            for (name, kind, help, value) in metrics {
+                let _ = writeln!(out, "# HELP clipboard_sync_{name} {help}");
+                let _ = writeln!(out, "# TYPE clipboard_sync_{name} {kind}");
+                let _ = writeln!(out, "clipboard_sync_{name} {}", value.load(Ordering::Relaxed));
+            }
+        }
+
+        let _ = writeln!(out, "# HELP clipboard_sync_payload_size_bytes Serialized clipboard payload sizes");
+        let _ = writeln!(out, "# TYPE clipboard_sync_payload_size_bytes histogram");
+        for ((direction, content_type), histogram) in &self.sizes {
+            let labels = format!("direction=\"{}\",content_type=\"{content_type}\"", direction.label());
+            for (bound, count) in histogram.cumulative() {
+                let _ = writeln!(out, "clipboard_sync_payload_size_bytes_bucket{{{labels},le=\"{bound}\"}} {count}");
+            }
+            let _ = writeln!(out, "clipboard_sync_payload_size_bytes_bucket{{{labels},le=\"+Inf\"}} {}", histogram.count());
+            let _ = writeln!(out, "clipboard_sync_payload_size_bytes_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(out, "clipboard_sync_payload_size_bytes_count{{{labels}}} {}", histogram.count());
+        }
+
+        let _ = writeln!(out, "# HELP clipboard_sync_publish_queue Clipboard publishes waiting for full send queues to drain");
+        let _ = writeln!(out, "# TYPE clipboard_sync_publish_queue gauge");
+        let _ = writeln!(out, "clipboard_sync_publish_queue {}", self.publish_queue);
+        let _ = writeln!(out, "# HELP clipboard_sync_publish_retries_total Publishes retried after finding the send queues full");
+        let _ = writeln!(out, "# TYPE clipboard_sync_publish_retries_total counter");
+        let _ = writeln!(out, "clipboard_sync_publish_retries_total {}", self.publish_retries);
+        let _ = writeln!(out, "# HELP clipboard_sync_publish_fallbacks_total Clipboard items sent directly after their retries ran out");
+        let _ = writeln!(out, "# TYPE clipboard_sync_publish_fallbacks_total counter");
+        let _ = writeln!(out, "clipboard_sync_publish_fallbacks_total {}", self.publish_fallbacks);
+        let _ = writeln!(out, "# HELP clipboard_sync_publish_failures_total Failed publishes by reason");
+        let _ = writeln!(out, "# TYPE clipboard_sync_publish_failures_total counter");
+        for (reason, count) in &self.publish_failures {
+            let _ = writeln!(out, "clipboard_sync_publish_failures_total{{reason=\"{reason}\"}} {count}");
+        }
+        let _ = writeln!(out, "# HELP clipboard_sync_downgrades_total Local copies sent in another form for being too large");
+        let _ = writeln!(out, "# TYPE clipboard_sync_downgrades_total counter");
+        for (tier, count) in &self.downgrades {
+            let _ = writeln!(out, "clipboard_sync_downgrades_total{{tier=\"{tier}\"}} {count}");
+        }
+
+        let _ = writeln!(out, "# HELP clipboard_sync_room_items_total Clipboard items sent to and received from each room");
+        let _ = writeln!(out, "# TYPE clipboard_sync_room_items_total counter");
+        for ((room, direction), count) in &self.rooms {
+            let _ = writeln!(out, "clipboard_sync_room_items_total{{room=\"{room}\",direction=\"{}\"}} {count}", direction.label());
+        }
+
+        if let Some(ref timings) = self.op_timings {
+            let _ = writeln!(out, "# HELP clipboard_sync_operation_duration_ms Durations of hot path operations");
+            let _ = writeln!(out, "# TYPE clipboard_sync_operation_duration_ms histogram");
+            for (operation, histogram) in timings.snapshot() {
+                let labels = format!("operation=\"{operation}\"");
+                for (bound, count) in histogram.cumulative() {
+                    let _ = writeln!(out, "clipboard_sync_operation_duration_ms_bucket{{{labels},le=\"{bound}\"}} {count}");
+                }
+                let _ = writeln!(out, "clipboard_sync_operation_duration_ms_bucket{{{labels},le=\"+Inf\"}} {}", histogram.count());
+                let _ = writeln!(out, "clipboard_sync_operation_duration_ms_sum{{{labels}}} {}", histogram.sum_ms());
+                let _ = writeln!(out, "clipboard_sync_operation_duration_ms_count{{{labels}}} {}", histogram.count());
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_latency_summary_has_a_sum_next_to_its_count() {
+        let mut stats = Stats::default();
+        let peer = PeerId::random();
+        stats.peer(peer).record_delivery(1_000, 1_010);
+        stats.peer(peer).record_delivery(2_000, 2_030);
+        let rendered = stats.render_prometheus();
+        assert!(rendered.contains(&format!("clipboard_sync_latency_ms_sum{{peer=\"{peer}\"}} 20\n")));
+        assert!(rendered.contains(&format!("clipboard_sync_latency_ms_count{{peer=\"{peer}\"}} 2\n")));
+    }
+
+    #[test]
+    fn recorded_sizes_land_in_cumulative_buckets() {
+        let mut histogram = SizeHistogram::default();
+        for size in [100, 1024, 1025, 3 << 20, 100 << 20] {
+            histogram.record(size);
+        }
+        let buckets: Vec<(u64, u64)> = histogram.cumulative().collect();
+        assert_eq!(buckets[0], (1 << 10, 2), "the bounds are inclusive");
+        assert_eq!(buckets[1], (4 << 10, 3));
+        assert_eq!(buckets[6], (4 << 20, 4));
+        // The largest only counts towards +Inf
+        assert_eq!(buckets.last(), Some(&(64 << 20, 4)));
+        assert_eq!((histogram.count(), histogram.max()), (5, 100 << 20));
+    }
+
+    #[test]
+    fn sizes_are_exported_by_direction_and_content_type() {
+        let mut stats = Stats::default();
+        stats.record_size(Direction::Sent, &ContentType::Text, 10);
+        stats.record_size(Direction::Received, &ContentType::Image, 2 << 20);
+        stats.record_size(Direction::Received, &ContentType::Image, 3 << 20);
+        let rendered = stats.render_prometheus();
+        let image = "direction=\"received\",content_type=\"image\"";
+        assert!(rendered.contains(&format!("clipboard_sync_payload_size_bytes_bucket{{{image},le=\"1048576\"}} 0\n")));
+        assert!(rendered.contains(&format!("clipboard_sync_payload_size_bytes_bucket{{{image},le=\"4194304\"}} 2\n")));
+        assert!(rendered.contains(&format!("clipboard_sync_payload_size_bytes_count{{{image}}} 2\n")));
+        assert!(rendered.contains("clipboard_sync_payload_size_bytes_count{direction=\"sent\",content_type=\"text\"} 1\n"));
+        assert!(!rendered.contains("direction=\"sent\",content_type=\"image\""));
+    }
+
+    #[test]
+    fn the_closest_peers_are_those_with_the_lowest_round_trip_time() {
+        let mut stats = Stats::default();
+        let [far, near, unmeasured, middle] = [PeerId::random(), PeerId::random(), PeerId::random(), PeerId::random()];
+        stats.peer(far).record_rtt(Duration::from_millis(180));
+        stats.peer(near).record_rtt(Duration::from_millis(4));
+        stats.peer(middle).record_rtt(Duration::from_millis(35));
+        let candidates = [far, near, unmeasured, middle];
+        assert_eq!(stats.closest_peers(&candidates, 2), [near, middle]);
+        // Peers never pinged come last
+        assert_eq!(stats.closest_peers(&candidates, 10), [near, middle, far, unmeasured]);
+        assert!(stats.closest_peers(&candidates, 0).is_empty());
+    }
+}
@@ -0,0 +1,236 @@
+use clap::ValueEnum;
+use futures::future::{self, Ready};
+use libp2p::core::transport::{DialOpts, ListenerId, TransportError, TransportEvent};
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Which end of the stdio link a node is. Connection upgrades need one
+/// dialer and one listener, so the two ends of a tunnel must differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Role {
+    /// Open the connection over stdio
+    Dial,
+    /// Accept the connection coming in over stdio
+    Listen,
+}
+
+/// Address standing for the process's stdio. Only this transport
+/// understands it, so it never reaches TCP.
+pub fn address() -> Multiaddr {
+    Multiaddr::empty().with(Protocol::Unix("stdio".into()))
+}
+
+/// A reader and a writer joined into one duplex stream
+#[derive(Debug)]
+pub struct Pipe<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> Pipe<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+}
+
+impl Pipe<tokio::io::Stdin, tokio::io::Stdout> {
+    /// The process's stdin and stdout. Nothing is read until the stream is
+    /// polled, so building the transport doesn't touch stdin.
+    pub fn stdio() -> Self {
+        Self::new(tokio::io::stdin(), tokio::io::stdout())
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> futures::AsyncRead for Pipe<R, W> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut read_buf = ReadBuf::new(buf);
+        match Pin::new(&mut self.reader).poll_read(cx, &mut read_buf) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(read_buf.filled().len())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> futures::AsyncWrite for Pipe<R, W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+/// Transport carrying exactly one connection over a byte stream, normally
+/// the process's stdio, so an `ssh` channel between two nodes can serve as
+/// the link. Once that connection is handed out there is nothing left to
+/// dial or accept. Without a link it supports no addresses at all.
+pub struct Transport<S> {
+    link: Option<(Role, S)>,
+    events: VecDeque<TransportEvent<Ready<Result<S, io::Error>>, io::Error>>,
+    waker: Option<Waker>,
+}
+
+impl<S> Transport<S> {
+    pub fn new(link: Option<(Role, S)>) -> Self {
+        Self { link, events: VecDeque::new(), waker: None }
+    }
+
+    /// Take the stream if this end plays `role` and it wasn't used yet
+    fn take(&mut self, role: Role, addr: &Multiaddr) -> Result<S, TransportError<io::Error>> {
+        if *addr != address() || !matches!(self.link, Some((link_role, _)) if link_role == role) {
+            return Err(TransportError::MultiaddrNotSupported(addr.clone()));
+        }
+        match self.link.take() {
+            Some((_, stream)) => Ok(stream),
+            None => Err(TransportError::Other(io::Error::other("the stdio connection was already used"))),
+        }
+    }
+}
+
+impl<S: Unpin> libp2p::core::Transport for Transport<S> {
+    type Output = S;
+    type Error = io::Error;
+    type ListenerUpgrade = Ready<Result<S, io::Error>>;
+    type Dial = Ready<Result<S, io::Error>>;
+
+    fn listen_on(&mut self, id: ListenerId, addr: Multiaddr) -> Result<(), TransportError<Self::Error>> {
+        let stream = self.take(Role::Listen, &addr)?;
+        // No `NewAddress`: the address means nothing to other peers, so it
+        // must not be advertised as a listen address
+        self.events.push_back(TransportEvent::Incoming {
+            listener_id: id,
+            upgrade: future::ready(Ok(stream)),
+            local_addr: addr.clone(),
+            send_back_addr: addr,
+        });
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn remove_listener(&mut self, _id: ListenerId) -> bool {
+        false
+    }
+
+    fn dial(&mut self, addr: Multiaddr, _opts: DialOpts) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.take(Role::Dial, &addr).map(|stream| future::ready(Ok(stream)))
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => {
+                self.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use libp2p::core::{upgrade, Transport as _};
+    use libp2p::swarm::{dummy, SwarmEvent};
+    use crate::security::{self, Security};
+    use libp2p::{identity, yamux, PeerId, Swarm, SwarmBuilder};
+    use std::time::Duration;
+
+    /// A swarm whose only transport is a stdio link over `pipe`, set up the
+    /// way `build_swarm` sets it up
+    fn swarm<S>(role: Role, pipe: S, security: Security) -> Swarm<dummy::Behaviour>
+    where
+        S: futures::AsyncRead + futures::AsyncWrite + Unpin + Send + 'static,
+    {
+        let builder = SwarmBuilder::with_existing_identity(identity::Keypair::generate_ed25519()).with_tokio();
+        macro_rules! build {
+            ($security:expr) => {
+                builder
+                    .with_other_transport(|key| -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+                        Ok(Transport::new(Some((role, pipe)))
+                            .upgrade(upgrade::Version::V1)
+                            .authenticate($security(key)?)
+                            .multiplex(yamux::Config::default()))
+                    })
+                    .unwrap()
+                    .with_behaviour(|_| dummy::Behaviour)
+                    .unwrap()
+                    .build()
+            };
+        }
+        match security {
+            Security::Noise => build!(security::noise),
+            Security::Tls => build!(security::tls),
+            Security::Both => build!(security::both),
+        }
+    }
+
+    async fn connected(swarm: &mut Swarm<dummy::Behaviour>) -> PeerId {
+        loop {
+            if let SwarmEvent::ConnectionEstablished { peer_id, .. } = swarm.select_next_some().await {
+                return peer_id;
+            }
+        }
+    }
+
+    /// Connect a dialer and a listener over a pair of pipes
+    async fn connect(dialer: Security, listener: Security) {
+        // Each end reads what the other writes, like `a | ssh host b` does
+        let (a_to_b_reader, a_to_b_writer) = tokio::io::simplex(64 * 1024);
+        let (b_to_a_reader, b_to_a_writer) = tokio::io::simplex(64 * 1024);
+        let mut dialer = swarm(Role::Dial, Pipe::new(b_to_a_reader, a_to_b_writer), dialer);
+        let mut listener = swarm(Role::Listen, Pipe::new(a_to_b_reader, b_to_a_writer), listener);
+
+        listener.listen_on(address()).unwrap();
+        dialer.dial(address()).unwrap();
+        let (dialed, accepted) = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(connected(&mut dialer), connected(&mut listener))
+        })
+        .await
+        .expect("the nodes didn't connect over the pipes");
+        assert_eq!(dialed, *listener.local_peer_id());
+        assert_eq!(accepted, *dialer.local_peer_id());
+    }
+
+    #[tokio::test]
+    async fn two_nodes_connect_over_a_pair_of_pipes() {
+        connect(Security::Noise, Security::Noise).await;
+    }
+
+    #[tokio::test]
+    async fn the_link_is_secured_with_the_chosen_protocol() {
+        connect(Security::Tls, Security::Tls).await;
+        // Offering both reaches either kind
+        connect(Security::Both, Security::Tls).await;
+        connect(Security::Both, Security::Noise).await;
+    }
+
+    #[test]
+    fn only_the_stdio_address_in_the_right_role_is_supported() {
+        let mut transport = Transport::new(Some((Role::Listen, ())));
+        let tcp: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        assert!(matches!(transport.take(Role::Listen, &tcp), Err(TransportError::MultiaddrNotSupported(_))));
+        assert!(matches!(transport.take(Role::Dial, &address()), Err(TransportError::MultiaddrNotSupported(_))));
+        assert!(transport.take(Role::Listen, &address()).is_ok());
+        // The link carries exactly one connection
+        assert!(matches!(transport.take(Role::Listen, &address()), Err(TransportError::MultiaddrNotSupported(_))));
+        assert!(Transport::<()>::new(None).take(Role::Dial, &address()).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use libp2p::gossipsub::{self, IdentTopic, TopicHash};
+use libp2p::PeerId;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Wall clock running ahead of the monotonic clock by more than this between
+/// two sleep checks means the machine was suspended
+const SLEEP_THRESHOLD: Duration = Duration::from_secs(30);
+/// How often to compare the clocks for a suspend
+pub const SLEEP_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What a subscription check found wrong with a topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Desync {
+    /// Gossipsub no longer lists the topic as subscribed
+    Lost,
+    /// Peers are subscribed to the topic but none of them is a mesh or
+    /// explicit peer, so nothing published there reaches us
+    Unmeshed,
+    /// Group members are connected, but gossipsub knows none of them to be
+    /// subscribed to any of our topics
+    Deserted,
+}
+
+/// What to do about a desynced topic, from the least to the most disruptive.
+/// Each check that still finds the topic desynced moves one step further.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Remedy {
+    /// Forward everything to the connected group members again
+    ReaddExplicitPeers,
+    /// Dial the address book, in case the connections are stale
+    RedialKnownPeers,
+    /// Leave and re-join the topic
+    Resubscribe,
+}
+
+/// Health of one topic, for `/status`
+#[derive(Debug, Clone)]
+pub struct TopicHealth {
+    pub topic: IdentTopic,
+    pub subscribed: bool,
+    pub mesh_peers: usize,
+    pub subscribers: usize,
+    /// Time since a message was last published or received on the topic
+    pub since_sync: Option<Duration>,
+}
+
+/// Periodic self-check that the node really is in the gossipsub mesh of every
+/// topic it means to be subscribed to. After network changes or a suspend a
+/// node can end up subscribed on paper while no peer forwards it anything.
+#[derive(Debug)]
+pub struct SubscriptionCheck {
+    topics: Vec<IdentTopic>,
+    /// Whether we deliberately stay out of the mesh (readonly topics)
+    fringe: bool,
+    /// Consecutive checks that found each topic desynced. A fresh subscriber
+    /// is only grafted on the next heartbeat, so one miss is not a desync yet.
+    strikes: HashMap<TopicHash, u32>,
+    /// When a message last went out or came in on each topic
+    last_sync: HashMap<TopicHash, Instant>,
+}
+
+impl SubscriptionCheck {
+    pub fn new(topics: Vec<IdentTopic>, fringe: bool) -> Self {
+        Self { topics, fringe, strikes: HashMap::new(), last_sync: HashMap::new() }
+    }
+
+    /// A message was published or received on `topic`
+    pub fn record_sync(&mut self, topic: &TopicHash) {
+        if self.topics.iter().any(|expected| expected.hash() == *topic) {
+            self.last_sync.insert(topic.clone(), Instant::now());
+        }
+    }
+
+    /// Check every topic, returning the ones that need recovering and the
+    /// next remedy to try. `members` are the connected group members.
+    pub fn run(
+        &mut self,
+        gossipsub: &gossipsub::Behaviour,
+        explicit_peers: &HashSet<PeerId>,
+        members: &[PeerId],
+    ) -> Vec<(IdentTopic, Desync, Remedy)> {
+        let subscribed: HashSet<&TopicHash> = gossipsub.topics().collect();
+        let hashes: HashSet<TopicHash> = self.topics.iter().map(IdentTopic::hash).collect();
+        let deserted = !members.is_empty()
+            && !gossipsub
+                .all_peers()
+                .any(|(peer, topics)| members.contains(peer) && topics.iter().any(|topic| hashes.contains(topic)));
+        let mut desynced = Vec::new();
+        for topic in &self.topics {
+            let hash = topic.hash();
+            // Nothing short of re-joining brings a lost subscription back
+            if !subscribed.contains(&hash) {
+                self.strikes.remove(&hash);
+                desynced.push((topic.clone(), Desync::Lost, Remedy::Resubscribe));
+                continue;
+            }
+            let desync = if deserted {
+                Desync::Deserted
+            } else if !self.fringe && is_unmeshed(gossipsub, &hash, explicit_peers) {
+                Desync::Unmeshed
+            } else {
+                self.strikes.remove(&hash);
+                continue;
+            };
+            let strikes = self.strikes.entry(hash.clone()).or_default();
+            *strikes += 1;
+            let remedy = match *strikes {
+                1 => continue,
+                2 => Remedy::ReaddExplicitPeers,
+                3 => Remedy::RedialKnownPeers,
+                _ => {
+                    // Give the fresh subscription its grace check again
+                    self.strikes.remove(&hash);
+                    Remedy::Resubscribe
+                }
+            };
+            desynced.push((topic.clone(), desync, remedy));
+        }
+        desynced
+    }
+
+    /// Re-join `topic`. Leaving first makes gossipsub announce the
+    /// subscription to every peer again and graft a fresh mesh.
+    pub fn recover(gossipsub: &mut gossipsub::Behaviour, topic: &IdentTopic) -> Result<(), gossipsub::SubscriptionError> {
+        gossipsub.unsubscribe(topic);
+        gossipsub.subscribe(topic).map(|_| ())
+    }
+
+    /// Current health of every topic
+    pub fn health(&self, gossipsub: &gossipsub::Behaviour) -> Vec<TopicHealth> {
+        let subscribed: HashSet<&TopicHash> = gossipsub.topics().collect();
+        self.topics
+            .iter()
+            .map(|topic| {
+                let hash = topic.hash();
+                TopicHealth {
+                    topic: topic.clone(),
+                    subscribed: subscribed.contains(&hash),
+                    mesh_peers: gossipsub.mesh_peers(&hash).count(),
+                    subscribers: topic_subscribers(gossipsub, &hash).len(),
+                    since_sync: self.last_sync.get(&hash).map(Instant::elapsed),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Peers gossipsub knows to be subscribed to `topic`, whether or not they
+/// are in our mesh. These are the peers a message published there reaches:
+/// flood publishing hands it to each of them, and otherwise gossip does.
+pub fn topic_subscribers(gossipsub: &gossipsub::Behaviour, topic: &TopicHash) -> Vec<PeerId> {
+    gossipsub
+        .all_peers()
+        .filter(|(_, topics)| topics.contains(&topic))
+        .map(|(peer, _)| *peer)
+        .collect()
+}
+
+fn is_unmeshed(gossipsub: &gossipsub::Behaviour, topic: &TopicHash, explicit_peers: &HashSet<PeerId>) -> bool {
+    let mesh: HashSet<&PeerId> = gossipsub.mesh_peers(topic).collect();
+    let subscribers = topic_subscribers(gossipsub, topic);
+    !subscribers.is_empty() && subscribers.iter().all(|peer| !mesh.contains(peer) && !explicit_peers.contains(peer))
+}
+
+/// Notices when the machine was suspended. The monotonic clock stops during
+/// a suspend on most platforms while the wall clock keeps going, so the wall
+/// clock suddenly running ahead means we just woke up.
+#[derive(Debug)]
+pub struct SleepDetector {
+    monotonic: Instant,
+    wall: SystemTime,
+}
+
+impl SleepDetector {
+    pub fn new() -> Self {
+        Self { monotonic: Instant::now(), wall: SystemTime::now() }
+    }
+
+    /// How long the machine slept since the previous call, if it did
+    pub fn check(&mut self) -> Option<Duration> {
+        let (monotonic, wall) = (Instant::now(), SystemTime::now());
+        let elapsed = monotonic.duration_since(self.monotonic);
+        let wall_elapsed = wall.duration_since(self.wall).unwrap_or_default();
+        self.monotonic = monotonic;
+        self.wall = wall;
+        wall_elapsed.checked_sub(elapsed).filter(|slept| *slept > SLEEP_THRESHOLD)
+    }
+}
+
+impl Default for SleepDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    fn subscribed(topic: &IdentTopic) -> gossipsub::Behaviour {
+        let mut gossipsub = gossipsub::Behaviour::new(
+            gossipsub::MessageAuthenticity::Signed(Keypair::generate_ed25519()),
+            gossipsub::Config::default(),
+        )
+        .unwrap();
+        gossipsub.subscribe(topic).unwrap();
+        gossipsub
+    }
+
+    #[test]
+    fn a_lost_subscription_is_rejoined_on_the_next_check() {
+        let topic = IdentTopic::new("clipboard");
+        let mut gossipsub = subscribed(&topic);
+        let mut check = SubscriptionCheck::new(vec![topic.clone()], false);
+        assert!(check.run(&gossipsub, &HashSet::new(), &[]).is_empty());
+
+        gossipsub.unsubscribe(&topic);
+        let desynced = check.run(&gossipsub, &HashSet::new(), &[]);
+        let [(lost, desync, remedy)] = desynced.as_slice() else { panic!("{desynced:?}") };
+        assert_eq!((lost.hash(), *desync, *remedy), (topic.hash(), Desync::Lost, Remedy::Resubscribe));
+
+        SubscriptionCheck::recover(&mut gossipsub, &topic).unwrap();
+        assert!(gossipsub.topics().any(|subscribed| *subscribed == topic.hash()));
+        assert!(check.run(&gossipsub, &HashSet::new(), &[]).is_empty());
+    }
+
+    #[test]
+    fn remedies_escalate_while_the_topic_stays_deserted() {
+        let topic = IdentTopic::new("clipboard");
+        let gossipsub = subscribed(&topic);
+        let mut check = SubscriptionCheck::new(vec![topic.clone()], false);
+        // Connected, yet gossipsub knows of no subscription of theirs
+        let members = [PeerId::random()];
+        let mut remedies = Vec::new();
+        for _ in 0..5 {
+            let desynced = check.run(&gossipsub, &HashSet::new(), &members);
+            assert!(desynced.iter().all(|(_, desync, _)| *desync == Desync::Deserted));
+            remedies.push(desynced.first().map(|(_, _, remedy)| *remedy));
+        }
+        assert_eq!(remedies, [
+            None,
+            Some(Remedy::ReaddExplicitPeers),
+            Some(Remedy::RedialKnownPeers),
+            Some(Remedy::Resubscribe),
+            None,
+        ]);
+
+        // Healthy again, so the next desync starts over with its grace check
+        assert!(check.run(&gossipsub, &HashSet::new(), &[]).is_empty());
+        assert!(check.run(&gossipsub, &HashSet::new(), &members).is_empty());
+    }
+
+    #[test]
+    fn health_reports_each_topic_and_its_last_sync() {
+        let (clipboard, chat) = (IdentTopic::new("clipboard"), IdentTopic::new("chat"));
+        let gossipsub = subscribed(&clipboard);
+        let mut check = SubscriptionCheck::new(vec![clipboard.clone(), chat.clone()], false);
+        check.record_sync(&clipboard.hash());
+        // Not one of ours
+        check.record_sync(&IdentTopic::new("other").hash());
+        let health = check.health(&gossipsub);
+        assert_eq!(health.iter().map(|topic| (topic.subscribed, topic.since_sync.is_some())).collect::<Vec<_>>(), [
+            (true, true),
+            (false, false)
+        ]);
+        assert_eq!(check.last_sync.len(), 1);
+    }
+}
@@ -0,0 +1,280 @@
+#[cfg(feature = "clipboard")]
+use arboard::Clipboard;
+use std::borrow::Cow;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// An image on a clipboard, tightly packed RGBA
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageData<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub bytes: Cow<'a, [u8]>,
+}
+
+/// Why a clipboard operation failed
+#[derive(Debug)]
+#[cfg_attr(not(feature = "clipboard"), allow(dead_code))]
+pub enum Error {
+    /// The clipboard holds nothing of the requested kind
+    ContentNotAvailable,
+    /// No clipboard to talk to, e.g. without a graphical session, or in a
+    /// build without the `clipboard` feature
+    ClipboardNotSupported,
+    /// Another program holds the clipboard
+    ClipboardOccupied,
+    /// The content could not be converted to or from the platform's format
+    ConversionFailure,
+    Unknown { description: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ContentNotAvailable => write!(f, "the clipboard holds no content of the requested kind"),
+            Error::ClipboardNotSupported => write!(f, "no clipboard is available"),
+            Error::ClipboardOccupied => write!(f, "the clipboard is held by another program"),
+            Error::ConversionFailure => write!(f, "the clipboard content could not be converted"),
+            Error::Unknown { description } => write!(f, "unknown clipboard error: {description}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(feature = "clipboard")]
+impl From<arboard::Error> for Error {
+    fn from(e: arboard::Error) -> Self {
+        match e {
+            arboard::Error::ContentNotAvailable => Error::ContentNotAvailable,
+            arboard::Error::ClipboardNotSupported => Error::ClipboardNotSupported,
+            arboard::Error::ClipboardOccupied => Error::ClipboardOccupied,
+            arboard::Error::ConversionFailure => Error::ConversionFailure,
+            other => Error::Unknown { description: other.to_string() },
+        }
+    }
+}
+
+/// The clipboard operations the node uses, so the same sync logic runs on
+/// the system clipboard and on an in-memory one for the self-test
+pub trait SystemClipboard: Send {
+    fn get_text(&mut self) -> Result<String, Error>;
+    fn get_image(&mut self) -> Result<ImageData<'static>, Error>;
+    fn get_files(&mut self) -> Result<Vec<PathBuf>, Error>;
+    fn set_text(&mut self, text: String) -> Result<(), Error>;
+    fn set_image(&mut self, image: ImageData<'static>) -> Result<(), Error>;
+    fn set_files(&mut self, paths: &[PathBuf]) -> Result<(), Error>;
+
+    /// A number that changes whenever anything is copied, even the same
+    /// content again, if the platform keeps one. X11 and Wayland don't.
+    fn change_count(&mut self) -> Option<u64> {
+        None
+    }
+}
+
+/// Opens a clipboard, called again whenever its backend has to be re-created
+pub type Connector = Box<dyn Fn() -> Result<Box<dyn SystemClipboard>, Error> + Send + Sync>;
+
+/// Connects to the system clipboard
+#[cfg(feature = "clipboard")]
+pub fn connect() -> Result<Box<dyn SystemClipboard>, Error> {
+    Ok(Box::new(Clipboard::new()?))
+}
+
+/// Built without the `clipboard` feature there is no system clipboard, only
+/// in-memory ones
+#[cfg(not(feature = "clipboard"))]
+pub fn connect() -> Result<Box<dyn SystemClipboard>, Error> {
+    Err(Error::ClipboardNotSupported)
+}
+
+#[cfg(feature = "clipboard")]
+impl SystemClipboard for Clipboard {
+    fn get_text(&mut self) -> Result<String, Error> {
+        Ok(Clipboard::get_text(self)?)
+    }
+
+    fn get_image(&mut self) -> Result<ImageData<'static>, Error> {
+        let image = Clipboard::get_image(self)?;
+        Ok(ImageData { width: image.width, height: image.height, bytes: image.bytes })
+    }
+
+    fn get_files(&mut self) -> Result<Vec<PathBuf>, Error> {
+        Ok(self.get().file_list()?)
+    }
+
+    fn set_text(&mut self, text: String) -> Result<(), Error> {
+        Ok(Clipboard::set_text(self, text)?)
+    }
+
+    fn set_image(&mut self, image: ImageData<'static>) -> Result<(), Error> {
+        Ok(Clipboard::set_image(self, arboard::ImageData { width: image.width, height: image.height, bytes: image.bytes })?)
+    }
+
+    fn set_files(&mut self, paths: &[PathBuf]) -> Result<(), Error> {
+        Ok(self.set().file_list(paths)?)
+    }
+
+    #[cfg(windows)]
+    fn change_count(&mut self) -> Option<u64> {
+        clipboard_win::raw::seq_num().map(|count| u64::from(count.get()))
+    }
+
+    #[cfg(target_os = "macos")]
+    fn change_count(&mut self) -> Option<u64> {
+        use objc2::{msg_send, rc::Retained, ClassType};
+        use objc2_app_kit::NSPasteboard;
+        // Looked up like arboard does, as it can be missing when run as a daemon
+        let pasteboard: Option<Retained<NSPasteboard>> = unsafe { msg_send![NSPasteboard::class(), generalPasteboard] };
+        let count: isize = unsafe { msg_send![&*pasteboard?, changeCount] };
+        Some(count as u64)
+    }
+}
+
+/// What an in-memory clipboard holds
+#[derive(Debug, Clone, Default)]
+enum Contents {
+    #[default]
+    Empty,
+    Text(String),
+    Image(ImageData<'static>),
+    Files(Vec<PathBuf>),
+}
+
+
+/// A clipboard that lives in memory, shared by its clones. The node writes
+/// to it through [`SystemClipboard`]; whoever holds a clone plays the user.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryClipboard {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    contents: Contents,
+    /// Bumped by every copy and write, like a platform change counter
+    changes: u64,
+    /// Refuse the node's writes, like a clipboard another program holds
+    #[cfg(test)]
+    fail_writes: bool,
+    /// Hold up the node's writes, like a clipboard backend that stalls
+    #[cfg(test)]
+    stall_writes: std::time::Duration,
+}
+
+impl MemoryClipboard {
+    /// Connector handing out clones of this clipboard, for
+    /// [`ClipboardSync::with_backend`](crate::clipboard::ClipboardSync::with_backend)
+    pub fn connector(&self) -> Connector {
+        let clipboard = self.clone();
+        Box::new(move || Ok(Box::new(clipboard.clone())))
+    }
+
+    /// Copy `text` as the user would
+    pub fn copy_text(&self, text: &str) {
+        self.replace(Contents::Text(text.to_string()));
+    }
+
+    /// Copy an RGBA image as the user would
+    pub fn copy_image(&self, bytes: Vec<u8>, width: usize, height: usize) {
+        self.replace(Contents::Image(ImageData { width, height, bytes: bytes.into() }));
+    }
+
+    /// Copy files as the user would
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn copy_files(&self, paths: Vec<PathBuf>) {
+        self.replace(Contents::Files(paths));
+    }
+
+    /// The text on the clipboard, if it holds text
+    pub fn text(&self) -> Option<String> {
+        match self.lock().contents {
+            Contents::Text(ref text) => Some(text.clone()),
+            _ => None,
+        }
+    }
+
+    /// The image on the clipboard, if it holds one
+    pub fn image(&self) -> Option<ImageData<'static>> {
+        match self.lock().contents {
+            Contents::Image(ref image) => Some(image.clone()),
+            _ => None,
+        }
+    }
+
+    /// The files on the clipboard, if it holds files
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn files(&self) -> Option<Vec<PathBuf>> {
+        match self.lock().contents {
+            Contents::Files(ref paths) => Some(paths.clone()),
+            _ => None,
+        }
+    }
+
+    /// Make the node's writes fail, or succeed again
+    #[cfg(test)]
+    pub fn fail_writes(&self, fail: bool) {
+        self.lock().fail_writes = fail;
+    }
+
+    /// Make the node's writes take `stall` before they go through
+    #[cfg(test)]
+    pub fn stall_writes(&self, stall: std::time::Duration) {
+        self.lock().stall_writes = stall;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().expect("memory clipboard lock poisoned")
+    }
+
+    fn replace(&self, contents: Contents) {
+        let mut state = self.lock();
+        state.contents = contents;
+        state.changes += 1;
+    }
+
+    fn write(&mut self, contents: Contents) -> Result<(), Error> {
+        #[cfg(test)]
+        if self.lock().fail_writes {
+            return Err(Error::ClipboardOccupied);
+        }
+        #[cfg(test)]
+        {
+            let stall = self.lock().stall_writes;
+            std::thread::sleep(stall);
+        }
+        self.replace(contents);
+        Ok(())
+    }
+}
+
+impl SystemClipboard for MemoryClipboard {
+    fn get_text(&mut self) -> Result<String, Error> {
+        self.text().ok_or(Error::ContentNotAvailable)
+    }
+
+    fn get_image(&mut self) -> Result<ImageData<'static>, Error> {
+        self.image().ok_or(Error::ContentNotAvailable)
+    }
+
+    fn get_files(&mut self) -> Result<Vec<PathBuf>, Error> {
+        self.files().ok_or(Error::ContentNotAvailable)
+    }
+
+    fn set_text(&mut self, text: String) -> Result<(), Error> {
+        self.write(Contents::Text(text))
+    }
+
+    fn set_image(&mut self, image: ImageData<'static>) -> Result<(), Error> {
+        self.write(Contents::Image(image))
+    }
+
+    fn set_files(&mut self, paths: &[PathBuf]) -> Result<(), Error> {
+        self.write(Contents::Files(paths.to_vec()))
+    }
+
+    fn change_count(&mut self) -> Option<u64> {
+        Some(self.lock().changes)
+    }
+}
@@ -0,0 +1,98 @@
+use log::warn;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the operation duration histogram buckets, in ms
+const DURATION_BUCKETS: [u64; 11] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+/// Distribution of how long one kind of operation took
+#[derive(Debug, Default, Clone)]
+pub struct DurationHistogram {
+    /// Non-cumulative count per bucket in `DURATION_BUCKETS`, plus one overflow bucket
+    buckets: [u64; DURATION_BUCKETS.len() + 1],
+    sum_ms: u64,
+    max_ms: u64,
+}
+
+impl DurationHistogram {
+    fn record(&mut self, ms: u64) {
+        let bucket = DURATION_BUCKETS.iter().position(|&bound| ms <= bound).unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum_ms += ms;
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    pub fn sum_ms(&self) -> u64 {
+        self.sum_ms
+    }
+
+    pub fn max_ms(&self) -> u64 {
+        self.max_ms
+    }
+
+    /// Cumulative count of operations no slower than each bucket bound, as
+    /// Prometheus expects
+    pub fn cumulative(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        DURATION_BUCKETS.iter().zip(self.buckets.iter().scan(0, |total, &n| {
+            *total += n;
+            Some(*total)
+        }))
+        .map(|(&bound, count)| (bound, count))
+    }
+}
+
+/// Durations of the operations on the sync hot path (serializing, publishing,
+/// clipboard access), shared between the event loop, the clipboard tasks and
+/// the metrics endpoint. Anything over the budget is logged, since a slow
+/// operation on the event loop stalls chat, swarm events and pings with it.
+#[derive(Debug)]
+pub struct OpTimings {
+    /// Warn about operations taking longer than this many ms (0 disables)
+    budget_ms: AtomicU64,
+    operations: Mutex<BTreeMap<&'static str, DurationHistogram>>,
+}
+
+impl OpTimings {
+    pub fn new(budget_ms: u64) -> Self {
+        Self { budget_ms: AtomicU64::new(budget_ms), operations: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn set_budget(&self, budget_ms: u64) {
+        self.budget_ms.store(budget_ms, Ordering::Relaxed);
+    }
+
+    /// Record that `operation` on `size` bytes took `elapsed`
+    pub fn record(&self, operation: &'static str, size: usize, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        let budget = self.budget_ms.load(Ordering::Relaxed);
+        if budget > 0 && ms > budget {
+            warn!("Slow {operation}: took {ms} ms for {size} bytes (budget {budget} ms)");
+        }
+        self.operations.lock().expect("timings lock poisoned").entry(operation).or_default().record(ms);
+    }
+
+    /// Run `f`, recording how long it took as `operation` on `size` bytes
+    pub fn time<T>(&self, operation: &'static str, size: usize, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.record(operation, size, started.elapsed());
+        result
+    }
+
+    /// Copy of the histograms, by operation name
+    pub fn snapshot(&self) -> BTreeMap<&'static str, DurationHistogram> {
+        self.operations.lock().expect("timings lock poisoned").clone()
+    }
+}
+
+impl Default for OpTimings {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
@@ -0,0 +1,508 @@
+use crate::clipboard::{now_millis, ClipboardContent, ClipboardOptions, ClipboardSync, ContentType};
+use crate::control::{DropReason, NodeEvent, PauseCause};
+use crate::pipeline::Superseded;
+use crate::poll::Schedule;
+use crate::system_clipboard::MemoryClipboard;
+use anyhow::{bail, Context, Result};
+use libp2p::PeerId;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, RandomState};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+
+/// Bumped whenever an event of the trace is renamed, removed or changes meaning
+const SCHEMA_VERSION: u32 = 1;
+
+/// How often the replayed clipboard monitor reads the clipboard
+const REPLAY_POLL: Duration = Duration::from_millis(10);
+/// Resend window of the replayed monitor. Copies that came after the
+/// recorded window are replayed after this one.
+const REPLAY_WINDOW: Duration = Duration::from_millis(500);
+/// How long the replayed monitor gets to publish what a step put on the clipboard
+const REPLAY_SETTLE: Duration = Duration::from_millis(150);
+
+/// One line of a trace
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Milliseconds since recording started
+    at_ms: u64,
+    #[serde(flatten)]
+    event: Event,
+}
+
+/// A node event with the content scrubbed out: items are told apart by a
+/// hash of their payload under a key of the trace's own, never by previews
+/// or window titles
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    /// First line of every trace
+    Started {
+        schema_version: u32,
+        version: String,
+        /// Milliseconds since the Unix epoch
+        started_at_ms: u64,
+        /// The node's --resend-window, in milliseconds
+        #[serde(default = "default_resend_window_ms")]
+        resend_window_ms: u64,
+    },
+    /// The recorder fell behind the event bus, so events are missing here
+    Gap { missed: u64 },
+    Connected { peer: PeerId },
+    Disconnected { peer: PeerId, cause: Option<String> },
+    Identified { peer: PeerId, agent_version: String },
+    Sent { content_type: ContentType, size: usize, hash: String, peers: usize },
+    Received { from: PeerId, content_type: ContentType, size: usize, hash: String, timestamp: u64, direct: bool },
+    Dropped {
+        from: PeerId,
+        content_type: ContentType,
+        size: usize,
+        hash: String,
+        timestamp: u64,
+        direct: bool,
+        reason: DropReason,
+    },
+    Applied { from: PeerId, hash: String, error: Option<String> },
+    PublishFailed { topic: String, reason: String },
+    Held { size: usize, threshold: u64 },
+    Downgraded { tier: String, content_type: ContentType, size: usize },
+    TooLarge { from: PeerId, content_type: ContentType, size: usize },
+    Paused { paused: bool, cause: PauseCause },
+    Confirmed { peer: PeerId },
+    Unconfirmed { missing: Vec<PeerId> },
+    Derived { from: PeerId, converter: String, size: usize },
+    Conflict { from: PeerId },
+    /// File names are left out like any other content
+    Progress { id: u64, done: u64, total: u64 },
+    Transferred { id: u64, bytes: u64, elapsed_ms: u64, error: Option<String> },
+}
+
+impl Event {
+    /// Scrub `event`, hashing the hashes of payloads again under `key`. The
+    /// key is random and never written, so short secrets can't be found in
+    /// a shared trace by hashing guesses.
+    fn scrubbed(event: NodeEvent, key: &RandomState) -> Self {
+        let hex = |hash: u64| format!("{:016x}", key.hash_one(hash));
+        match event {
+            NodeEvent::PeerConnected { peer, .. } => Event::Connected { peer },
+            NodeEvent::PeerDisconnected { peer, cause, .. } => Event::Disconnected { peer, cause },
+            NodeEvent::PeerIdentified { peer, agent_version, .. } => Event::Identified { peer, agent_version },
+            NodeEvent::ClipboardSent { content_type, size, hash, peers, .. } => {
+                Event::Sent { content_type, size, hash: hex(hash), peers }
+            }
+            NodeEvent::ClipboardReceived { from, content_type, size, hash, timestamp, direct, .. } => {
+                Event::Received { from, content_type, size, hash: hex(hash), timestamp, direct }
+            }
+            NodeEvent::ContentDropped { from, content_type, size, hash, timestamp, direct, reason } => {
+                Event::Dropped { from, content_type, size, hash: hex(hash), timestamp, direct, reason }
+            }
+            NodeEvent::ClipboardApplied { from, hash, error } => Event::Applied { from, hash: hex(hash), error },
+            NodeEvent::PublishFailed { topic, reason } => Event::PublishFailed { topic: topic.to_string(), reason },
+            NodeEvent::LargeCopyHeld { size, threshold, .. } => Event::Held { size, threshold },
+            NodeEvent::Downgraded { tier, content_type, size } => {
+                Event::Downgraded { tier: tier.to_string(), content_type, size }
+            }
+            NodeEvent::TooLarge { from, content_type, size } => Event::TooLarge { from, content_type, size },
+            NodeEvent::PauseChanged { paused, cause } => Event::Paused { paused, cause },
+            NodeEvent::DeliveryConfirmed { peer, .. } => Event::Confirmed { peer },
+            NodeEvent::DeliveryUnconfirmed { missing, .. } => Event::Unconfirmed { missing },
+            NodeEvent::Derived { from, converter, size, .. } => Event::Derived { from, converter: converter.to_string(), size },
+            NodeEvent::Conflict { from, .. } => Event::Conflict { from },
+            NodeEvent::TransferProgress { id, done, total, .. } => Event::Progress { id, done, total },
+            NodeEvent::TransferFinished { id, bytes, elapsed_ms, error, .. } => {
+                Event::Transferred { id, bytes, elapsed_ms, error }
+            }
+        }
+    }
+}
+
+fn default_resend_window_ms() -> u64 {
+    2000
+}
+
+/// Record node events to `path` as they happen, one JSON line each, for
+/// `replay` to go through later. Any trace already there is replaced.
+pub fn spawn(path: &Path, mut events: broadcast::Receiver<NodeEvent>, resend_window: Duration) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create trace {}", path.display()))?;
+    // Flushed line by line, so a crash loses nothing recorded before it
+    let mut writer = LineWriter::new(file);
+    let started = Instant::now();
+    let header = Event::Started {
+        schema_version: SCHEMA_VERSION,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        started_at_ms: now_millis(),
+        resend_window_ms: resend_window.as_millis() as u64,
+    };
+    write_entry(&mut writer, 0, header).with_context(|| format!("Failed to write trace {}", path.display()))?;
+    let path = path.to_path_buf();
+    let key = RandomState::new();
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => Event::scrubbed(event, &key),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Trace recorder fell behind, {missed} node events not recorded");
+                    Event::Gap { missed }
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let at_ms = started.elapsed().as_millis() as u64;
+            if let Err(e) = write_entry(&mut writer, at_ms, event) {
+                error!("Failed to write trace {}, recording stopped: {e}", path.display());
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+fn write_entry(writer: &mut impl Write, at_ms: u64, event: Event) -> Result<()> {
+    serde_json::to_writer(&mut *writer, &Entry { at_ms, event })?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Go through the trace at `path`, printing the sequence of decisions it
+/// records, and make the ordering decision on every item that got that far
+/// again with the current code. The local clipboard is replayed too, through
+/// the clipboard monitor on an in-memory clipboard, to check which copies
+/// get published and which applied items are recognized as echoes. Returns
+/// the number of decisions that differ, each of them marked in the output.
+pub async fn replay(path: &Path) -> Result<usize> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read trace {}", path.display()))?;
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let entry: Entry =
+            serde_json::from_str(line).with_context(|| format!("{}:{}: not a trace entry", path.display(), number + 1))?;
+        entries.push(entry);
+    }
+    let resend_window_ms = match entries.first() {
+        Some(Entry { event: Event::Started { schema_version: SCHEMA_VERSION, resend_window_ms, .. }, .. }) => *resend_window_ms,
+        Some(Entry { event: Event::Started { schema_version, .. }, .. }) => {
+            bail!("{} is a version {schema_version} trace, this build reads version {SCHEMA_VERSION}", path.display())
+        }
+        _ => bail!("{} does not start like a trace written with --record", path.display()),
+    };
+    let steps = clipboard_steps(&entries, resend_window_ms);
+    let clipboard_diverged = replay_clipboard(&steps).await?;
+
+    let mut superseded = Superseded::default();
+    let (mut checked, mut diverged) = (0, clipboard_diverged.len());
+    for (index, entry) in entries.iter().enumerate() {
+        println!("{:>10.3}s  {}", entry.at_ms as f64 / 1000.0, describe(&entry.event));
+        if let Some(now) = clipboard_diverged.get(&index) {
+            println!("{:>12}! now {now}", "");
+        }
+        // Only content received over gossipsub that passed the earlier checks
+        // reaches the ordering check
+        let recorded = match entry.event {
+            Event::Received { from, content_type, size, timestamp, direct: false, .. } => {
+                Some((false, from, content_type, size, timestamp))
+            }
+            Event::Dropped { from, content_type, size, timestamp, direct: false, reason: DropReason::Superseded, .. } => {
+                Some((true, from, content_type, size, timestamp))
+            }
+            _ => None,
+        };
+        if let Some((was_superseded, from, content_type, size, timestamp)) = recorded {
+            checked += 1;
+            if superseded.check_item(from, content_type, size, timestamp) != was_superseded {
+                diverged += 1;
+                let now = if was_superseded { "would be applied" } else { "would be dropped as superseded" };
+                println!("{:>12}! now {now}", "");
+            }
+        }
+    }
+    let missed: u64 = entries
+        .iter()
+        .map(|entry| match entry.event {
+            Event::Gap { missed } => missed,
+            _ => 0,
+        })
+        .sum();
+    println!(
+        "{} events, {checked} ordering decisions and {} clipboard changes replayed, {diverged} differ",
+        entries.len(),
+        steps.len()
+    );
+    if missed > 0 {
+        println!("{missed} events were not recorded, so decisions around the gaps may differ");
+    }
+    Ok(diverged)
+}
+
+/// A change of the local clipboard recorded in a trace
+#[derive(Debug, PartialEq)]
+enum Step<'a> {
+    /// Content from a peer was applied, and published back if `echoed`
+    Applied { hash: &'a str, content_type: ContentType, echoed: bool },
+    /// The user copied content, which was published. `after_window` when the
+    /// same content was on the clipboard until then, for longer than the
+    /// resend window.
+    Copied { hash: &'a str, content_type: ContentType, after_window: bool },
+}
+
+/// The changes of the local clipboard in `entries`, by the index of the
+/// entry recording them. Text and images only; files aren't read back
+/// from a trace. Content sent again soon after it was sent, by a retry or
+/// `/send-to`, is no new copy.
+fn clipboard_steps(entries: &[Entry], resend_window_ms: u64) -> Vec<(usize, Step<'_>)> {
+    let mut content_types = HashMap::new();
+    let mut steps: Vec<(usize, Step)> = Vec::new();
+    // What is on the clipboard, since when
+    let mut last: Option<(&str, u64)> = None;
+    for (index, entry) in entries.iter().enumerate() {
+        match entry.event {
+            Event::Received { ref hash, content_type, .. } => {
+                content_types.insert(hash.as_str(), content_type);
+            }
+            Event::Applied { ref hash, error: None, .. } => {
+                let content_type = content_types.get(hash.as_str()).copied().unwrap_or(ContentType::Text);
+                if content_type != ContentType::Files {
+                    steps.push((index, Step::Applied { hash, content_type, echoed: false }));
+                    last = Some((hash, entry.at_ms));
+                }
+            }
+            Event::Sent { ref hash, content_type: content_type @ (ContentType::Text | ContentType::Image), .. } => {
+                let within_window = last.is_some_and(|(last, at_ms)| {
+                    last == hash.as_str() && entry.at_ms.saturating_sub(at_ms) < resend_window_ms
+                });
+                if within_window {
+                    if let Some((_, Step::Applied { echoed, .. })) = steps.last_mut() {
+                        *echoed = true;
+                    }
+                    continue;
+                }
+                let after_window = last.is_some_and(|(last, _)| last == hash.as_str());
+                steps.push((index, Step::Copied { hash, content_type, after_window }));
+                last = Some((hash, entry.at_ms));
+            }
+            _ => {}
+        }
+    }
+    steps
+}
+
+/// Take `steps` through the clipboard monitor, returning the entries whose
+/// content it now handles differently with how
+async fn replay_clipboard(steps: &[(usize, Step<'_>)]) -> Result<HashMap<usize, &'static str>> {
+    let clipboard = MemoryClipboard::default();
+    let options = ClipboardOptions {
+        poll: Schedule { min: REPLAY_POLL, max: REPLAY_POLL, backoff: 1.0 },
+        resend_window: REPLAY_WINDOW,
+        ..ClipboardOptions::default()
+    };
+    let sync = ClipboardSync::with_backend(options, clipboard.connector())?;
+    let (published_tx, mut published) = mpsc::unbounded_channel();
+    let monitor = sync.start_monitoring(move |content| {
+        let _ = published_tx.send(content);
+    })
+    .await?;
+    let mut diverged = HashMap::new();
+    for &(index, ref step) in steps {
+        while published.try_recv().is_ok() {}
+        match *step {
+            Step::Applied { hash, content_type, echoed } => {
+                sync.handle_incoming_content(stand_in(hash, content_type)).await?;
+                let now = tokio::time::timeout(REPLAY_SETTLE, published.recv()).await.is_ok();
+                if now != echoed {
+                    diverged.insert(index, if now { "would be published back" } else { "would not be published back" });
+                }
+            }
+            Step::Copied { hash, content_type, after_window } => {
+                if after_window {
+                    tokio::time::sleep(REPLAY_WINDOW).await;
+                }
+                let content = stand_in(hash, content_type);
+                match (content.width, content.height) {
+                    (Some(width), Some(height)) => clipboard.copy_image(content.data.clone(), width as usize, height as usize),
+                    _ => clipboard.copy_text(hash),
+                }
+                let now = tokio::time::timeout(REPLAY_SETTLE, published.recv())
+                    .await
+                    .is_ok_and(|copy| copy.is_some_and(|copy| copy.data == content.data));
+                if !now {
+                    diverged.insert(index, "would not be published");
+                }
+            }
+        }
+    }
+    monitor.abort();
+    Ok(diverged)
+}
+
+/// Content standing in for the item `hash` identifies: its payload is the
+/// hash itself, as text or as the pixels of a 2x2 image
+fn stand_in(hash: &str, content_type: ContentType) -> ClipboardContent {
+    match content_type {
+        ContentType::Image if hash.len() == 16 => ClipboardContent::new_image(hash.as_bytes().to_vec(), 2, 2),
+        _ => ClipboardContent::new_text(hash.to_string()),
+    }
+}
+
+/// One line of the replayed decision sequence
+fn describe(event: &Event) -> String {
+    match event {
+        Event::Started { version, started_at_ms, .. } => format!("recording started by version {version} at {started_at_ms}"),
+        Event::Gap { missed } => format!("{missed} events missing"),
+        Event::Connected { peer } => format!("connected to {peer}"),
+        Event::Disconnected { peer, cause: Some(cause) } => format!("disconnected from {peer}: {cause}"),
+        Event::Disconnected { peer, cause: None } => format!("disconnected from {peer}"),
+        Event::Identified { peer, agent_version } => format!("{peer} identified as {agent_version}"),
+        Event::Sent { content_type, size, hash, peers } => {
+            format!("sent {content_type:?} {hash} ({size} bytes) to {peers} peers")
+        }
+        Event::Received { from, content_type, size, hash, timestamp, direct } => format!(
+            "received {content_type:?} {hash} ({size} bytes, copied at {timestamp}) from {from}{}",
+            if *direct { " directly" } else { "" }
+        ),
+        Event::Dropped { from, content_type, hash, reason, .. } => {
+            format!("dropped {content_type:?} {hash} from {from}: {reason:?}")
+        }
+        Event::Applied { hash, error: None, .. } => format!("applied {hash}"),
+        Event::Applied { hash, error: Some(error), .. } => format!("failed to apply {hash}: {error}"),
+        Event::PublishFailed { topic, reason } => format!("publishing to {topic} failed: {reason}"),
+        Event::Held { size, threshold } => format!("held a {size} byte copy over the {threshold} byte limit"),
+        Event::Downgraded { tier, content_type, size } => format!("downgraded a {size} byte {content_type:?} copy: {tier}"),
+        Event::TooLarge { from, content_type, size } => format!("{from} copied {content_type:?} too large to sync ({size} bytes)"),
+        Event::Paused { paused: true, cause } => format!("paused ({cause:?})"),
+        Event::Paused { paused: false, cause } => format!("resumed ({cause:?})"),
+        Event::Confirmed { peer } => format!("{peer} confirmed delivery"),
+        Event::Unconfirmed { missing } => format!("{} peers did not confirm delivery", missing.len()),
+        Event::Derived { from, converter, size } => format!("{converter} found {size} bytes of text in the image from {from}"),
+        Event::Conflict { from } => format!("content from {from} conflicted with a local copy"),
+        Event::Progress { id, done, total } => format!("transfer #{id} at {done} of {total} bytes"),
+        Event::Transferred { id, bytes, elapsed_ms, error: None } => format!("transfer #{id} done, {bytes} bytes in {elapsed_ms}ms"),
+        Event::Transferred { id, bytes, elapsed_ms, error: Some(error) } => {
+            format!("transfer #{id} stopped after {bytes} bytes in {elapsed_ms}ms: {error}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn applied(hash: u64) -> NodeEvent {
+        NodeEvent::ClipboardApplied { from: PeerId::random(), hash, error: None }
+    }
+
+    fn hash_of(event: &Event) -> &str {
+        match event {
+            Event::Applied { hash, .. } => hash,
+            _ => panic!("not an applied item: {event:?}"),
+        }
+    }
+
+    #[test]
+    fn hashes_are_keyed_per_trace() {
+        let (one, other) = (RandomState::new(), RandomState::new());
+        let first = Event::scrubbed(applied(42), &one);
+        assert_eq!(hash_of(&first), hash_of(&Event::scrubbed(applied(42), &one)));
+        assert_ne!(hash_of(&first), hash_of(&Event::scrubbed(applied(43), &one)));
+        assert_ne!(hash_of(&first), hash_of(&Event::scrubbed(applied(42), &other)));
+        assert_ne!(hash_of(&first), format!("{:016x}", 42));
+    }
+
+    /// A trace of `events`, each `gap_ms` after the one before
+    fn trace(dir: &TempDir, events: Vec<Event>, gap_ms: u64) -> std::path::PathBuf {
+        let path = dir.path().join("trace.jsonl");
+        let mut file = File::create(&path).unwrap();
+        let header = Event::Started {
+            schema_version: SCHEMA_VERSION,
+            version: "test".to_string(),
+            started_at_ms: 0,
+            resend_window_ms: 2000,
+        };
+        write_entry(&mut file, 0, header).unwrap();
+        for (index, event) in events.into_iter().enumerate() {
+            write_entry(&mut file, (index as u64 + 1) * gap_ms, event).unwrap();
+        }
+        path
+    }
+
+    fn received(from: PeerId, hash: &str, content_type: ContentType) -> Event {
+        Event::Received { from, content_type, size: 5, hash: hash.to_string(), timestamp: 1, direct: false }
+    }
+
+    fn applied_from(from: PeerId, hash: &str) -> Event {
+        Event::Applied { from, hash: hash.to_string(), error: None }
+    }
+
+    fn sent(hash: &str, content_type: ContentType) -> Event {
+        Event::Sent { content_type, size: 5, hash: hash.to_string(), peers: 1 }
+    }
+
+    #[test]
+    fn resends_and_echoes_are_told_from_copies() {
+        let peer = PeerId::random();
+        let entries: Vec<Entry> = [
+            received(peer, "aaaa", ContentType::Text),
+            applied_from(peer, "aaaa"),
+            sent("bbbb", ContentType::Text),
+            // A retry of the same copy
+            sent("bbbb", ContentType::Text),
+            applied_from(peer, "cccc"),
+            // Published back right away
+            sent("cccc", ContentType::Text),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(index, event)| Entry { at_ms: index as u64 * 100, event })
+        .collect();
+        assert_eq!(
+            clipboard_steps(&entries, 2000),
+            [
+                (1, Step::Applied { hash: "aaaa", content_type: ContentType::Text, echoed: false }),
+                (2, Step::Copied { hash: "bbbb", content_type: ContentType::Text, after_window: false }),
+                (4, Step::Applied { hash: "cccc", content_type: ContentType::Text, echoed: true }),
+            ]
+        );
+        let entries: Vec<Entry> = [sent("bbbb", ContentType::Text), sent("bbbb", ContentType::Text)]
+            .into_iter()
+            .enumerate()
+            .map(|(index, event)| Entry { at_ms: index as u64 * 5000, event })
+            .collect();
+        assert_eq!(clipboard_steps(&entries, 2000)[1].1, Step::Copied { hash: "bbbb", content_type: ContentType::Text, after_window: true });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_trace_of_copies_and_applied_items_replays_through_the_monitor() {
+        let dir = TempDir::new();
+        let peer = PeerId::random();
+        let path = trace(
+            &dir,
+            vec![
+                sent("1111111111111111", ContentType::Text),
+                received(peer, "2222222222222222", ContentType::Text),
+                applied_from(peer, "2222222222222222"),
+                sent("3333333333333333", ContentType::Image),
+                sent("4444444444444444", ContentType::Text),
+            ],
+            100,
+        );
+        assert_eq!(replay(&path).await.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn an_echo_published_back_is_a_divergence_now() {
+        let dir = TempDir::new();
+        let peer = PeerId::random();
+        let path = trace(
+            &dir,
+            vec![
+                received(peer, "2222222222222222", ContentType::Text),
+                applied_from(peer, "2222222222222222"),
+                sent("2222222222222222", ContentType::Text),
+            ],
+            10,
+        );
+        assert_eq!(replay(&path).await.unwrap(), 1);
+    }
+}
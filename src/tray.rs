@@ -0,0 +1,101 @@
+use crate::control::{NodeCommand, NodeStatus};
+use ksni::blocking::TrayMethods;
+use ksni::menu::StandardItem;
+use log::{error, info};
+use tokio::sync::{mpsc, watch};
+
+/// StatusNotifierItem tray icon; menu entries are forwarded to the node as commands
+struct ClipboardTray {
+    status: NodeStatus,
+    commands: mpsc::UnboundedSender<NodeCommand>,
+}
+
+impl ClipboardTray {
+    fn menu_item(&self, label: &str, command: NodeCommand) -> ksni::MenuItem<Self> {
+        StandardItem {
+            label: label.into(),
+            activate: Box::new(move |this: &mut Self| {
+                let _ = this.commands.send(command.clone());
+            }),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl ksni::Tray for ClipboardTray {
+    fn id(&self) -> String {
+        env!("CARGO_PKG_NAME").into()
+    }
+
+    fn title(&self) -> String {
+        let hardened = if self.status.hardened { "hardened, " } else { "" };
+        if self.status.paused {
+            format!("Clipboard sync ({hardened}paused, {} peers)", self.status.peers)
+        } else {
+            format!("Clipboard sync ({hardened}{} peers)", self.status.peers)
+        }
+    }
+
+    fn icon_name(&self) -> String {
+        if self.status.paused {
+            "media-playback-pause".into()
+        } else {
+            "edit-paste".into()
+        }
+    }
+
+    fn menu(&self) -> Vec<ksni::MenuItem<Self>> {
+        let toggle = if self.status.paused {
+            self.menu_item("Resume", NodeCommand::Resume)
+        } else {
+            self.menu_item("Pause", NodeCommand::Pause)
+        };
+
+        vec![
+            StandardItem {
+                label: format!("{} peers connected", self.status.peers),
+                enabled: false,
+                ..Default::default()
+            }
+            .into(),
+            ksni::MenuItem::Separator,
+            toggle,
+            if self.status.hardened {
+                self.menu_item("Leave hardened mode", NodeCommand::Hardened(false))
+            } else {
+                self.menu_item("Hardened mode", NodeCommand::Hardened(true))
+            },
+            self.menu_item("Send clipboard now", NodeCommand::SendClipboard),
+            self.menu_item("Show last received", NodeCommand::ShowLastReceived),
+            ksni::MenuItem::Separator,
+            self.menu_item("Quit", NodeCommand::Quit),
+        ]
+    }
+}
+
+/// Show the tray icon on its own thread, keeping it in sync with `status`
+pub fn spawn(commands: mpsc::UnboundedSender<NodeCommand>, mut status: watch::Receiver<NodeStatus>) {
+    let tray = ClipboardTray {
+        status: status.borrow().clone(),
+        commands,
+    };
+
+    let handle = match tray.spawn() {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("Failed to start tray icon: {e}");
+            return;
+        }
+    };
+    info!("Tray icon started");
+
+    std::thread::spawn(move || {
+        while futures::executor::block_on(status.changed()).is_ok() {
+            let current = status.borrow_and_update().clone();
+            if handle.update(|tray| tray.status = current).is_none() {
+                break;
+            }
+        }
+    });
+}
@@ -0,0 +1,434 @@
+use crate::address_book::format_age;
+use crate::clipboard::{now_millis, ContentType};
+use crate::control::{NodeCommand, NodeEvent, NodeStatus};
+use crate::progress::{self, format_bytes};
+use crate::stats::{SharedStats, Stats};
+use libp2p::PeerId;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::terminal;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, LineGauge, List, ListItem, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::mpsc as std_mpsc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+
+/// Below this size the dashboard is unreadable and plain mode is used instead
+const MIN_WIDTH: u16 = 80;
+const MIN_HEIGHT: u16 = 20;
+/// Log lines and history entries kept for scrollback
+const LOG_LIMIT: usize = 500;
+const HISTORY_LIMIT: usize = 100;
+/// Transfers shown at once, the oldest first
+const TRANSFERS_SHOWN: usize = 4;
+/// How long to wait for a key press before redrawing
+const TICK: Duration = Duration::from_millis(250);
+
+/// Log target that hands formatted lines to the dashboard's activity pane
+/// instead of writing them over the full-screen UI
+pub struct LogWriter {
+    lines: std_mpsc::Sender<String>,
+    buffer: Vec<u8>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let _ = self.lines.send(String::from_utf8_lossy(&line).trim_end().to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A terminal taken over for the dashboard, not yet drawing
+pub struct Tui {
+    terminal: DefaultTerminal,
+    logs: std_mpsc::Receiver<String>,
+}
+
+/// Switch the terminal to the full-screen dashboard. Fails, leaving the
+/// terminal untouched, if it is too small. The returned writer must become the
+/// logger's target so log output ends up in the activity pane.
+pub fn init() -> io::Result<(Tui, LogWriter)> {
+    let (width, height) = terminal::size()?;
+    if width < MIN_WIDTH || height < MIN_HEIGHT {
+        return Err(io::Error::other(format!(
+            "terminal is {width}x{height}, the dashboard needs at least {MIN_WIDTH}x{MIN_HEIGHT}"
+        )));
+    }
+
+    // Also installs a panic hook that restores the terminal
+    let terminal = ratatui::try_init().inspect_err(|_| ratatui::restore())?;
+    let (lines, logs) = std_mpsc::channel();
+    Ok((Tui { terminal, logs }, LogWriter { lines, buffer: Vec::new() }))
+}
+
+impl Tui {
+    /// Run the dashboard on its own thread until the node drops `status`,
+    /// then restore the terminal
+    pub fn spawn(
+        self,
+        local_peer_id: PeerId,
+        commands: mpsc::UnboundedSender<NodeCommand>,
+        status: watch::Receiver<NodeStatus>,
+        events: broadcast::Receiver<NodeEvent>,
+        stats: SharedStats,
+    ) -> JoinHandle<()> {
+        let Tui { mut terminal, logs } = self;
+        let mut dashboard = Dashboard {
+            local_peer_id,
+            status: status.borrow().clone(),
+            stats,
+            log: VecDeque::new(),
+            history: VecDeque::new(),
+            transfers: BTreeMap::new(),
+        };
+
+        std::thread::spawn(move || {
+            let mut status = status;
+            let mut events = events;
+            loop {
+                match status.has_changed() {
+                    Ok(true) => dashboard.status = status.borrow_and_update().clone(),
+                    Ok(false) => {}
+                    // The node has shut down
+                    Err(_) => break,
+                }
+                loop {
+                    match events.try_recv() {
+                        Ok(event) => dashboard.push_event(event),
+                        Err(broadcast::error::TryRecvError::Lagged(missed)) => {
+                            dashboard.push_log(format!("Dashboard fell behind, {missed} events not shown"));
+                        }
+                        Err(_) => break,
+                    }
+                }
+                while let Ok(line) = logs.try_recv() {
+                    dashboard.push_log(line);
+                }
+
+                if terminal.draw(|frame| dashboard.draw(frame)).is_err() {
+                    break;
+                }
+                if let Some(command) = read_key() {
+                    let _ = commands.send(command);
+                }
+            }
+            ratatui::restore();
+        })
+    }
+}
+
+/// Wait up to one tick for a key press and map it to a command
+fn read_key() -> Option<NodeCommand> {
+    if !event::poll(TICK).ok()? {
+        return None;
+    }
+    let Event::Key(key) = event::read().ok()? else {
+        return None;
+    };
+    if key.kind != KeyEventKind::Press {
+        return None;
+    }
+    match key.code {
+        KeyCode::Char('p') => Some(NodeCommand::Pause),
+        KeyCode::Char('r') => Some(NodeCommand::Resume),
+        KeyCode::Char('s') => Some(NodeCommand::SendClipboard),
+        KeyCode::Char('q') | KeyCode::Esc => Some(NodeCommand::Quit),
+        // Raw mode swallows the signal, so Ctrl+C arrives as a key
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Some(NodeCommand::Quit),
+        KeyCode::Char('c') => Some(NodeCommand::ConfirmLarge),
+        _ => None,
+    }
+}
+
+/// Clipboard item shown in the history pane
+struct HistoryEntry {
+    /// Peer the item came from, `None` for items we sent
+    from: Option<PeerId>,
+    content_type: ContentType,
+    preview: String,
+    size: usize,
+    at_ms: u64,
+}
+
+/// Everything the dashboard shows
+struct Dashboard {
+    local_peer_id: PeerId,
+    status: NodeStatus,
+    stats: SharedStats,
+    log: VecDeque<String>,
+    history: VecDeque<HistoryEntry>,
+    /// Transfers in progress by id: how far along, and the text next to the bar
+    transfers: BTreeMap<u64, (f64, String)>,
+}
+
+impl Dashboard {
+    fn push_log(&mut self, line: String) {
+        if self.log.len() == LOG_LIMIT {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+    }
+
+    fn push_event(&mut self, event: NodeEvent) {
+        let entry = match event {
+            NodeEvent::ClipboardSent { content_type, size, preview, .. } => HistoryEntry {
+                from: None,
+                content_type,
+                preview,
+                size,
+                at_ms: now_millis(),
+            },
+            NodeEvent::ClipboardReceived { from, content_type, size, preview, source, provenance, .. } => HistoryEntry {
+                from: Some(from),
+                content_type,
+                preview: match (source, provenance) {
+                    (Some(source), Some(provenance)) => format!("{preview} [{source}, {provenance}]"),
+                    (Some(label), None) | (None, Some(label)) => format!("{preview} [{label}]"),
+                    (None, None) => preview,
+                },
+                size,
+                at_ms: now_millis(),
+            },
+            NodeEvent::Derived { from, converter, size, preview } => HistoryEntry {
+                from: Some(from),
+                content_type: ContentType::Text,
+                preview: format!("{preview} [derived: {converter}]"),
+                size,
+                at_ms: now_millis(),
+            },
+            NodeEvent::Conflict { from, local_preview, remote_preview } => {
+                self.push_log(format!(
+                    "Conflict: {remote_preview} from {} replaced your copy {local_preview}",
+                    short_peer(&from)
+                ));
+                return;
+            }
+            NodeEvent::TransferProgress { id, label, done, total, rate, eta_secs } => {
+                let ratio = (done as f64 / total.max(1) as f64).min(1.0);
+                let text = format!("#{id} {label}: {}", progress::describe(done, total, rate, eta_secs));
+                self.transfers.insert(id, (ratio, text));
+                return;
+            }
+            // The summary is in the log pane through the console's log output
+            NodeEvent::TransferFinished { id, .. } => {
+                self.transfers.remove(&id);
+                return;
+            }
+            // Already in the log pane through the console's log output
+            _ => return,
+        };
+        if self.history.len() == HISTORY_LIMIT {
+            self.history.pop_back();
+        }
+        self.history.push_front(entry);
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [status, main, log, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Fill(1),
+            Constraint::Percentage(40),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [transfers, main] =
+            Layout::vertical([Constraint::Length(self.transfers_height()), Constraint::Fill(1)]).areas(main);
+        let [peers, history] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(main);
+
+        self.draw_status(frame, status);
+        self.draw_transfers(frame, transfers);
+        self.draw_peers(frame, peers);
+        self.draw_history(frame, history);
+        self.draw_log(frame, log);
+        frame.render_widget(
+            Paragraph::new(" p pause   r resume   s send clipboard   c confirm large copy   q quit").style(Style::new().fg(Color::DarkGray)),
+            help,
+        );
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let state = if self.status.paused {
+            Span::from(" PAUSED ").black().on_yellow()
+        } else {
+            Span::from(" SYNCING ").black().on_green()
+        };
+        let mut spans = vec![state];
+        if self.status.hardened {
+            spans.push(Span::from(" HARDENED ").white().on_red());
+        }
+        spans.push(Span::from(self.status_text()));
+        let line = Line::from(spans);
+        frame.render_widget(Paragraph::new(line).reversed(), area);
+    }
+
+    fn draw_peers(&self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self.peer_rows().into_iter().map(ListItem::new).collect();
+        frame.render_widget(List::new(items).block(Block::bordered().title(" Peers ")), area);
+    }
+
+    /// Rows for the transfers pane: one per transfer and the border, or
+    /// none while nothing is being transferred
+    fn transfers_height(&self) -> u16 {
+        match self.transfers.len() {
+            0 => 0,
+            n => n.min(TRANSFERS_SHOWN) as u16 + 2,
+        }
+    }
+
+    /// A progress bar per transfer in progress
+    fn draw_transfers(&self, frame: &mut Frame, area: Rect) {
+        if self.transfers.is_empty() {
+            return;
+        }
+        let block = Block::bordered().title(" Transfers ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        let rows = Layout::vertical(vec![Constraint::Length(1); self.transfers.len().min(TRANSFERS_SHOWN)]).split(inner);
+        for (row, (ratio, text)) in rows.iter().zip(self.transfers.values()) {
+            let gauge = LineGauge::default()
+                .ratio(*ratio)
+                .label(text.as_str())
+                .filled_style(Style::new().fg(Color::Green))
+                .unfilled_style(Style::new().fg(Color::DarkGray));
+            frame.render_widget(gauge, *row);
+        }
+    }
+
+    fn draw_history(&self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self.history_rows().into_iter().map(ListItem::new).collect();
+        frame.render_widget(List::new(items).block(Block::bordered().title(" History ")), area);
+    }
+
+    /// Text of the status bar after the pause state
+    fn status_text(&self) -> String {
+        let (sent, received) = self.stats.lock().expect("stats lock poisoned").bandwidth().peers().fold(
+            (0, 0),
+            |(sent, received), (_, traffic)| {
+                let (peer_sent, peer_received) = traffic.total();
+                (sent + peer_sent, received + peer_received)
+            },
+        );
+        format!(
+            "  {}  {}  {} peers  ↑ {}  ↓ {}",
+            env!("CARGO_PKG_NAME"),
+            short_peer(&self.local_peer_id),
+            self.status.peers,
+            format_bytes(sent),
+            format_bytes(received)
+        )
+    }
+
+    /// One row per connected peer: its device name once known, ping RTT,
+    /// last sync and the bytes exchanged with it
+    fn peer_rows(&self) -> Vec<String> {
+        let stats = self.stats.lock().expect("stats lock poisoned");
+        self.status
+            .connected
+            .iter()
+            .map(|peer| {
+                let name = peer_name(&stats, peer);
+                let latency = stats.latency(peer);
+                let rtt = latency
+                    .and_then(|latency| latency.rtt_ms())
+                    .map(|ms| format!("{ms}ms"))
+                    .unwrap_or_else(|| "-".to_string());
+                let last_sync = latency
+                    .and_then(|latency| latency.last_sync_ms())
+                    .map(|ms| format_age(ms / 1000))
+                    .unwrap_or_else(|| "never".to_string());
+                let (sent, received) = stats.traffic(peer).map(|(total, _)| total).unwrap_or_default();
+                format!(
+                    "{name}  rtt {rtt}  synced {last_sync}  ↑ {} ↓ {}",
+                    format_bytes(sent),
+                    format_bytes(received)
+                )
+            })
+            .collect()
+    }
+
+    /// One row per recent clipboard item, newest first
+    fn history_rows(&self) -> Vec<String> {
+        let stats = self.stats.lock().expect("stats lock poisoned");
+        self.history
+            .iter()
+            .map(|entry| {
+                let direction = match entry.from {
+                    Some(ref peer) => format!("← {}", peer_name(&stats, peer)),
+                    None => "→ sent".to_string(),
+                };
+                format!(
+                    "{:>8}  {direction}  {:?}: {} ({} bytes)",
+                    format_age(entry.at_ms / 1000),
+                    entry.content_type,
+                    entry.preview,
+                    entry.size
+                )
+            })
+            .collect()
+    }
+
+    fn draw_log(&self, frame: &mut Frame, area: Rect) {
+        let visible = area.height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = self
+            .log
+            .iter()
+            .skip(self.log.len().saturating_sub(visible))
+            .map(|line| Line::from(line.as_str()))
+            .collect();
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Activity ")), area);
+    }
+}
+
+/// Device name of `peer` once identified, with its abbreviated PeerId
+fn peer_name(stats: &Stats, peer: &PeerId) -> String {
+    match stats.peers_seen().get(peer) {
+        Some(Some(name)) => format!("{name} ({})", short_peer(peer)),
+        _ => short_peer(peer),
+    }
+}
+
+/// Abbreviated PeerId that still tells peers apart
+fn short_peer(peer: &PeerId) -> String {
+    let id = peer.to_string();
+    match (id.get(..6), id.get(id.len().saturating_sub(6)..)) {
+        (Some(start), Some(end)) if id.len() > 12 => format!("{start}…{end}"),
+        _ => id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_counts_use_the_largest_unit_that_keeps_them_at_least_one() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1_048_576), "3.0 MiB");
+        assert_eq!(format_bytes(5 * 1_073_741_824), "5.0 GiB");
+    }
+
+    #[test]
+    fn peers_show_their_device_name_once_identified() {
+        let mut stats = Stats::default();
+        let peer = PeerId::random();
+        assert_eq!(peer_name(&stats, &peer), short_peer(&peer));
+        stats.record_peer(peer, Some("laptop"));
+        assert_eq!(peer_name(&stats, &peer), format!("laptop ({})", short_peer(&peer)));
+    }
+}
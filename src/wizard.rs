@@ -0,0 +1,216 @@
+use crate::capabilities::sanitize_device_name;
+use crate::config::{Config, CONFIG_FILE};
+use crate::keystore;
+use crate::profile::Profile;
+use crate::Args;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// Longest group name accepted
+const MAX_GROUP_LEN: usize = 64;
+
+/// Arguments of the `init` subcommand. Each answers one of the questions
+/// asked on a terminal, so scripts can set up a profile the same way.
+#[derive(Debug, Default, clap::Args)]
+pub struct InitArgs {
+    /// Name other devices show for this one. Defaults to the hostname
+    #[clap(long)]
+    pub device_name: Option<String>,
+    /// Group to sync with: devices only sync with others in the same group
+    #[clap(long)]
+    pub group: Option<String>,
+    /// Only chat, without syncing the clipboard
+    #[clap(long)]
+    pub no_clipboard: bool,
+    /// Hold received content until it is accepted with /accept
+    #[clap(long)]
+    pub confirm_incoming: bool,
+    /// Don't ask anything; take the flags given and defaults for the rest
+    #[clap(long)]
+    pub yes: bool,
+    /// Replace the settings of a profile that is already set up
+    #[clap(long)]
+    pub force: bool,
+}
+
+/// Whether starting the node should set up its profile first: a new profile,
+/// started by hand on a terminal without other settings to go by
+pub fn first_run(args: &Args) -> bool {
+    let Some(ref name) = args.profile else {
+        return false;
+    };
+    args.command.is_none()
+        && args.config.is_none()
+        && args.identity_seed.is_none()
+        && !args.doctor
+        && !args.daemon
+        && io::stdin().is_terminal()
+        && io::stdout().is_terminal()
+        && Profile::open(name).is_ok_and(|profile| is_new(&profile))
+}
+
+/// Ask the setup questions, or take the answers from `init`, then create the
+/// identity key and write the profile's config file
+pub fn run(profile: &Profile, init: &InitArgs, identity_passphrase: Option<&str>) -> Result<()> {
+    let interactive = !init.yes && io::stdin().is_terminal();
+    let config_path = profile.dir().join(CONFIG_FILE);
+    if !is_new(profile) && !init.force {
+        if !interactive {
+            bail!("Profile '{}' is already set up; pass --force to replace its settings", profile.name());
+        }
+        if !confirm(&format!("Profile '{}' is already set up. Replace its settings?", profile.name()), false)? {
+            bail!("Left profile '{}' as it was", profile.name());
+        }
+    }
+    if interactive {
+        println!("Setting up profile '{}'. Press Enter to take the value in brackets.", profile.name());
+    }
+
+    let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+    let device_name = match init.device_name {
+        Some(ref name) => check_device_name(name)?,
+        None if interactive => ask("Device name", &hostname, check_device_name)?,
+        None => check_device_name(&hostname)?,
+    };
+    let group = match init.group {
+        Some(ref group) => check_group(group)?,
+        None if interactive => ask("Group name (the same on every device that should sync)", crate::bridge::DEFAULT_ROOM, check_group)?,
+        None => crate::bridge::DEFAULT_ROOM.to_string(),
+    };
+    let clipboard = if interactive && !init.no_clipboard { confirm("Sync the clipboard?", true)? } else { !init.no_clipboard };
+    let confirm_incoming = if interactive && !init.confirm_incoming && clipboard {
+        confirm("Hold received content until you accept it?", false)?
+    } else {
+        init.confirm_incoming
+    };
+
+    let text = [
+        "# Written by `clipboard-sync init`; flags given on the command line take precedence".to_string(),
+        format!("device-name = {}", toml::Value::String(device_name.clone())),
+        format!("room = {}", toml::Value::String(group.clone())),
+        format!("clipboard = {clipboard}"),
+        format!("queue-incoming = {confirm_incoming}"),
+    ]
+    .join("\n")
+        + "\n";
+    // Whatever is written must load again on the next start
+    Config::parse(&text).context("Generated an invalid config file")?;
+
+    let keypair = keystore::load_or_generate(&profile.identity_path(), identity_passphrase)?;
+    let temp = config_path.with_extension("toml.tmp");
+    fs::write(&temp, &text).with_context(|| format!("Failed to write {}", temp.display()))?;
+    fs::rename(&temp, &config_path).with_context(|| format!("Failed to replace {}", config_path.display()))?;
+
+    println!();
+    println!("Profile '{}' is ready: {}", profile.name(), config_path.display());
+    println!("This device is {device_name} ({}).", keypair.public().to_peer_id());
+    println!("Set up the next device with the same group, and they find each other on the local network:");
+    println!();
+    println!("    clipboard-sync --profile {} init --group {group}", profile.name());
+    println!();
+    println!("Start syncing with: clipboard-sync --profile {}", profile.name());
+    Ok(())
+}
+
+/// A profile without an identity key or settings yet
+fn is_new(profile: &Profile) -> bool {
+    !profile.identity_path().exists() && !profile.dir().join(CONFIG_FILE).exists()
+}
+
+/// Group names become room names, which end at a `:` direction suffix and
+/// show up in logs, so keep them to a plain charset
+fn check_group(group: &str) -> Result<String> {
+    let group = group.trim();
+    if group.is_empty()
+        || group.len() > MAX_GROUP_LEN
+        || !group.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        bail!("Invalid group name {group:?}: use 1-{MAX_GROUP_LEN} letters, digits, '-', '_' or '.'");
+    }
+    Ok(group.to_string())
+}
+
+fn check_device_name(name: &str) -> Result<String> {
+    sanitize_device_name(name).context("The device name must contain something besides control characters")
+}
+
+/// Ask for a value until `check` accepts it, taking `default` for an empty answer
+fn ask(question: &str, default: &str, check: impl Fn(&str) -> Result<String>) -> Result<String> {
+    loop {
+        let answer = prompt(&format!("{question} [{default}]: "))?;
+        match check(if answer.is_empty() { default } else { &answer }) {
+            Ok(value) => return Ok(value),
+            Err(e) => println!("{e}"),
+        }
+    }
+}
+
+/// Ask a yes/no question, taking `default` for an empty answer
+fn confirm(question: &str, default: bool) -> Result<bool> {
+    loop {
+        let answer = prompt(&format!("{question} [{}]: ", if default { "Y/n" } else { "y/N" }))?;
+        match answer.to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer yes or no"),
+        }
+    }
+}
+
+fn prompt(text: &str) -> Result<String> {
+    print!("{text}");
+    io::stdout().flush()?;
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        bail!("Setup cancelled");
+    }
+    Ok(line.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn scripted(group: &str, force: bool) -> InitArgs {
+        InitArgs {
+            device_name: Some("desk".to_string()),
+            group: Some(group.to_string()),
+            confirm_incoming: true,
+            yes: true,
+            force,
+            ..InitArgs::default()
+        }
+    }
+
+    #[test]
+    fn a_scripted_init_writes_a_config_that_loads() {
+        let dir = TempDir::new();
+        let profile = Profile::at(dir.path());
+        run(&profile, &scripted("home", false), None).unwrap();
+        assert!(profile.identity_path().exists());
+        let text = fs::read_to_string(profile.dir().join(CONFIG_FILE)).unwrap();
+        assert!(text.contains("device-name = \"desk\"\nroom = \"home\"\nclipboard = true\nqueue-incoming = true\n"), "{text}");
+        Config::load(&profile.dir().join(CONFIG_FILE)).unwrap();
+    }
+
+    #[test]
+    fn a_set_up_profile_is_only_replaced_with_force() {
+        let dir = TempDir::new();
+        let profile = Profile::at(dir.path());
+        run(&profile, &scripted("home", false), None).unwrap();
+        assert!(run(&profile, &scripted("work", false), None).is_err());
+        run(&profile, &scripted("work", true), None).unwrap();
+        assert!(fs::read_to_string(profile.dir().join(CONFIG_FILE)).unwrap().contains("room = \"work\""));
+    }
+
+    #[test]
+    fn group_names_keep_to_a_plain_charset() {
+        assert_eq!(check_group(" home-2.lan ").unwrap(), "home-2.lan");
+        assert!(check_group("home:send").is_err());
+        assert!(check_group("").is_err());
+        assert!(check_group(&"g".repeat(MAX_GROUP_LEN + 1)).is_err());
+    }
+}